@@ -1,11 +1,12 @@
 //! Throughput Benchmark for FlashKV
 //!
 //! This benchmark measures the performance of the storage engine
-//! under various workloads.
+//! under various workloads, and of the RESP parser.
 
 use bytes::Bytes;
 use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
-use flashkv::storage::StorageEngine;
+use flashkv::protocol::RespParser;
+use flashkv::storage::{ShardLocking, StorageEngine};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -84,6 +85,54 @@ fn bench_get(c: &mut Criterion) {
     group.finish();
 }
 
+/// Benchmark 50-key batches, which lock each shard once for all its keys
+fn bench_batch(c: &mut Criterion) {
+    let engine = Arc::new(StorageEngine::new());
+
+    for i in 0..100_000 {
+        let key = Bytes::from(format!("key:{}", i));
+        let value = Bytes::from(format!("value:{}", i));
+        engine.set(key, value);
+    }
+    let batches: Vec<Vec<Bytes>> = (0..1000)
+        .map(|batch| {
+            (0..50)
+                .map(|i| Bytes::from(format!("key:{}", (batch * 50 + i) % 100_000)))
+                .collect()
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("batch");
+    group.throughput(Throughput::Elements(50));
+
+    group.bench_function("mget_50", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            black_box(engine.mget(&batches[i % batches.len()]));
+            i += 1;
+        });
+    });
+
+    group.bench_function("mset_50", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            let keys = &batches[i % batches.len()];
+            engine.mset(keys.iter().map(|k| (k.clone(), k.clone())).collect());
+            i += 1;
+        });
+    });
+
+    group.bench_function("exists_50", |b| {
+        let mut i = 0;
+        b.iter(|| {
+            black_box(engine.exists_many(&batches[i % batches.len()]));
+            i += 1;
+        });
+    });
+
+    group.finish();
+}
+
 /// Benchmark mixed workload (80% reads, 20% writes)
 fn bench_mixed(c: &mut Criterion) {
     let engine = Arc::new(StorageEngine::new());
@@ -101,7 +150,7 @@ fn bench_mixed(c: &mut Criterion) {
     group.bench_function("80_read_20_write", |b| {
         let mut i = 0u64;
         b.iter(|| {
-            if i % 5 == 0 {
+            if i.is_multiple_of(5) {
                 // 20% writes
                 let key = Bytes::from(format!("new:{}", i));
                 let value = Bytes::from("value");
@@ -181,6 +230,71 @@ fn bench_concurrent(c: &mut Criterion) {
     group.finish();
 }
 
+/// Benchmark how the engine scales with threads, with reader-writer shards
+/// and with mutex buckets
+///
+/// Every thread runs the same 80% GET / 20% SET mix over a shared set of
+/// keys, so the total work grows with the thread count. Each locking gets
+/// its default shard count.
+fn bench_scaling(c: &mut Criterion) {
+    use std::thread;
+
+    const KEYS: usize = 10_000;
+    const OPS_PER_THREAD: usize = 10_000;
+
+    let keys: Arc<Vec<Bytes>> = Arc::new(
+        (0..KEYS)
+            .map(|i| Bytes::from(format!("key:{}", i)))
+            .collect(),
+    );
+    let value = Bytes::from("value");
+
+    let mut group = c.benchmark_group("scaling");
+    group.sample_size(20);
+    group.measurement_time(Duration::from_secs(5));
+
+    for locking in [ShardLocking::ReadWrite, ShardLocking::Bucket] {
+        let engine = Arc::new(StorageEngine::with_locking(
+            1,
+            locking.default_shard_count(),
+            locking,
+        ));
+        for key in keys.iter() {
+            engine.set(key.clone(), value.clone());
+        }
+
+        for threads in [8, 16, 32, 64] {
+            group.throughput(Throughput::Elements((threads * OPS_PER_THREAD) as u64));
+            group.bench_function(format!("{}_{}_threads", locking, threads), |b| {
+                b.iter(|| {
+                    let handles: Vec<_> = (0..threads)
+                        .map(|t| {
+                            let engine = Arc::clone(&engine);
+                            let keys = Arc::clone(&keys);
+                            let value = value.clone();
+                            thread::spawn(move || {
+                                for i in 0..OPS_PER_THREAD {
+                                    let key = &keys[(i * 7 + t * 131) % KEYS];
+                                    if i % 5 == 0 {
+                                        engine.set(key.clone(), value.clone());
+                                    } else {
+                                        black_box(engine.get(key));
+                                    }
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                });
+            });
+        }
+    }
+
+    group.finish();
+}
+
 /// Benchmark expiry operations
 fn bench_expiry(c: &mut Criterion) {
     let engine = Arc::new(StorageEngine::new());
@@ -237,16 +351,57 @@ fn bench_keys(c: &mut Criterion) {
 
     group.bench_function("keys_pattern", |b| {
         b.iter(|| {
-            black_box(engine.keys("user:*"));
+            black_box(engine.keys(b"user:*"));
         });
     });
 
     group.bench_function("keys_all", |b| {
         b.iter(|| {
-            black_box(engine.keys("*"));
+            black_box(engine.keys(b"*"));
+        });
+    });
+
+    group.finish();
+}
+
+/// Benchmark parsing a pipeline of many small commands, and one long
+/// inline command where finding the CRLF dominates
+fn bench_parse(c: &mut Criterion) {
+    let mut pipeline = Vec::new();
+    for i in 0..100 {
+        let key = format!("key:{}", i);
+        pipeline.extend_from_slice(
+            format!(
+                "*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n$5\r\nvalue\r\n",
+                key.len(),
+                key
+            )
+            .as_bytes(),
+        );
+    }
+
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(pipeline.len() as u64));
+
+    group.bench_function("pipeline_100_sets", |b| {
+        let mut parser = RespParser::new();
+        b.iter(|| {
+            let mut buf = &pipeline[..];
+            while let Some((command, consumed)) = parser.parse(buf).unwrap() {
+                black_box(command);
+                buf = &buf[consumed..];
+            }
         });
     });
 
+    // Inline commands have no length prefix, so the whole line is scanned
+    let inline = format!("SET key {}\r\n", "v".repeat(4096)).into_bytes();
+    group.throughput(Throughput::Bytes(inline.len() as u64));
+    group.bench_function("inline_4k", |b| {
+        let mut parser = RespParser::new();
+        b.iter(|| black_box(parser.parse(&inline).unwrap()));
+    });
+
     group.finish();
 }
 
@@ -254,11 +409,14 @@ criterion_group!(
     benches,
     bench_set,
     bench_get,
+    bench_batch,
     bench_mixed,
     bench_incr,
     bench_concurrent,
+    bench_scaling,
     bench_expiry,
     bench_keys,
+    bench_parse,
 );
 
 criterion_main!(benches);
@@ -0,0 +1,167 @@
+//! Command Keys
+//!
+//! To route a command, cluster mode needs the keys it touches. Most
+//! commands take a single key as their first argument; the rest are
+//! listed here by the shape of their arguments.
+
+use crate::protocol::RespValue;
+
+/// Returns the keys `cmd` (upper-cased) would touch with `args` (the
+/// arguments after the command name). Commands without keys, and malformed
+/// calls the command itself will reject, give no keys.
+pub fn command_keys<'a>(cmd: &str, args: &'a [RespValue]) -> Vec<&'a [u8]> {
+    let keys: &[RespValue] = match cmd {
+        // No keys
+        "PING" | "ECHO" | "INFO" | "DBSIZE" | "FLUSHDB" | "FLUSHALL" | "COMMAND" | "CONFIG"
+        | "TIME" | "SAVE" | "BGSAVE" | "LASTSAVE" | "EXPORT" | "BGREWRITEAOF" | "DEBUG"
+        | "QUIT" | "KEYS" | "SCAN" | "REPLICAOF" | "SLAVEOF" | "REPLCONF" | "SYNC" | "PSYNC"
+        | "CLUSTER" | "ASKING" | "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE"
+        | "PUBLISH" | "PUBSUB" | "HELLO" | "CLIENT" | "SELECT" | "MONITOR" | "FUNCTION" => &[],
+
+        // Every argument is a key
+        "DEL" | "EXISTS" | "MGET" | "TOUCH" | "UNLINK" | "SINTER" | "SUNION" | "SDIFF"
+        | "SINTERSTORE" | "SUNIONSTORE" | "SDIFFSTORE" | "PFCOUNT" | "PFMERGE" => args,
+
+        // Key/value pairs
+        "MSET" | "MSETNX" => {
+            return args
+                .iter()
+                .step_by(2)
+                .filter_map(RespValue::as_bytes)
+                .collect();
+        }
+
+        // Two keys
+        "RENAME" | "RENAMENX" | "COPY" | "LMOVE" | "RPOPLPUSH" => &args[..args.len().min(2)],
+
+        // Keys followed by a timeout
+        "BLPOP" | "BRPOP" | "BZPOPMIN" | "BZPOPMAX" => &args[..args.len().saturating_sub(1)],
+
+        // numkeys key [key ...]
+        "SINTERCARD" => numkeys(args, 0),
+
+        // function numkeys [key ...] [arg ...]
+        "FCALL" | "FCALL_RO" => numkeys(args, 1),
+
+        // destination numkeys key [key ...]
+        "ZUNIONSTORE" | "ZINTERSTORE" | "ZDIFFSTORE" => {
+            let mut keys = args
+                .first()
+                .and_then(RespValue::as_bytes)
+                .into_iter()
+                .collect::<Vec<_>>();
+            keys.extend(numkeys(args, 1).iter().filter_map(RespValue::as_bytes));
+            return keys;
+        }
+
+        // subcommand key ...
+        "OBJECT" | "MEMORY" | "XGROUP" => args.get(1..2).unwrap_or(&[]),
+
+        // ... STREAMS key [key ...] id [id ...]
+        "XREAD" | "XREADGROUP" => {
+            let streams = args.iter().position(|arg| {
+                arg.as_bytes()
+                    .is_some_and(|a| a.eq_ignore_ascii_case(b"STREAMS"))
+            });
+            match streams {
+                Some(at) => {
+                    let rest = &args[at + 1..];
+                    &rest[..rest.len() / 2]
+                }
+                None => &[],
+            }
+        }
+
+        // key [... STORE destination]
+        "SORT" => {
+            let mut keys = args
+                .first()
+                .and_then(RespValue::as_bytes)
+                .into_iter()
+                .collect::<Vec<_>>();
+            let store = args.iter().position(|arg| {
+                arg.as_bytes()
+                    .is_some_and(|a| a.eq_ignore_ascii_case(b"STORE"))
+            });
+            if let Some(dest) = store.and_then(|at| args.get(at + 1)) {
+                keys.extend(dest.as_bytes());
+            }
+            return keys;
+        }
+
+        // Everything else takes a single key first
+        _ => args.get(..1).unwrap_or(&[]),
+    };
+    keys.iter().filter_map(RespValue::as_bytes).collect()
+}
+
+/// Returns the keys of a `numkeys key [key ...]` list starting at `at`.
+fn numkeys(args: &[RespValue], at: usize) -> &[RespValue] {
+    let count = args
+        .get(at)
+        .and_then(RespValue::as_bytes)
+        .and_then(|n| std::str::from_utf8(n).ok())
+        .and_then(|n| n.parse::<usize>().ok())
+        .unwrap_or(0);
+    let rest = args.get(at + 1..).unwrap_or(&[]);
+    &rest[..count.min(rest.len())]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn keys(cmd: &str, args: &[&str]) -> Vec<Vec<u8>> {
+        let args: Vec<RespValue> = args
+            .iter()
+            .map(|a| RespValue::bulk_string(Bytes::from(a.to_string())))
+            .collect();
+        command_keys(cmd, &args)
+            .into_iter()
+            .map(|k| k.to_vec())
+            .collect()
+    }
+
+    fn expect(cmd: &str, args: &[&str], expected: &[&str]) {
+        let expected: Vec<Vec<u8>> = expected.iter().map(|k| k.as_bytes().to_vec()).collect();
+        assert_eq!(keys(cmd, args), expected, "{} {:?}", cmd, args);
+    }
+
+    #[test]
+    fn test_command_keys() {
+        expect("GET", &["a"], &["a"]);
+        expect("SET", &["a", "1", "EX", "10"], &["a"]);
+        expect("PING", &[], &[]);
+        expect("KEYS", &["*"], &[]);
+        expect("DEL", &["a", "b"], &["a", "b"]);
+        expect("MSET", &["a", "1", "b", "2"], &["a", "b"]);
+        expect("RENAME", &["a", "b"], &["a", "b"]);
+        expect("LMOVE", &["a", "b", "LEFT", "RIGHT"], &["a", "b"]);
+        expect("BLPOP", &["a", "b", "0"], &["a", "b"]);
+        expect("SINTERCARD", &["2", "a", "b", "LIMIT", "1"], &["a", "b"]);
+        expect("FCALL", &["f", "1", "a", "x"], &["a"]);
+        expect("FUNCTION", &["LOAD", "#!lua name=lib"], &[]);
+        expect(
+            "ZUNIONSTORE",
+            &["d", "2", "a", "b", "WEIGHTS", "1", "2"],
+            &["d", "a", "b"],
+        );
+        expect("OBJECT", &["ENCODING", "a"], &["a"]);
+        expect("MEMORY", &["USAGE", "a", "SAMPLES", "5"], &["a"]);
+        expect("MEMORY", &["STATS"], &[]);
+        expect("XGROUP", &["CREATE", "s", "g", "$"], &["s"]);
+        expect(
+            "XREAD",
+            &["COUNT", "1", "STREAMS", "a", "b", "0", "0"],
+            &["a", "b"],
+        );
+        expect(
+            "XREADGROUP",
+            &["GROUP", "g", "c", "STREAMS", "s", ">"],
+            &["s"],
+        );
+        expect("SORT", &["a", "LIMIT", "0", "1", "STORE", "d"], &["a", "d"]);
+        expect("ZUNIONSTORE", &["d", "9", "a"], &["d", "a"]);
+    }
+}
@@ -0,0 +1,37 @@
+//! Cluster Module
+//!
+//! In cluster mode the keyspace is split across several servers. Every key
+//! belongs to one of 16384 [hash slots](slot), and every slot is served by
+//! one node; a node only serves commands whose keys are in its own slots.
+//!
+//! ## Redirects
+//!
+//! A command for a slot served elsewhere is answered with a redirect that
+//! tells the client where to go:
+//!
+//! ```text
+//! client                            node A (0-8191)     node B (8192-16383)
+//!    │  GET foo                           │                    │
+//!    │ ─────────────────────────────────> │                    │
+//!    │  -MOVED 12182 127.0.0.1:7001       │                    │
+//!    │ <───────────────────────────────── │                    │
+//!    │  GET foo                                                │
+//!    │ ──────────────────────────────────────────────────────> │
+//! ```
+//!
+//! `MOVED` means the slot lives on the other node for good, and clients
+//! update their slot map (usually by re-reading `CLUSTER SLOTS`). While a
+//! slot is being moved, the node giving it up answers `-ASK` for keys it no
+//! longer has; the client then sends `ASKING` followed by the command to
+//! the node taking the slot over, without updating its map.
+//!
+//! Commands with several keys must have them all in one slot, or they fail
+//! with `-CROSSSLOT`; hash tags let related keys share a slot.
+
+pub mod keys;
+pub mod slot;
+pub mod state;
+
+pub use keys::command_keys;
+pub use slot::{hash_tag, key_slot, SLOT_COUNT};
+pub use state::{parse_slot_ranges, Cluster, Node, Redirect, SlotRange};
@@ -0,0 +1,91 @@
+//! Hash Slots
+//!
+//! A cluster splits the keyspace into [`SLOT_COUNT`] slots, and a key
+//! belongs to slot `CRC16(key) mod 16384`, with the same CRC16 (XMODEM) as
+//! Redis so clients compute the same slots.
+//!
+//! ## Hash Tags
+//!
+//! If a key contains `{...}` with at least one byte between the first `{`
+//! and the next `}`, only those bytes are hashed. `{user:1}:profile` and
+//! `{user:1}:sessions` both hash `user:1` and so share a slot, which lets
+//! multi-key commands use them together.
+
+/// Number of hash slots.
+pub const SLOT_COUNT: u16 = 16384;
+
+/// CRC16 lookup table (polynomial 0x1021, as used by XMODEM and Redis).
+const CRC16_TABLE: [u16; 256] = {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Computes the CRC16 (XMODEM) of `data`.
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &b| {
+        (crc << 8) ^ CRC16_TABLE[(((crc >> 8) as u8) ^ b) as usize]
+    })
+}
+
+/// Returns the part of `key` that is hashed: the hash tag if it has one,
+/// otherwise the whole key.
+pub fn hash_tag(key: &[u8]) -> &[u8] {
+    if let Some(open) = key.iter().position(|&b| b == b'{') {
+        if let Some(len) = key[open + 1..].iter().position(|&b| b == b'}') {
+            if len > 0 {
+                return &key[open + 1..open + 1 + len];
+            }
+        }
+    }
+    key
+}
+
+/// Returns the hash slot of `key`.
+pub fn key_slot(key: &[u8]) -> u16 {
+    crc16(hash_tag(key)) % SLOT_COUNT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc16_matches_redis() {
+        // The check value of CRC16/XMODEM, quoted in the cluster spec
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        assert_eq!(key_slot(b""), 0);
+    }
+
+    #[test]
+    fn test_hash_tags() {
+        assert_eq!(hash_tag(b"{user:1}:profile"), b"user:1");
+        assert_eq!(
+            key_slot(b"{user:1}:profile"),
+            key_slot(b"{user:1}:sessions")
+        );
+        assert_eq!(key_slot(b"{user:1}:profile"), key_slot(b"user:1"));
+
+        // Only the first braces count, and empty tags don't
+        assert_eq!(hash_tag(b"foo{}{bar}"), b"foo{}{bar}");
+        assert_eq!(hash_tag(b"foo{{bar}}"), b"{bar");
+        assert_eq!(hash_tag(b"foo{bar}{zap}"), b"bar");
+        assert_eq!(hash_tag(b"{unclosed"), b"{unclosed");
+    }
+}
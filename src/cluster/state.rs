@@ -0,0 +1,512 @@
+//! Cluster State
+//!
+//! [`Cluster`] holds this server's view of the cluster: the nodes it knows
+//! of and which of them serves each hash slot. Slots being moved between
+//! nodes are marked migrating (on the node giving them up) or importing
+//! (on the node taking them over), so keys already moved can be found with
+//! an `ASK` redirect.
+//!
+//! There is no cluster bus: every node is given the same layout with
+//! `--cluster-slots` and `--cluster-node`, and `CLUSTER SETSLOT` changes it
+//! on the node it is sent to.
+
+use super::slot::{key_slot, SLOT_COUNT};
+use rand::Rng;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+/// Length of a node ID in hex digits.
+const NODE_ID_LEN: usize = 40;
+
+/// Index of this server in the node list.
+const MYSELF: usize = 0;
+
+/// A node of the cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    /// Unique ID of the node
+    pub id: String,
+    /// Host clients reach the node at
+    pub host: String,
+    /// Port clients reach the node at
+    pub port: u16,
+}
+
+impl Node {
+    fn new(host: String, port: u16) -> Self {
+        let mut rng = rand::thread_rng();
+        let id = (0..NODE_ID_LEN)
+            .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+            .collect();
+        Self { id, host, port }
+    }
+
+    /// Returns `host:port`.
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// Why a command can't be served here.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redirect {
+    /// The slot is served by another node
+    Moved { slot: u16, addr: String },
+    /// The slot is migrating and the keys may already be on the target node;
+    /// ask there once, with `ASKING` first
+    Ask { slot: u16, addr: String },
+    /// The keys hash to different slots
+    CrossSlot,
+    /// Some of the keys have moved during a migration and some haven't
+    TryAgain,
+    /// No node serves the slot
+    Down,
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Redirect::Moved { slot, addr } => write!(f, "MOVED {} {}", slot, addr),
+            Redirect::Ask { slot, addr } => write!(f, "ASK {} {}", slot, addr),
+            Redirect::CrossSlot => {
+                write!(f, "CROSSSLOT Keys in request don't hash to the same slot")
+            }
+            Redirect::TryAgain => {
+                write!(f, "TRYAGAIN Multiple keys request during rehashing of slot")
+            }
+            Redirect::Down => write!(f, "CLUSTERDOWN Hash slot not served"),
+        }
+    }
+}
+
+/// A contiguous run of slots served by one node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotRange {
+    /// First slot of the run
+    pub start: u16,
+    /// Last slot of the run (inclusive)
+    pub end: u16,
+    /// Node serving the run
+    pub node: Node,
+}
+
+/// The cluster layout as seen from this server.
+#[derive(Debug)]
+pub struct Cluster {
+    /// Whether cluster mode is on
+    enabled: AtomicBool,
+    /// Nodes and slot assignments
+    state: RwLock<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Known nodes, this server first
+    nodes: Vec<Node>,
+    /// Index into `nodes` of the node serving each slot
+    slots: Vec<Option<usize>>,
+    /// Slots this server is handing over, with the node taking them
+    migrating: HashMap<u16, usize>,
+    /// Slots this server is taking over, with the node handing them over
+    importing: HashMap<u16, usize>,
+}
+
+impl State {
+    /// Returns the index of the node with the given ID.
+    fn node_index(&self, id: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.id == id)
+    }
+}
+
+impl Default for Cluster {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cluster {
+    /// Creates a disabled cluster state with no slots assigned.
+    pub fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            state: RwLock::new(State {
+                nodes: vec![Node::new(String::new(), 0)],
+                slots: vec![None; SLOT_COUNT as usize],
+                migrating: HashMap::new(),
+                importing: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Turns cluster mode on, with clients reaching this server at
+    /// `host:port`.
+    pub fn enable(&self, host: &str, port: u16) {
+        let mut state = self.state.write().unwrap();
+        state.nodes[MYSELF].host = host.to_string();
+        state.nodes[MYSELF].port = port;
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether cluster mode is on.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Returns this server's node.
+    pub fn myself(&self) -> Node {
+        self.state.read().unwrap().nodes[MYSELF].clone()
+    }
+
+    /// Returns every known node, this server first.
+    pub fn nodes(&self) -> Vec<Node> {
+        self.state.read().unwrap().nodes.clone()
+    }
+
+    /// Adds the node at `host:port`, if not already known, and returns its ID.
+    pub fn add_node(&self, host: &str, port: u16) -> String {
+        let mut state = self.state.write().unwrap();
+        if let Some(node) = state
+            .nodes
+            .iter()
+            .find(|node| node.host == host && node.port == port)
+        {
+            return node.id.clone();
+        }
+        let node = Node::new(host.to_string(), port);
+        let id = node.id.clone();
+        state.nodes.push(node);
+        id
+    }
+
+    /// Assigns the slot ranges (inclusive) to the node with the given ID.
+    ///
+    /// Returns `false` if no such node is known.
+    pub fn assign(&self, id: &str, ranges: &[(u16, u16)]) -> bool {
+        let mut state = self.state.write().unwrap();
+        let Some(node) = state.node_index(id) else {
+            return false;
+        };
+        for &(start, end) in ranges {
+            for slot in start..=end {
+                state.slots[slot as usize] = Some(node);
+            }
+        }
+        true
+    }
+
+    /// Assigns the given slots to this server (`CLUSTER ADDSLOTS`).
+    ///
+    /// Fails without assigning any if one is already served.
+    pub fn add_slots(&self, slots: &[u16]) -> Result<(), String> {
+        let mut state = self.state.write().unwrap();
+        if let Some(slot) = slots
+            .iter()
+            .find(|&&slot| state.slots[slot as usize].is_some())
+        {
+            return Err(format!("ERR Slot {} is already busy", slot));
+        }
+        for &slot in slots {
+            state.slots[slot as usize] = Some(MYSELF);
+        }
+        Ok(())
+    }
+
+    /// Unassigns the given slots (`CLUSTER DELSLOTS`).
+    ///
+    /// Fails without unassigning any if one isn't served.
+    pub fn del_slots(&self, slots: &[u16]) -> Result<(), String> {
+        let mut state = self.state.write().unwrap();
+        if let Some(slot) = slots
+            .iter()
+            .find(|&&slot| state.slots[slot as usize].is_none())
+        {
+            return Err(format!("ERR Slot {} is already unassigned", slot));
+        }
+        for &slot in slots {
+            state.slots[slot as usize] = None;
+            state.migrating.remove(&slot);
+            state.importing.remove(&slot);
+        }
+        Ok(())
+    }
+
+    /// Marks `slot`, served here, as migrating to the node with ID `id`.
+    pub fn set_migrating(&self, slot: u16, id: &str) -> Result<(), String> {
+        let mut state = self.state.write().unwrap();
+        if state.slots[slot as usize] != Some(MYSELF) {
+            return Err(format!("ERR I'm not the owner of hash slot {}", slot));
+        }
+        let node = Self::other_node(&state, id)?;
+        state.migrating.insert(slot, node);
+        Ok(())
+    }
+
+    /// Marks `slot` as importing from the node with ID `id`.
+    pub fn set_importing(&self, slot: u16, id: &str) -> Result<(), String> {
+        let mut state = self.state.write().unwrap();
+        if state.slots[slot as usize] == Some(MYSELF) {
+            return Err(format!("ERR I'm already the owner of hash slot {}", slot));
+        }
+        let node = Self::other_node(&state, id)?;
+        state.importing.insert(slot, node);
+        Ok(())
+    }
+
+    /// Clears any migrating or importing state of `slot`.
+    pub fn set_stable(&self, slot: u16) {
+        let mut state = self.state.write().unwrap();
+        state.migrating.remove(&slot);
+        state.importing.remove(&slot);
+    }
+
+    /// Assigns `slot` to the node with ID `id`, ending a migration.
+    pub fn set_node(&self, slot: u16, id: &str) -> Result<(), String> {
+        let mut state = self.state.write().unwrap();
+        let node = state
+            .node_index(id)
+            .ok_or_else(|| format!("ERR Unknown node {}", id))?;
+        state.slots[slot as usize] = Some(node);
+        state.migrating.remove(&slot);
+        state.importing.remove(&slot);
+        Ok(())
+    }
+
+    /// Looks up a node other than this server by ID.
+    fn other_node(state: &State, id: &str) -> Result<usize, String> {
+        match state.node_index(id) {
+            Some(MYSELF) => Err("ERR Target node is this node".to_string()),
+            Some(node) => Ok(node),
+            None => Err(format!("ERR Unknown node {}", id)),
+        }
+    }
+
+    /// Decides whether a command touching `keys` is served here.
+    ///
+    /// `asking` is set when the client sent `ASKING` just before, and
+    /// `exists` tells whether a key is present locally, which matters while
+    /// its slot migrates.
+    pub fn route(
+        &self,
+        keys: &[&[u8]],
+        asking: bool,
+        exists: impl Fn(&[u8]) -> bool,
+    ) -> Result<(), Redirect> {
+        let Some((first, rest)) = keys.split_first() else {
+            return Ok(());
+        };
+        let slot = key_slot(first);
+        if rest.iter().any(|key| key_slot(key) != slot) {
+            return Err(Redirect::CrossSlot);
+        }
+
+        let state = self.state.read().unwrap();
+        match state.slots[slot as usize] {
+            None if !(asking && state.importing.contains_key(&slot)) => Err(Redirect::Down),
+            Some(MYSELF) => match state.migrating.get(&slot) {
+                Some(&target) => {
+                    let missing = keys.iter().filter(|key| !exists(key)).count();
+                    if missing == 0 {
+                        Ok(())
+                    } else if missing == keys.len() {
+                        Err(Redirect::Ask {
+                            slot,
+                            addr: state.nodes[target].addr(),
+                        })
+                    } else {
+                        Err(Redirect::TryAgain)
+                    }
+                }
+                None => Ok(()),
+            },
+            _ if asking && state.importing.contains_key(&slot) => Ok(()),
+            Some(owner) => Err(Redirect::Moved {
+                slot,
+                addr: state.nodes[owner].addr(),
+            }),
+            None => Err(Redirect::Down),
+        }
+    }
+
+    /// Returns the runs of slots served by each node, in slot order.
+    pub fn slot_ranges(&self) -> Vec<SlotRange> {
+        let state = self.state.read().unwrap();
+        let mut ranges: Vec<(u16, u16, usize)> = Vec::new();
+        for (slot, owner) in state.slots.iter().enumerate() {
+            let Some(owner) = *owner else { continue };
+            let slot = slot as u16;
+            match ranges.last_mut() {
+                Some((_, end, node)) if *node == owner && *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot, owner)),
+            }
+        }
+        ranges
+            .into_iter()
+            .map(|(start, end, node)| SlotRange {
+                start,
+                end,
+                node: state.nodes[node].clone(),
+            })
+            .collect()
+    }
+
+    /// Returns the slots migrating away from this server, with the ID of
+    /// the node taking each.
+    pub fn migrating(&self) -> Vec<(u16, String)> {
+        let state = self.state.read().unwrap();
+        Self::slot_nodes(&state, &state.migrating)
+    }
+
+    /// Returns the slots this server is importing, with the ID of the node
+    /// handing each over.
+    pub fn importing(&self) -> Vec<(u16, String)> {
+        let state = self.state.read().unwrap();
+        Self::slot_nodes(&state, &state.importing)
+    }
+
+    /// Lists a slot-to-node map as `(slot, node ID)` pairs in slot order.
+    fn slot_nodes(state: &State, map: &HashMap<u16, usize>) -> Vec<(u16, String)> {
+        let mut list: Vec<(u16, String)> = map
+            .iter()
+            .map(|(&slot, &node)| (slot, state.nodes[node].id.clone()))
+            .collect();
+        list.sort();
+        list
+    }
+}
+
+/// Parses slot ranges such as `"0-5460 5461 7000-8000"` into inclusive
+/// `(start, end)` pairs.
+pub fn parse_slot_ranges(spec: &str) -> Result<Vec<(u16, u16)>, String> {
+    let parse = |slot: &str| match slot.parse::<u16>() {
+        Ok(slot) if slot < SLOT_COUNT => Ok(slot),
+        _ => Err(format!("invalid slot '{}'", slot)),
+    };
+
+    spec.split_whitespace()
+        .map(|range| {
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (parse(start)?, parse(end)?),
+                None => (parse(range)?, parse(range)?),
+            };
+            if start > end {
+                return Err(format!("invalid slot range '{}'", range));
+            }
+            Ok((start, end))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster() -> (Cluster, String) {
+        let cluster = Cluster::new();
+        cluster.enable("127.0.0.1", 7000);
+        let me = cluster.myself().id;
+        let other = cluster.add_node("127.0.0.1", 7001);
+        assert!(cluster.assign(&me, &[(0, 8191)]));
+        assert!(cluster.assign(&other, &[(8192, 16383)]));
+        (cluster, other)
+    }
+
+    #[test]
+    fn test_parse_slot_ranges() {
+        assert_eq!(
+            parse_slot_ranges("0-5460 5461 7000-8000").unwrap(),
+            vec![(0, 5460), (5461, 5461), (7000, 8000)]
+        );
+        assert!(parse_slot_ranges("16384").is_err());
+        assert!(parse_slot_ranges("10-5").is_err());
+        assert!(parse_slot_ranges("a-b").is_err());
+    }
+
+    #[test]
+    fn test_route_moved_and_crossslot() {
+        let (cluster, _) = cluster();
+        let exists = |_: &[u8]| true;
+
+        // "bar" is slot 5061, "foo" slot 12182
+        assert_eq!(cluster.route(&[b"bar"], false, exists), Ok(()));
+        assert_eq!(
+            cluster.route(&[b"foo"], false, exists),
+            Err(Redirect::Moved {
+                slot: 12182,
+                addr: "127.0.0.1:7001".to_string()
+            })
+        );
+        assert_eq!(
+            cluster.route(&[b"foo", b"bar"], false, exists),
+            Err(Redirect::CrossSlot)
+        );
+        assert_eq!(
+            cluster.route(&[b"{bar}1", b"{bar}2"], false, exists),
+            Ok(())
+        );
+        assert_eq!(cluster.route(&[], false, exists), Ok(()));
+
+        assert!(cluster.del_slots(&[5061]).is_ok());
+        assert_eq!(cluster.route(&[b"bar"], false, exists), Err(Redirect::Down));
+        assert!(cluster.del_slots(&[5061]).is_err());
+        assert!(cluster.add_slots(&[5061]).is_ok());
+        assert!(cluster.add_slots(&[5061]).is_err());
+    }
+
+    #[test]
+    fn test_route_during_migration() {
+        let (cluster, other) = cluster();
+
+        // Migrating away: missing keys are asked for on the target
+        cluster.set_migrating(5061, &other).unwrap();
+        assert_eq!(cluster.route(&[b"bar"], false, |_| true), Ok(()));
+        assert_eq!(
+            cluster.route(&[b"bar"], false, |_| false),
+            Err(Redirect::Ask {
+                slot: 5061,
+                addr: "127.0.0.1:7001".to_string()
+            })
+        );
+        assert_eq!(
+            cluster.route(&[b"{bar}1", b"{bar}2"], false, |key| key == b"{bar}1"),
+            Err(Redirect::TryAgain)
+        );
+        assert_eq!(cluster.migrating(), vec![(5061, other.clone())]);
+        assert!(cluster.importing().is_empty());
+
+        // Importing: only served after ASKING
+        cluster.set_importing(12182, &other).unwrap();
+        assert!(matches!(
+            cluster.route(&[b"foo"], false, |_| true),
+            Err(Redirect::Moved { .. })
+        ));
+        assert_eq!(cluster.route(&[b"foo"], true, |_| true), Ok(()));
+
+        // The migration ends with the slot assigned to its new owner
+        cluster.set_node(12182, &cluster.myself().id).unwrap();
+        assert_eq!(cluster.route(&[b"foo"], false, |_| true), Ok(()));
+        assert!(cluster.set_migrating(12182, &cluster.myself().id).is_err());
+    }
+
+    #[test]
+    fn test_slot_ranges() {
+        let (cluster, other) = cluster();
+        cluster.set_node(100, &other).unwrap();
+
+        let ranges: Vec<(u16, u16, u16)> = cluster
+            .slot_ranges()
+            .into_iter()
+            .map(|range| (range.start, range.end, range.node.port))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![
+                (0, 99, 7000),
+                (100, 100, 7001),
+                (101, 8191, 7000),
+                (8192, 16383, 7001)
+            ]
+        );
+    }
+}
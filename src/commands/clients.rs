@@ -0,0 +1,344 @@
+//! Client Registry
+//!
+//! Every connected client's [`Session`] is registered here for as long as
+//! it is connected, so `CLIENT LIST` can show who is connected and
+//! `CLIENT KILL` can disconnect them. Like client IDs, the registry belongs
+//! to the process rather than to a storage engine, and so does the
+//! `CLIENT PAUSE` state.
+//!
+//! Sessions of handlers that don't serve a network client (such as the
+//! one applying a master's stream on a replica) are never registered.
+//!
+//! ## Pausing
+//!
+//! `CLIENT PAUSE` only records until when, and which commands, are held
+//! back: the connection layer asks [`paused_until`] before running each
+//! command and waits out the pause (or until [`unpause`]) first.
+//!
+//! ## Monitoring
+//!
+//! A client that runs `MONITOR` is added to the monitors, and from then on
+//! every command any client runs is sent to it as a line like
+//!
+//! ```text
+//! +1700000000.123456 [0 127.0.0.1:52114] "SET" "key" "a \"quoted\" value"
+//! ```
+//!
+//! with the time it ran, the caller's database and address, and its
+//! arguments quoted. The lines go through the monitor's message queue, the
+//! same way pub/sub messages reach a subscriber, so its connection writes
+//! them as they come without the monitor sending anything. The passwords
+//! given to `HELLO ... AUTH` are redacted.
+//!
+//! While nobody monitors, [`feed_monitors`] costs one atomic load.
+
+use super::Session;
+use crate::protocol::RespValue;
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::futures::Notified;
+use tokio::sync::{mpsc, Notify};
+
+/// Sessions of the connected clients, by ID.
+static CLIENTS: Mutex<BTreeMap<u64, Arc<Session>>> = Mutex::new(BTreeMap::new());
+
+/// The current `CLIENT PAUSE`, if any: what it holds back and until when.
+static PAUSE: Mutex<Option<(PauseMode, Instant)>> = Mutex::new(None);
+
+/// Wakes the clients waiting out a pause when it is lifted.
+static UNPAUSED: Notify = Notify::const_new();
+
+/// Message queues of the clients that ran MONITOR, by ID.
+static MONITORS: Mutex<BTreeMap<u64, mpsc::UnboundedSender<Bytes>>> = Mutex::new(BTreeMap::new());
+
+/// Whether any client is monitoring. Only changed with [`MONITORS`] locked.
+static MONITORING: AtomicBool = AtomicBool::new(false);
+
+/// Which commands `CLIENT PAUSE` holds back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PauseMode {
+    /// Commands that may write (`CLIENT PAUSE ... WRITE`)
+    Write,
+    /// Every command (`CLIENT PAUSE ...` or `... ALL`)
+    All,
+}
+
+/// Which clients `CLIENT KILL` disconnects; every filter given must match.
+#[derive(Debug, Default)]
+pub struct KillFilter {
+    /// Client ID
+    pub id: Option<u64>,
+    /// Address the client connected from
+    pub addr: Option<SocketAddr>,
+    /// Local address the client connected to
+    pub laddr: Option<SocketAddr>,
+    /// User the client is authenticated as
+    pub user: Option<Vec<u8>>,
+    /// ID of a client to leave alone (the one sending `CLIENT KILL`
+    /// unless it asks for `SKIPME no`)
+    pub skip: Option<u64>,
+}
+
+impl KillFilter {
+    /// Returns whether the filter selects `session`.
+    fn matches(&self, session: &Session) -> bool {
+        self.id.is_none_or(|id| id == session.id())
+            && self.addr.is_none_or(|addr| Some(addr) == session.addr())
+            && self
+                .laddr
+                .is_none_or(|laddr| Some(laddr) == session.laddr())
+            && self
+                .user
+                .as_ref()
+                .is_none_or(|user| user[..] == session.user()[..])
+            && self.skip != Some(session.id())
+    }
+}
+
+/// Registers the session of a client that just connected.
+pub fn register(session: Arc<Session>) {
+    CLIENTS.lock().unwrap().insert(session.id(), session);
+}
+
+/// Removes the session of a client that disconnected, and stops it
+/// monitoring; a no-op if it was never registered.
+pub fn unregister(id: u64) {
+    CLIENTS.lock().unwrap().remove(&id);
+    let mut monitors = MONITORS.lock().unwrap();
+    if monitors.remove(&id).is_some() {
+        MONITORING.store(!monitors.is_empty(), Ordering::Relaxed);
+    }
+}
+
+/// Returns the session of the connected client `id`.
+pub fn get(id: u64) -> Option<Arc<Session>> {
+    CLIENTS.lock().unwrap().get(&id).cloned()
+}
+
+/// Returns the sessions of every connected client, in ID order.
+pub fn list() -> Vec<Arc<Session>> {
+    CLIENTS.lock().unwrap().values().cloned().collect()
+}
+
+/// Disconnects the clients `filter` selects (CLIENT KILL). Each one is
+/// sent the reply to the command it is running, if any, and disconnected.
+///
+/// # Returns
+/// The number of clients killed.
+pub fn kill(filter: &KillFilter) -> usize {
+    let clients = CLIENTS.lock().unwrap();
+    let mut killed = 0;
+    for session in clients.values().filter(|s| filter.matches(s)) {
+        session.kill();
+        killed += 1;
+    }
+    killed
+}
+
+/// Holds back the commands of `mode` until `until` (CLIENT PAUSE),
+/// replacing any pause in effect.
+pub fn pause(mode: PauseMode, until: Instant) {
+    *PAUSE.lock().unwrap() = Some((mode, until));
+}
+
+/// Lifts the pause, letting waiting clients go on (CLIENT UNPAUSE).
+pub fn unpause() {
+    *PAUSE.lock().unwrap() = None;
+    UNPAUSED.notify_waiters();
+}
+
+/// Returns until when a command must wait before running, or `None` if it
+/// can run now. `write` is whether it may write.
+pub fn paused_until(write: bool) -> Option<Instant> {
+    let mut pause = PAUSE.lock().unwrap();
+    match *pause {
+        Some((_, until)) if until <= Instant::now() => {
+            *pause = None;
+            None
+        }
+        Some((mode, until)) if write || mode == PauseMode::All => Some(until),
+        _ => None,
+    }
+}
+
+/// Sends `session` every command run from now on, on its message queue
+/// (MONITOR).
+pub fn monitor(session: &Session) {
+    let mut monitors = MONITORS.lock().unwrap();
+    monitors.insert(session.id(), session.subscriber().sender());
+    MONITORING.store(true, Ordering::Relaxed);
+}
+
+/// Sends the command `session` is running to every monitor. `cmd` is its
+/// name as in the command table and `args` the command as sent, name
+/// included.
+pub fn feed_monitors(session: &Session, cmd: &str, args: &[RespValue]) {
+    if !MONITORING.load(Ordering::Relaxed) {
+        return;
+    }
+    let line = Bytes::from(monitor_line(session, cmd, args, SystemTime::now()));
+    let mut monitors = MONITORS.lock().unwrap();
+    // A monitor whose queue is gone disconnected
+    monitors.retain(|_, sender| sender.send(line.clone()).is_ok());
+    MONITORING.store(!monitors.is_empty(), Ordering::Relaxed);
+}
+
+/// Formats the line monitors get for a command `session` ran at `at`.
+fn monitor_line(session: &Session, cmd: &str, args: &[RespValue], at: SystemTime) -> String {
+    let time = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let addr = session
+        .addr()
+        .map_or_else(|| "?:0".to_string(), |addr| addr.to_string());
+    let mut line = format!(
+        "+{}.{:06} [{} {}]",
+        time.as_secs(),
+        time.subsec_micros(),
+        session.db(),
+        addr
+    );
+
+    // HELLO ... AUTH username password
+    let redacted = match cmd {
+        "HELLO" => args
+            .iter()
+            .position(|arg| {
+                arg.as_bytes()
+                    .is_some_and(|a| a.eq_ignore_ascii_case(b"AUTH"))
+            })
+            .map_or(0..0, |at| at + 1..at + 3),
+        _ => 0..0,
+    };
+    for (i, arg) in args.iter().enumerate() {
+        line.push(' ');
+        if redacted.contains(&i) {
+            line.push_str("\"(redacted)\"");
+        } else {
+            push_quoted(&mut line, arg.as_bytes().unwrap_or_default());
+        }
+    }
+    line.push_str("\r\n");
+    line
+}
+
+/// Appends `arg` to `line` in double quotes, escaping quotes, backslashes
+/// and unprintable bytes, so the line stays one line whatever the argument.
+fn push_quoted(line: &mut String, arg: &[u8]) {
+    line.push('"');
+    for &byte in arg {
+        match byte {
+            b'\\' => line.push_str("\\\\"),
+            b'"' => line.push_str("\\\""),
+            b'\n' => line.push_str("\\n"),
+            b'\r' => line.push_str("\\r"),
+            b'\t' => line.push_str("\\t"),
+            0x07 => line.push_str("\\a"),
+            0x08 => line.push_str("\\b"),
+            b' '..=b'~' => line.push(byte as char),
+            _ => line.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    line.push('"');
+}
+
+/// Returns a future completing when the pause is lifted with [`unpause`];
+/// a pause ending on its own wakes nobody. Enable it before checking
+/// [`paused_until`] so an `unpause` in between isn't missed.
+pub fn unpaused() -> Notified<'static> {
+    UNPAUSED.notified()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register() {
+        let session = Arc::new(Session::new());
+        let id = session.id();
+        assert!(get(id).is_none());
+
+        register(Arc::clone(&session));
+        assert!(Arc::ptr_eq(&get(id).unwrap(), &session));
+        assert!(list().iter().any(|s| s.id() == id));
+
+        unregister(id);
+        assert!(get(id).is_none());
+        assert!(!list().iter().any(|s| s.id() == id));
+    }
+
+    #[test]
+    fn test_monitor_line() {
+        let session = Session::new();
+        let at = UNIX_EPOCH + std::time::Duration::from_micros(1_700_000_000_000_042);
+        let args = |args: &[&[u8]]| -> Vec<RespValue> {
+            args.iter()
+                .map(|arg| RespValue::bulk_string(Bytes::copy_from_slice(arg)))
+                .collect()
+        };
+
+        let line = monitor_line(
+            &session,
+            "SET",
+            &args(&[b"SET", b"k", b"a \"b\"\\\n\x01"]),
+            at,
+        );
+        assert_eq!(
+            line,
+            concat!(
+                r#"+1700000000.000042 [0 ?:0] "SET" "k" "a \"b\"\\\n\x01""#,
+                "\r\n"
+            )
+        );
+
+        session.set_addr(
+            "127.0.0.1:5000".parse().unwrap(),
+            "127.0.0.1:6379".parse().unwrap(),
+        );
+        session.select(3);
+        let line = monitor_line(
+            &session,
+            "HELLO",
+            &args(&[b"hello", b"3", b"auth", b"u", b"p"]),
+            at,
+        );
+        assert_eq!(
+            line,
+            concat!(
+                r#"+1700000000.000042 [3 127.0.0.1:5000] "hello" "3" "auth" "(redacted)" "(redacted)""#,
+                "\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_kill_filter() {
+        let session = Session::new();
+        session.set_addr(
+            "127.0.0.1:5000".parse().unwrap(),
+            "127.0.0.1:6379".parse().unwrap(),
+        );
+
+        let filter = KillFilter {
+            addr: Some("127.0.0.1:5000".parse().unwrap()),
+            laddr: Some("127.0.0.1:6379".parse().unwrap()),
+            user: Some(b"default".to_vec()),
+            ..Default::default()
+        };
+        assert!(filter.matches(&session));
+        let filter = KillFilter {
+            id: Some(session.id()),
+            skip: Some(session.id()),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&session));
+        let filter = KillFilter {
+            user: Some(b"other".to_vec()),
+            ..Default::default()
+        };
+        assert!(!filter.matches(&session));
+    }
+}
@@ -6,7 +6,7 @@
 //! ## Supported Commands
 //!
 //! ### String Commands
-//! - `SET key value [EX seconds | PX milliseconds]` - Set a key
+//! - `SET key value [EX seconds | PX milliseconds | EXAT timestamp | PXAT ms-timestamp | KEEPTTL] [NX | XX] [GET]` - Set a key
 //! - `GET key` - Get a key's value
 //! - `DEL key [key ...]` - Delete keys
 //! - `EXISTS key [key ...]` - Check if keys exist
@@ -14,14 +14,27 @@
 //! - `STRLEN key` - Get string length
 //! - `INCR key` - Increment integer
 //! - `INCRBY key increment` - Increment by amount
+//! - `INCRBYFLOAT key increment` - Increment by a float amount
 //! - `DECR key` - Decrement integer
 //! - `DECRBY key decrement` - Decrement by amount
 //! - `MSET key value [key value ...]` - Set multiple keys
 //! - `MGET key [key ...]` - Get multiple keys
+//! - `MSETNX key value [key value ...]` - Set multiple keys if none exist
 //! - `SETNX key value` - Set if not exists
 //! - `SETEX key seconds value` - Set with expiry
 //! - `GETSET key value` - Set and return old value
 //!
+//! ### Bitmap Commands
+//! - `SETBIT key offset value` - Set or clear a bit
+//! - `GETBIT key offset` - Get a bit
+//! - `BITCOUNT key [start end [BYTE|BIT]]` - Count set bits
+//! - `BITPOS key bit [start [end [BYTE|BIT]]]` - Find the first set or clear bit
+//!
+//! ### HyperLogLog Commands
+//! - `PFADD key [element ...]` - Add elements to a HyperLogLog
+//! - `PFCOUNT key [key ...]` - Estimate the number of distinct elements
+//! - `PFMERGE destkey [sourcekey ...]` - Merge HyperLogLogs
+//!
 //! ### List Commands
 //! - `LPUSH key value [value ...]` - Push values to the head of a list
 //! - `RPUSH key value [value ...]` - Push values to the tail of a list
@@ -32,27 +45,148 @@
 //! - `LRANGE key start stop` - Get a range of elements
 //! - `LSET key index value` - Set element at index
 //! - `LREM key count value` - Remove elements equal to value
+//! - `LTRIM key start stop` - Trim a list to a range of elements
+//! - `LMOVE source destination LEFT|RIGHT LEFT|RIGHT` - Atomically move an element between lists
+//! - `RPOPLPUSH source destination` - Move the tail of one list to the head of another
+//! - `BLPOP key [key ...] timeout` / `BRPOP key [key ...] timeout` - Blocking pops
+//!
+//! ### Hash Commands
+//! - `HSET key field value [field value ...]` - Set hash fields
+//! - `HGET key field` - Get a hash field
+//! - `HDEL key field [field ...]` - Delete hash fields
+//! - `HLEN key` - Get the number of fields in a hash
+//! - `HEXISTS key field` - Check if a hash field exists
+//! - `HGETALL key` - Get all fields and values
+//! - `HINCRBY key field increment` - Increment a field by an integer
+//! - `HINCRBYFLOAT key field increment` - Increment a field by a float
+//! - `HSETNX key field value` - Set a field if it doesn't exist
+//! - `HRANDFIELD key [count [WITHVALUES]]` - Get random fields
+//!
+//! ### Set Commands
+//! - `SADD key member [member ...]` - Add members to a set
+//! - `SREM key member [member ...]` - Remove members from a set
+//! - `SMEMBERS key` - Get all members of a set
+//! - `SISMEMBER key member` - Check if a value is a member
+//! - `SMISMEMBER key member [member ...]` - Check membership of multiple values
+//! - `SCARD key` - Get the number of members in a set
+//! - `SINTER key [key ...]` / `SINTERSTORE destination key [key ...]` - Intersection
+//! - `SUNION key [key ...]` / `SUNIONSTORE destination key [key ...]` - Union
+//! - `SDIFF key [key ...]` / `SDIFFSTORE destination key [key ...]` - Difference
+//! - `SINTERCARD numkeys key [key ...] [LIMIT limit]` - Intersection cardinality
+//!
+//! ### Sorted Set Commands
+//! - `ZADD key [NX|XX] [GT|LT] [CH] [INCR] score member [score member ...]` - Add members
+//! - `ZSCORE key member` - Get the score of a member
+//! - `ZREM key member [member ...]` - Remove members
+//! - `ZCARD key` - Get the number of members
+//! - `ZRANGE key start stop [REV] [WITHSCORES]` - Get members by rank
+//! - `ZREVRANGE key start stop [WITHSCORES]` - Get members by rank, highest first
+//! - `ZRANK key member` / `ZREVRANK key member` - Get the rank of a member
+//! - `ZINCRBY key increment member` - Increment the score of a member
+//! - `ZPOPMIN key [count]` / `ZPOPMAX key [count]` - Remove lowest/highest members
+//! - `ZRANDMEMBER key [count [WITHSCORES]]` - Get random members
+//! - `BZPOPMIN key [key ...] timeout` / `BZPOPMAX key [key ...] timeout` - Blocking pops
+//! - `ZUNIONSTORE dest numkeys key [key ...] [WEIGHTS w ...] [AGGREGATE SUM|MIN|MAX]` - Store union
+//! - `ZINTERSTORE dest numkeys key [key ...] [WEIGHTS w ...] [AGGREGATE SUM|MIN|MAX]` - Store intersection
+//! - `ZDIFFSTORE dest numkeys key [key ...]` - Store difference
+//!
+//! ### Stream Commands
+//! - `XADD key [NOMKSTREAM] [MAXLEN [=|~] n] <*|id> field value [...]` - Append an entry
+//! - `XLEN key` - Get the number of entries
+//! - `XRANGE key start end [COUNT n]` - Get entries in an ID range
+//! - `XREVRANGE key end start [COUNT n]` - Get entries in an ID range, newest first
+//! - `XREAD [COUNT n] STREAMS key [key ...] id [id ...]` - Read new entries
+//! - `XGROUP CREATE|DESTROY|CREATECONSUMER|DELCONSUMER key group ...` - Manage consumer groups
+//! - `XREADGROUP GROUP group consumer [COUNT n] [NOACK] STREAMS key [...] id [...]` - Read as a group consumer
+//! - `XACK key group id [id ...]` - Acknowledge entries
+//! - `XPENDING key group [[IDLE ms] start end count [consumer]]` - Inspect pending entries
+//! - `XCLAIM key group consumer min-idle-time id [id ...] [JUSTID]` - Take over pending entries
 //!
 //! ### Key Commands
-//! - `EXPIRE key seconds` - Set expiry
-//! - `PEXPIRE key milliseconds` - Set expiry in ms
+//! - `EXPIRE key seconds [NX|XX|GT|LT]` - Set expiry
+//! - `PEXPIRE key milliseconds [NX|XX|GT|LT]` - Set expiry in ms
+//! - `EXPIREAT key timestamp [NX|XX|GT|LT]` - Set expiry at a Unix time in seconds
+//! - `PEXPIREAT key timestamp [NX|XX|GT|LT]` - Set expiry at a Unix time in ms
+//! - `EXPIRETIME key` - Get the Unix time in seconds at which a key expires
+//! - `PEXPIRETIME key` - Get the Unix time in ms at which a key expires
 //! - `TTL key` - Get remaining TTL
 //! - `PTTL key` - Get remaining TTL in ms
 //! - `PERSIST key` - Remove expiry
 //! - `KEYS pattern` - Find keys by pattern
-//! - `TYPE key` - Get key type ("string", "list", or "none")
+//! - `TOUCH key [key ...]` - Update the last access time of keys
+//! - `UNLINK key [key ...]` - Delete keys, freeing large values in the background
+//! - `SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]` - Iterate keys incrementally
+//! - `TYPE key` - Get key type ("string", "list", "hash", "set", "zset", "stream", or "none")
 //! - `RENAME key newkey` - Rename a key
 //! - `RENAMENX key newkey` - Rename if new key doesn't exist
+//! - `COPY source destination [DB destination-db] [REPLACE]` - Copy a key's value and TTL
+//! - `MOVE key db` - Move a key, with its TTL, to another database
+//! - `SORT key [BY pattern] [LIMIT offset count] [GET pattern ...] [ASC|DESC] [ALPHA] [STORE destination]` - Sort a list, set or sorted set
+//! - `OBJECT ENCODING|IDLETIME|FREQ|REFCOUNT key` - Inspect a key's internals
+//! - `MEMORY USAGE key [SAMPLES count]` - Get the bytes a key and its value take
 //!
 //! ### Server Commands
 //! - `PING [message]` - Test connection
 //! - `ECHO message` - Echo message
-//! - `INFO [section]` - Server information
-//! - `DBSIZE` - Number of keys
-//! - `FLUSHDB` - Clear database
-//! - `COMMAND` - List commands
-//! - `CONFIG GET parameter` - Get config
+//! - `INFO [section ...]` - Server information, by section
+//! - `DBSIZE` - Number of keys in the selected database
+//! - `FLUSHDB [ASYNC|SYNC]` - Clear the selected database
+//! - `FLUSHALL [ASYNC|SYNC]` - Clear every database
+//! - `COMMAND [COUNT]` - List or count commands
+//! - `CONFIG GET parameter` / `CONFIG SET parameter value` - Get or set config (`notify-keyspace-events`, `maxmemory`, `maxmemory-policy`, `lfu-log-factor`, `lfu-decay-time`, `busy-reply-threshold`, `databases`, `compact-keys`)
+//! - `MEMORY STATS` - Memory use of the keyspace, and what compact keys save
 //! - `TIME` - Server time
+//! - `SAVE` - Write a snapshot to disk
+//! - `BGSAVE` - Write a snapshot to disk in the background
+//! - `LASTSAVE` - Unix time of the last successful snapshot
+//! - `EXPORT file [FORMAT JSON|CSV] [MATCH pattern]` - Write the keyspace as JSON lines or CSV into the data directory
+//! - `BGREWRITEAOF` - Compact the append-only file in the background
+//! - `MONITOR` - Stream every command run by any client to this connection
+//!
+//! ### Replication Commands
+//! - `REPLICAOF host port | NO ONE` - Replicate a master, or stop replicating (alias `SLAVEOF`)
+//! - `REPLCONF listening-port port | capa capability | ACK offset` - Configure a replica's connection
+//! - `SYNC` - Attach as a replica: receive a snapshot, then every write
+//! - `PSYNC replid offset` - Resume the stream from the backlog, or fall back to `SYNC`
+//!
+//! ### Cluster Commands
+//! - `CLUSTER KEYSLOT key` - Get the hash slot of a key
+//! - `CLUSTER SLOTS` / `CLUSTER SHARDS` / `CLUSTER NODES` - Get the slot layout
+//! - `CLUSTER INFO` / `CLUSTER MYID` - Get the cluster state and this node's ID
+//! - `CLUSTER ADDSLOTS slot [slot ...]` / `CLUSTER DELSLOTS slot [slot ...]` - Assign slots to this node
+//! - `CLUSTER SETSLOT slot MIGRATING|IMPORTING|NODE node-id | STABLE` - Move a slot between nodes
+//! - `ASKING` - Let the next command use a slot being imported
+//!
+//! ### Connection Commands
+//! - `HELLO [protover [AUTH user pass] [SETNAME name]]` - Switch to RESP2 or RESP3, authenticate and name the connection, and describe the server
+//! - `SELECT index` - Switch to another database
+//! - `CLIENT ID` - Get the client's ID
+//! - `CLIENT LIST [ID id ...]` / `CLIENT INFO` - Describe the connected clients, or this one
+//! - `CLIENT SETINFO LIB-NAME|LIB-VER value` - Record the client library's name or version
+//! - `CLIENT KILL [ID id] [ADDR addr] [LADDR addr] [USER user] [SKIPME yes|no]` - Disconnect clients
+//! - `CLIENT PAUSE timeout [WRITE|ALL]` / `CLIENT UNPAUSE` - Hold back clients' commands for a while
+//! - `CLIENT NO-TOUCH ON|OFF` - Leave the idle time and access frequency of keys read alone
+//! - `CLIENT NO-EVICT ON|OFF` - Accepted for compatibility (FlashKV never evicts clients)
+//! - `CLIENT SETNAME name` / `CLIENT GETNAME` - Name the connection, or get its name
+//! - `CLIENT TRACKING ON|OFF [REDIRECT id] [NOLOOP]` - Get told when keys the client read change
+//! - `CLIENT GETREDIR` - Get where invalidations go (-1 when tracking is off)
+//!
+//! ### Scripting Commands
+//! - `FUNCTION LOAD [REPLACE] code` - Load a Lua library (`#!lua name=library` first), which registers functions
+//! - `FUNCTION LIST [LIBRARYNAME pattern] [WITHCODE]` - Describe the loaded libraries and their functions
+//! - `FUNCTION DELETE library` / `FUNCTION FLUSH [ASYNC|SYNC]` - Delete a library, or all of them
+//! - `FUNCTION DUMP` / `FUNCTION RESTORE payload [FLUSH|APPEND|REPLACE]` - Serialize the libraries, or load them back
+//! - `FCALL function numkeys [key ...] [arg ...]` - Call a function
+//! - `FCALL_RO function numkeys [key ...] [arg ...]` - Call a function registered with the `no-writes` flag
+//! - `FUNCTION KILL` - Stop the function running
+//!
+//! ### Pub/Sub Commands
+//! - `SUBSCRIBE channel [channel ...]` / `UNSUBSCRIBE [channel ...]` - Listen to channels
+//! - `PSUBSCRIBE pattern [pattern ...]` / `PUNSUBSCRIBE [pattern ...]` - Listen to channels matching patterns
+//! - `PUBLISH channel message` - Send a message to a channel's subscribers
+//! - `PUBSUB CHANNELS [pattern]` - List channels with subscribers
+//! - `PUBSUB NUMSUB [channel ...]` - Count the subscribers of channels
+//! - `PUBSUB NUMPAT` - Count pattern subscriptions
 //!
 //! ## Architecture
 //!
@@ -69,19 +203,122 @@
 //! └─────────────────────────────────────────────────────────────┘
 //! ```
 
-use crate::protocol::RespValue;
-use crate::storage::StorageEngine;
+use super::clients::{self, KillFilter, PauseMode};
+use super::rename::CommandRenames;
+use super::session::Session;
+use crate::cluster::{command_keys, key_slot, SLOT_COUNT};
+use crate::connection::ConnectionStats;
+use crate::protocol::{BulkArrayBuilder, ProtocolLimits, RespValue};
+use crate::pubsub::{EventClass, NotifyFlags, Target};
+use crate::replication::MasterAddr;
+use crate::scripting::{self, FunctionError, RestorePolicy};
+use crate::storage::allocator::{self, AllocatorStats};
+use crate::storage::bitmap::MAX_BIT_OFFSET;
+use crate::storage::eviction::{self, EvictionPolicy};
+use crate::storage::glob::GlobPattern;
+use crate::storage::stream::{PendingQuery, StreamFields};
+use crate::storage::zset::format_score;
+use crate::storage::{
+    without_touching, Aggregate, BitRange, BitUnit, ExpireFlags, ExportFormat, ListEnd, MemoryInfo,
+    SetOp, SortOptions, StorageEngine, StreamId, StreamRecord, XAddId, ZAddFlags, WRONGTYPE,
+};
 use bytes::Bytes;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// The result of [`CommandHandler::execute_or_block`].
+#[derive(Debug)]
+pub enum CommandOutcome {
+    /// The command completed; send this response
+    Reply(RespValue),
+    /// The command must wait until one of its keys is written
+    Block(BlockingRequest),
+    /// The client asked to become a replica; the connection streams the
+    /// keyspace and writes to it
+    Sync(SyncRequest),
+    /// The command completed with several responses, sent in order (one
+    /// per channel for the subscribe commands)
+    Replies(Vec<RespValue>),
+}
+
+/// How a replica asked to synchronize.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncRequest {
+    /// `SYNC`: always a full resynchronization, sent without a reply line
+    Sync,
+    /// `PSYNC replid offset`: resume at `offset` if the backlog allows
+    /// (`None` for `-1`, which asks for a full resynchronization)
+    Psync { replid: String, offset: Option<u64> },
+}
+
+/// The operation a blocked client performs once data arrives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockingOp {
+    /// BLPOP
+    LPop,
+    /// BRPOP
+    RPop,
+    /// BZPOPMIN
+    ZPopMin,
+    /// BZPOPMAX
+    ZPopMax,
+}
+
+impl BlockingOp {
+    /// Returns the non-blocking command equivalent to a served pop, as
+    /// written to the append-only file.
+    pub fn pop_command(self) -> &'static str {
+        match self {
+            BlockingOp::LPop => "LPOP",
+            BlockingOp::RPop => "RPOP",
+            BlockingOp::ZPopMin => "ZPOPMIN",
+            BlockingOp::ZPopMax => "ZPOPMAX",
+        }
+    }
+
+    /// Returns the reply sent when the command times out without data.
+    pub fn timeout_reply(self) -> RespValue {
+        match self {
+            BlockingOp::LPop | BlockingOp::RPop => RespValue::null_array(),
+            BlockingOp::ZPopMin | BlockingOp::ZPopMax => RespValue::null(),
+        }
+    }
+}
+
+/// A parsed blocking command waiting for data.
+#[derive(Debug, Clone)]
+pub struct BlockingRequest {
+    /// Keys to wait on, served in the order given
+    pub keys: Vec<Bytes>,
+    /// How long to wait before replying nil (`None` waits forever)
+    pub timeout: Option<Duration>,
+    /// What to do once a key has data
+    pub op: BlockingOp,
+}
 
 /// Handles Redis commands by dispatching them to the appropriate handlers.
 #[derive(Clone)]
 pub struct CommandHandler {
     /// The storage engine
     storage: Arc<StorageEngine>,
+    /// The client's session state
+    session: Arc<Session>,
     /// Server start time for INFO command
     start_time: std::time::Instant,
+    /// Whether commands come from this server's master, and may write
+    /// even though a replica is read-only
+    from_master: bool,
+    /// Commands the client knows under another name, or not at all
+    renames: Arc<CommandRenames>,
+    /// What the client's requests may contain
+    protocol_limits: ProtocolLimits,
+    /// Counters of the server's connections, for INFO
+    connection_stats: Option<Arc<ConnectionStats>>,
 }
 
 impl CommandHandler {
@@ -89,7 +326,45 @@ impl CommandHandler {
     pub fn new(storage: Arc<StorageEngine>) -> Self {
         Self {
             storage,
+            session: Arc::new(Session::new()),
             start_time: std::time::Instant::now(),
+            from_master: false,
+            renames: Arc::default(),
+            protocol_limits: ProtocolLimits::default(),
+            connection_stats: None,
+        }
+    }
+
+    /// Makes the client call commands by the names in `renames`, as set
+    /// with `--rename-command` and `--disabled-commands`.
+    pub fn with_renames(self, renames: Arc<CommandRenames>) -> Self {
+        Self { renames, ..self }
+    }
+
+    /// Makes the client's connection reject requests breaking `limits`, as
+    /// set with the `--proto-max-*` options.
+    pub fn with_protocol_limits(self, protocol_limits: ProtocolLimits) -> Self {
+        Self {
+            protocol_limits,
+            ..self
+        }
+    }
+
+    /// Makes INFO report the clients, commands and traffic counted in
+    /// `stats`.
+    pub fn with_connection_stats(self, stats: Arc<ConnectionStats>) -> Self {
+        Self {
+            connection_stats: Some(stats),
+            ..self
+        }
+    }
+
+    /// Creates a handler for the commands this replica's master streams to
+    /// it, which write to the keyspace even though clients can't.
+    pub fn for_master(storage: Arc<StorageEngine>) -> Self {
+        Self {
+            from_master: true,
+            ..Self::new(storage)
         }
     }
 
@@ -103,34 +378,685 @@ impl CommandHandler {
     ///
     /// The RESP response to send back to the client.
     pub fn execute(&self, command: RespValue) -> RespValue {
+        let (cmd_name, args) = match self.split_command(command) {
+            Ok(parts) => parts,
+            Err(e) => return e,
+        };
+        self.session.record_command(cmd_name);
+        clients::feed_monitors(&self.session, cmd_name, &args);
+
+        // Dispatch to appropriate handler
+        self.dispatch(cmd_name, &args[1..])
+    }
+
+    /// Executes a command, or asks the caller to block if it is a blocking
+    /// command (such as `BLPOP` or `BZPOPMIN`) with nothing to serve yet.
+    ///
+    /// [`execute`](Self::execute) never blocks: blocking commands that find
+    /// no data reply with nil straight away. The connection layer uses this
+    /// method instead so it can park the client until a key is written.
+    pub fn execute_or_block(&self, command: RespValue) -> CommandOutcome {
+        let (cmd_name, args) = match self.split_command(command) {
+            Ok(parts) => parts,
+            Err(e) => return CommandOutcome::Reply(e),
+        };
+        self.session.record_command(cmd_name);
+
+        if self.session.subscribed() {
+            match cmd_name {
+                "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "QUIT" => {}
+                "PING" => return CommandOutcome::Reply(self.subscribed_ping(&args[1..])),
+                _ => {
+                    return CommandOutcome::Reply(RespValue::error(format!(
+                        "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context",
+                        cmd_name.to_lowercase()
+                    )))
+                }
+            }
+        }
+
+        clients::feed_monitors(&self.session, cmd_name, &args);
+
+        let op = match cmd_name {
+            "SYNC" | "PSYNC" => return self.sync(cmd_name, &args[1..]),
+            "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" => {
+                return self.subscribe(cmd_name, &args[1..])
+            }
+            "BLPOP" => BlockingOp::LPop,
+            "BRPOP" => BlockingOp::RPop,
+            "BZPOPMIN" => BlockingOp::ZPopMin,
+            "BZPOPMAX" => BlockingOp::ZPopMax,
+            _ => return CommandOutcome::Reply(self.dispatch(cmd_name, &args[1..])),
+        };
+
+        if let Err(e) = self.check_cluster(cmd_name, &args[1..]) {
+            return CommandOutcome::Reply(e);
+        }
+        if let Err(e) = self.check_writable(cmd_name) {
+            return CommandOutcome::Reply(e);
+        }
+        let request = match self.parse_blocking(cmd_name, &args[1..], op) {
+            Ok(request) => request,
+            Err(e) => return CommandOutcome::Reply(e),
+        };
+
+        match self.try_serve(&request) {
+            Some(response) => CommandOutcome::Reply(response),
+            None => CommandOutcome::Block(request),
+        }
+    }
+
+    /// Retries a blocked command.
+    ///
+    /// # Returns
+    ///
+    /// The response if one of the keys now has data (or holds the wrong
+    /// type), or `None` if the client should keep waiting.
+    pub fn try_serve(&self, request: &BlockingRequest) -> Option<RespValue> {
+        let (response, served) = self.run_write(
+            || self.serve(request),
+            |result| match result {
+                Some((_, Some(key))) => {
+                    let pop = Bytes::from_static(request.op.pop_command().as_bytes());
+                    vec![vec![pop, key.clone()]]
+                }
+                _ => Vec::new(),
+            },
+        )?;
+        if let Some(key) = served {
+            self.db().snapshots().record_changes(1);
+            let (class, event) = match request.op {
+                BlockingOp::LPop => (EventClass::List, "lpop"),
+                BlockingOp::RPop => (EventClass::List, "rpop"),
+                BlockingOp::ZPopMin => (EventClass::ZSet, "zpopmin"),
+                BlockingOp::ZPopMax => (EventClass::ZSet, "zpopmax"),
+            };
+            let pubsub = self.db().pubsub();
+            pubsub.notify(self.session.db(), class, event, &key);
+            pubsub.invalidate(&[&key], Some(self.session.id()));
+        }
+        Some(response)
+    }
+
+    /// Returns the log position the last command's write must reach on disk
+    /// before its reply is sent, if the append-only file commits in groups.
+    ///
+    /// The connection layer waits for it with
+    /// [`Aof::wait_durable`](crate::storage::Aof::wait_durable).
+    pub fn take_commit_position(&self) -> Option<u64> {
+        self.session.take_commit_position()
+    }
+
+    /// Takes the queue of messages published to the client's channels,
+    /// serialized as RESP; `None` if already taken.
+    ///
+    /// The connection layer drains it while waiting for commands.
+    pub fn take_messages(&self) -> Option<mpsc::UnboundedReceiver<Bytes>> {
+        self.session.subscriber().take_receiver()
+    }
+
+    /// Registers the handler as serving the network client connected from
+    /// `addr` to the local address `laddr`, listing it in `CLIENT LIST`
+    /// until [`close`](Self::close).
+    pub fn connect(&self, addr: SocketAddr, laddr: SocketAddr) {
+        self.session.set_addr(addr, laddr);
+        clients::register(Arc::clone(&self.session));
+    }
+
+    /// Releases what the client holds on the server, its subscriptions
+    /// and tracked keys, as when it disconnects.
+    pub fn close(&self) {
+        let pubsub = self.db().pubsub();
+        pubsub.unsubscribe_all(self.session.subscriber());
+        pubsub.tracking().disable(self.session.id());
+        clients::unregister(self.session.id());
+    }
+
+    /// Returns the client's session state.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Returns the port the client announced with `REPLCONF
+    /// listening-port`, or 0 if it didn't.
+    pub fn listening_port(&self) -> u16 {
+        self.session.listening_port()
+    }
+
+    /// Parses SYNC or PSYNC.
+    fn sync(&self, cmd: &str, args: &[RespValue]) -> CommandOutcome {
+        let arity = if cmd == "PSYNC" { 2 } else { 0 };
+        if args.len() != arity {
+            return CommandOutcome::Reply(RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                cmd
+            )));
+        }
+        if cmd == "SYNC" {
+            return CommandOutcome::Sync(SyncRequest::Sync);
+        }
+
+        let (Some(replid), Some(offset)) = (self.get_string(&args[0]), self.get_string(&args[1]))
+        else {
+            return CommandOutcome::Reply(RespValue::error("ERR syntax error"));
+        };
+        let offset = match offset.parse::<i64>() {
+            Ok(offset) => u64::try_from(offset).ok(),
+            Err(_) => {
+                return CommandOutcome::Reply(RespValue::error(
+                    "ERR value is not an integer or out of range",
+                ))
+            }
+        };
+        CommandOutcome::Sync(SyncRequest::Psync { replid, offset })
+    }
+
+    fn record_commit(&self, position: Option<u64>) {
+        if let Some(position) = position {
+            self.session.await_commit(position);
+        }
+    }
+
+    /// Pops for a blocking request from the first of its keys with data.
+    ///
+    /// # Returns
+    ///
+    /// The response and the key popped from (`None` for a type error), or
+    /// `None` if none of the keys has data.
+    fn serve(&self, request: &BlockingRequest) -> Option<(RespValue, Option<Bytes>)> {
+        for key in &request.keys {
+            let served = match request.op {
+                BlockingOp::LPop | BlockingOp::RPop => {
+                    if let Err(e) = self.check_type(key, "list") {
+                        return Some((e, None));
+                    }
+
+                    let value = if request.op == BlockingOp::LPop {
+                        self.db().lpop(key)
+                    } else {
+                        self.db().rpop(key)
+                    };
+                    value.map(|value| vec![RespValue::bulk_string(value)])
+                }
+                BlockingOp::ZPopMin | BlockingOp::ZPopMax => {
+                    if let Err(e) = self.check_type(key, "zset") {
+                        return Some((e, None));
+                    }
+
+                    let max = request.op == BlockingOp::ZPopMax;
+                    self.db()
+                        .zpop(key, 1, max)
+                        .into_iter()
+                        .next()
+                        .map(|(member, score)| {
+                            vec![RespValue::bulk_string(member), self.double(score)]
+                        })
+                }
+            };
+
+            if let Some(values) = served {
+                let mut reply = vec![RespValue::bulk_string(key.clone())];
+                reply.extend(values);
+                return Some((RespValue::array(reply), Some(key.clone())));
+            }
+        }
+        None
+    }
+
+    /// Returns the storage engine this handler executes against, which is
+    /// database 0 and holds the others.
+    pub fn storage(&self) -> &Arc<StorageEngine> {
+        &self.storage
+    }
+
+    /// Returns the commands this handler's client knows under another
+    /// name, or not at all.
+    pub fn renames(&self) -> &CommandRenames {
+        &self.renames
+    }
+
+    /// Returns the limits on what this handler's client may send.
+    pub fn protocol_limits(&self) -> &ProtocolLimits {
+        &self.protocol_limits
+    }
+
+    /// Returns the database the client selected.
+    fn db(&self) -> &StorageEngine {
+        self.storage
+            .db(self.session.db())
+            .expect("the selected database exists")
+    }
+
+    /// Splits a command array into its upper-cased name and arguments.
+    fn split_command(
+        &self,
+        command: RespValue,
+    ) -> Result<(&'static str, Vec<RespValue>), RespValue> {
         // Commands should be arrays
         let args = match command {
             RespValue::Array(args) => args,
             _ => {
-                return RespValue::error("ERR invalid command format");
+                return Err(RespValue::error("ERR invalid command format"));
             }
         };
 
         if args.is_empty() {
-            return RespValue::error("ERR empty command");
+            return Err(RespValue::error("ERR empty command"));
         }
 
         // Extract command name (first argument)
-        let cmd_name = match &args[0] {
-            RespValue::BulkString(s) => match std::str::from_utf8(s) {
-                Ok(s) => s.to_uppercase(),
-                Err(_) => return RespValue::error("ERR invalid command name"),
-            },
-            RespValue::SimpleString(s) => s.to_uppercase(),
-            _ => return RespValue::error("ERR invalid command name"),
+        let name = match &args[0] {
+            RespValue::BulkString(s) => &s[..],
+            RespValue::SimpleString(s) => s.as_bytes(),
+            _ => return Err(RespValue::error("ERR invalid command name")),
         };
 
-        // Dispatch to appropriate handler
-        self.dispatch(&cmd_name, &args[1..])
+        match self.resolve_command(name) {
+            Some(cmd_name) => Ok((cmd_name, args)),
+            None => match std::str::from_utf8(name) {
+                Ok(name) => Err(RespValue::error(format!(
+                    "ERR unknown command '{}'",
+                    name.to_uppercase()
+                ))),
+                Err(_) => Err(RespValue::error("ERR invalid command name")),
+            },
+        }
+    }
+
+    /// Returns the real name of the command a client sent as `name` (in
+    /// any case), or `None` if there's no such command or it was renamed
+    /// away or disabled.
+    ///
+    /// Unless commands are renamed, this allocates nothing.
+    pub fn resolve_command(&self, name: &[u8]) -> Option<&'static str> {
+        if self.renames.is_empty() {
+            return lookup_command(name);
+        }
+        // Renamed and disabled commands are unknown under their real name
+        let name = String::from_utf8_lossy(name).to_uppercase();
+        lookup_command(self.renames.resolve(&name)?.as_bytes())
     }
 
-    /// Dispatches a command to its handler.
+    /// Dispatches a command to its handler, counting successful writes as
+    /// changes towards the save rules and propagating them to the
+    /// append-only file and replicas.
     fn dispatch(&self, cmd: &str, args: &[RespValue]) -> RespValue {
+        if let Err(e) = self.check_cluster(cmd, args) {
+            return e;
+        }
+        if !is_write_command(cmd) {
+            self.track(cmd, args);
+            return self.run(cmd, args);
+        }
+        if let Err(e) = self.check_writable(cmd) {
+            return e;
+        }
+        if let Err(e) = self.check_memory(cmd) {
+            return e;
+        }
+
+        let response = self.run_write(
+            || self.run(cmd, args),
+            |response| {
+                if response.is_error() {
+                    Vec::new()
+                } else {
+                    self.propagate(cmd, args, response)
+                }
+            },
+        );
+
+        if !response.is_error() {
+            self.wrote(cmd, args, &response);
+        }
+        response
+    }
+
+    /// Remembers the keys a read command is about to read, if the client
+    /// tracks the keys it reads.
+    ///
+    /// They are tracked before the read, so a write racing it still
+    /// invalidates what the client caches.
+    fn track(&self, cmd: &str, args: &[RespValue]) {
+        if self.session.tracking() {
+            let keys = command_keys(cmd, args);
+            self.db()
+                .pubsub()
+                .tracking()
+                .track(self.session.id(), &keys);
+        }
+    }
+
+    /// Counts a successful write towards the save rules, publishes its
+    /// keyspace events and invalidates the keys it wrote for the clients
+    /// caching them.
+    fn wrote(&self, cmd: &str, args: &[RespValue], response: &RespValue) {
+        self.db().snapshots().record_changes(1);
+        self.notify(cmd, args, response);
+        let pubsub = self.db().pubsub();
+        if !pubsub.tracking().is_empty() {
+            pubsub.invalidate(&command_keys(cmd, args), Some(self.session.id()));
+        }
+    }
+
+    /// Rejects a write command sent by a client of a replica, or of a
+    /// server in read-only mode.
+    fn check_writable(&self, cmd: &str) -> Result<(), RespValue> {
+        if !is_write_command(cmd) {
+            return Ok(());
+        }
+        self.check_read_only()
+    }
+
+    /// Rejects a write sent by a client of a replica, or of a server in
+    /// read-only mode.
+    fn check_read_only(&self) -> Result<(), RespValue> {
+        if self.from_master {
+            return Ok(());
+        }
+        if self.db().replication().link().is_replica() {
+            return Err(RespValue::error(
+                "READONLY You can't write against a read only replica.",
+            ));
+        }
+        if self.storage.is_read_only() {
+            return Err(RespValue::error(
+                "READONLY You can't write against a read only server.",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Evicts keys to bring the memory used back within `maxmemory` before a
+    /// write, and rejects writes that may use more memory when that fails.
+    ///
+    /// Writes from the master are applied whatever the memory used: the
+    /// master evicts for its replicas.
+    fn check_memory(&self, cmd: &str) -> Result<(), RespValue> {
+        if self.from_master || self.storage.evict() || !may_use_memory(cmd) {
+            return Ok(());
+        }
+        Err(RespValue::error(
+            "OOM command not allowed when used memory > 'maxmemory'.",
+        ))
+    }
+
+    /// Redirects a command whose keys are in a slot this node doesn't serve,
+    /// when running in cluster mode.
+    fn check_cluster(&self, cmd: &str, args: &[RespValue]) -> Result<(), RespValue> {
+        let cluster = self.db().cluster();
+        if !cluster.is_enabled() || self.from_master {
+            return Ok(());
+        }
+        // ASKING only holds for the command right after it
+        let asking = cmd != "ASKING" && self.session.take_asking();
+
+        let keys = command_keys(cmd, args);
+        cluster
+            .route(&keys, asking, |key| {
+                self.db().exists(&Bytes::copy_from_slice(key))
+            })
+            .map_err(|redirect| RespValue::error(redirect.to_string()))
+    }
+
+    /// Runs a write, then appends the commands `propagate` derives from its
+    /// result to the append-only file and feeds them to replicas.
+    ///
+    /// Both happen under the same lock the write runs under, so the log and
+    /// the replication stream record writes in the order they were applied.
+    /// Keys expired since the last write are propagated first, as DELs. With
+    /// no log and no replica the lock is skipped.
+    fn run_write<T>(
+        &self,
+        write: impl FnOnce() -> T,
+        propagate: impl FnOnce(&T) -> Vec<Vec<Bytes>>,
+    ) -> T {
+        let aof = self.db().aof();
+        let replication = self.db().replication();
+        if !aof.is_enabled() {
+            if let Some(_unfed) = replication.unfed_write() {
+                return write();
+            }
+        }
+
+        let mut guard = aof.lock();
+        let result = write();
+        // Keys that expired before the write go first, as DELs
+        let mut commands = self.storage.take_expired(&mut guard);
+        let propagated = propagate(&result);
+        if !propagated.is_empty() {
+            commands.extend(guard.select(self.session.db()));
+            commands.extend(propagated);
+        }
+        let position = guard.append(&commands);
+        self.record_commit(position);
+        replication.feed(&commands);
+        result
+    }
+
+    /// Returns the commands to append to the append-only file for a
+    /// successful write, in a form that replays to the same result later:
+    /// expiries become absolute, generated stream IDs explicit and served
+    /// blocking pops plain pops.
+    fn propagate(&self, cmd: &str, args: &[RespValue], response: &RespValue) -> Vec<Vec<Bytes>> {
+        let mut command = Vec::with_capacity(args.len() + 1);
+        command.push(Bytes::copy_from_slice(cmd.as_bytes()));
+        command.extend(args.iter().filter_map(|arg| self.get_bytes(arg)));
+
+        match cmd {
+            "BLPOP" | "BRPOP" | "BZPOPMIN" | "BZPOPMAX" => {
+                let key = response
+                    .as_array()
+                    .and_then(|reply| reply.first())
+                    .and_then(|key| key.as_bytes());
+                let pop = match cmd {
+                    "BLPOP" => BlockingOp::LPop,
+                    "BRPOP" => BlockingOp::RPop,
+                    "BZPOPMIN" => BlockingOp::ZPopMin,
+                    _ => BlockingOp::ZPopMax,
+                };
+                key.map(|key| {
+                    vec![vec![
+                        Bytes::from_static(pop.pop_command().as_bytes()),
+                        Bytes::copy_from_slice(key),
+                    ]]
+                })
+                .unwrap_or_default()
+            }
+            "XADD" => {
+                let Some(id) = response.as_bytes() else {
+                    return Vec::new();
+                };
+                // The first `*` after the key is the ID to generate
+                if let Some(arg) = command.iter_mut().skip(2).find(|arg| arg[..] == b"*"[..]) {
+                    *arg = Bytes::copy_from_slice(id);
+                }
+                vec![command]
+            }
+            "SET" | "SETEX" | "PSETEX" | "EXPIRE" | "PEXPIRE" | "EXPIREAT" => {
+                let key = command.get(1).cloned().unwrap_or_default();
+                let expire_ms = self.db().expire_time(&key).filter(|&ms| ms >= 0);
+                let mut commands = vec![command];
+                if let Some(ms) = expire_ms {
+                    commands.push(vec![
+                        Bytes::from_static(b"PEXPIREAT"),
+                        key,
+                        Bytes::from(ms.to_string()),
+                    ]);
+                }
+                commands
+            }
+            _ => vec![command],
+        }
+    }
+
+    /// Publishes the keyspace events of a successful write.
+    ///
+    /// Deletions and expiries are published by the storage engine, which
+    /// knows which keys actually went away; this covers everything else,
+    /// working out from the response whether the command changed anything.
+    fn notify(&self, cmd: &str, args: &[RespValue], response: &RespValue) {
+        let pubsub = self.db().pubsub();
+        if pubsub.notify_flags() == NotifyFlags::default() {
+            return;
+        }
+
+        let key = |i: usize| args.get(i).and_then(|arg| self.get_bytes(arg));
+        let changed = match response {
+            RespValue::Integer(n) => *n > 0,
+            RespValue::Array(values) => !values.is_empty(),
+            value => !value.is_null(),
+        };
+        let has_option = |option: &[&str]| {
+            args.iter().skip(1).any(|arg| {
+                arg.as_bytes()
+                    .is_some_and(|a| option.iter().any(|o| a.eq_ignore_ascii_case(o.as_bytes())))
+            })
+        };
+
+        let mut events: Vec<(EventClass, &str, Option<Bytes>)> = Vec::new();
+        match cmd {
+            // SET replies nil when NX or XX stopped it, unless it has GET
+            "SET" if changed || has_option(&["GET"]) => {
+                events.push((EventClass::String, "set", key(0)));
+                if has_option(&["EX", "PX", "EXAT", "PXAT"]) {
+                    events.push((EventClass::Generic, "expire", key(0)));
+                }
+            }
+            "SETEX" | "PSETEX" => {
+                events.push((EventClass::String, "set", key(0)));
+                events.push((EventClass::Generic, "expire", key(0)));
+            }
+            "SETNX" | "MSETNX" if !changed => {}
+            "GETSET" | "SETNX" => events.push((EventClass::String, "set", key(0))),
+            "MSET" | "MSETNX" => {
+                for i in (0..args.len()).step_by(2) {
+                    events.push((EventClass::String, "set", key(i)));
+                }
+            }
+            "APPEND" => events.push((EventClass::String, "append", key(0))),
+            "INCR" | "INCRBY" | "DECR" | "DECRBY" => {
+                events.push((EventClass::String, "incrby", key(0)))
+            }
+            "INCRBYFLOAT" => events.push((EventClass::String, "incrbyfloat", key(0))),
+            "SETBIT" => events.push((EventClass::String, "setbit", key(0))),
+            "PFADD" | "PFMERGE" if changed => events.push((EventClass::String, "pfadd", key(0))),
+
+            "LPUSH" => events.push((EventClass::List, "lpush", key(0))),
+            "RPUSH" => events.push((EventClass::List, "rpush", key(0))),
+            "LPOP" if changed => events.push((EventClass::List, "lpop", key(0))),
+            "RPOP" if changed => events.push((EventClass::List, "rpop", key(0))),
+            "LSET" => events.push((EventClass::List, "lset", key(0))),
+            "LREM" if changed => events.push((EventClass::List, "lrem", key(0))),
+            "LTRIM" => events.push((EventClass::List, "ltrim", key(0))),
+            "LMOVE" | "RPOPLPUSH" if changed => {
+                let left = |i: usize| key(i).is_some_and(|end| end.eq_ignore_ascii_case(b"LEFT"));
+                let (pop, push) = match cmd {
+                    "LMOVE" => (
+                        if left(2) { "lpop" } else { "rpop" },
+                        if left(3) { "lpush" } else { "rpush" },
+                    ),
+                    _ => ("rpop", "lpush"),
+                };
+                events.push((EventClass::List, pop, key(0)));
+                events.push((EventClass::List, push, key(1)));
+            }
+
+            "HSET" | "HMSET" => events.push((EventClass::Hash, "hset", key(0))),
+            "HSETNX" if changed => events.push((EventClass::Hash, "hset", key(0))),
+            "HDEL" if changed => events.push((EventClass::Hash, "hdel", key(0))),
+            "HINCRBY" => events.push((EventClass::Hash, "hincrby", key(0))),
+            "HINCRBYFLOAT" => events.push((EventClass::Hash, "hincrbyfloat", key(0))),
+
+            "SADD" if changed => events.push((EventClass::Set, "sadd", key(0))),
+            "SREM" if changed => events.push((EventClass::Set, "srem", key(0))),
+            "SINTERSTORE" if changed => events.push((EventClass::Set, "sinterstore", key(0))),
+            "SUNIONSTORE" if changed => events.push((EventClass::Set, "sunionstore", key(0))),
+            "SDIFFSTORE" if changed => events.push((EventClass::Set, "sdiffstore", key(0))),
+
+            "ZADD" if !response.is_null() => {
+                let event = if has_option(&["INCR"]) {
+                    "zincr"
+                } else {
+                    "zadd"
+                };
+                events.push((EventClass::ZSet, event, key(0)));
+            }
+            "ZINCRBY" => events.push((EventClass::ZSet, "zincr", key(0))),
+            "ZREM" if changed => events.push((EventClass::ZSet, "zrem", key(0))),
+            "ZPOPMIN" if changed => events.push((EventClass::ZSet, "zpopmin", key(0))),
+            "ZPOPMAX" if changed => events.push((EventClass::ZSet, "zpopmax", key(0))),
+            "ZUNIONSTORE" if changed => events.push((EventClass::ZSet, "zunionstore", key(0))),
+            "ZINTERSTORE" if changed => events.push((EventClass::ZSet, "zinterstore", key(0))),
+            "ZDIFFSTORE" if changed => events.push((EventClass::ZSet, "zdiffstore", key(0))),
+
+            "XADD" if changed => events.push((EventClass::Stream, "xadd", key(0))),
+
+            "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" if changed => {
+                events.push((EventClass::Generic, "expire", key(0)))
+            }
+            "PERSIST" if changed => events.push((EventClass::Generic, "persist", key(0))),
+            "RENAME" | "RENAMENX" if changed => {
+                events.push((EventClass::Generic, "rename_from", key(0)));
+                events.push((EventClass::Generic, "rename_to", key(1)));
+            }
+            "COPY" if changed => events.push((EventClass::Generic, "copy_to", key(1))),
+            "MOVE" if changed => events.push((EventClass::Generic, "move_from", key(0))),
+            "SORT" if changed && has_option(&["STORE"]) => {
+                let store = args.iter().position(|arg| {
+                    arg.as_bytes()
+                        .is_some_and(|a| a.eq_ignore_ascii_case(b"STORE"))
+                });
+                events.push((
+                    EventClass::List,
+                    "sortstore",
+                    store.and_then(|at| key(at + 1)),
+                ));
+            }
+            _ => {}
+        }
+
+        // COPY ... DB writes to another database
+        let db = match cmd {
+            "COPY" => args
+                .iter()
+                .skip(2)
+                .position(|arg| {
+                    arg.as_bytes()
+                        .is_some_and(|a| a.eq_ignore_ascii_case(b"DB"))
+                })
+                .and_then(|at| args.get(at + 3))
+                .and_then(|arg| self.get_integer(arg))
+                .map_or(self.session.db(), |db| db as usize),
+            _ => self.session.db(),
+        };
+        for (class, event, key) in events {
+            if let Some(key) = key {
+                pubsub.notify(db, class, event, &key);
+            }
+        }
+
+        // MOVE publishes `move_to` in the database the key went to
+        if cmd == "MOVE" && changed {
+            if let (Some(key), Some(target)) =
+                (key(0), args.get(1).and_then(|a| self.get_integer(a)))
+            {
+                pubsub.notify(target as usize, EventClass::Generic, "move_to", &key);
+            }
+        }
+    }
+
+    /// Runs a command's handler.
+    fn run(&self, cmd: &str, args: &[RespValue]) -> RespValue {
+        // With CLIENT NO-TOUCH on, only TOUCH records key accesses
+        if self.session.no_touch() && cmd != "TOUCH" {
+            return without_touching(|| self.run_command(cmd, args));
+        }
+        self.run_command(cmd, args)
+    }
+
+    /// Runs a command's handler, matching it by name.
+    fn run_command(&self, cmd: &str, args: &[RespValue]) -> RespValue {
         match cmd {
             // String commands
             "SET" => self.cmd_set(args),
@@ -141,16 +1067,29 @@ impl CommandHandler {
             "STRLEN" => self.cmd_strlen(args),
             "INCR" => self.cmd_incr(args),
             "INCRBY" => self.cmd_incrby(args),
+            "INCRBYFLOAT" => self.cmd_incrbyfloat(args),
             "DECR" => self.cmd_decr(args),
             "DECRBY" => self.cmd_decrby(args),
             "MSET" => self.cmd_mset(args),
             "MGET" => self.cmd_mget(args),
+            "MSETNX" => self.cmd_msetnx(args),
             "SETNX" => self.cmd_setnx(args),
             "SETEX" => self.cmd_setex(args),
             "PSETEX" => self.cmd_psetex(args),
             "GETSET" => self.cmd_getset(args),
             "GETDEL" => self.cmd_getdel(args),
 
+            // Bitmap commands
+            "SETBIT" => self.cmd_setbit(args),
+            "GETBIT" => self.cmd_getbit(args),
+            "BITCOUNT" => self.cmd_bitcount(args),
+            "BITPOS" => self.cmd_bitpos(args),
+
+            // HyperLogLog commands
+            "PFADD" => self.cmd_pfadd(args),
+            "PFCOUNT" => self.cmd_pfcount(args),
+            "PFMERGE" => self.cmd_pfmerge(args),
+
             // List commands
             "LPUSH" => self.cmd_lpush(args),
             "RPUSH" => self.cmd_rpush(args),
@@ -161,11 +1100,77 @@ impl CommandHandler {
             "LRANGE" => self.cmd_lrange(args),
             "LSET" => self.cmd_lset(args),
             "LREM" => self.cmd_lrem(args),
+            "LTRIM" => self.cmd_ltrim(args),
+            "LMOVE" => self.cmd_lmove(args),
+            "RPOPLPUSH" => self.cmd_rpoplpush(args),
+            "BLPOP" => self.cmd_blocking_pop(cmd, args, BlockingOp::LPop),
+            "BRPOP" => self.cmd_blocking_pop(cmd, args, BlockingOp::RPop),
+
+            // Hash commands
+            "HSET" | "HMSET" => self.cmd_hset(cmd, args),
+            "HGET" => self.cmd_hget(args),
+            "HDEL" => self.cmd_hdel(args),
+            "HLEN" => self.cmd_hlen(args),
+            "HEXISTS" => self.cmd_hexists(args),
+            "HGETALL" => self.cmd_hgetall(args),
+            "HINCRBY" => self.cmd_hincrby(args),
+            "HINCRBYFLOAT" => self.cmd_hincrbyfloat(args),
+            "HSETNX" => self.cmd_hsetnx(args),
+            "HRANDFIELD" => self.cmd_hrandfield(args),
+
+            // Set commands
+            "SADD" => self.cmd_sadd(args),
+            "SREM" => self.cmd_srem(args),
+            "SMEMBERS" => self.cmd_smembers(args),
+            "SISMEMBER" => self.cmd_sismember(args),
+            "SMISMEMBER" => self.cmd_smismember(args),
+            "SCARD" => self.cmd_scard(args),
+            "SINTER" => self.cmd_set_op(cmd, SetOp::Inter, args),
+            "SUNION" => self.cmd_set_op(cmd, SetOp::Union, args),
+            "SDIFF" => self.cmd_set_op(cmd, SetOp::Diff, args),
+            "SINTERSTORE" => self.cmd_set_op_store(cmd, SetOp::Inter, args),
+            "SUNIONSTORE" => self.cmd_set_op_store(cmd, SetOp::Union, args),
+            "SDIFFSTORE" => self.cmd_set_op_store(cmd, SetOp::Diff, args),
+            "SINTERCARD" => self.cmd_sintercard(args),
+
+            // Sorted set commands
+            "ZADD" => self.cmd_zadd(args),
+            "ZSCORE" => self.cmd_zscore(args),
+            "ZREM" => self.cmd_zrem(args),
+            "ZCARD" => self.cmd_zcard(args),
+            "ZRANGE" => self.cmd_zrange(cmd, args, false),
+            "ZREVRANGE" => self.cmd_zrange(cmd, args, true),
+            "ZRANK" => self.cmd_zrank(cmd, args, false),
+            "ZREVRANK" => self.cmd_zrank(cmd, args, true),
+            "ZINCRBY" => self.cmd_zincrby(args),
+            "ZPOPMIN" => self.cmd_zpop(cmd, args, false),
+            "ZPOPMAX" => self.cmd_zpop(cmd, args, true),
+            "ZRANDMEMBER" => self.cmd_zrandmember(args),
+            "BZPOPMIN" => self.cmd_blocking_pop(cmd, args, BlockingOp::ZPopMin),
+            "BZPOPMAX" => self.cmd_blocking_pop(cmd, args, BlockingOp::ZPopMax),
+            "ZUNIONSTORE" => self.cmd_zset_op_store(cmd, SetOp::Union, args),
+            "ZINTERSTORE" => self.cmd_zset_op_store(cmd, SetOp::Inter, args),
+            "ZDIFFSTORE" => self.cmd_zset_op_store(cmd, SetOp::Diff, args),
+
+            // Stream commands
+            "XADD" => self.cmd_xadd(args),
+            "XLEN" => self.cmd_xlen(args),
+            "XRANGE" => self.cmd_xrange(cmd, args, false),
+            "XREVRANGE" => self.cmd_xrange(cmd, args, true),
+            "XREAD" => self.cmd_xread(args),
+            "XGROUP" => self.cmd_xgroup(args),
+            "XREADGROUP" => self.cmd_xreadgroup(args),
+            "XACK" => self.cmd_xack(args),
+            "XPENDING" => self.cmd_xpending(args),
+            "XCLAIM" => self.cmd_xclaim(args),
 
             // Key commands
             "EXPIRE" => self.cmd_expire(args),
             "PEXPIRE" => self.cmd_pexpire(args),
             "EXPIREAT" => self.cmd_expireat(args),
+            "PEXPIREAT" => self.cmd_pexpireat(args),
+            "EXPIRETIME" => self.cmd_expiretime(args),
+            "PEXPIRETIME" => self.cmd_pexpiretime(args),
             "TTL" => self.cmd_ttl(args),
             "PTTL" => self.cmd_pttl(args),
             "PERSIST" => self.cmd_persist(args),
@@ -173,19 +1178,61 @@ impl CommandHandler {
             "TYPE" => self.cmd_type(args),
             "RENAME" => self.cmd_rename(args),
             "RENAMENX" => self.cmd_renamenx(args),
+            "COPY" => self.cmd_copy(args),
+            "MOVE" => self.cmd_move(args),
+            "SORT" => self.cmd_sort(args),
+            "OBJECT" => self.cmd_object(args),
+            "MEMORY" => self.cmd_memory(args),
+            "SCAN" => self.cmd_scan(args),
+            "TOUCH" => self.cmd_touch(args),
+            "UNLINK" => self.cmd_unlink(args),
 
             // Server commands
             "PING" => self.cmd_ping(args),
             "ECHO" => self.cmd_echo(args),
             "INFO" => self.cmd_info(args),
             "DBSIZE" => self.cmd_dbsize(args),
-            "FLUSHDB" | "FLUSHALL" => self.cmd_flushdb(args),
+            "FLUSHDB" => self.cmd_flushdb(args),
+            "FLUSHALL" => self.cmd_flushall(args),
+            "SELECT" => self.cmd_select(args),
             "COMMAND" => self.cmd_command(args),
             "CONFIG" => self.cmd_config(args),
             "TIME" => self.cmd_time(args),
+            "SAVE" => self.cmd_save(args),
+            "BGSAVE" => self.cmd_bgsave(args),
+            "LASTSAVE" => self.cmd_lastsave(args),
+            "EXPORT" => self.cmd_export(args),
+            "BGREWRITEAOF" => self.cmd_bgrewriteaof(args),
             "DEBUG" => self.cmd_debug(args),
+            "MONITOR" => self.cmd_monitor(args),
             "QUIT" => RespValue::ok(),
 
+            // Replication commands
+            "REPLICAOF" | "SLAVEOF" => self.cmd_replicaof(cmd, args),
+            "REPLCONF" => self.cmd_replconf(args),
+            "SYNC" | "PSYNC" => {
+                RespValue::error(format!("ERR '{}' can only be sent by a replica", cmd))
+            }
+
+            // Connection commands
+            "HELLO" => self.cmd_hello(args),
+            "CLIENT" => self.cmd_client(args),
+
+            // Cluster commands
+            "CLUSTER" => self.cmd_cluster(args),
+            "ASKING" => self.cmd_asking(args),
+
+            // Scripting commands
+            "FUNCTION" => self.cmd_function(args),
+            "FCALL" | "FCALL_RO" => self.cmd_fcall(cmd, args),
+
+            // Pub/Sub commands
+            "PUBLISH" => self.cmd_publish(args),
+            "PUBSUB" => self.cmd_pubsub(args),
+            "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" => RespValue::error(
+                format!("ERR '{}' can only be sent by a connected client", cmd),
+            ),
+
             // Unknown command
             _ => RespValue::error(format!("ERR unknown command '{}'", cmd)),
         }
@@ -195,47 +1242,191 @@ impl CommandHandler {
     // Helper functions
     // ========================================================================
 
+    /// Replies with a floating point number: a double to RESP3 clients,
+    /// text to RESP2 ones.
+    fn double(&self, n: f64) -> RespValue {
+        if self.session.resp3() {
+            RespValue::Double(n)
+        } else {
+            RespValue::bulk_string(format_score(n))
+        }
+    }
+
+    /// Replies with key/value pairs: a map to RESP3 clients, a flat array
+    /// of keys and values to RESP2 ones.
+    fn map(&self, pairs: Vec<(RespValue, RespValue)>) -> RespValue {
+        if self.session.resp3() {
+            RespValue::Map(pairs)
+        } else {
+            RespValue::array(pairs.into_iter().flat_map(|(k, v)| [k, v]).collect())
+        }
+    }
+
+    /// Replies with an out-of-band message, such as a subscription change:
+    /// a push frame to RESP3 clients, an array to RESP2 ones.
+    fn push(&self, items: Vec<RespValue>) -> RespValue {
+        if self.session.resp3() {
+            RespValue::Push(items)
+        } else {
+            RespValue::array(items)
+        }
+    }
+
     /// Extracts a Bytes value from a RespValue.
     fn get_bytes(&self, value: &RespValue) -> Option<Bytes> {
-        match value {
-            RespValue::BulkString(b) => Some(b.clone()),
-            RespValue::SimpleString(s) => Some(Bytes::from(s.clone())),
-            _ => None,
-        }
+        value.extract().ok()
     }
 
     /// Extracts a string from a RespValue.
     fn get_string(&self, value: &RespValue) -> Option<String> {
-        match value {
-            RespValue::BulkString(b) => std::str::from_utf8(b).ok().map(|s| s.to_string()),
-            RespValue::SimpleString(s) => Some(s.clone()),
-            _ => None,
-        }
+        value.extract().ok()
     }
 
     /// Extracts an integer from a RespValue.
     fn get_integer(&self, value: &RespValue) -> Option<i64> {
-        match value {
-            RespValue::Integer(n) => Some(*n),
-            RespValue::BulkString(b) => std::str::from_utf8(b).ok().and_then(|s| s.parse().ok()),
-            RespValue::SimpleString(s) => s.parse().ok(),
-            _ => None,
-        }
+        value.extract().ok()
     }
 
-    // ========================================================================
-    // String Commands
-    // ========================================================================
+    /// Extracts a finite float from a RespValue.
+    fn get_float(&self, value: &RespValue) -> Option<f64> {
+        value.extract().ok().filter(|f: &f64| f.is_finite())
+    }
 
-    /// SET key value [EX seconds] [PX milliseconds] [NX|XX]
-    fn cmd_set(&self, args: &[RespValue]) -> RespValue {
-        if args.len() < 2 {
-            return RespValue::error("ERR wrong number of arguments for 'SET' command");
+    /// Turns `amount` units of `unit_ms` milliseconds into an expiry
+    /// deadline in Unix milliseconds, counted from now when `relative`.
+    /// Returns `None` when the deadline doesn't fit, which the expiring
+    /// commands reply to with "invalid expire time".
+    fn expire_deadline(&self, amount: i64, unit_ms: i64, relative: bool) -> Option<i64> {
+        let ms = amount.checked_mul(unit_ms)?;
+        if relative {
+            ms.checked_add(self.db().unix_time_ms())
+        } else {
+            Some(ms)
         }
+    }
 
-        let key = match self.get_bytes(&args[0]) {
-            Some(k) => k,
-            None => return RespValue::error("ERR invalid key"),
+    /// Extracts a sorted set score from a RespValue.
+    ///
+    /// Unlike [`get_float`](Self::get_float), this accepts `inf`/`-inf`
+    /// (and `+inf`) since infinite scores are valid in sorted sets.
+    fn get_score(&self, value: &RespValue) -> Option<f64> {
+        let s = match value {
+            RespValue::Integer(n) => return Some(*n as f64),
+            RespValue::BulkString(b) => std::str::from_utf8(b).ok()?,
+            RespValue::SimpleString(s) => s.as_str(),
+            _ => return None,
+        };
+        let f: f64 = s.parse().ok()?;
+        (!f.is_nan()).then_some(f)
+    }
+
+    /// Parses an XRANGE bound: `-`, `+`, a (possibly incomplete) ID, or an
+    /// exclusive `(id`.
+    ///
+    /// Returns `None` if the bound is malformed and `Some(None)` if an
+    /// exclusive bound leaves no room in the ID space.
+    fn get_stream_bound(&self, value: &RespValue, is_start: bool) -> Option<Option<StreamId>> {
+        let raw = self.get_bytes(value)?;
+        match raw.as_ref() {
+            b"-" => Some(Some(StreamId::MIN)),
+            b"+" => Some(Some(StreamId::MAX)),
+            [b'(', rest @ ..] => {
+                let default_seq = if is_start { 0 } else { u64::MAX };
+                let id = StreamId::parse(rest, default_seq)?;
+                Some(if is_start { id.next() } else { id.prev() })
+            }
+            _ => {
+                let default_seq = if is_start { 0 } else { u64::MAX };
+                StreamId::parse(&raw, default_seq).map(Some)
+            }
+        }
+    }
+
+    /// Parses a list of complete stream IDs.
+    fn get_stream_ids(&self, args: &[RespValue]) -> Result<Vec<StreamId>, RespValue> {
+        args.iter()
+            .map(|arg| {
+                self.get_bytes(arg)
+                    .and_then(|id| StreamId::parse(&id, 0))
+                    .ok_or_else(|| {
+                        RespValue::error(
+                            "ERR Invalid stream ID specified as stream command argument",
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    /// Parses a SETBIT/GETBIT offset.
+    fn get_bit_offset(&self, value: &RespValue) -> Result<u64, RespValue> {
+        match self.get_integer(value) {
+            Some(o) if (0..=MAX_BIT_OFFSET as i64).contains(&o) => Ok(o as u64),
+            _ => Err(RespValue::error(
+                "ERR bit offset is not an integer or out of range",
+            )),
+        }
+    }
+
+    /// Parses a BITCOUNT/BITPOS `start end [BYTE|BIT]` range.
+    fn get_bit_range(
+        &self,
+        start: &RespValue,
+        end: &RespValue,
+        unit: Option<&RespValue>,
+    ) -> Result<BitRange, RespValue> {
+        let (start, end) = match (self.get_integer(start), self.get_integer(end)) {
+            (Some(s), Some(e)) => (s, e),
+            _ => {
+                return Err(RespValue::error(
+                    "ERR value is not an integer or out of range",
+                ))
+            }
+        };
+
+        let unit = match unit.map(|u| self.get_string(u).map(|s| s.to_uppercase())) {
+            None => BitUnit::Byte,
+            Some(Some(u)) if u == "BYTE" => BitUnit::Byte,
+            Some(Some(u)) if u == "BIT" => BitUnit::Bit,
+            Some(_) => return Err(RespValue::error("ERR syntax error")),
+        };
+
+        Ok(BitRange { start, end, unit })
+    }
+
+    /// Returns a WRONGTYPE error if the key exists with a type other than `expected`.
+    fn check_type(&self, key: &Bytes, expected: &str) -> Result<(), RespValue> {
+        match self.db().key_type(key) {
+            "none" => Ok(()),
+            t if t == expected => Ok(()),
+            _ => Err(RespValue::error(WRONGTYPE)),
+        }
+    }
+
+    /// Converts a storage engine error into an error reply.
+    ///
+    /// Errors that already carry an error code (like WRONGTYPE) are passed
+    /// through, everything else gets the generic `ERR` prefix.
+    fn storage_error(e: &str) -> RespValue {
+        if e.starts_with("WRONGTYPE") {
+            RespValue::error(e)
+        } else {
+            RespValue::error(format!("ERR {}", e))
+        }
+    }
+
+    // ========================================================================
+    // String Commands
+    // ========================================================================
+
+    /// SET key value [EX seconds] [PX milliseconds] [NX|XX]
+    fn cmd_set(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::error("ERR wrong number of arguments for 'SET' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
         };
 
         let value = match self.get_bytes(&args[1]) {
@@ -244,7 +1435,9 @@ impl CommandHandler {
         };
 
         // Parse optional arguments
-        let mut ttl: Option<Duration> = None;
+        let mut expire_at: Option<i64> = None; // Unix time in milliseconds
+        let mut keepttl = false; // Keep the existing TTL
+        let mut expiry_opt: Option<String> = None; // EX, PX, EXAT, PXAT or KEEPTTL, only one allowed
         let mut nx = false; // Only set if not exists
         let mut xx = false; // Only set if exists
         let mut get = false; // Return old value
@@ -257,45 +1450,62 @@ impl CommandHandler {
             };
 
             match opt.as_str() {
-                "EX" => {
-                    i += 1;
-                    if i >= args.len() {
+                "EX" | "PX" | "EXAT" | "PXAT" | "KEEPTTL" => {
+                    if expiry_opt.as_deref().is_some_and(|o| o != opt) {
                         return RespValue::error("ERR syntax error");
                     }
-                    let secs = match self.get_integer(&args[i]) {
-                        Some(s) if s > 0 => s as u64,
-                        _ => return RespValue::error("ERR invalid expire time"),
-                    };
-                    ttl = Some(Duration::from_secs(secs));
+                    expiry_opt = Some(opt.clone());
                 }
-                "PX" => {
+                "NX" if xx => return RespValue::error("ERR syntax error"),
+                "XX" if nx => return RespValue::error("ERR syntax error"),
+                _ => {}
+            }
+
+            match opt.as_str() {
+                "EX" | "PX" | "EXAT" | "PXAT" => {
                     i += 1;
                     if i >= args.len() {
                         return RespValue::error("ERR syntax error");
                     }
-                    let ms = match self.get_integer(&args[i]) {
-                        Some(m) if m > 0 => m as u64,
-                        _ => return RespValue::error("ERR invalid expire time"),
+                    let unit_ms = if opt.starts_with("EX") { 1000 } else { 1 };
+                    // Out of range like EXPIRE: the deadline must fit in
+                    // Unix milliseconds
+                    let deadline = match self.get_integer(&args[i]) {
+                        Some(amount) if amount > 0 => {
+                            self.expire_deadline(amount, unit_ms, !opt.ends_with("AT"))
+                        }
+                        _ => None,
                     };
-                    ttl = Some(Duration::from_millis(ms));
+                    match deadline {
+                        // A deadline in the past stores an already expired key
+                        Some(unix_ms) => expire_at = Some(unix_ms),
+                        None => {
+                            return RespValue::error("ERR invalid expire time in 'set' command")
+                        }
+                    }
                 }
                 "NX" => nx = true,
                 "XX" => xx = true,
                 "GET" => get = true,
-                "KEEPTTL" => {
-                    // Keep existing TTL - we'd need to implement this
-                }
+                "KEEPTTL" => keepttl = true,
                 _ => return RespValue::error(format!("ERR unknown option '{}'", opt)),
             }
             i += 1;
         }
 
+        // GET needs the old value to be a string; otherwise nothing is set
+        if get {
+            if let Err(e) = self.check_type(&key, "string") {
+                return e;
+            }
+        }
+
         // Handle NX/XX conditions
-        let exists = self.storage.exists(&key);
+        let exists = self.db().exists(&key);
 
         if nx && exists {
             return if get {
-                match self.storage.get(&key) {
+                match self.db().get(&key) {
                     Some(v) => RespValue::bulk_string(v),
                     None => RespValue::null(),
                 }
@@ -309,12 +1519,13 @@ impl CommandHandler {
         }
 
         // Get old value if GET option is specified
-        let old_value = if get { self.storage.get(&key) } else { None };
+        let old_value = if get { self.db().get(&key) } else { None };
 
         // Perform the SET
-        match ttl {
-            Some(duration) => self.storage.set_with_ttl(key, value, duration),
-            None => self.storage.set(key, value),
+        match expire_at {
+            Some(unix_ms) => self.db().set_with_expire_at(key, value, unix_ms),
+            None if keepttl => self.db().set_keep_ttl(key, value),
+            None => self.db().set(key, value),
         };
 
         if get {
@@ -338,7 +1549,11 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        match self.storage.get(&key) {
+        if let Err(e) = self.check_type(&key, "string") {
+            return e;
+        }
+
+        match self.db().get(&key) {
             Some(value) => RespValue::bulk_string(value),
             None => RespValue::null(),
         }
@@ -352,7 +1567,7 @@ impl CommandHandler {
 
         let keys: Vec<Bytes> = args.iter().filter_map(|a| self.get_bytes(a)).collect();
 
-        let deleted = self.storage.delete_many(&keys);
+        let deleted = self.db().delete_many(&keys);
         RespValue::integer(deleted as i64)
     }
 
@@ -364,7 +1579,7 @@ impl CommandHandler {
 
         let keys: Vec<Bytes> = args.iter().filter_map(|a| self.get_bytes(a)).collect();
 
-        let count = self.storage.exists_many(&keys);
+        let count = self.db().exists_many(&keys);
         RespValue::integer(count as i64)
     }
 
@@ -384,8 +1599,10 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid value"),
         };
 
-        let new_len = self.storage.append(&key, &value);
-        RespValue::integer(new_len as i64)
+        match self.db().append(&key, &value) {
+            Ok(new_len) => RespValue::integer(new_len as i64),
+            Err(e) => Self::storage_error(e),
+        }
     }
 
     /// STRLEN key
@@ -399,7 +1616,11 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        let len = self.storage.strlen(&key);
+        if let Err(e) = self.check_type(&key, "string") {
+            return e;
+        }
+
+        let len = self.db().strlen(&key);
         RespValue::integer(len as i64)
     }
 
@@ -414,9 +1635,9 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        match self.storage.incr(&key) {
+        match self.db().incr(&key) {
             Ok(n) => RespValue::integer(n),
-            Err(e) => RespValue::error(format!("ERR {}", e)),
+            Err(e) => Self::storage_error(e),
         }
     }
 
@@ -436,9 +1657,36 @@ impl CommandHandler {
             None => return RespValue::error("ERR value is not an integer"),
         };
 
-        match self.storage.incr_by(&key, delta) {
+        match self.db().incr_by(&key, delta) {
             Ok(n) => RespValue::integer(n),
-            Err(e) => RespValue::error(format!("ERR {}", e)),
+            Err(e) => Self::storage_error(e),
+        }
+    }
+
+    /// INCRBYFLOAT key increment
+    fn cmd_incrbyfloat(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::error("ERR wrong number of arguments for 'INCRBYFLOAT' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let delta = match self.get_float(&args[1]) {
+            Some(d) => d,
+            None => return RespValue::error("ERR value is not a valid float"),
+        };
+
+        match self.db().incr_by_float(&key, delta) {
+            // RESP2 clients get the value as stored
+            Ok(v) if self.session.resp3() => {
+                let n = std::str::from_utf8(&v).ok().and_then(|v| v.parse().ok());
+                n.map_or_else(|| RespValue::bulk_string(v), RespValue::Double)
+            }
+            Ok(v) => RespValue::bulk_string(v),
+            Err(e) => Self::storage_error(e),
         }
     }
 
@@ -453,9 +1701,9 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        match self.storage.decr(&key) {
+        match self.db().decr(&key) {
             Ok(n) => RespValue::integer(n),
-            Err(e) => RespValue::error(format!("ERR {}", e)),
+            Err(e) => Self::storage_error(e),
         }
     }
 
@@ -475,52 +1723,78 @@ impl CommandHandler {
             None => return RespValue::error("ERR value is not an integer"),
         };
 
-        match self.storage.decr_by(&key, delta) {
+        match self.db().decr_by(&key, delta) {
             Ok(n) => RespValue::integer(n),
-            Err(e) => RespValue::error(format!("ERR {}", e)),
+            Err(e) => Self::storage_error(e),
         }
     }
 
     /// MSET key value [key value ...]
     fn cmd_mset(&self, args: &[RespValue]) -> RespValue {
-        if args.is_empty() || args.len() % 2 != 0 {
+        if args.is_empty() || !args.len().is_multiple_of(2) {
             return RespValue::error("ERR wrong number of arguments for 'MSET' command");
         }
 
-        for i in (0..args.len()).step_by(2) {
-            let key = match self.get_bytes(&args[i]) {
+        let mut pairs = Vec::with_capacity(args.len() / 2);
+        for pair in args.chunks_exact(2) {
+            let key = match self.get_bytes(&pair[0]) {
                 Some(k) => k,
                 None => return RespValue::error("ERR invalid key"),
             };
 
-            let value = match self.get_bytes(&args[i + 1]) {
+            let value = match self.get_bytes(&pair[1]) {
                 Some(v) => v,
                 None => return RespValue::error("ERR invalid value"),
             };
 
-            self.storage.set(key, value);
+            pairs.push((key, value));
         }
 
+        self.db().mset(pairs);
         RespValue::ok()
     }
 
+    /// MSETNX key value [key value ...]
+    fn cmd_msetnx(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() || !args.len().is_multiple_of(2) {
+            return RespValue::error("ERR wrong number of arguments for 'MSETNX' command");
+        }
+
+        let mut pairs = Vec::with_capacity(args.len() / 2);
+        for pair in args.chunks_exact(2) {
+            let key = match self.get_bytes(&pair[0]) {
+                Some(k) => k,
+                None => return RespValue::error("ERR invalid key"),
+            };
+
+            let value = match self.get_bytes(&pair[1]) {
+                Some(v) => v,
+                None => return RespValue::error("ERR invalid value"),
+            };
+
+            pairs.push((key, value));
+        }
+
+        RespValue::integer(self.db().msetnx(pairs) as i64)
+    }
+
     /// MGET key [key ...]
     fn cmd_mget(&self, args: &[RespValue]) -> RespValue {
         if args.is_empty() {
             return RespValue::error("ERR wrong number of arguments for 'MGET' command");
         }
 
-        let values: Vec<RespValue> = args
-            .iter()
-            .map(|arg| match self.get_bytes(arg) {
-                Some(key) => match self.storage.get(&key) {
-                    Some(v) => RespValue::bulk_string(v),
-                    None => RespValue::null(),
-                },
-                None => RespValue::null(),
-            })
-            .collect();
+        let keys: Option<Vec<Bytes>> = args.iter().map(|arg| self.get_bytes(arg)).collect();
+        let Some(keys) = keys else {
+            return RespValue::error("ERR invalid key");
+        };
 
+        let values = self
+            .db()
+            .mget(&keys)
+            .into_iter()
+            .map(|value| value.map_or_else(RespValue::null, RespValue::bulk_string))
+            .collect();
         RespValue::array(values)
     }
 
@@ -540,10 +1814,10 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid value"),
         };
 
-        if self.storage.exists(&key) {
+        if self.db().exists(&key) {
             RespValue::integer(0)
         } else {
-            self.storage.set(key, value);
+            self.db().set(key, value);
             RespValue::integer(1)
         }
     }
@@ -559,9 +1833,13 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        let seconds = match self.get_integer(&args[1]) {
-            Some(s) if s > 0 => s as u64,
-            _ => return RespValue::error("ERR invalid expire time"),
+        // Checked like SET ... EX
+        let deadline = match self.get_integer(&args[1]) {
+            Some(amount) if amount > 0 => self.expire_deadline(amount, 1000, true),
+            _ => None,
+        };
+        let Some(unix_ms) = deadline else {
+            return RespValue::error("ERR invalid expire time in 'setex' command");
         };
 
         let value = match self.get_bytes(&args[2]) {
@@ -569,8 +1847,7 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid value"),
         };
 
-        self.storage
-            .set_with_ttl(key, value, Duration::from_secs(seconds));
+        self.db().set_with_expire_at(key, value, unix_ms);
         RespValue::ok()
     }
 
@@ -585,9 +1862,13 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        let ms = match self.get_integer(&args[1]) {
-            Some(m) if m > 0 => m as u64,
-            _ => return RespValue::error("ERR invalid expire time"),
+        // Checked like SET ... PX
+        let deadline = match self.get_integer(&args[1]) {
+            Some(amount) if amount > 0 => self.expire_deadline(amount, 1, true),
+            _ => None,
+        };
+        let Some(unix_ms) = deadline else {
+            return RespValue::error("ERR invalid expire time in 'psetex' command");
         };
 
         let value = match self.get_bytes(&args[2]) {
@@ -595,8 +1876,7 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid value"),
         };
 
-        self.storage
-            .set_with_ttl(key, value, Duration::from_millis(ms));
+        self.db().set_with_expire_at(key, value, unix_ms);
         RespValue::ok()
     }
 
@@ -616,8 +1896,12 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid value"),
         };
 
-        let old_value = self.storage.get(&key);
-        self.storage.set(key, value);
+        if let Err(e) = self.check_type(&key, "string") {
+            return e;
+        }
+
+        let old_value = self.db().get(&key);
+        self.db().set(key, value);
 
         match old_value {
             Some(v) => RespValue::bulk_string(v),
@@ -636,8 +1920,12 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        let value = self.storage.get(&key);
-        self.storage.delete(&key);
+        if let Err(e) = self.check_type(&key, "string") {
+            return e;
+        }
+
+        let value = self.db().get(&key);
+        self.db().delete(&key);
 
         match value {
             Some(v) => RespValue::bulk_string(v),
@@ -645,6 +1933,218 @@ impl CommandHandler {
         }
     }
 
+    // ========================================================================
+    // Bitmap Commands
+    // ========================================================================
+
+    /// SETBIT key offset value
+    fn cmd_setbit(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 3 {
+            return RespValue::error("ERR wrong number of arguments for 'SETBIT' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let offset = match self.get_bit_offset(&args[1]) {
+            Ok(o) => o,
+            Err(e) => return e,
+        };
+
+        let bit = match self.get_integer(&args[2]) {
+            Some(0) => false,
+            Some(1) => true,
+            _ => return RespValue::error("ERR bit is not an integer or out of range"),
+        };
+
+        if let Err(e) = self.check_type(&key, "string") {
+            return e;
+        }
+
+        match self.db().setbit(&key, offset, bit) {
+            Ok(old) => RespValue::integer(old as i64),
+            Err(e) => Self::storage_error(e),
+        }
+    }
+
+    /// GETBIT key offset
+    fn cmd_getbit(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::error("ERR wrong number of arguments for 'GETBIT' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let offset = match self.get_bit_offset(&args[1]) {
+            Ok(o) => o,
+            Err(e) => return e,
+        };
+
+        if let Err(e) = self.check_type(&key, "string") {
+            return e;
+        }
+
+        RespValue::integer(self.db().getbit(&key, offset) as i64)
+    }
+
+    /// BITCOUNT key [start end [BYTE|BIT]]
+    fn cmd_bitcount(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'BITCOUNT' command");
+        }
+        if args.len() == 2 || args.len() > 4 {
+            return RespValue::error("ERR syntax error");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let range = if args.len() > 1 {
+            match self.get_bit_range(&args[1], &args[2], args.get(3)) {
+                Ok(r) => Some(r),
+                Err(e) => return e,
+            }
+        } else {
+            None
+        };
+
+        if let Err(e) = self.check_type(&key, "string") {
+            return e;
+        }
+
+        RespValue::integer(self.db().bitcount(&key, range) as i64)
+    }
+
+    /// BITPOS key bit [start [end [BYTE|BIT]]]
+    fn cmd_bitpos(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 || args.len() > 5 {
+            return RespValue::error("ERR wrong number of arguments for 'BITPOS' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let bit = match self.get_integer(&args[1]) {
+            Some(0) => false,
+            Some(1) => true,
+            _ => return RespValue::error("ERR The bit argument must be 1 or 0."),
+        };
+
+        // A start without an end searches to the end of the string
+        let end_given = args.len() > 3;
+        let range = match args.len() {
+            2 => None,
+            3 => match self.get_integer(&args[2]) {
+                Some(start) => Some(BitRange {
+                    start,
+                    end: -1,
+                    unit: BitUnit::Byte,
+                }),
+                None => return RespValue::error("ERR value is not an integer or out of range"),
+            },
+            _ => match self.get_bit_range(&args[2], &args[3], args.get(4)) {
+                Ok(r) => Some(r),
+                Err(e) => return e,
+            },
+        };
+
+        if let Err(e) = self.check_type(&key, "string") {
+            return e;
+        }
+
+        RespValue::integer(self.db().bitpos(&key, bit, range, end_given))
+    }
+
+    // ========================================================================
+    // HyperLogLog Commands
+    // ========================================================================
+
+    /// PFADD key [element ...]
+    fn cmd_pfadd(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'PFADD' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        if let Err(e) = self.check_type(&key, "string") {
+            return e;
+        }
+
+        let mut elements = Vec::with_capacity(args.len() - 1);
+        for arg in &args[1..] {
+            match self.get_bytes(arg) {
+                Some(e) => elements.push(e),
+                None => return RespValue::error("ERR invalid element"),
+            }
+        }
+
+        match self.db().pfadd(&key, &elements) {
+            Ok(changed) => RespValue::integer(changed as i64),
+            Err(e) => RespValue::error(format!("WRONGTYPE {}", e)),
+        }
+    }
+
+    /// PFCOUNT key [key ...]
+    fn cmd_pfcount(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'PFCOUNT' command");
+        }
+
+        let mut keys = Vec::with_capacity(args.len());
+        for arg in args {
+            let key = match self.get_bytes(arg) {
+                Some(k) => k,
+                None => return RespValue::error("ERR invalid key"),
+            };
+            if let Err(e) = self.check_type(&key, "string") {
+                return e;
+            }
+            keys.push(key);
+        }
+
+        match self.db().pfcount(&keys) {
+            Ok(count) => RespValue::integer(count as i64),
+            Err(e) => RespValue::error(format!("WRONGTYPE {}", e)),
+        }
+    }
+
+    /// PFMERGE destkey [sourcekey ...]
+    fn cmd_pfmerge(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'PFMERGE' command");
+        }
+
+        let mut keys = Vec::with_capacity(args.len());
+        for arg in args {
+            let key = match self.get_bytes(arg) {
+                Some(k) => k,
+                None => return RespValue::error("ERR invalid key"),
+            };
+            if let Err(e) = self.check_type(&key, "string") {
+                return e;
+            }
+            keys.push(key);
+        }
+
+        match self.db().pfmerge(&keys[0], &keys[1..]) {
+            Ok(()) => RespValue::ok(),
+            Err(e) => RespValue::error(format!("WRONGTYPE {}", e)),
+        }
+    }
+
     // ========================================================================
     // List Commands
     // ========================================================================
@@ -660,11 +2160,8 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        // Check if key exists as a string (type error)
-        if self.storage.exists(&key) {
-            return RespValue::error(
-                "WRONGTYPE Operation against a key holding the wrong kind of value",
-            );
+        if let Err(e) = self.check_type(&key, "list") {
+            return e;
         }
 
         let mut values = Vec::with_capacity(args.len() - 1);
@@ -675,7 +2172,7 @@ impl CommandHandler {
             }
         }
 
-        let len = self.storage.lpush(key, values);
+        let len = self.db().lpush(key, values);
         RespValue::integer(len as i64)
     }
 
@@ -690,11 +2187,8 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        // Check if key exists as a string (type error)
-        if self.storage.exists(&key) {
-            return RespValue::error(
-                "WRONGTYPE Operation against a key holding the wrong kind of value",
-            );
+        if let Err(e) = self.check_type(&key, "list") {
+            return e;
         }
 
         let mut values = Vec::with_capacity(args.len() - 1);
@@ -705,7 +2199,7 @@ impl CommandHandler {
             }
         }
 
-        let len = self.storage.rpush(key, values);
+        let len = self.db().rpush(key, values);
         RespValue::integer(len as i64)
     }
 
@@ -720,14 +2214,11 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        // Check if key exists as a string (type error)
-        if self.storage.exists(&key) {
-            return RespValue::error(
-                "WRONGTYPE Operation against a key holding the wrong kind of value",
-            );
+        if let Err(e) = self.check_type(&key, "list") {
+            return e;
         }
 
-        match self.storage.lpop(&key) {
+        match self.db().lpop(&key) {
             Some(v) => RespValue::bulk_string(v),
             None => RespValue::null(),
         }
@@ -744,14 +2235,11 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        // Check if key exists as a string (type error)
-        if self.storage.exists(&key) {
-            return RespValue::error(
-                "WRONGTYPE Operation against a key holding the wrong kind of value",
-            );
+        if let Err(e) = self.check_type(&key, "list") {
+            return e;
         }
 
-        match self.storage.rpop(&key) {
+        match self.db().rpop(&key) {
             Some(v) => RespValue::bulk_string(v),
             None => RespValue::null(),
         }
@@ -768,14 +2256,11 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        // Check if key exists as a string (type error)
-        if self.storage.exists(&key) {
-            return RespValue::error(
-                "WRONGTYPE Operation against a key holding the wrong kind of value",
-            );
+        if let Err(e) = self.check_type(&key, "list") {
+            return e;
         }
 
-        let len = self.storage.llen(&key);
+        let len = self.db().llen(&key);
         RespValue::integer(len as i64)
     }
 
@@ -790,11 +2275,8 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        // Check if key exists as a string (type error)
-        if self.storage.exists(&key) {
-            return RespValue::error(
-                "WRONGTYPE Operation against a key holding the wrong kind of value",
-            );
+        if let Err(e) = self.check_type(&key, "list") {
+            return e;
         }
 
         let index = match self.get_integer(&args[1]) {
@@ -802,7 +2284,7 @@ impl CommandHandler {
             None => return RespValue::error("ERR value is not an integer or out of range"),
         };
 
-        match self.storage.lindex(&key, index) {
+        match self.db().lindex(&key, index) {
             Some(v) => RespValue::bulk_string(v),
             None => RespValue::null(),
         }
@@ -819,11 +2301,8 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        // Check if key exists as a string (type error)
-        if self.storage.exists(&key) {
-            return RespValue::error(
-                "WRONGTYPE Operation against a key holding the wrong kind of value",
-            );
+        if let Err(e) = self.check_type(&key, "list") {
+            return e;
         }
 
         let start = match self.get_integer(&args[1]) {
@@ -836,7 +2315,7 @@ impl CommandHandler {
             None => return RespValue::error("ERR value is not an integer or out of range"),
         };
 
-        let elements = self.storage.lrange(&key, start, stop);
+        let elements = self.db().lrange(&key, start, stop);
         let values: Vec<RespValue> = elements.into_iter().map(RespValue::bulk_string).collect();
         RespValue::array(values)
     }
@@ -852,11 +2331,8 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        // Check if key exists as a string (type error)
-        if self.storage.exists(&key) {
-            return RespValue::error(
-                "WRONGTYPE Operation against a key holding the wrong kind of value",
-            );
+        if let Err(e) = self.check_type(&key, "list") {
+            return e;
         }
 
         let index = match self.get_integer(&args[1]) {
@@ -869,7 +2345,7 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid value"),
         };
 
-        match self.storage.lset(&key, index, value) {
+        match self.db().lset(&key, index, value) {
             Ok(()) => RespValue::ok(),
             Err(e) => RespValue::error(e),
         }
@@ -886,11 +2362,8 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        // Check if key exists as a string (type error)
-        if self.storage.exists(&key) {
-            return RespValue::error(
-                "WRONGTYPE Operation against a key holding the wrong kind of value",
-            );
+        if let Err(e) = self.check_type(&key, "list") {
+            return e;
         }
 
         let count = match self.get_integer(&args[1]) {
@@ -903,18 +2376,14 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid value"),
         };
 
-        let removed = self.storage.lrem(&key, count, &value);
+        let removed = self.db().lrem(&key, count, &value);
         RespValue::integer(removed as i64)
     }
 
-    // ========================================================================
-    // Key Commands
-    // ========================================================================
-
-    /// EXPIRE key seconds
-    fn cmd_expire(&self, args: &[RespValue]) -> RespValue {
-        if args.len() != 2 {
-            return RespValue::error("ERR wrong number of arguments for 'EXPIRE' command");
+    /// LTRIM key start stop
+    fn cmd_ltrim(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 3 {
+            return RespValue::error("ERR wrong number of arguments for 'LTRIM' command");
         }
 
         let key = match self.get_bytes(&args[0]) {
@@ -922,63 +2391,122 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        let seconds = match self.get_integer(&args[1]) {
-            Some(s) => s,
-            None => return RespValue::error("ERR value is not an integer"),
+        if let Err(e) = self.check_type(&key, "list") {
+            return e;
+        }
+
+        let start = match self.get_integer(&args[1]) {
+            Some(s) => s,
+            None => return RespValue::error("ERR value is not an integer or out of range"),
         };
 
-        if seconds <= 0 {
-            // Non-positive TTL deletes the key
-            if self.storage.delete(&key) {
-                return RespValue::integer(1);
-            }
-            return RespValue::integer(0);
+        let stop = match self.get_integer(&args[2]) {
+            Some(s) => s,
+            None => return RespValue::error("ERR value is not an integer or out of range"),
+        };
+
+        self.db().ltrim(&key, start, stop);
+        RespValue::ok()
+    }
+
+    /// LMOVE source destination LEFT|RIGHT LEFT|RIGHT
+    fn cmd_lmove(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 4 {
+            return RespValue::error("ERR wrong number of arguments for 'LMOVE' command");
         }
 
-        if self
-            .storage
-            .expire(&key, Duration::from_secs(seconds as u64))
-        {
-            RespValue::integer(1)
-        } else {
-            RespValue::integer(0)
+        let ends: Vec<ListEnd> = args[2..]
+            .iter()
+            .filter_map(|arg| match self.get_string(arg)?.to_uppercase().as_str() {
+                "LEFT" => Some(ListEnd::Left),
+                "RIGHT" => Some(ListEnd::Right),
+                _ => None,
+            })
+            .collect();
+        if ends.len() != 2 {
+            return RespValue::error("ERR syntax error");
         }
+
+        self.list_move(&args[0], &args[1], ends[0], ends[1])
     }
 
-    /// PEXPIRE key milliseconds
-    fn cmd_pexpire(&self, args: &[RespValue]) -> RespValue {
+    /// RPOPLPUSH source destination
+    fn cmd_rpoplpush(&self, args: &[RespValue]) -> RespValue {
         if args.len() != 2 {
-            return RespValue::error("ERR wrong number of arguments for 'PEXPIRE' command");
+            return RespValue::error("ERR wrong number of arguments for 'RPOPLPUSH' command");
         }
 
-        let key = match self.get_bytes(&args[0]) {
+        self.list_move(&args[0], &args[1], ListEnd::Right, ListEnd::Left)
+    }
+
+    /// Shared implementation of LMOVE and RPOPLPUSH.
+    fn list_move(&self, src: &RespValue, dst: &RespValue, from: ListEnd, to: ListEnd) -> RespValue {
+        let src = match self.get_bytes(src) {
             Some(k) => k,
             None => return RespValue::error("ERR invalid key"),
         };
 
-        let ms = match self.get_integer(&args[1]) {
-            Some(m) => m,
-            None => return RespValue::error("ERR value is not an integer"),
+        let dst = match self.get_bytes(dst) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
         };
 
-        if ms <= 0 {
-            if self.storage.delete(&key) {
-                return RespValue::integer(1);
-            }
-            return RespValue::integer(0);
+        match self.db().lmove(&src, dst, from, to) {
+            Ok(Some(value)) => RespValue::bulk_string(value),
+            Ok(None) => RespValue::null(),
+            Err(e) => Self::storage_error(e),
         }
+    }
 
-        if self.storage.expire(&key, Duration::from_millis(ms as u64)) {
-            RespValue::integer(1)
+    // ========================================================================
+    // Hash Commands
+    // ========================================================================
+
+    /// HSET key field value [field value ...]
+    ///
+    /// HMSET is accepted as an alias and replies with OK instead of a count.
+    fn cmd_hset(&self, cmd: &str, args: &[RespValue]) -> RespValue {
+        if args.len() < 3 || args.len() % 2 != 1 {
+            return RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                cmd
+            ));
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        if let Err(e) = self.check_type(&key, "hash") {
+            return e;
+        }
+
+        let mut pairs = Vec::with_capacity((args.len() - 1) / 2);
+        for pair in args[1..].chunks(2) {
+            let field = match self.get_bytes(&pair[0]) {
+                Some(f) => f,
+                None => return RespValue::error("ERR invalid field"),
+            };
+            let value = match self.get_bytes(&pair[1]) {
+                Some(v) => v,
+                None => return RespValue::error("ERR invalid value"),
+            };
+            pairs.push((field, value));
+        }
+
+        let added = self.db().hset(key, pairs);
+        if cmd == "HMSET" {
+            RespValue::ok()
         } else {
-            RespValue::integer(0)
+            RespValue::integer(added as i64)
         }
     }
 
-    /// EXPIREAT key timestamp
-    fn cmd_expireat(&self, args: &[RespValue]) -> RespValue {
+    /// HGET key field
+    fn cmd_hget(&self, args: &[RespValue]) -> RespValue {
         if args.len() != 2 {
-            return RespValue::error("ERR wrong number of arguments for 'EXPIREAT' command");
+            return RespValue::error("ERR wrong number of arguments for 'HGET' command");
         }
 
         let key = match self.get_bytes(&args[0]) {
@@ -986,39 +2514,25 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        let timestamp = match self.get_integer(&args[1]) {
-            Some(t) => t,
-            None => return RespValue::error("ERR value is not an integer"),
-        };
-
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or(Duration::ZERO)
-            .as_secs() as i64;
-
-        let ttl_secs = timestamp - now;
-
-        if ttl_secs <= 0 {
-            if self.storage.delete(&key) {
-                return RespValue::integer(1);
-            }
-            return RespValue::integer(0);
+        if let Err(e) = self.check_type(&key, "hash") {
+            return e;
         }
 
-        if self
-            .storage
-            .expire(&key, Duration::from_secs(ttl_secs as u64))
-        {
-            RespValue::integer(1)
-        } else {
-            RespValue::integer(0)
+        let field = match self.get_bytes(&args[1]) {
+            Some(f) => f,
+            None => return RespValue::error("ERR invalid field"),
+        };
+
+        match self.db().hget(&key, &field) {
+            Some(v) => RespValue::bulk_string(v),
+            None => RespValue::null(),
         }
     }
 
-    /// TTL key
-    fn cmd_ttl(&self, args: &[RespValue]) -> RespValue {
-        if args.len() != 1 {
-            return RespValue::error("ERR wrong number of arguments for 'TTL' command");
+    /// HDEL key field [field ...]
+    fn cmd_hdel(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::error("ERR wrong number of arguments for 'HDEL' command");
         }
 
         let key = match self.get_bytes(&args[0]) {
@@ -1026,16 +2540,20 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        match self.storage.ttl(&key) {
-            Some(ttl) => RespValue::integer(ttl),
-            None => RespValue::integer(-2), // Key doesn't exist
+        if let Err(e) = self.check_type(&key, "hash") {
+            return e;
         }
+
+        let fields: Vec<Bytes> = args[1..].iter().filter_map(|a| self.get_bytes(a)).collect();
+
+        let removed = self.db().hdel(&key, &fields);
+        RespValue::integer(removed as i64)
     }
 
-    /// PTTL key
-    fn cmd_pttl(&self, args: &[RespValue]) -> RespValue {
+    /// HLEN key
+    fn cmd_hlen(&self, args: &[RespValue]) -> RespValue {
         if args.len() != 1 {
-            return RespValue::error("ERR wrong number of arguments for 'PTTL' command");
+            return RespValue::error("ERR wrong number of arguments for 'HLEN' command");
         }
 
         let key = match self.get_bytes(&args[0]) {
@@ -1043,16 +2561,17 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        match self.storage.pttl(&key) {
-            Some(ttl) => RespValue::integer(ttl),
-            None => RespValue::integer(-2),
+        if let Err(e) = self.check_type(&key, "hash") {
+            return e;
         }
+
+        RespValue::integer(self.db().hlen(&key) as i64)
     }
 
-    /// PERSIST key
-    fn cmd_persist(&self, args: &[RespValue]) -> RespValue {
-        if args.len() != 1 {
-            return RespValue::error("ERR wrong number of arguments for 'PERSIST' command");
+    /// HEXISTS key field
+    fn cmd_hexists(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::error("ERR wrong number of arguments for 'HEXISTS' command");
         }
 
         let key = match self.get_bytes(&args[0]) {
@@ -1060,34 +2579,51 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        if self.storage.persist(&key) {
+        if let Err(e) = self.check_type(&key, "hash") {
+            return e;
+        }
+
+        let field = match self.get_bytes(&args[1]) {
+            Some(f) => f,
+            None => return RespValue::error("ERR invalid field"),
+        };
+
+        if self.db().hexists(&key, &field) {
             RespValue::integer(1)
         } else {
             RespValue::integer(0)
         }
     }
 
-    /// KEYS pattern
-    fn cmd_keys(&self, args: &[RespValue]) -> RespValue {
+    /// HGETALL key
+    fn cmd_hgetall(&self, args: &[RespValue]) -> RespValue {
         if args.len() != 1 {
-            return RespValue::error("ERR wrong number of arguments for 'KEYS' command");
+            return RespValue::error("ERR wrong number of arguments for 'HGETALL' command");
         }
 
-        let pattern = match self.get_string(&args[0]) {
-            Some(p) => p,
-            None => return RespValue::error("ERR invalid pattern"),
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
         };
 
-        let keys = self.storage.keys(&pattern);
-        let values: Vec<RespValue> = keys.into_iter().map(RespValue::bulk_string).collect();
+        if let Err(e) = self.check_type(&key, "hash") {
+            return e;
+        }
+
+        let values: Vec<RespValue> = self
+            .db()
+            .hgetall(&key)
+            .into_iter()
+            .flat_map(|(f, v)| [RespValue::bulk_string(f), RespValue::bulk_string(v)])
+            .collect();
 
         RespValue::array(values)
     }
 
-    /// TYPE key
-    fn cmd_type(&self, args: &[RespValue]) -> RespValue {
-        if args.len() != 1 {
-            return RespValue::error("ERR wrong number of arguments for 'TYPE' command");
+    /// HINCRBY key field increment
+    fn cmd_hincrby(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 3 {
+            return RespValue::error("ERR wrong number of arguments for 'HINCRBY' command");
         }
 
         let key = match self.get_bytes(&args[0]) {
@@ -1095,13 +2631,30 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        RespValue::simple_string(self.storage.key_type(&key))
+        if let Err(e) = self.check_type(&key, "hash") {
+            return e;
+        }
+
+        let field = match self.get_bytes(&args[1]) {
+            Some(f) => f,
+            None => return RespValue::error("ERR invalid field"),
+        };
+
+        let delta = match self.get_integer(&args[2]) {
+            Some(d) => d,
+            None => return RespValue::error("ERR value is not an integer or out of range"),
+        };
+
+        match self.db().hincrby(&key, &field, delta) {
+            Ok(n) => RespValue::integer(n),
+            Err(e) => Self::storage_error(e),
+        }
     }
 
-    /// RENAME key newkey
-    fn cmd_rename(&self, args: &[RespValue]) -> RespValue {
-        if args.len() != 2 {
-            return RespValue::error("ERR wrong number of arguments for 'RENAME' command");
+    /// HINCRBYFLOAT key field increment
+    fn cmd_hincrbyfloat(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 3 {
+            return RespValue::error("ERR wrong number of arguments for 'HINCRBYFLOAT' command");
         }
 
         let key = match self.get_bytes(&args[0]) {
@@ -1109,41 +2662,30 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        let newkey = match self.get_bytes(&args[1]) {
-            Some(k) => k,
-            None => return RespValue::error("ERR invalid new key"),
-        };
+        if let Err(e) = self.check_type(&key, "hash") {
+            return e;
+        }
 
-        // Get the value (and TTL if exists)
-        let entry = match self.storage.get_entry(&key) {
-            Some(e) => e,
-            None => return RespValue::error("ERR no such key"),
+        let field = match self.get_bytes(&args[1]) {
+            Some(f) => f,
+            None => return RespValue::error("ERR invalid field"),
         };
 
-        // Delete old key
-        self.storage.delete(&key);
+        let delta = match self.get_float(&args[2]) {
+            Some(d) => d,
+            None => return RespValue::error("ERR value is not a valid float"),
+        };
 
-        // Set new key with same value and TTL
-        if let Some(expires_at) = entry.expires_at {
-            let now = std::time::Instant::now();
-            if expires_at > now {
-                let remaining = expires_at - now;
-                self.storage.set_with_ttl(newkey, entry.value, remaining);
-            } else {
-                // Already expired, don't set
-                return RespValue::ok();
-            }
-        } else {
-            self.storage.set(newkey, entry.value);
+        match self.db().hincrbyfloat(&key, &field, delta) {
+            Ok(v) => RespValue::bulk_string(v),
+            Err(e) => Self::storage_error(e),
         }
-
-        RespValue::ok()
     }
 
-    /// RENAMENX key newkey
-    fn cmd_renamenx(&self, args: &[RespValue]) -> RespValue {
-        if args.len() != 2 {
-            return RespValue::error("ERR wrong number of arguments for 'RENAMENX' command");
+    /// HSETNX key field value
+    fn cmd_hsetnx(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 3 {
+            return RespValue::error("ERR wrong number of arguments for 'HSETNX' command");
         }
 
         let key = match self.get_bytes(&args[0]) {
@@ -1151,371 +2693,7930 @@ impl CommandHandler {
             None => return RespValue::error("ERR invalid key"),
         };
 
-        let newkey = match self.get_bytes(&args[1]) {
-            Some(k) => k,
-            None => return RespValue::error("ERR invalid new key"),
+        if let Err(e) = self.check_type(&key, "hash") {
+            return e;
+        }
+
+        let field = match self.get_bytes(&args[1]) {
+            Some(f) => f,
+            None => return RespValue::error("ERR invalid field"),
+        };
+
+        let value = match self.get_bytes(&args[2]) {
+            Some(v) => v,
+            None => return RespValue::error("ERR invalid value"),
         };
 
-        if !self.storage.exists(&key) {
-            return RespValue::error("ERR no such key");
+        if self.db().hsetnx(key, field, value) {
+            RespValue::integer(1)
+        } else {
+            RespValue::integer(0)
         }
+    }
 
-        if self.storage.exists(&newkey) {
-            return RespValue::integer(0);
+    /// HRANDFIELD key [count [WITHVALUES]]
+    fn cmd_hrandfield(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() || args.len() > 3 {
+            return RespValue::error("ERR wrong number of arguments for 'HRANDFIELD' command");
         }
 
-        let entry = self.storage.get_entry(&key).unwrap();
-        self.storage.delete(&key);
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
 
-        if let Some(expires_at) = entry.expires_at {
-            let now = std::time::Instant::now();
-            if expires_at > now {
-                let remaining = expires_at - now;
-                self.storage.set_with_ttl(newkey, entry.value, remaining);
-            }
-        } else {
-            self.storage.set(newkey, entry.value);
+        if let Err(e) = self.check_type(&key, "hash") {
+            return e;
         }
 
-        RespValue::integer(1)
+        // Without a count, reply with a single field (or nil)
+        if args.len() == 1 {
+            return match self.db().hrandfield(&key, 1).into_iter().next() {
+                Some((field, _)) => RespValue::bulk_string(field),
+                None => RespValue::null(),
+            };
+        }
+
+        let count = match self.get_integer(&args[1]) {
+            // Like Redis, so the repeats a negative count asks for stay
+            // countable
+            Some(c) if c < -(i64::MAX / 2) => return RespValue::error("ERR value is out of range"),
+            Some(c) => c,
+            None => return RespValue::error("ERR value is not an integer or out of range"),
+        };
+
+        let with_values = match args.get(2).map(|a| self.get_string(a)) {
+            None => false,
+            Some(Some(opt)) if opt.eq_ignore_ascii_case("WITHVALUES") => true,
+            Some(_) => return RespValue::error("ERR syntax error"),
+        };
+
+        let values: Vec<RespValue> = self
+            .db()
+            .hrandfield(&key, count)
+            .into_iter()
+            .flat_map(|(f, v)| {
+                let mut items = vec![RespValue::bulk_string(f)];
+                if with_values {
+                    items.push(RespValue::bulk_string(v));
+                }
+                items
+            })
+            .collect();
+
+        RespValue::array(values)
     }
 
     // ========================================================================
-    // Server Commands
+    // Set Commands
     // ========================================================================
 
-    /// PING [message]
-    fn cmd_ping(&self, args: &[RespValue]) -> RespValue {
-        if args.is_empty() {
-            RespValue::pong()
-        } else {
-            match self.get_bytes(&args[0]) {
-                Some(msg) => RespValue::bulk_string(msg),
-                None => RespValue::pong(),
-            }
+    /// SADD key member [member ...]
+    fn cmd_sadd(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::error("ERR wrong number of arguments for 'SADD' command");
         }
-    }
 
-    /// ECHO message
-    fn cmd_echo(&self, args: &[RespValue]) -> RespValue {
-        if args.len() != 1 {
-            return RespValue::error("ERR wrong number of arguments for 'ECHO' command");
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        if let Err(e) = self.check_type(&key, "set") {
+            return e;
         }
 
-        match self.get_bytes(&args[0]) {
-            Some(msg) => RespValue::bulk_string(msg),
-            None => RespValue::error("ERR invalid message"),
+        let mut members = Vec::with_capacity(args.len() - 1);
+        for arg in &args[1..] {
+            match self.get_bytes(arg) {
+                Some(m) => members.push(m),
+                None => return RespValue::error("ERR invalid member"),
+            }
         }
+
+        let added = self.db().sadd(key, members);
+        RespValue::integer(added as i64)
     }
 
-    /// INFO [section]
-    fn cmd_info(&self, _args: &[RespValue]) -> RespValue {
-        let stats = self.storage.stats();
-        let mem = self.storage.memory_info();
-        let uptime = self.start_time.elapsed().as_secs();
+    /// SREM key member [member ...]
+    fn cmd_srem(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::error("ERR wrong number of arguments for 'SREM' command");
+        }
 
-        let info = format!(
-            "# Server\r\n\
-             flashkv_version:0.1.0\r\n\
-             rust_version:{}\r\n\
-             os:{}\r\n\
-             uptime_in_seconds:{}\r\n\
-             \r\n\
-             # Stats\r\n\
-             total_connections_received:0\r\n\
-             total_commands_processed:{}\r\n\
-             \r\n\
-             # Keyspace\r\n\
-             db0:keys={},expires=0\r\n\
-             \r\n\
-             # Memory\r\n\
-             used_memory:{}\r\n\
-             used_memory_human:{}KB\r\n\
-             \r\n\
-             # Operations\r\n\
-             get_ops:{}\r\n\
-             set_ops:{}\r\n\
-             del_ops:{}\r\n\
-             expired_keys:{}\r\n",
-            env!("CARGO_PKG_RUST_VERSION").to_string(),
-            std::env::consts::OS,
-            uptime,
-            stats.get_ops + stats.set_ops + stats.del_ops,
-            stats.keys,
-            mem.used_memory,
-            mem.used_memory / 1024,
-            stats.get_ops,
-            stats.set_ops,
-            stats.del_ops,
-            stats.expired,
-        );
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
 
-        RespValue::bulk_string(Bytes::from(info))
-    }
+        if let Err(e) = self.check_type(&key, "set") {
+            return e;
+        }
 
-    /// DBSIZE
-    fn cmd_dbsize(&self, _args: &[RespValue]) -> RespValue {
-        RespValue::integer(self.storage.len() as i64)
-    }
+        let members: Vec<Bytes> = args[1..].iter().filter_map(|a| self.get_bytes(a)).collect();
 
-    /// FLUSHDB / FLUSHALL
-    fn cmd_flushdb(&self, _args: &[RespValue]) -> RespValue {
-        self.storage.flush();
-        RespValue::ok()
+        let removed = self.db().srem(&key, &members);
+        RespValue::integer(removed as i64)
     }
 
-    /// COMMAND
-    fn cmd_command(&self, _args: &[RespValue]) -> RespValue {
-        // Return a simple list of supported commands
-        let commands = vec![
-            "SET", "GET", "DEL", "EXISTS", "EXPIRE", "TTL", "PTTL", "INCR", "INCRBY", "DECR",
-            "DECRBY", "APPEND", "STRLEN", "MSET", "MGET", "SETNX", "SETEX", "PSETEX", "GETSET",
-            "PEXPIRE", "PERSIST", "KEYS", "TYPE", "RENAME", "RENAMENX", "PING", "ECHO", "INFO",
-            "DBSIZE", "FLUSHDB", "FLUSHALL", "COMMAND", "CONFIG", "TIME", "QUIT", "GETDEL",
-            "EXPIREAT",
-        ];
+    /// SMEMBERS key
+    fn cmd_smembers(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::error("ERR wrong number of arguments for 'SMEMBERS' command");
+        }
 
-        let values: Vec<RespValue> = commands
-            .into_iter()
-            .map(|c| RespValue::bulk_string(Bytes::from(c)))
-            .collect();
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
 
+        if let Err(e) = self.check_type(&key, "set") {
+            return e;
+        }
+
+        let members = self.db().smembers(&key);
+        let values: Vec<RespValue> = members.into_iter().map(RespValue::bulk_string).collect();
         RespValue::array(values)
     }
 
-    /// CONFIG GET parameter
-    fn cmd_config(&self, args: &[RespValue]) -> RespValue {
-        if args.is_empty() {
-            return RespValue::error("ERR wrong number of arguments for 'CONFIG' command");
+    /// SISMEMBER key member
+    fn cmd_sismember(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::error("ERR wrong number of arguments for 'SISMEMBER' command");
         }
 
-        let subcommand = match self.get_string(&args[0]) {
-            Some(s) => s.to_uppercase(),
-            None => return RespValue::error("ERR invalid subcommand"),
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
         };
 
-        match subcommand.as_str() {
-            "GET" => {
-                if args.len() < 2 {
-                    return RespValue::error("ERR wrong number of arguments for 'CONFIG GET'");
-                }
-                // Return empty array for most config gets (we don't have config)
-                RespValue::array(vec![])
-            }
-            "SET" => {
-                // We don't support config set
-                RespValue::ok()
+        if let Err(e) = self.check_type(&key, "set") {
+            return e;
+        }
+
+        let member = match self.get_bytes(&args[1]) {
+            Some(m) => m,
+            None => return RespValue::error("ERR invalid member"),
+        };
+
+        if self.db().sismember(&key, &member) {
+            RespValue::integer(1)
+        } else {
+            RespValue::integer(0)
+        }
+    }
+
+    /// SMISMEMBER key member [member ...]
+    fn cmd_smismember(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::error("ERR wrong number of arguments for 'SMISMEMBER' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        if let Err(e) = self.check_type(&key, "set") {
+            return e;
+        }
+
+        let mut members = Vec::with_capacity(args.len() - 1);
+        for arg in &args[1..] {
+            match self.get_bytes(arg) {
+                Some(m) => members.push(m),
+                None => return RespValue::error("ERR invalid member"),
             }
-            _ => RespValue::error(format!("ERR unknown CONFIG subcommand '{}'", subcommand)),
         }
+
+        let values: Vec<RespValue> = self
+            .db()
+            .smismember(&key, &members)
+            .into_iter()
+            .map(|found| RespValue::integer(found as i64))
+            .collect();
+        RespValue::array(values)
     }
 
-    /// TIME
-    fn cmd_time(&self, _args: &[RespValue]) -> RespValue {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or(Duration::ZERO);
+    /// SCARD key
+    fn cmd_scard(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::error("ERR wrong number of arguments for 'SCARD' command");
+        }
 
-        let secs = now.as_secs().to_string();
-        let micros = (now.subsec_micros()).to_string();
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
 
-        RespValue::array(vec![
-            RespValue::bulk_string(Bytes::from(secs)),
-            RespValue::bulk_string(Bytes::from(micros)),
-        ])
+        if let Err(e) = self.check_type(&key, "set") {
+            return e;
+        }
+
+        RespValue::integer(self.db().scard(&key) as i64)
     }
 
-    /// DEBUG commands (for testing)
-    fn cmd_debug(&self, args: &[RespValue]) -> RespValue {
+    /// Collects set keys from the arguments, rejecting keys of other types.
+    fn get_set_keys(&self, args: &[RespValue]) -> Result<Vec<Bytes>, RespValue> {
+        let mut keys = Vec::with_capacity(args.len());
+        for arg in args {
+            let key = self
+                .get_bytes(arg)
+                .ok_or_else(|| RespValue::error("ERR invalid key"))?;
+            self.check_type(&key, "set")?;
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    /// SINTER / SUNION / SDIFF key [key ...]
+    fn cmd_set_op(&self, cmd: &str, op: SetOp, args: &[RespValue]) -> RespValue {
         if args.is_empty() {
-            return RespValue::error("ERR wrong number of arguments for 'DEBUG' command");
+            return RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                cmd
+            ));
         }
 
-        let subcommand = match self.get_string(&args[0]) {
-            Some(s) => s.to_uppercase(),
-            None => return RespValue::error("ERR invalid subcommand"),
+        let keys = match self.get_set_keys(args) {
+            Ok(k) => k,
+            Err(e) => return e,
         };
 
-        match subcommand.as_str() {
-            "SLEEP" => {
-                if args.len() < 2 {
-                    return RespValue::error("ERR wrong number of arguments for 'DEBUG SLEEP'");
+        let members = self.db().set_op(op, &keys);
+        let values: Vec<RespValue> = members.into_iter().map(RespValue::bulk_string).collect();
+        RespValue::array(values)
+    }
+
+    /// SINTERSTORE / SUNIONSTORE / SDIFFSTORE destination key [key ...]
+    fn cmd_set_op_store(&self, cmd: &str, op: SetOp, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                cmd
+            ));
+        }
+
+        let dest = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let keys = match self.get_set_keys(&args[1..]) {
+            Ok(k) => k,
+            Err(e) => return e,
+        };
+
+        let len = self.db().set_op_store(op, dest, &keys);
+        RespValue::integer(len as i64)
+    }
+
+    /// SINTERCARD numkeys key [key ...] [LIMIT limit]
+    fn cmd_sintercard(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::error("ERR wrong number of arguments for 'SINTERCARD' command");
+        }
+
+        let numkeys = match self.get_integer(&args[0]) {
+            Some(n) if n > 0 => n as usize,
+            _ => return RespValue::error("ERR numkeys should be greater than 0"),
+        };
+
+        if args.len() < 1 + numkeys {
+            return RespValue::error("ERR Number of keys can't be greater than number of args");
+        }
+
+        let keys = match self.get_set_keys(&args[1..1 + numkeys]) {
+            Ok(k) => k,
+            Err(e) => return e,
+        };
+
+        let mut limit = 0;
+        let mut i = 1 + numkeys;
+        while i < args.len() {
+            match self.get_string(&args[i]) {
+                Some(opt) if opt.eq_ignore_ascii_case("LIMIT") && i + 1 < args.len() => {
+                    limit = match self.get_integer(&args[i + 1]) {
+                        Some(l) if l >= 0 => l as usize,
+                        _ => return RespValue::error("ERR LIMIT can't be negative"),
+                    };
+                    i += 2;
                 }
-                // We don't actually sleep (it would block), just return OK
-                RespValue::ok()
+                _ => return RespValue::error("ERR syntax error"),
             }
-            _ => RespValue::error(format!("ERR unknown DEBUG subcommand '{}'", subcommand)),
         }
+
+        RespValue::integer(self.db().sintercard(&keys, limit) as i64)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // ========================================================================
+    // Sorted Set Commands
+    // ========================================================================
 
-    fn create_handler() -> CommandHandler {
-        let storage = Arc::new(StorageEngine::new());
-        CommandHandler::new(storage)
+    /// ZADD key [NX|XX] [GT|LT] [CH] [INCR] score member [score member ...]
+    fn cmd_zadd(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 3 {
+            return RespValue::error("ERR wrong number of arguments for 'ZADD' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        // Parse leading flags
+        let mut flags = ZAddFlags::default();
+        let mut ch = false;
+        let mut i = 1;
+        while i < args.len() {
+            match self
+                .get_string(&args[i])
+                .map(|s| s.to_uppercase())
+                .as_deref()
+            {
+                Some("NX") => flags.nx = true,
+                Some("XX") => flags.xx = true,
+                Some("GT") => flags.gt = true,
+                Some("LT") => flags.lt = true,
+                Some("CH") => ch = true,
+                Some("INCR") => flags.incr = true,
+                _ => break,
+            }
+            i += 1;
+        }
+
+        let pairs_args = &args[i..];
+        if pairs_args.is_empty() || !pairs_args.len().is_multiple_of(2) {
+            return RespValue::error("ERR syntax error");
+        }
+        if flags.nx && flags.xx {
+            return RespValue::error("ERR XX and NX options at the same time are not compatible");
+        }
+        if (flags.gt && flags.lt) || (flags.nx && (flags.gt || flags.lt)) {
+            return RespValue::error(
+                "ERR GT, LT, and/or NX options at the same time are not compatible",
+            );
+        }
+        if flags.incr && pairs_args.len() != 2 {
+            return RespValue::error("ERR INCR option supports a single increment-element pair");
+        }
+
+        let mut pairs = Vec::with_capacity(pairs_args.len() / 2);
+        for pair in pairs_args.chunks(2) {
+            let score = match self.get_score(&pair[0]) {
+                Some(s) => s,
+                None => return RespValue::error("ERR value is not a valid float"),
+            };
+            let member = match self.get_bytes(&pair[1]) {
+                Some(m) => m,
+                None => return RespValue::error("ERR invalid member"),
+            };
+            pairs.push((score, member));
+        }
+
+        if let Err(e) = self.check_type(&key, "zset") {
+            return e;
+        }
+
+        match self.db().zadd(key, flags, pairs) {
+            Ok(result) if flags.incr => match result.score {
+                Some(score) => self.double(score),
+                None => RespValue::null(),
+            },
+            Ok(result) if ch => RespValue::integer((result.added + result.updated) as i64),
+            Ok(result) => RespValue::integer(result.added as i64),
+            Err(e) => Self::storage_error(e),
+        }
+    }
+
+    /// ZSCORE key member
+    fn cmd_zscore(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::error("ERR wrong number of arguments for 'ZSCORE' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        if let Err(e) = self.check_type(&key, "zset") {
+            return e;
+        }
+
+        let member = match self.get_bytes(&args[1]) {
+            Some(m) => m,
+            None => return RespValue::error("ERR invalid member"),
+        };
+
+        match self.db().zscore(&key, &member) {
+            Some(score) => self.double(score),
+            None => RespValue::null(),
+        }
+    }
+
+    /// ZREM key member [member ...]
+    fn cmd_zrem(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::error("ERR wrong number of arguments for 'ZREM' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        if let Err(e) = self.check_type(&key, "zset") {
+            return e;
+        }
+
+        let members: Vec<Bytes> = args[1..].iter().filter_map(|a| self.get_bytes(a)).collect();
+
+        let removed = self.db().zrem(&key, &members);
+        RespValue::integer(removed as i64)
+    }
+
+    /// ZCARD key
+    fn cmd_zcard(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::error("ERR wrong number of arguments for 'ZCARD' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        if let Err(e) = self.check_type(&key, "zset") {
+            return e;
+        }
+
+        RespValue::integer(self.db().zcard(&key) as i64)
+    }
+
+    /// ZRANGE key start stop [REV] [WITHSCORES]
+    /// ZREVRANGE key start stop [WITHSCORES]
+    fn cmd_zrange(&self, cmd: &str, args: &[RespValue], mut rev: bool) -> RespValue {
+        if args.len() < 3 {
+            return RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                cmd
+            ));
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let start = match self.get_integer(&args[1]) {
+            Some(i) => i,
+            None => return RespValue::error("ERR value is not an integer or out of range"),
+        };
+
+        let stop = match self.get_integer(&args[2]) {
+            Some(i) => i,
+            None => return RespValue::error("ERR value is not an integer or out of range"),
+        };
+
+        let mut with_scores = false;
+        for arg in &args[3..] {
+            match self.get_string(arg).map(|s| s.to_uppercase()).as_deref() {
+                Some("WITHSCORES") => with_scores = true,
+                Some("REV") if cmd == "ZRANGE" => rev = true,
+                _ => return RespValue::error("ERR syntax error"),
+            }
+        }
+
+        if let Err(e) = self.check_type(&key, "zset") {
+            return e;
+        }
+
+        let values: Vec<RespValue> = self
+            .db()
+            .zrange(&key, start, stop, rev)
+            .into_iter()
+            .flat_map(|(member, score)| {
+                let mut items = vec![RespValue::bulk_string(member)];
+                if with_scores {
+                    items.push(self.double(score));
+                }
+                items
+            })
+            .collect();
+
+        RespValue::array(values)
+    }
+
+    /// ZRANK key member / ZREVRANK key member
+    fn cmd_zrank(&self, cmd: &str, args: &[RespValue], rev: bool) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                cmd
+            ));
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        if let Err(e) = self.check_type(&key, "zset") {
+            return e;
+        }
+
+        let member = match self.get_bytes(&args[1]) {
+            Some(m) => m,
+            None => return RespValue::error("ERR invalid member"),
+        };
+
+        match self.db().zrank(&key, &member, rev) {
+            Some(rank) => RespValue::integer(rank as i64),
+            None => RespValue::null(),
+        }
+    }
+
+    /// ZINCRBY key increment member
+    fn cmd_zincrby(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 3 {
+            return RespValue::error("ERR wrong number of arguments for 'ZINCRBY' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let increment = match self.get_score(&args[1]) {
+            Some(i) => i,
+            None => return RespValue::error("ERR value is not a valid float"),
+        };
+
+        let member = match self.get_bytes(&args[2]) {
+            Some(m) => m,
+            None => return RespValue::error("ERR invalid member"),
+        };
+
+        if let Err(e) = self.check_type(&key, "zset") {
+            return e;
+        }
+
+        match self.db().zincrby(key, increment, member) {
+            Ok(score) => self.double(score),
+            Err(e) => Self::storage_error(e),
+        }
     }
 
-    fn make_command(args: &[&str]) -> RespValue {
-        RespValue::Array(
-            args.iter()
-                .map(|s| RespValue::bulk_string(Bytes::from(s.to_string())))
-                .collect(),
-        )
+    /// ZPOPMIN key [count] / ZPOPMAX key [count]
+    fn cmd_zpop(&self, cmd: &str, args: &[RespValue], max: bool) -> RespValue {
+        if args.is_empty() || args.len() > 2 {
+            return RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                cmd
+            ));
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let count = match args.get(1).map(|a| self.get_integer(a)) {
+            None => 1,
+            Some(Some(c)) if c >= 0 => c as usize,
+            Some(_) => return RespValue::error("ERR value is out of range, must be positive"),
+        };
+
+        if let Err(e) = self.check_type(&key, "zset") {
+            return e;
+        }
+
+        let values: Vec<RespValue> = self
+            .db()
+            .zpop(&key, count, max)
+            .into_iter()
+            .flat_map(|(member, score)| [RespValue::bulk_string(member), self.double(score)])
+            .collect();
+
+        RespValue::array(values)
+    }
+
+    /// ZRANDMEMBER key [count [WITHSCORES]]
+    fn cmd_zrandmember(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() || args.len() > 3 {
+            return RespValue::error("ERR wrong number of arguments for 'ZRANDMEMBER' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        if let Err(e) = self.check_type(&key, "zset") {
+            return e;
+        }
+
+        // Without a count, reply with a single member (or nil)
+        if args.len() == 1 {
+            return match self.db().zrandmember(&key, 1).into_iter().next() {
+                Some((member, _)) => RespValue::bulk_string(member),
+                None => RespValue::null(),
+            };
+        }
+
+        let count = match self.get_integer(&args[1]) {
+            // As for HRANDFIELD
+            Some(c) if c < -(i64::MAX / 2) => return RespValue::error("ERR value is out of range"),
+            Some(c) => c,
+            None => return RespValue::error("ERR value is not an integer or out of range"),
+        };
+
+        let with_scores = match args.get(2).map(|a| self.get_string(a)) {
+            None => false,
+            Some(Some(opt)) if opt.eq_ignore_ascii_case("WITHSCORES") => true,
+            Some(_) => return RespValue::error("ERR syntax error"),
+        };
+
+        let values: Vec<RespValue> = self
+            .db()
+            .zrandmember(&key, count)
+            .into_iter()
+            .flat_map(|(member, score)| {
+                let mut items = vec![RespValue::bulk_string(member)];
+                if with_scores {
+                    items.push(self.double(score));
+                }
+                items
+            })
+            .collect();
+
+        RespValue::array(values)
+    }
+
+    /// BLPOP, BRPOP, BZPOPMIN and BZPOPMAX key [key ...] timeout
+    ///
+    /// This is the non-blocking form used when the command is executed
+    /// directly; the connection layer blocks via
+    /// [`execute_or_block`](Self::execute_or_block).
+    fn cmd_blocking_pop(&self, cmd: &str, args: &[RespValue], op: BlockingOp) -> RespValue {
+        match self.parse_blocking(cmd, args, op) {
+            // Counted and logged by dispatch like any other write
+            Ok(request) => self
+                .serve(&request)
+                .map_or_else(|| op.timeout_reply(), |(response, _)| response),
+            Err(e) => e,
+        }
+    }
+
+    /// Parses `key [key ...] timeout` for a blocking command.
+    fn parse_blocking(
+        &self,
+        cmd: &str,
+        args: &[RespValue],
+        op: BlockingOp,
+    ) -> Result<BlockingRequest, RespValue> {
+        if args.len() < 2 {
+            return Err(RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                cmd
+            )));
+        }
+
+        let (timeout_arg, key_args) = args.split_last().unwrap();
+
+        let timeout = match self.get_float(timeout_arg) {
+            Some(t) if t < 0.0 => return Err(RespValue::error("ERR timeout is negative")),
+            Some(0.0) => None,
+            // The deadline is counted from now, so it must be representable
+            Some(t) => match Duration::try_from_secs_f64(t)
+                .ok()
+                .filter(|&timeout| Instant::now().checked_add(timeout).is_some())
+            {
+                Some(timeout) => Some(timeout),
+                None => return Err(RespValue::error("ERR timeout is out of range")),
+            },
+            None => {
+                return Err(RespValue::error(
+                    "ERR timeout is not a float or out of range",
+                ))
+            }
+        };
+
+        let mut keys = Vec::with_capacity(key_args.len());
+        for arg in key_args {
+            match self.get_bytes(arg) {
+                Some(k) => keys.push(k),
+                None => return Err(RespValue::error("ERR invalid key")),
+            }
+        }
+
+        Ok(BlockingRequest { keys, timeout, op })
+    }
+
+    /// ZUNIONSTORE / ZINTERSTORE destination numkeys key [key ...]
+    ///     [WEIGHTS weight [weight ...]] [AGGREGATE SUM|MIN|MAX]
+    /// ZDIFFSTORE destination numkeys key [key ...]
+    fn cmd_zset_op_store(&self, cmd: &str, op: SetOp, args: &[RespValue]) -> RespValue {
+        if args.len() < 3 {
+            return RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                cmd
+            ));
+        }
+
+        let dest = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let numkeys = match self.get_integer(&args[1]) {
+            Some(n) if n > 0 => n as usize,
+            Some(_) => {
+                return RespValue::error(format!(
+                    "ERR at least 1 input key is needed for '{}' command",
+                    cmd.to_lowercase()
+                ))
+            }
+            None => return RespValue::error("ERR value is not an integer or out of range"),
+        };
+
+        if args.len() < 2 + numkeys {
+            return RespValue::error("ERR syntax error");
+        }
+
+        let mut keys = Vec::with_capacity(numkeys);
+        for arg in &args[2..2 + numkeys] {
+            let key = match self.get_bytes(arg) {
+                Some(k) => k,
+                None => return RespValue::error("ERR invalid key"),
+            };
+            if let Err(e) = self.check_type(&key, "zset") {
+                return e;
+            }
+            keys.push(key);
+        }
+
+        // WEIGHTS and AGGREGATE are not accepted by ZDIFFSTORE
+        let mut weights = Vec::new();
+        let mut aggregate = Aggregate::Sum;
+        let mut i = 2 + numkeys;
+        while i < args.len() {
+            match self
+                .get_string(&args[i])
+                .map(|s| s.to_uppercase())
+                .as_deref()
+            {
+                Some("WEIGHTS") if op != SetOp::Diff && i + numkeys < args.len() => {
+                    weights.clear();
+                    for arg in &args[i + 1..=i + numkeys] {
+                        match self.get_float(arg) {
+                            Some(w) => weights.push(w),
+                            None => return RespValue::error("ERR weight value is not a float"),
+                        }
+                    }
+                    i += 1 + numkeys;
+                }
+                Some("AGGREGATE") if op != SetOp::Diff && i + 1 < args.len() => {
+                    aggregate = match self
+                        .get_string(&args[i + 1])
+                        .map(|s| s.to_uppercase())
+                        .as_deref()
+                    {
+                        Some("SUM") => Aggregate::Sum,
+                        Some("MIN") => Aggregate::Min,
+                        Some("MAX") => Aggregate::Max,
+                        _ => return RespValue::error("ERR syntax error"),
+                    };
+                    i += 2;
+                }
+                _ => return RespValue::error("ERR syntax error"),
+            }
+        }
+
+        let len = self
+            .db()
+            .zset_op_store(op, dest, &keys, &weights, aggregate);
+        RespValue::integer(len as i64)
+    }
+
+    // ========================================================================
+    // Stream Commands
+    // ========================================================================
+
+    /// XADD key [NOMKSTREAM] [MAXLEN [=|~] threshold] <*|id> field value [field value ...]
+    fn cmd_xadd(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 4 {
+            return RespValue::error("ERR wrong number of arguments for 'XADD' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let mut nomkstream = false;
+        let mut maxlen = None;
+        let mut i = 1;
+        while i < args.len() {
+            match self
+                .get_string(&args[i])
+                .map(|s| s.to_uppercase())
+                .as_deref()
+            {
+                Some("NOMKSTREAM") => {
+                    nomkstream = true;
+                    i += 1;
+                }
+                Some("MAXLEN") => {
+                    // Trimming is always exact, so "~" behaves like "="
+                    let mut j = i + 1;
+                    if matches!(
+                        args.get(j).and_then(|a| self.get_string(a)).as_deref(),
+                        Some("=" | "~")
+                    ) {
+                        j += 1;
+                    }
+                    maxlen = match args.get(j).and_then(|a| self.get_integer(a)) {
+                        Some(n) if n >= 0 => Some(n as usize),
+                        Some(_) => {
+                            return RespValue::error("ERR The MAXLEN argument must be >= 0.")
+                        }
+                        None => {
+                            return RespValue::error("ERR value is not an integer or out of range")
+                        }
+                    };
+                    i = j + 1;
+                }
+                _ => break,
+            }
+        }
+
+        let id = match args.get(i).and_then(|a| self.get_bytes(a)) {
+            Some(id) => match XAddId::parse(&id) {
+                Some(id) => id,
+                None => {
+                    return RespValue::error(
+                        "ERR Invalid stream ID specified as stream command argument",
+                    )
+                }
+            },
+            None => return RespValue::error("ERR syntax error"),
+        };
+
+        let field_args = &args[i + 1..];
+        if field_args.is_empty() || !field_args.len().is_multiple_of(2) {
+            return RespValue::error("ERR wrong number of arguments for 'XADD' command");
+        }
+
+        let mut fields = Vec::with_capacity(field_args.len() / 2);
+        for pair in field_args.chunks(2) {
+            match (self.get_bytes(&pair[0]), self.get_bytes(&pair[1])) {
+                (Some(f), Some(v)) => fields.push((f, v)),
+                _ => return RespValue::error("ERR invalid field or value"),
+            }
+        }
+
+        if let Err(e) = self.check_type(&key, "stream") {
+            return e;
+        }
+
+        match self.db().xadd(key, id, fields, maxlen, nomkstream) {
+            Ok(Some(id)) => RespValue::bulk_string(id.to_bytes()),
+            Ok(None) => RespValue::null(),
+            Err(e) => Self::storage_error(e),
+        }
+    }
+
+    /// XLEN key
+    fn cmd_xlen(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::error("ERR wrong number of arguments for 'XLEN' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        if let Err(e) = self.check_type(&key, "stream") {
+            return e;
+        }
+
+        RespValue::integer(self.db().xlen(&key) as i64)
+    }
+
+    /// XRANGE key start end [COUNT count]
+    /// XREVRANGE key end start [COUNT count]
+    fn cmd_xrange(&self, cmd: &str, args: &[RespValue], rev: bool) -> RespValue {
+        if args.len() != 3 && args.len() != 5 {
+            return RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                cmd
+            ));
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        // XREVRANGE takes its bounds in reverse order
+        let (start_arg, end_arg) = if rev {
+            (&args[2], &args[1])
+        } else {
+            (&args[1], &args[2])
+        };
+
+        let bounds = self
+            .get_stream_bound(start_arg, true)
+            .zip(self.get_stream_bound(end_arg, false));
+        let (start, end) = match bounds {
+            Some(bounds) => bounds,
+            None => {
+                return RespValue::error(
+                    "ERR Invalid stream ID specified as stream command argument",
+                )
+            }
+        };
+
+        let count = if args.len() == 5 {
+            match self.get_string(&args[3]) {
+                Some(opt) if opt.eq_ignore_ascii_case("COUNT") => {
+                    match self.get_integer(&args[4]) {
+                        Some(c) => Some(c.max(0) as usize),
+                        None => {
+                            return RespValue::error("ERR value is not an integer or out of range")
+                        }
+                    }
+                }
+                _ => return RespValue::error("ERR syntax error"),
+            }
+        } else {
+            None
+        };
+
+        if let Err(e) = self.check_type(&key, "stream") {
+            return e;
+        }
+
+        match (start, end) {
+            (Some(start), Some(end)) => {
+                stream_records_reply(self.db().xrange(&key, start, end, count, rev))
+            }
+            // An exclusive bound past the ends of the ID space matches nothing
+            _ => RespValue::array(vec![]),
+        }
+    }
+
+    /// XREAD [COUNT count] STREAMS key [key ...] id [id ...]
+    ///
+    /// Only the non-blocking form is supported.
+    fn cmd_xread(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 3 {
+            return RespValue::error("ERR wrong number of arguments for 'XREAD' command");
+        }
+
+        let mut count = None;
+        let mut i = 0;
+        loop {
+            match args
+                .get(i)
+                .and_then(|a| self.get_string(a))
+                .map(|s| s.to_uppercase())
+                .as_deref()
+            {
+                Some("COUNT") => {
+                    count = match args.get(i + 1).and_then(|a| self.get_integer(a)) {
+                        Some(c) => Some(c.max(0) as usize),
+                        None => {
+                            return RespValue::error("ERR value is not an integer or out of range")
+                        }
+                    };
+                    i += 2;
+                }
+                Some("STREAMS") => {
+                    i += 1;
+                    break;
+                }
+                _ => return RespValue::error("ERR syntax error"),
+            }
+        }
+
+        let stream_args = &args[i..];
+        if stream_args.is_empty() || !stream_args.len().is_multiple_of(2) {
+            return RespValue::error(
+                "ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.",
+            );
+        }
+
+        let (key_args, id_args) = stream_args.split_at(stream_args.len() / 2);
+        let mut streams = Vec::with_capacity(key_args.len());
+        for (key_arg, id_arg) in key_args.iter().zip(id_args) {
+            let key = match self.get_bytes(key_arg) {
+                Some(k) => k,
+                None => return RespValue::error("ERR invalid key"),
+            };
+
+            if let Err(e) = self.check_type(&key, "stream") {
+                return e;
+            }
+
+            // "$" means "only entries added from now on"
+            let id = match self.get_bytes(id_arg) {
+                Some(id) if id.as_ref() == b"$" => self.db().stream_last_id(&key),
+                Some(id) => match StreamId::parse(&id, 0) {
+                    Some(id) => id,
+                    None => {
+                        return RespValue::error(
+                            "ERR Invalid stream ID specified as stream command argument",
+                        )
+                    }
+                },
+                None => return RespValue::error("ERR invalid stream ID"),
+            };
+
+            streams.push((key, id));
+        }
+
+        let results = self.db().xread(&streams, count);
+        if results.is_empty() {
+            return RespValue::null();
+        }
+
+        RespValue::array(
+            results
+                .into_iter()
+                .map(|(key, records)| {
+                    RespValue::array(vec![
+                        RespValue::bulk_string(key),
+                        stream_records_reply(records),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    /// XGROUP CREATE key group <id|$> [MKSTREAM]
+    /// XGROUP DESTROY key group
+    /// XGROUP CREATECONSUMER key group consumer
+    /// XGROUP DELCONSUMER key group consumer
+    fn cmd_xgroup(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 3 {
+            return RespValue::error("ERR wrong number of arguments for 'XGROUP' command");
+        }
+
+        let subcommand = match self.get_string(&args[0]) {
+            Some(s) => s.to_uppercase(),
+            None => return RespValue::error("ERR syntax error"),
+        };
+
+        let (key, group) = match (self.get_bytes(&args[1]), self.get_bytes(&args[2])) {
+            (Some(k), Some(g)) => (k, g),
+            _ => return RespValue::error("ERR invalid key or group"),
+        };
+
+        if let Err(e) = self.check_type(&key, "stream") {
+            return e;
+        }
+
+        let no_group = || {
+            RespValue::error(format!(
+                "NOGROUP No such consumer group '{}' for key name '{}'",
+                String::from_utf8_lossy(&group),
+                String::from_utf8_lossy(&key)
+            ))
+        };
+
+        match (subcommand.as_str(), args.len()) {
+            ("CREATE", 4 | 5) => {
+                let mkstream = match args.get(4).map(|a| self.get_string(a)) {
+                    None => false,
+                    Some(Some(opt)) if opt.eq_ignore_ascii_case("MKSTREAM") => true,
+                    Some(_) => return RespValue::error("ERR syntax error"),
+                };
+
+                let last_id = match self.get_bytes(&args[3]) {
+                    Some(id) if id.as_ref() == b"$" => None,
+                    Some(id) => match StreamId::parse(&id, 0) {
+                        Some(id) => Some(id),
+                        None => {
+                            return RespValue::error(
+                                "ERR Invalid stream ID specified as stream command argument",
+                            )
+                        }
+                    },
+                    None => return RespValue::error("ERR invalid stream ID"),
+                };
+
+                match self.db().xgroup_create(key, group, last_id, mkstream) {
+                    Ok(true) => RespValue::ok(),
+                    Ok(false) => RespValue::error("BUSYGROUP Consumer Group name already exists"),
+                    Err(e) => Self::storage_error(e),
+                }
+            }
+            ("DESTROY", 3) => RespValue::integer(self.db().xgroup_destroy(&key, &group) as i64),
+            ("CREATECONSUMER", 4) => {
+                let consumer = match self.get_bytes(&args[3]) {
+                    Some(c) => c,
+                    None => return RespValue::error("ERR invalid consumer"),
+                };
+                match self.db().xgroup_createconsumer(&key, &group, &consumer) {
+                    Some(created) => RespValue::integer(created as i64),
+                    None => no_group(),
+                }
+            }
+            ("DELCONSUMER", 4) => {
+                let consumer = match self.get_bytes(&args[3]) {
+                    Some(c) => c,
+                    None => return RespValue::error("ERR invalid consumer"),
+                };
+                match self.db().xgroup_delconsumer(&key, &group, &consumer) {
+                    Some(pending) => RespValue::integer(pending as i64),
+                    None => no_group(),
+                }
+            }
+            ("CREATE" | "DESTROY" | "CREATECONSUMER" | "DELCONSUMER", _) => {
+                RespValue::error(format!(
+                    "ERR wrong number of arguments for 'XGROUP|{}' command",
+                    subcommand.to_lowercase()
+                ))
+            }
+            _ => RespValue::error(format!(
+                "ERR unknown subcommand '{}'. Try XGROUP HELP.",
+                subcommand
+            )),
+        }
+    }
+
+    /// XREADGROUP GROUP group consumer [COUNT count] [NOACK] STREAMS key [key ...] id [id ...]
+    fn cmd_xreadgroup(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 6 {
+            return RespValue::error("ERR wrong number of arguments for 'XREADGROUP' command");
+        }
+
+        match self.get_string(&args[0]) {
+            Some(opt) if opt.eq_ignore_ascii_case("GROUP") => {}
+            _ => return RespValue::error("ERR syntax error"),
+        }
+
+        let (group, consumer) = match (self.get_bytes(&args[1]), self.get_bytes(&args[2])) {
+            (Some(g), Some(c)) => (g, c),
+            _ => return RespValue::error("ERR invalid group or consumer"),
+        };
+
+        let mut count = None;
+        let mut noack = false;
+        let mut i = 3;
+        loop {
+            match args
+                .get(i)
+                .and_then(|a| self.get_string(a))
+                .map(|s| s.to_uppercase())
+                .as_deref()
+            {
+                Some("COUNT") => {
+                    count = match args.get(i + 1).and_then(|a| self.get_integer(a)) {
+                        Some(c) => Some(c.max(0) as usize),
+                        None => {
+                            return RespValue::error("ERR value is not an integer or out of range")
+                        }
+                    };
+                    i += 2;
+                }
+                Some("NOACK") => {
+                    noack = true;
+                    i += 1;
+                }
+                Some("STREAMS") => {
+                    i += 1;
+                    break;
+                }
+                _ => return RespValue::error("ERR syntax error"),
+            }
+        }
+
+        let stream_args = &args[i..];
+        if stream_args.is_empty() || !stream_args.len().is_multiple_of(2) {
+            return RespValue::error(
+                "ERR Unbalanced 'xreadgroup' list of streams: for each stream key an ID or '>' must be specified.",
+            );
+        }
+
+        let (key_args, id_args) = stream_args.split_at(stream_args.len() / 2);
+        let mut streams = Vec::with_capacity(key_args.len());
+        for (key_arg, id_arg) in key_args.iter().zip(id_args) {
+            let key = match self.get_bytes(key_arg) {
+                Some(k) => k,
+                None => return RespValue::error("ERR invalid key"),
+            };
+
+            if let Err(e) = self.check_type(&key, "stream") {
+                return e;
+            }
+
+            // ">" means "entries never delivered to this group"
+            let after = match self.get_bytes(id_arg) {
+                Some(id) if id.as_ref() == b">" => None,
+                Some(id) => match StreamId::parse(&id, 0) {
+                    Some(id) => Some(id),
+                    None => {
+                        return RespValue::error(
+                            "ERR Invalid stream ID specified as stream command argument",
+                        )
+                    }
+                },
+                None => return RespValue::error("ERR invalid stream ID"),
+            };
+
+            streams.push((key, after));
+        }
+
+        let mut results = Vec::with_capacity(streams.len());
+        for (key, after) in streams {
+            let records = match self
+                .db()
+                .xreadgroup(&key, &group, &consumer, after, count, noack)
+            {
+                Some(records) => records,
+                None => {
+                    return RespValue::error(format!(
+                        "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                        String::from_utf8_lossy(&key),
+                        String::from_utf8_lossy(&group)
+                    ))
+                }
+            };
+
+            // New-entry reads leave out streams with nothing to deliver;
+            // history reads always report every stream
+            if after.is_none() && records.is_empty() {
+                continue;
+            }
+
+            results.push(RespValue::array(vec![
+                RespValue::bulk_string(key),
+                RespValue::array(records.into_iter().map(stream_entry_reply).collect()),
+            ]));
+        }
+
+        if results.is_empty() {
+            RespValue::null()
+        } else {
+            RespValue::array(results)
+        }
+    }
+
+    /// XACK key group id [id ...]
+    fn cmd_xack(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 3 {
+            return RespValue::error("ERR wrong number of arguments for 'XACK' command");
+        }
+
+        let (key, group) = match (self.get_bytes(&args[0]), self.get_bytes(&args[1])) {
+            (Some(k), Some(g)) => (k, g),
+            _ => return RespValue::error("ERR invalid key or group"),
+        };
+
+        let ids = match self.get_stream_ids(&args[2..]) {
+            Ok(ids) => ids,
+            Err(e) => return e,
+        };
+
+        if let Err(e) = self.check_type(&key, "stream") {
+            return e;
+        }
+
+        RespValue::integer(self.db().xack(&key, &group, &ids) as i64)
+    }
+
+    /// XPENDING key group [[IDLE min-idle-time] start end count [consumer]]
+    fn cmd_xpending(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::error("ERR wrong number of arguments for 'XPENDING' command");
+        }
+
+        let (key, group) = match (self.get_bytes(&args[0]), self.get_bytes(&args[1])) {
+            (Some(k), Some(g)) => (k, g),
+            _ => return RespValue::error("ERR invalid key or group"),
+        };
+
+        if let Err(e) = self.check_type(&key, "stream") {
+            return e;
+        }
+
+        let no_group = RespValue::error(format!(
+            "NOGROUP No such key '{}' or consumer group '{}'",
+            String::from_utf8_lossy(&key),
+            String::from_utf8_lossy(&group)
+        ));
+
+        // Summary form
+        if args.len() == 2 {
+            let summary = match self.db().xpending_summary(&key, &group) {
+                Some(summary) => summary,
+                None => return no_group,
+            };
+
+            let (min, max, consumers) = match summary.bounds {
+                Some((min, max)) => (
+                    RespValue::bulk_string(min.to_bytes()),
+                    RespValue::bulk_string(max.to_bytes()),
+                    RespValue::array(
+                        summary
+                            .consumers
+                            .into_iter()
+                            .map(|(name, count)| {
+                                RespValue::array(vec![
+                                    RespValue::bulk_string(name),
+                                    RespValue::bulk_string(Bytes::from(count.to_string())),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                ),
+                None => (RespValue::null(), RespValue::null(), RespValue::null()),
+            };
+
+            return RespValue::array(vec![
+                RespValue::integer(summary.count as i64),
+                min,
+                max,
+                consumers,
+            ]);
+        }
+
+        // Extended form
+        let mut rest = &args[2..];
+        let mut min_idle = None;
+        if let Some(opt) = rest.first().and_then(|a| self.get_string(a)) {
+            if opt.eq_ignore_ascii_case("IDLE") {
+                min_idle = match rest.get(1).and_then(|a| self.get_integer(a)) {
+                    Some(ms) => Some(Duration::from_millis(ms.max(0) as u64)),
+                    None => return RespValue::error("ERR value is not an integer or out of range"),
+                };
+                rest = &rest[2..];
+            }
+        }
+
+        if rest.len() != 3 && rest.len() != 4 {
+            return RespValue::error("ERR syntax error");
+        }
+
+        let bounds = self
+            .get_stream_bound(&rest[0], true)
+            .zip(self.get_stream_bound(&rest[1], false));
+        let (start, end) = match bounds {
+            Some((Some(start), Some(end))) => (start, end),
+            Some(_) => (StreamId::MAX, StreamId::MIN),
+            None => {
+                return RespValue::error(
+                    "ERR Invalid stream ID specified as stream command argument",
+                )
+            }
+        };
+
+        let count = match self.get_integer(&rest[2]) {
+            Some(c) => c.max(0) as usize,
+            None => return RespValue::error("ERR value is not an integer or out of range"),
+        };
+
+        let consumer = rest.get(3).and_then(|a| self.get_bytes(a));
+        let query = PendingQuery {
+            start,
+            end,
+            count,
+            consumer: consumer.as_deref(),
+            min_idle,
+        };
+
+        match self.db().xpending_range(&key, &group, query) {
+            Some(entries) => RespValue::array(
+                entries
+                    .into_iter()
+                    .map(|info| {
+                        RespValue::array(vec![
+                            RespValue::bulk_string(info.id.to_bytes()),
+                            RespValue::bulk_string(info.consumer),
+                            RespValue::integer(info.idle.as_millis() as i64),
+                            RespValue::integer(info.delivery_count as i64),
+                        ])
+                    })
+                    .collect(),
+            ),
+            None => no_group,
+        }
+    }
+
+    /// XCLAIM key group consumer min-idle-time id [id ...] [JUSTID]
+    fn cmd_xclaim(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 5 {
+            return RespValue::error("ERR wrong number of arguments for 'XCLAIM' command");
+        }
+
+        let (key, group, consumer) = match (
+            self.get_bytes(&args[0]),
+            self.get_bytes(&args[1]),
+            self.get_bytes(&args[2]),
+        ) {
+            (Some(k), Some(g), Some(c)) => (k, g, c),
+            _ => return RespValue::error("ERR invalid key, group or consumer"),
+        };
+
+        let min_idle = match self.get_integer(&args[3]) {
+            Some(ms) => Duration::from_millis(ms.max(0) as u64),
+            None => return RespValue::error("ERR Invalid min-idle-time argument for XCLAIM"),
+        };
+
+        let mut id_args = &args[4..];
+        let justid = matches!(
+            id_args.last().and_then(|a| self.get_string(a)),
+            Some(opt) if opt.eq_ignore_ascii_case("JUSTID")
+        );
+        if justid {
+            id_args = &id_args[..id_args.len() - 1];
+        }
+        if id_args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'XCLAIM' command");
+        }
+
+        let ids = match self.get_stream_ids(id_args) {
+            Ok(ids) => ids,
+            Err(e) => return e,
+        };
+
+        if let Err(e) = self.check_type(&key, "stream") {
+            return e;
+        }
+
+        match self
+            .db()
+            .xclaim(&key, &group, &consumer, min_idle, &ids, justid)
+        {
+            Some(claimed) if justid => RespValue::array(
+                claimed
+                    .into_iter()
+                    .map(|(id, _)| RespValue::bulk_string(id.to_bytes()))
+                    .collect(),
+            ),
+            Some(claimed) => stream_records_reply(claimed),
+            None => RespValue::error(format!(
+                "NOGROUP No such key '{}' or consumer group '{}'",
+                String::from_utf8_lossy(&key),
+                String::from_utf8_lossy(&group)
+            )),
+        }
+    }
+
+    // ========================================================================
+    // Key Commands
+    // ========================================================================
+
+    /// EXPIRE key seconds [NX | XX | GT | LT]
+    fn cmd_expire(&self, args: &[RespValue]) -> RespValue {
+        self.expire_command("EXPIRE", args, 1000, true)
+    }
+
+    /// PEXPIRE key milliseconds [NX | XX | GT | LT]
+    fn cmd_pexpire(&self, args: &[RespValue]) -> RespValue {
+        self.expire_command("PEXPIRE", args, 1, true)
+    }
+
+    /// EXPIREAT key timestamp [NX | XX | GT | LT]
+    fn cmd_expireat(&self, args: &[RespValue]) -> RespValue {
+        self.expire_command("EXPIREAT", args, 1000, false)
+    }
+
+    /// PEXPIREAT key milliseconds-timestamp [NX | XX | GT | LT]
+    fn cmd_pexpireat(&self, args: &[RespValue]) -> RespValue {
+        self.expire_command("PEXPIREAT", args, 1, false)
+    }
+
+    /// Shared implementation of the EXPIRE family.
+    ///
+    /// The time argument is multiplied by `unit_ms` to get milliseconds and,
+    /// if `relative`, offset from the current time. A deadline that has
+    /// already passed deletes the key.
+    fn expire_command(
+        &self,
+        name: &str,
+        args: &[RespValue],
+        unit_ms: i64,
+        relative: bool,
+    ) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                name
+            ));
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let amount = match self.get_integer(&args[1]) {
+            Some(n) => n,
+            None => return RespValue::error("ERR value is not an integer"),
+        };
+
+        let mut flags = ExpireFlags::default();
+        for arg in &args[2..] {
+            match self.get_string(arg).map(|s| s.to_uppercase()).as_deref() {
+                Some("NX") => flags.nx = true,
+                Some("XX") => flags.xx = true,
+                Some("GT") => flags.gt = true,
+                Some("LT") => flags.lt = true,
+                Some(other) => {
+                    return RespValue::error(format!("ERR Unsupported option {}", other))
+                }
+                None => return RespValue::error("ERR syntax error"),
+            }
+        }
+
+        if flags.nx && (flags.xx || flags.gt || flags.lt) {
+            return RespValue::error(
+                "ERR NX and XX, GT or LT options at the same time are not compatible",
+            );
+        }
+        if flags.gt && flags.lt {
+            return RespValue::error("ERR GT and LT options at the same time are not compatible");
+        }
+
+        match self.expire_deadline(amount, unit_ms, relative) {
+            Some(unix_ms) => RespValue::integer(self.db().expire_at(&key, unix_ms, flags) as i64),
+            None => RespValue::error(format!(
+                "ERR invalid expire time in '{}' command",
+                name.to_lowercase()
+            )),
+        }
+    }
+
+    /// EXPIRETIME key
+    fn cmd_expiretime(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::error("ERR wrong number of arguments for 'EXPIRETIME' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        match self.db().expire_time(&key) {
+            Some(-1) => RespValue::integer(-1),
+            Some(ms) => RespValue::integer(ms / 1000),
+            None => RespValue::integer(-2),
+        }
+    }
+
+    /// PEXPIRETIME key
+    fn cmd_pexpiretime(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::error("ERR wrong number of arguments for 'PEXPIRETIME' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        match self.db().expire_time(&key) {
+            Some(ms) => RespValue::integer(ms),
+            None => RespValue::integer(-2),
+        }
+    }
+
+    /// TTL key
+    fn cmd_ttl(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::error("ERR wrong number of arguments for 'TTL' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        match self.db().ttl(&key) {
+            Some(ttl) => RespValue::integer(ttl),
+            None => RespValue::integer(-2), // Key doesn't exist
+        }
+    }
+
+    /// PTTL key
+    fn cmd_pttl(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::error("ERR wrong number of arguments for 'PTTL' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        match self.db().pttl(&key) {
+            Some(ttl) => RespValue::integer(ttl),
+            None => RespValue::integer(-2),
+        }
+    }
+
+    /// PERSIST key
+    fn cmd_persist(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::error("ERR wrong number of arguments for 'PERSIST' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        if self.db().persist(&key) {
+            RespValue::integer(1)
+        } else {
+            RespValue::integer(0)
+        }
+    }
+
+    /// KEYS pattern
+    fn cmd_keys(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::error("ERR wrong number of arguments for 'KEYS' command");
+        }
+
+        let pattern = match self.get_bytes(&args[0]) {
+            Some(p) => p,
+            None => return RespValue::error("ERR invalid pattern"),
+        };
+
+        // Serialized as they are found, so the reply costs little more
+        // than the keys' own bytes however many match
+        let mut keys = BulkArrayBuilder::new();
+        self.db().keys_with(&pattern, |key| keys.push(key));
+
+        keys.finish()
+    }
+
+    /// UNLINK key [key ...]
+    fn cmd_unlink(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'UNLINK' command");
+        }
+
+        let keys: Vec<Bytes> = args.iter().filter_map(|a| self.get_bytes(a)).collect();
+
+        RespValue::integer(self.db().unlink(&keys) as i64)
+    }
+
+    /// TOUCH key [key ...]
+    fn cmd_touch(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'TOUCH' command");
+        }
+
+        let keys: Vec<Bytes> = args.iter().filter_map(|a| self.get_bytes(a)).collect();
+
+        RespValue::integer(self.db().touch(&keys) as i64)
+    }
+
+    /// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+    fn cmd_scan(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'SCAN' command");
+        }
+
+        let cursor = match self
+            .get_string(&args[0])
+            .and_then(|c| c.parse::<u64>().ok())
+        {
+            Some(c) => c,
+            None => return RespValue::error("ERR invalid cursor"),
+        };
+
+        let mut pattern = None;
+        let mut count = 10;
+        let mut type_filter = None;
+
+        let mut i = 1;
+        while i < args.len() {
+            let opt = match self.get_string(&args[i]) {
+                Some(s) => s.to_uppercase(),
+                None => return RespValue::error("ERR syntax error"),
+            };
+            let value = match args.get(i + 1) {
+                Some(v) => v,
+                None => return RespValue::error("ERR syntax error"),
+            };
+
+            match opt.as_str() {
+                "MATCH" => match self.get_bytes(value) {
+                    Some(p) => pattern = Some(p),
+                    None => return RespValue::error("ERR invalid pattern"),
+                },
+                "COUNT" => match self.get_integer(value) {
+                    Some(c) if c >= 1 => count = c as usize,
+                    Some(_) => return RespValue::error("ERR syntax error"),
+                    None => return RespValue::error("ERR value is not an integer or out of range"),
+                },
+                "TYPE" => match self.get_string(value) {
+                    Some(t) => type_filter = Some(t.to_lowercase()),
+                    None => return RespValue::error("ERR syntax error"),
+                },
+                _ => return RespValue::error("ERR syntax error"),
+            }
+            i += 2;
+        }
+
+        let mut keys = Vec::new();
+        let next = self.db().scan_with(
+            cursor,
+            count,
+            pattern.as_deref(),
+            type_filter.as_deref(),
+            |key| keys.push(RespValue::bulk_string(key.clone())),
+        );
+
+        RespValue::array(vec![
+            RespValue::bulk_string(Bytes::from(next.to_string())),
+            RespValue::array(keys),
+        ])
+    }
+
+    /// TYPE key
+    fn cmd_type(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::error("ERR wrong number of arguments for 'TYPE' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        RespValue::simple_string(self.db().key_type(&key))
+    }
+
+    /// RENAME key newkey
+    fn cmd_rename(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::error("ERR wrong number of arguments for 'RENAME' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let newkey = match self.get_bytes(&args[1]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid new key"),
+        };
+
+        match self.db().rename(&key, newkey, false) {
+            Some(_) => RespValue::ok(),
+            None => RespValue::error("ERR no such key"),
+        }
+    }
+
+    /// RENAMENX key newkey
+    fn cmd_renamenx(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::error("ERR wrong number of arguments for 'RENAMENX' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let newkey = match self.get_bytes(&args[1]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid new key"),
+        };
+
+        match self.db().rename(&key, newkey, true) {
+            Some(renamed) => RespValue::integer(renamed as i64),
+            None => RespValue::error("ERR no such key"),
+        }
+    }
+
+    /// COPY source destination [DB destination-db] [REPLACE]
+    fn cmd_copy(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::error("ERR wrong number of arguments for 'COPY' command");
+        }
+
+        let src = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let dst = match self.get_bytes(&args[1]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let mut replace = false;
+        let mut target = self.db();
+        let mut i = 2;
+        while i < args.len() {
+            match self
+                .get_string(&args[i])
+                .map(|s| s.to_uppercase())
+                .as_deref()
+            {
+                Some("REPLACE") => replace = true,
+                Some("DB") if i + 1 < args.len() => {
+                    i += 1;
+                    target = match self.get_integer(&args[i]) {
+                        Some(index) => {
+                            match usize::try_from(index).ok().and_then(|i| self.storage.db(i)) {
+                                Some(db) => db,
+                                None => return RespValue::error("ERR DB index is out of range"),
+                            }
+                        }
+                        None => {
+                            return RespValue::error("ERR value is not an integer or out of range")
+                        }
+                    };
+                }
+                _ => return RespValue::error("ERR syntax error"),
+            }
+            i += 1;
+        }
+
+        if src == dst && target.index() == self.session.db() {
+            return RespValue::error("ERR source and destination objects are the same");
+        }
+
+        RespValue::integer(self.db().copy_to(&src, target, dst, replace) as i64)
+    }
+
+    /// MOVE key db
+    fn cmd_move(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::error("ERR wrong number of arguments for 'MOVE' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let index = match self.get_integer(&args[1]) {
+            Some(index) => index,
+            None => return RespValue::error("ERR value is not an integer or out of range"),
+        };
+        if self.storage.cluster().is_enabled() {
+            return RespValue::error("ERR MOVE is not allowed in cluster mode");
+        }
+        let target = match usize::try_from(index).ok().and_then(|i| self.storage.db(i)) {
+            Some(db) => db,
+            None => return RespValue::error("ERR DB index is out of range"),
+        };
+        if target.index() == self.session.db() {
+            return RespValue::error("ERR source and destination objects are the same");
+        }
+
+        RespValue::integer(self.db().move_to(&key, target) as i64)
+    }
+
+    /// SORT key [BY pattern] [LIMIT offset count] [GET pattern ...] [ASC|DESC] [ALPHA]
+    /// [STORE destination]
+    fn cmd_sort(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'SORT' command");
+        }
+
+        let key = match self.get_bytes(&args[0]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let mut opts = SortOptions::default();
+        let mut store = None;
+        let mut i = 1;
+        while i < args.len() {
+            let option = self.get_string(&args[i]).map(|s| s.to_uppercase());
+            let remaining = args.len() - i - 1;
+            match option.as_deref() {
+                Some("ASC") => opts.desc = false,
+                Some("DESC") => opts.desc = true,
+                Some("ALPHA") => opts.alpha = true,
+                Some("BY") if remaining >= 1 => {
+                    i += 1;
+                    opts.by = self.get_bytes(&args[i]);
+                }
+                Some("GET") if remaining >= 1 => {
+                    i += 1;
+                    if let Some(pattern) = self.get_bytes(&args[i]) {
+                        opts.get.push(pattern);
+                    }
+                }
+                Some("STORE") if remaining >= 1 => {
+                    i += 1;
+                    store = self.get_bytes(&args[i]);
+                }
+                Some("LIMIT") if remaining >= 2 => {
+                    let offset = self.get_integer(&args[i + 1]);
+                    let count = self.get_integer(&args[i + 2]);
+                    match (offset, count) {
+                        (Some(offset), Some(count)) => opts.limit = Some((offset, count)),
+                        _ => {
+                            return RespValue::error("ERR value is not an integer or out of range")
+                        }
+                    }
+                    i += 2;
+                }
+                _ => return RespValue::error("ERR syntax error"),
+            }
+            i += 1;
+        }
+
+        let values = match self.db().sort(&key, &opts) {
+            Ok(values) => values,
+            Err(e) => return Self::storage_error(e),
+        };
+
+        if let Some(dest) = store {
+            return RespValue::integer(self.db().sort_store(dest, values) as i64);
+        }
+
+        RespValue::array(
+            values
+                .into_iter()
+                .map(|v| v.map_or_else(RespValue::null, RespValue::bulk_string))
+                .collect(),
+        )
+    }
+
+    /// OBJECT ENCODING|IDLETIME|FREQ|REFCOUNT key, or OBJECT HELP
+    fn cmd_object(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'OBJECT' command");
+        }
+
+        let subcommand = match self.get_string(&args[0]) {
+            Some(s) => s.to_uppercase(),
+            None => return RespValue::error("ERR syntax error"),
+        };
+
+        if subcommand == "HELP" && args.len() == 1 {
+            let lines = [
+                "OBJECT <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                "ENCODING <key>",
+                "    Return the kind of internal representation used in order to store the value",
+                "    associated with a <key>.",
+                "FREQ <key>",
+                "    Return the access frequency index of the <key>.",
+                "IDLETIME <key>",
+                "    Return the idle time of the <key>, that is the approximated number of",
+                "    seconds elapsed since the last access to the key.",
+                "REFCOUNT <key>",
+                "    Return the number of references of the value associated with the specified",
+                "    <key>.",
+            ];
+            return RespValue::array(lines.iter().map(|l| RespValue::simple_string(*l)).collect());
+        }
+
+        if !matches!(
+            subcommand.as_str(),
+            "ENCODING" | "IDLETIME" | "FREQ" | "REFCOUNT"
+        ) {
+            return RespValue::error(format!(
+                "ERR unknown subcommand '{}'. Try OBJECT HELP.",
+                subcommand
+            ));
+        }
+
+        if args.len() != 2 {
+            return RespValue::error(format!(
+                "ERR wrong number of arguments for 'OBJECT|{}' command",
+                subcommand.to_lowercase()
+            ));
+        }
+
+        let key = match self.get_bytes(&args[1]) {
+            Some(k) => k,
+            None => return RespValue::error("ERR invalid key"),
+        };
+
+        let reply = match subcommand.as_str() {
+            "ENCODING" => self
+                .db()
+                .object_encoding(&key)
+                .map(|e| RespValue::bulk_string(Bytes::from(e))),
+            "IDLETIME" => self
+                .db()
+                .object_idletime(&key)
+                .map(|idle| RespValue::integer(idle.as_secs() as i64)),
+            "FREQ" => self
+                .db()
+                .object_freq(&key)
+                .map(|freq| RespValue::integer(freq as i64)),
+            // Values are never shared between keys
+            _ => self.db().exists(&key).then(|| RespValue::integer(1)),
+        };
+
+        reply.unwrap_or_else(RespValue::null)
+    }
+
+    /// MEMORY STATS / MEMORY USAGE key [SAMPLES count], or MEMORY HELP
+    ///
+    /// Sizes are the approximate ones memory accounting keeps for every key,
+    /// so SAMPLES is accepted but never needed.
+    fn cmd_memory(&self, args: &[RespValue]) -> RespValue {
+        let subcommand = match args.first().and_then(|arg| self.get_string(arg)) {
+            Some(s) => s.to_uppercase(),
+            None => return RespValue::error("ERR wrong number of arguments for 'MEMORY' command"),
+        };
+
+        match (subcommand.as_str(), args.len()) {
+            ("HELP", 1) => {
+                let lines = [
+                    "MEMORY <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                    "STATS",
+                    "    Return information about the memory usage of the server.",
+                    "USAGE <key> [SAMPLES <count>]",
+                    "    Return memory in bytes used by <key> and its value.",
+                ];
+                RespValue::array(lines.iter().map(|l| RespValue::simple_string(*l)).collect())
+            }
+            ("STATS", 1) => {
+                let mem = self.storage.memory_info();
+                let stats = [
+                    ("dataset.bytes", mem.used_memory),
+                    ("keys.count", mem.keys),
+                    (
+                        "keys.bytes-per-key",
+                        mem.used_memory.checked_div(mem.keys).unwrap_or(0),
+                    ),
+                    ("keys.key-bytes", mem.key_bytes),
+                    ("keys.compact", mem.compact_keys),
+                    ("keys.compact-saved-bytes", mem.compact_keys_saved()),
+                ];
+                let mut reply: Vec<_> = stats
+                    .into_iter()
+                    .map(|(name, value)| {
+                        (
+                            RespValue::bulk_string(name),
+                            RespValue::integer(value as i64),
+                        )
+                    })
+                    .collect();
+
+                // What the allocator and the OS report, where they do
+                let alloc = allocator::stats();
+                let sizes = [
+                    ("allocator.allocated", alloc.allocated),
+                    ("allocator.active", alloc.active),
+                    ("allocator.resident", alloc.resident),
+                    ("rss", alloc.rss),
+                ];
+                let ratios = [
+                    ("allocator-fragmentation.ratio", alloc.fragmentation_ratio()),
+                    ("allocator.rss-ratio", alloc.rss_ratio()),
+                    ("fragmentation", fragmentation_ratio(&alloc, &mem)),
+                ];
+                reply.extend(sizes.into_iter().filter_map(|(name, value)| {
+                    Some((
+                        RespValue::bulk_string(name),
+                        RespValue::integer(value? as i64),
+                    ))
+                }));
+                reply.extend(ratios.into_iter().filter_map(|(name, value)| {
+                    Some((RespValue::bulk_string(name), RespValue::Double(value?)))
+                }));
+                self.map(reply)
+            }
+            ("USAGE", 2 | 4) => {
+                if args.len() == 4 {
+                    let samples = self.get_string(&args[2]).unwrap_or_default();
+                    if !samples.eq_ignore_ascii_case("SAMPLES") {
+                        return RespValue::error("ERR syntax error");
+                    }
+                    if self.get_integer(&args[3]).is_none() {
+                        return RespValue::error("ERR value is not an integer or out of range");
+                    }
+                }
+                let key = match self.get_bytes(&args[1]) {
+                    Some(k) => k,
+                    None => return RespValue::error("ERR invalid key"),
+                };
+                match self.db().memory_usage(&key) {
+                    Some(bytes) => RespValue::integer(bytes as i64),
+                    None => RespValue::null(),
+                }
+            }
+            ("HELP" | "STATS" | "USAGE", _) => RespValue::error(format!(
+                "ERR wrong number of arguments for 'MEMORY|{}' command",
+                subcommand.to_lowercase()
+            )),
+            _ => RespValue::error(format!(
+                "ERR unknown subcommand '{}'. Try MEMORY HELP.",
+                subcommand
+            )),
+        }
+    }
+
+    // ========================================================================
+    // Pub/Sub Commands
+    // ========================================================================
+
+    /// SUBSCRIBE channel [channel ...] / UNSUBSCRIBE [channel ...] /
+    /// PSUBSCRIBE pattern [pattern ...] / PUNSUBSCRIBE [pattern ...]
+    ///
+    /// Replies once per channel with the kind of change, the channel and
+    /// the number of subscriptions the client is left with. Unsubscribing
+    /// without arguments drops every channel (or pattern).
+    fn subscribe(&self, cmd: &str, args: &[RespValue]) -> CommandOutcome {
+        let pattern = cmd.starts_with('P');
+        let subscribing = !cmd.contains("UNSUB");
+        if subscribing && args.is_empty() {
+            return CommandOutcome::Reply(RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                cmd
+            )));
+        }
+
+        let mut channels = Vec::with_capacity(args.len());
+        for arg in args {
+            match self.get_bytes(arg) {
+                Some(channel) => channels.push(channel),
+                None => return CommandOutcome::Reply(RespValue::error("ERR invalid channel")),
+            }
+        }
+        if channels.is_empty() {
+            channels = if pattern {
+                self.session.subscriber().patterns()
+            } else {
+                self.session.subscriber().channels()
+            };
+        }
+
+        let pubsub = self.db().pubsub();
+        let kind = Bytes::from(cmd.to_lowercase());
+        let confirm = |channel: RespValue, count: usize| {
+            self.push(vec![
+                RespValue::bulk_string(kind.clone()),
+                channel,
+                RespValue::integer(count as i64),
+            ])
+        };
+
+        if channels.is_empty() {
+            // Nothing to unsubscribe from
+            return CommandOutcome::Replies(vec![confirm(
+                RespValue::null(),
+                self.session.subscriber().count(),
+            )]);
+        }
+
+        let replies = channels
+            .into_iter()
+            .map(|channel| {
+                let count = match (subscribing, pattern) {
+                    (true, false) => pubsub.subscribe(self.session.subscriber(), channel.clone()),
+                    (true, true) => pubsub.psubscribe(self.session.subscriber(), channel.clone()),
+                    (false, false) => pubsub.unsubscribe(self.session.subscriber(), &channel),
+                    (false, true) => pubsub.punsubscribe(self.session.subscriber(), &channel),
+                };
+                confirm(RespValue::bulk_string(channel), count)
+            })
+            .collect();
+        CommandOutcome::Replies(replies)
+    }
+
+    /// PING [message], while subscribed
+    fn subscribed_ping(&self, args: &[RespValue]) -> RespValue {
+        let message = args
+            .first()
+            .and_then(|arg| self.get_bytes(arg))
+            .unwrap_or_default();
+        RespValue::array(vec![
+            RespValue::bulk_string("pong"),
+            RespValue::bulk_string(message),
+        ])
+    }
+
+    /// PUBLISH channel message
+    fn cmd_publish(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::error("ERR wrong number of arguments for 'PUBLISH' command");
+        }
+
+        let (Some(channel), Some(message)) = (self.get_bytes(&args[0]), self.get_bytes(&args[1]))
+        else {
+            return RespValue::error("ERR invalid channel or message");
+        };
+        RespValue::integer(self.db().pubsub().publish(&channel, &message) as i64)
+    }
+
+    /// PUBSUB CHANNELS [pattern] | NUMSUB [channel ...] | NUMPAT
+    fn cmd_pubsub(&self, args: &[RespValue]) -> RespValue {
+        let Some(subcommand) = args.first().and_then(|arg| self.get_string(arg)) else {
+            return RespValue::error("ERR wrong number of arguments for 'PUBSUB' command");
+        };
+        let subcommand = subcommand.to_uppercase();
+        let args = &args[1..];
+        let pubsub = self.db().pubsub();
+
+        match subcommand.as_str() {
+            "CHANNELS" => {
+                if args.len() > 1 {
+                    return RespValue::error(
+                        "ERR wrong number of arguments for 'PUBSUB|CHANNELS' command",
+                    );
+                }
+                let pattern = args.first().and_then(|arg| self.get_bytes(arg));
+                let channels = pubsub
+                    .channels(pattern.as_deref())
+                    .into_iter()
+                    .map(RespValue::bulk_string)
+                    .collect();
+                RespValue::array(channels)
+            }
+            "NUMSUB" => {
+                let mut reply = Vec::with_capacity(args.len() * 2);
+                for arg in args {
+                    let Some(channel) = self.get_bytes(arg) else {
+                        return RespValue::error("ERR invalid channel");
+                    };
+                    let count = pubsub.numsub(&channel);
+                    reply.push(RespValue::bulk_string(channel));
+                    reply.push(RespValue::integer(count as i64));
+                }
+                RespValue::array(reply)
+            }
+            "NUMPAT" => {
+                if !args.is_empty() {
+                    return RespValue::error(
+                        "ERR wrong number of arguments for 'PUBSUB|NUMPAT' command",
+                    );
+                }
+                RespValue::integer(pubsub.numpat() as i64)
+            }
+            _ => RespValue::error(format!(
+                "ERR unknown subcommand '{}'. Try PUBSUB HELP.",
+                subcommand
+            )),
+        }
+    }
+
+    // ========================================================================
+    // Scripting Commands
+    // ========================================================================
+
+    /// FUNCTION LOAD [REPLACE] code | LIST [LIBRARYNAME pattern] [WITHCODE] |
+    /// DELETE library | FLUSH [ASYNC|SYNC] | DUMP | RESTORE payload
+    /// [FLUSH|APPEND|REPLACE] | KILL
+    fn cmd_function(&self, args: &[RespValue]) -> RespValue {
+        let Some(subcommand) = args.first().and_then(|arg| self.get_string(arg)) else {
+            return RespValue::error("ERR wrong number of arguments for 'FUNCTION' command");
+        };
+        let subcommand = subcommand.to_uppercase();
+        let rest = &args[1..];
+        let functions = self.storage.functions();
+        let arity = || {
+            RespValue::error(format!(
+                "ERR wrong number of arguments for 'FUNCTION|{}' command",
+                subcommand
+            ))
+        };
+        let is = |arg: &RespValue, option: &str| {
+            arg.as_bytes()
+                .is_some_and(|a| a.eq_ignore_ascii_case(option.as_bytes()))
+        };
+
+        match subcommand.as_str() {
+            "LOAD" => {
+                let (replace, code) = match rest {
+                    [code] => (false, code),
+                    [option, code] if is(option, "REPLACE") => (true, code),
+                    [option, _] => {
+                        return RespValue::error(format!(
+                            "ERR Unknown option given: {}",
+                            self.get_string(option).unwrap_or_default()
+                        ))
+                    }
+                    _ => return arity(),
+                };
+                let Some(code) = self.get_bytes(code) else {
+                    return RespValue::error("ERR syntax error");
+                };
+                self.function_write(args, || match functions.load(code, replace) {
+                    Ok(name) => RespValue::bulk_string(name),
+                    Err(e) => function_error(e),
+                })
+            }
+            "DELETE" => {
+                let [name] = rest else {
+                    return arity();
+                };
+                let Some(name) = self.get_string(name) else {
+                    return RespValue::error("ERR syntax error");
+                };
+                self.function_write(args, || match functions.delete(&name) {
+                    Ok(()) => RespValue::ok(),
+                    Err(e) => function_error(e),
+                })
+            }
+            "FLUSH" => {
+                match rest {
+                    [] => {}
+                    [mode] if is(mode, "ASYNC") || is(mode, "SYNC") => {}
+                    [_] => {
+                        return RespValue::error(
+                            "ERR FUNCTION FLUSH only supports SYNC|ASYNC option",
+                        )
+                    }
+                    _ => return arity(),
+                }
+                self.function_write(args, || {
+                    functions.flush();
+                    RespValue::ok()
+                })
+            }
+            "DUMP" => {
+                if !rest.is_empty() {
+                    return arity();
+                }
+                RespValue::bulk_string(functions.dump())
+            }
+            "RESTORE" => {
+                let (payload, policy) = match rest {
+                    [payload] => (payload, RestorePolicy::Append),
+                    [payload, policy] => {
+                        let policy = if is(policy, "FLUSH") {
+                            RestorePolicy::Flush
+                        } else if is(policy, "APPEND") {
+                            RestorePolicy::Append
+                        } else if is(policy, "REPLACE") {
+                            RestorePolicy::Replace
+                        } else {
+                            return RespValue::error(
+                                "ERR Wrong restore policy given, value should be either FLUSH, APPEND or REPLACE.",
+                            );
+                        };
+                        (payload, policy)
+                    }
+                    _ => return arity(),
+                };
+                let Some(payload) = self.get_bytes(payload) else {
+                    return RespValue::error("ERR syntax error");
+                };
+                self.function_write(args, || match functions.restore(&payload, policy) {
+                    Ok(()) => RespValue::ok(),
+                    Err(e) => function_error(e),
+                })
+            }
+            "LIST" => self.function_list(rest),
+            "KILL" => {
+                if !rest.is_empty() {
+                    return arity();
+                }
+                match functions.kill() {
+                    Ok(()) => RespValue::ok(),
+                    Err(e) => function_error(e),
+                }
+            }
+            "HELP" if rest.is_empty() => {
+                let lines = [
+                    "FUNCTION <subcommand> [<arg> [value] [opt] ...]. Subcommands are:",
+                    "LOAD [REPLACE] <FUNCTION CODE>",
+                    "    Create a new library with the given library name and code.",
+                    "DELETE <LIBRARY NAME>",
+                    "    Delete the given library.",
+                    "LIST [LIBRARYNAME PATTERN] [WITHCODE]",
+                    "    Return general information on all the libraries.",
+                    "FLUSH [ASYNC|SYNC]",
+                    "    Delete all the libraries.",
+                    "DUMP",
+                    "    Return a serialized payload representing the current libraries.",
+                    "RESTORE <PAYLOAD> [FLUSH|APPEND|REPLACE]",
+                    "    Restore the libraries represented by the given payload.",
+                    "KILL",
+                    "    Kill the function currently in execution.",
+                ];
+                RespValue::array(lines.iter().map(|l| RespValue::simple_string(*l)).collect())
+            }
+            _ => RespValue::error(format!(
+                "ERR unknown subcommand '{}'. Try FUNCTION HELP.",
+                subcommand
+            )),
+        }
+    }
+
+    /// Runs a FUNCTION subcommand that changes the libraries as a write:
+    /// refused by a read-only server, counted towards the save rules and
+    /// propagated to the append-only file and replicas when it succeeds.
+    fn function_write(&self, args: &[RespValue], write: impl FnOnce() -> RespValue) -> RespValue {
+        if let Err(e) = self.check_read_only() {
+            return e;
+        }
+        // A busy function holds the append-only file's lock as well
+        if self.storage.functions().is_busy() {
+            return function_error(FunctionError::Busy);
+        }
+        let response = self.run_write(write, |response| {
+            if response.is_error() {
+                Vec::new()
+            } else {
+                self.propagate("FUNCTION", args, response)
+            }
+        });
+        if !response.is_error() {
+            self.db().snapshots().record_changes(1);
+        }
+        response
+    }
+
+    /// FUNCTION LIST [LIBRARYNAME pattern] [WITHCODE]
+    fn function_list(&self, args: &[RespValue]) -> RespValue {
+        let mut pattern = None;
+        let mut with_code = false;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let option = self.get_string(arg).unwrap_or_default().to_uppercase();
+            match option.as_str() {
+                "WITHCODE" if !with_code => with_code = true,
+                "LIBRARYNAME" if pattern.is_none() => {
+                    let Some(name) = args.next().and_then(|arg| self.get_bytes(arg)) else {
+                        return RespValue::error("ERR library name argument was not given");
+                    };
+                    pattern = Some(GlobPattern::new(&name));
+                }
+                _ => return RespValue::error(format!("ERR Unknown argument {}", option)),
+            }
+        }
+
+        let name = |name: &'static str| RespValue::bulk_string(name);
+        let libraries = self
+            .storage
+            .functions()
+            .libraries()
+            .into_iter()
+            .filter(|library| {
+                pattern
+                    .as_ref()
+                    .is_none_or(|pattern| pattern.matches(library.name().as_bytes()))
+            })
+            .map(|library| {
+                let functions = library
+                    .functions()
+                    .iter()
+                    .map(|function| {
+                        let description = function
+                            .description()
+                            .map_or(RespValue::null(), |d| RespValue::bulk_string(d.to_string()));
+                        let flags = function
+                            .flags()
+                            .iter()
+                            .map(|flag| RespValue::simple_string(*flag))
+                            .collect();
+                        self.map(vec![
+                            (
+                                name("name"),
+                                RespValue::bulk_string(function.name().to_string()),
+                            ),
+                            (name("description"), description),
+                            (name("flags"), RespValue::array(flags)),
+                        ])
+                    })
+                    .collect();
+
+                let mut fields = vec![
+                    (
+                        name("library_name"),
+                        RespValue::bulk_string(library.name().to_string()),
+                    ),
+                    (name("engine"), name(scripting::functions::ENGINE)),
+                    (name("functions"), RespValue::array(functions)),
+                ];
+                if with_code {
+                    fields.push((
+                        name("library_code"),
+                        RespValue::bulk_string(library.code().clone()),
+                    ));
+                }
+                self.map(fields)
+            })
+            .collect();
+        RespValue::array(libraries)
+    }
+
+    /// FCALL function numkeys [key ...] [arg ...], or FCALL_RO
+    ///
+    /// The commands the function sends run one by one, as though the client
+    /// had sent them, once checked against what the function may do. The
+    /// writes among them, rather than the FCALL, are propagated to the
+    /// append-only file and replicas, so replaying them gives the same
+    /// result even if the function isn't deterministic.
+    fn cmd_fcall(&self, cmd: &str, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                cmd.to_lowercase()
+            ));
+        }
+        let Some(name) = self.get_bytes(&args[0]) else {
+            return RespValue::error("ERR syntax error");
+        };
+        let numkeys = match self.get_integer(&args[1]) {
+            Some(n) if n < 0 => return RespValue::error("ERR Number of keys can't be negative"),
+            Some(n) if n as u64 > (args.len() - 2) as u64 => {
+                return RespValue::error("ERR Number of keys can't be greater than number of args")
+            }
+            Some(n) => n as usize,
+            None => return RespValue::error("ERR value is not an integer or out of range"),
+        };
+        let values: Vec<Bytes> = args[2..]
+            .iter()
+            .map(|arg| self.get_bytes(arg).unwrap_or_default())
+            .collect();
+        let (keys, argv) = values.split_at(numkeys);
+        // A busy function holds the append-only file's lock as well
+        if self.storage.functions().is_busy() {
+            return function_error(FunctionError::Busy);
+        }
+
+        let effects = RefCell::new(Vec::new());
+        self.run_write(
+            || {
+                self.storage
+                    .functions()
+                    .call(
+                        &name,
+                        keys,
+                        argv,
+                        cmd == "FCALL_RO",
+                        |command, may_write| self.script_command(command, may_write, &effects),
+                    )
+                    .unwrap_or_else(function_error)
+            },
+            |_| effects.take(),
+        )
+    }
+
+    /// Runs a command a function sent with `redis.call` or `redis.pcall`.
+    ///
+    /// A write runs only if the function `may_write`, and what it must
+    /// propagate is added to `effects`.
+    fn script_command(
+        &self,
+        command: &[Bytes],
+        may_write: bool,
+        effects: &RefCell<Vec<Vec<Bytes>>>,
+    ) -> RespValue {
+        let Some(cmd) = self.resolve_command(&command[0]) else {
+            return RespValue::error("ERR Unknown Redis command called from script");
+        };
+        if NOSCRIPT_COMMANDS.contains(&cmd) {
+            return RespValue::error("ERR This Redis command is not allowed from script");
+        }
+        let args: Vec<RespValue> = command[1..]
+            .iter()
+            .cloned()
+            .map(RespValue::bulk_string)
+            .collect();
+
+        if !is_write_command(cmd) {
+            self.track(cmd, &args);
+            return self.run(cmd, &args);
+        }
+        if !may_write {
+            return RespValue::error("ERR Write commands are not allowed from read-only scripts.");
+        }
+        if let Err(e) = self.check_writable(cmd) {
+            return e;
+        }
+        if let Err(e) = self.check_memory(cmd) {
+            return e;
+        }
+
+        let response = self.run(cmd, &args);
+        if !response.is_error() {
+            effects
+                .borrow_mut()
+                .extend(self.propagate(cmd, &args, &response));
+            self.wrote(cmd, &args, &response);
+        }
+        response
+    }
+
+    // ========================================================================
+    // Server Commands
+    // ========================================================================
+
+    /// PING [message]
+    fn cmd_ping(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            RespValue::pong()
+        } else {
+            match self.get_bytes(&args[0]) {
+                Some(msg) => RespValue::bulk_string(msg),
+                None => RespValue::pong(),
+            }
+        }
+    }
+
+    /// ECHO message
+    fn cmd_echo(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::error("ERR wrong number of arguments for 'ECHO' command");
+        }
+
+        match self.get_bytes(&args[0]) {
+            Some(msg) => RespValue::bulk_string(msg),
+            None => RespValue::error("ERR invalid message"),
+        }
+    }
+
+    /// INFO [section ...]
+    fn cmd_info(&self, args: &[RespValue]) -> RespValue {
+        let mut requested = Vec::with_capacity(args.len());
+        for arg in args {
+            match self.get_string(arg) {
+                Some(section) => requested.push(section.to_ascii_lowercase()),
+                None => return RespValue::error("ERR invalid section name"),
+            }
+        }
+        let everything = requested.is_empty()
+            || requested
+                .iter()
+                .any(|section| matches!(section.as_str(), "all" | "everything" | "default"));
+
+        let mut info = String::new();
+        for &(name, title) in INFO_SECTIONS {
+            if !everything && !requested.iter().any(|section| section == name) {
+                continue;
+            }
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            info.push_str(&format!("# {}\r\n", title));
+            info.push_str(&self.info_section(name));
+        }
+        RespValue::bulk_string(Bytes::from(info))
+    }
+
+    /// Formats the lines of one section of INFO, named as in
+    /// [`INFO_SECTIONS`].
+    fn info_section(&self, name: &str) -> String {
+        match name {
+            "server" => format!(
+                "flashkv_version:0.1.0\r\n\
+                 rust_version:{}\r\n\
+                 os:{}\r\n\
+                 process_id:{}\r\n\
+                 uptime_in_seconds:{}\r\n\
+                 read_only:{}\r\n",
+                env!("CARGO_PKG_RUST_VERSION"),
+                std::env::consts::OS,
+                std::process::id(),
+                self.start_time.elapsed().as_secs(),
+                self.storage.is_read_only() as u8,
+            ),
+            "clients" => self.clients_info(),
+            "memory" => {
+                let mem = self.storage.memory_info();
+                format!(
+                    "used_memory:{}\r\n\
+                     used_memory_human:{}KB\r\n\
+                     maxmemory:{}\r\n\
+                     maxmemory_policy:{}\r\n\
+                     {}",
+                    mem.used_memory,
+                    mem.used_memory / 1024,
+                    self.storage.eviction().maxmemory(),
+                    self.storage.eviction().policy(),
+                    allocator_info(&mem),
+                )
+            }
+            "persistence" => self.persistence_info(),
+            "stats" => self.stats_info(),
+            "replication" => self.replication_info(),
+            "cpu" => cpu_info(),
+            "cluster" => format!(
+                "cluster_enabled:{}\r\n",
+                self.db().cluster().is_enabled() as u8
+            ),
+            "keyspace" => self.keyspace_info(),
+            _ => String::new(),
+        }
+    }
+
+    /// Formats the `# Clients` section of INFO.
+    fn clients_info(&self) -> String {
+        let connected = match &self.connection_stats {
+            Some(stats) => stats.active_connections.load(Ordering::Relaxed) as usize,
+            None => clients::list().len(),
+        };
+        let pubsub = clients::list()
+            .iter()
+            .filter(|session| session.subscribed())
+            .count();
+        format!(
+            "connected_clients:{}\r\n\
+             pubsub_clients:{}\r\n",
+            connected, pubsub
+        )
+    }
+
+    /// Formats the `# Persistence` section of INFO.
+    fn persistence_info(&self) -> String {
+        let snapshots = self.storage.snapshots();
+        let aof = self.storage.aof();
+        let status = |ok: bool| if ok { "ok" } else { "err" };
+        format!(
+            "loading:0\r\n\
+             rdb_changes_since_last_save:{}\r\n\
+             rdb_bgsave_in_progress:{}\r\n\
+             rdb_last_save_time:{}\r\n\
+             rdb_last_bgsave_status:{}\r\n\
+             rdb_last_bgsave_time_sec:{}\r\n\
+             rdb_current_bgsave_time_sec:{}\r\n\
+             persistence_compression:{}\r\n\
+             aof_enabled:{}\r\n\
+             aof_rewrite_in_progress:{}\r\n\
+             aof_last_bgrewrite_status:{}\r\n\
+             aof_last_write_status:{}\r\n",
+            snapshots.changes(),
+            snapshots.bgsave_in_progress() as u8,
+            snapshots.last_save(),
+            status(snapshots.last_save_ok()),
+            snapshots.last_duration_secs(),
+            snapshots.current_duration_secs(),
+            snapshots.compression().codec,
+            aof.is_enabled() as u8,
+            aof.rewrite_in_progress() as u8,
+            status(aof.last_rewrite_ok()),
+            status(aof.last_write_ok()),
+        )
+    }
+
+    /// Formats the `# Stats` section of INFO. The connection counters are
+    /// zero for a handler that isn't serving a client connection.
+    fn stats_info(&self) -> String {
+        let stats = self.storage.total_stats();
+        let counter = |field: fn(&ConnectionStats) -> &AtomicU64| {
+            self.connection_stats
+                .as_deref()
+                .map_or(0, |stats| field(stats).load(Ordering::Relaxed))
+        };
+        format!(
+            "total_connections_received:{}\r\n\
+             total_commands_processed:{}\r\n\
+             total_net_input_bytes:{}\r\n\
+             total_net_output_bytes:{}\r\n\
+             rejected_connections:{}\r\n\
+             expired_keys:{}\r\n\
+             evicted_keys:{}\r\n\
+             keyspace_hits:{}\r\n\
+             keyspace_misses:{}\r\n\
+             get_ops:{}\r\n\
+             set_ops:{}\r\n\
+             del_ops:{}\r\n",
+            counter(|stats| &stats.connections_accepted),
+            counter(|stats| &stats.commands_processed),
+            counter(|stats| &stats.bytes_read),
+            counter(|stats| &stats.bytes_written),
+            counter(|stats| &stats.connections_rejected),
+            stats.expired,
+            self.storage.eviction().evicted(),
+            stats.keyspace_hits,
+            stats.keyspace_misses,
+            stats.get_ops,
+            stats.set_ops,
+            stats.del_ops,
+        )
+    }
+
+    /// Formats the lines of the `# Keyspace` section of INFO, one per
+    /// database holding keys.
+    fn keyspace_info(&self) -> String {
+        self.storage
+            .databases()
+            .map(|db| (db.index(), db.stats()))
+            .filter(|(_, stats)| stats.keys > 0)
+            .map(|(index, stats)| {
+                format!(
+                    "db{}:keys={},expires={}\r\n",
+                    index, stats.keys, stats.expires
+                )
+            })
+            .collect()
+    }
+
+    /// Formats the `# Replication` section of INFO.
+    fn replication_info(&self) -> String {
+        let replication = self.db().replication();
+        let link = replication.link();
+        let mut info = String::new();
+
+        let (replid, offset) = match link.master() {
+            Some(master) => {
+                info.push_str(&format!(
+                    "role:slave\r\n\
+                     master_host:{}\r\n\
+                     master_port:{}\r\n\
+                     master_link_status:{}\r\n\
+                     master_last_io_seconds_ago:{}\r\n\
+                     master_sync_in_progress:{}\r\n\
+                     slave_repl_offset:{}\r\n\
+                     slave_read_only:1\r\n",
+                    master.host,
+                    master.port,
+                    if link.is_up() { "up" } else { "down" },
+                    link.last_io_secs_ago().unwrap_or(-1),
+                    link.sync_in_progress() as u8,
+                    link.offset(),
+                ));
+                let replid = link
+                    .master_replid()
+                    .unwrap_or_else(|| replication.replid().to_string());
+                (replid, link.offset())
+            }
+            None => {
+                info.push_str("role:master\r\n");
+                (replication.replid().to_string(), replication.offset())
+            }
+        };
+
+        let replicas = replication.replicas();
+        info.push_str(&format!("connected_slaves:{}\r\n", replicas.len()));
+        for (i, replica) in replicas.iter().enumerate() {
+            info.push_str(&format!(
+                "slave{}:ip={},port={},state={},offset={},lag={}\r\n",
+                i,
+                replica.addr().ip(),
+                replica.listening_port().unwrap_or(replica.addr().port()),
+                if replica.is_online() {
+                    "online"
+                } else {
+                    "wait_bgsave"
+                },
+                replica.ack_offset(),
+                replica.lag_secs(),
+            ));
+        }
+
+        let (first_byte, histlen) = match replication.backlog_range() {
+            Some((start, end)) => (start + 1, end - start),
+            None => (0, 0),
+        };
+        info.push_str(&format!(
+            "master_replid:{}\r\n\
+             master_repl_offset:{}\r\n\
+             repl_backlog_active:{}\r\n\
+             repl_backlog_size:{}\r\n\
+             repl_backlog_first_byte_offset:{}\r\n\
+             repl_backlog_histlen:{}\r\n",
+            replid,
+            offset,
+            replication.backlog_range().is_some() as u8,
+            replication.backlog_size(),
+            first_byte,
+            histlen,
+        ));
+        info
+    }
+
+    /// DBSIZE
+    fn cmd_dbsize(&self, _args: &[RespValue]) -> RespValue {
+        RespValue::integer(self.db().len() as i64)
+    }
+
+    /// FLUSHDB [ASYNC|SYNC]
+    fn cmd_flushdb(&self, args: &[RespValue]) -> RespValue {
+        match self.flush_mode(args) {
+            Ok(true) => self.db().flush_async(),
+            Ok(false) => self.db().flush(),
+            Err(e) => return e,
+        }
+        RespValue::ok()
+    }
+
+    /// FLUSHALL [ASYNC|SYNC]
+    fn cmd_flushall(&self, args: &[RespValue]) -> RespValue {
+        match self.flush_mode(args) {
+            Ok(true) => self.storage.flush_all_async(),
+            Ok(false) => self.storage.flush_all(),
+            Err(e) => return e,
+        }
+        RespValue::ok()
+    }
+
+    /// Parses the ASYNC|SYNC flag of FLUSHDB and FLUSHALL, returning whether
+    /// to free the data in the background.
+    fn flush_mode(&self, args: &[RespValue]) -> Result<bool, RespValue> {
+        match args {
+            [] => Ok(false),
+            [mode] => match self.get_string(mode).map(|s| s.to_uppercase()).as_deref() {
+                Some("ASYNC") => Ok(true),
+                Some("SYNC") => Ok(false),
+                _ => Err(RespValue::error("ERR syntax error")),
+            },
+            _ => Err(RespValue::error("ERR syntax error")),
+        }
+    }
+
+    /// SELECT index
+    fn cmd_select(&self, args: &[RespValue]) -> RespValue {
+        if args.len() != 1 {
+            return RespValue::error("ERR wrong number of arguments for 'SELECT' command");
+        }
+
+        let index = match self.get_integer(&args[0]) {
+            Some(index) => index,
+            None => return RespValue::error("ERR value is not an integer or out of range"),
+        };
+        if index != 0 && self.storage.cluster().is_enabled() {
+            return RespValue::error("ERR SELECT is not allowed in cluster mode");
+        }
+        match usize::try_from(index).ok().and_then(|i| self.storage.db(i)) {
+            Some(db) => {
+                self.session.select(db.index());
+                RespValue::ok()
+            }
+            None => RespValue::error("ERR DB index is out of range"),
+        }
+    }
+
+    /// COMMAND [COUNT]
+    ///
+    /// Lists the supported command names, or counts them.
+    fn cmd_command(&self, args: &[RespValue]) -> RespValue {
+        let subcommand = match args.first() {
+            Some(arg) => self.get_string(arg).unwrap_or_default().to_uppercase(),
+            None => {
+                let values: Vec<RespValue> = COMMAND_NAMES
+                    .iter()
+                    .map(|c| RespValue::bulk_string(Bytes::from_static(c.as_bytes())))
+                    .collect();
+                return RespValue::array(values);
+            }
+        };
+
+        match (subcommand.as_str(), args.len()) {
+            ("COUNT", 1) => RespValue::integer(COMMAND_NAMES.len() as i64),
+            ("COUNT", _) => {
+                RespValue::error("ERR wrong number of arguments for 'command|count' command")
+            }
+            _ => RespValue::error(format!(
+                "ERR unknown subcommand '{}'. Try COMMAND HELP.",
+                subcommand
+            )),
+        }
+    }
+
+    /// CONFIG GET parameter / CONFIG SET parameter value
+    ///
+    /// `notify-keyspace-events`, `read-only`, the memory limit and eviction
+    /// settings and `busy-reply-threshold` are configurable, and `databases`, `compact-keys` and the
+    /// protocol limits can be read; other parameters read as absent and
+    /// setting them is accepted but has no effect.
+    fn cmd_config(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'CONFIG' command");
+        }
+
+        let subcommand = match self.get_string(&args[0]) {
+            Some(s) => s.to_uppercase(),
+            None => return RespValue::error("ERR invalid subcommand"),
+        };
+
+        match subcommand.as_str() {
+            "GET" => {
+                if args.len() < 2 {
+                    return RespValue::error("ERR wrong number of arguments for 'CONFIG GET'");
+                }
+                let parameters = [
+                    (
+                        "notify-keyspace-events",
+                        self.storage.pubsub().notify_flags().to_string(),
+                    ),
+                    ("databases", self.storage.database_count().to_string()),
+                    (
+                        "read-only",
+                        if self.storage.is_read_only() {
+                            "yes"
+                        } else {
+                            "no"
+                        }
+                        .to_string(),
+                    ),
+                    (
+                        "proto-max-bulk-len",
+                        self.protocol_limits.max_bulk_len.to_string(),
+                    ),
+                    (
+                        "client-query-buffer-limit",
+                        self.protocol_limits.max_query_buffer.to_string(),
+                    ),
+                    ("maxmemory", self.storage.eviction().maxmemory().to_string()),
+                    (
+                        "maxmemory-policy",
+                        self.storage.eviction().policy().to_string(),
+                    ),
+                    ("lfu-log-factor", eviction::lfu_log_factor().to_string()),
+                    ("lfu-decay-time", eviction::lfu_decay_time().to_string()),
+                    (
+                        "busy-reply-threshold",
+                        self.storage.functions().busy_reply_threshold().to_string(),
+                    ),
+                    (
+                        "compact-keys",
+                        if self.storage.compact_keys() {
+                            "yes"
+                        } else {
+                            "no"
+                        }
+                        .to_string(),
+                    ),
+                ];
+                let mut wanted = [false; 11];
+                for arg in &args[1..] {
+                    let Some(pattern) = self.get_bytes(arg) else {
+                        return RespValue::error("ERR invalid parameter");
+                    };
+                    let pattern = GlobPattern::new(&pattern.to_ascii_lowercase());
+                    for (want, (name, _)) in wanted.iter_mut().zip(&parameters) {
+                        *want |= pattern.matches(name.as_bytes());
+                    }
+                }
+                let reply = parameters
+                    .into_iter()
+                    .zip(wanted)
+                    .filter(|(_, want)| *want)
+                    .map(|((name, value), _)| {
+                        (
+                            RespValue::bulk_string(name),
+                            RespValue::bulk_string(Bytes::from(value)),
+                        )
+                    })
+                    .collect();
+                self.map(reply)
+            }
+            "SET" => {
+                if args.len() != 3 {
+                    return RespValue::error("ERR wrong number of arguments for 'CONFIG SET'");
+                }
+                let parameter = self
+                    .get_string(&args[1])
+                    .unwrap_or_default()
+                    .to_ascii_lowercase();
+                let value = self.get_string(&args[2]);
+                match parameter.as_str() {
+                    "notify-keyspace-events" => match value.as_deref().and_then(NotifyFlags::parse) {
+                        Some(flags) => {
+                            self.db().pubsub().set_notify_flags(flags);
+                            RespValue::ok()
+                        }
+                        None => RespValue::error(
+                            "ERR CONFIG SET failed (possibly related to argument 'notify-keyspace-events') - Invalid event class character. Use 'Ag$lshzxeKEt'.",
+                        ),
+                    },
+                    "read-only" => match value.map(|v| v.to_ascii_lowercase()).as_deref() {
+                        Some("yes") => {
+                            self.storage.set_read_only(true);
+                            RespValue::ok()
+                        }
+                        Some("no") => {
+                            self.storage.set_read_only(false);
+                            RespValue::ok()
+                        }
+                        _ => RespValue::error(
+                            "ERR CONFIG SET failed (possibly related to argument 'read-only') - argument must be 'yes' or 'no'",
+                        ),
+                    },
+                    "maxmemory" => match value.as_deref().and_then(eviction::parse_maxmemory) {
+                        Some(bytes) => {
+                            self.storage.eviction().set_maxmemory(bytes);
+                            // Make room right away rather than on the next write
+                            self.storage.evict();
+                            RespValue::ok()
+                        }
+                        None => RespValue::error(
+                            "ERR CONFIG SET failed (possibly related to argument 'maxmemory') - argument must be a memory value",
+                        ),
+                    },
+                    "maxmemory-policy" => match value.as_deref().and_then(EvictionPolicy::parse) {
+                        Some(policy) => {
+                            self.storage.eviction().set_policy(policy);
+                            RespValue::ok()
+                        }
+                        None => RespValue::error(
+                            "ERR CONFIG SET failed (possibly related to argument 'maxmemory-policy') - argument(s) must be one of the following: noeviction, allkeys-lfu, volatile-lfu",
+                        ),
+                    },
+                    "lfu-log-factor" => match value.and_then(|v| v.parse().ok()) {
+                        Some(factor) => {
+                            eviction::set_lfu_log_factor(factor);
+                            RespValue::ok()
+                        }
+                        None => RespValue::error(
+                            "ERR CONFIG SET failed (possibly related to argument 'lfu-log-factor') - argument couldn't be parsed into an integer",
+                        ),
+                    },
+                    "lfu-decay-time" => match value.and_then(|v| v.parse().ok()) {
+                        Some(minutes) => {
+                            eviction::set_lfu_decay_time(minutes);
+                            RespValue::ok()
+                        }
+                        None => RespValue::error(
+                            "ERR CONFIG SET failed (possibly related to argument 'lfu-decay-time') - argument couldn't be parsed into an integer",
+                        ),
+                    },
+                    "busy-reply-threshold" => match value.and_then(|v| v.parse().ok()) {
+                        Some(ms) => {
+                            self.storage.functions().set_busy_reply_threshold(ms);
+                            RespValue::ok()
+                        }
+                        None => RespValue::error(
+                            "ERR CONFIG SET failed (possibly related to argument 'busy-reply-threshold') - argument couldn't be parsed into an integer",
+                        ),
+                    },
+                    // We don't support other parameters
+                    _ => RespValue::ok(),
+                }
+            }
+            _ => RespValue::error(format!("ERR unknown CONFIG subcommand '{}'", subcommand)),
+        }
+    }
+
+    /// TIME
+    fn cmd_time(&self, _args: &[RespValue]) -> RespValue {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+
+        let secs = now.as_secs().to_string();
+        let micros = (now.subsec_micros()).to_string();
+
+        RespValue::array(vec![
+            RespValue::bulk_string(Bytes::from(secs)),
+            RespValue::bulk_string(Bytes::from(micros)),
+        ])
+    }
+
+    /// SAVE
+    fn cmd_save(&self, args: &[RespValue]) -> RespValue {
+        if !args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'SAVE' command");
+        }
+
+        match self.storage.save() {
+            Ok(_) => RespValue::ok(),
+            Err(e) => RespValue::error(format!("ERR {}", e)),
+        }
+    }
+
+    /// LASTSAVE
+    fn cmd_lastsave(&self, args: &[RespValue]) -> RespValue {
+        if !args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'LASTSAVE' command");
+        }
+
+        RespValue::integer(self.db().snapshots().last_save())
+    }
+
+    /// BGSAVE
+    fn cmd_bgsave(&self, args: &[RespValue]) -> RespValue {
+        if !args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'BGSAVE' command");
+        }
+
+        match self.storage.bgsave() {
+            Ok(()) => RespValue::simple_string("Background saving started"),
+            Err(e) => RespValue::error(format!("ERR {}", e)),
+        }
+    }
+
+    /// BGREWRITEAOF
+    fn cmd_bgrewriteaof(&self, args: &[RespValue]) -> RespValue {
+        if !args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'BGREWRITEAOF' command");
+        }
+        if !self.db().aof().is_enabled() {
+            return RespValue::error("ERR append only file is disabled");
+        }
+
+        match self.storage.bgrewriteaof() {
+            Ok(()) => RespValue::simple_string("Background append only file rewriting started"),
+            Err(e) => RespValue::error(format!("ERR {}", e)),
+        }
+    }
+
+    /// REPLICAOF host port | REPLICAOF NO ONE
+    fn cmd_replicaof(&self, cmd: &str, args: &[RespValue]) -> RespValue {
+        if args.len() != 2 {
+            return RespValue::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                cmd
+            ));
+        }
+        let (Some(host), Some(port)) = (self.get_string(&args[0]), self.get_string(&args[1]))
+        else {
+            return RespValue::error("ERR syntax error");
+        };
+
+        let link = self.db().replication().link();
+        if host.eq_ignore_ascii_case("NO") && port.eq_ignore_ascii_case("ONE") {
+            link.set_master(None);
+            return RespValue::ok();
+        }
+        let Ok(port) = port.parse::<u16>() else {
+            return RespValue::error("ERR Invalid master port");
+        };
+        link.set_master(Some(MasterAddr { host, port }));
+        RespValue::ok()
+    }
+
+    /// REPLCONF option value [option value ...]
+    fn cmd_replconf(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() || !args.len().is_multiple_of(2) {
+            return RespValue::error("ERR wrong number of arguments for 'REPLCONF' command");
+        }
+
+        for pair in args.chunks(2) {
+            let (Some(option), Some(value)) =
+                (self.get_string(&pair[0]), self.get_string(&pair[1]))
+            else {
+                return RespValue::error("ERR syntax error");
+            };
+            match option.to_ascii_lowercase().as_str() {
+                "listening-port" => match value.parse::<u16>() {
+                    Ok(port) => self.session.set_listening_port(port),
+                    Err(_) => {
+                        return RespValue::error("ERR value is not an integer or out of range")
+                    }
+                },
+                // Acknowledgements arrive on the replication stream, which
+                // the connection reads itself; capabilities need no setup
+                "capa" | "ack" => {}
+                _ => {
+                    return RespValue::error(format!(
+                        "ERR Unrecognized REPLCONF option: {}",
+                        option
+                    ))
+                }
+            }
+        }
+        RespValue::ok()
+    }
+
+    /// CLUSTER subcommand [argument ...]
+    fn cmd_cluster(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'CLUSTER' command");
+        }
+        let cluster = self.db().cluster();
+        if !cluster.is_enabled() {
+            return RespValue::error("ERR This instance has cluster support disabled");
+        }
+
+        let subcommand = match self.get_string(&args[0]) {
+            Some(s) => s.to_uppercase(),
+            None => return RespValue::error("ERR invalid subcommand"),
+        };
+        let args = &args[1..];
+        let arity_error = || {
+            RespValue::error(format!(
+                "ERR wrong number of arguments for 'CLUSTER|{}' command",
+                subcommand.to_lowercase()
+            ))
+        };
+
+        match subcommand.as_str() {
+            "KEYSLOT" => match args {
+                [key] => match self.get_bytes(key) {
+                    Some(key) => RespValue::integer(key_slot(&key) as i64),
+                    None => RespValue::error("ERR invalid key"),
+                },
+                _ => arity_error(),
+            },
+            "MYID" => match args {
+                [] => RespValue::bulk_string(Bytes::from(cluster.myself().id)),
+                _ => arity_error(),
+            },
+            "INFO" => match args {
+                [] => self.cluster_info(),
+                _ => arity_error(),
+            },
+            "SLOTS" => match args {
+                [] => self.cluster_slots(),
+                _ => arity_error(),
+            },
+            "SHARDS" => match args {
+                [] => self.cluster_shards(),
+                _ => arity_error(),
+            },
+            "NODES" => match args {
+                [] => self.cluster_nodes(),
+                _ => arity_error(),
+            },
+            "ADDSLOTS" | "DELSLOTS" => {
+                if args.is_empty() {
+                    return arity_error();
+                }
+                let slots = match args
+                    .iter()
+                    .map(|arg| self.get_slot(arg))
+                    .collect::<Result<Vec<_>, _>>()
+                {
+                    Ok(slots) => slots,
+                    Err(e) => return e,
+                };
+                let result = if subcommand == "ADDSLOTS" {
+                    cluster.add_slots(&slots)
+                } else {
+                    cluster.del_slots(&slots)
+                };
+                match result {
+                    Ok(()) => RespValue::ok(),
+                    Err(e) => RespValue::error(e),
+                }
+            }
+            "SETSLOT" => self.cluster_setslot(args),
+            _ => RespValue::error(format!(
+                "ERR unknown subcommand '{}'. Try CLUSTER HELP.",
+                subcommand
+            )),
+        }
+    }
+
+    /// CLUSTER SETSLOT slot MIGRATING|IMPORTING|NODE node-id | STABLE
+    fn cluster_setslot(&self, args: &[RespValue]) -> RespValue {
+        if args.len() < 2 {
+            return RespValue::error("ERR wrong number of arguments for 'CLUSTER|setslot' command");
+        }
+        let slot = match self.get_slot(&args[0]) {
+            Ok(slot) => slot,
+            Err(e) => return e,
+        };
+        let action = self
+            .get_string(&args[1])
+            .map(|s| s.to_uppercase())
+            .unwrap_or_default();
+
+        let cluster = self.db().cluster();
+        let result =
+            match (action.as_str(), &args[2..]) {
+                ("STABLE", []) => {
+                    cluster.set_stable(slot);
+                    Ok(())
+                }
+                ("MIGRATING" | "IMPORTING" | "NODE", [id]) => {
+                    let Some(id) = self.get_string(id) else {
+                        return RespValue::error("ERR syntax error");
+                    };
+                    match action.as_str() {
+                        "MIGRATING" => cluster.set_migrating(slot, &id),
+                        "IMPORTING" => cluster.set_importing(slot, &id),
+                        _ => cluster.set_node(slot, &id),
+                    }
+                }
+                _ => return RespValue::error(
+                    "ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP",
+                ),
+            };
+        match result {
+            Ok(()) => RespValue::ok(),
+            Err(e) => RespValue::error(e),
+        }
+    }
+
+    /// CLUSTER INFO
+    fn cluster_info(&self) -> RespValue {
+        let cluster = self.db().cluster();
+        let ranges = cluster.slot_ranges();
+        let assigned: usize = ranges
+            .iter()
+            .map(|range| (range.end - range.start) as usize + 1)
+            .sum();
+        let mut serving: Vec<&str> = ranges.iter().map(|range| range.node.id.as_str()).collect();
+        serving.sort_unstable();
+        serving.dedup();
+
+        let info = format!(
+            "cluster_enabled:1\r\n\
+             cluster_state:{}\r\n\
+             cluster_slots_assigned:{}\r\n\
+             cluster_slots_ok:{}\r\n\
+             cluster_slots_pfail:0\r\n\
+             cluster_slots_fail:0\r\n\
+             cluster_known_nodes:{}\r\n\
+             cluster_size:{}\r\n\
+             cluster_current_epoch:0\r\n\
+             cluster_my_epoch:0\r\n",
+            if assigned == SLOT_COUNT as usize {
+                "ok"
+            } else {
+                "fail"
+            },
+            assigned,
+            assigned,
+            cluster.nodes().len(),
+            serving.len(),
+        );
+        RespValue::bulk_string(Bytes::from(info))
+    }
+
+    /// CLUSTER SLOTS: `[[start, end, [host, port, id]], ...]`
+    fn cluster_slots(&self) -> RespValue {
+        let ranges = self.db().cluster().slot_ranges();
+        RespValue::array(
+            ranges
+                .into_iter()
+                .map(|range| {
+                    RespValue::array(vec![
+                        RespValue::integer(range.start as i64),
+                        RespValue::integer(range.end as i64),
+                        RespValue::array(vec![
+                            RespValue::bulk_string(Bytes::from(range.node.host)),
+                            RespValue::integer(range.node.port as i64),
+                            RespValue::bulk_string(Bytes::from(range.node.id)),
+                        ]),
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    /// CLUSTER SHARDS: one entry per node, each a shard of its own
+    fn cluster_shards(&self) -> RespValue {
+        let cluster = self.db().cluster();
+        let ranges = cluster.slot_ranges();
+        let bulk = |s: &str| RespValue::bulk_string(Bytes::from(s.to_string()));
+
+        let shards = cluster
+            .nodes()
+            .into_iter()
+            .map(|node| {
+                let slots = ranges
+                    .iter()
+                    .filter(|range| range.node.id == node.id)
+                    .flat_map(|range| {
+                        [
+                            RespValue::integer(range.start as i64),
+                            RespValue::integer(range.end as i64),
+                        ]
+                    })
+                    .collect();
+                let node = RespValue::array(vec![
+                    bulk("id"),
+                    bulk(&node.id),
+                    bulk("port"),
+                    RespValue::integer(node.port as i64),
+                    bulk("ip"),
+                    bulk(&node.host),
+                    bulk("endpoint"),
+                    bulk(&node.host),
+                    bulk("role"),
+                    bulk("master"),
+                    bulk("replication-offset"),
+                    RespValue::integer(0),
+                    bulk("health"),
+                    bulk("online"),
+                ]);
+                RespValue::array(vec![
+                    bulk("slots"),
+                    RespValue::array(slots),
+                    bulk("nodes"),
+                    RespValue::array(vec![node]),
+                ])
+            })
+            .collect();
+        RespValue::array(shards)
+    }
+
+    /// CLUSTER NODES: one line per node, in the Redis format
+    fn cluster_nodes(&self) -> RespValue {
+        let cluster = self.db().cluster();
+        let ranges = cluster.slot_ranges();
+        let myself = cluster.myself();
+
+        let mut out = String::new();
+        for node in cluster.nodes() {
+            let is_myself = node.id == myself.id;
+            out.push_str(&format!(
+                "{} {}:{}@{} {} - 0 0 0 connected",
+                node.id,
+                node.host,
+                node.port,
+                node.port as u32 + 10000,
+                if is_myself { "myself,master" } else { "master" },
+            ));
+            for range in ranges.iter().filter(|range| range.node.id == node.id) {
+                if range.start == range.end {
+                    out.push_str(&format!(" {}", range.start));
+                } else {
+                    out.push_str(&format!(" {}-{}", range.start, range.end));
+                }
+            }
+            if is_myself {
+                for (slot, id) in cluster.migrating() {
+                    out.push_str(&format!(" [{}->-{}]", slot, id));
+                }
+                for (slot, id) in cluster.importing() {
+                    out.push_str(&format!(" [{}-<-{}]", slot, id));
+                }
+            }
+            out.push('\n');
+        }
+        RespValue::bulk_string(Bytes::from(out))
+    }
+
+    /// Parses a hash slot number.
+    fn get_slot(&self, value: &RespValue) -> Result<u16, RespValue> {
+        match self.get_integer(value) {
+            Some(slot) if (0..SLOT_COUNT as i64).contains(&slot) => Ok(slot as u16),
+            _ => Err(RespValue::error("ERR Invalid or out of range slot")),
+        }
+    }
+
+    /// HELLO [protover [AUTH username password] [SETNAME clientname]]
+    ///
+    /// Switches the connection to RESP2 or RESP3 and describes the server.
+    /// Under RESP3, key/value replies (HELLO itself, CONFIG GET, CLIENT
+    /// INFO, MEMORY STATS) come back as maps, scores and INCRBYFLOAT as
+    /// doubles, and pub/sub messages and invalidations as push frames; the
+    /// rest keep their RESP2 shape.
+    ///
+    /// FlashKV has no passwords: like Redis' `default` user with `nopass`,
+    /// AUTH accepts any password for `default` and no other user. Nothing
+    /// changes unless every option is valid.
+    fn cmd_hello(&self, args: &[RespValue]) -> RespValue {
+        let version = match args.first().map(|arg| self.get_integer(arg)) {
+            None => None,
+            Some(Some(version @ (2 | 3))) => Some(version as u8),
+            Some(Some(_)) => return RespValue::error("NOPROTO unsupported protocol version"),
+            Some(None) => {
+                return RespValue::error("ERR Protocol version is not an integer or out of range")
+            }
+        };
+
+        let mut user = None;
+        let mut name = None;
+        let mut options = args.iter().skip(1);
+        while let Some(option) = options.next() {
+            let option = self.get_string(option).unwrap_or_default().to_uppercase();
+            match (option.as_str(), options.next()) {
+                ("AUTH", Some(username)) => {
+                    let (Some(username), Some(_password)) =
+                        (self.get_bytes(username), options.next())
+                    else {
+                        return RespValue::error("ERR Syntax error in HELLO option 'AUTH'");
+                    };
+                    if &username[..] != b"default" {
+                        return RespValue::error(
+                            "WRONGPASS invalid username-password pair or user is disabled.",
+                        );
+                    }
+                    user = Some(username);
+                }
+                ("SETNAME", Some(arg)) => match self.client_name(arg) {
+                    Ok(arg) => name = Some(arg),
+                    Err(e) => return e,
+                },
+                _ => {
+                    return RespValue::error(format!(
+                        "ERR Syntax error in HELLO option '{}'",
+                        option.to_lowercase()
+                    ))
+                }
+            }
+        }
+
+        if let Some(version) = version {
+            self.session.set_protocol(version);
+        }
+        if let Some(user) = user {
+            self.session.set_user(user);
+        }
+        if let Some(name) = name {
+            self.session.set_name(name);
+        }
+
+        let resp3 = self.session.resp3();
+        let mode = if self.db().cluster().is_enabled() {
+            "cluster"
+        } else {
+            "standalone"
+        };
+        let role = if self.db().replication().link().is_replica() {
+            "replica"
+        } else {
+            "master"
+        };
+        let fields = [
+            ("server", RespValue::bulk_string("flashkv")),
+            ("version", RespValue::bulk_string(crate::VERSION)),
+            ("proto", RespValue::integer(if resp3 { 3 } else { 2 })),
+            ("id", RespValue::integer(self.session.id() as i64)),
+            ("mode", RespValue::bulk_string(mode)),
+            ("role", RespValue::bulk_string(role)),
+            ("modules", RespValue::array(vec![])),
+        ]
+        .map(|(name, value)| (RespValue::bulk_string(name), value));
+        self.map(fields.into())
+    }
+
+    /// Parses the name given with CLIENT SETNAME or HELLO SETNAME, where
+    /// an empty name clears it.
+    fn client_name(&self, arg: &RespValue) -> Result<Option<Bytes>, RespValue> {
+        let Some(name) = self.get_bytes(arg) else {
+            return Err(RespValue::error("ERR invalid client name"));
+        };
+        // Names show up in space-separated lists, like Redis' CLIENT LIST
+        if name.iter().any(|b| !(b'!'..=b'~').contains(b)) {
+            return Err(RespValue::error(
+                "ERR Client names cannot contain spaces, newlines or special characters.",
+            ));
+        }
+        Ok((!name.is_empty()).then_some(name))
+    }
+
+    /// CLIENT ID | SETNAME name | GETNAME | TRACKING ON|OFF [REDIRECT id] [NOLOOP] | GETREDIR
+    fn cmd_client(&self, args: &[RespValue]) -> RespValue {
+        let Some(subcommand) = args.first().and_then(|arg| self.get_string(arg)) else {
+            return RespValue::error("ERR wrong number of arguments for 'CLIENT' command");
+        };
+        let subcommand = subcommand.to_uppercase();
+        let args = &args[1..];
+        let tracking = self.db().pubsub().tracking();
+
+        match subcommand.as_str() {
+            "ID" if args.is_empty() => RespValue::integer(self.session.id() as i64),
+            "INFO" if args.is_empty() && self.session.resp3() => self.map(
+                self.session
+                    .info()
+                    .split(' ')
+                    .filter_map(|field| field.split_once('='))
+                    .map(|(name, value)| {
+                        (
+                            RespValue::bulk_string(Bytes::copy_from_slice(name.as_bytes())),
+                            RespValue::bulk_string(Bytes::copy_from_slice(value.as_bytes())),
+                        )
+                    })
+                    .collect(),
+            ),
+            "INFO" if args.is_empty() => RespValue::bulk_string(self.session.info() + "\n"),
+            "LIST" => self.client_list(args),
+            "KILL" if !args.is_empty() => self.client_kill(args),
+            "PAUSE" if !args.is_empty() && args.len() <= 2 => self.client_pause(args),
+            "UNPAUSE" if args.is_empty() => {
+                clients::unpause();
+                RespValue::ok()
+            }
+            "SETINFO" if args.len() == 2 => self.client_setinfo(&args[0], &args[1]),
+            "NO-EVICT" | "NO-TOUCH" if args.len() == 1 => {
+                let on = match self
+                    .get_string(&args[0])
+                    .map(|s| s.to_uppercase())
+                    .as_deref()
+                {
+                    Some("ON") => true,
+                    Some("OFF") => false,
+                    _ => return RespValue::error("ERR syntax error"),
+                };
+                if subcommand == "NO-EVICT" {
+                    self.session.set_no_evict(on);
+                } else {
+                    self.session.set_no_touch(on);
+                }
+                RespValue::ok()
+            }
+            "GETNAME" if args.is_empty() => match self.session.name() {
+                Some(name) => RespValue::bulk_string(name),
+                None => RespValue::null(),
+            },
+            "SETNAME" if args.len() == 1 => match self.client_name(&args[0]) {
+                Ok(name) => {
+                    self.session.set_name(name);
+                    RespValue::ok()
+                }
+                Err(e) => e,
+            },
+            "GETREDIR" if args.is_empty() => match tracking.target(self.session.id()) {
+                None => RespValue::integer(-1),
+                Some(Target::Push(_)) => RespValue::integer(0),
+                Some(Target::Redirect(id)) => RespValue::integer(id as i64),
+            },
+            "TRACKING" if !args.is_empty() => self.client_tracking(args),
+            "ID" | "INFO" | "SETINFO" | "KILL" | "PAUSE" | "UNPAUSE" | "NO-EVICT" | "NO-TOUCH"
+            | "GETNAME" | "SETNAME" | "GETREDIR" | "TRACKING" => RespValue::error(format!(
+                "ERR wrong number of arguments for 'CLIENT|{}' command",
+                subcommand
+            )),
+            _ => RespValue::error(format!(
+                "ERR unknown subcommand '{}'. Try CLIENT HELP.",
+                subcommand
+            )),
+        }
+    }
+
+    /// CLIENT LIST [ID client-id [client-id ...]]
+    fn client_list(&self, args: &[RespValue]) -> RespValue {
+        let sessions = match args.split_first() {
+            None => clients::list(),
+            Some((option, ids))
+                if !ids.is_empty()
+                    && self
+                        .get_string(option)
+                        .is_some_and(|o| o.eq_ignore_ascii_case("ID")) =>
+            {
+                let mut sessions = Vec::new();
+                for id in ids {
+                    match self.get_integer(id) {
+                        Some(id) if id > 0 => sessions.extend(clients::get(id as u64)),
+                        _ => return RespValue::error("ERR Invalid client ID"),
+                    }
+                }
+                sessions
+            }
+            Some(_) => return RespValue::error("ERR syntax error"),
+        };
+
+        let list: String = sessions.iter().map(|s| s.info() + "\n").collect();
+        RespValue::bulk_string(list)
+    }
+
+    /// CLIENT KILL addr:port | CLIENT KILL [ID id] [ADDR addr:port] [LADDR addr:port]
+    /// [USER username] [SKIPME yes|no]
+    fn client_kill(&self, args: &[RespValue]) -> RespValue {
+        let addr = |arg: &RespValue| self.get_string(arg).and_then(|a| a.parse().ok());
+
+        // The old form kills one client by address, even this one
+        if let [arg] = args {
+            let Some(addr) = addr(arg) else {
+                return RespValue::error("ERR No such client");
+            };
+            let filter = KillFilter {
+                addr: Some(addr),
+                ..Default::default()
+            };
+            return match clients::kill(&filter) {
+                0 => RespValue::error("ERR No such client"),
+                _ => RespValue::ok(),
+            };
+        }
+
+        let mut filter = KillFilter {
+            skip: Some(self.session.id()),
+            ..Default::default()
+        };
+        for pair in args.chunks(2) {
+            let [option, value] = pair else {
+                return RespValue::error("ERR syntax error");
+            };
+            let option = self.get_string(option).unwrap_or_default().to_uppercase();
+            match option.as_str() {
+                "ID" => match self.get_integer(value) {
+                    Some(id) if id > 0 => filter.id = Some(id as u64),
+                    _ => return RespValue::error("ERR client-id should be greater than 0"),
+                },
+                "ADDR" | "LADDR" => {
+                    let Some(addr) = addr(value) else {
+                        return RespValue::error("ERR syntax error");
+                    };
+                    if option == "ADDR" {
+                        filter.addr = Some(addr);
+                    } else {
+                        filter.laddr = Some(addr);
+                    }
+                }
+                "USER" => filter.user = self.get_bytes(value).map(|user| user.to_vec()),
+                "SKIPME" => match self.get_string(value).map(|s| s.to_lowercase()).as_deref() {
+                    Some("yes") => filter.skip = Some(self.session.id()),
+                    Some("no") => filter.skip = None,
+                    _ => return RespValue::error("ERR syntax error"),
+                },
+                _ => return RespValue::error("ERR syntax error"),
+            }
+        }
+        RespValue::integer(clients::kill(&filter) as i64)
+    }
+
+    /// CLIENT PAUSE timeout [WRITE|ALL]
+    fn client_pause(&self, args: &[RespValue]) -> RespValue {
+        let timeout = match self.get_integer(&args[0]) {
+            Some(ms) if ms >= 0 => Duration::from_millis(ms as u64),
+            _ => return RespValue::error("ERR timeout is not an integer or out of range"),
+        };
+        let mode = match args
+            .get(1)
+            .map(|arg| self.get_string(arg).unwrap_or_default().to_uppercase())
+        {
+            None => PauseMode::All,
+            Some(mode) if mode == "ALL" => PauseMode::All,
+            Some(mode) if mode == "WRITE" => PauseMode::Write,
+            Some(_) => return RespValue::error("ERR syntax error"),
+        };
+        let Some(until) = Instant::now().checked_add(timeout) else {
+            return RespValue::error("ERR timeout is not an integer or out of range");
+        };
+        clients::pause(mode, until);
+        RespValue::ok()
+    }
+
+    /// CLIENT SETINFO LIB-NAME|LIB-VER value
+    fn client_setinfo(&self, attribute: &RespValue, value: &RespValue) -> RespValue {
+        let Some(value) = self.get_bytes(value) else {
+            return RespValue::error("ERR invalid value");
+        };
+        let attribute = self
+            .get_string(attribute)
+            .unwrap_or_default()
+            .to_uppercase();
+        // Like names, shown in space-separated lists
+        if value.iter().any(|b| !(b'!'..=b'~').contains(b)) {
+            return RespValue::error(format!(
+                "ERR {} cannot contain spaces, newlines or special characters.",
+                attribute
+            ));
+        }
+
+        let value = (!value.is_empty()).then_some(value);
+        match attribute.as_str() {
+            "LIB-NAME" => self.session.set_lib_name(value),
+            "LIB-VER" => self.session.set_lib_ver(value),
+            _ => {
+                return RespValue::error(format!("ERR Unrecognized option '{}'", attribute));
+            }
+        }
+        RespValue::ok()
+    }
+
+    /// CLIENT TRACKING ON|OFF [REDIRECT id] [NOLOOP]
+    fn client_tracking(&self, args: &[RespValue]) -> RespValue {
+        let tracking = self.db().pubsub().tracking();
+        let on = match self
+            .get_string(&args[0])
+            .map(|s| s.to_uppercase())
+            .as_deref()
+        {
+            Some("ON") => true,
+            Some("OFF") => false,
+            _ => return RespValue::error("ERR syntax error"),
+        };
+
+        let mut redirect = None;
+        let mut noloop = false;
+        let mut i = 1;
+        while i < args.len() {
+            let option = self.get_string(&args[i]).unwrap_or_default().to_uppercase();
+            match option.as_str() {
+                "REDIRECT" if i + 1 < args.len() => {
+                    match self.get_integer(&args[i + 1]) {
+                        Some(id) if id > 0 && id as u64 != self.session.id() => {
+                            redirect = Some(id as u64)
+                        }
+                        Some(_) => {
+                            return RespValue::error(
+                                "ERR The client ID you want redirect to does not exist",
+                            )
+                        }
+                        None => return RespValue::error("ERR Invalid client ID"),
+                    }
+                    i += 2;
+                }
+                "NOLOOP" => {
+                    noloop = true;
+                    i += 1;
+                }
+                "BCAST" | "OPTIN" | "OPTOUT" | "PREFIX" => {
+                    return RespValue::error(format!(
+                        "ERR CLIENT TRACKING {} is not supported",
+                        option
+                    ))
+                }
+                _ => return RespValue::error("ERR syntax error"),
+            }
+        }
+
+        if !on {
+            tracking.disable(self.session.id());
+            self.session.set_tracking(false);
+            return RespValue::ok();
+        }
+
+        let target = match redirect {
+            Some(id) => Target::Redirect(id),
+            None if self.session.resp3() => Target::Push(self.session.subscriber().sender()),
+            None => {
+                return RespValue::error(
+                    "ERR CLIENT TRACKING without REDIRECT needs RESP3 push messages; switch with HELLO 3",
+                )
+            }
+        };
+        tracking.enable(self.session.id(), target, noloop);
+        self.session.set_tracking(true);
+        RespValue::ok()
+    }
+
+    /// ASKING
+    fn cmd_asking(&self, args: &[RespValue]) -> RespValue {
+        if !args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'ASKING' command");
+        }
+        if !self.db().cluster().is_enabled() {
+            return RespValue::error("ERR This instance has cluster support disabled");
+        }
+        self.session.set_asking();
+        RespValue::ok()
+    }
+
+    /// EXPORT file [FORMAT JSON|CSV] [MATCH pattern]
+    fn cmd_export(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'EXPORT' command");
+        }
+
+        let path = match self.get_string(&args[0]) {
+            Some(p) => PathBuf::from(p),
+            None => return RespValue::error("ERR invalid file name"),
+        };
+
+        let mut format = ExportFormat::Json;
+        let mut pattern = None;
+
+        let mut i = 1;
+        while i < args.len() {
+            let opt = match self.get_string(&args[i]) {
+                Some(s) => s.to_uppercase(),
+                None => return RespValue::error("ERR syntax error"),
+            };
+            let value = match args.get(i + 1) {
+                Some(v) => v,
+                None => return RespValue::error("ERR syntax error"),
+            };
+
+            match opt.as_str() {
+                "FORMAT" => match self.get_string(value).and_then(|f| f.parse().ok()) {
+                    Some(f) => format = f,
+                    None => return RespValue::error("ERR FORMAT must be JSON or CSV"),
+                },
+                "MATCH" => match self.get_bytes(value) {
+                    Some(p) => pattern = Some(p),
+                    None => return RespValue::error("ERR invalid pattern"),
+                },
+                _ => return RespValue::error("ERR syntax error"),
+            }
+            i += 2;
+        }
+
+        match self.db().export(&path, format, pattern.as_deref()) {
+            Ok(keys) => RespValue::integer(keys as i64),
+            Err(e) => RespValue::error(format!("ERR {}", e)),
+        }
+    }
+
+    /// MONITOR
+    ///
+    /// Makes the client receive every command run from now on, through the
+    /// same queue as its pub/sub messages.
+    fn cmd_monitor(&self, args: &[RespValue]) -> RespValue {
+        if !args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'MONITOR' command");
+        }
+
+        self.session.set_monitor();
+        clients::monitor(&self.session);
+        RespValue::ok()
+    }
+
+    /// DEBUG commands (for testing)
+    fn cmd_debug(&self, args: &[RespValue]) -> RespValue {
+        if args.is_empty() {
+            return RespValue::error("ERR wrong number of arguments for 'DEBUG' command");
+        }
+
+        let subcommand = match self.get_string(&args[0]) {
+            Some(s) => s.to_uppercase(),
+            None => return RespValue::error("ERR invalid subcommand"),
+        };
+
+        match subcommand.as_str() {
+            "SLEEP" => {
+                if args.len() < 2 {
+                    return RespValue::error("ERR wrong number of arguments for 'DEBUG SLEEP'");
+                }
+                // We don't actually sleep (it would block), just return OK
+                RespValue::ok()
+            }
+            "RELOAD" => match self.storage.debug_reload() {
+                Ok(_) => RespValue::ok(),
+                Err(e) => RespValue::error(format!("ERR Error trying to reload: {}", e)),
+            },
+            _ => RespValue::error(format!("ERR unknown DEBUG subcommand '{}'", subcommand)),
+        }
+    }
+}
+
+/// Every command the handler runs, upper-cased and sorted so
+/// [`lookup_command`] can binary search it.
+#[rustfmt::skip]
+const COMMAND_NAMES: &[&str] = &[
+    "APPEND", "ASKING", "BGREWRITEAOF", "BGSAVE", "BITCOUNT", "BITPOS", "BLPOP", "BRPOP",
+    "BZPOPMAX", "BZPOPMIN", "CLIENT", "CLUSTER", "COMMAND", "CONFIG", "COPY", "DBSIZE", "DEBUG",
+    "DECR", "DECRBY", "DEL", "ECHO", "EXISTS", "EXPIRE", "EXPIREAT", "EXPIRETIME", "EXPORT",
+    "FCALL", "FCALL_RO", "FLUSHALL", "FLUSHDB", "FUNCTION", "GET", "GETBIT", "GETDEL", "GETSET",
+    "HDEL", "HELLO", "HEXISTS", "HGET", "HGETALL", "HINCRBY", "HINCRBYFLOAT", "HLEN", "HMSET", "HRANDFIELD", "HSET", "HSETNX", "INCR",
+    "INCRBY", "INCRBYFLOAT", "INFO", "KEYS", "LASTSAVE", "LINDEX", "LLEN", "LMOVE", "LPOP", "LPUSH",
+    "LRANGE", "LREM", "LSET", "LTRIM", "MEMORY", "MGET", "MONITOR", "MOVE", "MSET", "MSETNX", "OBJECT", "PERSIST",
+    "PEXPIRE", "PEXPIREAT", "PEXPIRETIME", "PFADD", "PFCOUNT", "PFMERGE", "PING", "PSETEX",
+    "PSUBSCRIBE", "PSYNC", "PTTL", "PUBLISH", "PUBSUB", "PUNSUBSCRIBE", "QUIT", "RENAME",
+    "RENAMENX", "REPLCONF", "REPLICAOF", "RPOP", "RPOPLPUSH", "RPUSH", "SADD", "SAVE", "SCAN",
+    "SCARD", "SDIFF", "SDIFFSTORE", "SELECT", "SET", "SETBIT", "SETEX", "SETNX", "SINTER",
+    "SINTERCARD", "SINTERSTORE", "SISMEMBER", "SLAVEOF", "SMEMBERS", "SMISMEMBER", "SORT", "SREM",
+    "STRLEN", "SUBSCRIBE", "SUNION", "SUNIONSTORE", "SYNC", "TIME", "TOUCH", "TTL", "TYPE",
+    "UNLINK", "UNSUBSCRIBE", "XACK", "XADD", "XCLAIM", "XGROUP", "XLEN", "XPENDING", "XRANGE",
+    "XREAD", "XREADGROUP", "XREVRANGE", "ZADD", "ZCARD", "ZDIFFSTORE", "ZINCRBY", "ZINTERSTORE",
+    "ZPOPMAX", "ZPOPMIN", "ZRANDMEMBER", "ZRANGE", "ZRANK", "ZREM", "ZREVRANGE", "ZREVRANK",
+    "ZSCORE", "ZUNIONSTORE",
+];
+
+/// Commands functions can't send: those that block or change the
+/// connection, would run a function themselves, or take the locks a
+/// function runs under.
+#[rustfmt::skip]
+const NOSCRIPT_COMMANDS: &[&str] = &[
+    "ASKING", "BGREWRITEAOF", "BGSAVE", "CLIENT", "CLUSTER", "CONFIG", "DEBUG", "EXPORT",
+    "FCALL", "FCALL_RO", "FUNCTION", "HELLO", "MONITOR", "PSUBSCRIBE", "PSYNC", "PUNSUBSCRIBE",
+    "QUIT", "REPLCONF", "REPLICAOF", "SAVE", "SELECT", "SLAVEOF", "SUBSCRIBE", "SYNC",
+    "UNSUBSCRIBE",
+];
+
+/// Replies with a function error.
+fn function_error(e: FunctionError) -> RespValue {
+    let prefix = match e {
+        FunctionError::Busy => "BUSY",
+        FunctionError::NotBusy => "NOTBUSY",
+        _ => "ERR",
+    };
+    RespValue::error(format!("{} {}", prefix, e))
+}
+
+/// Returns the name of the command `name` is, in any case, as it appears
+/// in the command table; `None` if there's no such command.
+///
+/// Comparing bytes case-insensitively against a static table lets hot-path
+/// dispatch match commands without allocating an upper-cased copy.
+pub fn lookup_command(name: &[u8]) -> Option<&'static str> {
+    COMMAND_NAMES
+        .binary_search_by(|probe| probe.bytes().cmp(name.iter().map(u8::to_ascii_uppercase)))
+        .ok()
+        .map(|i| COMMAND_NAMES[i])
+}
+
+/// Commands that can modify the keyspace.
+#[rustfmt::skip]
+const WRITE_COMMANDS: &[&str] = &[
+    "SET", "DEL", "APPEND", "INCR", "INCRBY", "INCRBYFLOAT", "DECR", "DECRBY", "MSET", "MSETNX",
+    "SETNX", "SETEX", "PSETEX", "GETSET", "GETDEL", "SETBIT", "PFADD", "PFMERGE", "LPUSH",
+    "RPUSH", "LPOP", "RPOP", "LSET", "LREM", "LTRIM", "LMOVE", "RPOPLPUSH", "BLPOP", "BRPOP",
+    "HSET", "HMSET", "HDEL", "HINCRBY", "HINCRBYFLOAT", "HSETNX", "SADD", "SREM",
+    "SINTERSTORE", "SUNIONSTORE", "SDIFFSTORE", "ZADD", "ZREM", "ZINCRBY", "ZPOPMIN", "ZPOPMAX",
+    "BZPOPMIN", "BZPOPMAX", "ZUNIONSTORE", "ZINTERSTORE", "ZDIFFSTORE", "XADD", "XGROUP",
+    "XREADGROUP", "XACK", "XCLAIM", "EXPIRE", "PEXPIRE", "EXPIREAT", "PEXPIREAT", "PERSIST",
+    "RENAME", "RENAMENX", "COPY", "MOVE", "SORT", "UNLINK", "FLUSHDB", "FLUSHALL",
+];
+
+/// Returns true if `cmd` (upper-cased) can modify the keyspace.
+///
+/// Every successful call counts as one change towards the save rules, even
+/// if it turned out to change nothing (such as DEL of a missing key).
+pub fn is_write_command(cmd: &str) -> bool {
+    WRITE_COMMANDS.contains(&cmd)
+}
+
+/// Write commands that never take more memory, so they still run when keys
+/// can't be evicted to stay within `maxmemory`.
+#[rustfmt::skip]
+const SHRINKING_COMMANDS: &[&str] = &[
+    "DEL", "UNLINK", "GETDEL", "LPOP", "RPOP", "BLPOP", "BRPOP", "LREM", "LTRIM", "HDEL",
+    "SREM", "ZREM", "ZPOPMIN", "ZPOPMAX", "BZPOPMIN", "BZPOPMAX", "XACK", "EXPIRE", "PEXPIRE",
+    "EXPIREAT", "PEXPIREAT", "PERSIST", "FLUSHDB", "FLUSHALL",
+];
+
+/// Returns true if the write command `cmd` (upper-cased) may take more
+/// memory, and so is refused while over `maxmemory`.
+fn may_use_memory(cmd: &str) -> bool {
+    !SHRINKING_COMMANDS.contains(&cmd)
+}
+
+/// Sections of INFO by the name that selects them and the title they're
+/// shown under, in the order INFO shows them.
+const INFO_SECTIONS: &[(&str, &str)] = &[
+    ("server", "Server"),
+    ("clients", "Clients"),
+    ("memory", "Memory"),
+    ("persistence", "Persistence"),
+    ("stats", "Stats"),
+    ("replication", "Replication"),
+    ("cpu", "CPU"),
+    ("cluster", "Cluster"),
+    ("keyspace", "Keyspace"),
+];
+
+/// Returns the process's resident memory per byte of the dataset, which
+/// counts allocator fragmentation and overhead alike.
+fn fragmentation_ratio(alloc: &AllocatorStats, mem: &MemoryInfo) -> Option<f64> {
+    match alloc.rss {
+        Some(rss) if mem.used_memory > 0 => Some(rss as f64 / mem.used_memory as f64),
+        _ => None,
+    }
+}
+
+/// Formats the allocator lines of the `# Memory` section of INFO, leaving
+/// out what the allocator doesn't report.
+fn allocator_info(mem: &MemoryInfo) -> String {
+    let alloc = allocator::stats();
+    let mut info = String::new();
+    if let Some(rss) = alloc.rss {
+        info.push_str(&format!("used_memory_rss:{}\r\n", rss));
+    }
+    if let Some(ratio) = fragmentation_ratio(&alloc, mem) {
+        info.push_str(&format!("mem_fragmentation_ratio:{:.2}\r\n", ratio));
+    }
+    info.push_str(&format!("mem_allocator:{}\r\n", allocator::name()));
+
+    let sizes = [
+        ("allocator_allocated", alloc.allocated),
+        ("allocator_active", alloc.active),
+        ("allocator_resident", alloc.resident),
+    ];
+    for (name, value) in sizes {
+        if let Some(value) = value {
+            info.push_str(&format!("{}:{}\r\n", name, value));
+        }
+    }
+    let ratios = [
+        ("allocator_frag_ratio", alloc.fragmentation_ratio()),
+        ("allocator_rss_ratio", alloc.rss_ratio()),
+    ];
+    for (name, ratio) in ratios {
+        if let Some(ratio) = ratio {
+            info.push_str(&format!("{}:{:.2}\r\n", name, ratio));
+        }
+    }
+    info
+}
+
+/// Formats the `# CPU` section of INFO: the seconds of CPU time the process
+/// and its children used, in the kernel and in user space. They are read
+/// from `/proc/self/stat`, so the section is empty off Linux.
+fn cpu_info() -> String {
+    let Some(times) = cpu_times() else {
+        return String::new();
+    };
+    format!(
+        "used_cpu_sys:{:.6}\r\n\
+         used_cpu_user:{:.6}\r\n\
+         used_cpu_sys_children:{:.6}\r\n\
+         used_cpu_user_children:{:.6}\r\n",
+        times[1], times[0], times[3], times[2]
+    )
+}
+
+/// Reads the user, system, children's user and children's system CPU
+/// seconds of the process.
+fn cpu_times() -> Option<[f64; 4]> {
+    // The kernel reports these times in USER_HZ ticks, 100 per second on
+    // every architecture Linux exposes it to user space for
+    const TICKS_PER_SECOND: f64 = 100.0;
+
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name before the fields may contain spaces, but is wrapped
+    // in parentheses; utime is the 12th field after it
+    let mut fields = stat
+        .get(stat.rfind(')')? + 1..)?
+        .split_whitespace()
+        .skip(11);
+    let mut times = [0.0; 4];
+    for time in &mut times {
+        *time = fields.next()?.parse::<u64>().ok()? as f64 / TICKS_PER_SECOND;
+    }
+    Some(times)
+}
+
+/// Formats stream entries as `[[id, [field, value, ...]], ...]`.
+fn stream_records_reply(records: Vec<StreamRecord>) -> RespValue {
+    RespValue::array(
+        records
+            .into_iter()
+            .map(|(id, fields)| stream_entry_reply((id, Some(fields))))
+            .collect(),
+    )
+}
+
+/// Formats one stream entry as `[id, [field, value, ...]]`, or `[id, nil]`
+/// for an entry that no longer exists.
+fn stream_entry_reply((id, fields): (StreamId, Option<StreamFields>)) -> RespValue {
+    let fields = match fields {
+        Some(fields) => RespValue::array(
+            fields
+                .into_iter()
+                .flat_map(|(f, v)| [RespValue::bulk_string(f), RespValue::bulk_string(v)])
+                .collect(),
+        ),
+        None => RespValue::null(),
+    };
+    RespValue::array(vec![RespValue::bulk_string(id.to_bytes()), fields])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_handler() -> CommandHandler {
+        let storage = Arc::new(StorageEngine::new());
+        CommandHandler::new(storage)
+    }
+
+    fn make_command(args: &[&str]) -> RespValue {
+        RespValue::Array(
+            args.iter()
+                .map(|s| RespValue::bulk_string(Bytes::from(s.to_string())))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_replconf_and_sync() {
+        let handler = create_handler();
+        assert_eq!(handler.listening_port(), 0);
+
+        let response = handler.execute(make_command(&[
+            "REPLCONF",
+            "listening-port",
+            "6380",
+            "capa",
+            "psync2",
+        ]));
+        assert_eq!(response, RespValue::ok());
+        assert_eq!(handler.listening_port(), 6380);
+        assert!(handler
+            .execute(make_command(&["REPLCONF", "listening-port"]))
+            .is_error());
+        assert!(handler
+            .execute(make_command(&["REPLCONF", "bogus", "1"]))
+            .is_error());
+
+        assert!(matches!(
+            handler.execute_or_block(make_command(&["PSYNC", "?", "-1"])),
+            CommandOutcome::Sync(SyncRequest::Psync { offset: None, .. })
+        ));
+        assert!(matches!(
+            handler.execute_or_block(make_command(&["PSYNC", "abc", "42"])),
+            CommandOutcome::Sync(SyncRequest::Psync {
+                offset: Some(42),
+                ..
+            })
+        ));
+        assert!(matches!(
+            handler.execute_or_block(make_command(&["PSYNC", "abc", "x"])),
+            CommandOutcome::Reply(RespValue::Error(_))
+        ));
+        assert!(matches!(
+            handler.execute_or_block(make_command(&["SYNC"])),
+            CommandOutcome::Sync(SyncRequest::Sync)
+        ));
+        assert!(matches!(
+            handler.execute_or_block(make_command(&["PSYNC"])),
+            CommandOutcome::Reply(RespValue::Error(_))
+        ));
+        assert!(handler.execute(make_command(&["SYNC"])).is_error());
+    }
+
+    #[test]
+    fn test_subscribe_and_pubsub_introspection() {
+        let handler = create_handler();
+        let other = CommandHandler::new(Arc::clone(handler.storage()));
+
+        let CommandOutcome::Replies(replies) =
+            handler.execute_or_block(make_command(&["SUBSCRIBE", "news", "sport"]))
+        else {
+            panic!("expected one reply per channel");
+        };
+        assert_eq!(
+            replies,
+            vec![
+                make_reply(&["subscribe", "news"], 1),
+                make_reply(&["subscribe", "sport"], 2),
+            ]
+        );
+        other.execute_or_block(make_command(&["SUBSCRIBE", "news"]));
+        other.execute_or_block(make_command(&["PSUBSCRIBE", "n*"]));
+
+        assert_eq!(
+            other.execute(make_command(&["PUBSUB", "CHANNELS"])),
+            RespValue::array(vec![
+                RespValue::bulk_string("news"),
+                RespValue::bulk_string("sport"),
+            ])
+        );
+        assert_eq!(
+            other.execute(make_command(&["PUBSUB", "CHANNELS", "s*"])),
+            RespValue::array(vec![RespValue::bulk_string("sport")])
+        );
+        assert_eq!(
+            other.execute(make_command(&["PUBSUB", "NUMSUB", "news", "none"])),
+            RespValue::array(vec![
+                RespValue::bulk_string("news"),
+                RespValue::integer(2),
+                RespValue::bulk_string("none"),
+                RespValue::integer(0),
+            ])
+        );
+        assert_eq!(
+            other.execute(make_command(&["PUBSUB", "NUMPAT"])),
+            RespValue::integer(1)
+        );
+        assert_eq!(
+            create_handler().execute(make_command(&["PUBSUB", "NUMSUB"])),
+            RespValue::array(vec![])
+        );
+        assert!(other.execute(make_command(&["PUBSUB", "BOGUS"])).is_error());
+
+        // A channel and a pattern subscription both receive the message
+        let publisher = CommandHandler::new(Arc::clone(handler.storage()));
+        assert_eq!(
+            publisher.execute(make_command(&["PUBLISH", "news", "hi"])),
+            RespValue::integer(3)
+        );
+
+        // Unsubscribing from everything replies once per channel
+        let CommandOutcome::Replies(replies) =
+            handler.execute_or_block(make_command(&["UNSUBSCRIBE"]))
+        else {
+            panic!("expected one reply per channel");
+        };
+        assert_eq!(
+            replies,
+            vec![
+                make_reply(&["unsubscribe", "news"], 1),
+                make_reply(&["unsubscribe", "sport"], 0),
+            ]
+        );
+        other.close();
+        assert_eq!(
+            other.execute(make_command(&["PUBSUB", "CHANNELS"])),
+            RespValue::array(vec![])
+        );
+        assert_eq!(
+            other.execute(make_command(&["PUBSUB", "NUMPAT"])),
+            RespValue::integer(0)
+        );
+    }
+
+    #[test]
+    fn test_subscribed_client_is_restricted() {
+        let handler = create_handler();
+        assert!(handler
+            .execute(make_command(&["SUBSCRIBE", "a"]))
+            .is_error());
+        assert!(matches!(
+            handler.execute_or_block(make_command(&["UNSUBSCRIBE"])),
+            CommandOutcome::Replies(replies)
+                if replies == vec![RespValue::array(vec![
+                    RespValue::bulk_string("unsubscribe"),
+                    RespValue::null(),
+                    RespValue::integer(0),
+                ])]
+        ));
+
+        handler.execute_or_block(make_command(&["PSUBSCRIBE", "a*"]));
+        assert!(matches!(
+            handler.execute_or_block(make_command(&["GET", "a"])),
+            CommandOutcome::Reply(RespValue::Error(e)) if e.contains("allowed in this context")
+        ));
+        assert!(matches!(
+            handler.execute_or_block(make_command(&["PING"])),
+            CommandOutcome::Reply(reply) if reply == RespValue::array(vec![
+                RespValue::bulk_string("pong"),
+                RespValue::bulk_string(""),
+            ])
+        ));
+
+        handler.execute_or_block(make_command(&["PUNSUBSCRIBE", "a*"]));
+        assert!(matches!(
+            handler.execute_or_block(make_command(&["PING"])),
+            CommandOutcome::Reply(reply) if reply == RespValue::pong()
+        ));
+    }
+
+    /// Returns the `(channel, message)` pairs queued for a handler's client.
+    fn received(messages: &mut mpsc::UnboundedReceiver<Bytes>) -> Vec<(String, String)> {
+        let mut received = Vec::new();
+        while let Ok(frame) = messages.try_recv() {
+            let (message, _) = crate::protocol::parse_message(&frame).unwrap().unwrap();
+            let parts = message.into_array().unwrap();
+            let text = |value: &RespValue| String::from_utf8(value.as_bytes().unwrap().to_vec());
+            // The channel and message end both `message` and `pmessage`
+            let [channel, message] = &parts[parts.len() - 2..] else {
+                unreachable!()
+            };
+            received.push((text(channel).unwrap(), text(message).unwrap()));
+        }
+        received
+    }
+
+    #[test]
+    fn test_keyspace_notifications() {
+        let handler = create_handler();
+        let subscriber = CommandHandler::new(Arc::clone(handler.storage()));
+        let mut messages = subscriber.take_messages().unwrap();
+        subscriber.execute_or_block(make_command(&["PSUBSCRIBE", "__keyevent@0__:*"]));
+
+        // Off by default
+        handler.execute(make_command(&["SET", "foo", "bar"]));
+        assert!(received(&mut messages).is_empty());
+        assert_eq!(
+            handler.execute(make_command(&["CONFIG", "GET", "notify-keyspace-events"])),
+            RespValue::array(vec![
+                RespValue::bulk_string("notify-keyspace-events"),
+                RespValue::bulk_string(""),
+            ])
+        );
+
+        assert!(handler
+            .execute(make_command(&[
+                "CONFIG",
+                "SET",
+                "notify-keyspace-events",
+                "Kq"
+            ]))
+            .is_error());
+        assert_eq!(
+            handler.execute(make_command(&[
+                "CONFIG",
+                "SET",
+                "notify-keyspace-events",
+                "EA"
+            ])),
+            RespValue::ok()
+        );
+        assert_eq!(
+            handler.execute(make_command(&["CONFIG", "GET", "notify-*"])),
+            RespValue::array(vec![
+                RespValue::bulk_string("notify-keyspace-events"),
+                RespValue::bulk_string("AE"),
+            ])
+        );
+
+        handler.execute(make_command(&["SET", "foo", "bar", "EX", "100"]));
+        handler.execute(make_command(&["SET", "foo", "baz", "NX"]));
+        handler.execute(make_command(&["DEL", "foo", "missing"]));
+        handler.execute(make_command(&["RPUSH", "list", "a", "b"]));
+        handler.execute(make_command(&["LMOVE", "list", "other", "LEFT", "RIGHT"]));
+        handler.execute(make_command(&["SREM", "set", "a"]));
+        handler.execute(make_command(&["RENAME", "other", "renamed"]));
+        assert_eq!(
+            received(&mut messages),
+            [
+                ("__keyevent@0__:set", "foo"),
+                ("__keyevent@0__:expire", "foo"),
+                ("__keyevent@0__:del", "foo"),
+                ("__keyevent@0__:rpush", "list"),
+                ("__keyevent@0__:lpop", "list"),
+                ("__keyevent@0__:rpush", "other"),
+                ("__keyevent@0__:rename_from", "other"),
+                ("__keyevent@0__:rename_to", "renamed"),
+            ]
+            .map(|(channel, key)| (channel.to_string(), key.to_string()))
+        );
+    }
+
+    #[test]
+    fn test_expired_keys_are_notified() {
+        let handler = create_handler();
+        let subscriber = CommandHandler::new(Arc::clone(handler.storage()));
+        let mut messages = subscriber.take_messages().unwrap();
+        subscriber.execute_or_block(make_command(&["SUBSCRIBE", "__keyevent@0__:expired"]));
+        handler.execute(make_command(&[
+            "CONFIG",
+            "SET",
+            "notify-keyspace-events",
+            "Ex",
+        ]));
+
+        handler.execute(make_command(&["SET", "lazy", "v", "PX", "1"]));
+        handler.execute(make_command(&["SET", "swept", "v", "PX", "1"]));
+        std::thread::sleep(Duration::from_millis(30));
+
+        // Expired on access, then by the sweeper
+        assert_eq!(
+            handler.execute(make_command(&["GET", "lazy"])),
+            RespValue::null()
+        );
+        assert_eq!(handler.storage().cleanup_expired(), 1);
+        assert_eq!(
+            received(&mut messages),
+            [
+                ("__keyevent@0__:expired", "lazy"),
+                ("__keyevent@0__:expired", "swept"),
+            ]
+            .map(|(channel, key)| (channel.to_string(), key.to_string()))
+        );
+    }
+
+    fn make_reply(strings: &[&str], count: i64) -> RespValue {
+        let mut reply: Vec<RespValue> = strings
+            .iter()
+            .map(|s| RespValue::bulk_string(Bytes::from(s.to_string())))
+            .collect();
+        reply.push(RespValue::integer(count));
+        RespValue::array(reply)
+    }
+
+    #[test]
+    fn test_hello() {
+        let handler = create_handler();
+        let response = handler.execute(make_command(&["HELLO"]));
+        let fields = response.into_array().unwrap();
+        assert_eq!(fields.len(), 14);
+        assert_eq!(fields[0], RespValue::bulk_string("server"));
+        assert_eq!(fields[5], RespValue::integer(2));
+
+        let response = handler.execute(make_command(&["HELLO", "3"]));
+        let RespValue::Map(fields) = response else {
+            panic!("expected a map, got {:?}", response);
+        };
+        assert_eq!(
+            fields[2],
+            (RespValue::bulk_string("proto"), RespValue::integer(3))
+        );
+        assert_eq!(
+            fields[3],
+            (
+                RespValue::bulk_string("id"),
+                handler.execute(make_command(&["CLIENT", "ID"]))
+            )
+        );
+
+        let response = handler.execute(make_command(&["HELLO", "4"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["HELLO", "3", "AUTH"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_resp3_replies() {
+        let handler = create_handler();
+        handler.execute(make_command(&["ZADD", "z", "1.5", "a"]));
+        handler.execute(make_command(&["SET", "f", "10.5"]));
+
+        // RESP2 clients keep the RESP2 shapes
+        let response = handler.execute(make_command(&["ZSCORE", "z", "a"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("1.5")));
+
+        handler.execute(make_command(&["HELLO", "3"]));
+        let response = handler.execute(make_command(&["ZSCORE", "z", "a"]));
+        assert_eq!(response, RespValue::Double(1.5));
+        let response = handler.execute(make_command(&["ZINCRBY", "z", "1", "a"]));
+        assert_eq!(response, RespValue::Double(2.5));
+        let response = handler.execute(make_command(&["INCRBYFLOAT", "f", "0.25"]));
+        assert_eq!(response, RespValue::Double(10.75));
+
+        let response = handler.execute(make_command(&["CONFIG", "GET", "databases"]));
+        assert_eq!(
+            response,
+            RespValue::Map(vec![(
+                RespValue::bulk_string("databases"),
+                RespValue::bulk_string(Bytes::from("16")),
+            )])
+        );
+
+        let response = handler.execute(make_command(&["CLIENT", "INFO"]));
+        let RespValue::Map(fields) = response else {
+            panic!("expected a map, got {:?}", response);
+        };
+        assert_eq!(
+            fields[0],
+            (
+                RespValue::bulk_string(Bytes::from("id")),
+                RespValue::bulk_string(Bytes::from(handler.session().id().to_string())),
+            )
+        );
+        assert!(fields.contains(&(
+            RespValue::bulk_string(Bytes::from("resp")),
+            RespValue::bulk_string(Bytes::from("3")),
+        )));
+
+        let CommandOutcome::Replies(replies) =
+            handler.execute_or_block(make_command(&["SUBSCRIBE", "news"]))
+        else {
+            panic!("expected subscription replies");
+        };
+        assert_eq!(
+            replies,
+            vec![RespValue::Push(vec![
+                RespValue::bulk_string("subscribe"),
+                RespValue::bulk_string("news"),
+                RespValue::integer(1),
+            ])]
+        );
+    }
+
+    #[test]
+    fn test_hello_auth_and_setname() {
+        let handler = create_handler();
+        let response = handler.execute(make_command(&[
+            "HELLO", "3", "AUTH", "default", "secret", "SETNAME", "worker",
+        ]));
+        assert!(matches!(response, RespValue::Map(_)));
+        assert!(handler.session().resp3());
+        assert_eq!(handler.session().user(), Bytes::from("default"));
+        assert_eq!(handler.session().name(), Some(Bytes::from("worker")));
+
+        // A bad option leaves everything as it was
+        for command in [
+            &["HELLO", "2", "AUTH", "admin", "secret"][..],
+            &["HELLO", "2", "SETNAME", "a b"],
+            &["HELLO", "2", "SETNAME", "other", "AUTH", "default"],
+            &["HELLO", "2", "FOO", "bar"],
+        ] {
+            let response = handler.execute(make_command(command));
+            assert!(response.is_error(), "{:?}", command);
+        }
+        assert!(handler.session().resp3());
+        assert_eq!(handler.session().name(), Some(Bytes::from("worker")));
+        let response = handler.execute(make_command(&["HELLO", "2", "AUTH", "admin", "x"]));
+        assert_eq!(
+            response,
+            RespValue::error("WRONGPASS invalid username-password pair or user is disabled.")
+        );
+    }
+
+    #[test]
+    fn test_client_name() {
+        let handler = create_handler();
+        let response = handler.execute(make_command(&["CLIENT", "GETNAME"]));
+        assert_eq!(response, RespValue::null());
+
+        let response = handler.execute(make_command(&["CLIENT", "SETNAME", "worker-1"]));
+        assert_eq!(response, RespValue::ok());
+        // Clones of a handler share its session
+        let clone = handler.clone();
+        let response = clone.execute(make_command(&["CLIENT", "GETNAME"]));
+        assert_eq!(response, RespValue::bulk_string("worker-1"));
+        assert_eq!(clone.session().id(), handler.session().id());
+
+        let response = handler.execute(make_command(&["CLIENT", "SETNAME", "a b"]));
+        assert!(response.is_error());
+        handler.execute(make_command(&["CLIENT", "SETNAME", ""]));
+        assert_eq!(handler.session().name(), None);
+    }
+
+    #[test]
+    fn test_client_list_info() {
+        let handler = create_handler();
+        let other = CommandHandler::new(Arc::clone(handler.storage()));
+        let laddr = "127.0.0.1:6379".parse().unwrap();
+        handler.connect("127.0.0.1:5000".parse().unwrap(), laddr);
+        other.connect("127.0.0.1:5001".parse().unwrap(), laddr);
+        handler.execute(make_command(&["CLIENT", "SETNAME", "worker"]));
+        let response =
+            handler.execute(make_command(&["CLIENT", "SETINFO", "lib-name", "redis-py"]));
+        assert_eq!(response, RespValue::ok());
+        handler.execute(make_command(&["CLIENT", "SETINFO", "LIB-VER", "5.0.1"]));
+        handler.execute(make_command(&["SELECT", "2"]));
+
+        let info = |response: RespValue| {
+            String::from_utf8(response.as_bytes().expect("a bulk string").to_vec()).unwrap()
+        };
+        let line = info(handler.execute(make_command(&["CLIENT", "INFO"])));
+        assert!(line.starts_with(&format!(
+            "id={} addr=127.0.0.1:5000 laddr=127.0.0.1:6379 name=worker age=0 idle=0 flags=N db=2 ",
+            handler.session().id()
+        )));
+        assert!(line.ends_with(" cmd=client user=default lib-name=redis-py lib-ver=5.0.1 resp=2\n"));
+
+        // Other clients are listed too, until they disconnect
+        let list = info(handler.execute(make_command(&["CLIENT", "LIST"])));
+        assert!(list.contains("addr=127.0.0.1:5000 "));
+        assert!(list.contains("addr=127.0.0.1:5001 "));
+        let id = other.session().id().to_string();
+        let list = info(handler.execute(make_command(&["CLIENT", "LIST", "ID", &id])));
+        assert_eq!(list.lines().count(), 1);
+        assert!(list.contains("cmd=NULL "));
+        other.close();
+        let list = info(handler.execute(make_command(&["CLIENT", "LIST", "ID", &id])));
+        assert_eq!(list, "");
+
+        let response = handler.execute(make_command(&["CLIENT", "LIST", "ID", "x"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["CLIENT", "LIST", "TYPE"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["CLIENT", "SETINFO", "LIB-VER", "1 2"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["CLIENT", "SETINFO", "OTHER", "x"]));
+        assert!(response.is_error());
+
+        // The old form of CLIENT KILL can kill the caller; the new one skips it
+        let response = handler.execute(make_command(&["CLIENT", "KILL", "ADDR", "127.0.0.1:5000"]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["CLIENT", "KILL", "127.0.0.1:5000"]));
+        assert_eq!(response, RespValue::ok());
+        assert!(handler.session().is_killed());
+        let response = handler.execute(make_command(&["CLIENT", "KILL", "127.0.0.1:1"]));
+        assert!(response.is_error());
+        for args in [
+            &["CLIENT", "KILL", "ID", "0"][..],
+            &["CLIENT", "KILL", "ID"],
+            &["CLIENT", "KILL", "SKIPME", "maybe", "ID", "1"],
+            &["CLIENT", "PAUSE", "-1"],
+            &["CLIENT", "PAUSE", "10", "READ"],
+        ] {
+            let response = handler.execute(make_command(args));
+            assert!(response.is_error());
+        }
+        handler.close();
+    }
+
+    #[test]
+    fn test_monitor() {
+        let monitor = create_handler();
+        let client = CommandHandler::new(Arc::clone(monitor.storage()));
+        let laddr = "127.0.0.1:6379".parse().unwrap();
+        monitor.connect("127.0.0.1:5100".parse().unwrap(), laddr);
+        client.connect("127.0.0.1:5101".parse().unwrap(), laddr);
+        let mut messages = monitor.take_messages().unwrap();
+
+        let response = monitor.execute(make_command(&["MONITOR", "extra"]));
+        assert!(response.is_error());
+        assert_eq!(monitor.execute(make_command(&["MONITOR"])), RespValue::ok());
+        let list = monitor.execute(make_command(&["CLIENT", "INFO"]));
+        assert!(String::from_utf8_lossy(list.as_bytes().unwrap()).contains(" flags=O "));
+
+        client.execute(make_command(&["SELECT", "1"]));
+        client.execute(make_command(&["set", "key", "two words"]));
+        client.execute_or_block(make_command(&["HELLO", "2", "AUTH", "default", "secret"]));
+
+        // Other tests run commands meanwhile; only this client's count
+        let mut lines = Vec::new();
+        while let Ok(line) = messages.try_recv() {
+            let line = String::from_utf8(line.to_vec()).unwrap();
+            if line.contains(" 127.0.0.1:5101] ") {
+                lines.push(line);
+            }
+        }
+        let args: Vec<&str> = lines
+            .iter()
+            .map(|line| {
+                assert!(line.starts_with('+') && line.ends_with("\r\n"), "{}", line);
+                line.split_once("] ").unwrap().1.trim_end()
+            })
+            .collect();
+        assert_eq!(
+            args,
+            [
+                r#""SELECT" "1""#,
+                r#""set" "key" "two words""#,
+                r#""HELLO" "2" "AUTH" "(redacted)" "(redacted)""#,
+            ]
+        );
+        assert!(lines[0].contains(" [0 127.0.0.1:5101] "));
+        assert!(lines[1].contains(" [1 127.0.0.1:5101] "));
+
+        // Once the monitor disconnects, nothing is sent to it
+        monitor.close();
+        client.execute(make_command(&["PING"]));
+        assert!(messages.try_recv().is_err());
+        client.close();
+    }
+
+    #[test]
+    fn test_client_no_touch() {
+        let handler = create_handler();
+        handler.execute(make_command(&["SET", "key", "v"]));
+        let freq = handler.execute(make_command(&["OBJECT", "FREQ", "key"]));
+
+        let response = handler.execute(make_command(&["CLIENT", "NO-TOUCH", "ON"]));
+        assert_eq!(response, RespValue::ok());
+        handler.execute(make_command(&["CLIENT", "NO-EVICT", "on"]));
+        for _ in 0..100 {
+            handler.execute(make_command(&["GET", "key"]));
+        }
+        let response = handler.execute(make_command(&["OBJECT", "FREQ", "key"]));
+        assert_eq!(response, freq);
+        let info = handler.execute(make_command(&["CLIENT", "INFO"]));
+        assert!(String::from_utf8_lossy(info.as_bytes().unwrap()).contains(" flags=eT "));
+
+        // TOUCH still counts, from the initial value that is certain
+        handler.execute(make_command(&["TOUCH", "key"]));
+        let response = handler.execute(make_command(&["OBJECT", "FREQ", "key"]));
+        assert_eq!(response, RespValue::integer(6));
+
+        handler.execute(make_command(&["CLIENT", "NO-TOUCH", "OFF"]));
+        assert!(!handler.session().no_touch());
+        let response = handler.execute(make_command(&["CLIENT", "NO-TOUCH", "MAYBE"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_renamed_and_disabled_commands() {
+        let mut renames = CommandRenames::new();
+        renames.rename("SET", "obscure-set");
+        renames.disable("FLUSHALL");
+        let handler = create_handler().with_renames(Arc::new(renames));
+        let storage = handler.storage();
+        let mut sync = storage
+            .replication()
+            .attach(storage, "127.0.0.1:7000".parse().unwrap(), 0);
+        storage.finish_snapshot(sync.snapshot);
+
+        let response = handler.execute(make_command(&["SET", "a", "1"]));
+        assert_eq!(response, RespValue::error("ERR unknown command 'SET'"));
+        let response = handler.execute(make_command(&["FLUSHALL"]));
+        assert_eq!(response, RespValue::error("ERR unknown command 'FLUSHALL'"));
+
+        // The write runs, and is propagated, under its real name
+        let response = handler.execute(make_command(&["obscure-set", "a", "1"]));
+        assert_eq!(response, RespValue::ok());
+        let fed = sync.receiver.try_recv().unwrap();
+        assert_eq!(&fed[..], b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n");
+        let response = handler.execute(make_command(&["GET", "a"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("1")));
+
+        // Handlers without the renames keep the real names
+        let other = CommandHandler::new(Arc::clone(storage));
+        let response = other.execute(make_command(&["SET", "b", "2"]));
+        assert_eq!(response, RespValue::ok());
+    }
+
+    /// Serializes the push invalidating `keys`, or everything if `None`.
+    fn invalidation(keys: Option<&[&str]>) -> Bytes {
+        let keys = match keys {
+            Some(keys) => RespValue::array(
+                keys.iter()
+                    .map(|k| RespValue::bulk_string(k.to_string()))
+                    .collect(),
+            ),
+            None => RespValue::null(),
+        };
+        Bytes::from(
+            RespValue::Push(vec![RespValue::bulk_string("invalidate"), keys]).serialize_resp3(),
+        )
+    }
+
+    #[test]
+    fn test_select() {
+        let handler = create_handler();
+        let other = CommandHandler::new(Arc::clone(handler.storage()));
+        handler.execute(make_command(&["SET", "key", "zero"]));
+
+        let response = handler.execute(make_command(&["SELECT", "3"]));
+        assert_eq!(response, RespValue::ok());
+        assert_eq!(handler.session().db(), 3);
+        let response = handler.execute(make_command(&["GET", "key"]));
+        assert_eq!(response, RespValue::null());
+        handler.execute(make_command(&["SET", "key", "three"]));
+        handler.execute(make_command(&["SET", "other", "x", "EX", "100"]));
+        let response = handler.execute(make_command(&["MGET", "key", "other"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("three")),
+                RespValue::bulk_string(Bytes::from("x")),
+            ])
+        );
+        let response = handler.execute(make_command(&["DBSIZE"]));
+        assert_eq!(response, RespValue::integer(2));
+
+        // Other clients stay on database 0
+        let response = other.execute(make_command(&["GET", "key"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("zero")));
+        let response = other.execute(make_command(&["DBSIZE"]));
+        assert_eq!(response, RespValue::integer(1));
+
+        let Some(info) = handler
+            .execute(make_command(&["INFO"]))
+            .as_bytes()
+            .map(|b| b.to_vec())
+        else {
+            panic!("INFO should reply with a bulk string");
+        };
+        let info = String::from_utf8(info).unwrap();
+        assert!(info.contains("db0:keys=1,expires=0\r\ndb3:keys=2,expires=1\r\n"));
+
+        let response = handler.execute(make_command(&["COPY", "key", "copied", "DB", "0"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = other.execute(make_command(&["GET", "copied"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("three")));
+
+        // Every type of key lives in the selected database
+        other.execute(make_command(&["HSET", "h", "zero", "0"]));
+        for cmd in [
+            vec!["HSET", "h", "f", "v"],
+            vec!["SADD", "s", "m"],
+            vec!["ZADD", "z", "1", "m"],
+            vec!["XADD", "x", "1-1", "f", "v"],
+            vec!["XGROUP", "CREATE", "x", "g", "0"],
+        ] {
+            assert!(!handler.execute(make_command(&cmd)).is_error());
+        }
+        let bulk = |s: &'static str| RespValue::bulk_string(Bytes::from(s));
+        for (cmd, expected) in [
+            (
+                vec!["HGETALL", "h"],
+                RespValue::array(vec![bulk("f"), bulk("v")]),
+            ),
+            (
+                vec!["HRANDFIELD", "h", "1"],
+                RespValue::array(vec![bulk("f")]),
+            ),
+            (
+                vec!["SMISMEMBER", "s", "m"],
+                RespValue::array(vec![RespValue::integer(1)]),
+            ),
+            (
+                vec!["ZRANGE", "z", "0", "-1"],
+                RespValue::array(vec![bulk("m")]),
+            ),
+            (
+                vec!["ZRANDMEMBER", "z", "1"],
+                RespValue::array(vec![bulk("m")]),
+            ),
+            (vec!["ZUNIONSTORE", "zu", "1", "z"], RespValue::integer(1)),
+            (vec!["OBJECT", "ENCODING", "h"], bulk("hashtable")),
+            (vec!["OBJECT", "IDLETIME", "z"], RespValue::integer(0)),
+        ] {
+            assert_eq!(handler.execute(make_command(&cmd)), expected, "{:?}", cmd);
+        }
+        match handler.execute(make_command(&[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "c",
+            "STREAMS",
+            "x",
+            ">",
+        ])) {
+            RespValue::Array(streams) => assert_eq!(streams.len(), 1),
+            other => panic!("unexpected XREADGROUP reply: {:?}", other),
+        }
+        let response = handler.execute(make_command(&[
+            "XCLAIM", "x", "g", "c2", "0", "1-1", "JUSTID",
+        ]));
+        assert_eq!(response, RespValue::array(vec![bulk("1-1")]));
+        let response = handler.execute(make_command(&["ZPOPMIN", "z"]));
+        assert_eq!(response, RespValue::array(vec![bulk("m"), bulk("1")]));
+        let response = other.execute(make_command(&["HGETALL", "h"]));
+        assert_eq!(response, RespValue::array(vec![bulk("zero"), bulk("0")]));
+        let response = other.execute(make_command(&["OBJECT", "ENCODING", "s"]));
+        assert_eq!(response, RespValue::null());
+
+        for index in ["16", "-1", "x"] {
+            let response = handler.execute(make_command(&["SELECT", index]));
+            assert!(response.is_error());
+        }
+        assert_eq!(handler.session().db(), 3);
+        let response = handler.execute(make_command(&["CONFIG", "GET", "databases"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string("databases"),
+                RespValue::bulk_string("16"),
+            ])
+        );
+
+        // FLUSHDB only clears the selected database, FLUSHALL all of them
+        handler.execute(make_command(&["FLUSHDB"]));
+        let response = handler.execute(make_command(&["DBSIZE"]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = other.execute(make_command(&["DBSIZE"]));
+        assert_eq!(response, RespValue::integer(3));
+        handler.execute(make_command(&["SET", "key", "three"]));
+        handler.execute(make_command(&["FLUSHALL"]));
+        assert_eq!(handler.storage().total_stats().keys, 0);
+
+        handler.execute(make_command(&["SET", "key", "three"]));
+        let response = handler.execute(make_command(&["FLUSHDB", "async"]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["DBSIZE"]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["FLUSHALL", "SYNC"]));
+        assert_eq!(response, RespValue::ok());
+        for args in [&["FLUSHDB", "LATER"][..], &["FLUSHALL", "ASYNC", "SYNC"]] {
+            let response = handler.execute(make_command(args));
+            assert!(response.is_error());
+        }
+
+        // Cluster mode only has database 0
+        handler.storage().cluster().enable("127.0.0.1", 7000);
+        let response = handler.execute(make_command(&["SELECT", "1"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["SELECT", "0"]));
+        assert_eq!(response, RespValue::ok());
+    }
+
+    #[test]
+    fn test_move() {
+        let handler = create_handler();
+        handler.execute(make_command(&["RPUSH", "list", "a", "b"]));
+        handler.execute(make_command(&["EXPIRE", "list", "100"]));
+
+        // The key leaves database 0 with its type and TTL
+        let response = handler.execute(make_command(&["MOVE", "list", "2"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["EXISTS", "list"]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["MOVE", "missing", "2"]));
+        assert_eq!(response, RespValue::integer(0));
+
+        handler.execute(make_command(&["SELECT", "2"]));
+        let response = handler.execute(make_command(&["LLEN", "list"]));
+        assert_eq!(response, RespValue::integer(2));
+        let response = handler.execute(make_command(&["TTL", "list"]));
+        assert!(matches!(response, RespValue::Integer(t) if t > 0));
+
+        // A key already in the destination is left alone
+        handler.execute(make_command(&["SELECT", "0"]));
+        handler.execute(make_command(&["SET", "list", "v"]));
+        let response = handler.execute(make_command(&["MOVE", "list", "2"]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["TYPE", "list"]));
+        assert_eq!(response, RespValue::simple_string("string"));
+
+        for index in ["0", "16", "-1", "x"] {
+            let response = handler.execute(make_command(&["MOVE", "list", index]));
+            assert!(response.is_error());
+        }
+
+        handler.storage().cluster().enable("127.0.0.1", 7000);
+        let response = handler.execute(make_command(&["MOVE", "list", "1"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_client_tracking() {
+        let handler = create_handler();
+        let writer = CommandHandler::new(Arc::clone(handler.storage()));
+        let mut messages = handler.take_messages().unwrap();
+
+        // Pushes need RESP3
+        let response = handler.execute(make_command(&["CLIENT", "TRACKING", "ON"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["CLIENT", "GETREDIR"]));
+        assert_eq!(response, RespValue::integer(-1));
+        handler.execute(make_command(&["HELLO", "3"]));
+        let response = handler.execute(make_command(&["CLIENT", "TRACKING", "ON"]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["CLIENT", "GETREDIR"]));
+        assert_eq!(response, RespValue::integer(0));
+
+        writer.execute(make_command(&["SET", "foo", "1"]));
+        handler.execute(make_command(&["GET", "foo"]));
+        handler.execute(make_command(&["MGET", "bar", "baz"]));
+        // Not read, so not tracked
+        writer.execute(make_command(&["SET", "other", "1"]));
+        assert!(messages.try_recv().is_err());
+
+        writer.execute(make_command(&["SET", "foo", "2"]));
+        writer.execute(make_command(&["DEL", "foo", "bar"]));
+        assert_eq!(messages.try_recv().unwrap(), invalidation(Some(&["foo"])));
+        // foo was reported already
+        assert_eq!(messages.try_recv().unwrap(), invalidation(Some(&["bar"])));
+        assert!(messages.try_recv().is_err());
+
+        // The client's own writes are reported too, unless NOLOOP
+        handler.execute(make_command(&["GET", "baz"]));
+        handler.execute(make_command(&["SET", "baz", "1"]));
+        assert_eq!(messages.try_recv().unwrap(), invalidation(Some(&["baz"])));
+        handler.execute(make_command(&["CLIENT", "TRACKING", "ON", "NOLOOP"]));
+        handler.execute(make_command(&["GET", "baz"]));
+        handler.execute(make_command(&["SET", "baz", "2"]));
+        assert!(messages.try_recv().is_err());
+
+        handler.execute(make_command(&["GET", "foo"]));
+        writer.execute(make_command(&["FLUSHDB"]));
+        assert_eq!(messages.try_recv().unwrap(), invalidation(None));
+
+        handler.execute(make_command(&["CLIENT", "TRACKING", "OFF"]));
+        handler.execute(make_command(&["GET", "foo"]));
+        writer.execute(make_command(&["SET", "foo", "3"]));
+        assert!(messages.try_recv().is_err());
+        assert!(handler.storage().pubsub().tracking().is_empty());
+    }
+
+    #[test]
+    fn test_client_tracking_redirect() {
+        let handler = create_handler();
+        let redirect = CommandHandler::new(Arc::clone(handler.storage()));
+        let mut messages = redirect.take_messages().unwrap();
+        redirect.execute_or_block(make_command(&["SUBSCRIBE", "__redis__:invalidate"]));
+        messages.try_recv().ok();
+        let id = redirect.execute(make_command(&["CLIENT", "ID"]));
+        let id = id.as_integer().unwrap().to_string();
+
+        // Redirecting works over RESP2
+        let response =
+            handler.execute(make_command(&["CLIENT", "TRACKING", "ON", "REDIRECT", &id]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["CLIENT", "GETREDIR"]));
+        assert_eq!(response.as_integer().unwrap().to_string(), id);
+        let response = handler.execute(make_command(&["CLIENT", "TRACKING", "ON", "BCAST"]));
+        assert!(response.is_error());
+
+        handler.execute(make_command(&["GET", "foo"]));
+        handler.execute(make_command(&["SET", "foo", "1", "PX", "20"]));
+        let frame = messages.try_recv().unwrap();
+        let (message, _) = crate::protocol::parse_message(&frame).unwrap().unwrap();
+        assert_eq!(
+            message,
+            RespValue::array(vec![
+                RespValue::bulk_string("message"),
+                RespValue::bulk_string("__redis__:invalidate"),
+                RespValue::array(vec![RespValue::bulk_string("foo")]),
+            ])
+        );
+
+        // Expired keys are invalidated as well
+        handler.execute(make_command(&["GET", "foo"]));
+        std::thread::sleep(Duration::from_millis(40));
+        handler.storage().cleanup_expired();
+        assert!(messages.try_recv().is_ok());
+
+        handler.close();
+        assert!(handler.storage().pubsub().tracking().is_empty());
+    }
+
+    #[test]
+    fn test_expired_keys_are_propagated_as_dels() {
+        let handler = create_handler();
+        let storage = handler.storage();
+        let mut sync = storage
+            .replication()
+            .attach(storage, "127.0.0.1:7000".parse().unwrap(), 0);
+        storage.finish_snapshot(sync.snapshot);
+
+        handler.execute(make_command(&["SET", "lazy", "v", "PX", "20"]));
+        handler.execute(make_command(&["SET", "swept", "v", "PX", "20"]));
+        sync.receiver.try_recv().unwrap();
+        sync.receiver.try_recv().unwrap();
+        std::thread::sleep(Duration::from_millis(40));
+
+        // A read expires the key; the next write sends the DEL first
+        let response = handler.execute(make_command(&["GET", "lazy"]));
+        assert_eq!(response, RespValue::null());
+        assert!(sync.receiver.try_recv().is_err());
+        handler.execute(make_command(&["SET", "k", "1"]));
+        let fed = sync.receiver.try_recv().unwrap();
+        assert_eq!(
+            &fed[..],
+            b"*2\r\n$3\r\nDEL\r\n$4\r\nlazy\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\n1\r\n"
+        );
+
+        // The sweeper sends its own
+        assert_eq!(storage.cleanup_expired(), 1);
+        storage.propagate_expired();
+        let fed = sync.receiver.try_recv().unwrap();
+        assert_eq!(&fed[..], b"*2\r\n$3\r\nDEL\r\n$5\r\nswept\r\n");
+        storage.propagate_expired();
+        assert!(sync.receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_writes_are_propagated_with_select() {
+        let handler = create_handler();
+        let storage = handler.storage();
+        let mut sync = storage
+            .replication()
+            .attach(storage, "127.0.0.1:7000".parse().unwrap(), 0);
+        storage.finish_snapshot(sync.snapshot);
+
+        // Database 0 is where the stream starts
+        handler.execute(make_command(&["SET", "a", "1"]));
+        let fed = sync.receiver.try_recv().unwrap();
+        assert_eq!(&fed[..], b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n");
+
+        handler.execute(make_command(&["SELECT", "2"]));
+        assert!(sync.receiver.try_recv().is_err());
+        handler.execute(make_command(&["SET", "b", "2"]));
+        let fed = sync.receiver.try_recv().unwrap();
+        assert_eq!(
+            &fed[..],
+            b"*2\r\n$6\r\nSELECT\r\n$1\r\n2\r\n*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n"
+        );
+        handler.execute(make_command(&["SET", "c", "3"]));
+        let fed = sync.receiver.try_recv().unwrap();
+        assert!(fed.starts_with(b"*3\r\n$3\r\nSET"));
+
+        // Another client writing to database 0 switches the stream back
+        let other = CommandHandler::new(Arc::clone(storage));
+        other.execute(make_command(&["DEL", "a"]));
+        let fed = sync.receiver.try_recv().unwrap();
+        assert!(fed.starts_with(b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*2\r\n$3\r\nDEL"));
+
+        // Expired keys are deleted in their own database
+        handler.execute(make_command(&["SET", "lazy", "v", "PX", "20"]));
+        sync.receiver.try_recv().unwrap();
+        std::thread::sleep(Duration::from_millis(40));
+        handler.execute(make_command(&["GET", "lazy"]));
+        other.execute(make_command(&["SET", "d", "4"]));
+        let fed = sync.receiver.try_recv().unwrap();
+        assert!(
+            fed.starts_with(b"*2\r\n$3\r\nDEL\r\n$4\r\nlazy\r\n*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n")
+        );
+    }
+
+    #[test]
+    fn test_replica_leaves_expiry_to_its_master() {
+        let handler = create_handler();
+        let storage = handler.storage();
+        storage.set_with_ttl(
+            Bytes::from("k"),
+            Bytes::from("v"),
+            Duration::from_millis(10),
+        );
+        handler.execute(make_command(&["REPLICAOF", "127.0.0.1", "6380"]));
+        std::thread::sleep(Duration::from_millis(40));
+
+        // Reads see the key as gone, but it stays until the master's DEL
+        let response = handler.execute(make_command(&["GET", "k"]));
+        assert_eq!(response, RespValue::null());
+        assert_eq!(storage.cleanup_expired(), 0);
+        assert_eq!(storage.len(), 1);
+
+        let from_master = CommandHandler::for_master(Arc::clone(storage));
+        from_master.execute(make_command(&["DEL", "k"]));
+        assert_eq!(storage.len(), 0);
+    }
+
+    #[test]
+    fn test_read_only_mode() {
+        let handler = create_handler();
+        handler.execute(make_command(&["SET", "k", "v"]));
+
+        let response = handler.execute(make_command(&["CONFIG", "SET", "read-only", "yes"]));
+        assert_eq!(response, RespValue::ok());
+        for command in [&["SET", "k", "w"][..], &["DEL", "k"], &["FLUSHALL"]] {
+            let response = handler.execute(make_command(command));
+            assert_eq!(
+                response,
+                RespValue::error("READONLY You can't write against a read only server.")
+            );
+        }
+        // Every database is frozen, and reads are still served
+        handler.execute(make_command(&["SELECT", "1"]));
+        let response = handler.execute(make_command(&["SET", "k", "w"]));
+        assert!(response.is_error());
+        handler.execute(make_command(&["SELECT", "0"]));
+        let response = handler.execute(make_command(&["GET", "k"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("v")));
+
+        let response = handler.execute(make_command(&["CONFIG", "GET", "read-only"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string("read-only"),
+                RespValue::bulk_string(Bytes::from("yes")),
+            ])
+        );
+        let info = handler.execute(make_command(&["INFO"]));
+        assert!(String::from_utf8_lossy(info.as_bytes().unwrap()).contains("read_only:1\r\n"));
+        let response = handler.execute(make_command(&["CONFIG", "SET", "read-only", "maybe"]));
+        assert!(response.is_error());
+
+        handler.execute(make_command(&["CONFIG", "SET", "READ-ONLY", "no"]));
+        let response = handler.execute(make_command(&["SET", "k", "w"]));
+        assert_eq!(response, RespValue::ok());
+    }
+
+    #[test]
+    fn test_config_get_protocol_limits() {
+        let handler = create_handler().with_protocol_limits(ProtocolLimits {
+            max_bulk_len: 1024,
+            ..ProtocolLimits::default()
+        });
+        let response = handler.execute(make_command(&["CONFIG", "GET", "proto-max-bulk-len"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string("proto-max-bulk-len"),
+                RespValue::bulk_string("1024"),
+            ])
+        );
+        let response = handler.execute(make_command(&["CONFIG", "GET", "client-query-*"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string("client-query-buffer-limit"),
+                RespValue::bulk_string((1024 * 1024 * 1024).to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_memory_command() {
+        let handler = create_handler();
+        handler.execute(make_command(&["SET", "key", "value"]));
+
+        let usage = handler.execute(make_command(&["MEMORY", "USAGE", "key"]));
+        let RespValue::Integer(bytes) = usage else {
+            panic!("expected an integer, got {:?}", usage);
+        };
+        assert!(bytes > 3);
+        assert_eq!(
+            handler.execute(make_command(&["MEMORY", "usage", "key", "SAMPLES", "0"])),
+            RespValue::integer(bytes)
+        );
+        assert_eq!(
+            handler.execute(make_command(&["MEMORY", "USAGE", "missing"])),
+            RespValue::null()
+        );
+        assert!(handler
+            .execute(make_command(&["MEMORY", "USAGE", "key", "COUNT", "1"]))
+            .is_error());
+
+        let stats = handler
+            .execute(make_command(&["MEMORY", "STATS"]))
+            .into_array()
+            .unwrap();
+        assert_eq!(stats[0], RespValue::bulk_string("dataset.bytes"));
+        assert_eq!(stats[1], RespValue::integer(bytes));
+        assert_eq!(stats[2], RespValue::bulk_string("keys.count"));
+        assert_eq!(stats[3], RespValue::integer(1));
+        assert_eq!(stats[9], RespValue::integer(0));
+        if cfg!(target_os = "linux") {
+            assert!(stats.contains(&RespValue::bulk_string("rss")));
+            let at = stats
+                .iter()
+                .position(|s| *s == RespValue::bulk_string("fragmentation"))
+                .unwrap();
+            assert!(matches!(stats[at + 1], RespValue::Double(ratio) if ratio > 0.0));
+        }
+
+        assert_eq!(
+            handler.execute(make_command(&["CONFIG", "GET", "compact-keys"])),
+            RespValue::array(vec![
+                RespValue::bulk_string("compact-keys"),
+                RespValue::bulk_string("no"),
+            ])
+        );
+        assert!(handler.execute(make_command(&["MEMORY"])).is_error());
+        assert!(handler
+            .execute(make_command(&["MEMORY", "DOCTOR"]))
+            .is_error());
+        assert!(handler
+            .execute(make_command(&["MEMORY", "STATS", "x"]))
+            .is_error());
+    }
+
+    #[test]
+    fn test_maxmemory() {
+        let handler = create_handler();
+        handler.execute(make_command(&["SET", "a", "1"]));
+        handler.execute(make_command(&["SET", "b", "2"]));
+
+        let response = handler.execute(make_command(&["CONFIG", "SET", "maxmemory", "1"]));
+        assert_eq!(response, RespValue::ok());
+        let oom = RespValue::error("OOM command not allowed when used memory > 'maxmemory'.");
+        assert_eq!(handler.execute(make_command(&["SET", "c", "3"])), oom);
+        assert_eq!(handler.execute(make_command(&["LPUSH", "l", "x"])), oom);
+
+        // Reads and writes that free memory still run
+        assert_eq!(
+            handler.execute(make_command(&["GET", "a"])),
+            RespValue::bulk_string(Bytes::from("1"))
+        );
+        assert_eq!(
+            handler.execute(make_command(&["DEL", "a"])),
+            RespValue::integer(1)
+        );
+
+        assert!(handler
+            .execute(make_command(&[
+                "CONFIG",
+                "SET",
+                "maxmemory-policy",
+                "allkeys-lru"
+            ]))
+            .is_error());
+        let response = handler.execute(make_command(&[
+            "CONFIG",
+            "SET",
+            "maxmemory-policy",
+            "allkeys-lfu",
+        ]));
+        assert_eq!(response, RespValue::ok());
+        assert_eq!(
+            handler.execute(make_command(&["SET", "c", "3"])),
+            RespValue::ok()
+        );
+        assert_eq!(
+            handler.execute(make_command(&["EXISTS", "b"])),
+            RespValue::integer(0)
+        );
+
+        let response = handler.execute(make_command(&["CONFIG", "GET", "maxmemory*"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string("maxmemory"),
+                RespValue::bulk_string("1"),
+                RespValue::bulk_string("maxmemory-policy"),
+                RespValue::bulk_string("allkeys-lfu"),
+            ])
+        );
+        let info = handler.execute(make_command(&["INFO"]));
+        let info = String::from_utf8(info.as_bytes().unwrap().to_vec()).unwrap();
+        assert!(info.contains("maxmemory:1\r\nmaxmemory_policy:allkeys-lfu\r\n"));
+        assert!(info.contains("evicted_keys:1\r\n"));
+
+        assert!(handler
+            .execute(make_command(&["CONFIG", "SET", "maxmemory", "lots"]))
+            .is_error());
+        // The LFU settings are the process's; other tests rely on the defaults
+        let response = handler.execute(make_command(&["CONFIG", "SET", "lfu-log-factor", "10"]));
+        assert_eq!(response, RespValue::ok());
+        assert!(handler
+            .execute(make_command(&["CONFIG", "SET", "lfu-decay-time", "-1"]))
+            .is_error());
+        let response = handler.execute(make_command(&["CONFIG", "GET", "lfu-*"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string("lfu-log-factor"),
+                RespValue::bulk_string("10"),
+                RespValue::bulk_string("lfu-decay-time"),
+                RespValue::bulk_string("1"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_replica_is_read_only() {
+        let handler = create_handler();
+        handler.execute(make_command(&["SET", "k", "v"]));
+        let info = handler.execute(make_command(&["INFO"]));
+        let info = String::from_utf8(info.as_bytes().unwrap().to_vec()).unwrap();
+        assert!(info.contains("role:master\r\nconnected_slaves:0\r\n"));
+        assert!(info.contains("repl_backlog_active:0\r\n"));
+
+        let response = handler.execute(make_command(&["REPLICAOF", "127.0.0.1", "6380"]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["SET", "k", "w"]));
+        assert_eq!(
+            response,
+            RespValue::error("READONLY You can't write against a read only replica.")
+        );
+        assert!(matches!(
+            handler.execute_or_block(make_command(&["BLPOP", "list", "0"])),
+            CommandOutcome::Reply(RespValue::Error(_))
+        ));
+        let response = handler.execute(make_command(&["GET", "k"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("v")));
+
+        let info = handler.execute(make_command(&["INFO"]));
+        let info = String::from_utf8(info.as_bytes().unwrap().to_vec()).unwrap();
+        assert!(info.contains("role:slave\r\nmaster_host:127.0.0.1\r\nmaster_port:6380\r\n"));
+        assert!(info.contains("master_link_status:down\r\n"));
+        assert!(info.contains("slave_read_only:1\r\n"));
+
+        // The master's stream still writes
+        let master = CommandHandler::for_master(Arc::clone(handler.storage()));
+        let response = master.execute(make_command(&["SET", "k", "x"]));
+        assert_eq!(response, RespValue::ok());
+
+        assert!(handler
+            .execute(make_command(&["REPLICAOF", "127.0.0.1", "port"]))
+            .is_error());
+        let response = handler.execute(make_command(&["SLAVEOF", "no", "one"]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["SET", "k", "y"]));
+        assert_eq!(response, RespValue::ok());
+    }
+
+    #[test]
+    fn test_cluster_redirects() {
+        let handler = create_handler();
+        assert_eq!(
+            handler.execute(make_command(&["CLUSTER", "KEYSLOT", "foo"])),
+            RespValue::error("ERR This instance has cluster support disabled")
+        );
+
+        // This node serves 0-8191, the other 8192-16383
+        let cluster = handler.storage().cluster();
+        cluster.enable("127.0.0.1", 7000);
+        cluster.assign(&cluster.myself().id, &[(0, 8191)]);
+        let other = cluster.add_node("127.0.0.1", 7001);
+        cluster.assign(&other, &[(8192, 16383)]);
+
+        let response = handler.execute(make_command(&["CLUSTER", "KEYSLOT", "foo"]));
+        assert_eq!(response, RespValue::integer(12182));
+        let response = handler.execute(make_command(&["SET", "bar", "1"]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["GET", "foo"]));
+        assert_eq!(response, RespValue::error("MOVED 12182 127.0.0.1:7001"));
+        let response = handler.execute(make_command(&["MGET", "bar", "foo"]));
+        assert_eq!(
+            response,
+            RespValue::error("CROSSSLOT Keys in request don't hash to the same slot")
+        );
+        assert!(matches!(
+            handler.execute_or_block(make_command(&["BLPOP", "foo", "0"])),
+            CommandOutcome::Reply(RespValue::Error(e)) if e.starts_with("MOVED")
+        ));
+        assert_eq!(
+            handler.execute(make_command(&["DBSIZE"])),
+            RespValue::integer(1)
+        );
+
+        // The master's stream is applied whatever the slot
+        let from_master = CommandHandler::for_master(Arc::clone(handler.storage()));
+        let response = from_master.execute(make_command(&["SET", "foo", "1"]));
+        assert_eq!(response, RespValue::ok());
+
+        // An imported slot is served only right after ASKING
+        let response = handler.execute(make_command(&[
+            "CLUSTER",
+            "SETSLOT",
+            "12182",
+            "IMPORTING",
+            &other,
+        ]));
+        assert_eq!(response, RespValue::ok());
+        assert!(handler.execute(make_command(&["GET", "foo"])).is_error());
+        let response = handler.execute(make_command(&["ASKING"]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["GET", "foo"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("1")));
+        assert!(handler.execute(make_command(&["GET", "foo"])).is_error());
+
+        // A migrating slot sends missing keys on with ASK
+        let response = handler.execute(make_command(&[
+            "CLUSTER",
+            "SETSLOT",
+            "5061",
+            "MIGRATING",
+            &other,
+        ]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["GET", "bar"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("1")));
+        let response = handler.execute(make_command(&["GET", "{bar}missing"]));
+        assert_eq!(response, RespValue::error("ASK 5061 127.0.0.1:7001"));
+
+        let slots = handler.execute(make_command(&["CLUSTER", "SLOTS"]));
+        let slots = slots.as_array().unwrap();
+        assert_eq!(slots.len(), 2);
+        assert_eq!(slots[0].as_array().unwrap()[1], RespValue::integer(8191));
+
+        let nodes = handler.execute(make_command(&["CLUSTER", "NODES"]));
+        let nodes = String::from_utf8(nodes.as_bytes().unwrap().to_vec()).unwrap();
+        let me = cluster.myself().id;
+        assert!(nodes.contains(&format!(
+            "{} 127.0.0.1:7000@17000 myself,master - 0 0 0 connected 0-8191 [5061->-{}] [12182-<-{}]\n",
+            me, other, other
+        )));
+        assert!(nodes.contains(&format!(
+            "{} 127.0.0.1:7001@17001 master - 0 0 0 connected 8192-16383\n",
+            other
+        )));
+
+        let shards = handler.execute(make_command(&["CLUSTER", "SHARDS"]));
+        assert_eq!(shards.as_array().unwrap().len(), 2);
+        let info = handler.execute(make_command(&["CLUSTER", "INFO"]));
+        let info = String::from_utf8(info.as_bytes().unwrap().to_vec()).unwrap();
+        assert!(info.contains("cluster_state:ok\r\n"));
+        assert!(info.contains("cluster_known_nodes:2\r\n"));
+        assert!(handler
+            .execute(make_command(&["CLUSTER", "ADDSLOTS", "16384"]))
+            .is_error());
+    }
+
+    #[test]
+    fn test_ping() {
+        let handler = create_handler();
+
+        let response = handler.execute(make_command(&["PING"]));
+        assert_eq!(response, RespValue::simple_string("PONG"));
+
+        let response = handler.execute(make_command(&["PING", "hello"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("hello")));
+    }
+
+    #[test]
+    fn test_set_get() {
+        let handler = create_handler();
+
+        let response = handler.execute(make_command(&["SET", "key", "value"]));
+        assert_eq!(response, RespValue::ok());
+
+        let response = handler.execute(make_command(&["GET", "key"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("value")));
+    }
+
+    #[test]
+    fn test_get_nonexistent() {
+        let handler = create_handler();
+
+        let response = handler.execute(make_command(&["GET", "nonexistent"]));
+        assert_eq!(response, RespValue::null());
+    }
+
+    #[test]
+    fn test_del() {
+        let handler = create_handler();
+
+        handler.execute(make_command(&["SET", "key1", "value1"]));
+        handler.execute(make_command(&["SET", "key2", "value2"]));
+
+        let response = handler.execute(make_command(&["DEL", "key1", "key2", "key3"]));
+        assert_eq!(response, RespValue::integer(2));
+    }
+
+    #[test]
+    fn test_exists() {
+        let handler = create_handler();
+
+        handler.execute(make_command(&["SET", "key1", "value1"]));
+
+        let response = handler.execute(make_command(&["EXISTS", "key1"]));
+        assert_eq!(response, RespValue::integer(1));
+
+        let response = handler.execute(make_command(&["EXISTS", "nonexistent"]));
+        assert_eq!(response, RespValue::integer(0));
+    }
+
+    #[test]
+    fn test_incr_decr() {
+        let handler = create_handler();
+
+        let response = handler.execute(make_command(&["INCR", "counter"]));
+        assert_eq!(response, RespValue::integer(1));
+
+        let response = handler.execute(make_command(&["INCR", "counter"]));
+        assert_eq!(response, RespValue::integer(2));
+
+        let response = handler.execute(make_command(&["DECR", "counter"]));
+        assert_eq!(response, RespValue::integer(1));
+
+        let response = handler.execute(make_command(&["INCRBY", "counter", "10"]));
+        assert_eq!(response, RespValue::integer(11));
+    }
+
+    #[test]
+    fn test_mset_mget() {
+        let handler = create_handler();
+
+        let response = handler.execute(make_command(&["MSET", "k1", "v1", "k2", "v2"]));
+        assert_eq!(response, RespValue::ok());
+
+        let response = handler.execute(make_command(&["MGET", "k1", "k2", "k3"]));
+        assert_eq!(
+            response,
+            RespValue::Array(vec![
+                RespValue::bulk_string(Bytes::from("v1")),
+                RespValue::bulk_string(Bytes::from("v2")),
+                RespValue::null(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_msetnx() {
+        let handler = create_handler();
+
+        let response = handler.execute(make_command(&["MSETNX", "a", "1", "b", "2"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["MSETNX", "b", "x", "c", "3"]));
+        assert_eq!(response, RespValue::integer(0));
+
+        let response = handler.execute(make_command(&["MGET", "a", "b", "c"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("1")),
+                RespValue::bulk_string(Bytes::from("2")),
+                RespValue::null(),
+            ])
+        );
+
+        let response = handler.execute(make_command(&["MSETNX", "a", "1", "b"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_set_with_options() {
+        let handler = create_handler();
+
+        // SET with NX
+        let response = handler.execute(make_command(&["SET", "key", "value", "NX"]));
+        assert_eq!(response, RespValue::ok());
+
+        // SET with NX on existing key should return nil
+        let response = handler.execute(make_command(&["SET", "key", "newvalue", "NX"]));
+        assert_eq!(response, RespValue::null());
+
+        // SET with XX on existing key
+        let response = handler.execute(make_command(&["SET", "key", "newvalue", "XX"]));
+        assert_eq!(response, RespValue::ok());
+
+        // Verify value changed
+        let response = handler.execute(make_command(&["GET", "key"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("newvalue")));
+    }
+
+    #[test]
+    fn test_set_keepttl() {
+        let handler = create_handler();
+
+        handler.execute(make_command(&["SET", "key", "v1", "EX", "100"]));
+
+        // KEEPTTL preserves the expiry, a plain SET clears it
+        let response = handler.execute(make_command(&["SET", "key", "v2", "KEEPTTL"]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["TTL", "key"]));
+        assert!(matches!(response, RespValue::Integer(t) if t > 0));
+        let response = handler.execute(make_command(&["GET", "key"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("v2")));
+
+        handler.execute(make_command(&["SET", "key", "v3"]));
+        let response = handler.execute(make_command(&["TTL", "key"]));
+        assert_eq!(response, RespValue::integer(-1));
+
+        // KEEPTTL on a new key sets it without expiry
+        handler.execute(make_command(&["SET", "new", "v", "KEEPTTL"]));
+        let response = handler.execute(make_command(&["TTL", "new"]));
+        assert_eq!(response, RespValue::integer(-1));
+
+        // Conflicting options are rejected
+        for cmd in [
+            vec!["SET", "key", "v", "EX", "10", "KEEPTTL"],
+            vec!["SET", "key", "v", "EX", "10", "PX", "100"],
+            vec!["SET", "key", "v", "NX", "XX"],
+        ] {
+            let response = handler.execute(make_command(&cmd));
+            assert_eq!(response, RespValue::error("ERR syntax error"), "{:?}", cmd);
+        }
+        let response = handler.execute(make_command(&["GET", "key"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("v3")));
+    }
+
+    #[test]
+    fn test_set_exat_pxat() {
+        let handler = create_handler();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        let at = (now.as_secs() + 100).to_string();
+        let response = handler.execute(make_command(&["SET", "a", "v", "EXAT", &at]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["TTL", "a"]));
+        assert!(matches!(response, RespValue::Integer(t) if (98..=100).contains(&t)));
+
+        let at = (now.as_millis() + 100_000).to_string();
+        let response = handler.execute(make_command(&["SET", "b", "v", "PXAT", &at]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["PTTL", "b"]));
+        assert!(matches!(response, RespValue::Integer(t) if t > 98_000 && t <= 100_000));
+
+        // A deadline in the past leaves no key behind
+        let response = handler.execute(make_command(&["SET", "c", "v", "PXAT", "1"]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["EXISTS", "c"]));
+        assert_eq!(response, RespValue::integer(0));
+
+        for cmd in [
+            vec!["SET", "d", "v", "EXAT", "0"],
+            vec!["SET", "d", "v", "PXAT", "-5"],
+            vec!["SET", "d", "v", "EXAT", "soon"],
+            vec!["SET", "d", "v", "EX", "0"],
+            // Deadlines past what Unix milliseconds can hold
+            vec!["SET", "d", "v", "EX", "9223372036854775807"],
+            vec!["SET", "d", "v", "PX", "9223372036854775807"],
+            vec!["SET", "d", "v", "EXAT", "9223372036854775807"],
+        ] {
+            let response = handler.execute(make_command(&cmd));
+            assert_eq!(
+                response,
+                RespValue::error("ERR invalid expire time in 'set' command"),
+                "{:?}",
+                cmd
+            );
+        }
+        assert_eq!(
+            handler.execute(make_command(&["EXISTS", "d"])),
+            RespValue::integer(0)
+        );
+
+        // The furthest deadline that fits is accepted, not a panic
+        let response = handler.execute(make_command(&[
+            "SET",
+            "far",
+            "v",
+            "PXAT",
+            "9223372036854775807",
+        ]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["GET", "far"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("v")));
+        let response = handler.execute(make_command(&["SET", "d", "v", "EXAT", &at, "PX", "5"]));
+        assert_eq!(response, RespValue::error("ERR syntax error"));
+        let response = handler.execute(make_command(&["SET", "d", "v", "PXAT"]));
+        assert_eq!(response, RespValue::error("ERR syntax error"));
+
+        // SETEX and PSETEX check their deadline the same way
+        for (cmd, amount) in [
+            ("SETEX", "0"),
+            ("SETEX", "9223372036854775807"),
+            ("PSETEX", "-5"),
+            ("PSETEX", "9223372036854775807"),
+        ] {
+            let response = handler.execute(make_command(&[cmd, "d", amount, "v"]));
+            assert_eq!(
+                response,
+                RespValue::error(format!(
+                    "ERR invalid expire time in '{}' command",
+                    cmd.to_lowercase()
+                ))
+            );
+        }
+        assert_eq!(
+            handler.execute(make_command(&["EXISTS", "d"])),
+            RespValue::integer(0)
+        );
+        let response = handler.execute(make_command(&["PSETEX", "d", "100000", "v"]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["PTTL", "d"]));
+        assert!(matches!(response, RespValue::Integer(t) if t > 98_000 && t <= 100_000));
+    }
+
+    #[test]
+    fn test_append() {
+        let handler = create_handler();
+
+        let response = handler.execute(make_command(&["APPEND", "key", "Hello"]));
+        assert_eq!(response, RespValue::integer(5));
+
+        let response = handler.execute(make_command(&["APPEND", "key", " World"]));
+        assert_eq!(response, RespValue::integer(11));
+
+        let response = handler.execute(make_command(&["GET", "key"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("Hello World")));
+    }
+
+    #[test]
+    fn test_incrbyfloat() {
+        let handler = create_handler();
+
+        let response = handler.execute(make_command(&["INCRBYFLOAT", "f", "10.50"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("10.5")));
+        let response = handler.execute(make_command(&["INCRBYFLOAT", "f", "-0.5"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("10")));
+        let response = handler.execute(make_command(&["INCRBY", "f", "1"]));
+        assert_eq!(response, RespValue::integer(11));
+
+        let response = handler.execute(make_command(&["INCRBYFLOAT", "f", "abc"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["INCRBYFLOAT", "f", "inf"]));
+        assert!(response.is_error());
+
+        handler.execute(make_command(&["SET", "s", "hello"]));
+        let response = handler.execute(make_command(&["INCRBYFLOAT", "s", "1"]));
+        assert_eq!(response, RespValue::error("ERR value is not a valid float"));
+    }
+
+    #[test]
+    fn test_string_wrongtype() {
+        let handler = create_handler();
+        handler.execute(make_command(&["RPUSH", "list", "a"]));
+
+        let wrongtype = RespValue::error(WRONGTYPE);
+        for cmd in [
+            vec!["GET", "list"],
+            vec!["STRLEN", "list"],
+            vec!["APPEND", "list", "x"],
+            vec!["INCR", "list"],
+            vec!["DECRBY", "list", "2"],
+            vec!["INCRBYFLOAT", "list", "2.5"],
+            vec!["GETSET", "list", "x"],
+            vec!["GETDEL", "list"],
+            vec!["SETBIT", "list", "0", "1"],
+            vec!["GETBIT", "list", "0"],
+        ] {
+            assert_eq!(handler.execute(make_command(&cmd)), wrongtype, "{:?}", cmd);
+        }
+
+        // The list survived every rejected command
+        let response = handler.execute(make_command(&["LRANGE", "list", "0", "-1"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![RespValue::bulk_string(Bytes::from("a"))])
+        );
+
+        // MGET reports keys of other types as missing, like Redis
+        let response = handler.execute(make_command(&["MGET", "list"]));
+        assert_eq!(response, RespValue::array(vec![RespValue::null()]));
+
+        // SET ... GET needs a string to return, and then writes nothing
+        handler.execute(make_command(&["HSET", "hash", "f", "v"]));
+        for key in ["list", "hash"] {
+            for cmd in [
+                vec!["SET", key, "v", "GET"],
+                vec!["SET", key, "v", "NX", "GET"],
+                vec!["SET", key, "v", "EX", "100", "GET"],
+            ] {
+                assert_eq!(handler.execute(make_command(&cmd)), wrongtype, "{:?}", cmd);
+            }
+        }
+        let response = handler.execute(make_command(&["TYPE", "list"]));
+        assert_eq!(response, RespValue::simple_string("list"));
+        let response = handler.execute(make_command(&["TYPE", "hash"]));
+        assert_eq!(response, RespValue::simple_string("hash"));
+        let response = handler.execute(make_command(&["TTL", "list"]));
+        assert_eq!(response, RespValue::integer(-1));
+
+        // SET replaces a value of any type
+        handler.execute(make_command(&["SET", "list", "v"]));
+        let response = handler.execute(make_command(&["GET", "list"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("v")));
+    }
+
+    #[test]
+    fn test_expire_any_type() {
+        let handler = create_handler();
+        handler.execute(make_command(&["RPUSH", "list", "a"]));
+        handler.execute(make_command(&["HSET", "hash", "f", "v"]));
+        handler.execute(make_command(&["ZADD", "zset", "1", "m"]));
+
+        let response = handler.execute(make_command(&["EXPIRE", "list", "60"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["TTL", "list"]));
+        assert!(matches!(response, RespValue::Integer(t) if t > 0 && t <= 60));
+        let response = handler.execute(make_command(&["PERSIST", "list"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["PTTL", "list"]));
+        assert_eq!(response, RespValue::integer(-1));
+
+        let response = handler.execute(make_command(&["PEXPIRE", "hash", "100000"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["PTTL", "hash"]));
+        assert!(matches!(response, RespValue::Integer(t) if t > 0));
+
+        // A timestamp in the past deletes the key
+        let response = handler.execute(make_command(&["PEXPIREAT", "zset", "1"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["TYPE", "zset"]));
+        assert_eq!(response, RespValue::simple_string("none"));
+
+        let future = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            + 60_000;
+        let response = handler.execute(make_command(&["PEXPIREAT", "hash", &future.to_string()]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["EXPIREAT", "missing", "99999999999"]));
+        assert_eq!(response, RespValue::integer(0));
+    }
+
+    #[test]
+    fn test_sort() {
+        let handler = create_handler();
+        handler.execute(make_command(&["RPUSH", "ids", "3", "1", "2"]));
+        handler.execute(make_command(&["SET", "w_1", "30"]));
+        handler.execute(make_command(&["SET", "w_2", "20"]));
+        handler.execute(make_command(&["SET", "w_3", "10"]));
+        handler.execute(make_command(&["HSET", "user_1", "name", "ann"]));
+        handler.execute(make_command(&["HSET", "user_3", "name", "cat"]));
+
+        let bulk = |s: &str| RespValue::bulk_string(Bytes::from(s.to_string()));
+
+        let response = handler.execute(make_command(&["SORT", "ids"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![bulk("1"), bulk("2"), bulk("3")])
+        );
+
+        let response = handler.execute(make_command(&["SORT", "ids", "DESC", "LIMIT", "0", "2"]));
+        assert_eq!(response, RespValue::array(vec![bulk("3"), bulk("2")]));
+
+        let response = handler.execute(make_command(&[
+            "SORT",
+            "ids",
+            "BY",
+            "w_*",
+            "GET",
+            "#",
+            "GET",
+            "user_*->name",
+        ]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                bulk("3"),
+                bulk("cat"),
+                bulk("2"),
+                RespValue::null(),
+                bulk("1"),
+                bulk("ann"),
+            ])
+        );
+
+        // STORE returns the number of stored elements
+        let response = handler.execute(make_command(&["SORT", "ids", "ALPHA", "STORE", "out"]));
+        assert_eq!(response, RespValue::integer(3));
+        let response = handler.execute(make_command(&["LRANGE", "out", "0", "-1"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![bulk("1"), bulk("2"), bulk("3")])
+        );
+
+        handler.execute(make_command(&["RPUSH", "words", "b", "a"]));
+        let response = handler.execute(make_command(&["SORT", "words"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["SORT", "words", "ALPHA"]));
+        assert_eq!(response, RespValue::array(vec![bulk("a"), bulk("b")]));
+        let response = handler.execute(make_command(&["SORT", "w_1"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["SORT", "ids", "LIMIT", "0"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_ltrim() {
+        let handler = create_handler();
+        handler.execute(make_command(&["RPUSH", "recent", "a", "b", "c", "d"]));
+
+        let response = handler.execute(make_command(&["LTRIM", "recent", "-2", "-1"]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["LRANGE", "recent", "0", "-1"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("c")),
+                RespValue::bulk_string(Bytes::from("d")),
+            ])
+        );
+
+        let response = handler.execute(make_command(&["LTRIM", "missing", "0", "1"]));
+        assert_eq!(response, RespValue::ok());
+        handler.execute(make_command(&["SET", "str", "v"]));
+        let response = handler.execute(make_command(&["LTRIM", "str", "0", "1"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_lmove_rpoplpush() {
+        let handler = create_handler();
+        handler.execute(make_command(&["RPUSH", "queue", "job1", "job2"]));
+
+        // Reliable queue: take the oldest job into a processing list
+        let response = handler.execute(make_command(&[
+            "LMOVE",
+            "queue",
+            "processing",
+            "LEFT",
+            "RIGHT",
+        ]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("job1")));
+        let response = handler.execute(make_command(&["RPOPLPUSH", "queue", "processing"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("job2")));
+        let response = handler.execute(make_command(&["LRANGE", "processing", "0", "-1"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("job2")),
+                RespValue::bulk_string(Bytes::from("job1")),
+            ])
+        );
+
+        let response = handler.execute(make_command(&["RPOPLPUSH", "queue", "processing"]));
+        assert_eq!(response, RespValue::null());
+        let response = handler.execute(make_command(&["LMOVE", "processing", "x", "UP", "LEFT"]));
+        assert!(response.is_error());
+        handler.execute(make_command(&["SET", "str", "v"]));
+        let response = handler.execute(make_command(&["RPOPLPUSH", "processing", "str"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_info_keyspace() {
+        let handler = create_handler();
+        handler.execute(make_command(&["SET", "a", "1"]));
+        handler.execute(make_command(&["RPUSH", "b", "x"]));
+        handler.execute(make_command(&["EXPIRE", "b", "100"]));
+
+        let response = handler.execute(make_command(&["INFO"]));
+        let info = match response {
+            RespValue::BulkString(info) => info,
+            other => panic!("unexpected INFO reply: {:?}", other),
+        };
+        let info = String::from_utf8(info.to_vec()).unwrap();
+        assert!(info.contains("db0:keys=2,expires=1\r\n"));
+        assert!(info.contains(&format!("mem_allocator:{}\r\n", allocator::name())));
+    }
+
+    #[test]
+    fn test_info_sections() {
+        let handler = create_handler();
+        let info = |args: &[&str]| match handler.execute(make_command(args)) {
+            RespValue::BulkString(info) => String::from_utf8(info.to_vec()).unwrap(),
+            other => panic!("unexpected INFO reply: {:?}", other),
+        };
+
+        let all = info(&["INFO"]);
+        let titles: Vec<&str> = all.lines().filter(|line| line.starts_with('#')).collect();
+        assert_eq!(
+            titles,
+            [
+                "# Server",
+                "# Clients",
+                "# Memory",
+                "# Persistence",
+                "# Stats",
+                "# Replication",
+                "# CPU",
+                "# Cluster",
+                "# Keyspace"
+            ]
+        );
+        for everything in ["all", "EVERYTHING", "default"] {
+            let info = info(&["INFO", everything]);
+            assert!(info.starts_with("# Server\r\n") && info.contains("# Keyspace\r\n"));
+        }
+
+        let stats = info(&["INFO", "stats"]);
+        assert!(stats.starts_with("# Stats\r\n"));
+        assert!(!stats.contains("# Server"));
+
+        // Several sections, in INFO's order whatever the order asked
+        let both = info(&["INFO", "Keyspace", "REPLICATION"]);
+        assert!(both.starts_with("# Replication\r\n"));
+        assert!(both.contains("\r\n\r\n# Keyspace\r\n"));
+
+        assert_eq!(info(&["INFO", "nosuchsection"]), "");
+        if cfg!(target_os = "linux") {
+            assert!(info(&["INFO", "cpu"]).contains("used_cpu_user:"));
+        }
+    }
+
+    #[test]
+    fn test_info_stats() {
+        let stats = Arc::new(ConnectionStats::new());
+        stats.connection_opened();
+        stats.connection_rejected();
+        stats.command_processed();
+        stats.bytes_read(14);
+        let handler = create_handler().with_connection_stats(stats);
+
+        handler.execute(make_command(&["SET", "a", "1"]));
+        handler.execute(make_command(&["GET", "a"]));
+        handler.execute(make_command(&["GET", "missing"]));
+        handler.execute(make_command(&["MGET", "a", "missing"]));
+        handler.execute(make_command(&["LLEN", "missing"]));
+
+        let info = match handler.execute(make_command(&["INFO", "stats", "clients"])) {
+            RespValue::BulkString(info) => String::from_utf8(info.to_vec()).unwrap(),
+            other => panic!("unexpected INFO reply: {:?}", other),
+        };
+        for line in [
+            "connected_clients:1",
+            "total_connections_received:1",
+            "total_commands_processed:1",
+            "total_net_input_bytes:14",
+            "rejected_connections:1",
+            "keyspace_hits:2",
+            "keyspace_misses:3",
+        ] {
+            assert!(
+                info.contains(&format!("{}\r\n", line)),
+                "{}: {}",
+                line,
+                info
+            );
+        }
+    }
+
+    #[test]
+    fn test_expire_flags() {
+        let handler = create_handler();
+        handler.execute(make_command(&["SET", "key", "v"]));
+
+        let response = handler.execute(make_command(&["EXPIRE", "key", "100", "XX"]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["EXPIRE", "key", "100", "NX"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["EXPIRE", "key", "50", "GT"]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["PEXPIRE", "key", "500000", "GT"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["TTL", "key"]));
+        assert!(matches!(response, RespValue::Integer(t) if t > 400));
+        let response = handler.execute(make_command(&["EXPIREAT", "key", "1", "LT"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["EXISTS", "key"]));
+        assert_eq!(response, RespValue::integer(0));
+
+        let response = handler.execute(make_command(&["EXPIRE", "key", "10", "NX", "GT"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["EXPIRE", "key", "10", "GT", "LT"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["EXPIRE", "key", "10", "BOGUS"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["EXPIRE", "key", &i64::MAX.to_string()]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_expiretime() {
+        let handler = create_handler();
+        handler.execute(make_command(&["SET", "key", "v"]));
+        handler.execute(make_command(&["RPUSH", "list", "a"]));
+
+        let response = handler.execute(make_command(&["EXPIRETIME", "key"]));
+        assert_eq!(response, RespValue::integer(-1));
+        let response = handler.execute(make_command(&["PEXPIRETIME", "missing"]));
+        assert_eq!(response, RespValue::integer(-2));
+
+        let response = handler.execute(make_command(&["PEXPIREAT", "key", "33177117420123"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["PEXPIRETIME", "key"]));
+        assert_eq!(response, RespValue::integer(33177117420123));
+        let response = handler.execute(make_command(&["EXPIRETIME", "key"]));
+        assert_eq!(response, RespValue::integer(33177117420));
+
+        let response = handler.execute(make_command(&["EXPIREAT", "list", "33177117420"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["EXPIRETIME", "list"]));
+        assert_eq!(response, RespValue::integer(33177117420));
+    }
+
+    #[test]
+    fn test_rename_copy_any_type() {
+        let handler = create_handler();
+        handler.execute(make_command(&["RPUSH", "list", "a", "b"]));
+        handler.execute(make_command(&["EXPIRE", "list", "100"]));
+        handler.execute(make_command(&["SET", "str", "v"]));
+
+        // RENAME moves the whole value, TTL included
+        let response = handler.execute(make_command(&["RENAME", "list", "moved"]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["LRANGE", "moved", "0", "-1"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("a")),
+                RespValue::bulk_string(Bytes::from("b")),
+            ])
+        );
+        let response = handler.execute(make_command(&["TTL", "moved"]));
+        assert!(matches!(response, RespValue::Integer(t) if t > 0));
+        let response = handler.execute(make_command(&["EXISTS", "list"]));
+        assert_eq!(response, RespValue::integer(0));
+
+        let response = handler.execute(make_command(&["RENAMENX", "moved", "str"]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["RENAME", "missing", "x"]));
+        assert!(response.is_error());
+
+        // COPY keeps the source and refuses to overwrite without REPLACE
+        let response = handler.execute(make_command(&["COPY", "moved", "str"]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["COPY", "moved", "str", "REPLACE"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["TYPE", "str"]));
+        assert_eq!(response, RespValue::simple_string("list"));
+        let response = handler.execute(make_command(&["LLEN", "moved"]));
+        assert_eq!(response, RespValue::integer(2));
+
+        // The copy is independent of the source
+        handler.execute(make_command(&["RPUSH", "str", "c"]));
+        let response = handler.execute(make_command(&["LLEN", "moved"]));
+        assert_eq!(response, RespValue::integer(2));
+
+        let response = handler.execute(make_command(&["COPY", "moved", "db0", "DB", "0"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["TTL", "db0"]));
+        assert!(matches!(response, RespValue::Integer(t) if t > 0));
+        let response = handler.execute(make_command(&["COPY", "moved", "db1", "DB", "16"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["COPY", "moved", "db1", "DB"]));
+        assert!(response.is_error());
+
+        let response = handler.execute(make_command(&["COPY", "moved", "moved"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["COPY", "moved", "x", "BOGUS"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["DBSIZE"]));
+        assert_eq!(response, RespValue::integer(3));
+    }
+
+    #[test]
+    fn test_copy_db_option() {
+        let handler = create_handler();
+        handler.execute(make_command(&["SET", "src", "v"]));
+
+        // DB 0 is the database the client is in
+        let response = handler.execute(make_command(&["COPY", "src", "dst", "DB", "0"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["GET", "dst"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("v")));
+        let response = handler.execute(make_command(&["COPY", "src", "src", "DB", "0"]));
+        assert_eq!(
+            response,
+            RespValue::error("ERR source and destination objects are the same")
+        );
+
+        // Another database gets the copy, and the source stays
+        let response = handler.execute(make_command(&["COPY", "src", "src", "DB", "15"]));
+        assert_eq!(response, RespValue::integer(1));
+        assert_eq!(
+            handler.storage().db(15).unwrap().get(&Bytes::from("src")),
+            Some(Bytes::from("v"))
+        );
+        let response = handler.execute(make_command(&["GET", "src"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("v")));
+
+        // Out-of-range indexes are refused without copying anything
+        for index in ["16", "-1", "9223372036854775807"] {
+            let response = handler.execute(make_command(&["COPY", "src", "other", "DB", index]));
+            assert_eq!(response, RespValue::error("ERR DB index is out of range"));
+        }
+        let response = handler.execute(make_command(&["COPY", "src", "other", "DB", "x"]));
+        assert_eq!(
+            response,
+            RespValue::error("ERR value is not an integer or out of range")
+        );
+        let response = handler.execute(make_command(&["EXISTS", "other"]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["DBSIZE"]));
+        assert_eq!(response, RespValue::integer(2));
+    }
+
+    #[test]
+    fn test_object_command() {
+        let handler = create_handler();
+        handler.execute(make_command(&["SET", "n", "42"]));
+        handler.execute(make_command(&["RPUSH", "list", "a"]));
+
+        let response = handler.execute(make_command(&["OBJECT", "ENCODING", "n"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("int")));
+        let response = handler.execute(make_command(&["OBJECT", "encoding", "list"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("quicklist")));
+        let response = handler.execute(make_command(&["OBJECT", "IDLETIME", "list"]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["OBJECT", "FREQ", "n"]));
+        assert!(matches!(response, RespValue::Integer(f) if f > 0));
+        let response = handler.execute(make_command(&["OBJECT", "REFCOUNT", "n"]));
+        assert_eq!(response, RespValue::integer(1));
+
+        let response = handler.execute(make_command(&["OBJECT", "ENCODING", "missing"]));
+        assert_eq!(response, RespValue::null());
+        let response = handler.execute(make_command(&["OBJECT", "HELP"]));
+        assert!(matches!(response, RespValue::Array(ref lines) if !lines.is_empty()));
+        let response = handler.execute(make_command(&["OBJECT", "BOGUS", "n"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["OBJECT", "FREQ"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_keys_command() {
+        let handler = create_handler();
+        for i in 0..5 {
+            handler.execute(make_command(&["SET", &format!("user:{}", i), "v"]));
+        }
+        handler.execute(make_command(&["SET", "other", "v"]));
+
+        // The reply is serialized up front; it reads back as a plain array
+        let response = handler.execute(make_command(&["KEYS", "user:*"]));
+        let (reply, _) = crate::protocol::parse_message(&response.serialize())
+            .unwrap()
+            .unwrap();
+        let mut keys: Vec<String> = reply.extract().unwrap();
+        keys.sort();
+        assert_eq!(keys, ["user:0", "user:1", "user:2", "user:3", "user:4"]);
+
+        let response = handler.execute(make_command(&["KEYS", "nothing*"]));
+        assert_eq!(response.serialize(), b"*0\r\n");
+        assert!(handler.execute(make_command(&["KEYS"])).is_error());
+    }
+
+    #[test]
+    fn test_scan_command() {
+        let handler = create_handler();
+        for i in 0..25 {
+            handler.execute(make_command(&["SET", &format!("user:{}", i), "v"]));
+        }
+        handler.execute(make_command(&["RPUSH", "queue", "a"]));
+
+        let mut cursor = "0".to_string();
+        let mut found = Vec::new();
+        loop {
+            let response = handler.execute(make_command(&[
+                "SCAN", &cursor, "MATCH", "user:*", "COUNT", "4",
+            ]));
+            let RespValue::Array(parts) = response else {
+                panic!("unexpected reply {:?}", response);
+            };
+            let (RespValue::BulkString(next), RespValue::Array(keys)) = (&parts[0], &parts[1])
+            else {
+                panic!("unexpected reply {:?}", parts);
+            };
+            found.extend(keys.iter().cloned());
+            cursor = String::from_utf8(next.to_vec()).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(found.len(), 25);
+
+        let response =
+            handler.execute(make_command(&["SCAN", "0", "TYPE", "LIST", "COUNT", "100"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("0")),
+                RespValue::array(vec![RespValue::bulk_string(Bytes::from("queue"))]),
+            ])
+        );
+
+        for cmd in [
+            vec!["SCAN", "abc"],
+            vec!["SCAN", "0", "COUNT", "0"],
+            vec!["SCAN", "0", "MATCH"],
+            vec!["SCAN", "0", "BOGUS", "x"],
+        ] {
+            assert!(handler.execute(make_command(&cmd)).is_error(), "{:?}", cmd);
+        }
+    }
+
+    #[test]
+    fn test_unlink() {
+        let handler = create_handler();
+        handler.execute(make_command(&["SET", "a", "1"]));
+        handler.execute(make_command(&["RPUSH", "b", "x", "y"]));
+
+        let response = handler.execute(make_command(&["UNLINK", "a", "b", "c"]));
+        assert_eq!(response, RespValue::integer(2));
+        let response = handler.execute(make_command(&["DBSIZE"]));
+        assert_eq!(response, RespValue::integer(0));
+    }
+
+    #[test]
+    fn test_touch() {
+        let handler = create_handler();
+        handler.execute(make_command(&["SET", "a", "1"]));
+        handler.execute(make_command(&["SADD", "b", "m"]));
+
+        let response = handler.execute(make_command(&["TOUCH", "a", "b", "c"]));
+        assert_eq!(response, RespValue::integer(2));
+        let response = handler.execute(make_command(&["TOUCH"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_dbsize() {
+        let handler = create_handler();
+
+        let response = handler.execute(make_command(&["DBSIZE"]));
+        assert_eq!(response, RespValue::integer(0));
+
+        handler.execute(make_command(&["SET", "key1", "value1"]));
+        handler.execute(make_command(&["SET", "key2", "value2"]));
+
+        let response = handler.execute(make_command(&["DBSIZE"]));
+        assert_eq!(response, RespValue::integer(2));
+    }
+
+    #[test]
+    fn test_flushdb() {
+        let handler = create_handler();
+
+        handler.execute(make_command(&["SET", "key1", "value1"]));
+        handler.execute(make_command(&["SET", "key2", "value2"]));
+
+        let response = handler.execute(make_command(&["FLUSHDB"]));
+        assert_eq!(response, RespValue::ok());
+
+        let response = handler.execute(make_command(&["DBSIZE"]));
+        assert_eq!(response, RespValue::integer(0));
+    }
+
+    #[test]
+    fn test_debug_reload() {
+        let handler = create_handler();
+        let path = std::env::temp_dir().join(format!("flashkv-{}-reload.fkv", std::process::id()));
+        handler.storage.snapshots().set_path(&path);
+
+        handler.execute(make_command(&["SET", "key1", "value1"]));
+        handler.execute(make_command(&["ZADD", "board", "1", "a", "2", "b"]));
+        handler.execute(make_command(&["EXPIRE", "key1", "100"]));
+
+        let response = handler.execute(make_command(&["DEBUG", "RELOAD"]));
+        assert_eq!(response, RespValue::ok());
+        std::fs::remove_file(&path).unwrap();
+
+        let response = handler.execute(make_command(&["DBSIZE"]));
+        assert_eq!(response, RespValue::integer(2));
+        let response = handler.execute(make_command(&["GET", "key1"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("value1")));
+        let response = handler.execute(make_command(&["ZSCORE", "board", "b"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("2")));
+        match handler.execute(make_command(&["TTL", "key1"])) {
+            RespValue::Integer(ttl) => assert!(ttl > 90 && ttl <= 100),
+            other => panic!("unexpected TTL reply: {:?}", other),
+        }
+
+        // A snapshot that can't be written leaves the keyspace alone
+        handler.storage.snapshots().set_path(
+            std::env::temp_dir()
+                .join("flashkv-missing-dir")
+                .join("x.fkv"),
+        );
+        let response = handler.execute(make_command(&["DEBUG", "RELOAD"]));
+        assert!(matches!(response, RespValue::Error(_)));
+        let response = handler.execute(make_command(&["DBSIZE"]));
+        assert_eq!(response, RespValue::integer(2));
+    }
+
+    #[test]
+    fn test_save_and_bgsave() {
+        let handler = create_handler();
+        let path = std::env::temp_dir().join(format!("flashkv-{}-save.fkv", std::process::id()));
+        handler.storage.snapshots().set_path(&path);
+
+        handler.execute(make_command(&["SET", "key1", "value1"]));
+        handler.execute(make_command(&["RPUSH", "list", "a", "b"]));
+
+        let response = handler.execute(make_command(&["SAVE"]));
+        assert_eq!(response, RespValue::ok());
+
+        let restored = StorageEngine::new();
+        restored.snapshots().set_path(&path);
+        assert_eq!(restored.load().unwrap(), 2);
+
+        handler.execute(make_command(&["SET", "key2", "value2"]));
+        let response = handler.execute(make_command(&["BGSAVE"]));
+        assert_eq!(
+            response,
+            RespValue::simple_string("Background saving started")
+        );
+        while handler.storage.snapshots().in_progress() {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(handler.storage.snapshots().last_save_ok());
+
+        let restored = StorageEngine::new();
+        restored.snapshots().set_path(&path);
+        assert_eq!(restored.load().unwrap(), 3);
+        std::fs::remove_file(&path).unwrap();
+
+        let response = handler.execute(make_command(&["LASTSAVE"]));
+        assert_eq!(
+            response,
+            RespValue::integer(handler.storage.snapshots().last_save())
+        );
+
+        let response = handler.execute(make_command(&["INFO"]));
+        let info = match response {
+            RespValue::BulkString(info) => String::from_utf8(info.to_vec()).unwrap(),
+            other => panic!("unexpected INFO reply: {:?}", other),
+        };
+        assert!(info.contains("# Persistence\r\n"));
+        assert!(info.contains("rdb_changes_since_last_save:0\r\n"));
+        assert!(info.contains("rdb_bgsave_in_progress:0\r\n"));
+        assert!(info.contains("rdb_last_bgsave_status:ok\r\n"));
+        assert!(info.contains("persistence_compression:none\r\n"));
+        assert!(info.contains("aof_enabled:0\r\n"));
+        assert!(info.contains(&format!(
+            "rdb_last_save_time:{}\r\n",
+            handler.storage.snapshots().last_save()
+        )));
+
+        let response = handler.execute(make_command(&["SAVE", "now"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_lastsave_and_persistence_info() {
+        let handler = create_handler();
+        let path =
+            std::env::temp_dir().join(format!("flashkv-{}-lastsave.fkv", std::process::id()));
+        handler.storage.snapshots().set_path(&path);
+        let persistence = || match handler.execute(make_command(&["INFO", "persistence"])) {
+            RespValue::BulkString(info) => String::from_utf8(info.to_vec()).unwrap(),
+            other => panic!("unexpected INFO reply: {:?}", other),
+        };
+
+        // Writes count as changes until a save
+        handler.execute(make_command(&["SET", "a", "1"]));
+        handler.execute(make_command(&["RPUSH", "list", "x", "y"]));
+        let info = persistence();
+        assert!(info.starts_with("# Persistence\r\n"));
+        assert!(info.contains("rdb_changes_since_last_save:2\r\n"));
+        assert!(info.contains("rdb_last_bgsave_time_sec:-1\r\n"));
+        assert!(info.contains("rdb_current_bgsave_time_sec:-1\r\n"));
+
+        let before = handler.storage.snapshots().last_save();
+        assert_eq!(handler.execute(make_command(&["SAVE"])), RespValue::ok());
+        std::fs::remove_file(&path).unwrap();
+        let saved = handler.storage.snapshots().last_save();
+        assert!(saved >= before);
+        assert_eq!(
+            handler.execute(make_command(&["LASTSAVE"])),
+            RespValue::integer(saved)
+        );
+        let info = persistence();
+        assert!(info.contains("rdb_changes_since_last_save:0\r\n"));
+        assert!(info.contains("rdb_last_bgsave_status:ok\r\n"));
+        assert!(info.contains("rdb_last_bgsave_time_sec:0\r\n"));
+        assert!(info.contains(&format!("rdb_last_save_time:{}\r\n", saved)));
+
+        // A failed save is reported, and LASTSAVE still gives the last good one
+        handler.execute(make_command(&["SET", "b", "2"]));
+        handler
+            .storage
+            .snapshots()
+            .set_path(path.join("missing").join("dump.fkv"));
+        assert!(handler.execute(make_command(&["SAVE"])).is_error());
+        assert_eq!(
+            handler.execute(make_command(&["LASTSAVE"])),
+            RespValue::integer(saved)
+        );
+        let info = persistence();
+        assert!(info.contains("rdb_changes_since_last_save:1\r\n"));
+        assert!(info.contains("rdb_last_bgsave_status:err\r\n"));
+
+        assert!(handler
+            .execute(make_command(&["LASTSAVE", "now"]))
+            .is_error());
+    }
+
+    #[test]
+    fn test_export() {
+        let handler = create_handler();
+        let dir = std::env::temp_dir();
+        handler.storage.snapshots().set_path(dir.join("dump.fkv"));
+        let name = format!("flashkv-{}-export.csv", std::process::id());
+        let path = dir.join(&name);
+
+        handler.execute(make_command(&["SET", "user:1", "Ariz"]));
+        handler.execute(make_command(&["SADD", "user:2", "a"]));
+        handler.execute(make_command(&["SET", "other", "x"]));
+
+        let response = handler.execute(make_command(&[
+            "EXPORT", &name, "FORMAT", "csv", "MATCH", "user:*",
+        ]));
+        assert_eq!(response, RespValue::integer(2));
+        let csv = std::fs::read_to_string(&path).unwrap();
+        assert!(csv.starts_with("key,type,ttl,value\r\n"));
+        assert!(csv.contains("user:1,string,-1,Ariz\r\n"));
+        assert!(csv.contains("user:2,set,-1,\"[\"\"a\"\"]\"\r\n"));
+        std::fs::remove_file(&path).unwrap();
+
+        // The file must stay inside the data directory
+        let outside = dir.join("..").join(&name);
+        for name in [
+            path.to_str().unwrap(),
+            "../out.json",
+            "a/../../out.json",
+            "",
+        ] {
+            assert_eq!(
+                handler.execute(make_command(&["EXPORT", name])),
+                RespValue::error(
+                    "ERR export file name must be a relative path inside the data directory"
+                )
+            );
+        }
+        assert!(!path.exists() && !outside.exists());
+
+        let response = handler.execute(make_command(&["EXPORT", "out.xml", "FORMAT", "XML"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["EXPORT"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_aof_replays_to_the_same_keyspace() {
+        use crate::storage::aof::{self, AppendFsync};
+
+        let handler = create_handler();
+        let path = std::env::temp_dir().join(format!("flashkv-{}-handler.aof", std::process::id()));
+        handler
+            .storage
+            .aof()
+            .open(&path, AppendFsync::Always)
+            .unwrap();
+
+        handler.execute(make_command(&["SET", "counter", "1"]));
+        handler.execute(make_command(&["SET", "session", "x", "EX", "100"]));
+        handler.execute(make_command(&["RPUSH", "queue", "a", "b"]));
+        let response = handler.execute(make_command(&["BGREWRITEAOF"]));
+        assert_eq!(
+            response,
+            RespValue::simple_string("Background append only file rewriting started")
+        );
+        handler.execute(make_command(&["INCR", "counter"]));
+        let id = handler.execute(make_command(&["XADD", "events", "*", "kind", "login"]));
+        handler.execute(make_command(&["BLPOP", "queue", "0"]));
+        handler.execute(make_command(&["GET", "counter"]));
+        handler.execute(make_command(&["INCR", "session"]));
+        while handler.storage.aof().rewrite_in_progress() {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(handler.storage.aof().last_rewrite_ok());
+        handler.execute(make_command(&["INCR", "counter"]));
+        handler.execute(make_command(&["SELECT", "5"]));
+        handler.execute(make_command(&["SET", "counter", "elsewhere"]));
+        handler.execute(make_command(&["SELECT", "0"]));
+
+        let replica = create_handler();
+        aof::load(&replica.storage, &path, false, |command| {
+            replica.execute(RespValue::array(
+                command.into_iter().map(RespValue::bulk_string).collect(),
+            ));
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        replica.execute(make_command(&["SELECT", "0"]));
+
+        for cmd in [
+            vec!["GET", "counter"],
+            vec!["LRANGE", "queue", "0", "-1"],
+            vec!["XRANGE", "events", "-", "+"],
+            vec!["PEXPIRETIME", "session"],
+        ] {
+            assert_eq!(
+                replica.execute(make_command(&cmd)),
+                handler.execute(make_command(&cmd))
+            );
+        }
+        assert!(matches!(id, RespValue::BulkString(_)));
+        assert_eq!(
+            replica.execute(make_command(&["GET", "counter"])),
+            RespValue::bulk_string(Bytes::from("3"))
+        );
+        assert_eq!(
+            replica.storage.db(5).unwrap().get(&Bytes::from("counter")),
+            Some(Bytes::from("elsewhere"))
+        );
+    }
+
+    #[test]
+    fn test_bgrewriteaof_requires_aof() {
+        let handler = create_handler();
+        let response = handler.execute(make_command(&["BGREWRITEAOF"]));
+        assert!(response.is_error());
+    }
+
+    const LIBRARY: &str = "#!lua name=counters
+redis.register_function('bump', function(keys, args)
+    return redis.call('INCRBY', keys[1], args[1])
+end)
+redis.register_function{
+    function_name = 'peek',
+    callback = function(keys) return redis.call('GET', keys[1]) end,
+    flags = {'no-writes'},
+    description = 'Reads a counter',
+}
+redis.register_function{
+    function_name = 'sneak',
+    callback = function(keys) return redis.call('DEL', keys[1]) end,
+    flags = {'no-writes'},
+}";
+
+    #[test]
+    fn test_function_load_and_fcall() {
+        let handler = create_handler();
+        let response = handler.execute(make_command(&["FUNCTION", "LOAD", LIBRARY]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("counters")));
+        let response = handler.execute(make_command(&["FUNCTION", "LOAD", LIBRARY]));
+        assert_eq!(
+            response,
+            RespValue::error("ERR Library 'counters' already exists")
+        );
+        let response = handler.execute(make_command(&["FUNCTION", "LOAD", "REPLACE", LIBRARY]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("counters")));
+        assert_eq!(handler.storage.snapshots().changes(), 2);
+
+        let response = handler.execute(make_command(&["FCALL", "bump", "1", "hits", "5"]));
+        assert_eq!(response, RespValue::integer(5));
+        let response = handler.execute(make_command(&["FCALL", "bump", "1", "hits", "2"]));
+        assert_eq!(response, RespValue::integer(7));
+        assert_eq!(handler.storage.snapshots().changes(), 4);
+        let response = handler.execute(make_command(&["FCALL_RO", "peek", "1", "hits"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("7")));
+
+        // A function that may write can't be called with FCALL_RO, and one
+        // that may not write can't send writes
+        let response = handler.execute(make_command(&["FCALL_RO", "bump", "1", "hits", "1"]));
+        assert_eq!(
+            response,
+            RespValue::error("ERR Can not execute a script with write flag using *_ro command.")
+        );
+        let response = handler.execute(make_command(&["FCALL", "sneak", "1", "hits"]));
+        assert_eq!(
+            response,
+            RespValue::error("ERR Write commands are not allowed from read-only scripts.")
+        );
+        let response = handler.execute(make_command(&["GET", "hits"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("7")));
+
+        let response = handler.execute(make_command(&["FCALL", "missing", "0"]));
+        assert_eq!(response, RespValue::error("ERR Function not found"));
+        let response = handler.execute(make_command(&["FCALL", "bump", "-1"]));
+        assert_eq!(
+            response,
+            RespValue::error("ERR Number of keys can't be negative")
+        );
+        let response = handler.execute(make_command(&["FCALL", "bump", "2", "hits"]));
+        assert_eq!(
+            response,
+            RespValue::error("ERR Number of keys can't be greater than number of args")
+        );
+        let response = handler.execute(make_command(&["FCALL", "bump"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_functions_cannot_send_noscript_commands() {
+        let handler = create_handler();
+        let library = "#!lua name=bad
+redis.register_function('save', function() return redis.call('SAVE') end)
+redis.register_function('nested', function() return redis.call('FCALL', 'save', '0') end)
+redis.register_function('unknown', function() return redis.pcall('NOPE') end)";
+        handler.execute(make_command(&["FUNCTION", "LOAD", library]));
+
+        for (function, error) in [
+            ("save", "This Redis command is not allowed from script"),
+            ("nested", "This Redis command is not allowed from script"),
+            ("unknown", "Unknown Redis command called from script"),
+        ] {
+            match handler.execute(make_command(&["FCALL", function, "0"])) {
+                RespValue::Error(e) => assert!(e.contains(error), "{}: {}", function, e),
+                other => panic!("unexpected reply: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_function_kill() {
+        let handler = create_handler();
+        let response = handler.execute(make_command(&["FUNCTION", "KILL"]));
+        assert_eq!(
+            response,
+            RespValue::error("NOTBUSY No scripts in execution right now.")
+        );
+        let response = handler.execute(make_command(&[
+            "FUNCTION",
+            "LOAD",
+            "#!lua name=stuck\nwhile true do end",
+        ]));
+        assert_eq!(response, RespValue::error("ERR FUNCTION LOAD timeout"));
+        assert!(handler.storage.functions().is_empty());
+
+        handler.execute(make_command(&[
+            "FUNCTION",
+            "LOAD",
+            "#!lua name=spin\nredis.register_function('spin', function(keys) \
+             redis.call('SET', keys[1], 'started') while true do end end)",
+        ]));
+        let response = handler.execute(make_command(&[
+            "CONFIG",
+            "SET",
+            "busy-reply-threshold",
+            "0",
+        ]));
+        assert_eq!(response, RespValue::ok());
+
+        let storage = Arc::clone(handler.storage());
+        let caller = std::thread::spawn(move || {
+            CommandHandler::new(storage).execute(make_command(&["FCALL", "spin", "1", "k"]))
+        });
+        while !handler.storage.functions().is_busy() {
+            std::thread::yield_now();
+        }
+
+        // Other clients still run commands, but not functions
+        let response = handler.execute(make_command(&["GET", "k"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("started")));
+        let busy = RespValue::error(
+            "BUSY Redis is busy running a script. You can only call FUNCTION KILL.",
+        );
+        let response = handler.execute(make_command(&["FCALL", "spin", "1", "k"]));
+        assert_eq!(response, busy);
+        let response = handler.execute(make_command(&["FUNCTION", "DELETE", "spin"]));
+        assert_eq!(response, busy);
+
+        let response = handler.execute(make_command(&["FUNCTION", "KILL"]));
+        assert_eq!(response, RespValue::ok());
+        assert_eq!(
+            caller.join().unwrap(),
+            RespValue::error("ERR Script killed by user with FUNCTION KILL")
+        );
+        let response = handler.execute(make_command(&["FUNCTION", "DELETE", "spin"]));
+        assert_eq!(response, RespValue::ok());
+    }
+
+    #[test]
+    fn test_function_list() {
+        let handler = create_handler();
+        handler.execute(make_command(&["FUNCTION", "LOAD", LIBRARY]));
+        handler.execute(make_command(&[
+            "FUNCTION",
+            "LOAD",
+            "#!lua name=other\nredis.register_function('noop', function() end)",
+        ]));
+
+        let response = handler.execute(make_command(&["FUNCTION", "LIST"]));
+        let RespValue::Array(libraries) = response else {
+            panic!("unexpected reply: {:?}", response);
+        };
+        assert_eq!(libraries.len(), 2);
+        let name = |s: &'static str| RespValue::bulk_string(Bytes::from(s));
+        assert_eq!(
+            libraries[0],
+            RespValue::array(vec![
+                name("library_name"),
+                name("counters"),
+                name("engine"),
+                name("LUA"),
+                name("functions"),
+                RespValue::array(vec![
+                    RespValue::array(vec![
+                        name("name"),
+                        name("bump"),
+                        name("description"),
+                        RespValue::null(),
+                        name("flags"),
+                        RespValue::array(vec![]),
+                    ]),
+                    RespValue::array(vec![
+                        name("name"),
+                        name("peek"),
+                        name("description"),
+                        name("Reads a counter"),
+                        name("flags"),
+                        RespValue::array(vec![RespValue::simple_string("no-writes")]),
+                    ]),
+                    RespValue::array(vec![
+                        name("name"),
+                        name("sneak"),
+                        name("description"),
+                        RespValue::null(),
+                        name("flags"),
+                        RespValue::array(vec![RespValue::simple_string("no-writes")]),
+                    ]),
+                ]),
+            ])
+        );
+
+        let response = handler.execute(make_command(&[
+            "FUNCTION",
+            "LIST",
+            "LIBRARYNAME",
+            "oth*",
+            "WITHCODE",
+        ]));
+        let RespValue::Array(libraries) = response else {
+            panic!("unexpected reply: {:?}", response);
+        };
+        assert_eq!(libraries.len(), 1);
+        let RespValue::Array(fields) = &libraries[0] else {
+            panic!("unexpected library: {:?}", libraries[0]);
+        };
+        assert_eq!(fields[1], name("other"));
+        assert_eq!(fields[6], name("library_code"));
+
+        let response = handler.execute(make_command(&["FUNCTION", "LIST", "BOGUS"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["FUNCTION", "BOGUS"]));
+        assert_eq!(
+            response,
+            RespValue::error("ERR unknown subcommand 'BOGUS'. Try FUNCTION HELP.")
+        );
+    }
+
+    #[test]
+    fn test_function_delete_flush_dump_and_restore() {
+        let handler = create_handler();
+        handler.execute(make_command(&["FUNCTION", "LOAD", LIBRARY]));
+        let payload = match handler.execute(make_command(&["FUNCTION", "DUMP"])) {
+            RespValue::BulkString(payload) => payload,
+            other => panic!("unexpected reply: {:?}", other),
+        };
+
+        let response = handler.execute(make_command(&["FUNCTION", "DELETE", "counters"]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["FUNCTION", "DELETE", "counters"]));
+        assert_eq!(response, RespValue::error("ERR Library not found"));
+        let response = handler.execute(make_command(&["FCALL", "bump", "1", "hits", "1"]));
+        assert_eq!(response, RespValue::error("ERR Function not found"));
+
+        let restore = |policy: &[&str]| {
+            let mut args = vec![
+                RespValue::bulk_string(Bytes::from("FUNCTION")),
+                RespValue::bulk_string(Bytes::from("RESTORE")),
+                RespValue::bulk_string(payload.clone()),
+            ];
+            args.extend(
+                policy
+                    .iter()
+                    .map(|p| RespValue::bulk_string(Bytes::from(p.to_string()))),
+            );
+            handler.execute(RespValue::array(args))
+        };
+        assert_eq!(restore(&[]), RespValue::ok());
+        assert_eq!(
+            restore(&[]),
+            RespValue::error("ERR Library 'counters' already exists")
+        );
+        assert_eq!(restore(&["REPLACE"]), RespValue::ok());
+        assert_eq!(restore(&["FLUSH"]), RespValue::ok());
+        assert!(restore(&["SOMETIMES"]).is_error());
+        let response = handler.execute(make_command(&["FCALL", "bump", "1", "hits", "1"]));
+        assert_eq!(response, RespValue::integer(1));
+
+        let response = handler.execute(make_command(&["FUNCTION", "RESTORE", "garbage"]));
+        assert_eq!(
+            response,
+            RespValue::error("ERR payload version or checksum are wrong")
+        );
+
+        let response = handler.execute(make_command(&["FUNCTION", "FLUSH", "SYNC"]));
+        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["FUNCTION", "LIST"]));
+        assert_eq!(response, RespValue::array(vec![]));
+        let response = handler.execute(make_command(&["FUNCTION", "FLUSH", "LATER"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_functions_survive_a_reload() {
+        let handler = create_handler();
+        let path =
+            std::env::temp_dir().join(format!("flashkv-{}-functions.fkv", std::process::id()));
+        handler.storage.snapshots().set_path(&path);
+        handler.execute(make_command(&["FUNCTION", "LOAD", LIBRARY]));
+        let response = handler.execute(make_command(&["SAVE"]));
+        assert_eq!(response, RespValue::ok());
+
+        // A restarted server loads them with the keyspace
+        let restarted = create_handler();
+        crate::storage::snapshot::load(&restarted.storage, &path).unwrap();
+        let response = restarted.execute(make_command(&["FCALL", "bump", "1", "hits", "3"]));
+        assert_eq!(response, RespValue::integer(3));
+
+        // DEBUG RELOAD saves the libraries with the keyspace and loads them back
+        handler.execute(make_command(&[
+            "FUNCTION",
+            "LOAD",
+            "#!lua name=later\nredis.register_function('noop', function() end)",
+        ]));
+        let response = handler.execute(make_command(&["DEBUG", "RELOAD"]));
+        assert_eq!(response, RespValue::ok());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(handler.storage.functions().len(), 2);
+        let response = handler.execute(make_command(&["FCALL", "bump", "1", "hits", "1"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["FCALL", "noop", "0"]));
+        assert_eq!(response, RespValue::null());
+    }
+
+    #[test]
+    fn test_fcall_propagates_its_writes() {
+        use crate::storage::aof::{self, AppendFsync};
+
+        let handler = create_handler();
+        let path =
+            std::env::temp_dir().join(format!("flashkv-{}-functions.aof", std::process::id()));
+        handler
+            .storage
+            .aof()
+            .open(&path, AppendFsync::Always)
+            .unwrap();
+        handler.execute(make_command(&[
+            "FUNCTION",
+            "LOAD",
+            "#!lua name=stamps
+redis.register_function('stamp', function(keys)
+    local now = redis.call('TIME')
+    redis.call('SET', keys[1], now[1] .. now[2])
+    return redis.call('EXPIRE', keys[1], 100)
+end)",
+        ]));
+        handler.execute(make_command(&["FCALL", "stamp", "1", "at"]));
+        handler.execute(make_command(&["FCALL", "stamp", "1", "at"]));
+
+        let replica = create_handler();
+        aof::load(&replica.storage, &path, false, |command| {
+            replica.execute(RespValue::array(
+                command.into_iter().map(RespValue::bulk_string).collect(),
+            ));
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(replica.storage.functions().len(), 1);
+        for cmd in [vec!["GET", "at"], vec!["PEXPIRETIME", "at"]] {
+            assert_eq!(
+                replica.execute(make_command(&cmd)),
+                handler.execute(make_command(&cmd))
+            );
+        }
+    }
+
+    #[test]
+    fn test_writes_count_as_changes() {
+        let handler = create_handler();
+        let changes = || handler.storage.snapshots().changes();
+
+        handler.execute(make_command(&["SET", "key", "1"]));
+        handler.execute(make_command(&["INCR", "key"]));
+        assert_eq!(changes(), 2);
+
+        // Reads and failed writes don't count
+        handler.execute(make_command(&["GET", "key"]));
+        handler.execute(make_command(&["LPUSH", "key", "a"]));
+        assert_eq!(changes(), 2);
+        assert!(is_write_command("ZADD"));
+        assert!(!is_write_command("ZSCORE"));
+    }
+
+    #[test]
+    fn test_hash_counters() {
+        let handler = create_handler();
+
+        let response = handler.execute(make_command(&["HINCRBY", "user:1", "visits", "5"]));
+        assert_eq!(response, RespValue::integer(5));
+
+        let response = handler.execute(make_command(&["HINCRBYFLOAT", "user:1", "score", "2.5"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("2.5")));
+
+        let response = handler.execute(make_command(&["HSETNX", "user:1", "visits", "0"]));
+        assert_eq!(response, RespValue::integer(0));
+
+        let response = handler.execute(make_command(&["HSETNX", "user:1", "name", "Ariz"]));
+        assert_eq!(response, RespValue::integer(1));
+
+        let response = handler.execute(make_command(&["HGET", "user:1", "visits"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("5")));
+
+        let response = handler.execute(make_command(&["HINCRBY", "user:1", "name", "1"]));
+        assert_eq!(
+            response,
+            RespValue::error("ERR hash value is not an integer")
+        );
+
+        // Hash commands against a string key
+        handler.execute(make_command(&["SET", "plain", "value"]));
+        let response = handler.execute(make_command(&["HINCRBY", "plain", "f", "1"]));
+        assert!(response.is_error());
+    }
+
+    #[test]
+    fn test_hrandfield() {
+        let handler = create_handler();
+
+        let response = handler.execute(make_command(&["HRANDFIELD", "missing"]));
+        assert_eq!(response, RespValue::null());
+
+        handler.execute(make_command(&["HSET", "h", "a", "1", "b", "2"]));
+
+        let response = handler.execute(make_command(&["HRANDFIELD", "h", "5", "WITHVALUES"]));
+        assert_eq!(response.as_array().map(|a| a.len()), Some(4));
+
+        let response = handler.execute(make_command(&["HRANDFIELD", "h", "-3"]));
+        assert_eq!(response.as_array().map(|a| a.len()), Some(3));
+
+        for count in ["-9223372036854775808", "-4611686018427387904"] {
+            let response = handler.execute(make_command(&["HRANDFIELD", "h", count]));
+            assert_eq!(response, RespValue::error("ERR value is out of range"));
+        }
+    }
+
+    #[test]
+    fn test_set_commands() {
+        let handler = create_handler();
+
+        let response = handler.execute(make_command(&["SADD", "tags", "a", "b", "a"]));
+        assert_eq!(response, RespValue::integer(2));
+
+        let response = handler.execute(make_command(&["SCARD", "tags"]));
+        assert_eq!(response, RespValue::integer(2));
+
+        let response = handler.execute(make_command(&["SISMEMBER", "tags", "a"]));
+        assert_eq!(response, RespValue::integer(1));
+
+        let response = handler.execute(make_command(&["SMISMEMBER", "tags", "a", "z"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![RespValue::integer(1), RespValue::integer(0)])
+        );
+
+        let response = handler.execute(make_command(&["SREM", "tags", "a", "z"]));
+        assert_eq!(response, RespValue::integer(1));
+
+        let response = handler.execute(make_command(&["SMEMBERS", "tags"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![RespValue::bulk_string(Bytes::from("b"))])
+        );
+
+        let response = handler.execute(make_command(&["TYPE", "tags"]));
+        assert_eq!(response, RespValue::simple_string("set"));
+    }
+
+    #[test]
+    fn test_set_algebra_commands() {
+        let handler = create_handler();
+
+        handler.execute(make_command(&["SADD", "a", "1", "2", "3"]));
+        handler.execute(make_command(&["SADD", "b", "2", "3", "4"]));
+
+        let response = handler.execute(make_command(&["SDIFF", "a", "b"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![RespValue::bulk_string(Bytes::from("1"))])
+        );
+
+        let response = handler.execute(make_command(&["SUNIONSTORE", "dest", "a", "b"]));
+        assert_eq!(response, RespValue::integer(4));
+
+        let response = handler.execute(make_command(&["SINTERSTORE", "dest", "a", "b"]));
+        assert_eq!(response, RespValue::integer(2));
+        let response = handler.execute(make_command(&["SCARD", "dest"]));
+        assert_eq!(response, RespValue::integer(2));
+
+        let response = handler.execute(make_command(&["SINTERCARD", "2", "a", "b"]));
+        assert_eq!(response, RespValue::integer(2));
+
+        let response = handler.execute(make_command(&["SINTERCARD", "2", "a", "b", "LIMIT", "1"]));
+        assert_eq!(response, RespValue::integer(1));
+
+        let response = handler.execute(make_command(&["SINTERCARD", "3", "a", "b"]));
+        assert!(response.is_error());
+
+        handler.execute(make_command(&["SET", "str", "value"]));
+        let response = handler.execute(make_command(&["SINTER", "a", "str"]));
+        assert!(response.is_error());
     }
 
     #[test]
-    fn test_ping() {
+    fn test_set_wrongtype() {
         let handler = create_handler();
 
-        let response = handler.execute(make_command(&["PING"]));
-        assert_eq!(response, RespValue::simple_string("PONG"));
+        handler.execute(make_command(&["SET", "str", "value"]));
+        handler.execute(make_command(&["RPUSH", "list", "a"]));
+        handler.execute(make_command(&["SADD", "set", "a"]));
 
-        let response = handler.execute(make_command(&["PING", "hello"]));
-        assert_eq!(response, RespValue::bulk_string(Bytes::from("hello")));
+        for key in ["str", "list"] {
+            let response = handler.execute(make_command(&["SADD", key, "m"]));
+            assert!(response.is_error());
+            let response = handler.execute(make_command(&["SMEMBERS", key]));
+            assert!(response.is_error());
+        }
+
+        // List commands reject set keys as well
+        let response = handler.execute(make_command(&["LPUSH", "set", "x"]));
+        assert!(response.is_error());
     }
 
     #[test]
-    fn test_set_get() {
+    fn test_sorted_set_commands() {
         let handler = create_handler();
 
-        let response = handler.execute(make_command(&["SET", "key", "value"]));
-        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&[
+            "ZADD", "board", "100", "alice", "50", "bob", "75", "carol",
+        ]));
+        assert_eq!(response, RespValue::integer(3));
 
-        let response = handler.execute(make_command(&["GET", "key"]));
-        assert_eq!(response, RespValue::bulk_string(Bytes::from("value")));
-    }
+        let response = handler.execute(make_command(&["ZRANGE", "board", "0", "-1"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("bob")),
+                RespValue::bulk_string(Bytes::from("carol")),
+                RespValue::bulk_string(Bytes::from("alice")),
+            ])
+        );
 
-    #[test]
-    fn test_get_nonexistent() {
-        let handler = create_handler();
+        let response = handler.execute(make_command(&[
+            "ZRANGE",
+            "board",
+            "0",
+            "0",
+            "REV",
+            "WITHSCORES",
+        ]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("alice")),
+                RespValue::bulk_string(Bytes::from("100")),
+            ])
+        );
 
-        let response = handler.execute(make_command(&["GET", "nonexistent"]));
+        let response = handler.execute(make_command(&["ZREVRANK", "board", "bob"]));
+        assert_eq!(response, RespValue::integer(2));
+
+        // GT only raises scores; CH counts the change
+        let response = handler.execute(make_command(&[
+            "ZADD", "board", "GT", "CH", "40", "bob", "60", "carol",
+        ]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["ZADD", "board", "GT", "CH", "80", "carol"]));
+        assert_eq!(response, RespValue::integer(1));
+
+        let response = handler.execute(make_command(&["ZADD", "board", "INCR", "2.5", "bob"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("52.5")));
+
+        let response = handler.execute(make_command(&["ZADD", "board", "NX", "INCR", "1", "bob"]));
         assert_eq!(response, RespValue::null());
-    }
 
-    #[test]
-    fn test_del() {
-        let handler = create_handler();
+        let response = handler.execute(make_command(&["ZSCORE", "board", "carol"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("80")));
 
-        handler.execute(make_command(&["SET", "key1", "value1"]));
-        handler.execute(make_command(&["SET", "key2", "value2"]));
+        let response = handler.execute(make_command(&["ZREM", "board", "bob", "nobody"]));
+        assert_eq!(response, RespValue::integer(1));
 
-        let response = handler.execute(make_command(&["DEL", "key1", "key2", "key3"]));
+        let response = handler.execute(make_command(&["ZCARD", "board"]));
         assert_eq!(response, RespValue::integer(2));
+
+        let response = handler.execute(make_command(&["ZADD", "board", "NX", "XX", "1", "x"]));
+        assert!(response.is_error());
+
+        let response = handler.execute(make_command(&["ZADD", "board", "abc", "x"]));
+        assert!(response.is_error());
     }
 
     #[test]
-    fn test_exists() {
+    fn test_sorted_set_pop_commands() {
         let handler = create_handler();
 
-        handler.execute(make_command(&["SET", "key1", "value1"]));
+        let response = handler.execute(make_command(&["ZINCRBY", "board", "5", "alice"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("5")));
+        let response = handler.execute(make_command(&["ZINCRBY", "board", "-1.5", "alice"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("3.5")));
 
-        let response = handler.execute(make_command(&["EXISTS", "key1"]));
-        assert_eq!(response, RespValue::integer(1));
+        handler.execute(make_command(&["ZADD", "board", "1", "bob", "9", "carol"]));
 
-        let response = handler.execute(make_command(&["EXISTS", "nonexistent"]));
-        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["ZPOPMAX", "board"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("carol")),
+                RespValue::bulk_string(Bytes::from("9")),
+            ])
+        );
+
+        let response = handler.execute(make_command(&["ZRANDMEMBER", "board", "-3"]));
+        assert_eq!(response.as_array().map(|a| a.len()), Some(3));
+
+        let response = handler.execute(make_command(&["ZRANDMEMBER", "board", "5", "WITHSCORES"]));
+        assert_eq!(response.as_array().map(|a| a.len()), Some(4));
+
+        let response = handler.execute(make_command(&[
+            "ZRANDMEMBER",
+            "board",
+            "-9223372036854775808",
+            "WITHSCORES",
+        ]));
+        assert_eq!(response, RespValue::error("ERR value is out of range"));
+
+        let response = handler.execute(make_command(&["ZPOPMIN", "board", "-1"]));
+        assert!(response.is_error());
+
+        // Executed directly, blocking pops never block
+        let response = handler.execute(make_command(&["BZPOPMIN", "missing", "board", "0"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("board")),
+                RespValue::bulk_string(Bytes::from("bob")),
+                RespValue::bulk_string(Bytes::from("1")),
+            ])
+        );
+
+        let response = handler.execute(make_command(&["ZPOPMIN", "board", "10"]));
+        assert_eq!(response.as_array().map(|a| a.len()), Some(2));
+
+        let response = handler.execute(make_command(&["BZPOPMAX", "board", "0"]));
+        assert!(response.is_null());
+
+        let response = handler.execute(make_command(&["BZPOPMAX", "board", "-1"]));
+        assert!(response.is_error());
+        for timeout in ["1e300", "1e19"] {
+            let response = handler.execute(make_command(&["BZPOPMIN", "board", timeout]));
+            assert_eq!(response, RespValue::error("ERR timeout is out of range"));
+        }
     }
 
     #[test]
-    fn test_incr_decr() {
+    fn test_sorted_set_store_commands() {
         let handler = create_handler();
 
-        let response = handler.execute(make_command(&["INCR", "counter"]));
+        handler.execute(make_command(&["ZADD", "z1", "1", "a", "2", "b"]));
+        handler.execute(make_command(&["ZADD", "z2", "3", "b", "4", "c"]));
+
+        let response = handler.execute(make_command(&[
+            "ZUNIONSTORE",
+            "out",
+            "2",
+            "z1",
+            "z2",
+            "WEIGHTS",
+            "1",
+            "10",
+        ]));
+        assert_eq!(response, RespValue::integer(3));
+        let response = handler.execute(make_command(&["ZSCORE", "out", "b"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("32")));
+
+        let response = handler.execute(make_command(&[
+            "ZINTERSTORE",
+            "out",
+            "2",
+            "z1",
+            "z2",
+            "AGGREGATE",
+            "MIN",
+        ]));
         assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["ZRANGE", "out", "0", "-1", "WITHSCORES"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("b")),
+                RespValue::bulk_string(Bytes::from("2")),
+            ])
+        );
 
-        let response = handler.execute(make_command(&["INCR", "counter"]));
-        assert_eq!(response, RespValue::integer(2));
-
-        let response = handler.execute(make_command(&["DECR", "counter"]));
+        let response = handler.execute(make_command(&["ZDIFFSTORE", "out", "2", "z1", "z2"]));
         assert_eq!(response, RespValue::integer(1));
+        assert_eq!(
+            handler.execute(make_command(&["ZCARD", "out"])),
+            RespValue::integer(1)
+        );
 
-        let response = handler.execute(make_command(&["INCRBY", "counter", "10"]));
-        assert_eq!(response, RespValue::integer(11));
+        // ZDIFFSTORE takes no WEIGHTS; bad numkeys and wrong types are rejected
+        let response = handler.execute(make_command(&[
+            "ZDIFFSTORE",
+            "out",
+            "1",
+            "z1",
+            "WEIGHTS",
+            "2",
+        ]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["ZUNIONSTORE", "out", "0", "z1"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["ZUNIONSTORE", "out", "3", "z1", "z2"]));
+        assert!(response.is_error());
+        handler.execute(make_command(&["SET", "str", "v"]));
+        let response = handler.execute(make_command(&["ZUNIONSTORE", "out", "1", "str"]));
+        assert!(response.is_error());
     }
 
     #[test]
-    fn test_mset_mget() {
+    fn test_stream_commands() {
         let handler = create_handler();
 
-        let response = handler.execute(make_command(&["MSET", "k1", "v1", "k2", "v2"]));
-        assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["XADD", "events", "1-1", "type", "click"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("1-1")));
+        let response = handler.execute(make_command(&["XADD", "events", "1-*", "type", "view"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("1-2")));
+        let response = handler.execute(make_command(&["XADD", "events", "2-0", "type", "buy"]));
+        assert_eq!(response, RespValue::bulk_string(Bytes::from("2-0")));
 
-        let response = handler.execute(make_command(&["MGET", "k1", "k2", "k3"]));
+        let response = handler.execute(make_command(&["XADD", "events", "1-5", "a", "b"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["XADD", "events", "*", "dangling"]));
+        assert!(response.is_error());
+
+        assert_eq!(
+            handler.execute(make_command(&["XLEN", "events"])),
+            RespValue::integer(3)
+        );
+        assert_eq!(
+            handler.execute(make_command(&["TYPE", "events"])),
+            RespValue::simple_string("stream")
+        );
+
+        // Incomplete IDs cover the whole millisecond
+        let response = handler.execute(make_command(&["XRANGE", "events", "1", "1"]));
+        assert_eq!(response.as_array().map(|a| a.len()), Some(2));
+
+        let response = handler.execute(make_command(&[
+            "XRANGE", "events", "(1-1", "+", "COUNT", "1",
+        ]));
         assert_eq!(
             response,
-            RespValue::Array(vec![
-                RespValue::bulk_string(Bytes::from("v1")),
-                RespValue::bulk_string(Bytes::from("v2")),
-                RespValue::null(),
-            ])
+            RespValue::array(vec![RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("1-2")),
+                RespValue::array(vec![
+                    RespValue::bulk_string(Bytes::from("type")),
+                    RespValue::bulk_string(Bytes::from("view")),
+                ]),
+            ])])
+        );
+
+        let response = handler.execute(make_command(&[
+            "XREVRANGE",
+            "events",
+            "+",
+            "-",
+            "COUNT",
+            "1",
+        ]));
+        let first = &response.as_array().unwrap()[0].as_array().unwrap()[0];
+        assert_eq!(first, &RespValue::bulk_string(Bytes::from("2-0")));
+
+        // MAXLEN trims the oldest entries
+        handler.execute(make_command(&[
+            "XADD", "events", "MAXLEN", "~", "2", "3-0", "k", "v",
+        ]));
+        assert_eq!(
+            handler.execute(make_command(&["XLEN", "events"])),
+            RespValue::integer(2)
+        );
+
+        let response = handler.execute(make_command(&["XADD", "new", "NOMKSTREAM", "*", "k", "v"]));
+        assert!(response.is_null());
+
+        let response = handler.execute(make_command(&[
+            "XREAD", "COUNT", "5", "STREAMS", "events", "2-0",
+        ]));
+        let streams = response.as_array().unwrap();
+        assert_eq!(streams.len(), 1);
+        assert_eq!(
+            streams[0].as_array().unwrap()[0],
+            RespValue::bulk_string(Bytes::from("events"))
         );
+
+        let response = handler.execute(make_command(&["XREAD", "STREAMS", "events", "$"]));
+        assert!(response.is_null());
+
+        let response = handler.execute(make_command(&["XREAD", "STREAMS", "events"]));
+        assert!(response.is_error());
     }
 
     #[test]
-    fn test_set_with_options() {
+    fn test_stream_consumer_group_commands() {
         let handler = create_handler();
 
-        // SET with NX
-        let response = handler.execute(make_command(&["SET", "key", "value", "NX"]));
+        let response = handler.execute(make_command(&["XGROUP", "CREATE", "jobs", "g", "$"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&[
+            "XGROUP", "CREATE", "jobs", "g", "0", "MKSTREAM",
+        ]));
         assert_eq!(response, RespValue::ok());
+        let response = handler.execute(make_command(&["XGROUP", "CREATE", "jobs", "g", "0"]));
+        assert_eq!(
+            response,
+            RespValue::error("BUSYGROUP Consumer Group name already exists")
+        );
 
-        // SET with NX on existing key should return nil
-        let response = handler.execute(make_command(&["SET", "key", "newvalue", "NX"]));
-        assert_eq!(response, RespValue::null());
+        handler.execute(make_command(&["XADD", "jobs", "1-0", "task", "a"]));
+        handler.execute(make_command(&["XADD", "jobs", "2-0", "task", "b"]));
+
+        let response = handler.execute(make_command(&[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "alice",
+            "COUNT",
+            "1",
+            "STREAMS",
+            "jobs",
+            ">",
+        ]));
+        let streams = response.as_array().unwrap();
+        let entries = streams[0].as_array().unwrap()[1].as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries[0].as_array().unwrap()[0],
+            RespValue::bulk_string(Bytes::from("1-0"))
+        );
 
-        // SET with XX on existing key
-        let response = handler.execute(make_command(&["SET", "key", "newvalue", "XX"]));
-        assert_eq!(response, RespValue::ok());
+        handler.execute(make_command(&[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "bob",
+            "STREAMS",
+            "jobs",
+            ">",
+        ]));
+
+        // Nothing new left
+        let response = handler.execute(make_command(&[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "bob",
+            "STREAMS",
+            "jobs",
+            ">",
+        ]));
+        assert!(response.is_null());
+
+        let response = handler.execute(make_command(&["XPENDING", "jobs", "g"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::integer(2),
+                RespValue::bulk_string(Bytes::from("1-0")),
+                RespValue::bulk_string(Bytes::from("2-0")),
+                RespValue::array(vec![
+                    RespValue::array(vec![
+                        RespValue::bulk_string(Bytes::from("alice")),
+                        RespValue::bulk_string(Bytes::from("1")),
+                    ]),
+                    RespValue::array(vec![
+                        RespValue::bulk_string(Bytes::from("bob")),
+                        RespValue::bulk_string(Bytes::from("1")),
+                    ]),
+                ]),
+            ])
+        );
 
-        // Verify value changed
-        let response = handler.execute(make_command(&["GET", "key"]));
-        assert_eq!(response, RespValue::bulk_string(Bytes::from("newvalue")));
+        let response = handler.execute(make_command(&[
+            "XCLAIM", "jobs", "g", "bob", "0", "1-0", "JUSTID",
+        ]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![RespValue::bulk_string(Bytes::from("1-0"))])
+        );
+
+        let response = handler.execute(make_command(&[
+            "XPENDING", "jobs", "g", "-", "+", "10", "bob",
+        ]));
+        assert_eq!(response.as_array().map(|a| a.len()), Some(2));
+
+        let response = handler.execute(make_command(&["XACK", "jobs", "g", "1-0", "2-0", "3-0"]));
+        assert_eq!(response, RespValue::integer(2));
+
+        let response = handler.execute(make_command(&["XPENDING", "jobs", "g"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::integer(0),
+                RespValue::null(),
+                RespValue::null(),
+                RespValue::null(),
+            ])
+        );
+
+        let response = handler.execute(make_command(&[
+            "XREADGROUP",
+            "GROUP",
+            "missing",
+            "alice",
+            "STREAMS",
+            "jobs",
+            ">",
+        ]));
+        assert!(matches!(response, RespValue::Error(ref e) if e.starts_with("NOGROUP")));
+
+        let response = handler.execute(make_command(&[
+            "XGROUP",
+            "DELCONSUMER",
+            "jobs",
+            "g",
+            "alice",
+        ]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["XGROUP", "DESTROY", "jobs", "g"]));
+        assert_eq!(response, RespValue::integer(1));
     }
 
     #[test]
-    fn test_append() {
+    fn test_bitmap_commands() {
         let handler = create_handler();
 
-        let response = handler.execute(make_command(&["APPEND", "key", "Hello"]));
-        assert_eq!(response, RespValue::integer(5));
+        let response = handler.execute(make_command(&["SETBIT", "dau", "7", "1"]));
+        assert_eq!(response, RespValue::integer(0));
+        let response = handler.execute(make_command(&["SETBIT", "dau", "7", "1"]));
+        assert_eq!(response, RespValue::integer(1));
+        handler.execute(make_command(&["SETBIT", "dau", "100", "1"]));
 
-        let response = handler.execute(make_command(&["APPEND", "key", " World"]));
-        assert_eq!(response, RespValue::integer(11));
+        assert_eq!(
+            handler.execute(make_command(&["GETBIT", "dau", "100"])),
+            RespValue::integer(1)
+        );
+        assert_eq!(
+            handler.execute(make_command(&["GETBIT", "dau", "99"])),
+            RespValue::integer(0)
+        );
+        assert_eq!(
+            handler.execute(make_command(&["STRLEN", "dau"])),
+            RespValue::integer(13)
+        );
 
-        let response = handler.execute(make_command(&["GET", "key"]));
-        assert_eq!(response, RespValue::bulk_string(Bytes::from("Hello World")));
+        handler.execute(make_command(&["SET", "word", "foobar"]));
+        assert_eq!(
+            handler.execute(make_command(&["BITCOUNT", "word"])),
+            RespValue::integer(26)
+        );
+        assert_eq!(
+            handler.execute(make_command(&["BITCOUNT", "word", "1", "1"])),
+            RespValue::integer(6)
+        );
+        assert_eq!(
+            handler.execute(make_command(&["BITCOUNT", "word", "5", "30", "BIT"])),
+            RespValue::integer(17)
+        );
+
+        assert_eq!(
+            handler.execute(make_command(&["BITPOS", "dau", "1"])),
+            RespValue::integer(7)
+        );
+        assert_eq!(
+            handler.execute(make_command(&["BITPOS", "dau", "1", "1"])),
+            RespValue::integer(100)
+        );
+        assert_eq!(
+            handler.execute(make_command(&["BITPOS", "dau", "1", "8", "99", "BIT"])),
+            RespValue::integer(-1)
+        );
+        assert_eq!(
+            handler.execute(make_command(&["BITPOS", "none", "0"])),
+            RespValue::integer(0)
+        );
+
+        assert!(handler
+            .execute(make_command(&["SETBIT", "dau", "-1", "1"]))
+            .is_error());
+        assert!(handler
+            .execute(make_command(&["SETBIT", "dau", "1", "2"]))
+            .is_error());
+        assert!(handler
+            .execute(make_command(&["BITPOS", "dau", "2"]))
+            .is_error());
+        assert!(handler
+            .execute(make_command(&["BITCOUNT", "dau", "0"]))
+            .is_error());
+
+        handler.execute(make_command(&["SADD", "set", "m"]));
+        assert!(handler
+            .execute(make_command(&["GETBIT", "set", "0"]))
+            .is_error());
     }
 
     #[test]
-    fn test_dbsize() {
+    fn test_hyperloglog_commands() {
         let handler = create_handler();
 
-        let response = handler.execute(make_command(&["DBSIZE"]));
+        let response = handler.execute(make_command(&["PFADD", "hll", "a", "b", "c", "d"]));
+        assert_eq!(response, RespValue::integer(1));
+        let response = handler.execute(make_command(&["PFADD", "hll", "a"]));
         assert_eq!(response, RespValue::integer(0));
+        assert_eq!(
+            handler.execute(make_command(&["PFCOUNT", "hll"])),
+            RespValue::integer(4)
+        );
 
-        handler.execute(make_command(&["SET", "key1", "value1"]));
-        handler.execute(make_command(&["SET", "key2", "value2"]));
+        // PFADD with no elements just creates the key
+        assert_eq!(
+            handler.execute(make_command(&["PFADD", "empty"])),
+            RespValue::integer(1)
+        );
+        assert_eq!(
+            handler.execute(make_command(&["TYPE", "empty"])),
+            RespValue::simple_string("string")
+        );
 
-        let response = handler.execute(make_command(&["DBSIZE"]));
-        assert_eq!(response, RespValue::integer(2));
+        handler.execute(make_command(&["PFADD", "other", "d", "e"]));
+        assert_eq!(
+            handler.execute(make_command(&["PFCOUNT", "hll", "other", "missing"])),
+            RespValue::integer(5)
+        );
+        assert_eq!(
+            handler.execute(make_command(&["PFMERGE", "all", "hll", "other"])),
+            RespValue::ok()
+        );
+        assert_eq!(
+            handler.execute(make_command(&["PFCOUNT", "all"])),
+            RespValue::integer(5)
+        );
+
+        // HyperLogLogs are plain strings and survive a GET/SET round trip
+        let value = handler.execute(make_command(&["GET", "all"]));
+        let value = match value {
+            RespValue::BulkString(v) => v,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        handler.execute(RespValue::Array(vec![
+            RespValue::bulk_string(Bytes::from("SET")),
+            RespValue::bulk_string(Bytes::from("copy")),
+            RespValue::bulk_string(value),
+        ]));
+        assert_eq!(
+            handler.execute(make_command(&["PFCOUNT", "copy"])),
+            RespValue::integer(5)
+        );
+
+        handler.execute(make_command(&["SET", "plain", "hello"]));
+        assert_eq!(
+            handler.execute(make_command(&["PFADD", "plain", "x"])),
+            RespValue::error("WRONGTYPE Key is not a valid HyperLogLog string value.")
+        );
+        handler.execute(make_command(&["SADD", "set", "m"]));
+        assert!(handler
+            .execute(make_command(&["PFCOUNT", "set"]))
+            .is_error());
+        assert!(handler.execute(make_command(&["PFCOUNT"])).is_error());
     }
 
     #[test]
-    fn test_flushdb() {
+    fn test_execute_or_block() {
         let handler = create_handler();
 
-        handler.execute(make_command(&["SET", "key1", "value1"]));
-        handler.execute(make_command(&["SET", "key2", "value2"]));
+        let outcome = handler.execute_or_block(make_command(&["BZPOPMIN", "a", "b", "1.5"]));
+        let request = match outcome {
+            CommandOutcome::Block(request) => request,
+            other => panic!("expected to block, got {:?}", other),
+        };
+        assert_eq!(request.keys, vec![Bytes::from("a"), Bytes::from("b")]);
+        assert_eq!(request.timeout, Some(Duration::from_millis(1500)));
+        assert!(handler.try_serve(&request).is_none());
+
+        handler.execute(make_command(&["ZADD", "b", "2", "x"]));
+        assert!(handler.try_serve(&request).is_some());
+
+        let outcome = handler.execute_or_block(make_command(&["PING"]));
+        assert!(matches!(
+            outcome,
+            CommandOutcome::Reply(RespValue::SimpleString(_))
+        ));
+    }
 
-        let response = handler.execute(make_command(&["FLUSHDB"]));
-        assert_eq!(response, RespValue::ok());
+    #[test]
+    fn test_blpop_brpop() {
+        let handler = create_handler();
+        handler.execute(make_command(&["RPUSH", "b", "x", "y"]));
 
-        let response = handler.execute(make_command(&["DBSIZE"]));
-        assert_eq!(response, RespValue::integer(0));
+        // Executed directly, keys are tried in order and nothing blocks
+        let response = handler.execute(make_command(&["BLPOP", "a", "b", "0"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("b")),
+                RespValue::bulk_string(Bytes::from("x")),
+            ])
+        );
+        let response = handler.execute(make_command(&["BRPOP", "b", "0"]));
+        assert_eq!(
+            response,
+            RespValue::array(vec![
+                RespValue::bulk_string(Bytes::from("b")),
+                RespValue::bulk_string(Bytes::from("y")),
+            ])
+        );
+        let response = handler.execute(make_command(&["BLPOP", "b", "0"]));
+        assert_eq!(response, RespValue::null_array());
+
+        let outcome = handler.execute_or_block(make_command(&["BRPOP", "a", "0"]));
+        let request = match outcome {
+            CommandOutcome::Block(request) => request,
+            other => panic!("expected to block, got {:?}", other),
+        };
+        assert_eq!(request.timeout, None);
+        handler.execute(make_command(&["LPUSH", "a", "z"]));
+        assert!(handler.try_serve(&request).is_some());
+
+        handler.execute(make_command(&["SET", "s", "v"]));
+        let response = handler.execute(make_command(&["BLPOP", "s", "0"]));
+        assert!(response.is_error());
+        let response = handler.execute(make_command(&["BLPOP", "a", "-1"]));
+        assert!(response.is_error());
+
+        // A timeout too large for a deadline is refused, not a crash
+        for cmd in ["BLPOP", "BRPOP"] {
+            for timeout in ["1e300", "1e19"] {
+                let response = handler.execute_or_block(make_command(&[cmd, "a", timeout]));
+                assert!(
+                    matches!(
+                        &response,
+                        CommandOutcome::Reply(RespValue::Error(e)) if e == "ERR timeout is out of range"
+                    ),
+                    "{} {}: {:?}",
+                    cmd,
+                    timeout,
+                    response
+                );
+            }
+        }
+        let outcome = handler.execute_or_block(make_command(&["BLPOP", "a", "1e9"]));
+        assert!(matches!(
+            outcome,
+            CommandOutcome::Block(BlockingRequest {
+                timeout: Some(_),
+                ..
+            })
+        ));
     }
 
     #[test]
@@ -1523,6 +10624,59 @@ mod tests {
         let handler = create_handler();
 
         let response = handler.execute(make_command(&["UNKNOWN"]));
-        assert!(matches!(response, RespValue::Error(_)));
+        assert_eq!(response, RespValue::error("ERR unknown command 'UNKNOWN'"));
+
+        let response = handler.execute(make_command(&["unknown"]));
+        assert_eq!(response, RespValue::error("ERR unknown command 'UNKNOWN'"));
+    }
+
+    #[test]
+    fn test_command_lookup() {
+        assert!(
+            COMMAND_NAMES.windows(2).all(|pair| pair[0] < pair[1]),
+            "the command table must be sorted and free of duplicates"
+        );
+        assert!(COMMAND_NAMES
+            .iter()
+            .all(|name| name.bytes().all(|b| !b.is_ascii_lowercase())));
+        assert!(WRITE_COMMANDS
+            .iter()
+            .all(|name| lookup_command(name.as_bytes()) == Some(*name)));
+        assert!(SHRINKING_COMMANDS.iter().all(|name| is_write_command(name)));
+
+        assert_eq!(lookup_command(b"get"), Some("GET"));
+        assert_eq!(lookup_command(b"ZuNiOnStOrE"), Some("ZUNIONSTORE"));
+        assert_eq!(lookup_command(b"GE"), None);
+        assert_eq!(lookup_command(b"GETX"), None);
+        assert_eq!(lookup_command(b""), None);
+        assert_eq!(lookup_command(b"\xff"), None);
+
+        // Commands run whatever their case
+        let handler = create_handler();
+        handler.execute(make_command(&["sEt", "key", "value"]));
+        assert_eq!(
+            handler.execute(make_command(&["get", "key"])),
+            RespValue::bulk_string(Bytes::from("value"))
+        );
+        assert_eq!(handler.session().last_command(), Some("get".to_string()));
+    }
+
+    #[test]
+    fn test_command_count() {
+        let handler = create_handler();
+        assert_eq!(
+            handler.execute(make_command(&["COMMAND", "count"])),
+            RespValue::integer(COMMAND_NAMES.len() as i64)
+        );
+        match handler.execute(make_command(&["COMMAND"])) {
+            RespValue::Array(names) => assert_eq!(names.len(), COMMAND_NAMES.len()),
+            other => panic!("expected an array, got {:?}", other),
+        }
+        assert!(handler
+            .execute(make_command(&["COMMAND", "COUNT", "x"]))
+            .is_error());
+        assert!(handler
+            .execute(make_command(&["COMMAND", "NOPE"]))
+            .is_error());
     }
 }
@@ -18,7 +18,7 @@
 //! ┌─────────────────┐
 //! │ CommandHandler  │  (this module)
 //! │                 │
-//! │  - Dispatch     │
+//! │  - Dispatch     │──── Session (per-client state)
 //! │  - Validate     │
 //! │  - Execute      │
 //! └────────┬────────┘
@@ -48,7 +48,14 @@
 //! - `DBSIZE`, `FLUSHDB`, `FLUSHALL`
 //! - `COMMAND`, `CONFIG`, `TIME`
 
+pub mod clients;
 pub mod handler;
+pub mod rename;
+pub mod session;
 
 // Re-export the main command handler
-pub use handler::CommandHandler;
+pub use handler::{
+    is_write_command, BlockingOp, BlockingRequest, CommandHandler, CommandOutcome, SyncRequest,
+};
+pub use rename::CommandRenames;
+pub use session::Session;
@@ -0,0 +1,89 @@
+//! Command Renaming
+//!
+//! Operators can hide dangerous commands from clients: `--rename-command
+//! "KEYS obscure-keys"` makes `KEYS` unknown and `OBSCURE-KEYS` run it
+//! instead, and `--disabled-commands "FLUSHALL DEBUG"` (or renaming to an
+//! empty name) makes commands unknown altogether.
+//!
+//! Only what clients send is translated: commands replayed from the
+//! append-only file or streamed from a master run under their real names,
+//! and writes are logged and propagated under their real names too.
+
+use std::collections::{HashMap, HashSet};
+
+/// The commands clients know under another name, or not at all.
+#[derive(Debug, Clone, Default)]
+pub struct CommandRenames {
+    /// Real name of each new name, all upper-cased
+    aliases: HashMap<String, String>,
+    /// Commands clients can't call by their real name
+    hidden: HashSet<String>,
+}
+
+impl CommandRenames {
+    /// Creates a table where every command keeps its name.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `command` callable only as `new_name`, or not at all if
+    /// `new_name` is empty. Names are case-insensitive.
+    pub fn rename(&mut self, command: &str, new_name: &str) {
+        let command = command.to_uppercase();
+        self.aliases.retain(|_, real| *real != command);
+        if !new_name.is_empty() {
+            self.aliases
+                .insert(new_name.to_uppercase(), command.clone());
+        }
+        self.hidden.insert(command);
+    }
+
+    /// Makes `command` unknown to clients.
+    pub fn disable(&mut self, command: &str) {
+        self.rename(command, "");
+    }
+
+    /// Returns whether no command is renamed or disabled.
+    pub fn is_empty(&self) -> bool {
+        self.hidden.is_empty()
+    }
+
+    /// Returns the real name of the command a client sent as `name`
+    /// (upper-cased), or `None` if it was renamed away or disabled.
+    pub fn resolve<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        if let Some(real) = self.aliases.get(name) {
+            return Some(real);
+        }
+        (!self.hidden.contains(name)).then_some(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rename_and_disable() {
+        let mut renames = CommandRenames::new();
+        assert!(renames.is_empty());
+        assert_eq!(renames.resolve("KEYS"), Some("KEYS"));
+
+        renames.rename("keys", "obscure-keys");
+        renames.disable("FLUSHALL");
+        assert_eq!(renames.resolve("KEYS"), None);
+        assert_eq!(renames.resolve("OBSCURE-KEYS"), Some("KEYS"));
+        assert_eq!(renames.resolve("FLUSHALL"), None);
+        assert_eq!(renames.resolve("GET"), Some("GET"));
+
+        // Renaming again replaces the previous name
+        renames.rename("KEYS", "k2");
+        assert_eq!(renames.resolve("OBSCURE-KEYS"), Some("OBSCURE-KEYS"));
+        assert_eq!(renames.resolve("K2"), Some("KEYS"));
+
+        // Two commands can swap names
+        renames.rename("GET", "SET");
+        renames.rename("SET", "GET");
+        assert_eq!(renames.resolve("GET"), Some("SET"));
+        assert_eq!(renames.resolve("SET"), Some("GET"));
+    }
+}
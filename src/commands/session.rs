@@ -0,0 +1,442 @@
+//! Client Sessions
+//!
+//! Everything the server remembers about one connected client between its
+//! commands lives in its [`Session`]: who it is, which protocol it speaks,
+//! what it subscribed to, and so on. Each connection's
+//! [`CommandHandler`](super::CommandHandler) owns one, and clones of the
+//! handler share it.
+//!
+//! ```text
+//! connection ──> CommandHandler ──> Session { id, name, user, db,
+//!                     │                       protocol, subscriber,
+//!                     │                       tracking, MULTI queue, ... }
+//!                     ▼
+//!               StorageEngine (shared by every connection)
+//! ```
+
+use crate::protocol::RespValue;
+use crate::pubsub::Subscriber;
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+use tokio::sync::Notify;
+
+/// ID given to the next client.
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The user clients are authenticated as until they log in as another.
+pub const DEFAULT_USER: &str = "default";
+
+/// The state of one client's connection.
+#[derive(Debug)]
+pub struct Session {
+    /// ID of the client, unique for the server's lifetime
+    id: u64,
+    /// Address the client connected from and the local address it
+    /// connected to, unless it isn't a network client
+    addr: OnceLock<(SocketAddr, SocketAddr)>,
+    /// Whether `CLIENT KILL` asked for the client to be disconnected
+    killed: AtomicBool,
+    /// Wakes the connection when the client is killed
+    kill_notify: Notify,
+    /// When the session was created
+    created: Instant,
+    /// The last command run, as named in the command table, and when it
+    /// was received
+    last_command: Mutex<(Option<&'static str>, Instant)>,
+    /// Name set with `CLIENT SETNAME`
+    name: Mutex<Option<Bytes>>,
+    /// Client library name and version set with `CLIENT SETINFO`
+    lib: Mutex<(Option<Bytes>, Option<Bytes>)>,
+    /// User the client is authenticated as
+    user: Mutex<Bytes>,
+    /// Index of the selected database
+    db: AtomicUsize,
+    /// RESP version spoken, 2 or 3 (`HELLO`)
+    protocol: AtomicU8,
+    /// Commands queued since MULTI, if a transaction is open
+    multi: Mutex<Option<Vec<RespValue>>>,
+    /// The client's pub/sub subscriptions and message queue
+    subscriber: Subscriber,
+    /// Whether the client turned on `CLIENT TRACKING`
+    tracking: AtomicBool,
+    /// Whether the client turned on `CLIENT NO-TOUCH`
+    no_touch: AtomicBool,
+    /// Whether the client turned on `CLIENT NO-EVICT`
+    no_evict: AtomicBool,
+    /// Whether the client ran MONITOR
+    monitor: AtomicBool,
+    /// Whether the client sent ASKING, letting its next command use a slot
+    /// this node is importing
+    asking: AtomicBool,
+    /// Port the client announced with `REPLCONF listening-port`, or 0
+    listening_port: AtomicU16,
+    /// Log position the last write must reach on disk before it is
+    /// acknowledged (group commit), or 0
+    commit_position: AtomicU64,
+}
+
+impl Session {
+    /// Creates the session of a newly connected client, with the next ID.
+    pub fn new() -> Self {
+        let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        let created = Instant::now();
+        Self {
+            id,
+            addr: OnceLock::new(),
+            killed: AtomicBool::new(false),
+            kill_notify: Notify::new(),
+            created,
+            last_command: Mutex::new((None, created)),
+            name: Mutex::new(None),
+            lib: Mutex::new((None, None)),
+            user: Mutex::new(Bytes::from_static(DEFAULT_USER.as_bytes())),
+            db: AtomicUsize::new(0),
+            protocol: AtomicU8::new(2),
+            multi: Mutex::new(None),
+            subscriber: Subscriber::new(id),
+            tracking: AtomicBool::new(false),
+            no_touch: AtomicBool::new(false),
+            no_evict: AtomicBool::new(false),
+            monitor: AtomicBool::new(false),
+            asking: AtomicBool::new(false),
+            listening_port: AtomicU16::new(0),
+            commit_position: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the ID of the client.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the address the client connected from, if it is a network
+    /// client.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        self.addr.get().map(|(addr, _)| *addr)
+    }
+
+    /// Returns the local address the client connected to, if it is a
+    /// network client.
+    pub fn laddr(&self) -> Option<SocketAddr> {
+        self.addr.get().map(|(_, laddr)| *laddr)
+    }
+
+    /// Records the address the client connected from, and the local one it
+    /// connected to. Only the first call has any effect.
+    pub fn set_addr(&self, addr: SocketAddr, laddr: SocketAddr) {
+        let _ = self.addr.set((addr, laddr));
+    }
+
+    /// Asks for the client to be disconnected (`CLIENT KILL`).
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::Relaxed);
+        self.kill_notify.notify_one();
+    }
+
+    /// Returns whether the client was killed.
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Relaxed)
+    }
+
+    /// Waits until the client is killed.
+    pub async fn wait_killed(&self) {
+        while !self.is_killed() {
+            self.kill_notify.notified().await;
+        }
+    }
+
+    /// Records that the client sent `cmd` (upper-cased) just now.
+    pub fn record_command(&self, cmd: &'static str) {
+        *self.last_command.lock().unwrap() = (Some(cmd), Instant::now());
+    }
+
+    /// Returns the last command the client sent, lower-cased.
+    pub fn last_command(&self) -> Option<String> {
+        self.last_command.lock().unwrap().0.map(str::to_lowercase)
+    }
+
+    /// Returns the client library name and version set with
+    /// `CLIENT SETINFO`.
+    pub fn lib(&self) -> (Option<Bytes>, Option<Bytes>) {
+        self.lib.lock().unwrap().clone()
+    }
+
+    /// Sets the client library name (`CLIENT SETINFO LIB-NAME`).
+    pub fn set_lib_name(&self, name: Option<Bytes>) {
+        self.lib.lock().unwrap().0 = name;
+    }
+
+    /// Sets the client library version (`CLIENT SETINFO LIB-VER`).
+    pub fn set_lib_ver(&self, version: Option<Bytes>) {
+        self.lib.lock().unwrap().1 = version;
+    }
+
+    /// Describes the client on one line of `field=value` pairs, in the
+    /// format of `CLIENT LIST` and `CLIENT INFO`.
+    ///
+    /// `age` is how long the client has been connected and `idle` how long
+    /// since its last command, both in seconds. `flags` has `P` for a
+    /// subscriber, `O` for a monitor, `x` inside MULTI, `e` and `T` for
+    /// `CLIENT NO-EVICT` and `NO-TOUCH`, or is `N` if none apply.
+    pub fn info(&self) -> String {
+        let text = |value: Option<Bytes>| {
+            value.map_or(String::new(), |v| String::from_utf8_lossy(&v).into_owned())
+        };
+        let (cmd, last) = *self.last_command.lock().unwrap();
+        let multi = self.multi.lock().unwrap().as_ref().map(Vec::len);
+        let (lib_name, lib_ver) = self.lib();
+
+        let mut flags = String::new();
+        if self.subscribed() {
+            flags.push('P');
+        }
+        if self.monitor() {
+            flags.push('O');
+        }
+        if multi.is_some() {
+            flags.push('x');
+        }
+        if self.no_evict() {
+            flags.push('e');
+        }
+        if self.no_touch() {
+            flags.push('T');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+
+        format!(
+            "id={} addr={} laddr={} name={} age={} idle={} flags={} db={} sub={} psub={} multi={} \
+             cmd={} user={} lib-name={} lib-ver={} resp={}",
+            self.id,
+            self.addr().map_or(String::new(), |addr| addr.to_string()),
+            self.laddr().map_or(String::new(), |addr| addr.to_string()),
+            text(self.name()),
+            self.created.elapsed().as_secs(),
+            last.elapsed().as_secs(),
+            flags,
+            self.db(),
+            self.subscriber.channels().len(),
+            self.subscriber.patterns().len(),
+            multi.map_or(-1, |n| n as i64),
+            cmd.map_or("NULL".to_string(), str::to_lowercase),
+            text(Some(self.user())),
+            text(lib_name),
+            text(lib_ver),
+            self.protocol(),
+        )
+    }
+
+    /// Returns the client's name, if it set one.
+    pub fn name(&self) -> Option<Bytes> {
+        self.name.lock().unwrap().clone()
+    }
+
+    /// Sets the client's name, or clears it.
+    pub fn set_name(&self, name: Option<Bytes>) {
+        *self.name.lock().unwrap() = name;
+    }
+
+    /// Returns the user the client is authenticated as.
+    pub fn user(&self) -> Bytes {
+        self.user.lock().unwrap().clone()
+    }
+
+    /// Records that the client authenticated as `user`.
+    pub fn set_user(&self, user: Bytes) {
+        *self.user.lock().unwrap() = user;
+    }
+
+    /// Returns the index of the selected database.
+    pub fn db(&self) -> usize {
+        self.db.load(Ordering::Relaxed)
+    }
+
+    /// Selects database `index`.
+    pub fn select(&self, index: usize) {
+        self.db.store(index, Ordering::Relaxed);
+    }
+
+    /// Returns the RESP version the client speaks, 2 or 3.
+    pub fn protocol(&self) -> u8 {
+        self.protocol.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the client speaks RESP3, and can receive pushes.
+    pub fn resp3(&self) -> bool {
+        self.protocol() == 3
+    }
+
+    /// Switches the client to RESP `version` (2 or 3).
+    pub fn set_protocol(&self, version: u8) {
+        self.protocol.store(version, Ordering::Relaxed);
+        self.subscriber.set_resp3(version == 3);
+    }
+
+    /// Returns whether a transaction is open (MULTI was sent).
+    pub fn in_multi(&self) -> bool {
+        self.multi.lock().unwrap().is_some()
+    }
+
+    /// Opens a transaction; returns `false` if one already was.
+    pub fn begin_multi(&self) -> bool {
+        let mut multi = self.multi.lock().unwrap();
+        if multi.is_some() {
+            return false;
+        }
+        *multi = Some(Vec::new());
+        true
+    }
+
+    /// Queues `command` in the open transaction; returns `false` if there
+    /// is none.
+    pub fn queue(&self, command: RespValue) -> bool {
+        match self.multi.lock().unwrap().as_mut() {
+            Some(queued) => {
+                queued.push(command);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Closes the transaction, returning the commands queued in it, or
+    /// `None` if none was open.
+    pub fn take_multi(&self) -> Option<Vec<RespValue>> {
+        self.multi.lock().unwrap().take()
+    }
+
+    /// Returns the client's pub/sub subscriptions.
+    pub fn subscriber(&self) -> &Subscriber {
+        &self.subscriber
+    }
+
+    /// Returns whether the client is in subscribe mode, with at least one
+    /// channel or pattern subscription.
+    pub fn subscribed(&self) -> bool {
+        self.subscriber.count() > 0
+    }
+
+    /// Returns whether the client turned on `CLIENT TRACKING`.
+    pub fn tracking(&self) -> bool {
+        self.tracking.load(Ordering::Relaxed)
+    }
+
+    /// Records whether the client has `CLIENT TRACKING` on.
+    pub fn set_tracking(&self, on: bool) {
+        self.tracking.store(on, Ordering::Relaxed);
+    }
+
+    /// Returns whether the client turned on `CLIENT NO-TOUCH`, so its
+    /// commands don't update the access stats of the keys they read.
+    pub fn no_touch(&self) -> bool {
+        self.no_touch.load(Ordering::Relaxed)
+    }
+
+    /// Records whether the client has `CLIENT NO-TOUCH` on.
+    pub fn set_no_touch(&self, on: bool) {
+        self.no_touch.store(on, Ordering::Relaxed);
+    }
+
+    /// Returns whether the client turned on `CLIENT NO-EVICT`. FlashKV
+    /// never evicts clients, so this only shows up in `CLIENT LIST`.
+    pub fn no_evict(&self) -> bool {
+        self.no_evict.load(Ordering::Relaxed)
+    }
+
+    /// Records whether the client has `CLIENT NO-EVICT` on.
+    pub fn set_no_evict(&self, on: bool) {
+        self.no_evict.store(on, Ordering::Relaxed);
+    }
+
+    /// Returns whether the client ran MONITOR.
+    pub fn monitor(&self) -> bool {
+        self.monitor.load(Ordering::Relaxed)
+    }
+
+    /// Records that the client ran MONITOR.
+    pub fn set_monitor(&self) {
+        self.monitor.store(true, Ordering::Relaxed);
+    }
+
+    /// Records that the client sent ASKING.
+    pub fn set_asking(&self) {
+        self.asking.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether the client sent ASKING, which only lasts one
+    /// command.
+    pub fn take_asking(&self) -> bool {
+        self.asking.swap(false, Ordering::Relaxed)
+    }
+
+    /// Returns the port the client announced with `REPLCONF
+    /// listening-port`, or 0 if it didn't.
+    pub fn listening_port(&self) -> u16 {
+        self.listening_port.load(Ordering::Relaxed)
+    }
+
+    /// Records the port the client announced with `REPLCONF
+    /// listening-port`.
+    pub fn set_listening_port(&self, port: u16) {
+        self.listening_port.store(port, Ordering::Relaxed);
+    }
+
+    /// Raises the log position the client's last write must reach on disk
+    /// before it is acknowledged.
+    pub(crate) fn await_commit(&self, position: u64) {
+        self.commit_position.fetch_max(position, Ordering::Relaxed);
+    }
+
+    /// Takes the log position to wait for, if any.
+    pub(crate) fn take_commit_position(&self) -> Option<u64> {
+        match self.commit_position.swap(0, Ordering::Relaxed) {
+            0 => None,
+            position => Some(position),
+        }
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_state() {
+        let session = Session::new();
+        let other = Session::new();
+        assert_ne!(session.id(), other.id());
+        assert_eq!(session.subscriber().id(), session.id());
+        assert_eq!(session.user(), DEFAULT_USER);
+        assert_eq!(session.protocol(), 2);
+        assert!(!session.subscribed());
+
+        assert!(!session.queue(RespValue::ok()));
+        assert!(session.begin_multi());
+        assert!(!session.begin_multi());
+        assert!(session.queue(RespValue::ok()));
+        assert!(session.in_multi());
+        assert_eq!(session.take_multi(), Some(vec![RespValue::ok()]));
+        assert!(!session.in_multi());
+
+        assert!(!session.take_asking());
+        session.set_asking();
+        assert!(session.take_asking());
+        assert!(!session.take_asking());
+
+        session.await_commit(7);
+        session.await_commit(3);
+        assert_eq!(session.take_commit_position(), Some(7));
+        assert_eq!(session.take_commit_position(), None);
+    }
+}
@@ -51,23 +51,54 @@
 //! We use a BytesMut buffer to accumulate incoming data. This is important
 //! because TCP is a stream protocol - we might receive partial commands,
 //! or multiple commands in a single read.
-
-use crate::commands::CommandHandler;
-use crate::protocol::{ParseError, RespParser, RespValue};
-use bytes::BytesMut;
+//!
+//! Responses don't go to the socket directly: they are queued on the
+//! connection's [outbound channel](super::writer), whose writer task owns
+//! the socket's write half. The replies to every command parsed from one
+//! read are serialized into a single buffer and queued together once the
+//! read buffer is drained (or the replies pass [`REPLY_FLUSH_THRESHOLD`]),
+//! so a pipeline of 100 GETs costs one write rather than 100. Bulk strings
+//! of [`ZERO_COPY_THRESHOLD`] bytes or more aren't copied into that buffer
+//! but queued alongside it, and the writer hands the pieces to a single
+//! vectored write. Messages for the client, such as pub/sub messages, are
+//! queued the same way whenever they arrive, even while the client is
+//! blocked on a command.
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use super::uring;
+use super::writer::{spawn_writer, Outbound};
+use crate::commands::{
+    clients, is_write_command, BlockingRequest, CommandHandler, CommandOutcome, SyncRequest,
+};
+use crate::protocol::{blank_lines_len, ParseError, RespParser, RespValue};
+use crate::replication::{FullSync, PartialSync, Replica};
+use crate::storage::snapshot;
+use bytes::{Bytes, BytesMut};
+use std::io;
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
 use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tracing::{debug, error, info, trace, warn};
 
-/// Maximum size for the read buffer (64 KB)
-const MAX_BUFFER_SIZE: usize = 64 * 1024;
-
 /// Initial buffer capacity
 const INITIAL_BUFFER_SIZE: usize = 4096;
 
+/// Size at which buffered replies are queued for the writer even though
+/// more pipelined commands are waiting, so a long pipeline's replies start
+/// going out before all of them are ready.
+pub const REPLY_FLUSH_THRESHOLD: usize = 64 * 1024;
+
+/// Size from which a bulk string in a reply is handed to the writer as it
+/// is, instead of being copied into the write buffer. Copying smaller ones
+/// is cheaper than writing them separately.
+pub const ZERO_COPY_THRESHOLD: usize = 16 * 1024;
+
 /// Statistics for connection handling
 #[derive(Debug, Default)]
 pub struct ConnectionStats {
@@ -81,6 +112,8 @@ pub struct ConnectionStats {
     pub bytes_read: AtomicU64,
     /// Total bytes written
     pub bytes_written: AtomicU64,
+    /// Total connections refused, by protected mode
+    pub connections_rejected: AtomicU64,
 }
 
 impl ConnectionStats {
@@ -97,6 +130,10 @@ impl ConnectionStats {
         self.active_connections.fetch_sub(1, Ordering::Relaxed);
     }
 
+    pub fn connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn command_processed(&self) {
         self.commands_processed.fetch_add(1, Ordering::Relaxed);
     }
@@ -116,8 +153,14 @@ impl ConnectionStats {
 /// This struct manages the read buffer, parsing, and response sending
 /// for one connected client.
 pub struct ConnectionHandler {
-    /// The TCP stream for this connection
-    stream: BufWriter<TcpStream>,
+    /// The read half of the TCP stream
+    reader: OwnedReadHalf,
+
+    /// Queue of frames for the writer task, which owns the write half
+    outbound: Outbound,
+
+    /// The writer task
+    writer: JoinHandle<io::Result<()>>,
 
     /// Client's address (for logging)
     addr: SocketAddr,
@@ -125,6 +168,16 @@ pub struct ConnectionHandler {
     /// Buffer for incoming data
     buffer: BytesMut,
 
+    /// Buffer replies are serialized into. The replies to a batch of
+    /// pipelined commands are split off together and queued for the
+    /// writer, and the space is reused once they are written.
+    write_buffer: BytesMut,
+
+    /// Replies split off the write buffer ahead of what it holds: the
+    /// bytes before each large bulk string, and the bulk string's payload,
+    /// which is sent as it is rather than copied
+    reply_frames: Vec<Bytes>,
+
     /// The command handler (shared across connections)
     command_handler: CommandHandler,
 
@@ -133,6 +186,10 @@ pub struct ConnectionHandler {
 
     /// Connection statistics (shared)
     stats: Arc<ConnectionStats>,
+
+    /// Reads through io_uring, if connections use it
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    uring: Option<uring::Reader>,
 }
 
 impl ConnectionHandler {
@@ -151,14 +208,27 @@ impl ConnectionHandler {
         stats: Arc<ConnectionStats>,
     ) -> Self {
         stats.connection_opened();
+        let laddr = stream
+            .local_addr()
+            .unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+        let command_handler = command_handler.with_connection_stats(Arc::clone(&stats));
+        command_handler.connect(addr, laddr);
+        let (reader, writer) = stream.into_split();
+        let (outbound, writer) = spawn_writer(writer, addr, Arc::clone(&stats));
 
         Self {
-            stream: BufWriter::new(stream),
+            reader,
+            outbound,
+            writer,
             addr,
             buffer: BytesMut::with_capacity(INITIAL_BUFFER_SIZE),
+            write_buffer: BytesMut::with_capacity(INITIAL_BUFFER_SIZE),
+            reply_frames: Vec::new(),
+            parser: RespParser::with_limits(*command_handler.protocol_limits()),
             command_handler,
-            parser: RespParser::new(),
             stats,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            uring: uring::is_enabled().then(uring::Reader::new),
         }
     }
 
@@ -170,6 +240,20 @@ impl ConnectionHandler {
         info!(client = %self.addr, "Client connected");
 
         let result = self.main_loop().await;
+        self.command_handler.close();
+
+        // Let the writer finish what is queued; if a write failed, the main
+        // loop only saw the queue close, so report the writer's error
+        drop(self.outbound);
+        let written = self.writer.await.expect("connection writer panicked");
+        let result = match (result, written) {
+            (Err(ConnectionError::IoError(e)), Err(written))
+                if e.kind() == io::ErrorKind::BrokenPipe =>
+            {
+                Err(written.into())
+            }
+            (result, _) => result,
+        };
 
         match &result {
             Ok(()) => info!(client = %self.addr, "Client disconnected gracefully"),
@@ -177,6 +261,7 @@ impl ConnectionHandler {
                 ConnectionError::ClientDisconnected => {
                     debug!(client = %self.addr, "Client disconnected")
                 }
+                ConnectionError::Killed => info!(client = %self.addr, "Client killed"),
                 ConnectionError::IoError(io_err)
                     if io_err.kind() == std::io::ErrorKind::ConnectionReset =>
                 {
@@ -192,11 +277,35 @@ impl ConnectionHandler {
 
     /// The main read-execute-respond loop.
     async fn main_loop(&mut self) -> Result<(), ConnectionError> {
+        let mut messages = self.command_handler.take_messages();
         loop {
             // Try to parse a complete command from the buffer
-            while let Some(command) = self.try_parse_command()? {
-                // Execute the command
-                let response = self.command_handler.execute(command);
+            while let Some(command) = self.try_parse_command().await? {
+                self.wait_unpaused(&command).await?;
+
+                // Execute the command, parking the client if it blocks
+                let response = match self.command_handler.execute_or_block(command) {
+                    CommandOutcome::Reply(response) => response,
+                    CommandOutcome::Block(request) => {
+                        self.wait_for_keys(request, &mut messages).await?
+                    }
+                    CommandOutcome::Sync(request) => return self.serve_replica(request).await,
+                    CommandOutcome::Replies(replies) => {
+                        self.stats.command_processed();
+                        for reply in &replies {
+                            self.send_response(reply).await?;
+                        }
+                        continue;
+                    }
+                };
+
+                // Under group commit, a write is only acknowledged once it
+                // is on disk; the replies before it don't wait for it
+                if let Some(position) = self.command_handler.take_commit_position() {
+                    self.flush_replies().await?;
+                    let storage = self.command_handler.storage();
+                    storage.aof().wait_durable(position).await;
+                }
                 self.stats.command_processed();
 
                 // Check for QUIT command
@@ -206,44 +315,310 @@ impl ConnectionHandler {
 
                 // Send the response
                 self.send_response(&response).await?;
+
+                // A client killing itself gets its reply first
+                if self.command_handler.session().is_killed() {
+                    self.flush_replies().await?;
+                    return Err(ConnectionError::Killed);
+                }
             }
 
-            // Need more data - read from the socket
-            self.read_more_data().await?;
+            // Every complete command has run; send their replies together
+            self.flush_replies().await?;
+
+            // Need more data - read from the socket, delivering messages
+            // published to the client's channels meanwhile
+            self.read_or_deliver(&mut messages).await?;
         }
     }
 
-    /// Attempts to parse a command from the buffer.
-    fn try_parse_command(&mut self) -> Result<Option<RespValue>, ConnectionError> {
-        if self.buffer.is_empty() {
-            return Ok(None);
+    /// Reads more data from the socket, sending the client any pub/sub
+    /// messages that arrive first.
+    async fn read_or_deliver(
+        &mut self,
+        messages: &mut Option<mpsc::UnboundedReceiver<Bytes>>,
+    ) -> Result<(), ConnectionError> {
+        let handler = self.command_handler.clone();
+        loop {
+            tokio::select! {
+                message = next_message(messages) => self.send(message).await?,
+                result = self.read_more_data() => return result,
+                _ = handler.session().wait_killed() => return Err(ConnectionError::Killed),
+            }
         }
+    }
 
-        match self.parser.parse(&self.buffer) {
-            Ok(Some((value, consumed))) => {
-                // Successfully parsed a command - consume the bytes
-                let _ = self.buffer.split_to(consumed);
-                trace!(
-                    client = %self.addr,
-                    consumed = consumed,
-                    remaining = self.buffer.len(),
-                    "Parsed command"
-                );
-                Ok(Some(value))
+    /// Waits while `CLIENT PAUSE` holds back `command`, until the pause
+    /// ends or is lifted. CLIENT commands are never held back, so a pause
+    /// can always be lifted and clients killed.
+    async fn wait_unpaused(&mut self, command: &RespValue) -> Result<(), ConnectionError> {
+        let name = command
+            .as_array()
+            .and_then(|args| args.first())
+            .and_then(RespValue::as_bytes)
+            .and_then(|name| self.command_handler.resolve_command(name));
+        if name == Some("CLIENT") {
+            return Ok(());
+        }
+        let write = name.is_some_and(is_write_command);
+
+        loop {
+            let unpaused = clients::unpaused();
+            tokio::pin!(unpaused);
+            unpaused.as_mut().enable();
+            let Some(until) = clients::paused_until(write) else {
+                return Ok(());
+            };
+            // The replies to the commands before this one don't wait
+            self.flush_replies().await?;
+            tokio::select! {
+                _ = tokio::time::sleep_until(Instant::from_std(until)) => {}
+                _ = unpaused => {}
+                _ = self.command_handler.session().wait_killed() => {
+                    return Err(ConnectionError::Killed)
+                }
             }
-            Ok(None) => {
-                // Incomplete data - need to read more
-                trace!(
+        }
+    }
+
+    /// Parks the client on a blocking command until it can be served.
+    ///
+    /// The client registers itself on every key of the request, then retries
+    /// the command each time one of those keys is written. It replies nil
+    /// once the timeout elapses. Incoming bytes are still read while blocked
+    /// so a disconnect is noticed straight away; any pipelined commands wait
+    /// in the buffer until this one completes. Messages for the client are
+    /// still delivered.
+    async fn wait_for_keys(
+        &mut self,
+        request: BlockingRequest,
+        messages: &mut Option<mpsc::UnboundedReceiver<Bytes>>,
+    ) -> Result<RespValue, ConnectionError> {
+        // The replies to the commands before this one don't wait
+        self.flush_replies().await?;
+
+        let storage = Arc::clone(self.command_handler.storage());
+        // The client can't SELECT another database while blocked
+        let db = storage
+            .db(self.command_handler.session().db())
+            .expect("the selected database exists");
+        let notify = Arc::new(Notify::new());
+        let deadline = request
+            .timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+
+        trace!(client = %self.addr, keys = request.keys.len(), "Client blocked");
+        db.waiters().register(&request.keys, &notify);
+
+        let result = self
+            .block_until_served(&request, &notify, deadline, messages)
+            .await;
+
+        db.waiters().unregister(&request.keys, &notify);
+        result
+    }
+
+    /// Retries a blocked request until it is served, times out, or the
+    /// client goes away.
+    async fn block_until_served(
+        &mut self,
+        request: &BlockingRequest,
+        notify: &Notify,
+        deadline: Option<Instant>,
+        messages: &mut Option<mpsc::UnboundedReceiver<Bytes>>,
+    ) -> Result<RespValue, ConnectionError> {
+        let handler = self.command_handler.clone();
+        loop {
+            if let Some(response) = self.command_handler.try_serve(request) {
+                return Ok(response);
+            }
+
+            let timeout = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                _ = notify.notified() => {}
+                _ = timeout => return Ok(request.op.timeout_reply()),
+                message = next_message(messages) => self.send(message).await?,
+                result = self.read_more_data() => result?,
+                _ = handler.session().wait_killed() => return Err(ConnectionError::Killed),
+            }
+        }
+    }
+
+    /// Turns the connection into a replica's: resumes the replication
+    /// stream from the backlog if the replica asked to and that is possible,
+    /// otherwise sends a snapshot of the keyspace first. Then forwards every
+    /// write for as long as the replica stays connected.
+    async fn serve_replica(&mut self, request: SyncRequest) -> Result<(), ConnectionError> {
+        let storage = Arc::clone(self.command_handler.storage());
+        let port = self.command_handler.listening_port();
+        let partial = match &request {
+            SyncRequest::Psync {
+                replid,
+                offset: Some(offset),
+            } => storage
+                .replication()
+                .attach_partial(self.addr, port, replid, *offset),
+            _ => None,
+        };
+
+        let (replica, result) = match partial {
+            Some(sync) => {
+                info!(client = %self.addr, "Replica attached, resuming from the backlog");
+                let replica = Arc::clone(&sync.replica);
+                (replica, self.resume_replica(sync).await)
+            }
+            None => {
+                let sync = {
+                    let storage = Arc::clone(&storage);
+                    let addr = self.addr;
+                    tokio::task::spawn_blocking(move || {
+                        storage.replication().attach(&storage, addr, port)
+                    })
+                    .await
+                    .expect("attaching a replica panicked")
+                };
+                info!(
                     client = %self.addr,
-                    buffered = self.buffer.len(),
-                    "Incomplete command, need more data"
+                    offset = sync.offset,
+                    "Replica attached, starting full resynchronization"
                 );
-                Ok(None)
+                let replica = Arc::clone(&sync.replica);
+                let psync = matches!(request, SyncRequest::Psync { .. });
+                (replica, self.full_resync(psync, sync).await)
             }
-            Err(e) => {
-                // Parse error - send error response and clear buffer
-                warn!(client = %self.addr, error = %e, "Parse error");
-                Err(ConnectionError::ParseError(e))
+        };
+
+        storage.replication().detach(&replica);
+        info!(client = %self.addr, "Replica detached");
+        result
+    }
+
+    /// Answers a partial resynchronization, then forwards the stream.
+    async fn resume_replica(&mut self, sync: PartialSync) -> Result<(), ConnectionError> {
+        let reply = format!("+CONTINUE {}\r\n", sync.replid);
+        self.send(reply.into()).await?;
+        self.forward_to_replica(&sync.replica, sync.receiver).await
+    }
+
+    /// Sends a full resynchronization, then forwards the stream.
+    async fn full_resync(&mut self, psync: bool, sync: FullSync) -> Result<(), ConnectionError> {
+        let FullSync {
+            replica,
+            receiver,
+            snapshot,
+            replid,
+            offset,
+        } = sync;
+        if psync {
+            let header = format!("+FULLRESYNC {} {}\r\n", replid, offset);
+            self.send(header.into()).await?;
+        }
+
+        // Encode the snapshot off the runtime; writes meanwhile queue up in
+        // the replica's output buffer
+        let storage = Arc::clone(self.command_handler.storage());
+        let payload = tokio::task::spawn_blocking(move || {
+            let snapshot = storage.finish_snapshot(snapshot);
+            let compression = storage.snapshots().compression();
+            snapshot::write_compressed_to(&snapshot, compression, Vec::new())
+        })
+        .await
+        .expect("encoding a snapshot panicked")?;
+        self.send(format!("${}\r\n", payload.len()).into()).await?;
+        self.send(payload.into()).await?;
+        replica.set_online();
+
+        self.forward_to_replica(&replica, receiver).await
+    }
+
+    /// Forwards the replica's output buffer until either side goes away,
+    /// recording the acknowledgements it sends back.
+    async fn forward_to_replica(
+        &mut self,
+        replica: &Replica,
+        mut receiver: mpsc::UnboundedReceiver<Bytes>,
+    ) -> Result<(), ConnectionError> {
+        let handler = self.command_handler.clone();
+        loop {
+            tokio::select! {
+                _ = handler.session().wait_killed() => return Err(ConnectionError::Killed),
+                commands = receiver.recv() => match commands {
+                    Some(commands) => {
+                        let len = commands.len();
+                        self.send(commands).await?;
+                        replica.sent(len);
+                    }
+                    // Dropped for falling too far behind
+                    None => return Err(ConnectionError::BufferFull),
+                },
+                result = self.read_more_data() => {
+                    result?;
+                    while let Some(command) = self.try_parse_command().await? {
+                        if let Some(offset) = parse_replconf_ack(&command) {
+                            replica.ack(offset);
+                        }
+                    }
+                    self.flush_replies().await?;
+                }
+            }
+        }
+    }
+
+    /// Attempts to parse a command from the buffer.
+    ///
+    /// Blank lines are dropped without a reply. Protocol errors get an
+    /// error reply. A bad inline command is skipped
+    /// so the requests after it still run; after any other error there is
+    /// no telling where the next request starts, so the connection is
+    /// closed.
+    async fn try_parse_command(&mut self) -> Result<Option<RespValue>, ConnectionError> {
+        loop {
+            let _ = self.buffer.split_to(blank_lines_len(&self.buffer));
+            if self.buffer.is_empty() {
+                return Ok(None);
+            }
+
+            match self.parser.parse(&self.buffer) {
+                Ok(Some((value, consumed))) => {
+                    // Successfully parsed a command - consume the bytes
+                    let _ = self.buffer.split_to(consumed);
+                    trace!(
+                        client = %self.addr,
+                        consumed = consumed,
+                        remaining = self.buffer.len(),
+                        "Parsed command"
+                    );
+                    return Ok(Some(value));
+                }
+                Ok(None) => {
+                    // Incomplete data - need to read more
+                    trace!(
+                        client = %self.addr,
+                        buffered = self.buffer.len(),
+                        "Incomplete command, need more data"
+                    );
+                    return Ok(None);
+                }
+                Err(e) => {
+                    warn!(client = %self.addr, error = %e, "Parse error");
+                    let reply = RespValue::error(format!("ERR Protocol error: {}", e));
+                    self.send_response(&reply).await?;
+                    match e.resync_len() {
+                        Some(len) => {
+                            let _ = self.buffer.split_to(len);
+                        }
+                        None => {
+                            self.flush_replies().await?;
+                            return Err(ConnectionError::ParseError(e));
+                        }
+                    }
+                }
             }
         }
     }
@@ -251,7 +626,7 @@ impl ConnectionHandler {
     /// Reads more data from the socket into the buffer.
     async fn read_more_data(&mut self) -> Result<(), ConnectionError> {
         // Check buffer size limit
-        if self.buffer.len() >= MAX_BUFFER_SIZE {
+        if self.buffer.len() >= self.parser.limits().max_query_buffer {
             error!(
                 client = %self.addr,
                 size = self.buffer.len(),
@@ -266,7 +641,7 @@ impl ConnectionHandler {
         }
 
         // Read data
-        let n = self.stream.get_mut().read_buf(&mut self.buffer).await?;
+        let n = self.read_socket().await?;
 
         if n == 0 {
             // Connection closed by client
@@ -284,18 +659,98 @@ impl ConnectionHandler {
         Ok(())
     }
 
-    /// Sends a response to the client.
+    /// Reads from the socket into the spare capacity of the buffer.
+    async fn read_socket(&mut self) -> io::Result<usize> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(uring) = &mut self.uring {
+            return uring.read(&mut self.reader, &mut self.buffer).await;
+        }
+        self.reader.read_buf(&mut self.buffer).await
+    }
+
+    /// Returns a handle for sending the client frames at any time.
+    ///
+    /// The connection's writer keeps running while a handle is alive, so
+    /// holders must drop theirs once the client disconnects.
+    pub fn outbound(&self) -> Outbound {
+        self.outbound.clone()
+    }
+
+    /// Sends bytes to the client as they are, after any buffered replies.
+    async fn send(&mut self, bytes: Bytes) -> Result<(), ConnectionError> {
+        self.flush_replies().await?;
+        self.outbound.send(bytes).await
+    }
+
+    /// Buffers a response for the client, in the protocol it chose with
+    /// HELLO. It is sent with the next [`flush_replies`](Self::flush_replies),
+    /// or straight away if the buffered replies pass
+    /// [`REPLY_FLUSH_THRESHOLD`].
     async fn send_response(&mut self, response: &RespValue) -> Result<(), ConnectionError> {
-        let bytes = response.serialize();
-        self.stream.write_all(&bytes).await?;
-        self.stream.flush().await?;
-        self.stats.bytes_written(bytes.len());
+        response.serialize_shared_into(
+            &mut self.write_buffer,
+            &mut self.reply_frames,
+            ZERO_COPY_THRESHOLD,
+            self.command_handler.session().resp3(),
+        );
+        let buffered =
+            self.write_buffer.len() + self.reply_frames.iter().map(Bytes::len).sum::<usize>();
+        if buffered >= REPLY_FLUSH_THRESHOLD {
+            self.flush_replies().await?;
+        }
+        Ok(())
+    }
+
+    /// Queues the buffered replies for the writer, as one frame.
+    async fn flush_replies(&mut self) -> Result<(), ConnectionError> {
+        if self.reply_frames.is_empty() {
+            if self.write_buffer.is_empty() {
+                return Ok(());
+            }
+            let bytes = self.write_buffer.split().freeze();
+            trace!(client = %self.addr, bytes = bytes.len(), "Sending replies");
+            return self.outbound.send(bytes).await;
+        }
+
+        // Large values were left out of the write buffer, so the replies
+        // go out in pieces
+        if !self.write_buffer.is_empty() {
+            let bytes = self.write_buffer.split().freeze();
+            self.reply_frames.push(bytes);
+        }
+        let pieces = std::mem::take(&mut self.reply_frames);
         trace!(
             client = %self.addr,
-            bytes = bytes.len(),
-            "Sent response"
+            bytes = pieces.iter().map(Bytes::len).sum::<usize>(),
+            pieces = pieces.len(),
+            "Sending replies"
         );
-        Ok(())
+        self.outbound.send_pieces(pieces).await
+    }
+}
+
+/// Waits for the next message for the client. Never completes once the
+/// queue is closed, or if there is none.
+async fn next_message(messages: &mut Option<mpsc::UnboundedReceiver<Bytes>>) -> Bytes {
+    if let Some(receiver) = messages {
+        if let Some(message) = receiver.recv().await {
+            return message;
+        }
+        *messages = None;
+    }
+    std::future::pending().await
+}
+
+/// Returns the offset of a `REPLCONF ACK offset` sent by a replica.
+fn parse_replconf_ack(command: &RespValue) -> Option<u64> {
+    match command.as_array()? {
+        [cmd, option, offset]
+            if cmd.as_bytes()?.eq_ignore_ascii_case(b"REPLCONF")
+                && option.as_bytes()?.eq_ignore_ascii_case(b"ACK") =>
+        {
+            std::str::from_utf8(offset.as_bytes()?).ok()?.parse().ok()
+        }
+        _ => None,
     }
 }
 
@@ -314,6 +769,10 @@ pub enum ConnectionError {
     #[error("Client disconnected")]
     ClientDisconnected,
 
+    /// Client disconnected by `CLIENT KILL`
+    #[error("Client killed")]
+    Killed,
+
     /// Unexpected end of stream (partial command)
     #[error("Unexpected end of stream")]
     UnexpectedEof,
@@ -353,11 +812,35 @@ pub async fn handle_connection(
     }
 }
 
+/// Error sent to the clients protected mode refuses.
+pub const PROTECTED_MODE_ERROR: &str = "DENIED FlashKV is running in protected mode because \
+    protected mode is enabled and no password is set. In this mode connections are only \
+    accepted from the loopback interface. To connect from other hosts, restart the server \
+    with '--protected-mode no', or bind only the loopback interface and reach it through a \
+    tunnel.";
+
+/// Returns whether protected mode lets in a client connecting from
+/// `addr`, which it does only for the loopback interface.
+pub fn allowed_in_protected_mode(addr: &SocketAddr) -> bool {
+    addr.ip().to_canonical().is_loopback()
+}
+
+/// Refuses a client in protected mode: it is sent
+/// [`PROTECTED_MODE_ERROR`] and disconnected without running any command.
+pub async fn refuse_connection(mut stream: TcpStream, addr: SocketAddr) {
+    warn!(client = %addr, "Refused connection in protected mode");
+    let reply = RespValue::error(PROTECTED_MODE_ERROR).serialize();
+    if let Err(e) = stream.write_all(&reply).await {
+        debug!(client = %addr, error = %e, "Failed to send protected mode error");
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::ProtocolLimits;
     use crate::storage::StorageEngine;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::io::AsyncReadExt;
     use tokio::net::TcpListener;
 
     async fn create_test_server() -> (SocketAddr, Arc<StorageEngine>, Arc<ConnectionStats>) {
@@ -396,6 +879,237 @@ mod tests {
         assert_eq!(&buf[..n], b"+PONG\r\n");
     }
 
+    #[tokio::test]
+    async fn test_protected_mode_refuses_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, client_addr) = listener.accept().await.unwrap();
+            refuse_connection(stream, client_addr).await;
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, RespValue::error(PROTECTED_MODE_ERROR).serialize());
+
+        assert!(allowed_in_protected_mode(
+            &"127.0.0.1:5000".parse().unwrap()
+        ));
+        assert!(allowed_in_protected_mode(&"[::1]:5000".parse().unwrap()));
+        assert!(allowed_in_protected_mode(
+            &"[::ffff:127.0.0.1]:5000".parse().unwrap()
+        ));
+        assert!(!allowed_in_protected_mode(
+            &"10.0.0.7:5000".parse().unwrap()
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_client_kill() {
+        let (addr, _, _) = create_test_server().await;
+        let mut victim = TcpStream::connect(addr).await.unwrap();
+        let mut admin = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 64];
+
+        victim
+            .write_all(b"*2\r\n$6\r\nCLIENT\r\n$2\r\nID\r\n")
+            .await
+            .unwrap();
+        let n = victim.read(&mut buf).await.unwrap();
+        let id = std::str::from_utf8(&buf[1..n - 2]).unwrap().to_string();
+
+        let kill = format!(
+            "*4\r\n$6\r\nCLIENT\r\n$4\r\nKILL\r\n$2\r\nID\r\n${}\r\n{}\r\n",
+            id.len(),
+            id
+        );
+        admin.write_all(kill.as_bytes()).await.unwrap();
+        let n = admin.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b":1\r\n");
+
+        // The victim is disconnected, the admin isn't
+        assert_eq!(victim.read(&mut buf).await.unwrap(), 0);
+        admin.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        let n = admin.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"+PONG\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_client_pause_holds_back_writes() {
+        let (addr, _, _) = create_test_server().await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut admin = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 64];
+
+        admin
+            .write_all(b"*4\r\n$6\r\nCLIENT\r\n$5\r\nPAUSE\r\n$5\r\n10000\r\n$5\r\nWRITE\r\n")
+            .await
+            .unwrap();
+        let n = admin.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"+OK\r\n");
+
+        // Reads go on, writes wait for the pause to be lifted
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n")
+            .await
+            .unwrap();
+        let n = client.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"$-1\r\n");
+        client
+            .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$1\r\nv\r\n")
+            .await
+            .unwrap();
+        let read =
+            tokio::time::timeout(std::time::Duration::from_millis(100), client.read(&mut buf));
+        assert!(read.await.is_err());
+
+        admin
+            .write_all(b"*2\r\n$6\r\nCLIENT\r\n$7\r\nUNPAUSE\r\n")
+            .await
+            .unwrap();
+        let n = admin.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"+OK\r\n");
+        let n = client.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_group_commit_acknowledges_durable_writes() {
+        let (addr, storage, _) = create_test_server().await;
+        let path = std::env::temp_dir().join(format!("flashkv-{}-ack.aof", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        storage
+            .aof()
+            .open(&path, crate::storage::AppendFsync::Group)
+            .unwrap();
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let set = b"*3\r\n$3\r\nSET\r\n$4\r\nname\r\n$4\r\nAriz\r\n";
+        client.write_all(set).await.unwrap();
+
+        let mut buf = [0u8; 64];
+        let n = client.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"+OK\r\n");
+        assert_eq!(std::fs::read(&path).unwrap(), set);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replica_receives_snapshot_then_writes() {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+
+        let (addr, storage, _) = create_test_server().await;
+        storage.set(Bytes::from("before"), Bytes::from("1"));
+
+        let mut replica = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        replica
+            .write_all(b"*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n$4\r\n6380\r\n")
+            .await
+            .unwrap();
+        let mut line = String::new();
+        replica.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "+OK\r\n");
+
+        replica
+            .write_all(b"*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n")
+            .await
+            .unwrap();
+        line.clear();
+        replica.read_line(&mut line).await.unwrap();
+        let replid = storage.replication().replid();
+        assert_eq!(line, format!("+FULLRESYNC {} 0\r\n", replid));
+
+        // The snapshot holds the keyspace as of the FULLRESYNC offset
+        line.clear();
+        replica.read_line(&mut line).await.unwrap();
+        let len: usize = line.trim_end()[1..].parse().unwrap();
+        let mut payload = vec![0; len];
+        replica.read_exact(&mut payload).await.unwrap();
+        let copy = StorageEngine::new();
+        assert_eq!(snapshot::load_slice(&copy, &mut &payload[..]).unwrap(), 1);
+        assert_eq!(copy.get(&Bytes::from("before")), Some(Bytes::from("1")));
+
+        // Writes from other clients follow on the stream
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let set = b"*3\r\n$3\r\nSET\r\n$5\r\nafter\r\n$1\r\n2\r\n";
+        client.write_all(set).await.unwrap();
+        let mut buf = [0u8; 64];
+        let n = client.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"+OK\r\n");
+
+        let mut fed = vec![0; set.len()];
+        replica.read_exact(&mut fed).await.unwrap();
+        assert_eq!(&fed[..], set);
+
+        let attached = storage.replication().replicas();
+        assert_eq!(attached.len(), 1);
+        assert_eq!(attached[0].listening_port(), Some(6380));
+        let ack = format!(
+            "*3\r\n$8\r\nREPLCONF\r\n$3\r\nACK\r\n$2\r\n{}\r\n",
+            set.len()
+        );
+        replica.write_all(ack.as_bytes()).await.unwrap();
+        while attached[0].ack_offset() != set.len() as u64 {
+            tokio::task::yield_now().await;
+        }
+
+        drop(replica);
+        while !storage.replication().replicas().is_empty() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replica_resumes_from_backlog() {
+        use tokio::io::{AsyncBufReadExt, BufReader};
+
+        let (addr, storage, _) = create_test_server().await;
+        let replid = storage.replication().replid().to_string();
+        let psync = |offset: &str| {
+            format!(
+                "*3\r\n$5\r\nPSYNC\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+                replid.len(),
+                replid,
+                offset.len(),
+                offset
+            )
+        };
+
+        // An unknown offset falls back to a full resynchronization
+        let mut replica = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        replica.write_all(psync("1").as_bytes()).await.unwrap();
+        let mut line = String::new();
+        replica.read_line(&mut line).await.unwrap();
+        assert_eq!(line, format!("+FULLRESYNC {} 0\r\n", replid));
+        line.clear();
+        replica.read_line(&mut line).await.unwrap();
+        let len: usize = line.trim_end()[1..].parse().unwrap();
+        let mut payload = vec![0; len];
+        replica.read_exact(&mut payload).await.unwrap();
+        drop(replica);
+        while !storage.replication().replicas().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        // Writes while disconnected are kept in the backlog
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let set = b"*3\r\n$3\r\nSET\r\n$4\r\nname\r\n$4\r\nAriz\r\n";
+        client.write_all(set).await.unwrap();
+        let mut buf = [0u8; 64];
+        let n = client.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"+OK\r\n");
+
+        let mut replica = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        replica.write_all(psync("1").as_bytes()).await.unwrap();
+        line.clear();
+        replica.read_line(&mut line).await.unwrap();
+        assert_eq!(line, format!("+CONTINUE {}\r\n", replid));
+        let mut missed = vec![0; set.len()];
+        replica.read_exact(&mut missed).await.unwrap();
+        assert_eq!(&missed[..], set);
+    }
+
     #[tokio::test]
     async fn test_set_get() {
         let (addr, _, _) = create_test_server().await;
@@ -461,6 +1175,73 @@ mod tests {
         assert!(response.contains("v2"));
     }
 
+    #[tokio::test]
+    async fn test_pipelined_replies() {
+        let (addr, storage, _) = create_test_server().await;
+        let value = Bytes::from(vec![b'x'; 1000]);
+        storage.set(Bytes::from("key"), value.clone());
+
+        // Enough replies to pass the flush threshold several times over
+        let count = 3 * REPLY_FLUSH_THRESHOLD / value.len();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let pipeline = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n".repeat(count);
+        client.write_all(&pipeline).await.unwrap();
+
+        let reply = [&b"$1000\r\n"[..], &value, b"\r\n"].concat();
+        let mut buf = vec![0u8; reply.len() * count];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, reply.repeat(count));
+
+        // Replies buffered before a blocking command go out while it waits
+        client
+            .write_all(b"*1\r\n$4\r\nPING\r\n*3\r\n$5\r\nBLPOP\r\n$4\r\nlist\r\n$1\r\n0\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 7];
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(2),
+            client.read_exact(&mut buf),
+        )
+        .await
+        .expect("PING's reply was held back by BLPOP")
+        .unwrap();
+        assert_eq!(&buf, b"+PONG\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_large_replies() {
+        let (addr, storage, _) = create_test_server().await;
+        let value = Bytes::from((0..4 << 20).map(|i| i as u8).collect::<Vec<u8>>());
+        storage.set(Bytes::from("big"), value.clone());
+
+        // The large values are sent apart from the replies around them
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(
+                b"*1\r\n$4\r\nPING\r\n\
+                  *2\r\n$3\r\nGET\r\n$3\r\nbig\r\n\
+                  *3\r\n$4\r\nMGET\r\n$3\r\nbig\r\n$3\r\nbig\r\n\
+                  *1\r\n$4\r\nPING\r\n",
+            )
+            .await
+            .unwrap();
+
+        let header = format!("${}\r\n", value.len());
+        let bulk = [header.as_bytes(), &value, b"\r\n"].concat();
+        let expected = [
+            &b"+PONG\r\n"[..],
+            &bulk,
+            b"*2\r\n",
+            &bulk,
+            &bulk,
+            b"+PONG\r\n",
+        ]
+        .concat();
+        let mut buf = vec![0u8; expected.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert!(buf == expected, "large replies arrived out of order");
+    }
+
     #[tokio::test]
     async fn test_connection_stats() {
         let (addr, _, stats) = create_test_server().await;
@@ -493,4 +1274,287 @@ mod tests {
 
         assert_eq!(stats.active_connections.load(Ordering::Relaxed), 0);
     }
+
+    #[tokio::test]
+    async fn test_bzpopmin_wakes_on_zadd() {
+        let (addr, storage, _) = create_test_server().await;
+
+        let mut blocked = TcpStream::connect(addr).await.unwrap();
+        blocked
+            .write_all(b"*3\r\n$8\r\nBZPOPMIN\r\n$5\r\nqueue\r\n$1\r\n0\r\n")
+            .await
+            .unwrap();
+
+        // Wait until the client is parked on the key
+        let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_secs(2);
+        while storage.waiters().waiting_on(b"queue") == 0 {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "client never blocked"
+            );
+            tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
+        }
+
+        let mut writer = TcpStream::connect(addr).await.unwrap();
+        writer
+            .write_all(b"*4\r\n$4\r\nZADD\r\n$5\r\nqueue\r\n$1\r\n7\r\n$3\r\njob\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 64];
+        let n = writer.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b":1\r\n");
+
+        let n = tokio::time::timeout(tokio::time::Duration::from_secs(2), blocked.read(&mut buf))
+            .await
+            .expect("blocked client was not woken")
+            .unwrap();
+        assert_eq!(&buf[..n], b"*3\r\n$5\r\nqueue\r\n$3\r\njob\r\n$1\r\n7\r\n");
+
+        // The popped member is gone and the waiter was released
+        assert_eq!(storage.zcard(&bytes::Bytes::from("queue")), 0);
+        assert_eq!(storage.waiters().waiting_on(b"queue"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_receives_published_messages() {
+        let (addr, storage, _) = create_test_server().await;
+
+        let mut subscriber = TcpStream::connect(addr).await.unwrap();
+        subscriber
+            .write_all(b"*2\r\n$9\r\nSUBSCRIBE\r\n$4\r\nnews\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 128];
+        let n = subscriber.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n");
+
+        let mut publisher = TcpStream::connect(addr).await.unwrap();
+        publisher
+            .write_all(b"*3\r\n$7\r\nPUBLISH\r\n$4\r\nnews\r\n$5\r\nhello\r\n")
+            .await
+            .unwrap();
+        let n = publisher.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b":1\r\n");
+
+        let n = tokio::time::timeout(
+            tokio::time::Duration::from_secs(2),
+            subscriber.read(&mut buf),
+        )
+        .await
+        .expect("message was not delivered")
+        .unwrap();
+        assert_eq!(
+            &buf[..n],
+            b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n"
+        );
+
+        // Disconnecting drops the subscription
+        drop(subscriber);
+        let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_secs(2);
+        while storage.pubsub().numsub(b"news") != 0 {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "subscription outlived its client"
+            );
+            tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blocked_client_receives_invalidations() {
+        let (addr, storage, _) = create_test_server().await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"*2\r\n$5\r\nHELLO\r\n$1\r\n3\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 256];
+        let _ = client.read(&mut buf).await.unwrap();
+        client
+            .write_all(b"*3\r\n$6\r\nCLIENT\r\n$8\r\nTRACKING\r\n$2\r\nON\r\n*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n")
+            .await
+            .unwrap();
+        let mut read = 0;
+        while read < b"+OK\r\n_\r\n".len() {
+            read += client.read(&mut buf[read..]).await.unwrap();
+        }
+        assert_eq!(&buf[..read], b"+OK\r\n_\r\n");
+
+        client
+            .write_all(b"*3\r\n$5\r\nBLPOP\r\n$5\r\nqueue\r\n$1\r\n0\r\n")
+            .await
+            .unwrap();
+        let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_secs(2);
+        while storage.waiters().waiting_on(b"queue") == 0 {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "client never blocked"
+            );
+            tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
+        }
+
+        // The invalidation reaches the client while it is still blocked
+        let mut writer = TcpStream::connect(addr).await.unwrap();
+        writer
+            .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n")
+            .await
+            .unwrap();
+        let n = tokio::time::timeout(tokio::time::Duration::from_secs(2), client.read(&mut buf))
+            .await
+            .expect("invalidation was not delivered")
+            .unwrap();
+        assert_eq!(&buf[..n], b">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nfoo\r\n");
+        assert_eq!(storage.waiters().waiting_on(b"queue"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_blpop_wakes_on_rpush() {
+        let (addr, storage, _) = create_test_server().await;
+
+        let mut blocked = TcpStream::connect(addr).await.unwrap();
+        blocked
+            .write_all(b"*3\r\n$5\r\nBLPOP\r\n$4\r\njobs\r\n$1\r\n0\r\n")
+            .await
+            .unwrap();
+
+        let deadline = tokio::time::Instant::now() + tokio::time::Duration::from_secs(2);
+        while storage.waiters().waiting_on(b"jobs") == 0 {
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "client never blocked"
+            );
+            tokio::time::sleep(tokio::time::Duration::from_millis(5)).await;
+        }
+
+        let mut writer = TcpStream::connect(addr).await.unwrap();
+        writer
+            .write_all(b"*3\r\n$5\r\nRPUSH\r\n$4\r\njobs\r\n$2\r\nj1\r\n")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 64];
+        let n = writer.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b":1\r\n");
+
+        let n = tokio::time::timeout(tokio::time::Duration::from_secs(2), blocked.read(&mut buf))
+            .await
+            .expect("blocked client was not woken")
+            .unwrap();
+        assert_eq!(&buf[..n], b"*2\r\n$4\r\njobs\r\n$2\r\nj1\r\n");
+
+        assert_eq!(storage.llen(&bytes::Bytes::from("jobs")), 0);
+        assert_eq!(storage.waiters().waiting_on(b"jobs"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_brpop_times_out_with_null_array() {
+        let (addr, _, _) = create_test_server().await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"*3\r\n$5\r\nBRPOP\r\n$5\r\nempty\r\n$4\r\n0.05\r\n")
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 64];
+        let n = tokio::time::timeout(tokio::time::Duration::from_secs(2), client.read(&mut buf))
+            .await
+            .expect("blocking command never timed out")
+            .unwrap();
+        assert_eq!(&buf[..n], b"*-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_bzpopmax_times_out() {
+        let (addr, _, _) = create_test_server().await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"*3\r\n$8\r\nBZPOPMAX\r\n$5\r\nempty\r\n$4\r\n0.05\r\n")
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 64];
+        let n = tokio::time::timeout(tokio::time::Duration::from_secs(2), client.read(&mut buf))
+            .await
+            .expect("blocking command never timed out")
+            .unwrap();
+        assert_eq!(&buf[..n], b"$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_protocol_limits() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let storage = Arc::new(StorageEngine::new());
+        let limits = ProtocolLimits {
+            max_multibulk_len: 3,
+            ..ProtocolLimits::default()
+        };
+        tokio::spawn(async move {
+            while let Ok((stream, client_addr)) = listener.accept().await {
+                let handler =
+                    CommandHandler::new(Arc::clone(&storage)).with_protocol_limits(limits);
+                let stats = Arc::new(ConnectionStats::new());
+                tokio::spawn(handle_connection(stream, client_addr, handler, stats));
+            }
+        });
+
+        // Values bigger than the read buffer's initial size get through
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let value = vec![b'v'; 100 * 1024];
+        let mut request = b"*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n$102400\r\n".to_vec();
+        request.extend_from_slice(&value);
+        request.extend_from_slice(b"\r\n*2\r\n$6\r\nSTRLEN\r\n$3\r\nbig\r\n");
+        client.write_all(&request).await.unwrap();
+        let mut buf = [0u8; 64];
+        let mut reply = Vec::new();
+        while reply.len() < b"+OK\r\n:102400\r\n".len() {
+            let n = client.read(&mut buf).await.unwrap();
+            assert!(n > 0);
+            reply.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(reply, b"+OK\r\n:102400\r\n");
+
+        // Breaking a limit gets an error before the connection is closed
+        client
+            .write_all(b"*4\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n")
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(
+            reply,
+            b"-ERR Protocol error: too many elements: 4 (max: 3)\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_protocol_errors() {
+        let (addr, _, _) = create_test_server().await;
+        let mut client = TcpStream::connect(addr).await.unwrap();
+
+        // Blank lines get no reply
+        client.write_all(b"\r\n \r\nPING\r\n").await.unwrap();
+        let mut reply = [0u8; 7];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+
+        // A bad inline command is answered and skipped
+        client.write_all(b"GET \xff\r\nPING\r\n").await.unwrap();
+        let expected = b"-ERR Protocol error: invalid UTF-8: \
+            invalid utf-8 sequence of 1 bytes from index 4\r\n+PONG\r\n";
+        let mut reply = vec![0u8; expected.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, expected);
+
+        // Anything else is answered before the connection is closed
+        client.write_all(b"$x\r\nPING\r\n").await.unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(
+            reply,
+            b"-ERR Protocol error: invalid integer: invalid digit found in string\r\n"
+        );
+    }
 }
@@ -0,0 +1,94 @@
+//! Listening Sockets
+//!
+//! By default the server accepts clients with one loop per address on the
+//! shared multi-threaded runtime, which moves connection tasks between
+//! worker threads as it balances load. With `--reactors N`, it instead runs
+//! N reactors: threads with a single-threaded runtime each, and each with a
+//! listener of its own on every address.
+//!
+//! ```text
+//!                  clients ──> port 6379
+//!                                 │  kernel picks a listener
+//!            ┌────────────────────┼────────────────────┐
+//!            ▼                    ▼                    ▼
+//!     ┌─────────────┐      ┌─────────────┐      ┌─────────────┐
+//!     │  reactor 0  │      │  reactor 1  │      │  reactor N  │
+//!     │  listener   │      │  listener   │      │  listener   │
+//!     │  runtime    │      │  runtime    │      │  runtime    │
+//!     │ connections │      │ connections │      │ connections │
+//!     └─────────────┘      └─────────────┘      └─────────────┘
+//! ```
+//!
+//! The listeners share the address through `SO_REUSEPORT`, so the kernel
+//! spreads incoming connections across them and no single accept loop
+//! limits how fast clients can connect. A connection then lives on the
+//! thread that accepted it, keeping a busy client's buffers in one core's
+//! cache. The keyspace, pub/sub and replication state stay shared by all
+//! reactors, as they are by all tasks of the shared runtime.
+//!
+//! `SO_REUSEPORT` also lets other processes of the same user bind the port,
+//! and is only available on Unix systems.
+
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpSocket};
+
+/// Most connections waiting to be accepted by a listener, as Redis's
+/// default `tcp-backlog`.
+pub const LISTEN_BACKLOG: u32 = 511;
+
+/// Returns the number of reactors `--reactors 0` runs: one per core.
+pub fn default_reactor_count() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Binds a listener to `addr` that other listeners bound the same way can
+/// share, for one reactor. Must be called from within a runtime.
+#[cfg(unix)]
+pub fn bind_shared(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.set_reuseport(true)?;
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+/// Binds a listener to `addr` that other listeners bound the same way can
+/// share, for one reactor. Must be called from within a runtime.
+#[cfg(not(unix))]
+pub fn bind_shared(_addr: SocketAddr) -> io::Result<TcpListener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--reactors needs SO_REUSEPORT, which only Unix systems have",
+    ))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn test_bind_shared() {
+        let first = bind_shared("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind_shared(addr).unwrap();
+
+        // A listener that didn't ask to share the address can't have it
+        assert!(TcpListener::bind(addr).await.is_err());
+
+        // Connections reach one listener or the other
+        for _ in 0..4 {
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (_, peer) = tokio::select! {
+                result = first.accept() => result.unwrap(),
+                result = second.accept() => result.unwrap(),
+            };
+            assert_eq!(peer, client.local_addr().unwrap());
+        }
+    }
+}
@@ -29,7 +29,12 @@
 //! │                                               │             │
 //! │                                               ▼             │
 //! │                                      ┌─────────────┐        │
-//! │                                      │ Send resp   │        │
+//! │                                      │ Queue resp  │        │
+//! │                                      └──────┬──────┘        │
+//! │                                             │ outbound      │
+//! │                                             ▼               │
+//! │                                      ┌─────────────┐        │
+//! │                                      │ Writer task │        │
 //! │                                      └─────────────┘        │
 //! └─────────────────────────────────────────────────────────────┘
 //! ```
@@ -39,7 +44,12 @@
 //! - **Async I/O**: Uses Tokio for non-blocking network operations
 //! - **Buffer Management**: Efficient BytesMut buffer for incoming data
 //! - **Pipelining**: Supports multiple commands in a single TCP packet
+//! - **Out-of-band writes**: Frames can be pushed to a client at any time
 //! - **Statistics**: Tracks connection and command metrics
+//! - **Reactors**: Optionally one accept loop and runtime per core, sharing
+//!   the port (see [`listener`])
+//! - **io_uring**: Optionally socket reads and writes through io_uring on
+//!   Linux (see [`uring`])
 //!
 //! ## Example
 //!
@@ -59,6 +69,14 @@
 //! ```
 
 pub mod handler;
+pub mod listener;
+pub mod uring;
+pub mod writer;
 
 // Re-export commonly used types
-pub use handler::{handle_connection, ConnectionError, ConnectionHandler, ConnectionStats};
+pub use handler::{
+    allowed_in_protected_mode, handle_connection, refuse_connection, ConnectionError,
+    ConnectionHandler, ConnectionStats, PROTECTED_MODE_ERROR,
+};
+pub use listener::{bind_shared, default_reactor_count};
+pub use writer::Outbound;
@@ -0,0 +1,608 @@
+//! io_uring Socket I/O
+//!
+//! With the `io-uring` cargo feature on Linux, `--io-uring yes` has
+//! connections read from and write to their sockets through io_uring rather
+//! than tokio's readiness polling. Readiness polling takes an `epoll_wait`
+//! to learn that a socket is readable, then a `read` on it; with many
+//! clients sending small commands, those syscalls are most of the work.
+//! Through io_uring, a connection hands the kernel a receive or a send
+//! along with its buffers, and the kernel completes it once the socket is
+//! ready:
+//!
+//! ```text
+//!  connection ──> recv / sendmsg ──┐                  ┌──────────┐
+//!  connection ──> recv / sendmsg ──┼──> submission ──>│  kernel  │
+//!  connection ──> recv / sendmsg ──┘      queue       └────┬─────┘
+//!       ▲                                                  │
+//!       └────── driver task <─── completion queue <────────┘
+//! ```
+//!
+//! Each runtime thread has a ring of its own, with a driver task that
+//! submits every operation queued since it last ran in one `io_uring_enter`
+//! and wakes the connections whose operations completed. The kernel
+//! signals completions on an eventfd, which the driver task watches through
+//! tokio, so the ring and tokio's other I/O share the thread.
+//!
+//! The kernel writes into a receive buffer and reads from send buffers
+//! until the operation completes, so operations own their buffers and hand
+//! them back with the result. An operation dropped before it completes is
+//! cancelled, and its buffers are kept until the kernel is done with them.
+//! Should the kernel answer an operation with `EAGAIN` rather than wait for
+//! the socket, the connection falls back to readiness polling for it.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use {
+    super::writer::MAX_WRITE_FRAMES,
+    bytes::{Bytes, BytesMut},
+    io_uring::{opcode, squeue, types, IoUring, Probe},
+    parking_lot::Mutex,
+    std::any::Any,
+    std::cell::RefCell,
+    std::collections::VecDeque,
+    std::future::Future,
+    std::os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    std::pin::Pin,
+    std::sync::Arc,
+    std::task::{Context, Poll, Waker},
+    tokio::io::unix::AsyncFd,
+    tokio::io::{AsyncReadExt, AsyncWriteExt},
+    tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf},
+    tokio::sync::Notify,
+    tracing::warn,
+};
+
+/// Whether connections use io_uring
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Number of submission queue entries in each ring
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+const RING_ENTRIES: u32 = 256;
+
+/// User data of cancellations, whose completions are ignored
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+const CANCEL: u64 = u64::MAX;
+
+/// Returns true if this build can use io_uring.
+pub fn is_available() -> bool {
+    cfg!(all(feature = "io-uring", target_os = "linux"))
+}
+
+/// Returns true if connections use io_uring.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Has connections accepted from now on use io_uring, after checking that
+/// the kernel supports the operations they need.
+pub fn enable() -> io::Result<()> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    {
+        let ring = IoUring::new(2)?;
+        let mut probe = Probe::new();
+        ring.submitter().register_probe(&mut probe)?;
+        let supported = [
+            opcode::Recv::CODE,
+            opcode::SendMsg::CODE,
+            opcode::AsyncCancel::CODE,
+        ]
+        .iter()
+        .all(|&code| probe.is_supported(code));
+        if !supported {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the kernel's io_uring lacks socket receives and sends",
+            ));
+        }
+        ENABLED.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+    #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "io_uring needs Linux and the `io-uring` feature",
+    ))
+}
+
+/// State of one operation in a ring.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+enum Slot {
+    /// Unused
+    Free,
+    /// In the kernel, with the waker of the task awaiting it
+    Waiting(Option<Waker>),
+    /// Completed with this result, not yet taken
+    Completed(i32),
+    /// Dropped before completing; its buffers live until it does
+    Abandoned(#[allow(dead_code)] Box<dyn Any + Send>),
+}
+
+/// A ring and its operations.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+struct Ring {
+    ring: IoUring,
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+    /// Counts operations started, so a cancellation can't reach a later
+    /// operation reusing the slot of the one it was meant for
+    started: u32,
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl Ring {
+    /// Queues `sqe` for submission, submitting what is queued already if
+    /// the queue is full.
+    ///
+    /// # Safety
+    /// Whatever the entry points to must stay valid until it completes.
+    unsafe fn push(&mut self, sqe: &squeue::Entry) {
+        while self.ring.submission().push(sqe).is_err() {
+            if self.ring.submit().is_err() {
+                // The completion queue is full: make room in it
+                self.reap();
+            }
+        }
+    }
+
+    /// Hands queued operations to the kernel.
+    fn submit(&mut self) {
+        if self.ring.submission().is_empty() {
+            return;
+        }
+        if let Err(e) = self.ring.submit() {
+            if e.raw_os_error() != Some(libc::EBUSY) {
+                warn!(error = %e, "io_uring submission failed");
+            }
+        }
+    }
+
+    /// Records completed operations, waking the tasks awaiting them.
+    fn reap(&mut self) {
+        let Self {
+            ring, slots, free, ..
+        } = self;
+        for cqe in ring.completion() {
+            if cqe.user_data() == CANCEL {
+                continue;
+            }
+            let index = cqe.user_data() as u32 as usize;
+            match std::mem::replace(&mut slots[index], Slot::Completed(cqe.result())) {
+                Slot::Waiting(waker) => {
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+                Slot::Abandoned(_) => {
+                    slots[index] = Slot::Free;
+                    free.push(index);
+                }
+                Slot::Free | Slot::Completed(_) => unreachable!("completion of an idle slot"),
+            }
+        }
+    }
+
+    fn release(&mut self, index: usize) {
+        self.slots[index] = Slot::Free;
+        self.free.push(index);
+    }
+}
+
+/// A thread's ring, shared by the operations started on it and the task
+/// driving it.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+struct Driver {
+    ring: Mutex<Ring>,
+    /// Signalled by the kernel on completions
+    eventfd: AsyncFd<OwnedFd>,
+    /// Wakes the driver task to submit newly queued operations
+    submit: Notify,
+    /// Set once the driver task is gone, with its runtime
+    stopped: AtomicBool,
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+thread_local! {
+    /// The driver of the ring operations on this thread start on
+    static DRIVER: RefCell<Option<Arc<Driver>>> = const { RefCell::new(None) };
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl Driver {
+    /// Returns this thread's driver, creating it and spawning its task on
+    /// the current runtime if there is none running.
+    fn current() -> io::Result<Arc<Driver>> {
+        DRIVER.with(|driver| {
+            let mut driver = driver.borrow_mut();
+            match &*driver {
+                Some(running) if !running.stopped.load(Ordering::Acquire) => {
+                    Ok(Arc::clone(running))
+                }
+                _ => {
+                    let started = Driver::start()?;
+                    *driver = Some(Arc::clone(&started));
+                    Ok(started)
+                }
+            }
+        })
+    }
+
+    fn start() -> io::Result<Arc<Driver>> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+        // SAFETY: eventfd has no preconditions; the result is checked
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a new descriptor nothing else owns
+        let eventfd = unsafe { OwnedFd::from_raw_fd(fd) };
+        ring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+
+        let driver = Arc::new(Driver {
+            ring: Mutex::new(Ring {
+                ring,
+                slots: Vec::new(),
+                free: Vec::new(),
+                started: 0,
+            }),
+            eventfd: AsyncFd::new(eventfd)?,
+            submit: Notify::new(),
+            stopped: AtomicBool::new(false),
+        });
+        let stopped = StopGuard(Arc::clone(&driver));
+        tokio::spawn(async move { stopped.0.drive().await });
+        Ok(driver)
+    }
+
+    /// Submits queued operations and reaps completed ones, until the
+    /// runtime shuts down.
+    async fn drive(&self) {
+        loop {
+            tokio::select! {
+                _ = self.submit.notified() => {}
+                ready = self.eventfd.readable() => match ready {
+                    Ok(mut ready) => {
+                        let mut count = [0u8; 8];
+                        // SAFETY: `count` is a valid buffer of its length
+                        unsafe {
+                            libc::read(self.eventfd.as_raw_fd(), count.as_mut_ptr().cast(), 8)
+                        };
+                        ready.clear_ready();
+                    }
+                    Err(e) => {
+                        warn!(error = %e, "io_uring eventfd failed");
+                        return;
+                    }
+                },
+            }
+            let mut ring = self.ring.lock();
+            ring.submit();
+            ring.reap();
+        }
+    }
+
+    /// Starts the operation `sqe`, whose buffers `buffers` owns.
+    ///
+    /// # Safety
+    /// Whatever `sqe` points to must be owned by `buffers`, and stay put
+    /// when they move.
+    unsafe fn start_op<B: Send + 'static>(
+        self: &Arc<Self>,
+        sqe: squeue::Entry,
+        buffers: B,
+    ) -> Op<B> {
+        let mut ring = self.ring.lock();
+        let index = match ring.free.pop() {
+            Some(index) => index,
+            None => {
+                ring.slots.push(Slot::Free);
+                ring.slots.len() - 1
+            }
+        };
+        ring.slots[index] = Slot::Waiting(None);
+        ring.started = ring.started.wrapping_add(1);
+        let user_data = (u64::from(ring.started) << 32) | index as u64;
+        ring.push(&sqe.user_data(user_data));
+        drop(ring);
+        self.submit.notify_one();
+        Op {
+            driver: Arc::clone(self),
+            index,
+            user_data,
+            buffers: Some(buffers),
+        }
+    }
+}
+
+/// Marks a driver stopped when its task is dropped.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+struct StopGuard(Arc<Driver>);
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl Drop for StopGuard {
+    fn drop(&mut self) {
+        self.0.stopped.store(true, Ordering::Release);
+    }
+}
+
+/// An operation in a ring, resolving to its result and its buffers.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+struct Op<B: Send + 'static> {
+    driver: Arc<Driver>,
+    index: usize,
+    /// Identifies the operation to the kernel: its slot, and a sequence
+    /// number in the upper half
+    user_data: u64,
+    /// Taken once the operation completes
+    buffers: Option<B>,
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl<B: Send + Unpin + 'static> Future for Op<B> {
+    type Output = (i32, B);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut ring = this.driver.ring.lock();
+        match &mut ring.slots[this.index] {
+            Slot::Completed(result) => {
+                let result = *result;
+                ring.release(this.index);
+                let buffers = this
+                    .buffers
+                    .take()
+                    .expect("operation polled after completing");
+                Poll::Ready((result, buffers))
+            }
+            Slot::Waiting(waker) => {
+                if !waker.as_ref().is_some_and(|w| w.will_wake(cx.waker())) {
+                    *waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            Slot::Free | Slot::Abandoned(_) => unreachable!("operation lost its slot"),
+        }
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl<B: Send + 'static> Drop for Op<B> {
+    fn drop(&mut self) {
+        let Some(buffers) = self.buffers.take() else {
+            return;
+        };
+        let mut ring = self.driver.ring.lock();
+        if let Slot::Completed(_) = ring.slots[self.index] {
+            ring.release(self.index);
+            return;
+        }
+        ring.slots[self.index] = Slot::Abandoned(Box::new(buffers));
+        let cancel = opcode::AsyncCancel::new(self.user_data)
+            .build()
+            .user_data(CANCEL);
+        // SAFETY: a cancellation points to nothing
+        unsafe { ring.push(&cancel) };
+        drop(ring);
+        self.driver.submit.notify_one();
+    }
+}
+
+/// Reads from a connection's socket through io_uring.
+///
+/// A read interrupted by dropping its future carries on in the kernel, and
+/// the next read picks it up, so nothing received is lost to a `select!`.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+#[derive(Default)]
+pub struct Reader {
+    pending: Option<Op<BytesMut>>,
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl Reader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads from `socket` into the spare capacity of `buffer`, as
+    /// `read_buf` does.
+    pub async fn read(
+        &mut self,
+        socket: &mut OwnedReadHalf,
+        buffer: &mut BytesMut,
+    ) -> io::Result<usize> {
+        let op = match &mut self.pending {
+            Some(op) => op,
+            None => {
+                let fd = socket.as_ref().as_raw_fd();
+                let mut spare = buffer.split_off(buffer.len());
+                let len = spare.capacity().min(u32::MAX as usize) as u32;
+                let sqe = opcode::Recv::new(types::Fd(fd), spare.as_mut_ptr(), len).build();
+                // SAFETY: the receive writes into `spare`'s own allocation
+                let op = unsafe { Driver::current()?.start_op(sqe, spare) };
+                self.pending.insert(op)
+            }
+        };
+        let (result, mut spare) = op.await;
+        self.pending = None;
+
+        if result >= 0 {
+            // SAFETY: the kernel initialized the first `result` bytes
+            unsafe { spare.set_len(result as usize) };
+        }
+        buffer.unsplit(spare);
+        match result {
+            n if n >= 0 => Ok(n as usize),
+            n if -n == libc::EAGAIN => socket.read_buf(buffer).await,
+            n => Err(io::Error::from_raw_os_error(-n)),
+        }
+    }
+}
+
+/// Frames being sent, with the message header and vectors pointing into
+/// them.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+struct SendBuffers {
+    frames: VecDeque<Bytes>,
+    iovecs: Box<[libc::iovec]>,
+    header: Box<libc::msghdr>,
+}
+
+// SAFETY: the raw pointers only point into the frames and vectors owned
+// alongside them
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+unsafe impl Send for SendBuffers {}
+
+/// Sends the first frames of `frames` to `socket` through io_uring, as
+/// `write_vectored` does.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub async fn send(socket: &mut OwnedWriteHalf, frames: &mut VecDeque<Bytes>) -> io::Result<usize> {
+    let fd = socket.as_ref().as_raw_fd();
+    let mut buffers = SendBuffers {
+        iovecs: frames
+            .iter()
+            .take(MAX_WRITE_FRAMES)
+            .map(|frame| libc::iovec {
+                iov_base: frame.as_ptr() as *mut _,
+                iov_len: frame.len(),
+            })
+            .collect(),
+        frames: std::mem::take(frames),
+        // SAFETY: a zeroed msghdr is an empty message
+        header: Box::new(unsafe { std::mem::zeroed() }),
+    };
+    buffers.header.msg_iov = buffers.iovecs.as_mut_ptr();
+    buffers.header.msg_iovlen = buffers.iovecs.len() as _;
+    let sqe = opcode::SendMsg::new(types::Fd(fd), &*buffers.header)
+        .flags(libc::MSG_NOSIGNAL as u32)
+        .build();
+
+    // SAFETY: the header, vectors and frames are heap allocations owned
+    // by `buffers`
+    let (result, buffers) = unsafe { Driver::current()?.start_op(sqe, buffers) }.await;
+    *frames = buffers.frames;
+    match result {
+        n if n >= 0 => Ok(n as usize),
+        n if -n == libc::EAGAIN => fallback_write(socket, frames).await,
+        n => Err(io::Error::from_raw_os_error(-n)),
+    }
+}
+
+/// Writes the first frames of `frames` through readiness polling.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+async fn fallback_write(
+    socket: &mut OwnedWriteHalf,
+    frames: &VecDeque<Bytes>,
+) -> io::Result<usize> {
+    let slices: Vec<_> = frames
+        .iter()
+        .take(MAX_WRITE_FRAMES)
+        .map(|frame| io::IoSlice::new(frame))
+        .collect();
+    socket.write_vectored(&slices).await
+}
+
+#[cfg(all(test, feature = "io-uring", target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_read_and_send() {
+        let (client, server) = socket_pair().await;
+        let (mut client_reader, mut client_writer) = client.into_split();
+        let (mut server_reader, mut server_writer) = server.into_split();
+
+        // A read waits for data to arrive
+        let mut reader = Reader::new();
+        let mut buffer = BytesMut::from(&b"left:"[..]);
+        buffer.reserve(64);
+        let sent = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            client_writer.write_all(b"PING\r\n").await.unwrap();
+            client_writer
+        });
+        let n = reader.read(&mut server_reader, &mut buffer).await.unwrap();
+        assert_eq!(n, 6);
+        assert_eq!(&buffer[..], b"left:PING\r\n");
+        let mut client_writer = sent.await.unwrap();
+
+        // A send larger than the socket buffers completes in parts
+        let payload: Vec<u8> = (0..4 << 20).map(|i| i as u8).collect();
+        let expected = payload.clone();
+        let received = tokio::spawn(async move {
+            let mut received = Vec::new();
+            client_reader.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let mut frames: VecDeque<Bytes> = payload
+            .chunks(100_000)
+            .map(Bytes::copy_from_slice)
+            .collect();
+        while !frames.is_empty() {
+            let mut written = send(&mut server_writer, &mut frames).await.unwrap();
+            while let Some(frame) = frames.front_mut() {
+                if frame.len() > written {
+                    bytes::Buf::advance(frame, written);
+                    break;
+                }
+                written -= frame.len();
+                frames.pop_front();
+            }
+        }
+        drop(server_writer);
+        assert_eq!(received.await.unwrap(), expected);
+        client_writer.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_read_resumes() {
+        let (mut client, server) = socket_pair().await;
+        let (mut server_reader, _server_writer) = server.into_split();
+        let mut reader = Reader::new();
+        let mut buffer = BytesMut::with_capacity(64);
+
+        // Dropping the read's future leaves the receive in flight
+        let read = reader.read(&mut server_reader, &mut buffer);
+        assert!(tokio::time::timeout(Duration::from_millis(20), read)
+            .await
+            .is_err());
+        assert!(reader.pending.is_some());
+
+        // What arrives meanwhile goes to the next read
+        client.write_all(b"GET key\r\n").await.unwrap();
+        let n = reader.read(&mut server_reader, &mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"GET key\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_dropped_read_is_cancelled() {
+        let (mut client, server) = socket_pair().await;
+        let (mut server_reader, server_writer) = server.into_split();
+        let mut reader = Reader::new();
+        let mut buffer = BytesMut::with_capacity(64);
+        let read = reader.read(&mut server_reader, &mut buffer);
+        assert!(tokio::time::timeout(Duration::from_millis(20), read)
+            .await
+            .is_err());
+
+        // The kernel lets go of the socket once the receive is cancelled,
+        // so closing it reaches the client
+        drop(reader);
+        drop(server_reader);
+        drop(server_writer);
+        let mut rest = Vec::new();
+        let closed = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest));
+        assert_eq!(closed.await.unwrap().unwrap(), 0);
+    }
+}
@@ -0,0 +1,178 @@
+//! Connection Writer
+//!
+//! Each connection's socket is split in two. The handler keeps the read
+//! half, and a writer task owns the write half, writing whatever frames
+//! are queued for the client:
+//!
+//! ```text
+//!  replies ───────────────┐
+//!  pub/sub messages ──────┤   outbound queue    ┌──────────────┐
+//!  invalidations ─────────┼──────────────────>  │ writer task  │ ──> socket
+//!  replication stream ────┘                     └──────────────┘
+//! ```
+//!
+//! Anything holding an [`Outbound`] can therefore send the client a frame at
+//! any time, not just in reply to a command. The handler queues the replies
+//! to a pipeline of commands as one frame, and whatever else piles up in
+//! the queue meanwhile shares its write.
+//!
+//! Frames are handed to the socket with vectored writes, rather than copied
+//! through a `BufWriter`. A frame can also be queued in pieces, so a reply
+//! carrying a multi-megabyte value goes out without that value ever being
+//! copied into a buffer.
+
+use super::handler::{ConnectionError, ConnectionStats};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use super::uring;
+use bytes::{Buf, Bytes};
+use std::collections::VecDeque;
+use std::io::{self, IoSlice};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// Maximum number of frames queued for a client before senders wait
+const OUTBOUND_CAPACITY: usize = 64;
+
+/// Maximum number of frames handed to the socket in one write
+pub(super) const MAX_WRITE_FRAMES: usize = 64;
+
+/// A handle for sending frames to a client.
+///
+/// Frames are RESP, already serialized. They are written in the order they
+/// were sent; once the queue is full, senders wait for the client to catch
+/// up.
+#[derive(Debug, Clone)]
+pub struct Outbound {
+    sender: mpsc::Sender<Queued>,
+}
+
+/// An entry in the outbound queue.
+#[derive(Debug)]
+enum Queued {
+    /// A frame
+    Frame(Bytes),
+
+    /// Pieces of one frame, written back to back
+    Pieces(Vec<Bytes>),
+}
+
+impl Outbound {
+    /// Queues `frame` to be written to the client.
+    ///
+    /// Fails with a broken pipe once the writer has stopped, as when a
+    /// write to the socket failed.
+    pub async fn send(&self, frame: Bytes) -> Result<(), ConnectionError> {
+        self.queue(Queued::Frame(frame)).await
+    }
+
+    /// Queues a frame made of `pieces`, such as a reply with a large value
+    /// that wasn't copied into the rest of it. Nothing sent through other
+    /// handles is written between the pieces.
+    pub async fn send_pieces(&self, pieces: Vec<Bytes>) -> Result<(), ConnectionError> {
+        self.queue(Queued::Pieces(pieces)).await
+    }
+
+    async fn queue(&self, queued: Queued) -> Result<(), ConnectionError> {
+        self.sender
+            .send(queued)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe).into())
+    }
+}
+
+/// Spawns the task writing the frames sent through the returned
+/// [`Outbound`] to `stream`.
+///
+/// The task ends once every `Outbound` is dropped and the queue is written,
+/// or at the first failed write, which it returns.
+pub fn spawn_writer(
+    stream: OwnedWriteHalf,
+    addr: SocketAddr,
+    stats: Arc<ConnectionStats>,
+) -> (Outbound, JoinHandle<io::Result<()>>) {
+    let (sender, queue) = mpsc::channel(OUTBOUND_CAPACITY);
+    let writer = tokio::spawn(async move {
+        let result = write_frames(stream, queue, &stats).await;
+        if let Err(e) = &result {
+            debug!(client = %addr, error = %e, "Write failed");
+        }
+        result
+    });
+    (Outbound { sender }, writer)
+}
+
+/// Writes queued frames until the queue closes.
+async fn write_frames(
+    mut stream: OwnedWriteHalf,
+    mut queue: mpsc::Receiver<Queued>,
+    stats: &ConnectionStats,
+) -> io::Result<()> {
+    let mut batch = VecDeque::with_capacity(MAX_WRITE_FRAMES);
+    while let Some(queued) = queue.recv().await {
+        push_queued(&mut batch, queued);
+        // Take whatever else is queued, so it shares the write
+        while batch.len() < MAX_WRITE_FRAMES {
+            match queue.try_recv() {
+                Ok(queued) => push_queued(&mut batch, queued),
+                Err(_) => break,
+            }
+        }
+        write_batch(&mut stream, &mut batch, stats).await?;
+    }
+    Ok(())
+}
+
+/// Adds what was queued to the frames to write.
+fn push_queued(batch: &mut VecDeque<Bytes>, queued: Queued) {
+    match queued {
+        Queued::Frame(frame) => batch.push_back(frame),
+        Queued::Pieces(pieces) => batch.extend(pieces),
+    }
+}
+
+/// Writes the first frames of `batch` in one vectored write, returning
+/// how many bytes the socket took.
+async fn write_some(stream: &mut OwnedWriteHalf, batch: &mut VecDeque<Bytes>) -> io::Result<usize> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if uring::is_enabled() {
+        return uring::send(stream, batch).await;
+    }
+    let mut slices = [IoSlice::new(&[]); MAX_WRITE_FRAMES];
+    let count = batch.len().min(MAX_WRITE_FRAMES);
+    for (slice, frame) in slices.iter_mut().zip(batch.iter()) {
+        *slice = IoSlice::new(frame);
+    }
+    stream.write_vectored(&slices[..count]).await
+}
+
+/// Writes every frame in `batch` to the socket, in as few vectored writes
+/// as it accepts.
+async fn write_batch(
+    stream: &mut OwnedWriteHalf,
+    batch: &mut VecDeque<Bytes>,
+    stats: &ConnectionStats,
+) -> io::Result<()> {
+    while !batch.is_empty() {
+        let mut written = write_some(stream, batch).await?;
+        if written == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        stats.bytes_written(written);
+
+        // Drop the frames written, and the start of one written in part
+        while let Some(frame) = batch.front_mut() {
+            if frame.len() > written {
+                frame.advance(written);
+                break;
+            }
+            written -= frame.len();
+            batch.pop_front();
+        }
+    }
+    Ok(())
+}
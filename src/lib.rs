@@ -111,6 +111,10 @@
 //! - [`storage`]: Thread-safe storage engine with TTL support
 //! - [`commands`]: Command handlers for all supported Redis commands
 //! - [`connection`]: Client connection management
+//! - [`replication`]: Streaming writes to replicas
+//! - [`cluster`]: Hash slots and redirects for cluster mode
+//! - [`pubsub`]: Publish/subscribe channels and patterns
+//! - [`scripting`]: Lua function libraries for FUNCTION and FCALL
 //!
 //! ## Design Highlights
 //!
@@ -133,9 +137,13 @@
 //!
 //! This ensures memory is reclaimed even for keys that are never accessed again.
 
+pub mod cluster;
 pub mod commands;
 pub mod connection;
 pub mod protocol;
+pub mod pubsub;
+pub mod replication;
+pub mod scripting;
 pub mod storage;
 
 // Re-export commonly used types for convenience
@@ -3,28 +3,153 @@
 //! This is the main entry point for the FlashKV server.
 //! It sets up the TCP listener, storage engine, and handles incoming connections.
 
-use flashkv::commands::CommandHandler;
-use flashkv::connection::{handle_connection, ConnectionStats};
-use flashkv::storage::{start_expiry_sweeper, StorageEngine};
+use bytes::Bytes;
+use flashkv::cluster::parse_slot_ranges;
+use flashkv::commands::{CommandHandler, CommandRenames};
+use flashkv::connection::{
+    allowed_in_protected_mode, bind_shared, default_reactor_count, handle_connection,
+    refuse_connection, uring, ConnectionStats,
+};
+use flashkv::protocol::{ProtocolLimits, RespValue};
+use flashkv::pubsub::NotifyFlags;
+use flashkv::replication::{start_replica_link, MasterAddr, DEFAULT_BACKLOG_SIZE};
+use flashkv::scripting::DEFAULT_BUSY_REPLY_THRESHOLD;
+use flashkv::storage::aof::{self, DEFAULT_APPENDFILENAME};
+use flashkv::storage::eviction::{
+    self, parse_maxmemory, EvictionPolicy, DEFAULT_LFU_DECAY_TIME, DEFAULT_LFU_LOG_FACTOR,
+};
+use flashkv::storage::rdb;
+use flashkv::storage::snapshot::DEFAULT_DBFILENAME;
+use flashkv::storage::{
+    start_expiry_sweeper, start_save_scheduler, AppendFsync, Codec, Compression, SaveRule,
+    ShardLocking, StorageEngine, DEFAULT_DATABASES, MAX_SHARDS,
+};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::net::TcpListener;
 use tokio::signal;
-use tracing::{error, info, Level};
+use tokio::sync::watch;
+use tracing::{error, info, warn, Level};
 use tracing_subscriber::FmtSubscriber;
 
 /// Server configuration
 struct Config {
-    /// Host to bind to
-    host: String,
+    /// Addresses to listen on
+    bind: Vec<String>,
     /// Port to listen on
     port: u16,
+    /// Accept loops with a runtime of their own (1 = the shared runtime)
+    reactors: usize,
+    /// Whether connections read and write through io_uring
+    io_uring: bool,
+    /// Whether only loopback clients may connect while the server listens
+    /// on other interfaces
+    protected_mode: bool,
+    /// Number of databases
+    databases: usize,
+    /// Number of shards per database, or the locking's default
+    shards: Option<usize>,
+    /// How each shard is locked
+    shard_locking: ShardLocking,
+    /// Keys database 0 is sized for up front and after each flush
+    expected_keys: usize,
+    /// Directory holding the snapshot file
+    dir: PathBuf,
+    /// Name of the snapshot file
+    dbfilename: String,
+    /// Rules triggering automatic background saves
+    save_rules: Vec<SaveRule>,
+    /// Redis RDB file to import at startup
+    load_rdb: Option<PathBuf>,
+    /// Whether write commands are logged to the append-only file
+    appendonly: bool,
+    /// Name of the append-only file
+    appendfilename: String,
+    /// When the append-only file is flushed to disk
+    appendfsync: AppendFsync,
+    /// Whether an incomplete last command in the append-only file is dropped
+    aof_load_truncated: bool,
+    /// How snapshots and append-only file preambles are compressed
+    compression: Compression,
+    /// Size of the replication backlog in bytes
+    repl_backlog_size: usize,
+    /// Master to replicate at startup
+    replicaof: Option<MasterAddr>,
+    /// Whether to run in cluster mode
+    cluster_enabled: bool,
+    /// Hash slots this server serves in cluster mode
+    cluster_slots: Vec<(u16, u16)>,
+    /// Other nodes of the cluster and the slots they serve
+    cluster_nodes: Vec<ClusterNode>,
+    /// Keyspace events published to pub/sub clients
+    notify_keyspace_events: NotifyFlags,
+    /// Whether clients may only read
+    read_only: bool,
+    /// Most bytes the keyspace may use (0 = no limit)
+    maxmemory: u64,
+    /// Which keys are evicted to stay within `maxmemory`
+    maxmemory_policy: EvictionPolicy,
+    /// How quickly the access-frequency counters saturate
+    lfu_log_factor: u32,
+    /// Idle minutes per decrement of the access-frequency counters
+    lfu_decay_time: u64,
+    /// Milliseconds a function runs before other calls get BUSY
+    busy_reply_threshold: u64,
+    /// Whether short keys are copied into shared chunks
+    compact_keys: bool,
+    /// Commands clients know under another name, or not at all
+    renames: CommandRenames,
+    /// What client requests may contain
+    protocol_limits: ProtocolLimits,
+}
+
+/// Another node of the cluster, as given with `--cluster-node`
+struct ClusterNode {
+    /// Host clients reach the node at
+    host: String,
+    /// Port clients reach the node at
+    port: u16,
+    /// Hash slots the node serves
+    slots: Vec<(u16, u16)>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
-            host: "127.0.0.1".to_string(),
+            bind: vec!["127.0.0.1".to_string()],
             port: 6379,
+            reactors: 1,
+            io_uring: false,
+            protected_mode: true,
+            databases: DEFAULT_DATABASES,
+            shards: None,
+            shard_locking: ShardLocking::default(),
+            expected_keys: 0,
+            dir: PathBuf::from("."),
+            dbfilename: DEFAULT_DBFILENAME.to_string(),
+            save_rules: SaveRule::DEFAULTS.to_vec(),
+            load_rdb: None,
+            appendonly: false,
+            appendfilename: DEFAULT_APPENDFILENAME.to_string(),
+            appendfsync: AppendFsync::default(),
+            aof_load_truncated: true,
+            compression: Compression::NONE,
+            repl_backlog_size: DEFAULT_BACKLOG_SIZE,
+            replicaof: None,
+            cluster_enabled: false,
+            cluster_slots: Vec::new(),
+            cluster_nodes: Vec::new(),
+            notify_keyspace_events: NotifyFlags::default(),
+            read_only: false,
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::default(),
+            lfu_log_factor: DEFAULT_LFU_LOG_FACTOR,
+            lfu_decay_time: DEFAULT_LFU_DECAY_TIME,
+            busy_reply_threshold: DEFAULT_BUSY_REPLY_THRESHOLD,
+            compact_keys: false,
+            renames: CommandRenames::new(),
+            protocol_limits: ProtocolLimits::default(),
         }
     }
 }
@@ -38,15 +163,23 @@ impl Config {
         let mut i = 1;
         while i < args.len() {
             match args[i].as_str() {
-                "--host" | "-h" => {
+                "--bind" | "--host" | "-h" => {
                     if i + 1 < args.len() {
-                        config.host = args[i + 1].clone();
+                        config.bind = args[i + 1].split_whitespace().map(String::from).collect();
+                        if config.bind.is_empty() {
+                            eprintln!("Error: {} needs at least one address", args[i]);
+                            std::process::exit(1);
+                        }
                         i += 2;
                     } else {
-                        eprintln!("Error: --host requires a value");
+                        eprintln!("Error: {} requires a value", args[i]);
                         std::process::exit(1);
                     }
                 }
+                "--protected-mode" => {
+                    config.protected_mode = yes_no_arg(&args, i);
+                    i += 2;
+                }
                 "--port" | "-p" => {
                     if i + 1 < args.len() {
                         config.port = args[i + 1].parse().unwrap_or_else(|_| {
@@ -59,6 +192,294 @@ impl Config {
                         std::process::exit(1);
                     }
                 }
+                "--reactors" => {
+                    config.reactors = match value_arg(&args, i, |v| v.parse().ok()) {
+                        0 => default_reactor_count(),
+                        reactors => reactors,
+                    };
+                    i += 2;
+                }
+                "--io-uring" => {
+                    config.io_uring = yes_no_arg(&args, i);
+                    if config.io_uring && !uring::is_available() {
+                        eprintln!(
+                            "Error: FlashKV was built without io_uring support (enable the `io-uring` feature, on Linux)"
+                        );
+                        std::process::exit(1);
+                    }
+                    i += 2;
+                }
+                "--databases" => {
+                    if i + 1 < args.len() {
+                        config.databases = match args[i + 1].parse() {
+                            Ok(databases) if databases > 0 => databases,
+                            _ => {
+                                eprintln!("Error: --databases must be a positive number");
+                                std::process::exit(1);
+                            }
+                        };
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --databases requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--shards" => {
+                    config.shards = Some(value_arg(&args, i, parse_shards));
+                    i += 2;
+                }
+                "--shard-locking" => {
+                    config.shard_locking = value_arg(&args, i, ShardLocking::parse);
+                    i += 2;
+                }
+                "--expected-keys" => {
+                    config.expected_keys = value_arg(&args, i, |v| v.parse().ok());
+                    i += 2;
+                }
+                "--dir" => {
+                    if i + 1 < args.len() {
+                        config.dir = PathBuf::from(&args[i + 1]);
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --dir requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--dbfilename" => {
+                    if i + 1 < args.len() {
+                        config.dbfilename = args[i + 1].clone();
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --dbfilename requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--save" => {
+                    if i + 1 < args.len() {
+                        config.save_rules =
+                            SaveRule::parse_list(&args[i + 1]).unwrap_or_else(|| {
+                                eprintln!("Error: invalid save rules");
+                                std::process::exit(1);
+                            });
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --save requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--load-rdb" => {
+                    if i + 1 < args.len() {
+                        config.load_rdb = Some(PathBuf::from(&args[i + 1]));
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --load-rdb requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--appendonly" => {
+                    config.appendonly = yes_no_arg(&args, i);
+                    i += 2;
+                }
+                "--aof-load-truncated" => {
+                    config.aof_load_truncated = yes_no_arg(&args, i);
+                    i += 2;
+                }
+                "--appendfilename" => {
+                    if i + 1 < args.len() {
+                        config.appendfilename = args[i + 1].clone();
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --appendfilename requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--appendfsync" => {
+                    if i + 1 < args.len() {
+                        config.appendfsync = args[i + 1].parse().unwrap_or_else(|_| {
+                            eprintln!("Error: --appendfsync must be always, everysec, no or group");
+                            std::process::exit(1);
+                        });
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --appendfsync requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--compression" => {
+                    if i + 1 < args.len() {
+                        let codec: Codec = args[i + 1].parse().unwrap_or_else(|_| {
+                            eprintln!("Error: --compression must be none, zstd or lz4");
+                            std::process::exit(1);
+                        });
+                        if !codec.is_available() {
+                            eprintln!(
+                                "Error: FlashKV was built without {} support (enable the `{}` feature)",
+                                codec,
+                                codec.feature().unwrap_or_default()
+                            );
+                            std::process::exit(1);
+                        }
+                        config.compression.codec = codec;
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --compression requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--compression-level" => {
+                    if i + 1 < args.len() {
+                        config.compression.level = args[i + 1].parse().unwrap_or_else(|_| {
+                            eprintln!("Error: invalid compression level");
+                            std::process::exit(1);
+                        });
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --compression-level requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--repl-backlog-size" => {
+                    if i + 1 < args.len() {
+                        config.repl_backlog_size = parse_bytes(&args[i + 1]).unwrap_or_else(|| {
+                            eprintln!("Error: invalid --repl-backlog-size");
+                            std::process::exit(1);
+                        });
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --repl-backlog-size requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--replicaof" => {
+                    if i + 1 < args.len() {
+                        config.replicaof = Some(parse_master(&args[i + 1]).unwrap_or_else(|| {
+                            eprintln!("Error: --replicaof must be \"<host> <port>\"");
+                            std::process::exit(1);
+                        }));
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --replicaof requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--cluster-enabled" => {
+                    config.cluster_enabled = yes_no_arg(&args, i);
+                    i += 2;
+                }
+                "--cluster-slots" => {
+                    if i + 1 < args.len() {
+                        config.cluster_slots =
+                            parse_slot_ranges(&args[i + 1]).unwrap_or_else(|e| {
+                                eprintln!("Error: --cluster-slots: {}", e);
+                                std::process::exit(1);
+                            });
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --cluster-slots requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--cluster-node" => {
+                    if i + 1 < args.len() {
+                        let node = parse_cluster_node(&args[i + 1]).unwrap_or_else(|| {
+                            eprintln!("Error: --cluster-node must be \"<host>:<port> <slots>\"");
+                            std::process::exit(1);
+                        });
+                        config.cluster_nodes.push(node);
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --cluster-node requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--notify-keyspace-events" => {
+                    if i + 1 < args.len() {
+                        config.notify_keyspace_events = NotifyFlags::parse(&args[i + 1])
+                            .unwrap_or_else(|| {
+                                eprintln!("Error: invalid --notify-keyspace-events");
+                                std::process::exit(1);
+                            });
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --notify-keyspace-events requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--read-only" => {
+                    config.read_only = yes_no_arg(&args, i);
+                    i += 2;
+                }
+                "--maxmemory" => {
+                    config.maxmemory = value_arg(&args, i, parse_maxmemory);
+                    i += 2;
+                }
+                "--maxmemory-policy" => {
+                    config.maxmemory_policy = value_arg(&args, i, EvictionPolicy::parse);
+                    i += 2;
+                }
+                "--lfu-log-factor" => {
+                    config.lfu_log_factor = value_arg(&args, i, |v| v.parse().ok());
+                    i += 2;
+                }
+                "--lfu-decay-time" => {
+                    config.lfu_decay_time = value_arg(&args, i, |v| v.parse().ok());
+                    i += 2;
+                }
+                "--busy-reply-threshold" => {
+                    config.busy_reply_threshold = value_arg(&args, i, |v| v.parse().ok());
+                    i += 2;
+                }
+                "--compact-keys" => {
+                    config.compact_keys = yes_no_arg(&args, i);
+                    i += 2;
+                }
+                "--rename-command" => {
+                    if i + 1 < args.len() {
+                        let mut names = args[i + 1].split_whitespace();
+                        let Some(command) = names.next() else {
+                            eprintln!("Error: --rename-command must be \"<COMMAND> <NEW-NAME>\"");
+                            std::process::exit(1);
+                        };
+                        config
+                            .renames
+                            .rename(command, names.next().unwrap_or_default());
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --rename-command requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--disabled-commands" => {
+                    if i + 1 < args.len() {
+                        for command in args[i + 1].split_whitespace() {
+                            config.renames.disable(command);
+                        }
+                        i += 2;
+                    } else {
+                        eprintln!("Error: --disabled-commands requires a value");
+                        std::process::exit(1);
+                    }
+                }
+                "--proto-max-bulk-len" => {
+                    config.protocol_limits.max_bulk_len = value_arg(&args, i, parse_bytes);
+                    i += 2;
+                }
+                "--proto-max-multibulk-len" => {
+                    config.protocol_limits.max_multibulk_len = value_arg(&args, i, parse_count);
+                    i += 2;
+                }
+                "--proto-max-inline-len" => {
+                    config.protocol_limits.max_inline_len = value_arg(&args, i, parse_bytes);
+                    i += 2;
+                }
+                "--proto-max-nesting-depth" => {
+                    config.protocol_limits.max_nesting_depth = value_arg(&args, i, parse_count);
+                    i += 2;
+                }
+                "--client-query-buffer-limit" => {
+                    config.protocol_limits.max_query_buffer = value_arg(&args, i, parse_bytes);
+                    i += 2;
+                }
                 "--help" => {
                     print_help();
                     std::process::exit(0);
@@ -78,12 +499,114 @@ impl Config {
         config
     }
 
-    /// Returns the bind address as a string
-    fn bind_address(&self) -> String {
-        format!("{}:{}", self.host, self.port)
+    /// Returns the addresses to listen on, with the port
+    fn bind_addresses(&self) -> Vec<String> {
+        self.bind
+            .iter()
+            .map(|host| {
+                // IPv6 addresses are bracketed to set them apart from the port
+                if host.contains(':') {
+                    format!("[{}]:{}", host, self.port)
+                } else {
+                    format!("{}:{}", host, self.port)
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the path of the snapshot file
+    fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    /// Returns the path of the append-only file
+    fn aof_path(&self) -> PathBuf {
+        self.dir.join(&self.appendfilename)
+    }
+}
+
+/// Parses the yes/no value of the flag at `args[i]`, exiting on error.
+fn yes_no_arg(args: &[String], i: usize) -> bool {
+    match args.get(i + 1).map(|v| v.to_lowercase()).as_deref() {
+        Some("yes") => true,
+        Some("no") => false,
+        Some(_) => {
+            eprintln!("Error: {} must be yes or no", args[i]);
+            std::process::exit(1);
+        }
+        None => {
+            eprintln!("Error: {} requires a value", args[i]);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parses the value given to the flag at `args[i]`, exiting on error.
+fn value_arg<T>(args: &[String], i: usize, parse: impl Fn(&str) -> Option<T>) -> T {
+    match args.get(i + 1).map(|v| parse(v)) {
+        Some(Some(value)) => value,
+        Some(None) => {
+            eprintln!("Error: invalid {}", args[i]);
+            std::process::exit(1);
+        }
+        None => {
+            eprintln!("Error: {} requires a value", args[i]);
+            std::process::exit(1);
+        }
     }
 }
 
+/// Parses a positive count.
+fn parse_count(value: &str) -> Option<usize> {
+    value.parse().ok().filter(|&n| n > 0)
+}
+
+/// Parses a shard count: a power of two up to [`MAX_SHARDS`].
+fn parse_shards(value: &str) -> Option<usize> {
+    parse_count(value).filter(|n| n.is_power_of_two() && *n <= MAX_SHARDS)
+}
+
+/// Parses a `"<host> <port>"` master address.
+fn parse_master(value: &str) -> Option<MasterAddr> {
+    let (host, port) = value.trim().split_once(' ')?;
+    Some(MasterAddr {
+        host: host.to_string(),
+        port: port.trim().parse().ok()?,
+    })
+}
+
+/// Parses a `"<host>:<port> <slots>"` cluster node.
+fn parse_cluster_node(value: &str) -> Option<ClusterNode> {
+    let (addr, slots) = value.trim().split_once(' ')?;
+    let (host, port) = addr.rsplit_once(':')?;
+    Some(ClusterNode {
+        host: host.to_string(),
+        port: port.parse().ok()?,
+        slots: parse_slot_ranges(slots).ok()?,
+    })
+}
+
+/// Parses a size in bytes, with an optional `kb`, `mb` or `gb` suffix.
+fn parse_bytes(value: &str) -> Option<usize> {
+    let value = value.to_ascii_lowercase();
+    let (digits, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => value.split_at(at),
+        None => (value.as_str(), ""),
+    };
+    let unit = match unit {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .filter(|&n| n > 0)?
+        .checked_mul(unit)
+}
+
 fn print_help() {
     println!(
         r#"
@@ -93,15 +616,129 @@ USAGE:
     flashkv [OPTIONS]
 
 OPTIONS:
-    -h, --host <HOST>    Host to bind to (default: 127.0.0.1)
+    -h, --bind <ADDRS>   Addresses to listen on, separated by spaces
+                         (default: 127.0.0.1; --host is an alias)
+        --protected-mode <yes|no>
+                         Only accept clients on the loopback interface when
+                         listening on other addresses (default: yes)
     -p, --port <PORT>    Port to listen on (default: 6379)
+        --reactors <N>   Accept clients on N threads with a runtime each,
+                         sharing the port with SO_REUSEPORT; 0 for one per
+                         CPU (default: 1, a single shared runtime)
+        --io-uring <yes|no>
+                         Read from and write to client sockets through
+                         io_uring; needs Linux and the `io-uring` feature
+                         (default: no)
+        --databases <N>  Number of databases clients can SELECT (default: 16)
+        --shards <N>     Shards per database, a power of two; more shards
+                         mean less lock contention (default: 4 per CPU,
+                         between 16 and 1024; with bucket locking, 64 per
+                         CPU, between 256 and 16384)
+        --shard-locking <rwlock|bucket>
+                         Lock each shard with a reader-writer lock, or lock
+                         many small buckets with a mutex each (default:
+                         rwlock)
+        --expected-keys <N>
+                         Size database 0 for this many keys up front, and
+                         again after each flush, so a bulk load doesn't keep
+                         growing its tables (default: 0)
+        --dir <DIR>      Directory for the snapshot file (default: .)
+        --dbfilename <NAME>
+                         Snapshot file name (default: dump.fkv)
+        --save <RULES>   Save after <seconds> <changes> pairs, "" to disable
+                         (default: "3600 1 300 100 60 10000")
+        --load-rdb <FILE>
+                         Import a Redis RDB dump at startup
+        --appendonly <yes|no>
+                         Log every write to the append-only file (default: no)
+        --appendfilename <NAME>
+                         Append-only file name (default: appendonly.aof)
+        --appendfsync <always|everysec|no|group>
+                         When to flush the append-only file (default: everysec);
+                         group commits writes in batches and replies once
+                         they are on disk
+        --aof-load-truncated <yes|no>
+                         Drop an incomplete last command in the append-only
+                         file instead of refusing to start (default: yes)
+        --compression <none|zstd|lz4>
+                         Compress snapshots and append-only file rewrites
+                         (default: none; needs the matching cargo feature)
+        --compression-level <LEVEL>
+                         zstd compression level (default: 3)
+        --repl-backlog-size <SIZE>
+                         Bytes of recent writes kept so replicas can resume
+                         after a disconnect, e.g. 64mb (default: 1mb)
+        --replicaof "<HOST> <PORT>"
+                         Start as a read-only replica of another server
+        --cluster-enabled <yes|no>
+                         Serve only the hash slots given below, redirecting
+                         clients for the others (default: no)
+        --cluster-slots "<SLOTS>"
+                         Hash slots this server serves, e.g. "0-5460"
+        --cluster-node "<HOST>:<PORT> <SLOTS>"
+                         Another node and its slots (repeatable)
+        --notify-keyspace-events <FLAGS>
+                         Publish changes to keys to pub/sub channels, e.g.
+                         "Ex" for expired keys (default: "", disabled)
+        --read-only <yes|no>
+                         Reject writes from clients while still serving
+                         reads; CONFIG SET read-only turns it off (default: no)
+        --maxmemory <SIZE>
+                         Evict keys, or refuse writes, once the keyspace uses
+                         this much memory, e.g. 1gb (default: 0, no limit)
+        --maxmemory-policy <noeviction|allkeys-lfu|volatile-lfu>
+                         Which keys are evicted past --maxmemory: none, the
+                         least frequently used, or those among keys with an
+                         expiry (default: noeviction)
+        --lfu-log-factor <N>
+                         How many accesses it takes to raise a key's access
+                         frequency; higher is slower (default: 10)
+        --lfu-decay-time <MINUTES>
+                         Idle minutes per drop of a key's access frequency,
+                         0 to never drop (default: 1)
+        --busy-reply-threshold <MS>
+                         How long a function runs before other FCALLs get a
+                         BUSY error and FUNCTION KILL is needed (default: 5000)
+        --compact-keys <yes|no>
+                         Store keys of up to 64 bytes packed into shared
+                         chunks rather than one allocation each; MEMORY STATS
+                         shows the savings (default: no)
+        --rename-command "<COMMAND> <NEW-NAME>"
+                         Make clients call a command by another name, or
+                         not at all if the new name is left out (repeatable)
+        --disabled-commands "<COMMANDS>"
+                         Commands clients can't call, e.g. "FLUSHALL DEBUG"
+        --proto-max-bulk-len <SIZE>
+                         Longest string a client may send (default: 512mb)
+        --proto-max-multibulk-len <N>
+                         Most arguments in a request (default: 1048576)
+        --proto-max-inline-len <SIZE>
+                         Longest inline command (default: 64kb)
+        --proto-max-nesting-depth <N>
+                         Deepest nesting of arrays in a request (default: 32)
+        --client-query-buffer-limit <SIZE>
+                         Most unparsed data buffered for a client; clients
+                         going over it are disconnected (default: 1gb)
     -v, --version        Print version information
         --help           Print this help message
 
 EXAMPLES:
     flashkv                        # Start on 127.0.0.1:6379
     flashkv --port 6380            # Start on port 6380
-    flashkv --host 0.0.0.0         # Listen on all interfaces
+    flashkv --bind "127.0.0.1 ::1" # Listen on IPv4 and IPv6 loopback
+    flashkv --bind 0.0.0.0 --protected-mode no
+                                   # Accept clients on all interfaces
+    flashkv --dir /var/lib/flashkv # Keep snapshots in /var/lib/flashkv
+    flashkv --save "900 1"         # Save every 15 minutes if anything changed
+    flashkv --load-rdb dump.rdb    # Import an existing Redis dataset
+    flashkv --appendonly yes       # Lose at most a second of writes
+    flashkv --port 6380 --replicaof "127.0.0.1 6379"
+                                   # Replicate the server on port 6379
+    flashkv --port 7000 --cluster-enabled yes --cluster-slots "0-8191" \
+            --cluster-node "127.0.0.1:7001 8192-16383"
+                                   # Serve half of a two-node cluster
+    flashkv --rename-command "KEYS obscure-keys" --disabled-commands FLUSHALL
+                                   # Hide dangerous commands from clients
 
 CONNECTING:
     Use redis-cli or any Redis client to connect:
@@ -138,7 +775,7 @@ Ready to accept connections.
 Use Ctrl+C to shutdown gracefully.
 "#,
         flashkv::VERSION,
-        config.bind_address()
+        config.bind_addresses().join(", ")
     );
 }
 
@@ -148,7 +785,7 @@ async fn main() -> anyhow::Result<()> {
     let config = Config::from_args();
 
     // Set up logging
-    let _subscriber = FmtSubscriber::builder()
+    FmtSubscriber::builder()
         .with_max_level(Level::INFO)
         .with_target(false)
         .with_thread_ids(false)
@@ -160,19 +797,194 @@ async fn main() -> anyhow::Result<()> {
     print_banner(&config);
 
     // Create the storage engine (shared across all connections)
-    let storage = Arc::new(StorageEngine::new());
-    info!("Storage engine initialized with 64 shards");
+    let shards = config
+        .shards
+        .unwrap_or_else(|| config.shard_locking.default_shard_count());
+    let storage = Arc::new(StorageEngine::with_locking(
+        config.databases,
+        shards,
+        config.shard_locking,
+    ));
+    info!(
+        "Storage engine initialized with {} databases of {} shards ({} locking)",
+        config.databases,
+        storage.shard_count(),
+        config.shard_locking
+    );
+    // Before anything is loaded, so every key is stored the same way
+    storage.set_compact_keys(config.compact_keys);
+    if config.expected_keys > 0 {
+        storage.set_expected_keys(config.expected_keys);
+    }
+
+    // Load the append-only file if there is one (it is more recent than any
+    // snapshot), otherwise the last snapshot
+    let snapshot_path = config.snapshot_path();
+    let aof_path = config.aof_path();
+    let aof_exists = config.appendonly && aof_path.exists();
+    storage.snapshots().set_path(&snapshot_path);
+    storage.snapshots().set_rules(config.save_rules.clone());
+    storage.snapshots().set_compression(config.compression);
+    storage
+        .replication()
+        .set_backlog_size(config.repl_backlog_size);
+    if aof_exists {
+        let handler = CommandHandler::new(Arc::clone(&storage));
+        let replay = |command: Vec<Bytes>| {
+            handler.execute(RespValue::array(
+                command.into_iter().map(RespValue::bulk_string).collect(),
+            ));
+        };
+        match aof::load(&storage, &aof_path, config.aof_load_truncated, replay) {
+            Ok(report) => {
+                if let Some((offset, dropped)) = report.truncated {
+                    warn!(
+                        "{} ended with an incomplete command: dropped {} bytes at byte {}",
+                        aof_path.display(),
+                        dropped,
+                        offset
+                    );
+                }
+                info!(
+                    "Loaded {} keys and replayed {} commands from {}",
+                    report.keys,
+                    report.commands,
+                    aof_path.display()
+                );
+            }
+            Err(e) => {
+                error!("Failed to load {}: {}", aof_path.display(), e);
+                return Err(e.into());
+            }
+        }
+    } else {
+        match storage.load() {
+            Ok(keys) => info!("Loaded {} keys from {}", keys, snapshot_path.display()),
+            Err(e) if e.is_not_found() => info!("No snapshot at {}", snapshot_path.display()),
+            Err(e) => {
+                error!("Failed to load {}: {}", snapshot_path.display(), e);
+                return Err(e.into());
+            }
+        }
+    }
+
+    // Import a Redis dump on top of it, if asked to
+    if let Some(rdb_path) = &config.load_rdb {
+        match rdb::load(&storage, rdb_path) {
+            Ok(keys) => info!("Imported {} keys from {}", keys, rdb_path.display()),
+            Err(e) => {
+                error!("Failed to import {}: {}", rdb_path.display(), e);
+                return Err(e.into());
+            }
+        }
+    }
+
+    // Start logging writes. A new log (or one missing imported keys) starts
+    // with a snapshot of what was loaded
+    if config.appendonly {
+        storage.aof().open(&aof_path, config.appendfsync)?;
+        if !aof_exists || config.load_rdb.is_some() {
+            storage.rewrite_aof()?;
+        }
+        info!("Appending writes to {}", aof_path.display());
+    }
+
+    // Publish keyspace events from here on
+    storage
+        .pubsub()
+        .set_notify_flags(config.notify_keyspace_events);
+
+    // Refuse writes from here on, once the append-only file has replayed
+    if config.read_only {
+        storage.set_read_only(true);
+        info!("Read-only mode is on, clients can't write (CONFIG SET read-only no)");
+    }
+
+    // Likewise, loading never evicts: the limit holds from here on
+    eviction::set_lfu_log_factor(config.lfu_log_factor);
+    eviction::set_lfu_decay_time(config.lfu_decay_time);
+    storage
+        .functions()
+        .set_busy_reply_threshold(config.busy_reply_threshold);
+    storage.eviction().set_policy(config.maxmemory_policy);
+    if config.maxmemory > 0 {
+        storage.eviction().set_maxmemory(config.maxmemory);
+        info!(
+            "Memory limited to {} bytes, evicting with {}",
+            config.maxmemory, config.maxmemory_policy
+        );
+    }
 
     // Start the background expiry sweeper
     let _sweeper = start_expiry_sweeper(Arc::clone(&storage));
     info!("Background expiry sweeper started");
 
+    // Start the background save scheduler
+    let _scheduler = start_save_scheduler(Arc::clone(&storage));
+
+    // Start the replica link; it stays idle until REPLICAOF names a master
+    let link = storage.replication().link();
+    link.set_listening_port(config.port);
+    link.set_master(config.replicaof.clone());
+    let _replica_link = start_replica_link(Arc::clone(&storage));
+
+    // Switch to cluster mode once the data is loaded, so the append-only
+    // file replays without being redirected
+    if config.cluster_enabled {
+        let cluster = storage.cluster();
+        cluster.enable(&config.bind[0], config.port);
+        cluster.assign(&cluster.myself().id, &config.cluster_slots);
+        for node in &config.cluster_nodes {
+            let id = cluster.add_node(&node.host, node.port);
+            cluster.assign(&id, &node.slots);
+        }
+        info!(
+            "Cluster mode enabled with {} known nodes",
+            cluster.nodes().len()
+        );
+    }
+
+    if config.io_uring {
+        uring::enable()?;
+        info!("Connections read and write through io_uring");
+    }
+
     // Create connection statistics
     let stats = Arc::new(ConnectionStats::new());
 
-    // Bind the TCP listener
-    let listener = TcpListener::bind(config.bind_address()).await?;
-    info!("Listening on {}", config.bind_address());
+    // Bind the TCP listeners: one per address, or one per address for
+    // each reactor
+    let mut listeners = Vec::new();
+    for address in config.bind_addresses() {
+        if config.reactors == 1 {
+            listeners.push(TcpListener::bind(&address).await?);
+        } else {
+            let addr = resolve(&address)?;
+            for _ in 0..config.reactors {
+                listeners.push(bind_shared(addr)?);
+            }
+        }
+        info!("Listening on {}", address);
+    }
+
+    // Protected mode: without a password, a server reachable from other
+    // hosts only lets in clients on the loopback interface
+    let protected = config.protected_mode
+        && listeners.iter().any(|listener| {
+            listener
+                .local_addr()
+                .is_ok_and(|addr| !addr.ip().is_loopback())
+        });
+    if protected {
+        warn!("Protected mode is on, only loopback clients can connect (--protected-mode no)");
+    }
+    let acceptor = Acceptor {
+        storage: Arc::clone(&storage),
+        stats,
+        renames: Arc::new(config.renames.clone()),
+        protocol_limits: config.protocol_limits,
+        protected,
+    };
 
     // Set up graceful shutdown
     let shutdown = async {
@@ -182,37 +994,124 @@ async fn main() -> anyhow::Result<()> {
         info!("Shutdown signal received, stopping server...");
     };
 
-    // Main accept loop
-    tokio::select! {
-        _ = accept_loop(listener, storage, stats) => {}
-        _ = shutdown => {}
+    // Accept connections on every address until shutdown
+    if config.reactors == 1 {
+        let accept_loops: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| tokio::spawn(acceptor.clone().accept_loop(listener)))
+            .collect();
+        shutdown.await;
+        for accept_loop in accept_loops {
+            accept_loop.abort();
+        }
+    } else {
+        let (stop, stopped) = watch::channel(false);
+        let mut reactors: Vec<Vec<std::net::TcpListener>> =
+            (0..config.reactors).map(|_| Vec::new()).collect();
+        for (i, listener) in listeners.into_iter().enumerate() {
+            reactors[i % config.reactors].push(listener.into_std()?);
+        }
+        let threads = reactors
+            .into_iter()
+            .enumerate()
+            .map(|(i, listeners)| spawn_reactor(i, listeners, acceptor.clone(), stopped.clone()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        info!("Accepting connections on {} reactors", threads.len());
+        shutdown.await;
+        let _ = stop.send(true);
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }
+
+    // Persist the keyspace before exiting
+    storage.aof().flush();
+    match storage.save() {
+        Ok(keys) => info!("Saved {} keys to {}", keys, snapshot_path.display()),
+        Err(e) => warn!("Failed to save {}: {}", snapshot_path.display(), e),
     }
 
     info!("Server shutdown complete");
     Ok(())
 }
 
-/// Main loop that accepts incoming connections
-async fn accept_loop(
-    listener: TcpListener,
+/// Resolves a `host:port` address to listen on.
+fn resolve(address: &str) -> std::io::Result<SocketAddr> {
+    address.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("{} doesn't resolve to an address", address),
+        )
+    })
+}
+
+/// What every accept loop needs to set up the connections it accepts.
+#[derive(Clone)]
+struct Acceptor {
     storage: Arc<StorageEngine>,
     stats: Arc<ConnectionStats>,
-) {
-    loop {
-        match listener.accept().await {
-            Ok((stream, addr)) => {
-                // Create a command handler for this connection
-                let handler = CommandHandler::new(Arc::clone(&storage));
-                let stats = Arc::clone(&stats);
-
-                // Spawn a task to handle this connection
-                tokio::spawn(async move {
-                    handle_connection(stream, addr, handler, stats).await;
-                });
-            }
-            Err(e) => {
-                error!("Failed to accept connection: {}", e);
+    renames: Arc<CommandRenames>,
+    protocol_limits: ProtocolLimits,
+    protected: bool,
+}
+
+impl Acceptor {
+    /// Main loop that accepts incoming connections on one address
+    async fn accept_loop(self, listener: TcpListener) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) if self.protected && !allowed_in_protected_mode(&addr) => {
+                    self.stats.connection_rejected();
+                    tokio::spawn(refuse_connection(stream, addr));
+                }
+                Ok((stream, addr)) => {
+                    // Create a command handler for this connection
+                    let handler = CommandHandler::new(Arc::clone(&self.storage))
+                        .with_renames(Arc::clone(&self.renames))
+                        .with_protocol_limits(self.protocol_limits);
+                    let stats = Arc::clone(&self.stats);
+
+                    // Spawn a task to handle this connection
+                    tokio::spawn(async move {
+                        handle_connection(stream, addr, handler, stats).await;
+                    });
+                }
+                Err(e) => {
+                    error!("Failed to accept connection: {}", e);
+                }
             }
         }
     }
 }
+
+/// Starts reactor `index`: a thread running a single-threaded runtime that
+/// accepts connections on `listeners` and serves them, until `stopped`
+/// says the server is shutting down.
+fn spawn_reactor(
+    index: usize,
+    listeners: Vec<std::net::TcpListener>,
+    acceptor: Acceptor,
+    mut stopped: watch::Receiver<bool>,
+) -> std::io::Result<std::thread::JoinHandle<()>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    std::thread::Builder::new()
+        .name(format!("reactor-{}", index))
+        .spawn(move || {
+            runtime.block_on(async {
+                for listener in listeners {
+                    match TcpListener::from_std(listener) {
+                        Ok(listener) => {
+                            tokio::spawn(acceptor.clone().accept_loop(listener));
+                        }
+                        Err(e) => error!("Reactor {} failed to listen: {}", index, e),
+                    }
+                }
+                let _ = stopped.wait_for(|stopped| *stopped).await;
+            });
+            // Connections still open are dropped with the runtime, without
+            // waiting for blocking work such as a replica's full sync
+            runtime.shutdown_background();
+        })
+}
@@ -0,0 +1,248 @@
+//! A `tokio_util` Codec for RESP
+//!
+//! [`RespCodec`] plugs the parser and serializer into
+//! [`tokio_util::codec`], so any `AsyncRead + AsyncWrite` stream can be
+//! wrapped in a [`Framed`](tokio_util::codec::Framed) that yields and
+//! accepts [`RespValue`]s:
+//!
+//! ```ignore
+//! use flashkv::protocol::{RespCodec, RespValue};
+//! use futures::{SinkExt, StreamExt};
+//! use tokio_util::codec::Framed;
+//!
+//! let mut framed = Framed::new(stream, RespCodec::new());
+//! framed.send(RespValue::array(vec![RespValue::bulk_string("PING")])).await?;
+//! let reply = framed.next().await.transpose()?;
+//! ```
+//!
+//! Decoding enforces the same [`ProtocolLimits`] as the server, including
+//! the query buffer limit, so a peer can't make a `Framed` buffer without
+//! bound. Encoding writes RESP2 unless the codec is switched to RESP3,
+//! e.g. after a successful `HELLO 3`.
+
+use crate::protocol::parser::{blank_lines_len, ParseError, ProtocolLimits, RespParser};
+use crate::protocol::types::RespValue;
+use bytes::{Buf, BytesMut};
+use thiserror::Error;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Errors reading or writing a framed RESP stream.
+#[derive(Debug, Error)]
+pub enum CodecError {
+    /// I/O error on the underlying stream
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// The peer sent invalid RESP or broke a limit
+    #[error("Protocol error: {0}")]
+    Protocol(#[from] ParseError),
+}
+
+/// Frames a byte stream into [`RespValue`]s and back.
+#[derive(Debug, Default)]
+pub struct RespCodec {
+    /// Parser for incoming frames (carries the limits)
+    parser: RespParser,
+
+    /// Whether outgoing values are written as RESP3
+    resp3: bool,
+}
+
+impl RespCodec {
+    /// Creates a codec with the default limits, writing RESP2.
+    pub fn new() -> Self {
+        Self::with_limits(ProtocolLimits::default())
+    }
+
+    /// Creates a codec rejecting incoming frames that break `limits`.
+    pub fn with_limits(limits: ProtocolLimits) -> Self {
+        Self {
+            parser: RespParser::with_limits(limits),
+            resp3: false,
+        }
+    }
+
+    /// Returns the limits incoming frames are held to.
+    pub fn limits(&self) -> &ProtocolLimits {
+        self.parser.limits()
+    }
+
+    /// Returns whether outgoing values are written as RESP3.
+    pub fn is_resp3(&self) -> bool {
+        self.resp3
+    }
+
+    /// Sets whether outgoing values are written as RESP3.
+    pub fn set_resp3(&mut self, resp3: bool) {
+        self.resp3 = resp3;
+    }
+}
+
+impl Decoder for RespCodec {
+    type Item = RespValue;
+    type Error = CodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<RespValue>, CodecError> {
+        src.advance(blank_lines_len(src));
+        match self.parser.parse(src)? {
+            Some((value, consumed)) => {
+                src.advance(consumed);
+                Ok(Some(value))
+            }
+            None => {
+                let max = self.limits().max_query_buffer;
+                if src.len() >= max {
+                    return Err(ParseError::MessageTooLarge {
+                        size: src.len(),
+                        max,
+                    }
+                    .into());
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Encoder<&RespValue> for RespCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: &RespValue, dst: &mut BytesMut) -> Result<(), CodecError> {
+        if self.resp3 {
+            item.serialize_resp3_into(dst);
+        } else {
+            item.serialize_into(dst);
+        }
+        Ok(())
+    }
+}
+
+impl Encoder<RespValue> for RespCodec {
+    type Error = CodecError;
+
+    fn encode(&mut self, item: RespValue, dst: &mut BytesMut) -> Result<(), CodecError> {
+        self.encode(&item, dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_decode() {
+        let mut codec = RespCodec::new();
+        let mut buf = BytesMut::from(&b"+OK\r\n:42\r\n$5\r\nhel"[..]);
+
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(RespValue::simple_string("OK"))
+        );
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(RespValue::Integer(42))
+        );
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(&buf[..], b"$5\r\nhel");
+
+        buf.extend_from_slice(b"lo\r\n");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(RespValue::bulk_string(Bytes::from("hello")))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_skips_blank_lines() {
+        let mut codec = RespCodec::new();
+        let mut buf = BytesMut::from(&b"\r\n  \r\n"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+
+        buf.extend_from_slice(b"\r\nPING\r\n");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            Some(RespValue::Array(vec![RespValue::bulk_string(Bytes::from(
+                "PING"
+            ))]))
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        let limits = ProtocolLimits {
+            max_query_buffer: 8,
+            ..ProtocolLimits::default()
+        };
+        let mut codec = RespCodec::with_limits(limits);
+
+        let mut buf = BytesMut::from(&b"$3\r\nab"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        buf.extend_from_slice(b"c");
+        assert_eq!(
+            codec.decode(&mut buf).unwrap(),
+            None,
+            "an incomplete frame under the limit waits for more data"
+        );
+
+        let mut buf = BytesMut::from(&b"$10\r\nabcde"[..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(CodecError::Protocol(ParseError::MessageTooLarge {
+                size: 10,
+                max: 8
+            }))
+        ));
+
+        let mut buf = BytesMut::from(&b"$x\r\n"[..]);
+        assert!(matches!(
+            codec.decode(&mut buf),
+            Err(CodecError::Protocol(ParseError::InvalidInteger(_)))
+        ));
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut codec = RespCodec::new();
+        let mut buf = BytesMut::new();
+
+        codec.encode(RespValue::Null, &mut buf).unwrap();
+        codec.encode(&RespValue::Boolean(true), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"$-1\r\n:1\r\n");
+
+        codec.set_resp3(true);
+        assert!(codec.is_resp3());
+        buf.clear();
+        codec.encode(RespValue::Null, &mut buf).unwrap();
+        codec.encode(&RespValue::Boolean(true), &mut buf).unwrap();
+        assert_eq!(&buf[..], b"_\r\n#t\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_framed_round_trip() {
+        use futures::{SinkExt, StreamExt};
+        use tokio_util::codec::{FramedRead, FramedWrite};
+
+        let (client, server) = tokio::io::duplex(64);
+        let mut writer = FramedWrite::new(client, RespCodec::new());
+        let mut reader = FramedRead::new(server, RespCodec::new());
+
+        let command = RespValue::array(vec![
+            RespValue::bulk_string(Bytes::from("SET")),
+            RespValue::bulk_string(Bytes::from("key")),
+            RespValue::bulk_string(Bytes::from(vec![b'x'; 1000])),
+        ]);
+
+        // The value is larger than the pipe, so it arrives in pieces
+        let sent = command.clone();
+        let send = tokio::spawn(async move { writer.send(sent).await });
+
+        let received = reader.next().await.unwrap().unwrap();
+        send.await.unwrap().unwrap();
+        assert_eq!(received, command);
+    }
+}
@@ -0,0 +1,473 @@
+//! Conversions Between RESP Values and Rust Types
+//!
+//! Building a reply or reading an argument shouldn't take a `match` on
+//! every variant. Common Rust types convert into a [`RespValue`] with
+//! `From`, and back out with [`FromResp`] (or `TryFrom`, which is
+//! implemented on top of it):
+//!
+//! ```
+//! use flashkv::protocol::{FromResp, RespValue};
+//!
+//! let reply = RespValue::from(vec![Some("a"), None]);
+//! let values: Vec<Option<String>> = reply.extract().unwrap();
+//! assert_eq!(values, vec![Some("a".to_string()), None]);
+//!
+//! // Numbers arrive as bulk strings in commands, and convert all the same
+//! let n = i64::from_resp(&RespValue::bulk_string("42")).unwrap();
+//! assert_eq!(n, 42);
+//! ```
+//!
+//! [`Args`] reads a command's arguments one after the other the same way.
+//!
+//! Strings convert to bulk strings, since that's what replies carry; use
+//! [`RespValue::simple_string`] for status replies.
+
+use crate::protocol::types::RespValue;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::hash::Hash;
+use thiserror::Error;
+
+/// Errors converting a [`RespValue`] into a Rust type.
+#[derive(Debug, Error, Clone, PartialEq)]
+pub enum FromRespError {
+    /// The value is of a RESP type that can't become the Rust type
+    #[error("expected {expected}, found {found}")]
+    WrongType {
+        expected: &'static str,
+        found: &'static str,
+    },
+
+    /// The value is of the right RESP type, but doesn't hold a valid one
+    /// (e.g. a bulk string that isn't a number)
+    #[error("invalid {expected}")]
+    Invalid { expected: &'static str },
+
+    /// The value is an error reply, carrying its message
+    #[error("{0}")]
+    Error(String),
+
+    /// [`Args`] ran out of arguments
+    #[error("missing argument")]
+    MissingArgument,
+}
+
+/// Types that can be read out of a [`RespValue`].
+pub trait FromResp: Sized {
+    /// Reads `Self` out of `value`.
+    fn from_resp(value: &RespValue) -> Result<Self, FromRespError>;
+}
+
+impl RespValue {
+    /// Converts the value into `T`, e.g. `value.extract::<Vec<String>>()`.
+    pub fn extract<T: FromResp>(&self) -> Result<T, FromRespError> {
+        T::from_resp(self)
+    }
+
+    /// Returns the name of the value's RESP type, for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            RespValue::SimpleString(_) => "simple string",
+            RespValue::Error(_) => "error",
+            RespValue::Integer(_) => "integer",
+            RespValue::BulkString(_) => "bulk string",
+            RespValue::Null | RespValue::NullArray => "null",
+            RespValue::Array(_) | RespValue::EncodedArray { .. } => "array",
+            RespValue::Map(_) => "map",
+            RespValue::Set(_) => "set",
+            RespValue::Double(_) => "double",
+            RespValue::Boolean(_) => "boolean",
+            RespValue::BigNumber(_) => "big number",
+            RespValue::VerbatimString { .. } => "verbatim string",
+            RespValue::Push(_) => "push",
+        }
+    }
+}
+
+/// The error for a `value` that can't become an `expected`: error replies
+/// keep their message.
+fn mismatch(value: &RespValue, expected: &'static str) -> FromRespError {
+    match value {
+        RespValue::Error(message) => FromRespError::Error(message.clone()),
+        _ => FromRespError::WrongType {
+            expected,
+            found: value.type_name(),
+        },
+    }
+}
+
+/// Returns the text of a string-like value.
+fn text(value: &RespValue) -> Option<&[u8]> {
+    match value {
+        RespValue::SimpleString(s) | RespValue::BigNumber(s) => Some(s.as_bytes()),
+        RespValue::BulkString(b) | RespValue::VerbatimString { text: b, .. } => Some(b),
+        _ => None,
+    }
+}
+
+/// Parses the text of a string-like value as a `T`.
+fn parse_text<T: std::str::FromStr>(
+    value: &RespValue,
+    expected: &'static str,
+) -> Result<T, FromRespError> {
+    let text = text(value).ok_or_else(|| mismatch(value, expected))?;
+    std::str::from_utf8(text)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(FromRespError::Invalid { expected })
+}
+
+impl FromResp for RespValue {
+    fn from_resp(value: &RespValue) -> Result<Self, FromRespError> {
+        Ok(value.clone())
+    }
+}
+
+impl FromResp for i64 {
+    fn from_resp(value: &RespValue) -> Result<Self, FromRespError> {
+        match value {
+            RespValue::Integer(n) => Ok(*n),
+            _ => parse_text(value, "integer"),
+        }
+    }
+}
+
+impl FromResp for u64 {
+    fn from_resp(value: &RespValue) -> Result<Self, FromRespError> {
+        match value {
+            RespValue::Integer(n) => u64::try_from(*n).map_err(|_| FromRespError::Invalid {
+                expected: "non-negative integer",
+            }),
+            _ => parse_text(value, "non-negative integer"),
+        }
+    }
+}
+
+impl FromResp for usize {
+    fn from_resp(value: &RespValue) -> Result<Self, FromRespError> {
+        usize::try_from(u64::from_resp(value)?).map_err(|_| FromRespError::Invalid {
+            expected: "non-negative integer",
+        })
+    }
+}
+
+impl FromResp for f64 {
+    fn from_resp(value: &RespValue) -> Result<Self, FromRespError> {
+        match value {
+            RespValue::Double(n) => Ok(*n),
+            RespValue::Integer(n) => Ok(*n as f64),
+            _ => parse_text(value, "number"),
+        }
+    }
+}
+
+impl FromResp for bool {
+    fn from_resp(value: &RespValue) -> Result<Self, FromRespError> {
+        match value {
+            RespValue::Boolean(b) => Ok(*b),
+            RespValue::Integer(0) => Ok(false),
+            RespValue::Integer(1) => Ok(true),
+            RespValue::Integer(_) => Err(FromRespError::Invalid {
+                expected: "boolean",
+            }),
+            _ => Err(mismatch(value, "boolean")),
+        }
+    }
+}
+
+impl FromResp for String {
+    fn from_resp(value: &RespValue) -> Result<Self, FromRespError> {
+        let text = text(value).ok_or_else(|| mismatch(value, "string"))?;
+        String::from_utf8(text.to_vec()).map_err(|_| FromRespError::Invalid {
+            expected: "UTF-8 string",
+        })
+    }
+}
+
+impl FromResp for Bytes {
+    fn from_resp(value: &RespValue) -> Result<Self, FromRespError> {
+        match value {
+            RespValue::BulkString(b) | RespValue::VerbatimString { text: b, .. } => Ok(b.clone()),
+            RespValue::SimpleString(s) | RespValue::BigNumber(s) => Ok(Bytes::from(s.clone())),
+            _ => Err(mismatch(value, "string")),
+        }
+    }
+}
+
+/// Arrays, sets and pushes convert element by element.
+impl<T: FromResp> FromResp for Vec<T> {
+    fn from_resp(value: &RespValue) -> Result<Self, FromRespError> {
+        match value {
+            RespValue::Array(values) | RespValue::Set(values) | RespValue::Push(values) => {
+                values.iter().map(T::from_resp).collect()
+            }
+            _ => Err(mismatch(value, "array")),
+        }
+    }
+}
+
+/// Nulls become `None`.
+impl<T: FromResp> FromResp for Option<T> {
+    fn from_resp(value: &RespValue) -> Result<Self, FromRespError> {
+        match value {
+            RespValue::Null | RespValue::NullArray => Ok(None),
+            _ => T::from_resp(value).map(Some),
+        }
+    }
+}
+
+/// RESP3 maps convert pair by pair, and so do the flat arrays of keys and
+/// values RESP2 clients get instead.
+impl<K: FromResp + Eq + Hash, V: FromResp> FromResp for HashMap<K, V> {
+    fn from_resp(value: &RespValue) -> Result<Self, FromRespError> {
+        match value {
+            RespValue::Map(pairs) => pairs
+                .iter()
+                .map(|(k, v)| Ok((K::from_resp(k)?, V::from_resp(v)?)))
+                .collect(),
+            RespValue::Array(values) if values.len() % 2 == 0 => values
+                .chunks_exact(2)
+                .map(|pair| Ok((K::from_resp(&pair[0])?, V::from_resp(&pair[1])?)))
+                .collect(),
+            RespValue::Array(_) => Err(FromRespError::Invalid { expected: "map" }),
+            _ => Err(mismatch(value, "map")),
+        }
+    }
+}
+
+/// Implements `TryFrom<RespValue>` and `TryFrom<&RespValue>` for types
+/// implementing [`FromResp`], with the generic parameters in brackets.
+///
+/// `Option` is left out: core's `From<T> for Option<T>` already gives
+/// `Option<RespValue>` a `TryFrom<RespValue>`. Use [`FromResp`] for it.
+macro_rules! impl_try_from {
+    ([$($generics:tt)*] $ty:ty) => {
+        impl<$($generics)*> TryFrom<&RespValue> for $ty {
+            type Error = FromRespError;
+
+            fn try_from(value: &RespValue) -> Result<Self, Self::Error> {
+                <$ty>::from_resp(value)
+            }
+        }
+
+        impl<$($generics)*> TryFrom<RespValue> for $ty {
+            type Error = FromRespError;
+
+            fn try_from(value: RespValue) -> Result<Self, Self::Error> {
+                <$ty>::from_resp(&value)
+            }
+        }
+    };
+    ($($ty:ty),*) => {
+        $(impl_try_from!([] $ty);)*
+    };
+}
+
+impl_try_from!(i64, u64, usize, f64, bool, String, Bytes);
+impl_try_from!([T: FromResp] Vec<T>);
+impl_try_from!([K: FromResp + Eq + Hash, V: FromResp] HashMap<K, V>);
+
+impl From<i64> for RespValue {
+    fn from(n: i64) -> Self {
+        RespValue::Integer(n)
+    }
+}
+
+/// Counts too large for an `i64` saturate.
+impl From<usize> for RespValue {
+    fn from(n: usize) -> Self {
+        RespValue::Integer(i64::try_from(n).unwrap_or(i64::MAX))
+    }
+}
+
+impl From<f64> for RespValue {
+    fn from(n: f64) -> Self {
+        RespValue::Double(n)
+    }
+}
+
+impl From<bool> for RespValue {
+    fn from(b: bool) -> Self {
+        RespValue::Boolean(b)
+    }
+}
+
+impl From<&str> for RespValue {
+    fn from(s: &str) -> Self {
+        RespValue::BulkString(Bytes::copy_from_slice(s.as_bytes()))
+    }
+}
+
+impl From<String> for RespValue {
+    fn from(s: String) -> Self {
+        RespValue::BulkString(Bytes::from(s))
+    }
+}
+
+impl From<Bytes> for RespValue {
+    fn from(b: Bytes) -> Self {
+        RespValue::BulkString(b)
+    }
+}
+
+impl<T: Into<RespValue>> From<Vec<T>> for RespValue {
+    fn from(values: Vec<T>) -> Self {
+        RespValue::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+/// `None` becomes a null.
+impl<T: Into<RespValue>> From<Option<T>> for RespValue {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(RespValue::Null, Into::into)
+    }
+}
+
+impl<K: Into<RespValue>, V: Into<RespValue>> From<HashMap<K, V>> for RespValue {
+    fn from(map: HashMap<K, V>) -> Self {
+        RespValue::Map(map.into_iter().map(|(k, v)| (k.into(), v.into())).collect())
+    }
+}
+
+/// Reads a command's arguments in order.
+///
+/// ```
+/// use flashkv::protocol::{Args, RespValue};
+///
+/// // SET key value EX 10
+/// let args: Vec<RespValue> = ["key", "value", "EX", "10"].map(RespValue::from).into();
+/// let mut args = Args::new(&args);
+/// let key: String = args.next().unwrap();
+/// let value: bytes::Bytes = args.next().unwrap();
+/// let options: Vec<String> = args.rest().unwrap();
+/// assert_eq!((key.as_str(), options.len()), ("key", 2));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Args<'a> {
+    args: std::slice::Iter<'a, RespValue>,
+}
+
+impl<'a> Args<'a> {
+    /// Starts reading `args` from the first one.
+    pub fn new(args: &'a [RespValue]) -> Self {
+        Self { args: args.iter() }
+    }
+
+    /// Reads the next argument, failing if there is none left.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next<T: FromResp>(&mut self) -> Result<T, FromRespError> {
+        self.optional()?.ok_or(FromRespError::MissingArgument)
+    }
+
+    /// Reads the next argument, if there is one left.
+    pub fn optional<T: FromResp>(&mut self) -> Result<Option<T>, FromRespError> {
+        self.args.next().map(T::from_resp).transpose()
+    }
+
+    /// Reads all the arguments left.
+    pub fn rest<T: FromResp>(&mut self) -> Result<Vec<T>, FromRespError> {
+        self.args.by_ref().map(T::from_resp).collect()
+    }
+
+    /// Returns how many arguments are left.
+    pub fn remaining(&self) -> usize {
+        self.args.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_resp() {
+        assert_eq!(RespValue::from(42i64), RespValue::Integer(42));
+        assert_eq!(RespValue::from(3usize), RespValue::Integer(3));
+        assert_eq!(RespValue::from(1.5), RespValue::Double(1.5));
+        assert_eq!(RespValue::from(true), RespValue::Boolean(true));
+        assert_eq!(RespValue::from("a"), RespValue::bulk_string("a"));
+        assert_eq!(
+            RespValue::from(vec![Some("a".to_string()), None]),
+            RespValue::array(vec![RespValue::bulk_string("a"), RespValue::Null])
+        );
+        let map = HashMap::from([("field", 1i64)]);
+        assert_eq!(
+            RespValue::from(map),
+            RespValue::Map(vec![(
+                RespValue::bulk_string("field"),
+                RespValue::Integer(1)
+            )])
+        );
+    }
+
+    #[test]
+    fn test_from_resp() {
+        assert_eq!(RespValue::Integer(7).extract::<i64>(), Ok(7));
+        assert_eq!(RespValue::bulk_string("-7").extract::<i64>(), Ok(-7));
+        assert_eq!(RespValue::bulk_string("7").extract::<usize>(), Ok(7));
+        assert_eq!(RespValue::bulk_string("2.5").extract::<f64>(), Ok(2.5));
+        assert_eq!(RespValue::Integer(1).extract::<bool>(), Ok(true));
+        assert_eq!(
+            RespValue::simple_string("OK").extract::<String>(),
+            Ok("OK".to_string())
+        );
+        assert_eq!(
+            String::try_from(RespValue::bulk_string("x")),
+            Ok("x".to_string())
+        );
+
+        let reply = RespValue::array(vec![RespValue::Integer(1), RespValue::Null]);
+        assert_eq!(reply.extract::<Vec<Option<i64>>>(), Ok(vec![Some(1), None]));
+
+        // RESP3 maps and their RESP2 flattened form read the same
+        let expected = HashMap::from([("a".to_string(), 1i64)]);
+        let map = RespValue::Map(vec![(RespValue::bulk_string("a"), RespValue::Integer(1))]);
+        assert_eq!(map.extract(), Ok(expected.clone()));
+        let flat = RespValue::array(vec![RespValue::bulk_string("a"), RespValue::Integer(1)]);
+        assert_eq!(flat.extract(), Ok(expected));
+    }
+
+    #[test]
+    fn test_from_resp_errors() {
+        assert_eq!(
+            RespValue::Integer(1).extract::<Vec<i64>>(),
+            Err(FromRespError::WrongType {
+                expected: "array",
+                found: "integer"
+            })
+        );
+        assert_eq!(
+            RespValue::bulk_string("abc").extract::<i64>(),
+            Err(FromRespError::Invalid {
+                expected: "integer"
+            })
+        );
+        assert!(RespValue::Integer(-1).extract::<u64>().is_err());
+        assert_eq!(
+            RespValue::error("ERR no such key").extract::<String>(),
+            Err(FromRespError::Error("ERR no such key".to_string()))
+        );
+        assert_eq!(
+            RespValue::Null.extract::<i64>(),
+            Err(FromRespError::WrongType {
+                expected: "integer",
+                found: "null"
+            })
+        );
+    }
+
+    #[test]
+    fn test_args() {
+        let args: Vec<RespValue> = ["key", "10", "a", "b"].map(RespValue::from).into();
+        let mut args = Args::new(&args);
+        assert_eq!(args.next::<Bytes>(), Ok(Bytes::from("key")));
+        assert_eq!(args.next::<i64>(), Ok(10));
+        assert_eq!(args.remaining(), 2);
+        assert_eq!(
+            args.rest::<String>(),
+            Ok(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(args.optional::<i64>(), Ok(None));
+        assert_eq!(args.next::<i64>(), Err(FromRespError::MissingArgument));
+    }
+}
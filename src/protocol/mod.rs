@@ -11,6 +11,8 @@
 //!
 //! - `types`: Defines the `RespValue` enum and serialization
 //! - `parser`: Zero-copy parser for incoming RESP data
+//! - `convert`: Conversions between `RespValue` and common Rust types
+//! - `codec`: A `tokio_util` codec for framing streams of `RespValue`s
 //!
 //! ## Example
 //!
@@ -27,9 +29,15 @@
 //! let bytes = response.serialize();
 //! ```
 
+pub mod codec;
+pub mod convert;
 pub mod parser;
 pub mod types;
 
 // Re-export commonly used types for convenience
-pub use parser::{parse_message, ParseError, ParseResult, RespParser};
-pub use types::RespValue;
+pub use codec::{CodecError, RespCodec};
+pub use convert::{Args, FromResp, FromRespError};
+pub use parser::{
+    blank_lines_len, parse_message, ParseError, ParseResult, ProtocolLimits, RespParser,
+};
+pub use types::{BulkArrayBuilder, RespValue};
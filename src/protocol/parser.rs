@@ -57,12 +57,52 @@ pub enum ParseError {
     InvalidArrayLength(i64),
 
     /// Protocol violation (missing CRLF, etc.)
-    #[error("protocol error: {0}")]
+    #[error("{0}")]
     ProtocolError(String),
 
     /// The message exceeds maximum allowed size
     #[error("message too large: {size} bytes (max: {max})")]
     MessageTooLarge { size: usize, max: usize },
+
+    /// An array, set, push or map has more elements than allowed
+    #[error("too many elements: {count} (max: {max})")]
+    TooManyElements { count: usize, max: usize },
+
+    /// An inline command is longer than allowed
+    #[error("too big inline request (max: {max} bytes)")]
+    InlineTooLong { max: usize },
+
+    /// Aggregates are nested deeper than allowed
+    #[error("maximum nesting depth exceeded: {max}")]
+    NestingTooDeep { max: usize },
+
+    /// An inline command that makes no sense, on a line `len` bytes long
+    /// (CRLF included)
+    #[error("{reason}")]
+    InvalidInline { reason: String, len: usize },
+}
+
+impl ParseError {
+    /// Returns whether the request broke one of the [`ProtocolLimits`]
+    /// rather than the protocol itself.
+    pub fn exceeds_limit(&self) -> bool {
+        matches!(
+            self,
+            ParseError::MessageTooLarge { .. }
+                | ParseError::TooManyElements { .. }
+                | ParseError::InlineTooLong { .. }
+                | ParseError::NestingTooDeep { .. }
+        )
+    }
+
+    /// Returns how many bytes to skip to get past the bad request, if the
+    /// requests after it can still be parsed.
+    pub fn resync_len(&self) -> Option<usize> {
+        match self {
+            ParseError::InvalidInline { len, .. } => Some(*len),
+            _ => None,
+        }
+    }
 }
 
 /// Result type for parsing operations.
@@ -74,6 +114,47 @@ pub const MAX_BULK_SIZE: usize = 512 * 1024 * 1024;
 /// Maximum array nesting depth (prevent stack overflow)
 pub const MAX_NESTING_DEPTH: usize = 32;
 
+/// Maximum number of elements in an array, set, push or map (1M)
+pub const MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+
+/// Maximum length of an inline command (64 KB, same as Redis)
+pub const MAX_INLINE_SIZE: usize = 64 * 1024;
+
+/// Maximum amount of unparsed data buffered for a client (1 GB, same as
+/// Redis)
+pub const MAX_QUERY_BUFFER: usize = 1024 * 1024 * 1024;
+
+/// Limits on the requests clients may send, so that one client can't make
+/// the server allocate unbounded memory or recurse too deeply.
+///
+/// The defaults are the `MAX_*` constants above; the server's
+/// `--proto-max-*` and `--client-query-buffer-limit` options override them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolLimits {
+    /// Longest bulk string, in bytes
+    pub max_bulk_len: usize,
+    /// Most elements in an array, set, push or map
+    pub max_multibulk_len: usize,
+    /// Longest inline command, in bytes
+    pub max_inline_len: usize,
+    /// Deepest nesting of arrays, sets, pushes and maps
+    pub max_nesting_depth: usize,
+    /// Most unparsed data buffered for a connection, in bytes
+    pub max_query_buffer: usize,
+}
+
+impl Default for ProtocolLimits {
+    fn default() -> Self {
+        Self {
+            max_bulk_len: MAX_BULK_SIZE,
+            max_multibulk_len: MAX_MULTIBULK_LEN,
+            max_inline_len: MAX_INLINE_SIZE,
+            max_nesting_depth: MAX_NESTING_DEPTH,
+            max_query_buffer: MAX_QUERY_BUFFER,
+        }
+    }
+}
+
 /// A zero-copy RESP protocol parser.
 ///
 /// # Example
@@ -94,12 +175,25 @@ pub const MAX_NESTING_DEPTH: usize = 32;
 pub struct RespParser {
     /// Current nesting depth (for array parsing)
     depth: usize,
+
+    /// What requests may contain
+    limits: ProtocolLimits,
 }
 
 impl RespParser {
-    /// Creates a new parser instance.
+    /// Creates a new parser instance with the default limits.
     pub fn new() -> Self {
-        Self { depth: 0 }
+        Self::with_limits(ProtocolLimits::default())
+    }
+
+    /// Creates a parser rejecting requests that break `limits`.
+    pub fn with_limits(limits: ProtocolLimits) -> Self {
+        Self { depth: 0, limits }
+    }
+
+    /// Returns the limits this parser enforces.
+    pub fn limits(&self) -> &ProtocolLimits {
+        &self.limits
     }
 
     /// Attempts to parse a RESP value from the buffer.
@@ -125,11 +219,10 @@ impl RespParser {
         }
 
         // Check nesting depth
-        if self.depth > MAX_NESTING_DEPTH {
-            return Err(ParseError::ProtocolError(format!(
-                "maximum nesting depth exceeded: {}",
-                MAX_NESTING_DEPTH
-            )));
+        if self.depth > self.limits.max_nesting_depth {
+            return Err(ParseError::NestingTooDeep {
+                max: self.limits.max_nesting_depth,
+            });
         }
 
         match buf[0] {
@@ -138,6 +231,13 @@ impl RespParser {
             prefix::INTEGER => self.parse_integer(buf),
             prefix::BULK_STRING => self.parse_bulk_string(buf),
             prefix::ARRAY => self.parse_array(buf),
+            prefix::MAP => self.parse_map(buf),
+            prefix::SET | prefix::PUSH => self.parse_array(buf),
+            prefix::DOUBLE => self.parse_double(buf),
+            prefix::BOOLEAN => self.parse_boolean(buf),
+            prefix::BIG_NUMBER => self.parse_big_number(buf),
+            prefix::VERBATIM_STRING => self.parse_verbatim_string(buf),
+            prefix::NULL => self.parse_null(buf),
             _ => self.parse_inline(buf),
         }
     }
@@ -199,7 +299,10 @@ impl RespParser {
 
     /// Parses a bulk string: `$<length>\r\n<data>\r\n`
     fn parse_bulk_string(&mut self, buf: &[u8]) -> ParseResult<Option<(RespValue, usize)>> {
-        debug_assert!(buf[0] == prefix::BULK_STRING);
+        debug_assert!(matches!(
+            buf[0],
+            prefix::BULK_STRING | prefix::VERBATIM_STRING
+        ));
 
         // First, find the length line
         let length_end = match find_crlf(&buf[1..]) {
@@ -228,10 +331,10 @@ impl RespParser {
         let length = length as usize;
 
         // Check size limit
-        if length > MAX_BULK_SIZE {
+        if length > self.limits.max_bulk_len {
             return Err(ParseError::MessageTooLarge {
                 size: length,
-                max: MAX_BULK_SIZE,
+                max: self.limits.max_bulk_len,
             });
         }
 
@@ -257,9 +360,10 @@ impl RespParser {
         Ok(Some((RespValue::BulkString(data), total_needed)))
     }
 
-    /// Parses an array: `*<count>\r\n<elements...>`
+    /// Parses an array: `*<count>\r\n<elements...>`, or a RESP3 set
+    /// (`~`) or push (`>`) laid out the same way.
     fn parse_array(&mut self, buf: &[u8]) -> ParseResult<Option<(RespValue, usize)>> {
-        debug_assert!(buf[0] == prefix::ARRAY);
+        debug_assert!(matches!(buf[0], prefix::ARRAY | prefix::SET | prefix::PUSH));
 
         // Find the count line
         let count_end = match find_crlf(&buf[1..]) {
@@ -285,7 +389,7 @@ impl RespParser {
             return Err(ParseError::InvalidArrayLength(count));
         }
 
-        let count = count as usize;
+        let count = self.check_count(count as usize)?;
 
         // Parse each element
         let mut elements = Vec::with_capacity(count);
@@ -309,23 +413,175 @@ impl RespParser {
 
         self.depth -= 1;
 
-        Ok(Some((RespValue::Array(elements), consumed)))
+        let value = match buf[0] {
+            prefix::SET => RespValue::Set(elements),
+            prefix::PUSH => RespValue::Push(elements),
+            _ => RespValue::Array(elements),
+        };
+        Ok(Some((value, consumed)))
+    }
+
+    /// Parses a RESP3 map: `%<count>\r\n<key1><value1>...`
+    fn parse_map(&mut self, buf: &[u8]) -> ParseResult<Option<(RespValue, usize)>> {
+        debug_assert!(buf[0] == prefix::MAP);
+
+        let Some((count, mut consumed)) = parse_line(buf)? else {
+            return Ok(None);
+        };
+        let count: usize = count
+            .parse()
+            .map_err(|e: ParseIntError| ParseError::InvalidInteger(e.to_string()))?;
+        let count = self.check_count(count)?;
+
+        let mut pairs = Vec::with_capacity(count);
+        self.depth += 1;
+        for _ in 0..count {
+            let mut pair = [RespValue::Null, RespValue::Null];
+            for slot in &mut pair {
+                match self.parse_value(&buf[consumed..])? {
+                    Some((value, element_consumed)) => {
+                        *slot = value;
+                        consumed += element_consumed;
+                    }
+                    None => return Ok(None),
+                }
+            }
+            let [key, value] = pair;
+            pairs.push((key, value));
+        }
+        self.depth -= 1;
+
+        Ok(Some((RespValue::Map(pairs), consumed)))
+    }
+
+    /// Parses a RESP3 double: `,<double>\r\n`
+    fn parse_double(&mut self, buf: &[u8]) -> ParseResult<Option<(RespValue, usize)>> {
+        debug_assert!(buf[0] == prefix::DOUBLE);
+
+        let Some((line, consumed)) = parse_line(buf)? else {
+            return Ok(None);
+        };
+        let n = match line {
+            "inf" => f64::INFINITY,
+            "-inf" => f64::NEG_INFINITY,
+            "nan" => f64::NAN,
+            _ => line
+                .parse()
+                .map_err(|_| ParseError::ProtocolError(format!("invalid double: {}", line)))?,
+        };
+        Ok(Some((RespValue::Double(n), consumed)))
+    }
+
+    /// Parses a RESP3 boolean: `#t\r\n` or `#f\r\n`
+    fn parse_boolean(&mut self, buf: &[u8]) -> ParseResult<Option<(RespValue, usize)>> {
+        debug_assert!(buf[0] == prefix::BOOLEAN);
+
+        let Some((line, consumed)) = parse_line(buf)? else {
+            return Ok(None);
+        };
+        let b = match line {
+            "t" => true,
+            "f" => false,
+            _ => {
+                return Err(ParseError::ProtocolError(format!(
+                    "invalid boolean: {}",
+                    line
+                )))
+            }
+        };
+        Ok(Some((RespValue::Boolean(b), consumed)))
+    }
+
+    /// Parses a RESP3 big number: `(<digits>\r\n`
+    fn parse_big_number(&mut self, buf: &[u8]) -> ParseResult<Option<(RespValue, usize)>> {
+        debug_assert!(buf[0] == prefix::BIG_NUMBER);
+
+        let Some((line, consumed)) = parse_line(buf)? else {
+            return Ok(None);
+        };
+        let digits = line.strip_prefix(['-', '+']).unwrap_or(line);
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseError::InvalidInteger(line.to_string()));
+        }
+        Ok(Some((RespValue::BigNumber(line.to_string()), consumed)))
+    }
+
+    /// Parses a RESP3 verbatim string: `=<length>\r\n<format>:<text>\r\n`
+    fn parse_verbatim_string(&mut self, buf: &[u8]) -> ParseResult<Option<(RespValue, usize)>> {
+        debug_assert!(buf[0] == prefix::VERBATIM_STRING);
+
+        // Laid out like a bulk string, but never null
+        let (data, consumed) = match self.parse_bulk_string(buf)? {
+            Some((RespValue::BulkString(data), consumed)) => (data, consumed),
+            Some(_) => return Err(ParseError::InvalidBulkLength(-1)),
+            None => return Ok(None),
+        };
+        match (data.get(..3), data.get(3)) {
+            (Some(format), Some(b':')) => Ok(Some((
+                RespValue::VerbatimString {
+                    format: format.try_into().expect("three bytes"),
+                    text: data.slice(4..),
+                },
+                consumed,
+            ))),
+            _ => Err(ParseError::ProtocolError(
+                "verbatim string missing its format".to_string(),
+            )),
+        }
+    }
+
+    /// Parses a RESP3 null: `_\r\n`
+    fn parse_null(&mut self, buf: &[u8]) -> ParseResult<Option<(RespValue, usize)>> {
+        debug_assert!(buf[0] == prefix::NULL);
+
+        match parse_line(buf)? {
+            Some(("", consumed)) => Ok(Some((RespValue::Null, consumed))),
+            Some(_) => Err(ParseError::ProtocolError("invalid null".to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks the element count of an aggregate against the limit.
+    fn check_count(&self, count: usize) -> ParseResult<usize> {
+        if count > self.limits.max_multibulk_len {
+            return Err(ParseError::TooManyElements {
+                count,
+                max: self.limits.max_multibulk_len,
+            });
+        }
+        Ok(count)
     }
 
     fn parse_inline(&mut self, buf: &[u8]) -> ParseResult<Option<(RespValue, usize)>> {
-        let crlf_pos = match find_crlf(buf) {
+        // Only look as far as the longest line allowed, and give up once
+        // the line is longer rather than buffering it forever
+        let max = self.limits.max_inline_len;
+        let end = max.saturating_add(2);
+        let crlf_pos = match find_crlf(&buf[..buf.len().min(end)]) {
             Some(pos) => pos,
+            None if buf.len() >= end => return Err(ParseError::InlineTooLong { max }),
             None => return Ok(None),
         };
 
+        // A bad request on a line of its own can be skipped, keeping the
+        // requests after it
+        let depth = self.depth;
+        let invalid = |error: ParseError| match depth {
+            0 => ParseError::InvalidInline {
+                reason: error.to_string(),
+                len: crlf_pos + 2,
+            },
+            _ => error,
+        };
+
         let line = std::str::from_utf8(&buf[..crlf_pos])
-            .map_err(|e| ParseError::InvalidUtf8(e.to_string()))?;
+            .map_err(|e| invalid(ParseError::InvalidUtf8(e.to_string())))?;
 
         let parts: Vec<&str> = line.split_whitespace().collect();
         if parts.is_empty() {
-            return Err(ParseError::ProtocolError(
-                "Empty inline command".to_string(),
-            ));
+            return Err(invalid(ParseError::ProtocolError(
+                "empty inline command".to_string(),
+            )));
         }
 
         let elements: Vec<RespValue> = parts
@@ -340,17 +596,56 @@ impl RespParser {
 /// Finds the position of CRLF in the buffer.
 ///
 /// Returns the position of `\r` if found, or None if CRLF is not present.
+/// `memchr` jumps between `\r` bytes with SIMD, so only those candidates
+/// get their next byte checked.
 #[inline]
 fn find_crlf(buf: &[u8]) -> Option<usize> {
-    // Use memchr for optimized byte searching on larger buffers
-    for i in 0..buf.len().saturating_sub(1) {
-        if buf[i] == b'\r' && buf[i + 1] == b'\n' {
-            return Some(i);
+    let mut start = 0;
+    while let Some(i) = memchr::memchr(b'\r', &buf[start..]) {
+        let pos = start + i;
+        match buf.get(pos + 1) {
+            Some(b'\n') => return Some(pos),
+            Some(_) => start = pos + 1,
+            None => return None,
         }
     }
     None
 }
 
+/// Reads the line after the type prefix, returning it with the number of
+/// bytes it took up (prefix and CRLF included), or `None` if incomplete.
+fn parse_line(buf: &[u8]) -> ParseResult<Option<(&str, usize)>> {
+    match find_crlf(&buf[1..]) {
+        Some(pos) => {
+            let line = std::str::from_utf8(&buf[1..1 + pos])
+                .map_err(|e| ParseError::InvalidUtf8(e.to_string()))?;
+            Ok(Some((line, 1 + pos + 2)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Returns the length of the blank lines at the start of `buf`, CRLFs
+/// included.
+///
+/// Like Redis, request readers drop lines holding nothing but spaces and
+/// tabs (telnet users and keepalive probes send them) before parsing, so
+/// they get no reply rather than an error.
+pub fn blank_lines_len(buf: &[u8]) -> usize {
+    let mut len = 0;
+    loop {
+        let rest = &buf[len..];
+        let spaces = rest
+            .iter()
+            .take_while(|&&b| b == b' ' || b == b'\t')
+            .count();
+        if !rest[spaces..].starts_with(b"\r\n") {
+            return len;
+        }
+        len += spaces + 2;
+    }
+}
+
 /// Helper function to parse a single RESP message from bytes.
 ///
 /// This is a convenience function for simple use cases.
@@ -376,6 +671,18 @@ mod tests {
         assert!(parse_message(input).unwrap().is_none());
     }
 
+    #[test]
+    fn test_parse_line_with_lone_cr() {
+        // A `\r` not followed by `\n` is part of the line
+        let input = b"+a\rb\r\r\n";
+        let result = parse_message(input).unwrap().unwrap();
+        assert_eq!(result.0, RespValue::SimpleString("a\rb\r".to_string()));
+        assert_eq!(result.1, 7);
+
+        // A trailing `\r` still needs its `\n`
+        assert!(parse_message(b"+a\rb\r").unwrap().is_none());
+    }
+
     #[test]
     fn test_parse_error() {
         let input = b"-ERR unknown command\r\n";
@@ -499,6 +806,66 @@ mod tests {
         assert!(matches!(value, RespValue::Array(ref arr) if arr.len() == 1));
     }
 
+    #[test]
+    fn test_protocol_limits() {
+        let mut parser = RespParser::with_limits(ProtocolLimits {
+            max_bulk_len: 5,
+            max_multibulk_len: 2,
+            max_inline_len: 8,
+            max_nesting_depth: 1,
+            ..ProtocolLimits::default()
+        });
+
+        // Within the limits
+        assert!(parser.parse(b"$5\r\nhello\r\n").unwrap().is_some());
+        assert!(parser.parse(b"*2\r\n:1\r\n:2\r\n").unwrap().is_some());
+        assert!(parser.parse(b"GET key1\r\n").unwrap().is_some());
+        assert!(parser.parse(b"GET key1").unwrap().is_none());
+
+        let errors = [
+            (
+                &b"$6\r\n"[..],
+                ParseError::MessageTooLarge { size: 6, max: 5 },
+            ),
+            (b"*3\r\n", ParseError::TooManyElements { count: 3, max: 2 }),
+            (b"%3\r\n", ParseError::TooManyElements { count: 3, max: 2 }),
+            (b"GET key12\r\n", ParseError::InlineTooLong { max: 8 }),
+            // Too long even before the CRLF arrives
+            (b"GET key123", ParseError::InlineTooLong { max: 8 }),
+            (b"*1\r\n*1\r\n:1\r\n", ParseError::NestingTooDeep { max: 1 }),
+        ];
+        for (input, expected) in errors {
+            let err = parser.parse(input).unwrap_err();
+            assert!(err.exceeds_limit());
+            assert_eq!(err, expected);
+        }
+
+        assert!(!parser.parse(b"$-2\r\n").unwrap_err().exceeds_limit());
+    }
+
+    #[test]
+    fn test_invalid_inline_can_be_skipped() {
+        let err = parse_message(b"GET \xff\r\nPING\r\n").unwrap_err();
+        assert!(matches!(err, ParseError::InvalidInline { .. }));
+        assert_eq!(err.resync_len(), Some(7));
+        assert_eq!(parse_message(b"  \r\n").unwrap_err().resync_len(), Some(4));
+
+        // Inside an array, the line doesn't say where the request ends
+        let err = parse_message(b"*2\r\n\r\n:1\r\n").unwrap_err();
+        assert_eq!(err.resync_len(), None);
+        assert_eq!(parse_message(b"$x\r\n").unwrap_err().resync_len(), None);
+    }
+
+    #[test]
+    fn test_blank_lines_len() {
+        assert_eq!(blank_lines_len(b"\r\n \t\r\nPING\r\n"), 6);
+        assert_eq!(blank_lines_len(b"PING\r\n\r\n"), 0);
+        assert_eq!(blank_lines_len(b"  PING\r\n"), 0);
+        assert_eq!(blank_lines_len(b"\r\n  \r"), 2);
+        assert_eq!(blank_lines_len(b"*1\r\n"), 0);
+        assert_eq!(blank_lines_len(b""), 0);
+    }
+
     #[test]
     fn test_parse_invalid_integer() {
         let input = b":not_a_number\r\n";
@@ -520,6 +887,73 @@ mod tests {
         assert_eq!(original, parsed);
     }
 
+    #[test]
+    fn test_parse_resp3() {
+        let cases: [(&[u8], RespValue); 9] = [
+            (
+                b"%1\r\n+proto\r\n:3\r\n",
+                RespValue::Map(vec![(
+                    RespValue::simple_string("proto"),
+                    RespValue::integer(3),
+                )]),
+            ),
+            (
+                b"~2\r\n:1\r\n:2\r\n",
+                RespValue::Set(vec![RespValue::integer(1), RespValue::integer(2)]),
+            ),
+            (
+                b">2\r\n+invalidate\r\n_\r\n",
+                RespValue::Push(vec![
+                    RespValue::simple_string("invalidate"),
+                    RespValue::Null,
+                ]),
+            ),
+            (b",3.25\r\n", RespValue::Double(3.25)),
+            (b",-inf\r\n", RespValue::Double(f64::NEG_INFINITY)),
+            (b"#t\r\n", RespValue::Boolean(true)),
+            (
+                b"(-12345678901234567890\r\n",
+                RespValue::BigNumber("-12345678901234567890".to_string()),
+            ),
+            (
+                b"=15\r\ntxt:Some string\r\n",
+                RespValue::VerbatimString {
+                    format: *b"txt",
+                    text: Bytes::from("Some string"),
+                },
+            ),
+            (b"_\r\n", RespValue::Null),
+        ];
+        for (input, expected) in cases {
+            let (value, consumed) = parse_message(input).unwrap().unwrap();
+            assert_eq!(value, expected);
+            assert_eq!(consumed, input.len());
+            // Every prefix of a frame is incomplete, not an error
+            assert!(parse_message(&input[..input.len() - 1]).unwrap().is_none());
+        }
+
+        let value = parse_message(b",nan\r\n").unwrap().unwrap().0;
+        assert!(matches!(value, RespValue::Double(n) if n.is_nan()));
+        assert!(parse_message(b"#x\r\n").is_err());
+        assert!(parse_message(b",abc\r\n").is_err());
+        assert!(parse_message(b"(12a\r\n").is_err());
+        assert!(parse_message(b"=3\r\ntxt\r\n").is_err());
+    }
+
+    #[test]
+    fn test_resp3_roundtrip() {
+        let original = RespValue::Map(vec![
+            (
+                RespValue::bulk_string("set"),
+                RespValue::Set(vec![RespValue::Boolean(false)]),
+            ),
+            (RespValue::bulk_string("score"), RespValue::Double(0.5)),
+        ]);
+        let serialized = original.serialize_resp3();
+        let (parsed, _) = parse_message(&serialized).unwrap().unwrap();
+        assert_eq!(original, parsed);
+    }
+
     #[test]
     fn test_parse_set_command() {
         // Real Redis command: SET user:101 "Ariz"
@@ -12,6 +12,22 @@
 //! - `$` Bulk String
 //! - `*` Array
 //!
+//! Clients that switch to RESP3 with `HELLO 3` can also receive:
+//! - `%` Map
+//! - `~` Set
+//! - `,` Double
+//! - `#` Boolean
+//! - `(` Big Number
+//! - `=` Verbatim String
+//! - `_` Null
+//! - `>` Push (out-of-band data, such as cache invalidations)
+//!
+//! Which one a value is written as depends on the connection:
+//! [`serialize`](RespValue::serialize) writes the RESP2 shape of every
+//! value (a map becomes a flat array of keys and values, a double a bulk
+//! string, and so on), [`serialize_resp3`](RespValue::serialize_resp3) the
+//! RESP3 one.
+//!
 //! All types are terminated with CRLF (`\r\n`).
 //!
 //! ## Examples
@@ -23,8 +39,9 @@
 //! Array: `*2\r\n$3\r\nGET\r\n$4\r\nname\r\n`
 //! Null Bulk String: `$-1\r\n`
 
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 use std::fmt;
+use std::io::Write;
 
 /// The CRLF terminator used in RESP protocol
 pub const CRLF: &[u8] = b"\r\n";
@@ -36,13 +53,21 @@ pub mod prefix {
     pub const INTEGER: u8 = b':';
     pub const BULK_STRING: u8 = b'$';
     pub const ARRAY: u8 = b'*';
+    pub const MAP: u8 = b'%';
+    pub const SET: u8 = b'~';
+    pub const DOUBLE: u8 = b',';
+    pub const BOOLEAN: u8 = b'#';
+    pub const BIG_NUMBER: u8 = b'(';
+    pub const VERBATIM_STRING: u8 = b'=';
+    pub const NULL: u8 = b'_';
+    pub const PUSH: u8 = b'>';
 }
 
 /// Represents a value in the RESP protocol.
 ///
 /// This enum covers all RESP data types and can be used for both
 /// parsing incoming data and serializing outgoing responses.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RespValue {
     /// Simple strings are used for non-binary safe strings.
     /// They cannot contain CRLF characters.
@@ -63,12 +88,55 @@ pub enum RespValue {
     BulkString(Bytes),
 
     /// Null value (null bulk string or null array)
+    /// Format: `$-1\r\n`, or `_\r\n` in RESP3
     Null,
 
+    /// Null array, sent where a command replies with an array that is absent
+    /// (such as a blocking pop that timed out)
+    /// Format: `*-1\r\n`, or `_\r\n` in RESP3
+    NullArray,
+
     /// Arrays can contain any RESP type, including nested arrays.
     /// Format: `*<count>\r\n<element1><element2>...`
     /// Null array: `*-1\r\n`
     Array(Vec<RespValue>),
+
+    /// RESP3 maps of key/value pairs; RESP2 clients get a flat array.
+    /// Format: `%<count>\r\n<key1><value1>...`
+    Map(Vec<(RespValue, RespValue)>),
+
+    /// RESP3 unordered collections; RESP2 clients get an array.
+    /// Format: `~<count>\r\n<element1><element2>...`
+    Set(Vec<RespValue>),
+
+    /// RESP3 floating point numbers; RESP2 clients get a bulk string.
+    /// Format: `,<double>\r\n`, with `inf`, `-inf` and `nan`
+    Double(f64),
+
+    /// RESP3 booleans; RESP2 clients get the integer 1 or 0.
+    /// Format: `#t\r\n` or `#f\r\n`
+    Boolean(bool),
+
+    /// RESP3 integers too large for 64 bits, as decimal digits; RESP2
+    /// clients get a bulk string.
+    /// Format: `(<digits>\r\n`
+    BigNumber(String),
+
+    /// RESP3 text tagged with a three-letter format (`txt` for plain text,
+    /// `mkd` for markdown); RESP2 clients get a bulk string of the text.
+    /// Format: `=<length>\r\n<format>:<text>\r\n`
+    VerbatimString { format: [u8; 3], text: Bytes },
+
+    /// RESP3 push messages, sent to RESP3 clients outside the normal
+    /// request/response flow; RESP2 clients get an array.
+    /// Format: `><count>\r\n<element1><element2>...`
+    Push(Vec<RespValue>),
+
+    /// An array of `len` elements that were serialized ahead of time, as
+    /// built by [`BulkArrayBuilder`]. The elements are written as they are,
+    /// so they must be spelled the same in RESP2 and RESP3.
+    /// Format: `*<count>\r\n<elements>`
+    EncodedArray { len: usize, elements: Bytes },
 }
 
 impl RespValue {
@@ -116,6 +184,11 @@ impl RespValue {
         RespValue::Null
     }
 
+    /// Creates a null array response.
+    pub fn null_array() -> Self {
+        RespValue::NullArray
+    }
+
     /// Creates an array response.
     pub fn array(values: Vec<RespValue>) -> Self {
         RespValue::Array(values)
@@ -133,59 +206,123 @@ impl RespValue {
 
     /// Serializes the RESP value to bytes for sending over the wire.
     ///
-    /// This method converts the RESP value into its wire format representation.
+    /// This method converts the RESP value into its RESP2 wire format
+    /// representation, which is what clients get until they send `HELLO 3`.
     pub fn serialize(&self) -> Vec<u8> {
         let mut buf = Vec::new();
         self.serialize_into(&mut buf);
         buf
     }
 
-    /// Serializes the RESP value into an existing buffer.
+    /// Serializes the RESP value into an existing buffer, such as a `Vec`
+    /// or a connection's `BytesMut` write buffer.
     ///
     /// This is more efficient than `serialize()` when you want to reuse a buffer.
-    pub fn serialize_into(&self, buf: &mut Vec<u8>) {
+    pub fn serialize_into(&self, buf: &mut impl BufMut) {
+        self.write(buf, false);
+    }
+
+    /// Serializes the RESP value to bytes for a client speaking RESP3.
+    pub fn serialize_resp3(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.serialize_resp3_into(&mut buf);
+        buf
+    }
+
+    /// Serializes the RESP value into an existing buffer, for a client
+    /// speaking RESP3.
+    pub fn serialize_resp3_into(&self, buf: &mut impl BufMut) {
+        self.write(buf, true);
+    }
+
+    /// Serializes the value into `buf` like
+    /// [`serialize_into`](Self::serialize_into) (or
+    /// [`serialize_resp3_into`](Self::serialize_resp3_into) if `resp3` is
+    /// set), except that the payloads of bulk strings of at least `min_len`
+    /// bytes aren't copied.
+    ///
+    /// Instead, what `buf` holds up to such a payload is split off and
+    /// pushed to `frames`, followed by the payload itself, so the serialized
+    /// value is `frames` followed by what is left in `buf`. This keeps
+    /// large values, such as a multi-megabyte `GET` reply, from being copied
+    /// on their way to the socket.
+    pub fn serialize_shared_into(
+        &self,
+        buf: &mut BytesMut,
+        frames: &mut Vec<Bytes>,
+        min_len: usize,
+        resp3: bool,
+    ) {
+        let mut out = SharedPayloads {
+            buf,
+            frames,
+            min_len,
+        };
+        self.write(&mut out, resp3);
+    }
+
+    /// Writes the value in RESP3 if `resp3` is set, or else in RESP2,
+    /// where the RESP3 types fall back to the closest RESP2 one.
+    fn write(&self, out: &mut impl Output, resp3: bool) {
         match self {
-            RespValue::SimpleString(s) => {
-                buf.push(prefix::SIMPLE_STRING);
-                buf.extend_from_slice(s.as_bytes());
-                buf.extend_from_slice(CRLF);
+            RespValue::SimpleString(s) => write_line(out.buf(), prefix::SIMPLE_STRING, s),
+            RespValue::Error(s) => write_line(out.buf(), prefix::ERROR, s),
+            RespValue::Integer(n) => write_line(out.buf(), prefix::INTEGER, n),
+            RespValue::BulkString(data) => out.put_bulk(data),
+            RespValue::Null | RespValue::NullArray if resp3 => {
+                write_line(out.buf(), prefix::NULL, "")
             }
-            RespValue::Error(s) => {
-                buf.push(prefix::ERROR);
-                buf.extend_from_slice(s.as_bytes());
-                buf.extend_from_slice(CRLF);
+            RespValue::Null => write_line(out.buf(), prefix::BULK_STRING, -1),
+            RespValue::NullArray => write_line(out.buf(), prefix::ARRAY, -1),
+            RespValue::Array(values) => write_aggregate(out, prefix::ARRAY, values, resp3),
+            RespValue::Map(pairs) if resp3 => {
+                write_line(out.buf(), prefix::MAP, pairs.len());
+                for (key, value) in pairs {
+                    key.write(out, resp3);
+                    value.write(out, resp3);
+                }
             }
-            RespValue::Integer(n) => {
-                buf.push(prefix::INTEGER);
-                buf.extend_from_slice(n.to_string().as_bytes());
-                buf.extend_from_slice(CRLF);
+            RespValue::Map(pairs) => {
+                write_line(out.buf(), prefix::ARRAY, pairs.len() * 2);
+                for (key, value) in pairs {
+                    key.write(out, resp3);
+                    value.write(out, resp3);
+                }
             }
-            RespValue::BulkString(data) => {
-                buf.push(prefix::BULK_STRING);
-                buf.extend_from_slice(data.len().to_string().as_bytes());
-                buf.extend_from_slice(CRLF);
-                buf.extend_from_slice(data);
-                buf.extend_from_slice(CRLF);
+            RespValue::Set(values) if resp3 => write_aggregate(out, prefix::SET, values, resp3),
+            RespValue::Set(values) => write_aggregate(out, prefix::ARRAY, values, resp3),
+            RespValue::Double(n) => {
+                let n = format_double(*n);
+                if resp3 {
+                    write_line(out.buf(), prefix::DOUBLE, n);
+                } else {
+                    write_bulk(out.buf(), prefix::BULK_STRING, &[n.as_bytes()]);
+                }
             }
-            RespValue::Null => {
-                buf.push(prefix::BULK_STRING);
-                buf.extend_from_slice(b"-1");
-                buf.extend_from_slice(CRLF);
+            RespValue::Boolean(b) if resp3 => {
+                write_line(out.buf(), prefix::BOOLEAN, if *b { "t" } else { "f" })
             }
-            RespValue::Array(values) => {
-                buf.push(prefix::ARRAY);
-                buf.extend_from_slice(values.len().to_string().as_bytes());
-                buf.extend_from_slice(CRLF);
-                for value in values {
-                    value.serialize_into(buf);
-                }
+            RespValue::Boolean(b) => write_line(out.buf(), prefix::INTEGER, *b as u8),
+            RespValue::BigNumber(n) if resp3 => write_line(out.buf(), prefix::BIG_NUMBER, n),
+            RespValue::BigNumber(n) => write_bulk(out.buf(), prefix::BULK_STRING, &[n.as_bytes()]),
+            RespValue::VerbatimString { format, text } if resp3 => {
+                write_bulk(out.buf(), prefix::VERBATIM_STRING, &[format, b":", text])
+            }
+            RespValue::VerbatimString { text, .. } => {
+                write_bulk(out.buf(), prefix::BULK_STRING, &[text])
+            }
+            RespValue::Push(values) if resp3 => write_aggregate(out, prefix::PUSH, values, resp3),
+            RespValue::Push(values) => write_aggregate(out, prefix::ARRAY, values, resp3),
+            RespValue::EncodedArray { len, elements } => {
+                write_line(out.buf(), prefix::ARRAY, len);
+                out.put_encoded(elements);
             }
         }
     }
 
     /// Returns true if this value is null.
     pub fn is_null(&self) -> bool {
-        matches!(self, RespValue::Null)
+        matches!(self, RespValue::Null | RespValue::NullArray)
     }
 
     /// Returns true if this value is an error.
@@ -235,6 +372,150 @@ impl RespValue {
     }
 }
 
+/// Where [`RespValue::write`] puts a serialized value.
+trait Output {
+    type Buf: BufMut;
+
+    /// Returns the buffer the value is written to.
+    fn buf(&mut self) -> &mut Self::Buf;
+
+    /// Writes a bulk string, copying its payload into the buffer.
+    fn put_bulk(&mut self, data: &Bytes) {
+        write_bulk(self.buf(), prefix::BULK_STRING, &[data]);
+    }
+
+    /// Writes already serialized values, copying them into the buffer.
+    fn put_encoded(&mut self, data: &Bytes) {
+        self.buf().put_slice(data);
+    }
+}
+
+impl<B: BufMut> Output for B {
+    type Buf = B;
+
+    fn buf(&mut self) -> &mut B {
+        self
+    }
+}
+
+/// Output for [`RespValue::serialize_shared_into`], handing large bulk
+/// string payloads over as frames of their own.
+struct SharedPayloads<'a> {
+    buf: &'a mut BytesMut,
+    frames: &'a mut Vec<Bytes>,
+    min_len: usize,
+}
+
+impl Output for SharedPayloads<'_> {
+    type Buf = BytesMut;
+
+    fn buf(&mut self) -> &mut BytesMut {
+        self.buf
+    }
+
+    fn put_bulk(&mut self, data: &Bytes) {
+        if data.len() < self.min_len {
+            return write_bulk(self.buf, prefix::BULK_STRING, &[data]);
+        }
+        write_line(self.buf, prefix::BULK_STRING, data.len());
+        self.frames.push(self.buf.split().freeze());
+        self.frames.push(data.clone());
+        self.buf.put_slice(CRLF);
+    }
+
+    fn put_encoded(&mut self, data: &Bytes) {
+        if data.len() < self.min_len {
+            return self.buf.put_slice(data);
+        }
+        self.frames.push(self.buf.split().freeze());
+        self.frames.push(data.clone());
+    }
+}
+
+/// Serializes an array of bulk strings one element at a time, for replies
+/// too large to build as a `Vec` of values first, such as KEYS over
+/// millions of keys.
+///
+/// # Example
+/// ```
+/// use flashkv::protocol::types::{BulkArrayBuilder, RespValue};
+/// let mut array = BulkArrayBuilder::new();
+/// array.push(b"a");
+/// array.push(b"b");
+/// assert_eq!(array.finish().serialize(), b"*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+/// ```
+#[derive(Debug, Default)]
+pub struct BulkArrayBuilder {
+    elements: BytesMut,
+    len: usize,
+}
+
+impl BulkArrayBuilder {
+    /// Creates an empty array.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a bulk string.
+    pub fn push(&mut self, data: &[u8]) {
+        write_bulk(&mut self.elements, prefix::BULK_STRING, &[data]);
+        self.len += 1;
+    }
+
+    /// Returns the number of elements pushed so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether no element has been pushed.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the array as a reply.
+    pub fn finish(self) -> RespValue {
+        RespValue::EncodedArray {
+            len: self.len,
+            elements: self.elements.freeze(),
+        }
+    }
+}
+
+/// Writes a `<prefix><content>\r\n` line, formatting `content` straight
+/// into the buffer.
+fn write_line(buf: &mut impl BufMut, prefix: u8, content: impl fmt::Display) {
+    buf.put_u8(prefix);
+    write!(buf.writer(), "{}", content).expect("writing to a growable buffer never fails");
+    buf.put_slice(CRLF);
+}
+
+/// Writes a length-prefixed string made of `parts`.
+fn write_bulk(buf: &mut impl BufMut, prefix: u8, parts: &[&[u8]]) {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    write_line(buf, prefix, len);
+    for part in parts {
+        buf.put_slice(part);
+    }
+    buf.put_slice(CRLF);
+}
+
+/// Writes a count-prefixed sequence of values.
+fn write_aggregate(out: &mut impl Output, prefix: u8, values: &[RespValue], resp3: bool) {
+    write_line(out.buf(), prefix, values.len());
+    for value in values {
+        value.write(out, resp3);
+    }
+}
+
+/// Formats a double the way RESP3 spells it (e.g. `1.5`, `3`, `inf`, `nan`).
+fn format_double(n: f64) -> String {
+    if n.is_nan() {
+        "nan".to_string()
+    } else {
+        n.to_string()
+    }
+}
+
 impl fmt::Display for RespValue {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -248,8 +529,14 @@ impl fmt::Display for RespValue {
                     write!(f, "(binary data, {} bytes)", data.len())
                 }
             }
-            RespValue::Null => write!(f, "(nil)"),
-            RespValue::Array(values) => {
+            RespValue::Null | RespValue::NullArray => write!(f, "(nil)"),
+            RespValue::Double(n) => write!(f, "(double) {}", format_double(*n)),
+            RespValue::Boolean(b) => write!(f, "({})", b),
+            RespValue::BigNumber(n) => write!(f, "(big number) {}", n),
+            RespValue::VerbatimString { text, .. } => {
+                write!(f, "\"{}\"", String::from_utf8_lossy(text))
+            }
+            RespValue::Array(values) | RespValue::Set(values) | RespValue::Push(values) => {
                 if values.is_empty() {
                     write!(f, "(empty array)")
                 } else {
@@ -260,6 +547,19 @@ impl fmt::Display for RespValue {
                     Ok(())
                 }
             }
+            RespValue::EncodedArray { len: 0, .. } => write!(f, "(empty array)"),
+            RespValue::EncodedArray { len, .. } => write!(f, "(array of {} elements)", len),
+            RespValue::Map(pairs) => {
+                if pairs.is_empty() {
+                    write!(f, "(empty hash)")
+                } else {
+                    writeln!(f)?;
+                    for (i, (k, v)) in pairs.iter().enumerate() {
+                        writeln!(f, "{}# {} => {}", i + 1, k, v)?;
+                    }
+                    Ok(())
+                }
+            }
         }
     }
 }
@@ -299,6 +599,10 @@ mod tests {
     fn test_null_serialize() {
         let value = RespValue::null();
         assert_eq!(value.serialize(), b"$-1\r\n");
+
+        let value = RespValue::null_array();
+        assert_eq!(value.serialize(), b"*-1\r\n");
+        assert!(value.is_null());
     }
 
     #[test]
@@ -310,6 +614,64 @@ mod tests {
         assert_eq!(value.serialize(), b"*2\r\n$3\r\nGET\r\n$4\r\nname\r\n");
     }
 
+    #[test]
+    fn test_resp3_serialize() {
+        let value = RespValue::Map(vec![(
+            RespValue::bulk_string(Bytes::from("proto")),
+            RespValue::integer(3),
+        )]);
+        assert_eq!(value.serialize_resp3(), b"%1\r\n$5\r\nproto\r\n:3\r\n");
+        assert_eq!(value.serialize(), b"*2\r\n$5\r\nproto\r\n:3\r\n");
+
+        let value = RespValue::Push(vec![
+            RespValue::bulk_string(Bytes::from("invalidate")),
+            RespValue::array(vec![RespValue::bulk_string(Bytes::from("k"))]),
+        ]);
+        assert_eq!(
+            value.serialize_resp3(),
+            b">2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\nk\r\n"
+        );
+
+        let value = RespValue::Set(vec![RespValue::integer(1)]);
+        assert_eq!(value.serialize_resp3(), b"~1\r\n:1\r\n");
+        assert_eq!(value.serialize(), b"*1\r\n:1\r\n");
+
+        assert_eq!(RespValue::null().serialize_resp3(), b"_\r\n");
+        assert_eq!(RespValue::null_array().serialize_resp3(), b"_\r\n");
+    }
+
+    #[test]
+    fn test_resp3_scalars_serialize() {
+        let cases: [(RespValue, &[u8], &[u8]); 7] = [
+            (RespValue::Double(1.5), b",1.5\r\n", b"$3\r\n1.5\r\n"),
+            (
+                RespValue::Double(f64::NEG_INFINITY),
+                b",-inf\r\n",
+                b"$4\r\n-inf\r\n",
+            ),
+            (RespValue::Double(f64::NAN), b",nan\r\n", b"$3\r\nnan\r\n"),
+            (RespValue::Boolean(true), b"#t\r\n", b":1\r\n"),
+            (RespValue::Boolean(false), b"#f\r\n", b":0\r\n"),
+            (
+                RespValue::BigNumber("3492890328409238509324850943850943825024385".to_string()),
+                b"(3492890328409238509324850943850943825024385\r\n",
+                b"$43\r\n3492890328409238509324850943850943825024385\r\n",
+            ),
+            (
+                RespValue::VerbatimString {
+                    format: *b"txt",
+                    text: Bytes::from("Some string"),
+                },
+                b"=15\r\ntxt:Some string\r\n",
+                b"$11\r\nSome string\r\n",
+            ),
+        ];
+        for (value, resp3, resp2) in cases {
+            assert_eq!(value.serialize_resp3(), resp3, "{:?}", value);
+            assert_eq!(value.serialize(), resp2, "{:?}", value);
+        }
+    }
+
     #[test]
     fn test_nested_array_serialize() {
         let value = RespValue::array(vec![
@@ -319,6 +681,86 @@ mod tests {
         assert_eq!(value.serialize(), b"*2\r\n:1\r\n*2\r\n:2\r\n:3\r\n");
     }
 
+    #[test]
+    fn test_serialize_into_bytes_mut() {
+        let value = RespValue::Map(vec![(
+            RespValue::bulk_string("score"),
+            RespValue::Double(2.5),
+        )]);
+        let mut buf = bytes::BytesMut::new();
+
+        // Each reply is split off, leaving the buffer ready for the next
+        value.serialize_into(&mut buf);
+        assert_eq!(buf.split().freeze(), value.serialize());
+        value.serialize_resp3_into(&mut buf);
+        assert_eq!(buf.split().freeze(), value.serialize_resp3());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_serialize_shared_into() {
+        let large = Bytes::from(vec![b'x'; 100]);
+        let value = RespValue::array(vec![
+            RespValue::bulk_string("small"),
+            RespValue::BulkString(large.clone()),
+            RespValue::Null,
+        ]);
+        let mut buf = BytesMut::from(&b"+OK\r\n"[..]);
+        let mut frames = Vec::new();
+
+        value.serialize_shared_into(&mut buf, &mut frames, 100, false);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], &b"+OK\r\n*3\r\n$5\r\nsmall\r\n$100\r\n"[..]);
+        assert_eq!(
+            frames[1].as_ptr(),
+            large.as_ptr(),
+            "the payload is shared, not copied"
+        );
+        assert_eq!(&buf[..], b"\r\n$-1\r\n");
+
+        let joined: Vec<u8> = frames.concat().into_iter().chain(buf).collect();
+        assert_eq!(joined, [&b"+OK\r\n"[..], &value.serialize()].concat());
+
+        // Smaller payloads are copied like the rest of the value
+        let mut buf = BytesMut::new();
+        frames.clear();
+        value.serialize_shared_into(&mut buf, &mut frames, 101, true);
+        assert!(frames.is_empty());
+        assert_eq!(buf, value.serialize_resp3());
+    }
+
+    #[test]
+    fn test_bulk_array_builder() {
+        let mut array = BulkArrayBuilder::new();
+        assert!(array.is_empty());
+        array.push(b"key:1");
+        array.push(b"");
+        assert_eq!(array.len(), 2);
+        let value = array.finish();
+
+        let expected = RespValue::array(vec![
+            RespValue::bulk_string("key:1"),
+            RespValue::bulk_string(""),
+        ]);
+        assert_eq!(value.serialize(), expected.serialize());
+        assert_eq!(value.serialize_resp3(), expected.serialize_resp3());
+        assert_eq!(BulkArrayBuilder::new().finish().serialize(), b"*0\r\n");
+
+        // Large element runs are shared, not copied
+        let mut array = BulkArrayBuilder::new();
+        for _ in 0..10 {
+            array.push(b"0123456789");
+        }
+        let value = array.finish();
+        let mut buf = BytesMut::new();
+        let mut frames = Vec::new();
+        value.serialize_shared_into(&mut buf, &mut frames, 100, false);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], &b"*10\r\n"[..]);
+        assert!(buf.is_empty());
+        assert_eq!(frames.concat(), value.serialize());
+    }
+
     #[test]
     fn test_ok_response() {
         assert_eq!(RespValue::ok().serialize(), b"+OK\r\n");
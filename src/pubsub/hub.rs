@@ -0,0 +1,427 @@
+//! Pub/Sub Registry
+//!
+//! The [`PubSub`] registry maps every channel and pattern to the
+//! subscribers listening to it. PUBLISH looks up the channel and every
+//! pattern matching it, and queues the message for each subscriber found.
+
+use super::notify::{EventClass, NotifyFlags};
+use super::subscriber::{Frame, Mailbox, Subscriber};
+use super::tracking::{Target, Tracking, INVALIDATE_CHANNEL};
+use crate::protocol::RespValue;
+use crate::storage::glob::GlobPattern;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::RwLock;
+
+/// Subscribers of one channel or pattern, by ID.
+type Subscribers = HashMap<u64, Mailbox>;
+
+/// A pattern subscription.
+struct Pattern {
+    glob: GlobPattern,
+    subscribers: Subscribers,
+}
+
+#[derive(Default)]
+struct Registry {
+    channels: HashMap<Bytes, Subscribers>,
+    patterns: HashMap<Bytes, Pattern>,
+}
+
+/// Channels and patterns with their subscribers.
+#[derive(Default)]
+pub struct PubSub {
+    registry: RwLock<Registry>,
+    /// Keyspace events to publish, as [`NotifyFlags`] bits
+    notify_flags: AtomicU16,
+    /// Keys read by clients with tracking on
+    tracking: Tracking,
+}
+
+impl std::fmt::Debug for PubSub {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let registry = self.registry.read().unwrap();
+        f.debug_struct("PubSub")
+            .field("channels", &registry.channels.len())
+            .field("patterns", &registry.patterns.len())
+            .field("notify_flags", &self.notify_flags().to_string())
+            .finish()
+    }
+}
+
+impl PubSub {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribes `subscriber` to `channel`. Returns the number of
+    /// channels and patterns it is now subscribed to.
+    pub fn subscribe(&self, subscriber: &Subscriber, channel: Bytes) -> usize {
+        if subscriber.add(&channel, false) {
+            let mut registry = self.registry.write().unwrap();
+            registry
+                .channels
+                .entry(channel)
+                .or_default()
+                .insert(subscriber.id(), subscriber.mailbox());
+        }
+        subscriber.count()
+    }
+
+    /// Unsubscribes `subscriber` from `channel`. Returns the number of
+    /// channels and patterns it is still subscribed to.
+    pub fn unsubscribe(&self, subscriber: &Subscriber, channel: &[u8]) -> usize {
+        if subscriber.remove(channel, false) {
+            let mut registry = self.registry.write().unwrap();
+            if let Some(subscribers) = registry.channels.get_mut(channel) {
+                subscribers.remove(&subscriber.id());
+                if subscribers.is_empty() {
+                    registry.channels.remove(channel);
+                }
+            }
+        }
+        subscriber.count()
+    }
+
+    /// Subscribes `subscriber` to every channel matching `pattern`.
+    /// Returns the number of channels and patterns it is now subscribed to.
+    pub fn psubscribe(&self, subscriber: &Subscriber, pattern: Bytes) -> usize {
+        if subscriber.add(&pattern, true) {
+            let mut registry = self.registry.write().unwrap();
+            registry
+                .patterns
+                .entry(pattern.clone())
+                .or_insert_with(|| Pattern {
+                    glob: GlobPattern::new(&pattern),
+                    subscribers: Subscribers::new(),
+                })
+                .subscribers
+                .insert(subscriber.id(), subscriber.mailbox());
+        }
+        subscriber.count()
+    }
+
+    /// Unsubscribes `subscriber` from `pattern`. Returns the number of
+    /// channels and patterns it is still subscribed to.
+    pub fn punsubscribe(&self, subscriber: &Subscriber, pattern: &[u8]) -> usize {
+        if subscriber.remove(pattern, true) {
+            let mut registry = self.registry.write().unwrap();
+            if let Some(entry) = registry.patterns.get_mut(pattern) {
+                entry.subscribers.remove(&subscriber.id());
+                if entry.subscribers.is_empty() {
+                    registry.patterns.remove(pattern);
+                }
+            }
+        }
+        subscriber.count()
+    }
+
+    /// Drops every subscription of `subscriber`, as when its client
+    /// disconnects.
+    pub fn unsubscribe_all(&self, subscriber: &Subscriber) {
+        for channel in subscriber.channels() {
+            self.unsubscribe(subscriber, &channel);
+        }
+        for pattern in subscriber.patterns() {
+            self.punsubscribe(subscriber, &pattern);
+        }
+    }
+
+    /// Publishes `message` to `channel`. Returns the number of
+    /// subscribers it was delivered to, counting pattern subscriptions.
+    pub fn publish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let registry = self.registry.read().unwrap();
+        let channel = Bytes::copy_from_slice(channel);
+        let message = Bytes::copy_from_slice(message);
+        let mut delivered = 0;
+
+        if let Some(subscribers) = registry.channels.get(&channel) {
+            let frame = Frame::new(&RespValue::Push(vec![
+                RespValue::bulk_string("message"),
+                RespValue::bulk_string(channel.clone()),
+                RespValue::bulk_string(message.clone()),
+            ]));
+            delivered += deliver(subscribers, &frame);
+        }
+
+        for (pattern, entry) in &registry.patterns {
+            if !entry.glob.matches(&channel) {
+                continue;
+            }
+            let frame = Frame::new(&RespValue::Push(vec![
+                RespValue::bulk_string("pmessage"),
+                RespValue::bulk_string(pattern.clone()),
+                RespValue::bulk_string(channel.clone()),
+                RespValue::bulk_string(message.clone()),
+            ]));
+            delivered += deliver(&entry.subscribers, &frame);
+        }
+
+        delivered
+    }
+
+    /// Returns the channels with at least one subscriber, optionally only
+    /// those matching `pattern`. Pattern subscriptions are not counted.
+    pub fn channels(&self, pattern: Option<&[u8]>) -> Vec<Bytes> {
+        let pattern = pattern.map(GlobPattern::new);
+        let registry = self.registry.read().unwrap();
+        let mut channels: Vec<Bytes> = registry
+            .channels
+            .keys()
+            .filter(|channel| pattern.as_ref().is_none_or(|p| p.matches(channel)))
+            .cloned()
+            .collect();
+        channels.sort();
+        channels
+    }
+
+    /// Returns the number of subscribers of `channel`, not counting
+    /// pattern subscriptions.
+    pub fn numsub(&self, channel: &[u8]) -> usize {
+        let registry = self.registry.read().unwrap();
+        registry.channels.get(channel).map_or(0, HashMap::len)
+    }
+
+    /// Returns the keyspace events published (`notify-keyspace-events`).
+    pub fn notify_flags(&self) -> NotifyFlags {
+        NotifyFlags::from_bits(self.notify_flags.load(Ordering::Relaxed))
+    }
+
+    /// Sets the keyspace events to publish.
+    pub fn set_notify_flags(&self, flags: NotifyFlags) {
+        self.notify_flags.store(flags.bits(), Ordering::Relaxed);
+    }
+
+    /// Returns whether events of `class` are published, so callers can
+    /// skip working out the events otherwise.
+    pub fn notifies(&self, class: EventClass) -> bool {
+        self.notify_flags().notifies(class)
+    }
+
+    /// Publishes keyspace event `event` on `key` of database `db`, if
+    /// events of `class` are enabled.
+    pub fn notify(&self, db: usize, class: EventClass, event: &str, key: &[u8]) {
+        let flags = self.notify_flags();
+        if !flags.notifies(class) {
+            return;
+        }
+        if flags.keyspace() {
+            let mut channel = format!("__keyspace@{}__:", db).into_bytes();
+            channel.extend_from_slice(key);
+            self.publish(&channel, event.as_bytes());
+        }
+        if flags.keyevent() {
+            let channel = format!("__keyevent@{}__:{}", db, event);
+            self.publish(channel.as_bytes(), key);
+        }
+    }
+
+    /// Returns the invalidation table of the clients with tracking on.
+    pub fn tracking(&self) -> &Tracking {
+        &self.tracking
+    }
+
+    /// Tells the clients tracking `keys` that they changed, as client
+    /// `origin` (`None` for the server itself) wrote them.
+    pub fn invalidate(&self, keys: &[&[u8]], origin: Option<u64>) {
+        if self.tracking.is_empty() {
+            return;
+        }
+        for (target, keys) in self.tracking.invalidate(keys, origin) {
+            let keys = keys.into_iter().map(RespValue::bulk_string).collect();
+            self.send_invalidation(&target, RespValue::array(keys));
+        }
+    }
+
+    /// Tells every client with tracking on to drop its whole cache, as the
+    /// keyspace was flushed.
+    pub fn invalidate_all(&self) {
+        if self.tracking.is_empty() {
+            return;
+        }
+        for target in self.tracking.invalidate_all() {
+            self.send_invalidation(&target, RespValue::null());
+        }
+    }
+
+    /// Sends an invalidation of `keys` (an array, or nil for everything).
+    fn send_invalidation(&self, target: &Target, keys: RespValue) {
+        match target {
+            Target::Push(sender) => {
+                let push = RespValue::Push(vec![RespValue::bulk_string("invalidate"), keys]);
+                let _ = sender.send(Bytes::from(push.serialize_resp3()));
+            }
+            Target::Redirect(id) => {
+                let registry = self.registry.read().unwrap();
+                let mailbox = registry
+                    .channels
+                    .get(INVALIDATE_CHANNEL)
+                    .and_then(|subscribers| subscribers.get(id));
+                if let Some(mailbox) = mailbox {
+                    mailbox.deliver(&Frame::new(&RespValue::Push(vec![
+                        RespValue::bulk_string("message"),
+                        RespValue::bulk_string(INVALIDATE_CHANNEL),
+                        keys,
+                    ])));
+                }
+            }
+        }
+    }
+
+    /// Returns the number of pattern subscriptions across all clients.
+    pub fn numpat(&self) -> usize {
+        let registry = self.registry.read().unwrap();
+        registry
+            .patterns
+            .values()
+            .map(|entry| entry.subscribers.len())
+            .sum()
+    }
+}
+
+/// Queues `frame` for each of `subscribers`; returns how many took it.
+fn deliver(subscribers: &Subscribers, frame: &Frame) -> usize {
+    subscribers
+        .values()
+        .filter(|mailbox| mailbox.deliver(frame))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::parser::parse_message;
+    use std::sync::atomic::AtomicU64;
+
+    fn next_id() -> u64 {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn drain(subscriber: &Subscriber) -> Vec<RespValue> {
+        let mut receiver = subscriber.take_receiver().unwrap();
+        let mut messages = Vec::new();
+        while let Ok(frame) = receiver.try_recv() {
+            messages.push(parse_message(&frame).unwrap().unwrap().0);
+        }
+        messages
+    }
+
+    #[test]
+    fn test_publish_to_channels_and_patterns() {
+        let pubsub = PubSub::new();
+        let a = Subscriber::new(next_id());
+        let b = Subscriber::new(next_id());
+
+        assert_eq!(pubsub.subscribe(&a, Bytes::from("news")), 1);
+        assert_eq!(pubsub.subscribe(&a, Bytes::from("news")), 1);
+        assert_eq!(pubsub.psubscribe(&b, Bytes::from("n*")), 1);
+
+        assert_eq!(pubsub.publish(b"news", b"hi"), 2);
+        assert_eq!(pubsub.publish(b"other", b"hi"), 0);
+
+        assert_eq!(
+            drain(&a),
+            vec![RespValue::array(vec![
+                RespValue::bulk_string("message"),
+                RespValue::bulk_string("news"),
+                RespValue::bulk_string("hi"),
+            ])]
+        );
+        assert_eq!(
+            drain(&b),
+            vec![RespValue::array(vec![
+                RespValue::bulk_string("pmessage"),
+                RespValue::bulk_string("n*"),
+                RespValue::bulk_string("news"),
+                RespValue::bulk_string("hi"),
+            ])]
+        );
+    }
+
+    #[test]
+    fn test_resp3_subscribers_get_pushes() {
+        let pubsub = PubSub::new();
+        let resp2 = Subscriber::new(next_id());
+        let resp3 = Subscriber::new(next_id());
+        resp3.set_resp3(true);
+        pubsub.subscribe(&resp2, Bytes::from("news"));
+        pubsub.subscribe(&resp3, Bytes::from("news"));
+
+        assert_eq!(pubsub.publish(b"news", b"hi"), 2);
+        let message = vec![
+            RespValue::bulk_string("message"),
+            RespValue::bulk_string("news"),
+            RespValue::bulk_string("hi"),
+        ];
+        assert_eq!(drain(&resp2), vec![RespValue::array(message.clone())]);
+        assert_eq!(drain(&resp3), vec![RespValue::Push(message)]);
+    }
+
+    #[test]
+    fn test_introspection() {
+        let pubsub = PubSub::new();
+        let a = Subscriber::new(next_id());
+        let b = Subscriber::new(next_id());
+
+        pubsub.subscribe(&a, Bytes::from("news"));
+        pubsub.subscribe(&a, Bytes::from("sport"));
+        pubsub.subscribe(&b, Bytes::from("news"));
+        pubsub.psubscribe(&a, Bytes::from("n*"));
+        pubsub.psubscribe(&b, Bytes::from("n*"));
+
+        assert_eq!(
+            pubsub.channels(None),
+            vec![Bytes::from("news"), Bytes::from("sport")]
+        );
+        assert_eq!(pubsub.channels(Some(b"s*")), vec![Bytes::from("sport")]);
+        assert_eq!(pubsub.numsub(b"news"), 2);
+        assert_eq!(pubsub.numsub(b"none"), 0);
+        assert_eq!(pubsub.numpat(), 2);
+
+        assert_eq!(pubsub.unsubscribe(&b, b"news"), 1);
+        assert_eq!(pubsub.numsub(b"news"), 1);
+
+        pubsub.unsubscribe_all(&a);
+        assert_eq!(a.count(), 0);
+        assert_eq!(pubsub.channels(None), Vec::<Bytes>::new());
+        assert_eq!(pubsub.numpat(), 1);
+    }
+
+    #[test]
+    fn test_keyspace_notifications() {
+        let pubsub = PubSub::new();
+        let subscriber = Subscriber::new(next_id());
+        pubsub.psubscribe(&subscriber, Bytes::from("__key*__:*"));
+
+        // Disabled by default
+        pubsub.notify(0, EventClass::Generic, "del", b"foo");
+
+        pubsub.set_notify_flags(NotifyFlags::parse("KEg").unwrap());
+        pubsub.notify(0, EventClass::Generic, "del", b"foo");
+        pubsub.notify(0, EventClass::String, "set", b"foo");
+
+        let messages: Vec<(RespValue, RespValue)> = drain(&subscriber)
+            .into_iter()
+            .map(|message| {
+                let parts = message.into_array().unwrap();
+                (parts[2].clone(), parts[3].clone())
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    RespValue::bulk_string("__keyspace@0__:foo"),
+                    RespValue::bulk_string("del")
+                ),
+                (
+                    RespValue::bulk_string("__keyevent@0__:del"),
+                    RespValue::bulk_string("foo")
+                ),
+            ]
+        );
+    }
+}
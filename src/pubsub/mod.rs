@@ -0,0 +1,35 @@
+//! Pub/Sub Module
+//!
+//! Clients subscribe to channels, or to glob patterns over channel names,
+//! and receive every message published to them:
+//!
+//! ```text
+//! subscriber                        server                        publisher
+//!    │  SUBSCRIBE news                │                               │
+//!    │ ─────────────────────────────> │                               │
+//!    │  ["subscribe", "news", 1]      │                               │
+//!    │ <───────────────────────────── │        PUBLISH news hello     │
+//!    │                                │ <──────────────────────────── │
+//!    │                                │  :1                           │
+//!    │  ["message", "news", "hello"]  │ ────────────────────────────> │
+//!    │ <───────────────────────────── │                               │
+//! ```
+//!
+//! Messages are fire-and-forget: a message published to a channel nobody
+//! listens to is dropped. While subscribed, a client may only send the
+//! subscribe commands, PING and QUIT.
+//!
+//! The [registry](hub) is shared by all connections; each client's
+//! [subscriptions](subscriber) live with its command handler. Changes to
+//! keys can be published too, as [keyspace notifications](notify), and
+//! pushed to the clients caching them, as [invalidations](tracking).
+
+pub mod hub;
+pub mod notify;
+pub mod subscriber;
+pub mod tracking;
+
+pub use hub::PubSub;
+pub use notify::{EventClass, NotifyFlags};
+pub use subscriber::Subscriber;
+pub use tracking::{Target, Tracking};
@@ -0,0 +1,196 @@
+//! Keyspace Notifications
+//!
+//! When enabled, changes to keys are published as pub/sub messages on two
+//! kinds of channels:
+//!
+//! ```text
+//! SET foo bar  ──>  PUBLISH __keyspace@0__:foo  set    (K: what happened to foo)
+//!              ──>  PUBLISH __keyevent@0__:set  foo    (E: which key was set)
+//! ```
+//!
+//! The `notify-keyspace-events` setting picks the channel kinds and event
+//! classes, one letter each, as in Redis:
+//!
+//! | Letter | Events                                            |
+//! |--------|---------------------------------------------------|
+//! | `K`    | Keyspace channels, `__keyspace@<db>__:<key>`      |
+//! | `E`    | Keyevent channels, `__keyevent@<db>__:<event>`    |
+//! | `g`    | Generic: `del`, `expire`, `persist`, `rename_*`   |
+//! | `$`    | String commands                                   |
+//! | `l`    | List commands                                     |
+//! | `s`    | Set commands                                      |
+//! | `h`    | Hash commands                                     |
+//! | `z`    | Sorted set commands                               |
+//! | `x`    | `expired`, when a key with a TTL is removed       |
+//! | `e`    | `evicted`                                         |
+//! | `t`    | Stream commands                                   |
+//! | `A`    | Alias for `g$lshzxet`                             |
+//!
+//! At least one of `K` and `E` and one class must be given for anything to
+//! be published. The empty string, the default, disables notifications.
+
+use std::fmt;
+
+/// A class of keyspace events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventClass {
+    /// Type-independent commands: DEL, EXPIRE, RENAME, ...
+    Generic,
+    /// String commands
+    String,
+    /// List commands
+    List,
+    /// Set commands
+    Set,
+    /// Hash commands
+    Hash,
+    /// Sorted set commands
+    ZSet,
+    /// Keys removed because their TTL elapsed
+    Expired,
+    /// Keys removed to free memory
+    Evicted,
+    /// Stream commands
+    Stream,
+}
+
+impl EventClass {
+    /// Every class, in the order their letters are listed.
+    const ALL: [EventClass; 9] = [
+        EventClass::Generic,
+        EventClass::String,
+        EventClass::List,
+        EventClass::Set,
+        EventClass::Hash,
+        EventClass::ZSet,
+        EventClass::Expired,
+        EventClass::Evicted,
+        EventClass::Stream,
+    ];
+
+    /// Returns the letter selecting the class in `notify-keyspace-events`.
+    fn letter(self) -> char {
+        match self {
+            EventClass::Generic => 'g',
+            EventClass::String => '$',
+            EventClass::List => 'l',
+            EventClass::Set => 's',
+            EventClass::Hash => 'h',
+            EventClass::ZSet => 'z',
+            EventClass::Expired => 'x',
+            EventClass::Evicted => 'e',
+            EventClass::Stream => 't',
+        }
+    }
+
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// Keyspace channels are enabled
+const KEYSPACE: u16 = 1 << 14;
+/// Keyevent channels are enabled
+const KEYEVENT: u16 = 1 << 15;
+/// Every event class
+const ALL_CLASSES: u16 = (1 << EventClass::ALL.len()) - 1;
+
+/// The `notify-keyspace-events` setting: which events are published, and
+/// on which channels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NotifyFlags(u16);
+
+impl NotifyFlags {
+    /// Parses a `notify-keyspace-events` value such as `"Ex"` or `"KA"`.
+    /// Returns `None` if it contains a letter that isn't a class.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut flags = 0;
+        for letter in value.chars() {
+            flags |= match letter {
+                'K' => KEYSPACE,
+                'E' => KEYEVENT,
+                'A' => ALL_CLASSES,
+                _ => EventClass::ALL
+                    .iter()
+                    .find(|class| class.letter() == letter)?
+                    .bit(),
+            };
+        }
+        Some(Self(flags))
+    }
+
+    /// Creates flags from their [`bits`](Self::bits).
+    pub(crate) fn from_bits(bits: u16) -> Self {
+        Self(bits)
+    }
+
+    /// Returns the flags as bits, for storing them atomically.
+    pub(crate) fn bits(self) -> u16 {
+        self.0
+    }
+
+    /// Returns whether events of `class` are published at all.
+    pub fn notifies(self, class: EventClass) -> bool {
+        self.0 & class.bit() != 0 && self.0 & (KEYSPACE | KEYEVENT) != 0
+    }
+
+    /// Returns whether events are published on keyspace channels.
+    pub fn keyspace(self) -> bool {
+        self.0 & KEYSPACE != 0
+    }
+
+    /// Returns whether events are published on keyevent channels.
+    pub fn keyevent(self) -> bool {
+        self.0 & KEYEVENT != 0
+    }
+}
+
+impl fmt::Display for NotifyFlags {
+    /// Formats the flags as `CONFIG GET` reports them: classes first, with
+    /// `A` standing for all of them, then `K` and `E`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 & ALL_CLASSES == ALL_CLASSES {
+            f.write_str("A")?;
+        } else {
+            for class in EventClass::ALL {
+                if self.0 & class.bit() != 0 {
+                    write!(f, "{}", class.letter())?;
+                }
+            }
+        }
+        if self.keyspace() {
+            f.write_str("K")?;
+        }
+        if self.keyevent() {
+            f.write_str("E")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_format() {
+        let flags = NotifyFlags::parse("Ex").unwrap();
+        assert!(flags.notifies(EventClass::Expired));
+        assert!(!flags.notifies(EventClass::Generic));
+        assert!(flags.keyevent());
+        assert!(!flags.keyspace());
+        assert_eq!(flags.to_string(), "xE");
+
+        let flags = NotifyFlags::parse("KEA").unwrap();
+        assert!(flags.notifies(EventClass::Stream));
+        assert_eq!(flags.to_string(), "AKE");
+        assert_eq!(NotifyFlags::parse("g$lshzxetKE"), Some(flags));
+
+        // Classes without a channel kind publish nothing
+        assert!(!NotifyFlags::parse("g")
+            .unwrap()
+            .notifies(EventClass::Generic));
+        assert_eq!(NotifyFlags::parse("").unwrap().to_string(), "");
+        assert_eq!(NotifyFlags::parse("Kq"), None);
+    }
+}
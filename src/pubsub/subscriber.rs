@@ -0,0 +1,155 @@
+//! Subscribers
+//!
+//! A [`Subscriber`] is one client's side of pub/sub: the channels and
+//! patterns it listens to, and the queue messages published to them are
+//! delivered through. The connection drains the queue while it waits for
+//! the client's next command.
+
+use crate::protocol::RespValue;
+use bytes::Bytes;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// A client's subscriptions and message queue.
+#[derive(Debug)]
+pub struct Subscriber {
+    /// ID of the client
+    id: u64,
+    /// Sending end of the message queue, cloned into the registry
+    sender: mpsc::UnboundedSender<Bytes>,
+    /// Whether the client speaks RESP3, and gets messages as pushes
+    resp3: Arc<AtomicBool>,
+    /// Receiving end, until the connection takes it
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<Bytes>>>,
+    /// Channels subscribed to, in subscription order
+    channels: Mutex<Vec<Bytes>>,
+    /// Patterns subscribed to, in subscription order
+    patterns: Mutex<Vec<Bytes>>,
+}
+
+impl Subscriber {
+    /// Creates a subscriber with no subscriptions for the client `id`.
+    pub fn new(id: u64) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            id,
+            sender,
+            resp3: Arc::new(AtomicBool::new(false)),
+            receiver: Mutex::new(Some(receiver)),
+            channels: Mutex::new(Vec::new()),
+            patterns: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the ID of the client.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns a sender delivering to this subscriber's queue.
+    pub fn sender(&self) -> mpsc::UnboundedSender<Bytes> {
+        self.sender.clone()
+    }
+
+    /// Returns where the registry delivers this subscriber's messages.
+    pub fn mailbox(&self) -> Mailbox {
+        Mailbox {
+            sender: self.sender.clone(),
+            resp3: Arc::clone(&self.resp3),
+        }
+    }
+
+    /// Records whether the client speaks RESP3, as it switches with HELLO.
+    pub fn set_resp3(&self, resp3: bool) {
+        self.resp3.store(resp3, Ordering::Relaxed);
+    }
+
+    /// Takes the receiving end of the message queue; `None` once taken.
+    ///
+    /// Messages arrive already serialized as RESP.
+    pub fn take_receiver(&self) -> Option<mpsc::UnboundedReceiver<Bytes>> {
+        self.receiver.lock().unwrap().take()
+    }
+
+    /// Returns the channels subscribed to.
+    pub fn channels(&self) -> Vec<Bytes> {
+        self.channels.lock().unwrap().clone()
+    }
+
+    /// Returns the patterns subscribed to.
+    pub fn patterns(&self) -> Vec<Bytes> {
+        self.patterns.lock().unwrap().clone()
+    }
+
+    /// Returns the number of channels and patterns subscribed to.
+    pub fn count(&self) -> usize {
+        self.channels.lock().unwrap().len() + self.patterns.lock().unwrap().len()
+    }
+
+    /// Records a subscription to `channel` (or a pattern); returns `false`
+    /// if it already existed.
+    pub(crate) fn add(&self, channel: &Bytes, pattern: bool) -> bool {
+        let mut list = self.list(pattern).lock().unwrap();
+        if list.contains(channel) {
+            return false;
+        }
+        list.push(channel.clone());
+        true
+    }
+
+    /// Removes a subscription to `channel` (or a pattern); returns `false`
+    /// if there was none.
+    pub(crate) fn remove(&self, channel: &[u8], pattern: bool) -> bool {
+        let mut list = self.list(pattern).lock().unwrap();
+        let before = list.len();
+        list.retain(|c| c != channel);
+        list.len() != before
+    }
+
+    fn list(&self, pattern: bool) -> &Mutex<Vec<Bytes>> {
+        if pattern {
+            &self.patterns
+        } else {
+            &self.channels
+        }
+    }
+}
+
+/// A subscriber's message queue, as held by the registry.
+#[derive(Debug, Clone)]
+pub struct Mailbox {
+    sender: mpsc::UnboundedSender<Bytes>,
+    resp3: Arc<AtomicBool>,
+}
+
+impl Mailbox {
+    /// Queues `frame` in the protocol the client speaks. Returns `false`
+    /// if the client is gone.
+    pub fn deliver(&self, frame: &Frame) -> bool {
+        let bytes = if self.resp3.load(Ordering::Relaxed) {
+            &frame.resp3
+        } else {
+            &frame.resp2
+        };
+        self.sender.send(bytes.clone()).is_ok()
+    }
+}
+
+/// A message serialized once for each protocol, however many subscribers
+/// it goes to: a push frame for RESP3 clients, an array for the others.
+#[derive(Debug)]
+pub struct Frame {
+    resp2: Bytes,
+    resp3: Bytes,
+}
+
+impl Frame {
+    /// Serializes `message` for both protocols.
+    pub fn new(message: &RespValue) -> Self {
+        Self {
+            resp2: Bytes::from(message.serialize()),
+            resp3: Bytes::from(message.serialize_resp3()),
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Client-Side Caching
+//!
+//! Clients that cache values locally turn on `CLIENT TRACKING`; from then
+//! on the server remembers which keys each of them read, and tells them
+//! when one of those keys changes so they can drop it from their cache:
+//!
+//! ```text
+//! client (tracking)                 server                     other client
+//!    │  GET foo                       │                               │
+//!    │ ─────────────────────────────> │  foo: {client}                │
+//!    │  "bar"                         │                               │
+//!    │ <───────────────────────────── │          SET foo baz          │
+//!    │                                │ <──────────────────────────── │
+//!    │  >2 invalidate [foo]           │  foo: {}                      │
+//!    │ <───────────────────────────── │                               │
+//! ```
+//!
+//! A key is reported once: it leaves the [invalidation table](Tracking)
+//! when it changes, and is only tracked again once the client reads it
+//! again. Invalidations go to the client itself as RESP3 push messages, or,
+//! with `REDIRECT`, to another client subscribed to `__redis__:invalidate`
+//! (which also works over RESP2). Flushing the keyspace invalidates
+//! everything at once, as an invalidation of `nil`.
+
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+/// The channel redirected invalidations are published on.
+pub const INVALIDATE_CHANNEL: &[u8] = b"__redis__:invalidate";
+
+/// Where a client's invalidations go.
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// To the client itself, as RESP3 pushes on its message queue
+    Push(mpsc::UnboundedSender<Bytes>),
+    /// To the client with this ID, if it is subscribed to
+    /// [`INVALIDATE_CHANNEL`]
+    Redirect(u64),
+}
+
+/// A client with tracking on.
+#[derive(Debug)]
+struct Client {
+    target: Target,
+    /// Don't report keys the client changed itself
+    noloop: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Clients with tracking on, by ID
+    clients: HashMap<u64, Client>,
+    /// The invalidation table: the clients that read each key
+    keys: HashMap<Bytes, HashSet<u64>>,
+}
+
+/// The invalidation table of the clients with tracking on.
+#[derive(Debug, Default)]
+pub struct Tracking {
+    /// Number of clients with tracking on, so writes can skip the table
+    /// while there are none
+    clients: AtomicUsize,
+    state: Mutex<State>,
+}
+
+impl Tracking {
+    /// Creates an empty table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether no client has tracking on.
+    pub fn is_empty(&self) -> bool {
+        self.clients.load(Ordering::Relaxed) == 0
+    }
+
+    /// Turns tracking on for client `id`, or changes its options.
+    pub fn enable(&self, id: u64, target: Target, noloop: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .clients
+            .insert(id, Client { target, noloop })
+            .is_none()
+        {
+            self.clients.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Turns tracking off for client `id`, forgetting the keys it read.
+    pub fn disable(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.clients.remove(&id).is_none() {
+            return;
+        }
+        self.clients.fetch_sub(1, Ordering::Relaxed);
+        state.keys.retain(|_, clients| {
+            clients.remove(&id);
+            !clients.is_empty()
+        });
+    }
+
+    /// Returns where client `id`'s invalidations go, if it has tracking on.
+    pub fn target(&self, id: u64) -> Option<Target> {
+        let state = self.state.lock().unwrap();
+        state.clients.get(&id).map(|client| client.target.clone())
+    }
+
+    /// Records that client `id` read `keys`, if it has tracking on.
+    pub fn track(&self, id: u64, keys: &[&[u8]]) {
+        let mut state = self.state.lock().unwrap();
+        if !state.clients.contains_key(&id) {
+            return;
+        }
+        for key in keys {
+            match state.keys.get_mut(*key) {
+                Some(clients) => {
+                    clients.insert(id);
+                }
+                None => {
+                    state
+                        .keys
+                        .insert(Bytes::copy_from_slice(key), HashSet::from([id]));
+                }
+            }
+        }
+    }
+
+    /// Removes `keys` from the table, as they were changed by client
+    /// `origin` (`None` for the server itself, as when keys expire).
+    ///
+    /// # Returns
+    ///
+    /// The invalidations to send: each target with the keys it must drop.
+    pub fn invalidate(&self, keys: &[&[u8]], origin: Option<u64>) -> Vec<(Target, Vec<Bytes>)> {
+        let mut state = self.state.lock().unwrap();
+        let mut invalidated: HashMap<u64, Vec<Bytes>> = HashMap::new();
+        for key in keys {
+            let Some((key, clients)) = state.keys.remove_entry(*key) else {
+                continue;
+            };
+            for id in clients {
+                invalidated.entry(id).or_default().push(key.clone());
+            }
+        }
+
+        invalidated
+            .into_iter()
+            .filter_map(|(id, keys)| {
+                let client = state.clients.get(&id)?;
+                let own = client.noloop && origin == Some(id);
+                (!own).then(|| (client.target.clone(), keys))
+            })
+            .collect()
+    }
+
+    /// Empties the table, as when the keyspace is flushed.
+    ///
+    /// # Returns
+    ///
+    /// The targets of every client with tracking on, which must all drop
+    /// their whole cache.
+    pub fn invalidate_all(&self) -> Vec<Target> {
+        let mut state = self.state.lock().unwrap();
+        state.keys.clear();
+        state
+            .clients
+            .values()
+            .map(|client| client.target.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirected(invalidations: Vec<(Target, Vec<Bytes>)>) -> Vec<(u64, Vec<Bytes>)> {
+        let mut redirected: Vec<_> = invalidations
+            .into_iter()
+            .map(|(target, keys)| match target {
+                Target::Redirect(id) => (id, keys),
+                Target::Push(_) => panic!("expected a redirect"),
+            })
+            .collect();
+        redirected.sort();
+        redirected
+    }
+
+    #[test]
+    fn test_keys_are_invalidated_once() {
+        let tracking = Tracking::new();
+        assert!(tracking.is_empty());
+        tracking.enable(1, Target::Redirect(10), false);
+        tracking.enable(2, Target::Redirect(20), false);
+        assert!(!tracking.is_empty());
+
+        tracking.track(1, &[b"a", b"b"]);
+        tracking.track(2, &[b"a"]);
+        // Not tracking
+        tracking.track(3, &[b"c"]);
+
+        assert_eq!(
+            redirected(tracking.invalidate(&[b"a", b"c"], None)),
+            vec![(10, vec![Bytes::from("a")]), (20, vec![Bytes::from("a")])]
+        );
+        // Reported already; must be read again to be tracked
+        assert!(tracking.invalidate(&[b"a"], None).is_empty());
+        assert_eq!(
+            redirected(tracking.invalidate(&[b"b"], Some(2))),
+            vec![(10, vec![Bytes::from("b")])]
+        );
+    }
+
+    #[test]
+    fn test_noloop_and_disable() {
+        let tracking = Tracking::new();
+        tracking.enable(1, Target::Redirect(10), true);
+        tracking.track(1, &[b"a", b"b"]);
+
+        // Its own change isn't reported back
+        assert!(tracking.invalidate(&[b"a"], Some(1)).is_empty());
+        assert_eq!(
+            redirected(tracking.invalidate(&[b"b"], Some(2))),
+            vec![(10, vec![Bytes::from("b")])]
+        );
+
+        tracking.track(1, &[b"a"]);
+        tracking.disable(1);
+        assert!(tracking.is_empty());
+        assert!(tracking.target(1).is_none());
+        assert!(tracking.invalidate(&[b"a"], None).is_empty());
+        assert!(tracking.invalidate_all().is_empty());
+    }
+}
@@ -0,0 +1,148 @@
+//! Replication Backlog
+//!
+//! The backlog keeps the most recent bytes of the replication stream in a
+//! fixed-size ring, addressed by replication offset. A replica that loses
+//! its connection asks to resume from the offset it had reached; if the
+//! backlog still holds everything from there on, the master sends just
+//! that instead of a full snapshot.
+//!
+//! ```text
+//!   offset:  start                                   end
+//!              │◄──────────── held (≤ size) ────────►│
+//!   ring:   [ ....new bytes.... | ....old bytes.... ]
+//!                               ▲
+//!                        end % size
+//! ```
+//!
+//! Byte `n` of the stream lives at `n % size` in the ring, so writing never
+//! moves data; the oldest bytes are simply overwritten.
+
+/// Default size of the backlog in bytes.
+pub const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+
+/// A ring buffer holding the tail of the replication stream.
+#[derive(Debug)]
+pub struct Backlog {
+    /// The ring, allocated up front
+    ring: Vec<u8>,
+    /// Number of bytes held, at most `ring.len()`
+    len: usize,
+    /// Replication offset just past the last byte held
+    end: u64,
+}
+
+impl Backlog {
+    /// Creates an empty backlog of `size` bytes starting at replication
+    /// offset `offset`.
+    pub fn new(size: usize, offset: u64) -> Self {
+        Self {
+            ring: vec![0; size.max(1)],
+            len: 0,
+            end: offset,
+        }
+    }
+
+    /// Returns the size of the ring in bytes.
+    pub fn size(&self) -> usize {
+        self.ring.len()
+    }
+
+    /// Returns the offset of the oldest byte held.
+    pub fn start(&self) -> u64 {
+        self.end - self.len as u64
+    }
+
+    /// Returns the offset just past the newest byte held.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Appends bytes of the replication stream, overwriting the oldest ones
+    /// once the ring is full.
+    pub fn push(&mut self, data: &[u8]) {
+        let size = self.ring.len();
+        self.len = (self.len + data.len()).min(size);
+        // Only the last `size` bytes can survive the push
+        let skipped = data.len().saturating_sub(size);
+        self.end += skipped as u64;
+        let mut data = &data[skipped..];
+
+        while !data.is_empty() {
+            let at = (self.end % size as u64) as usize;
+            let n = data.len().min(size - at);
+            self.ring[at..at + n].copy_from_slice(&data[..n]);
+            self.end += n as u64;
+            data = &data[n..];
+        }
+    }
+
+    /// Returns the bytes from replication offset `offset` to the end, or
+    /// `None` if the backlog no longer (or doesn't yet) hold that offset.
+    pub fn read_from(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.start() || offset > self.end {
+            return None;
+        }
+        let size = self.ring.len();
+        let mut out = Vec::with_capacity((self.end - offset) as usize);
+        let mut from = offset;
+        while from < self.end {
+            let at = (from % size as u64) as usize;
+            let n = ((self.end - from) as usize).min(size - at);
+            out.extend_from_slice(&self.ring[at..at + n]);
+            from += n as u64;
+        }
+        Some(out)
+    }
+
+    /// Changes the size of the ring, keeping as many of the newest bytes
+    /// as fit.
+    pub fn resize(&mut self, size: usize) {
+        let held = self.read_from(self.start()).unwrap_or_default();
+        let mut resized = Backlog::new(size, self.end - held.len() as u64);
+        resized.push(&held);
+        *self = resized;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_keeps_the_newest_bytes() {
+        let mut backlog = Backlog::new(8, 100);
+        assert_eq!(backlog.read_from(100), Some(Vec::new()));
+        assert_eq!(backlog.read_from(101), None);
+
+        backlog.push(b"abcde");
+        assert_eq!((backlog.start(), backlog.end()), (100, 105));
+        assert_eq!(backlog.read_from(102).unwrap(), b"cde");
+
+        // Wraps around, dropping the oldest bytes
+        backlog.push(b"fghij");
+        assert_eq!((backlog.start(), backlog.end()), (102, 110));
+        assert_eq!(backlog.read_from(102).unwrap(), b"cdefghij");
+        assert_eq!(backlog.read_from(101), None);
+        assert_eq!(backlog.read_from(110).unwrap(), b"");
+
+        // A push larger than the ring keeps only its tail
+        backlog.push(b"0123456789");
+        assert_eq!((backlog.start(), backlog.end()), (112, 120));
+        assert_eq!(backlog.read_from(112).unwrap(), b"23456789");
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut backlog = Backlog::new(8, 0);
+        backlog.push(b"abcdefghij");
+
+        backlog.resize(4);
+        assert_eq!(backlog.size(), 4);
+        assert_eq!((backlog.start(), backlog.end()), (6, 10));
+        assert_eq!(backlog.read_from(6).unwrap(), b"ghij");
+
+        backlog.resize(16);
+        backlog.push(b"klm");
+        assert_eq!(backlog.read_from(6).unwrap(), b"ghijklm");
+    }
+}
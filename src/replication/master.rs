@@ -0,0 +1,547 @@
+//! Master Side of Replication
+//!
+//! [`Replication`] keeps the list of attached replicas and feeds each of
+//! them the write commands applied on this server. The command layer calls
+//! [`Replication::feed`] under the same lock the append-only file is written
+//! under, so replicas get commands in the order they were applied, in the
+//! same replayable form.
+//!
+//! From the first replica on, everything fed is also kept in the
+//! [backlog](super::backlog), so a replica that reconnects can pick up
+//! where it left off with [`Replication::attach_partial`].
+
+use super::backlog::{Backlog, DEFAULT_BACKLOG_SIZE};
+use super::replica::MasterLink;
+use crate::storage::engine::{PendingSnapshot, StorageEngine};
+use crate::storage::{aof, clock};
+use bytes::Bytes;
+use rand::Rng;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// A replica whose output buffer grows past this many bytes is
+/// disconnected, so a stalled replica can't exhaust the master's memory.
+pub const REPLICA_OUTPUT_BUFFER_LIMIT: usize = 256 * 1024 * 1024;
+
+/// Length of a replication ID in hex digits.
+const REPLID_LEN: usize = 40;
+
+/// The replication state of this server: as a master, and as a replica
+/// through its [`MasterLink`].
+#[derive(Debug)]
+pub struct Replication {
+    /// Replication ID of the dataset history this server serves
+    replid: String,
+    /// Bytes of write commands fed to replicas so far
+    offset: AtomicU64,
+    /// Attached replicas and the backlog
+    feed: Mutex<Feed>,
+    /// Size the backlog is created with
+    backlog_size: AtomicUsize,
+    /// Replicas attached or attaching, plus one once the backlog exists;
+    /// writes must be fed while non-zero
+    active: AtomicUsize,
+    /// Writes running without being fed, see [`unfed_write`](Self::unfed_write)
+    unfed_writes: AtomicUsize,
+    /// ID given to the next replica
+    next_id: AtomicU64,
+    /// Link to this server's own master
+    link: MasterLink,
+}
+
+/// Where fed commands go.
+#[derive(Debug, Default)]
+struct Feed {
+    /// Attached replicas, each with the sending end of its output buffer
+    replicas: Vec<(Arc<Replica>, mpsc::UnboundedSender<Bytes>)>,
+    /// The tail of the stream, created when the first replica attaches
+    backlog: Option<Backlog>,
+}
+
+/// A replica attached to this server.
+#[derive(Debug)]
+pub struct Replica {
+    /// Unique ID of the replica's connection
+    id: u64,
+    /// Address the replica connected from
+    addr: SocketAddr,
+    /// Port the replica listens on, as announced with REPLCONF (0 if not)
+    listening_port: u16,
+    /// Bytes queued in the output buffer and not yet sent
+    queued: AtomicUsize,
+    /// Replication offset the replica last acknowledged
+    ack_offset: AtomicU64,
+    /// Unix time in milliseconds of the last acknowledgement (or of the
+    /// attach)
+    acked_at_ms: AtomicI64,
+    /// Whether the replica is past its initial synchronization
+    online: AtomicBool,
+}
+
+impl Replica {
+    /// Returns the unique ID of the replica's connection.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the address the replica connected from.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the port the replica announced, if any.
+    pub fn listening_port(&self) -> Option<u16> {
+        (self.listening_port != 0).then_some(self.listening_port)
+    }
+
+    /// Returns the number of bytes waiting in the output buffer.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Records that `n` bytes of the output buffer were sent.
+    pub fn sent(&self, n: usize) {
+        self.queued.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    /// Returns the replication offset the replica last acknowledged.
+    pub fn ack_offset(&self) -> u64 {
+        self.ack_offset.load(Ordering::Relaxed)
+    }
+
+    /// Records an acknowledgement (REPLCONF ACK) from the replica.
+    pub fn ack(&self, offset: u64) {
+        self.ack_offset.fetch_max(offset, Ordering::Relaxed);
+        self.acked_at_ms
+            .store(clock::unix_time_ms(), Ordering::Relaxed);
+    }
+
+    /// Returns the seconds since the replica last acknowledged.
+    pub fn lag_secs(&self) -> i64 {
+        (clock::unix_time_ms() - self.acked_at_ms.load(Ordering::Relaxed)).max(0) / 1000
+    }
+
+    /// Returns true once the replica received its initial snapshot (or
+    /// the backlog it resumed from) and follows the live stream.
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Relaxed)
+    }
+
+    /// Records that the replica's initial synchronization was sent.
+    pub fn set_online(&self) {
+        self.online.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A replica that just asked for a full resynchronization.
+///
+/// The connection finishes the snapshot, sends it, and then forwards what
+/// arrives on `receiver`: every write applied after the snapshot started.
+#[derive(Debug)]
+pub struct FullSync {
+    /// The attached replica
+    pub replica: Arc<Replica>,
+    /// The replica's output buffer
+    pub receiver: mpsc::UnboundedReceiver<Bytes>,
+    /// The keyspace as of the replication offset below
+    pub snapshot: PendingSnapshot,
+    /// Replication ID to send in the `FULLRESYNC` reply
+    pub replid: String,
+    /// Replication offset the snapshot corresponds to
+    pub offset: u64,
+}
+
+/// A replica resuming the stream from the backlog.
+///
+/// The missed part of the stream is already queued on `receiver`; the
+/// connection answers `+CONTINUE` and forwards it like a live stream.
+#[derive(Debug)]
+pub struct PartialSync {
+    /// The attached replica
+    pub replica: Arc<Replica>,
+    /// The replica's output buffer
+    pub receiver: mpsc::UnboundedReceiver<Bytes>,
+    /// Replication ID to send in the `CONTINUE` reply
+    pub replid: String,
+}
+
+/// Marks a write that runs without being fed to replicas; see
+/// [`Replication::unfed_write`].
+pub struct UnfedWrite<'a> {
+    replication: &'a Replication,
+}
+
+impl Drop for UnfedWrite<'_> {
+    fn drop(&mut self) {
+        self.replication.unfed_writes.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Replication {
+    /// Creates the state of a master with no replicas and a new random
+    /// replication ID.
+    pub fn new() -> Self {
+        let mut rng = rand::thread_rng();
+        let replid = (0..REPLID_LEN)
+            .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+            .collect();
+        Self {
+            replid,
+            offset: AtomicU64::new(0),
+            feed: Mutex::new(Feed::default()),
+            backlog_size: AtomicUsize::new(DEFAULT_BACKLOG_SIZE),
+            active: AtomicUsize::new(0),
+            unfed_writes: AtomicUsize::new(0),
+            next_id: AtomicU64::new(1),
+            link: MasterLink::default(),
+        }
+    }
+
+    /// Returns the link to this server's master.
+    pub fn link(&self) -> &MasterLink {
+        &self.link
+    }
+
+    /// Returns the replication ID.
+    pub fn replid(&self) -> &str {
+        &self.replid
+    }
+
+    /// Returns the replication offset: the number of bytes of write
+    /// commands fed to replicas so far.
+    pub fn offset(&self) -> u64 {
+        self.offset.load(Ordering::Relaxed)
+    }
+
+    /// Returns the attached replicas.
+    pub fn replicas(&self) -> Vec<Arc<Replica>> {
+        let feed = self.feed.lock().unwrap();
+        feed.replicas
+            .iter()
+            .map(|(replica, _)| Arc::clone(replica))
+            .collect()
+    }
+
+    /// Returns the size of the backlog in bytes.
+    pub fn backlog_size(&self) -> usize {
+        self.backlog_size.load(Ordering::Relaxed)
+    }
+
+    /// Sets the size of the backlog, resizing it if it exists already.
+    pub fn set_backlog_size(&self, size: usize) {
+        self.backlog_size.store(size, Ordering::Relaxed);
+        if let Some(backlog) = self.feed.lock().unwrap().backlog.as_mut() {
+            backlog.resize(size);
+        }
+    }
+
+    /// Returns the range of offsets the backlog holds, if it exists.
+    pub fn backlog_range(&self) -> Option<(u64, u64)> {
+        let feed = self.feed.lock().unwrap();
+        feed.backlog
+            .as_ref()
+            .map(|backlog| (backlog.start(), backlog.end()))
+    }
+
+    /// Returns whether writes are fed to replicas or the backlog.
+    pub(crate) fn is_feeding(&self) -> bool {
+        self.active.load(Ordering::SeqCst) > 0
+    }
+
+    /// Lets a write skip the feed (and its lock) if no replica is attached
+    /// or attaching.
+    ///
+    /// # Returns
+    ///
+    /// A marker to hold while the write runs, or `None` if the write must
+    /// be fed to replicas under the log lock.
+    pub(crate) fn unfed_write(&self) -> Option<UnfedWrite<'_>> {
+        self.unfed_writes.fetch_add(1, Ordering::SeqCst);
+        let write = UnfedWrite { replication: self };
+        (self.active.load(Ordering::SeqCst) == 0).then_some(write)
+    }
+
+    /// Feeds write commands to every replica and the backlog. Must be
+    /// called with the log lock held, right after the commands were
+    /// applied.
+    ///
+    /// A replica whose output buffer would grow past
+    /// [`REPLICA_OUTPUT_BUFFER_LIMIT`] is disconnected.
+    pub(crate) fn feed(&self, commands: &[Vec<Bytes>]) {
+        if commands.is_empty() {
+            return;
+        }
+        let mut feed = self.feed.lock().unwrap();
+        let Feed { replicas, backlog } = &mut *feed;
+        let Some(backlog) = backlog else {
+            return;
+        };
+
+        let mut buf = Vec::new();
+        for command in commands {
+            aof::encode_command(&mut buf, command);
+        }
+        backlog.push(&buf);
+        let buf = Bytes::from(buf);
+        self.offset.fetch_add(buf.len() as u64, Ordering::Relaxed);
+
+        replicas.retain(|(replica, sender)| {
+            let queued = replica.queued.fetch_add(buf.len(), Ordering::Relaxed) + buf.len();
+            let keep = queued <= REPLICA_OUTPUT_BUFFER_LIMIT && sender.send(buf.clone()).is_ok();
+            if !keep {
+                warn!(
+                    replica = %replica.addr,
+                    queued = queued,
+                    "Disconnecting replica: output buffer limit reached"
+                );
+                self.active.fetch_sub(1, Ordering::SeqCst);
+            }
+            keep
+        });
+    }
+
+    /// Attaches a replica asking for a full resynchronization.
+    ///
+    /// Writes are briefly held off so the snapshot and the stream of
+    /// commands fed afterwards line up exactly: each write is either in the
+    /// snapshot or on the stream, never both or neither.
+    pub fn attach(
+        &self,
+        engine: &StorageEngine,
+        addr: SocketAddr,
+        listening_port: u16,
+    ) -> FullSync {
+        // From now on writes are fed; wait for those that started unfed
+        self.active.fetch_add(1, Ordering::SeqCst);
+        while self.unfed_writes.load(Ordering::SeqCst) > 0 {
+            std::thread::yield_now();
+        }
+
+        let mut log = engine.aof().lock();
+        // The replica starts on database 0 once the snapshot is loaded
+        log.restart_db();
+        let snapshot = engine.begin_snapshot();
+        let mut feed = self.feed.lock().unwrap();
+        if feed.backlog.is_none() {
+            // The backlog has to see every write from now on, replicas or not
+            self.active.fetch_add(1, Ordering::SeqCst);
+            feed.backlog = Some(Backlog::new(self.backlog_size(), self.offset()));
+        }
+        let (replica, receiver) = self.register(&mut feed, addr, listening_port);
+
+        FullSync {
+            replica,
+            receiver,
+            snapshot,
+            replid: self.replid.clone(),
+            offset: self.offset(),
+        }
+    }
+
+    /// Attaches a replica asking to resume the stream at `offset`, as sent
+    /// with PSYNC: one past the last byte it processed.
+    ///
+    /// # Returns
+    ///
+    /// The attached replica with the missed commands queued, or `None` if
+    /// `replid` names another history or the backlog no longer holds the
+    /// offset, in which case the replica needs a full resynchronization.
+    pub fn attach_partial(
+        &self,
+        addr: SocketAddr,
+        listening_port: u16,
+        replid: &str,
+        offset: u64,
+    ) -> Option<PartialSync> {
+        if replid != self.replid {
+            return None;
+        }
+        let mut feed = self.feed.lock().unwrap();
+        let missed = feed.backlog.as_ref()?.read_from(offset.checked_sub(1)?)?;
+
+        self.active.fetch_add(1, Ordering::SeqCst);
+        let (replica, receiver) = self.register(&mut feed, addr, listening_port);
+        replica.set_online();
+        if !missed.is_empty() {
+            replica.queued.fetch_add(missed.len(), Ordering::Relaxed);
+            let (_, sender) = feed.replicas.last().unwrap();
+            let _ = sender.send(Bytes::from(missed));
+        }
+
+        Some(PartialSync {
+            replica,
+            receiver,
+            replid: self.replid.clone(),
+        })
+    }
+
+    /// Adds a replica to the feed. The caller counts it as active.
+    fn register(
+        &self,
+        feed: &mut Feed,
+        addr: SocketAddr,
+        listening_port: u16,
+    ) -> (Arc<Replica>, mpsc::UnboundedReceiver<Bytes>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let replica = Arc::new(Replica {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            addr,
+            listening_port,
+            queued: AtomicUsize::new(0),
+            ack_offset: AtomicU64::new(0),
+            acked_at_ms: AtomicI64::new(clock::unix_time_ms()),
+            online: AtomicBool::new(false),
+        });
+        feed.replicas.push((Arc::clone(&replica), sender));
+        (replica, receiver)
+    }
+
+    /// Detaches a replica whose connection ended.
+    pub fn detach(&self, replica: &Replica) {
+        let mut feed = self.feed.lock().unwrap();
+        let replicas = &mut feed.replicas;
+        if let Some(pos) = replicas.iter().position(|(r, _)| r.id == replica.id) {
+            replicas.remove(pos);
+            self.active.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> SocketAddr {
+        "127.0.0.1:7000".parse().unwrap()
+    }
+
+    fn command(args: &[&str]) -> Vec<Bytes> {
+        args.iter()
+            .map(|a| Bytes::copy_from_slice(a.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn test_writes_after_attach_are_fed() {
+        let engine = StorageEngine::new();
+        let replication = engine.replication();
+        assert_eq!(replication.replid().len(), REPLID_LEN);
+        assert!(replication.unfed_write().is_some());
+
+        engine.set(Bytes::from("before"), Bytes::from("1"));
+        let mut sync = replication.attach(&engine, addr(), 7001);
+        assert!(replication.unfed_write().is_none());
+        assert_eq!(sync.replica.listening_port(), Some(7001));
+        assert_eq!(sync.offset, 0);
+
+        engine.set(Bytes::from("after"), Bytes::from("2"));
+        replication.feed(&[command(&["SET", "after", "2"])]);
+
+        let snapshot = engine.finish_snapshot(sync.snapshot);
+        assert_eq!(snapshot.len(), 1);
+        let fed = sync.receiver.try_recv().unwrap();
+        assert_eq!(&fed[..], b"*3\r\n$3\r\nSET\r\n$5\r\nafter\r\n$1\r\n2\r\n");
+        assert_eq!(replication.offset(), fed.len() as u64);
+        assert_eq!(sync.replica.queued(), fed.len());
+        sync.replica.sent(fed.len());
+
+        replication.detach(&sync.replica);
+        assert!(replication.replicas().is_empty());
+        // The backlog keeps being fed with no replica attached
+        assert!(replication.unfed_write().is_none());
+        assert_eq!(replication.backlog_range(), Some((0, fed.len() as u64)));
+    }
+
+    #[test]
+    fn test_slow_replica_is_disconnected() {
+        let engine = StorageEngine::new();
+        let replication = engine.replication();
+        let mut sync = replication.attach(&engine, addr(), 0);
+        assert_eq!(sync.replica.listening_port(), None);
+
+        let big = "x".repeat(REPLICA_OUTPUT_BUFFER_LIMIT / 2);
+        replication.feed(&[command(&["SET", "k", &big])]);
+        assert_eq!(replication.replicas().len(), 1);
+        replication.feed(&[command(&["SET", "k", &big])]);
+        assert!(replication.replicas().is_empty());
+
+        // The connection sees the buffered commands, then the end
+        assert!(sync.receiver.try_recv().is_ok());
+        assert!(sync.receiver.try_recv().is_err());
+
+        // Detaching again after the feed dropped it is harmless
+        replication.detach(&sync.replica);
+        assert!(replication.replicas().is_empty());
+    }
+
+    #[test]
+    fn test_partial_resync_from_backlog() {
+        let engine = StorageEngine::new();
+        let replication = engine.replication();
+        let replid = replication.replid().to_string();
+        // Nothing to resume from before the backlog exists
+        assert!(replication.attach_partial(addr(), 0, &replid, 1).is_none());
+
+        let sync = replication.attach(&engine, addr(), 0);
+        replication.feed(&[command(&["SET", "a", "1"])]);
+        let reached = replication.offset();
+        replication.detach(&sync.replica);
+
+        // Writes while disconnected go to the backlog only
+        replication.feed(&[command(&["SET", "b", "2"])]);
+        replication.feed(&[command(&["DEL", "a"])]);
+
+        let mut partial = replication
+            .attach_partial(addr(), 0, &replid, reached + 1)
+            .unwrap();
+        let missed = partial.receiver.try_recv().unwrap();
+        assert_eq!(
+            &missed[..],
+            b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n*2\r\n$3\r\nDEL\r\n$1\r\na\r\n"
+        );
+        assert_eq!(partial.replica.queued(), missed.len());
+        replication.feed(&[command(&["SET", "c", "3"])]);
+        assert!(partial.receiver.try_recv().is_ok());
+        replication.detach(&partial.replica);
+
+        // Another history, or an offset the backlog doesn't hold
+        assert!(replication
+            .attach_partial(addr(), 0, "0000", reached + 1)
+            .is_none());
+        let end = replication.offset();
+        assert!(replication
+            .attach_partial(addr(), 0, &replid, end + 2)
+            .is_none());
+        let caught_up = replication
+            .attach_partial(addr(), 0, &replid, end + 1)
+            .unwrap();
+        assert_eq!(caught_up.replica.queued(), 0);
+    }
+
+    #[test]
+    fn test_backlog_size() {
+        let engine = StorageEngine::new();
+        let replication = engine.replication();
+        assert_eq!(replication.backlog_size(), DEFAULT_BACKLOG_SIZE);
+
+        let sync = replication.attach(&engine, addr(), 0);
+        replication.detach(&sync.replica);
+        replication.set_backlog_size(16);
+        replication.feed(&[command(&["SET", "key", "value"])]);
+
+        // Only the newest 16 bytes are left to resume from
+        let end = replication.offset();
+        assert_eq!(replication.backlog_range(), Some((end - 16, end)));
+        let replid = replication.replid().to_string();
+        assert!(replication.attach_partial(addr(), 0, &replid, 1).is_none());
+    }
+}
@@ -0,0 +1,73 @@
+//! Replication Module
+//!
+//! A replica keeps a copy of a master's keyspace up to date by receiving
+//! every write the master applies.
+//!
+//! ## Protocol
+//!
+//! A replica connects like any client and speaks the Redis replication
+//! handshake:
+//!
+//! ```text
+//! replica                                   master
+//!    │  REPLCONF listening-port 6380          │
+//!    │ ─────────────────────────────────────> │  +OK
+//!    │  PSYNC ? -1          (or SYNC)         │
+//!    │ ─────────────────────────────────────> │
+//!    │       +FULLRESYNC <replid> <offset>    │  (PSYNC only)
+//!    │ <───────────────────────────────────── │
+//!    │       $<length>\r\n<snapshot>          │
+//!    │ <───────────────────────────────────── │
+//!    │       *3\r\n$3\r\nSET\r\n...           │  every write from then on
+//!    │ <───────────────────────────────────── │
+//!    │  REPLCONF ACK <offset>                 │  (from time to time)
+//!    │ ─────────────────────────────────────> │
+//! ```
+//!
+//! The snapshot is in the FlashKV [snapshot format](crate::storage::snapshot)
+//! (compressed if the server compresses its snapshots), and the commands
+//! are those the [append-only file](crate::storage::aof) would log: relative
+//! expiries become absolute, generated IDs explicit.
+//!
+//! ## Partial Resynchronization
+//!
+//! Every byte of the stream has an offset, counted from the start of the
+//! server's replication ID. A replica that lost its connection sends
+//! `PSYNC <replid> <offset + 1>` with the ID and offset it had reached. If
+//! the ID matches and the [backlog](backlog) still holds the bytes from
+//! there on, the master answers `+CONTINUE <replid>` and sends just those,
+//! then carries on with the live stream. Otherwise it falls back to a full
+//! resynchronization.
+//!
+//! ## Replicas
+//!
+//! A FlashKV server becomes a replica with `REPLICAOF host port`; the
+//! [`ReplicaLink`](replica::ReplicaLink) task speaks the replica's half of
+//! the protocol above, and reconnects with `PSYNC` to resume when the link
+//! drops. A replica is read-only to its clients: write commands fail with
+//! `-READONLY` and only the master's stream changes the keyspace.
+//!
+//! ## Expiry
+//!
+//! Replicas don't expire keys themselves. When the master finds a key
+//! expired, on access or in the [expiry sweeper](crate::storage::expiry),
+//! it removes it and sends an explicit `DEL`, ahead of the next write (or
+//! after the sweeper pass if none comes). A replica reads an expired key as
+//! missing but keeps it until that `DEL` arrives, so master and replica end
+//! up with the same keys even if their clocks disagree.
+//!
+//! ## Output Buffers
+//!
+//! Commands are queued per replica while the snapshot is being produced and
+//! sent, and whenever the replica reads slower than the master writes. A
+//! replica whose queue passes
+//! [`REPLICA_OUTPUT_BUFFER_LIMIT`](master::REPLICA_OUTPUT_BUFFER_LIMIT) is
+//! disconnected and has to resynchronize.
+
+pub mod backlog;
+pub mod master;
+pub mod replica;
+
+pub use backlog::{Backlog, DEFAULT_BACKLOG_SIZE};
+pub use master::{FullSync, PartialSync, Replica, Replication, REPLICA_OUTPUT_BUFFER_LIMIT};
+pub use replica::{start_replica_link, MasterAddr, MasterLink, ReplicaLink};
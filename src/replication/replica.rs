@@ -0,0 +1,524 @@
+//! Replica Side of Replication
+//!
+//! `REPLICAOF host port` (or `--replicaof` at startup) points the
+//! [`MasterLink`] at a master. The [`ReplicaLink`] task then keeps this
+//! server in sync with it:
+//!
+//! 1. Connects and performs the handshake (`PING`, `REPLCONF`, `PSYNC`).
+//! 2. On `+FULLRESYNC`, replaces the keyspace with the snapshot that
+//!    follows; on `+CONTINUE`, keeps it and resumes the stream.
+//! 3. Applies every command the master streams, and acknowledges the
+//!    offset it reached once a second with `REPLCONF ACK`.
+//!
+//! When the connection drops it reconnects, asking to resume from the
+//! offset it had reached. While this server replicates a master, clients
+//! can read but not write: write commands fail with `-READONLY`.
+
+use crate::commands::CommandHandler;
+use crate::protocol::{RespParser, RespValue};
+use crate::storage::{clock, snapshot, StorageEngine};
+use bytes::{Bytes, BytesMut};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU16, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+/// How often the replica acknowledges the offset it reached.
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the replica waits before reconnecting to its master.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Address of a master.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MasterAddr {
+    /// Host name or IP address
+    pub host: String,
+    /// Port
+    pub port: u16,
+}
+
+/// The replication state of this server as a replica.
+#[derive(Debug)]
+pub struct MasterLink {
+    /// The master to replicate, or `None` if this server is a master
+    master: watch::Sender<Option<MasterAddr>>,
+    /// Port this server listens on, announced to the master
+    listening_port: AtomicU16,
+    /// Whether the link is synchronized and streaming
+    up: AtomicBool,
+    /// Whether a snapshot is being received and loaded
+    sync_in_progress: AtomicBool,
+    /// The master's replication ID and the offset reached in its stream,
+    /// kept across reconnects to resume from
+    position: Mutex<Option<(String, u64)>>,
+    /// Database the master's stream is on at that offset
+    db: AtomicUsize,
+    /// Unix time in milliseconds of the last data from the master, or 0
+    last_io_ms: AtomicI64,
+}
+
+impl Default for MasterLink {
+    fn default() -> Self {
+        Self {
+            master: watch::Sender::new(None),
+            listening_port: AtomicU16::new(0),
+            up: AtomicBool::new(false),
+            sync_in_progress: AtomicBool::new(false),
+            position: Mutex::new(None),
+            db: AtomicUsize::new(0),
+            last_io_ms: AtomicI64::new(0),
+        }
+    }
+}
+
+impl MasterLink {
+    /// Returns the master this server replicates, if any.
+    pub fn master(&self) -> Option<MasterAddr> {
+        self.master.borrow().clone()
+    }
+
+    /// Returns true if this server replicates a master.
+    pub fn is_replica(&self) -> bool {
+        self.master.borrow().is_some()
+    }
+
+    /// Starts replicating `master`, or stops replicating with `None`
+    /// (REPLICAOF). The keyspace is kept either way until a full
+    /// resynchronization replaces it.
+    pub fn set_master(&self, master: Option<MasterAddr>) {
+        self.master.send_if_modified(|current| {
+            if *current == master {
+                return false;
+            }
+            // A different master has a different history to resume
+            *self.position.lock().unwrap() = None;
+            self.up.store(false, Ordering::Relaxed);
+            *current = master;
+            true
+        });
+    }
+
+    /// Sets the port announced to the master with `REPLCONF listening-port`.
+    pub fn set_listening_port(&self, port: u16) {
+        self.listening_port.store(port, Ordering::Relaxed);
+    }
+
+    /// Returns true if the link is synchronized and streaming.
+    pub fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if a snapshot from the master is being loaded.
+    pub fn sync_in_progress(&self) -> bool {
+        self.sync_in_progress.load(Ordering::Relaxed)
+    }
+
+    /// Returns the master's replication ID, once synchronized.
+    pub fn master_replid(&self) -> Option<String> {
+        let position = self.position.lock().unwrap();
+        position.as_ref().map(|(replid, _)| replid.clone())
+    }
+
+    /// Returns the offset reached in the master's stream (0 before the
+    /// first synchronization).
+    pub fn offset(&self) -> u64 {
+        let position = self.position.lock().unwrap();
+        position.as_ref().map_or(0, |&(_, offset)| offset)
+    }
+
+    /// Returns the seconds since the master last sent anything, or `None`
+    /// if it never did.
+    pub fn last_io_secs_ago(&self) -> Option<i64> {
+        match self.last_io_ms.load(Ordering::Relaxed) {
+            0 => None,
+            at => Some((clock::unix_time_ms() - at).max(0) / 1000),
+        }
+    }
+
+    fn advance(&self, n: usize) {
+        if let Some((_, offset)) = self.position.lock().unwrap().as_mut() {
+            *offset += n as u64;
+        }
+    }
+
+    fn touch(&self) {
+        self.last_io_ms
+            .store(clock::unix_time_ms(), Ordering::Relaxed);
+    }
+}
+
+/// A handle to the running replica link.
+///
+/// When this handle is dropped, the link task will be stopped.
+#[derive(Debug)]
+pub struct ReplicaLink {
+    /// Sender to signal shutdown
+    shutdown_tx: watch::Sender<bool>,
+}
+
+impl ReplicaLink {
+    /// Starts the replica link as a background task. It stays idle until
+    /// the engine's [`MasterLink`] names a master.
+    ///
+    /// # Returns
+    ///
+    /// Returns a handle that can be used to stop the link.
+    /// The link will automatically stop when the handle is dropped.
+    pub fn start(engine: Arc<StorageEngine>) -> Self {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        tokio::spawn(link_loop(engine, shutdown_rx));
+
+        Self { shutdown_tx }
+    }
+
+    /// Stops the replica link.
+    ///
+    /// This is called automatically when the handle is dropped.
+    pub fn stop(&self) {
+        let _ = self.shutdown_tx.send(true);
+    }
+}
+
+impl Drop for ReplicaLink {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Starts the replica link.
+pub fn start_replica_link(engine: Arc<StorageEngine>) -> ReplicaLink {
+    ReplicaLink::start(engine)
+}
+
+/// The main link loop: (re)connects to the current master until it changes
+/// or the link is stopped.
+async fn link_loop(engine: Arc<StorageEngine>, mut shutdown_rx: watch::Receiver<bool>) {
+    let link = engine.replication().link();
+    let mut master_rx = link.master.subscribe();
+
+    loop {
+        let master = master_rx.borrow_and_update().clone();
+        let attempt = async {
+            let Some(master) = master else {
+                return std::future::pending().await;
+            };
+            info!(host = %master.host, port = master.port, "Connecting to master");
+            if let Err(e) = sync_with_master(&engine, &master).await {
+                warn!(host = %master.host, port = master.port, error = %e, "Lost link with master");
+            }
+            link.up.store(false, Ordering::Relaxed);
+            link.sync_in_progress.store(false, Ordering::Relaxed);
+            tokio::time::sleep(RETRY_DELAY).await;
+        };
+
+        tokio::select! {
+            _ = attempt => {}
+            result = master_rx.changed() => {
+                if result.is_err() {
+                    return;
+                }
+                link.up.store(false, Ordering::Relaxed);
+                link.sync_in_progress.store(false, Ordering::Relaxed);
+            }
+            result = shutdown_rx.changed() => {
+                if result.is_err() || *shutdown_rx.borrow() {
+                    debug!("Replica link received shutdown signal");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// A connection to the master.
+struct Connection {
+    stream: TcpStream,
+    buffer: BytesMut,
+    parser: RespParser,
+}
+
+impl Connection {
+    /// Sends a command.
+    async fn send(&mut self, args: &[&str]) -> io::Result<()> {
+        let command = RespValue::array(
+            args.iter()
+                .map(|arg| RespValue::bulk_string(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        );
+        self.stream.write_all(&command.serialize()).await
+    }
+
+    /// Sends a command and reads the reply, failing on an error reply.
+    async fn call(&mut self, args: &[&str]) -> io::Result<RespValue> {
+        self.send(args).await?;
+        loop {
+            if let Some((reply, consumed)) = self.parse()? {
+                let _ = self.buffer.split_to(consumed);
+                if let RespValue::Error(e) = reply {
+                    return Err(io::Error::other(format!("{} failed: {}", args[0], e)));
+                }
+                return Ok(reply);
+            }
+            self.read().await?;
+        }
+    }
+
+    /// Reads one line, skipping the empty lines a master may send to keep
+    /// the connection alive.
+    async fn read_line(&mut self) -> io::Result<String> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+                let line = self.buffer.split_to(end + 1);
+                let line = String::from_utf8_lossy(&line).trim_end().to_string();
+                if !line.is_empty() {
+                    return Ok(line);
+                }
+                continue;
+            }
+            self.read().await?;
+        }
+    }
+
+    /// Reads a `$<length>` payload.
+    async fn read_payload(&mut self) -> io::Result<BytesMut> {
+        let line = self.read_line().await?;
+        let len = line
+            .strip_prefix('$')
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or_else(|| invalid(format!("bad snapshot header '{}'", line)))?;
+        while self.buffer.len() < len {
+            self.buffer.reserve(len - self.buffer.len());
+            self.read().await?;
+        }
+        Ok(self.buffer.split_to(len))
+    }
+
+    fn parse(&mut self) -> io::Result<Option<(RespValue, usize)>> {
+        self.parser
+            .parse(&self.buffer)
+            .map_err(|e| invalid(e.to_string()))
+    }
+
+    async fn read(&mut self) -> io::Result<()> {
+        if self.stream.read_buf(&mut self.buffer).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "master closed the connection",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Synchronizes with the master, then applies its stream until the
+/// connection fails.
+async fn sync_with_master(engine: &Arc<StorageEngine>, master: &MasterAddr) -> io::Result<()> {
+    let link = engine.replication().link();
+    let mut conn = Connection {
+        stream: TcpStream::connect((master.host.as_str(), master.port)).await?,
+        buffer: BytesMut::with_capacity(16 * 1024),
+        parser: RespParser::new(),
+    };
+
+    conn.call(&["PING"]).await?;
+    let port = link.listening_port.load(Ordering::Relaxed).to_string();
+    conn.call(&["REPLCONF", "listening-port", &port]).await?;
+    conn.call(&["REPLCONF", "capa", "psync2"]).await?;
+
+    let resume = link.position.lock().unwrap().clone();
+    let (replid, offset) = match &resume {
+        Some((replid, offset)) => (replid.clone(), (offset + 1).to_string()),
+        None => ("?".to_string(), "-1".to_string()),
+    };
+    conn.send(&["PSYNC", &replid, &offset]).await?;
+    let reply = conn.read_line().await?;
+    link.touch();
+
+    let mut words = reply.split_whitespace();
+    match words.next() {
+        Some("+FULLRESYNC") => {
+            let (Some(replid), Some(Ok(offset))) =
+                (words.next(), words.next().map(str::parse::<u64>))
+            else {
+                return Err(invalid(format!("bad reply to PSYNC '{}'", reply)));
+            };
+            link.sync_in_progress.store(true, Ordering::Relaxed);
+            let payload = conn.read_payload().await?;
+            let keys = load_snapshot(engine, payload).await?;
+            info!(
+                keys = keys,
+                offset = offset,
+                "Full resynchronization with master done"
+            );
+            *link.position.lock().unwrap() = Some((replid.to_string(), offset));
+            // The stream that follows a snapshot starts on database 0
+            link.db.store(0, Ordering::Relaxed);
+            link.sync_in_progress.store(false, Ordering::Relaxed);
+        }
+        Some("+CONTINUE") => {
+            if let (Some(replid), Some((current, _))) =
+                (words.next(), link.position.lock().unwrap().as_mut())
+            {
+                *current = replid.to_string();
+            }
+            info!("Resumed the replication stream from the master's backlog");
+        }
+        _ => return Err(invalid(format!("bad reply to PSYNC '{}'", reply))),
+    }
+    link.up.store(true, Ordering::Relaxed);
+
+    stream_from_master(engine, &mut conn).await
+}
+
+/// Replaces the keyspace with a snapshot sent by the master.
+async fn load_snapshot(engine: &Arc<StorageEngine>, payload: BytesMut) -> io::Result<usize> {
+    let engine = Arc::clone(engine);
+    tokio::task::spawn_blocking(move || {
+        engine.flush_all();
+        engine.functions().flush();
+        let keys = snapshot::load_slice(&engine, &mut &payload[..])
+            .map_err(|e| invalid(format!("bad snapshot from master: {}", e)))?;
+        // The log must describe the new keyspace, not the old one
+        if engine.aof().is_enabled() {
+            if let Err(e) = engine.rewrite_aof() {
+                warn!(
+                    "Failed to rewrite the append-only file after a resync: {}",
+                    e
+                );
+            }
+        }
+        Ok(keys)
+    })
+    .await
+    .expect("loading a snapshot panicked")
+}
+
+/// Applies the commands the master streams, acknowledging the offset
+/// reached once a second.
+async fn stream_from_master(engine: &Arc<StorageEngine>, conn: &mut Connection) -> io::Result<()> {
+    let link = engine.replication().link();
+    let handler = CommandHandler::for_master(Arc::clone(engine));
+    handler.session().select(link.db.load(Ordering::Relaxed));
+    let mut ack = tokio::time::interval(ACK_INTERVAL);
+
+    loop {
+        while let Some((command, consumed)) = conn.parse()? {
+            let _ = conn.buffer.split_to(consumed);
+            if is_getack(&command) {
+                link.advance(consumed);
+                send_ack(conn, link.offset()).await?;
+                continue;
+            }
+            handler.execute(command);
+            handler.take_commit_position();
+            link.db.store(handler.session().db(), Ordering::Relaxed);
+            link.advance(consumed);
+        }
+
+        tokio::select! {
+            result = conn.read() => {
+                result?;
+                link.touch();
+            }
+            _ = ack.tick() => send_ack(conn, link.offset()).await?,
+        }
+    }
+}
+
+async fn send_ack(conn: &mut Connection, offset: u64) -> io::Result<()> {
+    conn.send(&["REPLCONF", "ACK", &offset.to_string()]).await
+}
+
+/// Returns true for `REPLCONF GETACK *`, which asks for an ACK right away.
+fn is_getack(command: &RespValue) -> bool {
+    matches!(
+        command.as_array(),
+        Some([cmd, option, ..])
+            if cmd.as_bytes().is_some_and(|c| c.eq_ignore_ascii_case(b"REPLCONF"))
+                && option.as_bytes().is_some_and(|o| o.eq_ignore_ascii_case(b"GETACK"))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{handle_connection, ConnectionStats};
+    use tokio::net::TcpListener;
+
+    async fn start_server(storage: Arc<StorageEngine>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stats = Arc::new(ConnectionStats::new());
+        tokio::spawn(async move {
+            while let Ok((stream, addr)) = listener.accept().await {
+                let handler = CommandHandler::new(Arc::clone(&storage));
+                tokio::spawn(handle_connection(stream, addr, handler, Arc::clone(&stats)));
+            }
+        });
+        port
+    }
+
+    async fn wait_for(mut done: impl FnMut() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("replica didn't catch up");
+    }
+
+    #[tokio::test]
+    async fn test_replica_follows_master() {
+        let master = Arc::new(StorageEngine::new());
+        let port = start_server(Arc::clone(&master)).await;
+        master.set(Bytes::from("before"), Bytes::from("1"));
+
+        let replica = Arc::new(StorageEngine::new());
+        replica.set(Bytes::from("stale"), Bytes::from("x"));
+        let _link = start_replica_link(Arc::clone(&replica));
+        let link = replica.replication().link();
+        link.set_master(Some(MasterAddr {
+            host: "127.0.0.1".to_string(),
+            port,
+        }));
+
+        // The full resynchronization replaces the keyspace
+        wait_for(|| link.is_up()).await;
+        assert_eq!(replica.get(&Bytes::from("before")), Some(Bytes::from("1")));
+        assert_eq!(replica.get(&Bytes::from("stale")), None);
+        assert_eq!(
+            link.master_replid().as_deref(),
+            Some(master.replication().replid())
+        );
+
+        // Then writes on the master stream in, and are acknowledged
+        let handler = CommandHandler::new(Arc::clone(&master));
+        handler.execute(RespValue::array(vec![
+            RespValue::bulk_string(Bytes::from("SET")),
+            RespValue::bulk_string(Bytes::from("after")),
+            RespValue::bulk_string(Bytes::from("2")),
+        ]));
+        wait_for(|| replica.get(&Bytes::from("after")).is_some()).await;
+        assert_eq!(link.offset(), master.replication().offset());
+        let attached = master.replication().replicas();
+        wait_for(|| attached[0].ack_offset() == link.offset()).await;
+        assert!(attached[0].is_online());
+
+        // Stopping replication keeps the data
+        link.set_master(None);
+        wait_for(|| master.replication().replicas().is_empty()).await;
+        assert!(!link.is_up());
+        assert_eq!(replica.get(&Bytes::from("after")), Some(Bytes::from("2")));
+    }
+}
@@ -0,0 +1,658 @@
+//! Function Libraries
+//!
+//! FUNCTION LOAD takes a library: Lua code whose first line names it, and
+//! which registers functions when it runs:
+//!
+//! ```text
+//! #!lua name=counters
+//! redis.register_function('bump', function(keys, args)
+//!     return redis.call('INCRBY', keys[1], args[1])
+//! end)
+//! redis.register_function{
+//!     function_name = 'peek',
+//!     callback = function(keys) return redis.call('GET', keys[1]) end,
+//!     flags = { 'no-writes' },
+//! }
+//! ```
+//!
+//! [`Functions`] holds the libraries loaded, by name, and runs their
+//! functions for FCALL and FCALL_RO. Function names are unique across
+//! libraries. A function without the `no-writes` flag may write, and so
+//! can't be called with FCALL_RO.
+//!
+//! Libraries are kept as the code they were loaded from: snapshots and
+//! FUNCTION DUMP save the code, and loading runs it again.
+//!
+//! A library's code gets [`LOAD_TIMEOUT`] to run when it loads. A function
+//! runs until it returns or FUNCTION KILL stops it; once it has run for
+//! the busy reply threshold, other calls and loads are turned away with a
+//! `BUSY` error instead of waiting for it.
+
+use super::lua::{Vm, Watchdog};
+use crate::protocol::RespValue;
+use crate::storage::snapshot::{self, SnapshotError};
+use bytes::Bytes;
+use mlua::RegistryKey;
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The flags a function can be registered with.
+pub const FUNCTION_FLAGS: &[&str] = &[
+    "no-writes",
+    "allow-oom",
+    "allow-stale",
+    "no-cluster",
+    "allow-cross-slot-keys",
+];
+
+/// The only engine libraries can be written for.
+pub const ENGINE: &str = "LUA";
+
+/// How long a library's code may run while it loads.
+pub const LOAD_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long, in milliseconds, a function runs before other calls are
+/// turned away as busy, by default.
+pub const DEFAULT_BUSY_REPLY_THRESHOLD: u64 = 5000;
+
+/// Errors from loading, deleting or calling functions.
+#[derive(Debug, thiserror::Error)]
+pub enum FunctionError {
+    /// The code doesn't start with a `#!<engine> name=<library>` line
+    #[error("Missing library metadata")]
+    MissingMetadata,
+
+    /// The `#!` line names an engine other than Lua
+    #[error("Engine '{0}' not found")]
+    UnknownEngine(String),
+
+    /// The `#!` line has something other than `name=`
+    #[error("Invalid metadata value given: {0}")]
+    InvalidMetadata(String),
+
+    /// The library's name has characters other than letters, digits and `_`
+    #[error("Library names can only contain letters, numbers, or underscores(_) and must be at least one character long")]
+    InvalidLibraryName,
+
+    /// A library of that name is loaded, and REPLACE wasn't given
+    #[error("Library '{0}' already exists")]
+    LibraryExists(String),
+
+    /// Another library has a function of that name
+    #[error("Function {0} already exists")]
+    FunctionExists(String),
+
+    /// The library registered no functions
+    #[error("No functions registered")]
+    NoFunctions,
+
+    /// The library's code isn't valid Lua
+    #[error("Error compiling function: {0}")]
+    Compile(String),
+
+    /// Running the library's code failed, or registered a bad function
+    #[error("Error registering functions: {0}")]
+    Register(String),
+
+    /// No library of that name is loaded
+    #[error("Library not found")]
+    LibraryNotFound,
+
+    /// No function of that name is loaded
+    #[error("Function not found")]
+    FunctionNotFound,
+
+    /// FCALL_RO called a function that may write
+    #[error("Can not execute a script with write flag using *_ro command.")]
+    MayWrite,
+
+    /// A FUNCTION RESTORE payload that doesn't decode
+    #[error("payload version or checksum are wrong")]
+    BadPayload,
+
+    /// A library's code ran past [`LOAD_TIMEOUT`]
+    #[error("FUNCTION LOAD timeout")]
+    LoadTimeout,
+
+    /// A function has run past the busy reply threshold
+    #[error("Redis is busy running a script. You can only call FUNCTION KILL.")]
+    Busy,
+
+    /// FUNCTION KILL without a function running
+    #[error("No scripts in execution right now.")]
+    NotBusy,
+}
+
+/// What FUNCTION RESTORE does with the libraries already loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RestorePolicy {
+    /// Delete them all first
+    Flush,
+    /// Keep them, and fail if a restored library has the name of one
+    #[default]
+    Append,
+    /// Keep them, but replace those with the name of a restored library
+    Replace,
+}
+
+/// A function a library registered.
+pub struct LibraryFunction {
+    pub(super) name: String,
+    pub(super) description: Option<String>,
+    pub(super) flags: Vec<&'static str>,
+    /// The Lua function, kept in the runtime's registry
+    pub(super) callback: RegistryKey,
+}
+
+impl LibraryFunction {
+    /// Returns the function's name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the description it was registered with, if any.
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// Returns the flags it was registered with.
+    pub fn flags(&self) -> &[&'static str] {
+        &self.flags
+    }
+
+    /// Returns true unless the function has the `no-writes` flag.
+    pub fn may_write(&self) -> bool {
+        !self.flags.contains(&"no-writes")
+    }
+}
+
+/// A loaded library.
+pub struct Library {
+    name: String,
+    code: Bytes,
+    functions: Vec<LibraryFunction>,
+}
+
+impl Library {
+    /// Returns the library's name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the code the library was loaded from.
+    pub fn code(&self) -> &Bytes {
+        &self.code
+    }
+
+    /// Returns the functions it registered, in the order it registered
+    /// them.
+    pub fn functions(&self) -> &[LibraryFunction] {
+        &self.functions
+    }
+}
+
+/// The libraries loaded, by name, and which library has each function.
+#[derive(Default, Clone)]
+struct Registry {
+    libraries: BTreeMap<String, Arc<Library>>,
+    functions: HashMap<String, Arc<Library>>,
+}
+
+impl Registry {
+    fn insert(&mut self, library: Arc<Library>) {
+        for function in &library.functions {
+            self.functions
+                .insert(function.name.clone(), Arc::clone(&library));
+        }
+        self.libraries.insert(library.name.clone(), library);
+    }
+
+    fn remove(&mut self, name: &str) -> Option<Arc<Library>> {
+        let library = self.libraries.remove(name)?;
+        for function in &library.functions {
+            self.functions.remove(&function.name);
+        }
+        Some(library)
+    }
+
+    /// Returns a copy with `libraries` added, replacing those of the same
+    /// name when `replace` is set.
+    fn with(&self, libraries: Vec<Library>, replace: bool) -> Result<Registry, FunctionError> {
+        let mut next = self.clone();
+        for library in libraries {
+            if next.libraries.contains_key(&library.name) {
+                if !replace {
+                    return Err(FunctionError::LibraryExists(library.name));
+                }
+                next.remove(&library.name);
+            }
+            if let Some(taken) = library
+                .functions
+                .iter()
+                .find(|f| next.functions.contains_key(&f.name))
+            {
+                return Err(FunctionError::FunctionExists(taken.name.clone()));
+            }
+            next.insert(Arc::new(library));
+        }
+        Ok(next)
+    }
+}
+
+/// The function libraries of the server, shared by every database.
+pub struct Functions {
+    /// The Lua state libraries run in, held while one loads or one of its
+    /// functions runs, so functions run one at a time
+    vm: Mutex<Vm>,
+    /// Never held while a function runs, so saving a snapshot doesn't wait
+    /// for one to finish
+    registry: RwLock<Registry>,
+    /// Tracks the code running in `vm`, and stops it
+    watchdog: Arc<Watchdog>,
+    /// Milliseconds a function runs before it makes the others busy
+    busy_reply_threshold: AtomicU64,
+}
+
+impl std::fmt::Debug for Functions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Functions")
+            .field("libraries", &self.registry.read().libraries.len())
+            .finish()
+    }
+}
+
+impl Default for Functions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Functions {
+    /// Creates an empty registry with a fresh Lua state.
+    pub fn new() -> Self {
+        let watchdog = Arc::new(Watchdog::new());
+        Self {
+            vm: Mutex::new(Vm::new(Arc::clone(&watchdog))),
+            registry: RwLock::default(),
+            watchdog,
+            busy_reply_threshold: AtomicU64::new(DEFAULT_BUSY_REPLY_THRESHOLD),
+        }
+    }
+
+    /// Returns how long, in milliseconds, a function runs before other
+    /// calls are turned away as busy.
+    pub fn busy_reply_threshold(&self) -> u64 {
+        self.busy_reply_threshold.load(Ordering::Relaxed)
+    }
+
+    /// Sets how long, in milliseconds, a function runs before other calls
+    /// are turned away as busy (CONFIG SET busy-reply-threshold).
+    pub fn set_busy_reply_threshold(&self, ms: u64) {
+        self.busy_reply_threshold.store(ms, Ordering::Relaxed);
+    }
+
+    /// Returns true if a function has run for the busy reply threshold.
+    pub fn is_busy(&self) -> bool {
+        self.watchdog
+            .running_for()
+            .is_some_and(|ran| ran.as_millis() as u64 >= self.busy_reply_threshold())
+    }
+
+    /// Stops the running function (FUNCTION KILL). The writes it made
+    /// already stay, as they were applied one by one.
+    pub fn kill(&self) -> Result<(), FunctionError> {
+        match self.watchdog.kill() {
+            true => Ok(()),
+            false => Err(FunctionError::NotBusy),
+        }
+    }
+
+    /// Takes the Lua state, waiting for the function running, unless it
+    /// has run for the busy reply threshold.
+    fn lock_vm(&self) -> Result<MutexGuard<'_, Vm>, FunctionError> {
+        loop {
+            if let Some(vm) = self.vm.try_lock_for(Duration::from_millis(10)) {
+                return Ok(vm);
+            }
+            if self.is_busy() {
+                return Err(FunctionError::Busy);
+            }
+        }
+    }
+
+    /// Loads a library (FUNCTION LOAD), replacing the library of the same
+    /// name if `replace` is set.
+    ///
+    /// # Returns
+    ///
+    /// The library's name.
+    pub fn load(&self, code: Bytes, replace: bool) -> Result<String, FunctionError> {
+        let vm = self.lock_vm()?;
+        let library = compile(&vm, code)?;
+        let name = library.name.clone();
+
+        let mut registry = self.registry.write();
+        *registry = registry.with(vec![library], replace)?;
+        Ok(name)
+    }
+
+    /// Deletes a library and its functions (FUNCTION DELETE).
+    pub fn delete(&self, name: &str) -> Result<(), FunctionError> {
+        let _vm = self.lock_vm()?;
+        match self.registry.write().remove(name) {
+            Some(_) => Ok(()),
+            None => Err(FunctionError::LibraryNotFound),
+        }
+    }
+
+    /// Deletes every library (FUNCTION FLUSH).
+    pub fn flush(&self) {
+        let _vm = self.vm.lock();
+        *self.registry.write() = Registry::default();
+    }
+
+    /// Returns the loaded libraries, sorted by name.
+    pub fn libraries(&self) -> Vec<Arc<Library>> {
+        self.registry.read().libraries.values().cloned().collect()
+    }
+
+    /// Returns the number of libraries loaded.
+    pub fn len(&self) -> usize {
+        self.registry.read().libraries.len()
+    }
+
+    /// Returns true if no library is loaded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the code of every library, sorted by name, as saved in
+    /// snapshots.
+    pub fn codes(&self) -> Vec<Bytes> {
+        self.registry
+            .read()
+            .libraries
+            .values()
+            .map(|library| library.code.clone())
+            .collect()
+    }
+
+    /// Loads libraries from their code, all of them or none.
+    pub fn restore_codes(
+        &self,
+        codes: Vec<Bytes>,
+        policy: RestorePolicy,
+    ) -> Result<(), FunctionError> {
+        let vm = self.lock_vm()?;
+        let libraries = codes
+            .into_iter()
+            .map(|code| compile(&vm, code))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut registry = self.registry.write();
+        let base = match policy {
+            RestorePolicy::Flush => Registry::default(),
+            _ => registry.clone(),
+        };
+        *registry = base.with(libraries, policy == RestorePolicy::Replace)?;
+        Ok(())
+    }
+
+    /// Serializes every library (FUNCTION DUMP), for
+    /// [`restore`](Self::restore).
+    pub fn dump(&self) -> Bytes {
+        Bytes::from(snapshot::dump_functions(&self.codes()))
+    }
+
+    /// Loads the libraries of a [`dump`](Self::dump) payload (FUNCTION
+    /// RESTORE), all of them or none.
+    pub fn restore(&self, payload: &[u8], policy: RestorePolicy) -> Result<(), FunctionError> {
+        let codes = snapshot::read_functions(payload).map_err(|e| match e {
+            SnapshotError::Function(e) => e,
+            _ => FunctionError::BadPayload,
+        })?;
+        self.restore_codes(codes, policy)
+    }
+
+    /// Calls a function (FCALL, or FCALL_RO when `read_only` is set) with
+    /// its keys and arguments.
+    ///
+    /// Each command the function sends is passed to `run`, along with
+    /// whether the function may write, and `run`'s reply is handed back to
+    /// the function.
+    ///
+    /// # Returns
+    ///
+    /// The function's reply.
+    pub fn call(
+        &self,
+        name: &[u8],
+        keys: &[Bytes],
+        args: &[Bytes],
+        read_only: bool,
+        mut run: impl FnMut(&[Bytes], bool) -> RespValue,
+    ) -> Result<RespValue, FunctionError> {
+        let library = std::str::from_utf8(name)
+            .ok()
+            .and_then(|name| self.registry.read().functions.get(name).cloned())
+            .ok_or(FunctionError::FunctionNotFound)?;
+        let function = library
+            .functions
+            .iter()
+            .find(|f| f.name.as_bytes() == name)
+            .expect("a library has the functions it is indexed under");
+
+        let may_write = function.may_write();
+        if read_only && may_write {
+            return Err(FunctionError::MayWrite);
+        }
+        let vm = self.lock_vm()?;
+        Ok(vm.call(function, keys, args, &mut |command| run(command, may_write)))
+    }
+}
+
+/// Reads a library's `#!` line and runs its code.
+fn compile(vm: &Vm, code: Bytes) -> Result<Library, FunctionError> {
+    let (header, body) = match code.iter().position(|&b| b == b'\n') {
+        Some(end) => (&code[..end], &code[end..]),
+        None => (&code[..], &b""[..]),
+    };
+    let header = std::str::from_utf8(header).map_err(|_| FunctionError::MissingMetadata)?;
+    let mut words = header
+        .strip_prefix("#!")
+        .ok_or(FunctionError::MissingMetadata)?
+        .split_whitespace();
+    let engine = words.next().ok_or(FunctionError::MissingMetadata)?;
+    if !engine.eq_ignore_ascii_case(ENGINE) {
+        return Err(FunctionError::UnknownEngine(engine.to_string()));
+    }
+
+    let mut name = None;
+    for word in words {
+        match word.strip_prefix("name=") {
+            Some(value) => name = Some(value.to_string()),
+            None => return Err(FunctionError::InvalidMetadata(word.to_string())),
+        }
+    }
+    let name = name.ok_or(FunctionError::MissingMetadata)?;
+    if !super::is_valid_name(&name) {
+        return Err(FunctionError::InvalidLibraryName);
+    }
+
+    // The body keeps the newline ending the `#!` line, so line numbers in
+    // errors match the code as loaded
+    let functions = vm.load_library(body, LOAD_TIMEOUT)?;
+    Ok(Library {
+        name,
+        code,
+        functions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_nothing(_: &[Bytes], _: bool) -> RespValue {
+        RespValue::null()
+    }
+
+    #[test]
+    fn test_load_and_call() {
+        let functions = Functions::new();
+        let code = "#!lua name=lib\n\
+                    redis.register_function('echo', function(keys, args) return args[1] end)";
+        assert_eq!(functions.load(Bytes::from(code), false).unwrap(), "lib");
+
+        let reply = functions
+            .call(b"echo", &[], &[Bytes::from("hi")], false, run_nothing)
+            .unwrap();
+        assert_eq!(reply, RespValue::bulk_string("hi"));
+        assert!(matches!(
+            functions.call(b"missing", &[], &[], false, run_nothing),
+            Err(FunctionError::FunctionNotFound)
+        ));
+        assert!(matches!(
+            functions.call(b"echo", &[], &[], true, run_nothing),
+            Err(FunctionError::MayWrite)
+        ));
+    }
+
+    #[test]
+    fn test_bad_libraries() {
+        let functions = Functions::new();
+        let load = |code: &str| functions.load(Bytes::from(code.to_string()), false);
+
+        assert!(matches!(
+            load("return 1"),
+            Err(FunctionError::MissingMetadata)
+        ));
+        assert!(matches!(
+            load("#!js name=lib\n"),
+            Err(FunctionError::UnknownEngine(e)) if e == "js"
+        ));
+        assert!(matches!(
+            load("#!lua name=lib foo=bar\n"),
+            Err(FunctionError::InvalidMetadata(_))
+        ));
+        assert!(matches!(
+            load("#!lua name=my-lib\n"),
+            Err(FunctionError::InvalidLibraryName)
+        ));
+        assert!(matches!(
+            load("#!lua name=lib\nlocal x = 1"),
+            Err(FunctionError::NoFunctions)
+        ));
+        assert!(matches!(
+            load("#!lua name=lib\nthis is not lua"),
+            Err(FunctionError::Compile(_))
+        ));
+        assert!(matches!(
+            load("#!lua name=lib\nredis.register_function('f', function() end, 1)"),
+            Err(FunctionError::Register(_))
+        ));
+        assert!(matches!(
+            load("#!lua name=lib\nredis.register_function{function_name='f', callback=function() end, flags={'fast'}}"),
+            Err(FunctionError::Register(e)) if e == "unknown flag given"
+        ));
+        assert!(functions.is_empty());
+    }
+
+    #[test]
+    fn test_names_are_unique() {
+        let functions = Functions::new();
+        let lib = |name: &str, function: &str| {
+            Bytes::from(format!(
+                "#!lua name={}\nredis.register_function('{}', function() return 1 end)",
+                name, function
+            ))
+        };
+
+        functions.load(lib("a", "f"), false).unwrap();
+        assert!(matches!(
+            functions.load(lib("a", "g"), false),
+            Err(FunctionError::LibraryExists(_))
+        ));
+        assert!(matches!(
+            functions.load(lib("b", "f"), false),
+            Err(FunctionError::FunctionExists(_))
+        ));
+
+        // Replacing a library frees the names of its functions
+        functions.load(lib("a", "g"), true).unwrap();
+        functions.load(lib("b", "f"), false).unwrap();
+        assert_eq!(functions.len(), 2);
+
+        functions.delete("a").unwrap();
+        assert!(matches!(
+            functions.delete("a"),
+            Err(FunctionError::LibraryNotFound)
+        ));
+        assert!(matches!(
+            functions.call(b"g", &[], &[], false, run_nothing),
+            Err(FunctionError::FunctionNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_dump_and_restore() {
+        let functions = Functions::new();
+        let code = Bytes::from(
+            "#!lua name=lib\nredis.register_function{function_name='f', \
+             callback=function() return 1 end, flags={'no-writes'}, description='one'}",
+        );
+        functions.load(code.clone(), false).unwrap();
+        let payload = functions.dump();
+
+        let restored = Functions::new();
+        restored.restore(&payload, RestorePolicy::Append).unwrap();
+        assert_eq!(restored.codes(), vec![code]);
+        let library = &restored.libraries()[0];
+        let function = &library.functions()[0];
+        assert_eq!(function.description(), Some("one"));
+        assert!(!function.may_write());
+
+        assert!(matches!(
+            restored.restore(&payload, RestorePolicy::Append),
+            Err(FunctionError::LibraryExists(_))
+        ));
+        restored.restore(&payload, RestorePolicy::Replace).unwrap();
+        restored.restore(&payload, RestorePolicy::Flush).unwrap();
+        assert_eq!(restored.len(), 1);
+
+        assert!(matches!(
+            restored.restore(b"garbage", RestorePolicy::Flush),
+            Err(FunctionError::BadPayload)
+        ));
+        assert_eq!(restored.len(), 1);
+    }
+
+    #[test]
+    fn test_libraries_have_their_own_globals() {
+        let functions = Functions::new();
+        let set = "#!lua name=a\ncounter = 1\n\
+                   redis.register_function('a', function() return counter end)";
+        let get = "#!lua name=b\n\
+                   redis.register_function('b', function() return counter end)";
+        functions.load(Bytes::from(set), false).unwrap();
+        functions.load(Bytes::from(get), false).unwrap();
+
+        let call = |name: &[u8]| functions.call(name, &[], &[], false, run_nothing).unwrap();
+        assert_eq!(call(b"a"), RespValue::integer(1));
+        assert_eq!(call(b"b"), RespValue::null());
+
+        let sneaky = "#!lua name=c\nredis.call = nil\n\
+                      redis.register_function('c', function() return 1 end)";
+        assert!(matches!(
+            functions.load(Bytes::from(sneaky), false),
+            Err(FunctionError::Register(e)) if e.contains("readonly table")
+        ));
+        let io = "#!lua name=d\n\
+                  redis.register_function('d', function() return io.open('/etc/passwd') end)";
+        functions.load(Bytes::from(io), false).unwrap();
+        assert!(call(b"d").is_error());
+    }
+}
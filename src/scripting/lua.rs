@@ -0,0 +1,725 @@
+//! Lua Runtime
+//!
+//! Every library runs in the same Lua 5.4 state, with only the `base`,
+//! `table`, `string`, `math` and `utf8` standard libraries (and without
+//! `dofile` and `loadfile`), so functions can't reach the file system or
+//! the process. `load` only takes source code: precompiled chunks aren't
+//! verified by Lua and could corrupt the state. Each library's code runs in an environment of its own that
+//! falls back to the shared globals, so the globals one library sets are
+//! invisible to the others.
+//!
+//! A hook checks the [`Watchdog`] every [`HOOK_INSTRUCTIONS`] instructions,
+//! and raises an error once the code was killed or ran out of time, which
+//! `pcall` and `xpcall` pass on rather than catch.
+//!
+//! Functions talk to the server through the read-only `redis` table:
+//!
+//! - `redis.call(command, ...)` runs a command and raises its error reply
+//! - `redis.pcall(command, ...)` runs a command and returns its error reply
+//!   as `{err = message}`
+//! - `redis.error_reply(message)` / `redis.status_reply(message)` build
+//!   error and status replies
+//! - `redis.log(level, message)` logs at `redis.LOG_DEBUG`,
+//!   `redis.LOG_VERBOSE`, `redis.LOG_NOTICE` or `redis.LOG_WARNING`
+//! - `redis.register_function(...)`, only while a library loads
+//!
+//! ## Conversions
+//!
+//! Replies become Lua values the way Redis converts them: integers become
+//! numbers, bulk strings strings, arrays tables, nil `false`, a status
+//! `{ok = status}` and an error `{err = message}`. Going back, numbers are
+//! truncated to integers, `true` becomes 1, `false` and `nil` become nil,
+//! and a table becomes an array up to its first nil unless it has an `ok`
+//! or `err` field.
+
+use super::functions::{FunctionError, LibraryFunction, FUNCTION_FLAGS};
+use crate::protocol::{parse_message, RespValue};
+use crate::storage::zset::format_score;
+use bytes::Bytes;
+use mlua::{
+    Function, HookTriggers, Lua, LuaOptions, MultiValue, RegistryKey, StdLib, Table, Value,
+};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Runs once when the state is created: fills in the Lua half of the
+/// `redis` API, exposes it as a read-only table and returns the trampoline
+/// every function call goes through, which turns errors into error replies.
+const PRELUDE: &str = r#"
+local api, stopped = ...
+local error, pcall, xpcall, tostring, type = error, pcall, xpcall, tostring, type
+local load, select = load, select
+
+dofile, loadfile = nil, nil
+
+-- Code that was killed or timed out can't catch the error and go on
+local function rethrow(ok, ...)
+    if not ok and stopped() then
+        error((...), 0)
+    end
+    return ok, ...
+end
+
+function _G.pcall(f, ...)
+    return rethrow(pcall(f, ...))
+end
+
+function _G.xpcall(f, handler, ...)
+    return rethrow(xpcall(f, handler, ...))
+end
+
+function _G.load(chunk, name, mode, ...)
+    -- An explicit nil environment would replace the globals
+    if select('#', ...) > 0 then
+        return load(chunk, name, 't', ...)
+    end
+    return load(chunk, name, 't')
+end
+
+api.LOG_DEBUG, api.LOG_VERBOSE, api.LOG_NOTICE, api.LOG_WARNING = 0, 1, 2, 3
+
+function api.error_reply(message)
+    return { err = message }
+end
+
+function api.status_reply(message)
+    return { ok = message }
+end
+
+function api.call(...)
+    local reply = api.pcall(...)
+    if type(reply) == 'table' and reply.err then
+        error(reply, 0)
+    end
+    return reply
+end
+
+redis = setmetatable({}, {
+    __index = api,
+    __newindex = function()
+        error('Attempt to modify a readonly table', 2)
+    end,
+    __metatable = false,
+})
+
+return function(callback, keys, args)
+    local ok, reply = pcall(callback, keys, args)
+    if ok then
+        return reply
+    end
+    if type(reply) == 'table' and reply.err then
+        return reply
+    end
+    return { err = 'ERR ' .. tostring(reply) }
+end
+"#;
+
+/// How many instructions Lua code runs between two checks of the
+/// [`Watchdog`].
+const HOOK_INSTRUCTIONS: u32 = 100_000;
+
+/// Running, nothing asked it to stop
+const RUNNING: u8 = 0;
+/// FUNCTION KILL was called
+const KILLED: u8 = 1;
+/// The code ran past its time limit
+const TIMED_OUT: u8 = 2;
+
+/// Tracks the Lua code running, so other threads can see how long it has
+/// run and stop it.
+#[derive(Debug)]
+pub(super) struct Watchdog {
+    epoch: Instant,
+    /// When the code started, in milliseconds since `epoch` plus one, or 0
+    /// when nothing runs
+    started: AtomicU64,
+    /// How long the code may run, in milliseconds, or 0 for as long as it
+    /// takes
+    limit: AtomicU64,
+    /// [`RUNNING`], [`KILLED`] or [`TIMED_OUT`]
+    stop: AtomicU8,
+}
+
+impl Watchdog {
+    pub(super) fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            started: AtomicU64::new(0),
+            limit: AtomicU64::new(0),
+            stop: AtomicU8::new(RUNNING),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64 + 1
+    }
+
+    /// Starts watching code that may run for `limit`, if any.
+    fn start(&self, limit: Option<Duration>) {
+        self.stop.store(RUNNING, Ordering::SeqCst);
+        let limit = limit.map_or(0, |limit| (limit.as_millis() as u64).max(1));
+        self.limit.store(limit, Ordering::SeqCst);
+        self.started.store(self.now(), Ordering::SeqCst);
+    }
+
+    /// Stops watching, returning why the code was stopped, if it was.
+    fn finish(&self) -> u8 {
+        self.started.store(0, Ordering::SeqCst);
+        self.stop.swap(RUNNING, Ordering::SeqCst)
+    }
+
+    /// Returns how long the running code has run, if some is.
+    pub(super) fn running_for(&self) -> Option<Duration> {
+        match self.started.load(Ordering::SeqCst) {
+            0 => None,
+            started => Some(Duration::from_millis(self.now().saturating_sub(started))),
+        }
+    }
+
+    /// Stops the running code at its next check.
+    ///
+    /// # Returns
+    ///
+    /// False if no code was running.
+    pub(super) fn kill(&self) -> bool {
+        if self.started.load(Ordering::SeqCst) == 0 {
+            return false;
+        }
+        let _ = self
+            .stop
+            .compare_exchange(RUNNING, KILLED, Ordering::SeqCst, Ordering::SeqCst);
+        true
+    }
+
+    /// Returns true if the running code was killed or timed out.
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::SeqCst) != RUNNING
+    }
+
+    /// Called by the hook: errors if the code should stop.
+    fn check(&self) -> mlua::Result<()> {
+        let limit = self.limit.load(Ordering::SeqCst);
+        if limit > 0
+            && self
+                .running_for()
+                .is_some_and(|ran| ran.as_millis() as u64 > limit)
+        {
+            let _ =
+                self.stop
+                    .compare_exchange(RUNNING, TIMED_OUT, Ordering::SeqCst, Ordering::SeqCst);
+        }
+        match self.stop.load(Ordering::SeqCst) {
+            RUNNING => Ok(()),
+            KILLED => Err(mlua::Error::RuntimeError(
+                "Script killed by user with FUNCTION KILL".to_string(),
+            )),
+            _ => Err(mlua::Error::RuntimeError("Script timed out".to_string())),
+        }
+    }
+}
+
+/// Tables nested deeper than this in a function's reply are cut off, so a
+/// table that contains itself can't recurse forever.
+const MAX_REPLY_DEPTH: usize = 64;
+
+/// The Lua state functions run in.
+pub(super) struct Vm {
+    lua: Lua,
+    watchdog: Arc<Watchdog>,
+    /// The table behind `redis`, where the Rust half of the API goes
+    api: RegistryKey,
+    /// The trampoline returned by [`PRELUDE`]
+    invoke: RegistryKey,
+}
+
+impl Vm {
+    /// Creates a state with the sandboxed libraries and the `redis` API,
+    /// whose code `watchdog` can stop.
+    pub(super) fn new(watchdog: Arc<Watchdog>) -> Self {
+        let libs = StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8;
+        let lua = Lua::new_with(libs, LuaOptions::default()).expect("the Lua libraries load");
+        let hook = Arc::clone(&watchdog);
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS),
+            move |_, _| hook.check(),
+        );
+
+        let (api, invoke) = (|| {
+            let api = lua.create_table()?;
+            api.set("log", lua.create_function(log)?)?;
+            let watchdog = Arc::clone(&watchdog);
+            let stopped = lua.create_function(move |_, ()| Ok(watchdog.stopped()))?;
+            let invoke: Function = lua
+                .load(PRELUDE)
+                .set_name("=prelude")
+                .call((api.clone(), stopped))?;
+            Ok::<_, mlua::Error>((
+                lua.create_registry_value(api)?,
+                lua.create_registry_value(invoke)?,
+            ))
+        })()
+        .expect("the Lua prelude runs");
+
+        Self {
+            lua,
+            watchdog,
+            api,
+            invoke,
+        }
+    }
+
+    /// Runs a library's code (without its `#!` line) in an environment of
+    /// its own, for up to `timeout`, and returns the functions it
+    /// registered.
+    pub(super) fn load_library(
+        &self,
+        code: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<LibraryFunction>, FunctionError> {
+        let lua = &self.lua;
+        let api: Table = lua.registry_value(&self.api)?;
+
+        let env = lua.create_table()?;
+        env.set("_G", env.clone())?;
+        let meta = lua.create_table()?;
+        meta.set("__index", lua.globals())?;
+        env.set_metatable(Some(meta));
+
+        let chunk = lua
+            .load(code)
+            .set_name("@user_function")
+            .set_environment(env)
+            .into_function()
+            .map_err(|e| FunctionError::Compile(error_message(&e)))?;
+
+        let registered = RefCell::new(Vec::new());
+        self.watchdog.start(Some(timeout));
+        let result = lua.scope(|scope| {
+            let register = scope.create_function(|lua, args| register(lua, args, &registered))?;
+            api.set("register_function", register)?;
+            chunk.call::<_, ()>(())
+        });
+        let stopped = self.watchdog.finish();
+        api.set("register_function", Value::Nil)?;
+        match stopped {
+            RUNNING => {}
+            TIMED_OUT => return Err(FunctionError::LoadTimeout),
+            _ => {
+                return Err(FunctionError::Register(
+                    "Script killed by user with FUNCTION KILL".to_string(),
+                ))
+            }
+        }
+        result.map_err(|e| FunctionError::Register(error_message(&e)))?;
+
+        let registered = registered.into_inner();
+        if registered.is_empty() {
+            return Err(FunctionError::NoFunctions);
+        }
+        Ok(registered)
+    }
+
+    /// Calls a registered function with its `keys` and `args`, passing each
+    /// command it sends with `redis.call` or `redis.pcall` to `run`. The
+    /// function runs until it returns or is killed.
+    ///
+    /// # Returns
+    ///
+    /// The function's reply, or an error reply if it raised an error or
+    /// was killed.
+    pub(super) fn call(
+        &self,
+        function: &LibraryFunction,
+        keys: &[Bytes],
+        args: &[Bytes],
+        run: &mut dyn FnMut(&[Bytes]) -> RespValue,
+    ) -> RespValue {
+        let lua = &self.lua;
+        let run = RefCell::new(run);
+        self.watchdog.start(None);
+        let result = lua.scope(|scope| {
+            let api: Table = lua.registry_value(&self.api)?;
+            let pcall = scope.create_function(|lua, args: MultiValue| {
+                let reply = match command_args(args) {
+                    Ok(command) => (run.borrow_mut())(&command),
+                    Err(e) => e,
+                };
+                to_lua(lua, reply)
+            })?;
+            api.set("pcall", pcall)?;
+
+            let invoke: Function = lua.registry_value(&self.invoke)?;
+            let callback: Function = lua.registry_value(&function.callback)?;
+            let reply: Value =
+                invoke.call((callback, sequence(lua, keys)?, sequence(lua, args)?))?;
+            api.set("pcall", Value::Nil)?;
+            from_lua(reply, 0)
+        });
+        // Even if the function caught the error and returned
+        if self.watchdog.finish() == KILLED {
+            return RespValue::error("ERR Script killed by user with FUNCTION KILL");
+        }
+        result.unwrap_or_else(|e| {
+            RespValue::error(single_line(&format!("ERR {}", error_message(&e))))
+        })
+    }
+}
+
+/// `redis.register_function(name, callback)`, or
+/// `redis.register_function{function_name=..., callback=..., flags={...},
+/// description=...}`.
+fn register<'lua>(
+    lua: &'lua Lua,
+    args: MultiValue<'lua>,
+    registered: &RefCell<Vec<LibraryFunction>>,
+) -> mlua::Result<()> {
+    let error = |message: &str| Err(mlua::Error::RuntimeError(message.to_string()));
+
+    let mut args = args.into_iter();
+    let (spec, callback) = match (args.next(), args.next(), args.next()) {
+        (Some(spec), None, None) => (spec, None),
+        (Some(spec), Some(Value::Function(callback)), None) => (spec, Some(callback)),
+        _ => return error("wrong number of arguments to redis.register_function"),
+    };
+    let (name, callback, flags, description) = match spec {
+        Value::Table(spec) if callback.is_none() => {
+            for pair in spec.clone().pairs::<Value, Value>() {
+                let (key, _) = pair?;
+                let known = matches!(
+                    &key,
+                    Value::String(key) if matches!(
+                        key.as_bytes(),
+                        b"function_name" | b"callback" | b"flags" | b"description"
+                    )
+                );
+                if !known {
+                    return error("unknown argument given to redis.register_function");
+                }
+            }
+            let Ok(name) = spec.get::<_, Option<String>>("function_name") else {
+                return error(
+                    "function_name argument given to redis.register_function must be a string",
+                );
+            };
+            let Ok(callback) = spec.get::<_, Option<Function>>("callback") else {
+                return error(
+                    "callback argument given to redis.register_function must be a function",
+                );
+            };
+            let Ok(flags) = spec.get::<_, Option<Table>>("flags") else {
+                return error("flags argument to redis.register_function must be a table representing function flags");
+            };
+            let Ok(description) = spec.get::<_, Option<String>>("description") else {
+                return error(
+                    "description argument given to redis.register_function must be a string",
+                );
+            };
+            (name, callback, flags, description)
+        }
+        Value::String(name) => (Some(name.to_str()?.to_string()), callback, None, None),
+        _ => return error("wrong arguments given to redis.register_function"),
+    };
+
+    let Some(name) = name else {
+        return error("redis.register_function must get a function name argument");
+    };
+    let Some(callback) = callback else {
+        return error("redis.register_function must get a callback argument");
+    };
+    if !super::is_valid_name(&name) {
+        return error("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long");
+    }
+    if registered.borrow().iter().any(|f| f.name == name) {
+        return error("Function already exists in the library");
+    }
+
+    let mut function_flags = Vec::new();
+    for flag in flags
+        .into_iter()
+        .flat_map(|flags| flags.sequence_values::<String>())
+    {
+        let flag = flag?;
+        match FUNCTION_FLAGS.iter().find(|known| **known == flag) {
+            Some(flag) => function_flags.push(*flag),
+            None => return error("unknown flag given"),
+        }
+    }
+
+    registered.borrow_mut().push(LibraryFunction {
+        name,
+        description,
+        flags: function_flags,
+        callback: lua.create_registry_value(callback)?,
+    });
+    Ok(())
+}
+
+/// `redis.log(level, message)`.
+fn log(_: &Lua, (level, message): (i64, mlua::String)) -> mlua::Result<()> {
+    let message = message.to_string_lossy();
+    match level {
+        0 | 1 => debug!("{}", message),
+        2 => info!("{}", message),
+        3 => warn!("{}", message),
+        _ => return Err(mlua::Error::RuntimeError("Invalid debug level.".into())),
+    }
+    Ok(())
+}
+
+/// Turns the arguments of `redis.call` into a command, or the error reply
+/// to give the function instead.
+fn command_args(args: MultiValue) -> Result<Vec<Bytes>, RespValue> {
+    if args.is_empty() {
+        return Err(RespValue::error(
+            "ERR Please specify at least one argument for this redis lib call",
+        ));
+    }
+    args.into_iter()
+        .map(|arg| match arg {
+            Value::String(s) => Ok(Bytes::copy_from_slice(s.as_bytes())),
+            Value::Integer(n) => Ok(Bytes::from(n.to_string())),
+            Value::Number(n) => Ok(format_score(n)),
+            _ => Err(RespValue::error(
+                "ERR Lua redis lib command arguments must be strings or integers",
+            )),
+        })
+        .collect()
+}
+
+/// Creates a Lua array of strings.
+fn sequence<'lua>(lua: &'lua Lua, items: &[Bytes]) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table_with_capacity(items.len(), 0)?;
+    for item in items {
+        table.raw_push(lua.create_string(item)?)?;
+    }
+    Ok(table)
+}
+
+/// Converts a command's reply to the Lua value a function gets.
+fn to_lua(lua: &Lua, reply: RespValue) -> mlua::Result<Value<'_>> {
+    let tagged = |tag: &str, message: String| -> mlua::Result<Value<'_>> {
+        let table = lua.create_table()?;
+        table.set(tag, message)?;
+        Ok(Value::Table(table))
+    };
+
+    Ok(match reply {
+        RespValue::SimpleString(s) => return tagged("ok", s),
+        RespValue::Error(e) => return tagged("err", e),
+        RespValue::Integer(n) => Value::Integer(n),
+        RespValue::BulkString(b) => Value::String(lua.create_string(&b)?),
+        RespValue::Double(n) => Value::String(lua.create_string(format_score(n))?),
+        RespValue::Boolean(b) => Value::Integer(b as i64),
+        RespValue::BigNumber(n) => Value::String(lua.create_string(n)?),
+        RespValue::VerbatimString { text, .. } => Value::String(lua.create_string(&text)?),
+        RespValue::Array(items) | RespValue::Set(items) | RespValue::Push(items) => {
+            let table = lua.create_table_with_capacity(items.len(), 0)?;
+            for item in items {
+                table.raw_push(to_lua(lua, item)?)?;
+            }
+            Value::Table(table)
+        }
+        RespValue::Map(pairs) => {
+            let table = lua.create_table_with_capacity(pairs.len() * 2, 0)?;
+            for (key, value) in pairs {
+                table.raw_push(to_lua(lua, key)?)?;
+                table.raw_push(to_lua(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+        RespValue::EncodedArray { len, elements } => {
+            // Serialized ahead of time, so parsed back
+            let mut frame = format!("*{}\r\n", len).into_bytes();
+            frame.extend_from_slice(&elements);
+            match parse_message(&frame) {
+                Ok(Some((array, _))) => return to_lua(lua, array),
+                _ => return tagged("err", "ERR unreadable reply".to_string()),
+            }
+        }
+        RespValue::Null | RespValue::NullArray => Value::Boolean(false),
+    })
+}
+
+/// Converts what a function returned to the reply the client gets.
+fn from_lua(value: Value, depth: usize) -> mlua::Result<RespValue> {
+    Ok(match value {
+        Value::Boolean(true) => RespValue::integer(1),
+        Value::Integer(n) => RespValue::integer(n),
+        Value::Number(n) => RespValue::integer(n as i64),
+        Value::String(s) => RespValue::bulk_string(Bytes::copy_from_slice(s.as_bytes())),
+        Value::Table(table) => {
+            if let Some(err) = table
+                .raw_get::<_, Option<mlua::String>>("err")
+                .ok()
+                .flatten()
+            {
+                return Ok(RespValue::error(single_line(&err.to_string_lossy())));
+            }
+            if let Some(ok) = table
+                .raw_get::<_, Option<mlua::String>>("ok")
+                .ok()
+                .flatten()
+            {
+                return Ok(RespValue::simple_string(single_line(&ok.to_string_lossy())));
+            }
+            if depth >= MAX_REPLY_DEPTH {
+                return Ok(RespValue::error("ERR reached lua stack limit"));
+            }
+            let mut items = Vec::new();
+            for i in 1.. {
+                match table.raw_get::<_, Value>(i)? {
+                    Value::Nil => break,
+                    item => items.push(from_lua(item, depth + 1)?),
+                }
+            }
+            RespValue::array(items)
+        }
+        _ => RespValue::null(),
+    })
+}
+
+/// Returns the message of a Lua error, without the callback wrappers and
+/// tracebacks mlua adds around it.
+fn error_message(error: &mlua::Error) -> String {
+    match error {
+        mlua::Error::RuntimeError(message) => message.clone(),
+        mlua::Error::SyntaxError { message, .. } => message.clone(),
+        mlua::Error::CallbackError { cause, .. } => error_message(cause),
+        error => error.to_string(),
+    }
+}
+
+/// Replaces line breaks, which can't appear in a status or error reply.
+fn single_line(message: &str) -> String {
+    message.replace(['\r', '\n'], " ")
+}
+
+impl From<mlua::Error> for FunctionError {
+    fn from(error: mlua::Error) -> Self {
+        FunctionError::Register(error_message(&error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_conversions() {
+        let lua = Lua::new();
+        let reply = RespValue::array(vec![
+            RespValue::integer(7),
+            RespValue::bulk_string("v"),
+            RespValue::null(),
+            RespValue::simple_string("OK"),
+            RespValue::Double(1.5),
+        ]);
+        let value = to_lua(&lua, reply).unwrap();
+        let back = from_lua(value, 0).unwrap();
+        assert_eq!(
+            back,
+            RespValue::array(vec![
+                RespValue::integer(7),
+                RespValue::bulk_string("v"),
+                RespValue::null(),
+                RespValue::simple_string("OK"),
+                RespValue::bulk_string("1.5"),
+            ])
+        );
+
+        let table = lua.create_table().unwrap();
+        table.set("ok", "fine").unwrap();
+        assert_eq!(
+            from_lua(Value::Table(table), 0).unwrap(),
+            RespValue::simple_string("fine")
+        );
+        assert_eq!(
+            from_lua(Value::Number(3.9), 0).unwrap(),
+            RespValue::integer(3)
+        );
+        assert_eq!(
+            from_lua(Value::Boolean(false), 0).unwrap(),
+            RespValue::null()
+        );
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_load_only_takes_source() {
+        let vm = Vm::new(Arc::new(Watchdog::new()));
+        let function = |code: &str| {
+            let code = format!("redis.register_function('f', function() {} end)", code);
+            let functions = vm.load_library(code.as_bytes(), TIMEOUT).unwrap();
+            vm.call(&functions[0], &[], &[], &mut |_| RespValue::null())
+        };
+
+        assert_eq!(
+            function("return load('return 1 + 1')()"),
+            RespValue::integer(2)
+        );
+        assert_eq!(
+            function("local env = {x = 5} return load('return x', 'x', 'b', env)()"),
+            RespValue::integer(5)
+        );
+        let reply = function(
+            "local f, e = load(string.dump(function() return 1 end), 'b', 'b') return {f == nil, e}",
+        );
+        match reply.as_array() {
+            Some([RespValue::Integer(1), RespValue::BulkString(e)]) => {
+                assert!(e.starts_with(b"attempt to load a binary chunk"))
+            }
+            _ => panic!("unexpected reply: {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn test_runaway_code_is_stopped() {
+        let watchdog = Arc::new(Watchdog::new());
+        let vm = Vm::new(Arc::clone(&watchdog));
+        assert!(matches!(
+            vm.load_library(b"while true do end", Duration::from_millis(50)),
+            Err(FunctionError::LoadTimeout)
+        ));
+        assert!(watchdog.running_for().is_none());
+        assert!(!watchdog.kill());
+
+        // Catching the error doesn't keep a killed function running
+        let code = "redis.register_function('spin', function() \
+                        while true do pcall(function() while true do end end) end \
+                    end)";
+        let functions = vm.load_library(code.as_bytes(), TIMEOUT).unwrap();
+        let killer = std::thread::spawn(move || {
+            while watchdog.running_for().is_none() {
+                std::thread::yield_now();
+            }
+            assert!(watchdog.kill());
+        });
+        let reply = vm.call(&functions[0], &[], &[], &mut |_| RespValue::null());
+        killer.join().unwrap();
+        assert_eq!(
+            reply,
+            RespValue::error("ERR Script killed by user with FUNCTION KILL")
+        );
+
+        // The next call runs normally
+        let code = "redis.register_function('one', function() return 1 end)";
+        let functions = vm.load_library(code.as_bytes(), TIMEOUT).unwrap();
+        let reply = vm.call(&functions[0], &[], &[], &mut |_| RespValue::null());
+        assert_eq!(reply, RespValue::integer(1));
+    }
+
+    #[test]
+    fn test_self_referencing_reply() {
+        let lua = Lua::new();
+        let table: Table = lua.load("local t = {} t[1] = t return t").eval().unwrap();
+        let mut reply = from_lua(Value::Table(table), 0).unwrap();
+        for _ in 0..MAX_REPLY_DEPTH {
+            reply = reply.as_array().unwrap()[0].clone();
+        }
+        assert!(reply.is_error());
+    }
+}
@@ -0,0 +1,34 @@
+//! Scripting Module
+//!
+//! Server-side functions, written in Lua and loaded in libraries:
+//!
+//! ```text
+//! client                                     server
+//!    │  FUNCTION LOAD "#!lua name=lib ..."     │
+//!    │ ──────────────────────────────────────> │  runs the library, which
+//!    │  "lib"                                  │  registers its functions
+//!    │ <────────────────────────────────────── │
+//!    │  FCALL bump 1 counter 5                 │
+//!    │ ──────────────────────────────────────> │  calls bump({"counter"}, {"5"}),
+//!    │  :5                                     │  which runs INCRBY counter 5
+//!    │ <────────────────────────────────────── │
+//! ```
+//!
+//! The [registry](functions) is the server's, shared by every database and
+//! saved with the keyspace in snapshots; the [runtime](lua) is a single
+//! sandboxed Lua state, so functions run one at a time. Other clients'
+//! commands still run while a function does: a function's commands are
+//! applied one by one, not as a single atomic step.
+
+pub mod functions;
+mod lua;
+
+pub use functions::{
+    FunctionError, Functions, Library, LibraryFunction, RestorePolicy, DEFAULT_BUSY_REPLY_THRESHOLD,
+};
+
+/// Returns true if `name` is a valid library or function name: one or more
+/// ASCII letters, digits and underscores.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
@@ -0,0 +1,204 @@
+//! Global Allocator and Its Statistics
+//!
+//! Values of very different sizes, freed and reallocated as keys are
+//! overwritten, fragment the system allocator's heap: the space freed by
+//! small values can't hold larger ones, so the process keeps much more
+//! memory resident than the dataset needs. The `jemalloc` and `mimalloc`
+//! cargo features make one of those the global allocator instead; both
+//! group allocations by size class, which keeps fragmentation down. If both
+//! features are enabled, jemalloc is used.
+//!
+//! [`stats`] reports what the allocator in use knows about its memory, for
+//! MEMORY STATS and INFO:
+//!
+//! | Field       | jemalloc          | mimalloc              | System      |
+//! |-------------|-------------------|-----------------------|-------------|
+//! | `allocated` | `stats.allocated` | -                     | -           |
+//! | `active`    | `stats.active`    | committed memory      | -           |
+//! | `resident`  | `stats.resident`  | resident set estimate | -           |
+//! | `rss`       | process RSS       | process RSS           | process RSS |
+//!
+//! `active / allocated` is the allocator's own fragmentation: the share of
+//! the pages it hands out that allocations don't fill. The process RSS
+//! against the dataset's accounted size covers fragmentation and overhead
+//! together, whatever the allocator. The RSS is read from `/proc`, so it is
+//! only known on Linux.
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+/// Memory statistics of the global allocator. Fields the allocator in use
+/// doesn't report are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AllocatorStats {
+    /// Bytes allocated and not yet freed
+    pub allocated: Option<usize>,
+    /// Bytes in the pages the allocator has handed out allocations from
+    pub active: Option<usize>,
+    /// Bytes of the allocator's memory resident in RAM
+    pub resident: Option<usize>,
+    /// Resident set size of the whole process
+    pub rss: Option<usize>,
+}
+
+impl AllocatorStats {
+    /// Returns the allocator's fragmentation: active bytes per allocated
+    /// byte.
+    pub fn fragmentation_ratio(&self) -> Option<f64> {
+        ratio(self.active, self.allocated)
+    }
+
+    /// Returns resident bytes per active byte, how much the allocator keeps
+    /// in RAM beyond the pages in use.
+    pub fn rss_ratio(&self) -> Option<f64> {
+        ratio(self.resident, self.active)
+    }
+}
+
+fn ratio(numerator: Option<usize>, denominator: Option<usize>) -> Option<f64> {
+    match (numerator, denominator) {
+        (Some(n), Some(d)) if d > 0 => Some(n as f64 / d as f64),
+        _ => None,
+    }
+}
+
+/// Returns the name and version of the global allocator, as INFO's
+/// `mem_allocator` shows it.
+pub fn name() -> String {
+    #[cfg(feature = "jemalloc")]
+    {
+        let version = tikv_jemalloc_ctl::version::read().unwrap_or("unknown");
+        // Drop the commit the version string ends with
+        let version = version.split('-').next().unwrap_or(version);
+        format!("jemalloc-{}", version)
+    }
+    #[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+    {
+        // SAFETY: mi_version has no preconditions
+        let version = unsafe { libmimalloc_sys::mi_version() };
+        format!(
+            "mimalloc-{}.{}.{}",
+            version / 100,
+            version / 10 % 10,
+            version % 10
+        )
+    }
+    #[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+    "libc".to_string()
+}
+
+/// Reads the global allocator's current statistics.
+pub fn stats() -> AllocatorStats {
+    AllocatorStats {
+        rss: process_rss(),
+        ..allocator_stats()
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+fn allocator_stats() -> AllocatorStats {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // jemalloc caches its statistics until the epoch is advanced
+    if epoch::advance().is_err() {
+        return AllocatorStats::default();
+    }
+    AllocatorStats {
+        allocated: stats::allocated::read().ok(),
+        active: stats::active::read().ok(),
+        resident: stats::resident::read().ok(),
+        rss: None,
+    }
+}
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+fn allocator_stats() -> AllocatorStats {
+    let (mut elapsed, mut user, mut system, mut page_faults) = (0, 0, 0, 0);
+    let (mut rss, mut peak_rss, mut commit, mut peak_commit) = (0, 0, 0, 0);
+    // SAFETY: every pointer is to a local that outlives the call
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            &mut elapsed,
+            &mut user,
+            &mut system,
+            &mut rss,
+            &mut peak_rss,
+            &mut commit,
+            &mut peak_commit,
+            &mut page_faults,
+        )
+    };
+    AllocatorStats {
+        allocated: None,
+        active: Some(commit),
+        resident: Some(rss),
+        rss: None,
+    }
+}
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+fn allocator_stats() -> AllocatorStats {
+    AllocatorStats::default()
+}
+
+/// Returns the resident set size of the process, from `/proc/self/status`.
+pub fn process_rss() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kb: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ratios() {
+        let stats = AllocatorStats {
+            allocated: Some(1000),
+            active: Some(1500),
+            resident: Some(3000),
+            rss: None,
+        };
+        assert_eq!(stats.fragmentation_ratio(), Some(1.5));
+        assert_eq!(stats.rss_ratio(), Some(2.0));
+
+        let unknown = AllocatorStats {
+            allocated: Some(0),
+            ..AllocatorStats::default()
+        };
+        assert_eq!(unknown.fragmentation_ratio(), None);
+        assert_eq!(unknown.rss_ratio(), None);
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = stats();
+        if cfg!(target_os = "linux") {
+            assert!(stats.rss.unwrap() > 0);
+        }
+
+        let name = name();
+        if cfg!(feature = "jemalloc") {
+            assert!(name.starts_with("jemalloc-5."), "{}", name);
+            // Live allocations show up, in pages jemalloc handed out
+            let buffer = vec![1u8; 1 << 20];
+            let stats = super::stats();
+            assert!(stats.allocated.unwrap() >= buffer.len());
+            assert!(stats.active.unwrap() >= stats.allocated.unwrap());
+            assert!(stats.fragmentation_ratio().unwrap() >= 1.0);
+        } else if cfg!(feature = "mimalloc") {
+            assert!(name.starts_with("mimalloc-"), "{}", name);
+            assert!(stats.active.unwrap() > 0);
+        } else {
+            assert_eq!(name, "libc");
+            assert_eq!(stats.allocated, None);
+        }
+    }
+}
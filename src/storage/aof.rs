@@ -0,0 +1,751 @@
+//! Append-Only File
+//!
+//! With `--appendonly yes` every successful write command is appended to a
+//! log as it runs, so a restart loses at most the last second of writes
+//! (with the default `everysec` fsync policy) instead of everything since
+//! the last snapshot.
+//!
+//! ## Hybrid Files
+//!
+//! A log of commands only grows, so BGREWRITEAOF compacts it: the rewrite
+//! writes a [`Snapshot`] of the keyspace in the snapshot format as a
+//! preamble, followed by the commands that ran while it was being written.
+//! Later writes keep appending commands after them.
+//!
+//! ```text
+//! ┌──────────────────────────────┬──────────────────────────────────┐
+//! │ snapshot preamble (optional) │ *3\r\n$3\r\nSET\r\n$1\r\nk\r\n... │
+//! └──────────────────────────────┴──────────────────────────────────┘
+//! ```
+//!
+//! Loading reads the preamble in one go (its checksum is verified before
+//! any key is inserted) and replays the command tail, which gives fast
+//! restarts with a small data-loss window. A file that doesn't start with
+//! the snapshot magic is replayed as plain commands.
+//!
+//! A crash in the middle of an append can leave the last command
+//! half-written. Loading can cut such a file back to its last complete
+//! command (`aof-load-truncated`); any other damage stops the load with the
+//! byte offset of the first command that doesn't parse.
+//!
+//! ## Ordering
+//!
+//! While the log is enabled, write commands run under the log's lock and
+//! are appended before it is released, so the log records them in the order
+//! they were applied. Starting a rewrite takes the same lock to capture the
+//! snapshot, so every command lands either in the snapshot or in the tail,
+//! never both.
+//!
+//! ## Group Commit
+//!
+//! The `group` fsync policy turns the log into a write-ahead log: commands
+//! are queued instead of written under the lock, and a background thread
+//! commits them in batches while clients wait for their write to be on disk
+//! before getting a reply (see [`wal`](crate::storage::wal)).
+//!
+//! Commands are logged in a form that replays to the same result: the
+//! command layer turns relative expiries into `PEXPIREAT`, generated stream
+//! IDs into explicit ones and served blocking pops into plain pops.
+
+use crate::storage::compression::Compression;
+use crate::storage::engine::{PendingSnapshot, StorageEngine};
+use crate::storage::snapshot::{self, Snapshot, SnapshotError};
+use crate::storage::wal::Wal;
+use bytes::Bytes;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::warn;
+
+/// Default append-only file name.
+pub const DEFAULT_APPENDFILENAME: &str = "appendonly.aof";
+
+/// When appended commands are flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AppendFsync {
+    /// After every write command
+    Always,
+    /// Once a second
+    #[default]
+    EverySec,
+    /// Whenever the operating system decides
+    No,
+    /// In batches, replying to clients once their write is on disk
+    Group,
+}
+
+impl FromStr for AppendFsync {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::EverySec),
+            "no" => Ok(AppendFsync::No),
+            "group" => Ok(AppendFsync::Group),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for AppendFsync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AppendFsync::Always => "always",
+            AppendFsync::EverySec => "everysec",
+            AppendFsync::No => "no",
+            AppendFsync::Group => "group",
+        })
+    }
+}
+
+/// The append-only file and its rewrite state.
+#[derive(Debug)]
+pub struct Aof {
+    /// The open file and everything guarded by the log lock
+    state: Mutex<AofState>,
+    /// Whether the log is open (readable without taking the lock)
+    enabled: AtomicBool,
+    /// Whether a rewrite is running
+    rewriting: AtomicBool,
+    /// Whether the last rewrite succeeded
+    last_rewrite_ok: AtomicBool,
+    /// Whether the last append succeeded
+    last_write_ok: AtomicBool,
+    /// Commit queue for the `group` policy
+    wal: Arc<Wal>,
+}
+
+#[derive(Debug, Default)]
+struct AofState {
+    /// The log, opened for appending (None while disabled)
+    file: Option<File>,
+    /// Path of the log
+    path: PathBuf,
+    /// Fsync policy
+    fsync: AppendFsync,
+    /// Whether data was written since the last fsync
+    dirty: bool,
+    /// Commands appended since the running rewrite took its snapshot
+    rewrite_buf: Option<Vec<u8>>,
+    /// Database the log and the replication stream are on, `None` if a
+    /// reader may be on another (the next command selects its database)
+    db: Option<usize>,
+}
+
+/// Holds the log lock while a write command runs; see [`Aof::lock`].
+pub struct AofGuard<'a> {
+    aof: &'a Aof,
+    state: MutexGuard<'a, AofState>,
+}
+
+impl AofGuard<'_> {
+    /// Returns the SELECT to log and feed to replicas before a command on
+    /// database `db`, or `None` if they are on it already.
+    ///
+    /// Call it only for commands that are then appended.
+    pub fn select(&mut self, db: usize) -> Option<Vec<Bytes>> {
+        if self.state.db == Some(db) {
+            return None;
+        }
+        self.state.db = Some(db);
+        Some(vec![
+            Bytes::from_static(b"SELECT"),
+            Bytes::from(db.to_string()),
+        ])
+    }
+
+    /// Notes that a new reader starts on database 0, as a replica after a
+    /// full sync or a rewritten log does, so the next command selects its
+    /// database unless all readers are on database 0.
+    pub(crate) fn restart_db(&mut self) {
+        if self.state.db != Some(0) {
+            self.state.db = None;
+        }
+    }
+
+    /// Appends commands (each a list of arguments, name first) to the log.
+    ///
+    /// A failed write is logged and reported in INFO rather than failing
+    /// the command, which has already been applied.
+    ///
+    /// # Returns
+    ///
+    /// Under the `group` policy, the log position to pass to
+    /// [`Aof::wait_durable`] before acknowledging the command.
+    pub fn append(&mut self, commands: &[Vec<Bytes>]) -> Option<u64> {
+        if commands.is_empty() {
+            return None;
+        }
+        let state = &mut *self.state;
+        let file = state.file.as_mut()?;
+
+        let mut buf = Vec::new();
+        for command in commands {
+            encode_command(&mut buf, command);
+        }
+
+        if let Some(rewrite_buf) = state.rewrite_buf.as_mut() {
+            rewrite_buf.extend_from_slice(&buf);
+        }
+        if state.fsync == AppendFsync::Group {
+            return Some(self.aof.wal.push(&buf));
+        }
+
+        let mut result = file.write_all(&buf);
+        if result.is_ok() && state.fsync == AppendFsync::Always {
+            result = file.sync_data();
+        }
+        match result {
+            Ok(()) => self.aof.last_write_ok.store(true, Ordering::Relaxed),
+            Err(e) => {
+                warn!("Failed to write to the append-only file: {}", e);
+                self.aof.last_write_ok.store(false, Ordering::Relaxed);
+            }
+        }
+        state.dirty = state.fsync != AppendFsync::Always;
+        None
+    }
+}
+
+impl Aof {
+    /// Creates a disabled log.
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(AofState {
+                db: Some(0),
+                ..AofState::default()
+            }),
+            enabled: AtomicBool::new(false),
+            rewriting: AtomicBool::new(false),
+            last_rewrite_ok: AtomicBool::new(true),
+            last_write_ok: AtomicBool::new(true),
+            wal: Arc::default(),
+        }
+    }
+
+    /// Opens (or creates) the log at `path` and starts appending to it.
+    pub fn open(&self, path: &Path, fsync: AppendFsync) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        if fsync == AppendFsync::Group {
+            self.wal.set_file(file.try_clone()?);
+            self.wal.start()?;
+        }
+        let mut state = self.state.lock().unwrap();
+        // An existing log may end on another database
+        if file.metadata()?.len() > 0 {
+            state.db = None;
+        }
+        state.file = Some(file);
+        state.path = path.to_path_buf();
+        state.fsync = fsync;
+        self.enabled.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns true if write commands are being logged.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Acquire)
+    }
+
+    /// Returns the path of the log.
+    pub fn path(&self) -> PathBuf {
+        self.state.lock().unwrap().path.clone()
+    }
+
+    /// Returns true if a rewrite is running.
+    pub fn rewrite_in_progress(&self) -> bool {
+        self.rewriting.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the last rewrite succeeded (or none has run).
+    pub fn last_rewrite_ok(&self) -> bool {
+        self.last_rewrite_ok.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the last append succeeded (or none has run).
+    pub fn last_write_ok(&self) -> bool {
+        self.last_write_ok.load(Ordering::Relaxed) && self.wal.last_write_ok()
+    }
+
+    /// Waits until the log is on disk up to `position`, as returned by
+    /// [`AofGuard::append`] under the `group` policy.
+    pub async fn wait_durable(&self, position: u64) {
+        self.wal.wait_durable(position).await
+    }
+
+    /// Takes the log lock. Hold it while applying a write command, then
+    /// append the command with [`AofGuard::append`].
+    pub fn lock(&self) -> AofGuard<'_> {
+        AofGuard {
+            aof: self,
+            state: self.state.lock().unwrap(),
+        }
+    }
+
+    /// Flushes appended commands to disk if the policy is `everysec` and
+    /// anything was written since the last flush. Called once a second.
+    pub fn fsync_if_due(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.fsync == AppendFsync::EverySec {
+            Self::sync(&mut state);
+        }
+    }
+
+    /// Flushes appended commands to disk, whatever the policy (at shutdown).
+    pub fn flush(&self) {
+        if let Err(e) = self.wal.commit() {
+            warn!("Failed to commit to the append-only file: {}", e);
+        }
+        Self::sync(&mut self.state.lock().unwrap());
+    }
+
+    fn sync(state: &mut AofState) {
+        if !state.dirty {
+            return;
+        }
+        if let Some(file) = &state.file {
+            match file.sync_data() {
+                Ok(()) => state.dirty = false,
+                Err(e) => warn!("Failed to fsync the append-only file: {}", e),
+            }
+        }
+    }
+
+    /// Starts a rewrite: starts a snapshot of the keyspace under the log
+    /// lock and starts collecting the commands appended from then on.
+    pub(crate) fn begin_rewrite(
+        &self,
+        engine: &StorageEngine,
+    ) -> Result<PendingSnapshot, SnapshotError> {
+        let mut guard = self.lock();
+        if self.rewriting.swap(true, Ordering::AcqRel) {
+            return Err(SnapshotError::RewriteInProgress);
+        }
+        guard.state.rewrite_buf = Some(Vec::new());
+        guard.restart_db();
+        Ok(engine.begin_snapshot())
+    }
+
+    /// Writes the rewritten log (the snapshot, then the commands collected
+    /// meanwhile) and swaps it in for the current one.
+    pub(crate) fn finish_rewrite(
+        &self,
+        snapshot: &Snapshot,
+        compression: Compression,
+    ) -> Result<(), SnapshotError> {
+        let path = self.path();
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let tmp = path.with_file_name(format!(
+            "temp-rewriteaof-{}-{}",
+            std::process::id(),
+            file_name
+        ));
+
+        let result = self.write_rewrite(snapshot, compression, &tmp, &path);
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+            self.state.lock().unwrap().rewrite_buf = None;
+        }
+        self.last_rewrite_ok
+            .store(result.is_ok(), Ordering::Relaxed);
+        self.rewriting.store(false, Ordering::Release);
+        result
+    }
+
+    /// Abandons a rewrite that never got to write its file.
+    pub(crate) fn abort_rewrite(&self) {
+        self.state.lock().unwrap().rewrite_buf = None;
+        self.last_rewrite_ok.store(false, Ordering::Relaxed);
+        self.rewriting.store(false, Ordering::Release);
+    }
+
+    fn write_rewrite(
+        &self,
+        snapshot: &Snapshot,
+        compression: Compression,
+        tmp: &Path,
+        path: &Path,
+    ) -> Result<(), SnapshotError> {
+        // The bulk of the file is written without holding the log lock
+        let writer = BufWriter::new(File::create(tmp)?);
+        let mut writer = snapshot::write_compressed_to(snapshot, compression, writer)?;
+        writer.flush()?;
+        let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+
+        let mut state = self.state.lock().unwrap();
+        let tail = state.rewrite_buf.take().unwrap_or_default();
+        file.write_all(&tail)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+
+        if state.file.is_some() {
+            let file = OpenOptions::new().append(true).open(path)?;
+            if state.fsync == AppendFsync::Group {
+                // Everything queued is already in the tail just synced
+                self.wal.set_file(file.try_clone()?);
+                self.wal.discard_queued();
+            }
+            state.file = Some(file);
+            state.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Aof {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Aof {
+    fn drop(&mut self) {
+        self.wal.close();
+    }
+}
+
+/// Appends a command as a RESP array of bulk strings.
+pub(crate) fn encode_command(buf: &mut Vec<u8>, command: &[Bytes]) {
+    buf.extend_from_slice(format!("*{}\r\n", command.len()).as_bytes());
+    for arg in command {
+        buf.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buf.extend_from_slice(arg);
+        buf.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Parses one command written by [`encode_command`].
+///
+/// # Returns
+///
+/// The command and the number of bytes it took, or `None` if `buf` ends
+/// before the command does.
+fn parse_command(buf: &[u8]) -> Result<Option<(Vec<Bytes>, usize)>, SnapshotError> {
+    let mut pos = 0;
+    let Some(count) = parse_header(buf, &mut pos, b'*')? else {
+        return Ok(None);
+    };
+
+    let mut command = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let Some(len) = parse_header(buf, &mut pos, b'$')? else {
+            return Ok(None);
+        };
+        if buf.len() < pos + len + 2 {
+            return Ok(None);
+        }
+        if &buf[pos + len..pos + len + 2] != b"\r\n" {
+            return Err(SnapshotError::Corrupt(
+                "invalid command in append-only file",
+            ));
+        }
+        command.push(Bytes::copy_from_slice(&buf[pos..pos + len]));
+        pos += len + 2;
+    }
+    Ok(Some((command, pos)))
+}
+
+/// Parses a `<prefix><number>\r\n` line at `pos`, advancing past it.
+fn parse_header(buf: &[u8], pos: &mut usize, prefix: u8) -> Result<Option<usize>, SnapshotError> {
+    let rest = &buf[*pos..];
+    let Some(end) = rest.windows(2).position(|w| w == b"\r\n") else {
+        return Ok(None);
+    };
+    if rest.first() != Some(&prefix) {
+        return Err(SnapshotError::Corrupt(
+            "invalid command in append-only file",
+        ));
+    }
+    let n = std::str::from_utf8(&rest[1..end])
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .ok_or(SnapshotError::Corrupt(
+            "invalid command in append-only file",
+        ))?;
+    *pos += end + 2;
+    Ok(Some(n))
+}
+
+/// What [`load`] found in an append-only file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoadReport {
+    /// Keys loaded from the snapshot preamble
+    pub keys: usize,
+    /// Commands replayed from the tail
+    pub commands: usize,
+    /// Where the file was cut if its last command was incomplete, and how
+    /// many bytes were dropped
+    pub truncated: Option<(u64, u64)>,
+}
+
+/// Loads the append-only file at `path`: the snapshot preamble, if there is
+/// one, goes straight into `engine`, and each command of the tail is passed
+/// to `execute`.
+///
+/// A crash can leave the last command half-written. With
+/// `truncate_incomplete`, the file is cut back to the end of the last
+/// complete command and loading succeeds; otherwise it fails. Anything else
+/// that doesn't parse fails the load, with the offset of the bad command,
+/// since dropping it would also drop every command after it.
+pub fn load(
+    engine: &StorageEngine,
+    path: &Path,
+    truncate_incomplete: bool,
+    mut execute: impl FnMut(Vec<Bytes>),
+) -> Result<LoadReport, SnapshotError> {
+    let data = fs::read(path)?;
+    let mut rest = &data[..];
+    let mut report = LoadReport::default();
+
+    if snapshot::has_magic(rest) {
+        report.keys = snapshot::load_slice(engine, &mut rest).map_err(|e| match e {
+            SnapshotError::Corrupt(reason) => SnapshotError::BadAof { offset: 0, reason },
+            e => e,
+        })?;
+    }
+
+    while !rest.is_empty() {
+        let offset = (data.len() - rest.len()) as u64;
+        let bad = |reason| SnapshotError::BadAof { offset, reason };
+
+        let Some((command, used)) = parse_command(rest).map_err(|_| bad("invalid command"))? else {
+            if !truncate_incomplete {
+                return Err(bad(
+                    "incomplete command at end of file (enable aof-load-truncated to drop it)",
+                ));
+            }
+            OpenOptions::new().write(true).open(path)?.set_len(offset)?;
+            report.truncated = Some((offset, rest.len() as u64));
+            break;
+        };
+        if command.is_empty() {
+            return Err(bad("empty command"));
+        }
+
+        execute(command);
+        report.commands += 1;
+        rest = &rest[used..];
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::compression::Codec;
+
+    fn command(args: &[&str]) -> Vec<Bytes> {
+        args.iter()
+            .map(|a| Bytes::copy_from_slice(a.as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn test_command_round_trip() {
+        let mut buf = Vec::new();
+        encode_command(&mut buf, &command(&["SET", "key", "a\r\nb"]));
+        assert_eq!(
+            &buf[..],
+            b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$4\r\na\r\nb\r\n"
+        );
+
+        let (parsed, used) = parse_command(&buf).unwrap().unwrap();
+        assert_eq!(parsed, command(&["SET", "key", "a\r\nb"]));
+        assert_eq!(used, buf.len());
+
+        assert!(parse_command(&buf[..buf.len() - 1]).unwrap().is_none());
+        assert!(parse_command(b"+OK\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_fsync_policy() {
+        assert_eq!("always".parse(), Ok(AppendFsync::Always));
+        assert_eq!("EVERYSEC".parse(), Ok(AppendFsync::EverySec));
+        assert_eq!("no".parse(), Ok(AppendFsync::No));
+        assert!("sometimes".parse::<AppendFsync>().is_err());
+        assert_eq!(AppendFsync::default().to_string(), "everysec");
+    }
+
+    #[test]
+    fn test_rewrite_keeps_commands_appended_meanwhile() {
+        let path = std::env::temp_dir().join(format!("flashkv-{}-rewrite.aof", std::process::id()));
+        let engine = StorageEngine::new();
+        let aof = Aof::new();
+        aof.open(&path, AppendFsync::Always).unwrap();
+
+        engine.set(Bytes::from("a"), Bytes::from("1"));
+        aof.lock().append(&[command(&["SET", "a", "1"])]);
+
+        let pending = aof.begin_rewrite(&engine).unwrap();
+        assert!(aof.rewrite_in_progress());
+        assert!(matches!(
+            aof.begin_rewrite(&engine),
+            Err(SnapshotError::RewriteInProgress)
+        ));
+
+        // Runs while the snapshot is being written
+        engine.set(Bytes::from("b"), Bytes::from("2"));
+        aof.lock().append(&[command(&["SET", "b", "2"])]);
+        aof.finish_rewrite(&engine.finish_snapshot(pending), Compression::NONE)
+            .unwrap();
+        assert!(!aof.rewrite_in_progress());
+
+        // And after the new file was swapped in
+        aof.lock().append(&[command(&["DEL", "a"])]);
+
+        let loaded = StorageEngine::new();
+        let mut replayed = Vec::new();
+        let report = load(&loaded, &path, false, |c| replayed.push(c)).unwrap();
+        assert_eq!((report.keys, report.commands), (1, 2));
+        assert_eq!(loaded.get(&Bytes::from("a")), Some(Bytes::from("1")));
+        assert_eq!(
+            replayed,
+            vec![command(&["SET", "b", "2"]), command(&["DEL", "a"])]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_preamble() {
+        let engine = StorageEngine::new();
+        engine.set(Bytes::from("a"), Bytes::from("1"));
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            if !codec.is_available() {
+                continue;
+            }
+            let path = std::env::temp_dir().join(format!(
+                "flashkv-{}-compressed-{}.aof",
+                std::process::id(),
+                codec
+            ));
+            let aof = Aof::new();
+            aof.open(&path, AppendFsync::Always).unwrap();
+            let pending = aof.begin_rewrite(&engine).unwrap();
+            aof.finish_rewrite(&engine.finish_snapshot(pending), Compression::new(codec))
+                .unwrap();
+            aof.lock().append(&[command(&["SET", "b", "2"])]);
+
+            let loaded = StorageEngine::new();
+            let mut replayed = Vec::new();
+            let report = load(&loaded, &path, false, |c| replayed.push(c)).unwrap();
+            assert_eq!((report.keys, report.commands), (1, 1));
+            assert_eq!(loaded.get(&Bytes::from("a")), Some(Bytes::from("1")));
+            assert_eq!(replayed, vec![command(&["SET", "b", "2"])]);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_group_commit() {
+        let path = std::env::temp_dir().join(format!("flashkv-{}-group.aof", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let engine = StorageEngine::new();
+        let aof = Aof::new();
+        aof.open(&path, AppendFsync::Group).unwrap();
+
+        engine.set(Bytes::from("a"), Bytes::from("1"));
+        let position = aof.lock().append(&[command(&["SET", "a", "1"])]).unwrap();
+        aof.wait_durable(position).await;
+        let mut expected = Vec::new();
+        encode_command(&mut expected, &command(&["SET", "a", "1"]));
+        assert_eq!(fs::read(&path).unwrap(), expected);
+
+        // Commands queued during a rewrite land in the new file exactly once
+        let pending = aof.begin_rewrite(&engine).unwrap();
+        engine.set(Bytes::from("b"), Bytes::from("2"));
+        let position = aof.lock().append(&[command(&["SET", "b", "2"])]).unwrap();
+        aof.finish_rewrite(&engine.finish_snapshot(pending), Compression::NONE)
+            .unwrap();
+        aof.wait_durable(position).await;
+        aof.flush();
+
+        let loaded = StorageEngine::new();
+        let mut replayed = Vec::new();
+        let report = load(&loaded, &path, false, |c| replayed.push(c)).unwrap();
+        assert_eq!(report.keys, 1);
+        assert_eq!(replayed, vec![command(&["SET", "b", "2"])]);
+        assert!(aof.last_write_ok());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_incomplete_tail_is_truncated() {
+        let path = std::env::temp_dir().join(format!("flashkv-{}-torn.aof", std::process::id()));
+        let mut data = Vec::new();
+        encode_command(&mut data, &command(&["SET", "a", "1"]));
+        let valid_len = data.len() as u64;
+        encode_command(&mut data, &command(&["SET", "b", "2"]));
+        data.truncate(data.len() - 4);
+        fs::write(&path, &data).unwrap();
+
+        let engine = StorageEngine::new();
+        let err = load(&engine, &path, false, |_| {}).unwrap_err();
+        assert!(matches!(
+            err,
+            SnapshotError::BadAof { offset, .. } if offset == valid_len
+        ));
+        assert_eq!(fs::metadata(&path).unwrap().len(), data.len() as u64);
+
+        let mut replayed = Vec::new();
+        let report = load(&engine, &path, true, |c| replayed.push(c)).unwrap();
+        assert_eq!(report.commands, 1);
+        assert_eq!(
+            report.truncated,
+            Some((valid_len, data.len() as u64 - valid_len))
+        );
+        assert_eq!(replayed, vec![command(&["SET", "a", "1"])]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), valid_len);
+
+        // Loads cleanly now
+        let report = load(&engine, &path, false, |_| {}).unwrap();
+        assert_eq!((report.commands, report.truncated), (1, None));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corruption_is_reported_with_its_offset() {
+        let path = std::env::temp_dir().join(format!("flashkv-{}-bad.aof", std::process::id()));
+        let mut data = Vec::new();
+        encode_command(&mut data, &command(&["SET", "a", "1"]));
+        let bad_at = data.len() as u64;
+        data.extend_from_slice(b"*2\r\n$3\r\nDEL\r\nXX1\r\na\r\n");
+        encode_command(&mut data, &command(&["SET", "c", "3"]));
+        fs::write(&path, &data).unwrap();
+
+        // Not an incomplete tail, so it isn't truncated even when allowed
+        let engine = StorageEngine::new();
+        let err = load(&engine, &path, true, |_| {}).unwrap_err();
+        assert!(matches!(
+            err,
+            SnapshotError::BadAof { offset, reason: "invalid command" } if offset == bad_at
+        ));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "bad append-only file format at byte {}: invalid command",
+                bad_at
+            )
+        );
+        assert_eq!(fs::metadata(&path).unwrap().len(), data.len() as u64);
+
+        // A damaged preamble fails before anything is loaded
+        let snapshot = engine.snapshot();
+        let mut data = snapshot::write_to(&snapshot, Vec::new()).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xFF;
+        fs::write(&path, &data).unwrap();
+        assert!(matches!(
+            load(&engine, &path, true, |_| {}),
+            Err(SnapshotError::BadAof { offset: 0, .. })
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
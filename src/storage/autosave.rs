@@ -0,0 +1,138 @@
+//! Automatic Background Saves
+//!
+//! This module implements the background task that enforces the save rules
+//! (`save 900 1` and friends). Once a second it checks whether any rule is
+//! due, and if so starts a background save, just like BGSAVE. The same tick
+//! flushes the append-only file to disk under the `everysec` policy.
+//!
+//! ## Design
+//!
+//! The scheduler doesn't track writes itself: the command layer counts
+//! every change on the engine's [`Snapshots`](crate::storage::Snapshots),
+//! which also decides whether a rule is due. The scheduler only supplies the
+//! clock ticks, so it runs alongside the expiry sweeper and is stopped the
+//! same way, by dropping its handle.
+
+use crate::storage::{clock, StorageEngine};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+/// How often the save rules are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A handle to the running save scheduler.
+///
+/// When this handle is dropped, the scheduler task will be stopped.
+#[derive(Debug)]
+pub struct SaveScheduler {
+    /// Sender to signal shutdown
+    shutdown_tx: watch::Sender<bool>,
+}
+
+impl SaveScheduler {
+    /// Starts the save scheduler as a background task, checking the rules
+    /// every `interval`.
+    ///
+    /// # Returns
+    ///
+    /// Returns a handle that can be used to stop the scheduler.
+    /// The scheduler will automatically stop when the handle is dropped.
+    pub fn start(engine: Arc<StorageEngine>, interval: Duration) -> Self {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+        tokio::spawn(scheduler_loop(engine, interval, shutdown_rx));
+
+        info!("Background save scheduler started");
+
+        Self { shutdown_tx }
+    }
+
+    /// Stops the save scheduler.
+    ///
+    /// This is called automatically when the handle is dropped.
+    pub fn stop(&self) {
+        let _ = self.shutdown_tx.send(true);
+        info!("Background save scheduler stopped");
+    }
+}
+
+impl Drop for SaveScheduler {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// The main scheduler loop.
+async fn scheduler_loop(
+    engine: Arc<StorageEngine>,
+    interval: Duration,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            result = shutdown_rx.changed() => {
+                if result.is_err() || *shutdown_rx.borrow() {
+                    debug!("Save scheduler received shutdown signal");
+                    return;
+                }
+            }
+        }
+
+        engine.aof().fsync_if_due();
+
+        let snapshots = engine.snapshots();
+        let Some(rule) = snapshots.due_rule(clock::unix_time_ms() / 1000) else {
+            continue;
+        };
+
+        info!(
+            "{} changes in {} seconds. Saving...",
+            rule.changes, rule.seconds
+        );
+        if let Err(e) = engine.bgsave() {
+            warn!("Failed to start background save: {}", e);
+        }
+    }
+}
+
+/// Starts the save scheduler, checking the rules once a second.
+pub fn start_save_scheduler(engine: Arc<StorageEngine>) -> SaveScheduler {
+    SaveScheduler::start(engine, CHECK_INTERVAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::snapshot::SaveRule;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_scheduler_saves_when_rule_is_due() {
+        let path =
+            std::env::temp_dir().join(format!("flashkv-{}-autosave.fkv", std::process::id()));
+        let engine = Arc::new(StorageEngine::new());
+        engine.snapshots().set_path(&path);
+        engine.snapshots().set_rules(vec![SaveRule::new(0, 2)]);
+
+        let _scheduler = SaveScheduler::start(Arc::clone(&engine), Duration::from_millis(10));
+
+        engine.set(Bytes::from("key"), Bytes::from("value"));
+        engine.snapshots().record_changes(1);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!path.exists());
+
+        engine.snapshots().record_changes(1);
+        for _ in 0..100 {
+            if path.exists() && !engine.snapshots().in_progress() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(path.exists());
+        assert_eq!(engine.snapshots().changes(), 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
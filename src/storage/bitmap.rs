@@ -0,0 +1,254 @@
+//! Bitmap Operations on String Values
+//!
+//! Redis has no dedicated bitmap type: SETBIT, GETBIT, BITCOUNT and BITPOS
+//! treat an ordinary string value as an array of bits. Bit 0 is the most
+//! significant bit of the first byte, so `SETBIT key 0 1` on an empty key
+//! produces the byte `0x80`.
+//!
+//! This module contains the pure bit manipulation helpers; the storage
+//! engine applies them to string entries.
+
+/// The largest bit offset SETBIT/GETBIT accept (strings are capped at 512 MB).
+pub const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8 - 1;
+
+/// The unit of a BITCOUNT/BITPOS range.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BitUnit {
+    /// Range indices are byte offsets (the default)
+    #[default]
+    Byte,
+    /// Range indices are bit offsets
+    Bit,
+}
+
+/// An inclusive BITCOUNT/BITPOS range. Negative indices count from the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitRange {
+    /// First index of the range
+    pub start: i64,
+    /// Last index of the range (inclusive)
+    pub end: i64,
+    /// Whether indices are bytes or bits
+    pub unit: BitUnit,
+}
+
+impl BitRange {
+    /// Resolves the range to inclusive bit offsets within a value of
+    /// `len_bytes` bytes, or `None` if the range is empty.
+    fn to_bits(self, len_bytes: usize) -> Option<(u64, u64)> {
+        let len = match self.unit {
+            BitUnit::Byte => len_bytes as i64,
+            BitUnit::Bit => len_bytes as i64 * 8,
+        };
+        if len == 0 {
+            return None;
+        }
+
+        let start = if self.start < 0 {
+            (len + self.start).max(0)
+        } else {
+            self.start
+        };
+        let end = if self.end < 0 {
+            (len + self.end).max(0)
+        } else {
+            self.end.min(len - 1)
+        };
+
+        if start > end {
+            return None;
+        }
+
+        match self.unit {
+            BitUnit::Byte => Some((start as u64 * 8, end as u64 * 8 + 7)),
+            BitUnit::Bit => Some((start as u64, end as u64)),
+        }
+    }
+}
+
+/// Returns the bit at `offset` (0 if past the end of the value).
+pub fn get_bit(value: &[u8], offset: u64) -> u8 {
+    let byte = (offset / 8) as usize;
+    match value.get(byte) {
+        Some(b) => (b >> (7 - offset % 8)) & 1,
+        None => 0,
+    }
+}
+
+/// Sets or clears the bit at `offset`, growing the value with zero bytes
+/// as needed.
+///
+/// # Returns
+/// The previous value of the bit.
+pub fn set_bit(value: &mut Vec<u8>, offset: u64, bit: bool) -> u8 {
+    let byte = (offset / 8) as usize;
+    if value.len() <= byte {
+        value.resize(byte + 1, 0);
+    }
+
+    let mask = 1u8 << (7 - offset % 8);
+    let old = (value[byte] & mask != 0) as u8;
+    if bit {
+        value[byte] |= mask;
+    } else {
+        value[byte] &= !mask;
+    }
+    old
+}
+
+/// Mask selecting bits `lo..=hi` (0 = most significant) of a byte.
+fn byte_mask(lo: u64, hi: u64) -> u8 {
+    (0xFFu8 >> lo) & (0xFFu8 << (7 - hi))
+}
+
+/// Iterates over `(byte index, masked bits)` for the bytes covering an
+/// inclusive bit range.
+fn masked_bytes(value: &[u8], first: u64, last: u64) -> impl Iterator<Item = (usize, u8)> + '_ {
+    let first_byte = (first / 8) as usize;
+    let last_byte = (last / 8) as usize;
+    (first_byte..=last_byte).map(move |i| {
+        let lo = if i == first_byte { first % 8 } else { 0 };
+        let hi = if i == last_byte { last % 8 } else { 7 };
+        (i, value[i] & byte_mask(lo, hi))
+    })
+}
+
+/// Counts the set bits in the value, optionally restricted to a range.
+pub fn count_bits(value: &[u8], range: Option<BitRange>) -> usize {
+    let (first, last) = match range {
+        Some(range) => match range.to_bits(value.len()) {
+            Some(bits) => bits,
+            None => return 0,
+        },
+        None if value.is_empty() => return 0,
+        None => (0, value.len() as u64 * 8 - 1),
+    };
+
+    masked_bytes(value, first, last)
+        .map(|(_, b)| b.count_ones() as usize)
+        .sum()
+}
+
+/// Finds the first bit set to `bit` in the value, optionally within a range.
+///
+/// Follows BITPOS semantics: when looking for a clear bit and no explicit
+/// range end was given, the value is treated as padded with zeros, so a
+/// value of all ones yields the offset just past its end.
+///
+/// # Returns
+/// The bit offset, or -1 if no such bit exists.
+pub fn bit_position(value: &[u8], bit: bool, range: Option<BitRange>, end_given: bool) -> i64 {
+    let padded_result = if bit { -1 } else { value.len() as i64 * 8 };
+
+    let (first, last) = match range {
+        Some(range) => match range.to_bits(value.len()) {
+            Some(bits) => bits,
+            None => return if bit || end_given { -1 } else { padded_result },
+        },
+        None if value.is_empty() => return padded_result,
+        None => (0, value.len() as u64 * 8 - 1),
+    };
+
+    let first_byte = (first / 8) as usize;
+    let last_byte = (last / 8) as usize;
+    for (i, masked) in masked_bytes(value, first, last) {
+        // Look for ones in the byte itself, or in its complement for zeros
+        let lo = if i == first_byte { first % 8 } else { 0 };
+        let hi = if i == last_byte { last % 8 } else { 7 };
+        let candidates = if bit {
+            masked
+        } else {
+            !value[i] & byte_mask(lo, hi)
+        };
+        if candidates != 0 {
+            return i as i64 * 8 + candidates.leading_zeros() as i64;
+        }
+    }
+
+    if bit || end_given {
+        -1
+    } else {
+        padded_result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_get_bit() {
+        let mut value = Vec::new();
+        assert_eq!(set_bit(&mut value, 7, true), 0);
+        assert_eq!(value, vec![0x01]);
+        assert_eq!(set_bit(&mut value, 0, true), 0);
+        assert_eq!(value, vec![0x81]);
+        assert_eq!(set_bit(&mut value, 7, false), 1);
+        assert_eq!(value, vec![0x80]);
+
+        // Growing pads with zero bytes
+        set_bit(&mut value, 17, true);
+        assert_eq!(value, vec![0x80, 0x00, 0x40]);
+
+        assert_eq!(get_bit(&value, 0), 1);
+        assert_eq!(get_bit(&value, 1), 0);
+        assert_eq!(get_bit(&value, 17), 1);
+        assert_eq!(get_bit(&value, 1000), 0);
+    }
+
+    #[test]
+    fn test_count_bits() {
+        let value = b"foobar";
+        assert_eq!(count_bits(value, None), 26);
+
+        let bytes = |start, end| {
+            Some(BitRange {
+                start,
+                end,
+                unit: BitUnit::Byte,
+            })
+        };
+        let bits = |start, end| {
+            Some(BitRange {
+                start,
+                end,
+                unit: BitUnit::Bit,
+            })
+        };
+
+        assert_eq!(count_bits(value, bytes(0, 0)), 4);
+        assert_eq!(count_bits(value, bytes(1, 1)), 6);
+        assert_eq!(count_bits(value, bytes(-2, -1)), 7);
+        assert_eq!(count_bits(value, bits(5, 30)), 17);
+        assert_eq!(count_bits(value, bytes(3, 1)), 0);
+        assert_eq!(count_bits(b"", None), 0);
+    }
+
+    #[test]
+    fn test_bit_position() {
+        let range = |start, end, unit| Some(BitRange { start, end, unit });
+
+        assert_eq!(bit_position(&[0xFF, 0xF0, 0x00], false, None, false), 12);
+        assert_eq!(bit_position(&[0x00, 0xFF, 0xF0], true, None, false), 8);
+        assert_eq!(
+            bit_position(&[0x00, 0xFF, 0xF0], true, range(2, -1, BitUnit::Byte), true),
+            16
+        );
+        assert_eq!(
+            bit_position(&[0x00, 0xFF, 0xF0], true, range(7, 15, BitUnit::Bit), true),
+            8
+        );
+        assert_eq!(bit_position(&[0x00, 0x00], true, None, false), -1);
+
+        // All ones: clear bits are found past the end unless an end was given
+        assert_eq!(bit_position(&[0xFF, 0xFF], false, None, false), 16);
+        assert_eq!(
+            bit_position(&[0xFF, 0xFF], false, range(0, -1, BitUnit::Byte), true),
+            -1
+        );
+
+        // Missing keys behave like empty strings
+        assert_eq!(bit_position(&[], false, None, false), 0);
+        assert_eq!(bit_position(&[], true, None, false), -1);
+    }
+}
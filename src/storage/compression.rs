@@ -0,0 +1,277 @@
+//! Persistence File Compression
+//!
+//! Snapshots and the preamble of a rewritten append-only file can be
+//! compressed with zstd or LZ4. The codecs are optional dependencies behind
+//! the `zstd` and `lz4` cargo features; a build without them reads and
+//! writes uncompressed files only.
+//!
+//! Compression wraps the whole snapshot encoding rather than individual
+//! values, since keys and values of the same dataset tend to share most of
+//! their bytes. A compressed snapshot is framed so its end can be found
+//! without decompressing it, which lets commands follow it in an
+//! append-only file:
+//!
+//! ```text
+//! ┌─────────────────────────────────────────────────────────────┐
+//! │ "FLASHKZ" │ codec (u8) │ compressed length (u64)            │
+//! ├─────────────────────────────────────────────────────────────┤
+//! │ compressed snapshot (the uncompressed format, checksum and  │
+//! │ all)                                                        │
+//! └─────────────────────────────────────────────────────────────┘
+//! ```
+//!
+//! Loading recognizes both forms, so changing the codec doesn't strand
+//! files written with the previous one.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// Magic bytes at the start of every compressed snapshot.
+pub(crate) const MAGIC: &[u8; 7] = b"FLASHKZ";
+
+/// Length of the frame header: magic, codec and compressed length.
+pub(crate) const HEADER_LEN: usize = MAGIC.len() + 1 + 8;
+
+/// Default zstd compression level.
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// A compression algorithm for persistence files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// Files are written uncompressed
+    #[default]
+    None,
+    /// Zstandard: the better ratio, the level trades speed for size
+    Zstd,
+    /// LZ4 frames: the faster codec, the level is ignored
+    Lz4,
+}
+
+impl Codec {
+    /// Returns true if this build can read and write the codec.
+    pub fn is_available(self) -> bool {
+        match self {
+            Codec::None => true,
+            Codec::Zstd => cfg!(feature = "zstd"),
+            Codec::Lz4 => cfg!(feature = "lz4"),
+        }
+    }
+
+    /// Returns the name of the cargo feature providing the codec.
+    pub fn feature(self) -> Option<&'static str> {
+        match self {
+            Codec::None => None,
+            Codec::Zstd => Some("zstd"),
+            Codec::Lz4 => Some("lz4"),
+        }
+    }
+
+    fn tag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Zstd => 1,
+            Codec::Lz4 => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" | "no" => Ok(Codec::None),
+            "zstd" => Ok(Codec::Zstd),
+            "lz4" => Ok(Codec::Lz4),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4",
+        })
+    }
+}
+
+/// How persistence files are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    /// The codec, or [`Codec::None`] to write plain files
+    pub codec: Codec,
+    /// Compression level, for codecs that have one
+    pub level: i32,
+}
+
+impl Compression {
+    /// No compression.
+    pub const NONE: Compression = Compression {
+        codec: Codec::None,
+        level: DEFAULT_ZSTD_LEVEL,
+    };
+
+    /// Creates a setting for `codec` at its default level.
+    pub fn new(codec: Codec) -> Self {
+        Self {
+            codec,
+            level: DEFAULT_ZSTD_LEVEL,
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+fn unavailable(codec: Codec) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} compression requires building with the `{}` feature",
+            codec,
+            codec.feature().unwrap_or_default()
+        ),
+    )
+}
+
+/// Compresses what `write` writes into a framed buffer.
+///
+/// Only the compressed output is held in memory, not the uncompressed
+/// encoding. `compression.codec` must not be [`Codec::None`].
+pub(crate) fn compress(
+    compression: Compression,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> io::Result<Vec<u8>> {
+    let mut framed = Vec::with_capacity(HEADER_LEN);
+    framed.extend_from_slice(MAGIC);
+    framed.push(compression.codec.tag());
+    framed.extend_from_slice(&[0; 8]);
+
+    let compressed: io::Result<Vec<u8>> = match compression.codec {
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => {
+            zstd::stream::write::Encoder::new(framed, compression.level).and_then(|mut encoder| {
+                write(&mut encoder)?;
+                encoder.finish()
+            })
+        }
+        #[cfg(feature = "lz4")]
+        Codec::Lz4 => {
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(framed);
+            write(&mut encoder).and_then(|()| encoder.finish().map_err(io::Error::other))
+        }
+        codec => {
+            let _ = (write, framed);
+            Err(unavailable(codec))
+        }
+    };
+    let mut framed = compressed?;
+
+    let len = (framed.len() - HEADER_LEN) as u64;
+    framed[MAGIC.len() + 1..HEADER_LEN].copy_from_slice(&len.to_le_bytes());
+    Ok(framed)
+}
+
+/// Returns true if `data` starts with a compressed snapshot.
+pub(crate) fn has_magic(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Decompresses the framed snapshot at the start of `data`, advancing
+/// `data` past it.
+pub(crate) fn decompress(data: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    if data.len() < HEADER_LEN || !has_magic(data) {
+        return Err(invalid("compressed snapshot header is truncated"));
+    }
+    let codec =
+        Codec::from_tag(data[MAGIC.len()]).ok_or_else(|| invalid("unknown compression codec"))?;
+    let mut len = [0; 8];
+    len.copy_from_slice(&data[MAGIC.len() + 1..HEADER_LEN]);
+    let len = usize::try_from(u64::from_le_bytes(len)).unwrap_or(usize::MAX);
+    if data.len() - HEADER_LEN < len {
+        return Err(invalid("compressed snapshot is truncated"));
+    }
+
+    let payload = &data[HEADER_LEN..HEADER_LEN + len];
+    let plain = match codec {
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => zstd::stream::decode_all(payload),
+        #[cfg(feature = "lz4")]
+        Codec::Lz4 => {
+            let mut plain = Vec::new();
+            let mut decoder = lz4_flex::frame::FrameDecoder::new(payload);
+            io::Read::read_to_end(&mut decoder, &mut plain).map(|_| plain)
+        }
+        codec => {
+            let _ = payload;
+            Err(unavailable(codec))
+        }
+    }?;
+    *data = &data[HEADER_LEN + len..];
+    Ok(plain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn available_codecs() -> Vec<Codec> {
+        [Codec::Zstd, Codec::Lz4]
+            .into_iter()
+            .filter(|codec| codec.is_available())
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip_leaves_trailing_data() {
+        let plain = b"FLASHKV snapshot ".repeat(1000);
+        for codec in available_codecs() {
+            let mut data = compress(Compression::new(codec), |w| w.write_all(&plain)).unwrap();
+            assert!(data.len() < plain.len() / 10, "{} didn't compress", codec);
+            data.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+
+            let mut rest = &data[..];
+            assert_eq!(decompress(&mut rest).unwrap(), plain);
+            assert_eq!(rest, b"*1\r\n$4\r\nPING\r\n");
+
+            let mut truncated = &data[..HEADER_LEN + 4];
+            assert!(decompress(&mut truncated).is_err());
+        }
+    }
+
+    #[test]
+    fn test_unavailable_codec_is_reported() {
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            if codec.is_available() {
+                continue;
+            }
+            let err = compress(Compression::new(codec), |_| Ok(())).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        }
+    }
+
+    #[test]
+    fn test_parse_codec() {
+        assert_eq!("ZSTD".parse(), Ok(Codec::Zstd));
+        assert_eq!("lz4".parse(), Ok(Codec::Lz4));
+        assert_eq!("none".parse(), Ok(Codec::None));
+        assert_eq!("gzip".parse::<Codec>(), Err(()));
+        assert!(Codec::None.is_available());
+    }
+}
@@ -2,14 +2,18 @@
 //!
 //! This module implements the core storage engine for FlashKV.
 //! It provides a thread-safe, concurrent HashMap with TTL (Time-To-Live) support.
-//! It also supports List data structures (similar to Redis lists).
+//! It also supports List, Hash, Set, Sorted Set and Stream data structures (similar to Redis).
 //!
 //! ## Design Decisions
 //!
 //! 1. **Sharded Locks**: Instead of one big lock, we use multiple shards to reduce contention.
 //! 2. **Lazy Expiry**: Keys are checked for expiry on access (lazy) plus background cleanup.
-//! 3. **Arc<RwLock>**: Allows multiple concurrent readers with exclusive writers.
-//! 4. **Separate List Storage**: Lists are stored separately from strings for type safety.
+//! 3. **Arc<RwLock>**: Allows multiple concurrent readers with exclusive writers. The locks
+//!    are `parking_lot`'s, which are faster under contention than `std`'s and aren't poisoned
+//!    when a thread panics while holding one, so one failed command can't wedge its shard.
+//! 4. **Unified Keyspace**: Every key lives in a single map per shard. Its [`Entry`] holds a
+//!    [`Value`] of any type plus the expiry metadata, so key-level commands (TYPE, DEL, EXPIRE,
+//!    KEYS, ...) behave the same regardless of the value's type.
 //!
 //! ## Concurrency Model
 //!
@@ -24,65 +28,445 @@
 //! └─────────────────────────────────────────────────────────────┘
 //! ```
 //!
-//! Keys are distributed across shards using a hash function.
+//! Keys are distributed across shards using a hash function: the shard count
+//! is a power of two (four per CPU unless configured otherwise), and the low
+//! bits of a key's hash pick its shard.
 //! This allows multiple threads to read/write different keys concurrently.
-
+//! With [`ShardLocking::Bucket`] each shard is a small bucket behind a mutex
+//! instead; see [`shard_lock`](crate::storage::shard_lock).
+//!
+//! Like Redis cluster slots, a key with a `{...}` [hash tag](crate::cluster::hash_tag)
+//! is placed by its tag alone, so related keys such as `{user:1}:profile` and
+//! `{user:1}:sessions` share a shard and multi-key commands on them take a
+//! single lock.
+
+use crate::cluster::{hash_tag, Cluster};
+use crate::pubsub::{EventClass, PubSub};
+use crate::replication::Replication;
+use crate::scripting::{Functions, RestorePolicy};
+use crate::storage::aof::{Aof, AofGuard};
+use crate::storage::arena::{KeyArena, COMPACT_KEY_LIMIT, KEY_ALLOC_OVERHEAD};
+use crate::storage::bitmap::{self, BitRange};
+use crate::storage::clock;
+use crate::storage::eviction::{
+    self, Candidate, Eviction, EvictionPolicy, EVICTION_SAMPLES, LFU_INIT_VAL,
+};
+use crate::storage::export::{self, ExportFormat};
+use crate::storage::glob::GlobPattern;
+use crate::storage::hyperloglog::{HyperLogLog, HLL_INVALID};
+use crate::storage::lazyfree::{LazyFree, LAZYFREE_STRING_UNIT};
+use crate::storage::shard_lock::{ReadGuard, ShardLock, ShardLocking, UpgradableGuard, WriteGuard};
+use crate::storage::snapshot::{self, Snapshot, SnapshotError, Snapshots};
+use crate::storage::sort::{self, Lookup, SortOptions, Weight, SORT_NOT_NUMERIC};
+use crate::storage::stream::{
+    PendingInfo, PendingQuery, PendingSummary, Stream, StreamFields, StreamId, StreamRecord, XAddId,
+};
+use crate::storage::string::StringValue;
+use crate::storage::waiters::KeyWaiters;
+use crate::storage::zset::{weighted, Aggregate, SortedSet, ZAddFlags, ZAddResult};
 use bytes::Bytes;
-use std::collections::{HashMap, VecDeque};
-use std::hash::{DefaultHasher, Hash, Hasher};
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::RwLock;
+use parking_lot::Mutex;
+use rand::seq::{IteratorRandom, SliceRandom};
+use rand::Rng;
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Component, Path};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tracing::{info, warn};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Bytes a key is assumed to cost on top of its name and value, for the
+/// hash table slot, the entry's metadata and allocator overhead.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Bytes a stream entry is assumed to take (entries aren't cheaply
+/// measurable).
+const STREAM_ENTRY_SIZE: usize = 64;
+
+/// Random shards tried for each key sampled for eviction, before giving up
+/// on a database whose keys are spread too thin.
+const EVICTION_SAMPLE_TRIES: usize = 4;
+
+/// Slots of a shard's table looked at to pick one of its keys at random.
+const RANDOM_ENTRY_PROBES: usize = 128;
+
+/// Significant digits INCRBYFLOAT and HINCRBYFLOAT results are rounded to.
+///
+/// Redis computes them in a `long double` and prints 17 digits, fewer than
+/// the type holds, so the error from `0.1` and `0.2` having no exact binary
+/// form is rounded away. An `f64` holds about 17 digits, so the same
+/// margin leaves 15 (`DBL_DIG`).
+const FLOAT_DIGITS: usize = 15;
+
+/// Most shards a database may have.
+pub const MAX_SHARDS: usize = 1 << 16;
+
+/// Returns the number of shards a database has unless configured
+/// otherwise: four per available CPU, rounded up to a power of two,
+/// between 16 and 1024.
+///
+/// More shards = less lock contention, but more memory overhead, and
+/// KEYS, SCAN and snapshots have more shards to go through.
+pub fn default_shard_count() -> usize {
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    (cpus * 4).next_power_of_two().clamp(16, 1024)
+}
+
+/// Number of databases a server has unless configured otherwise.
+pub const DEFAULT_DATABASES: usize = 16;
+
+/// Error returned when an operation targets a key holding another type.
+pub const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// List payload: a deque for O(1) push/pop on both ends.
+type List = VecDeque<Bytes>;
+
+/// Hash payload: field-value pairs.
+type Hash = HashMap<Bytes, Bytes>;
+
+/// Set payload: unique members.
+type Set = HashSet<Bytes>;
+
+/// A shard's keys and their entries: `hashbrown`'s map rather than `std`'s
+/// for its raw table, which eviction picks random keys from.
+pub(crate) type KeyMap = hashbrown::HashMap<Bytes, Entry, RandomState>;
+
+/// The value stored under a key.
+#[derive(Debug, Clone)]
+pub enum Value {
+    /// A binary-safe string (bitmaps and HyperLogLogs are strings too)
+    String(StringValue),
+    /// A list of values
+    List(VecDeque<Bytes>),
+    /// A field-value map
+    Hash(HashMap<Bytes, Bytes>),
+    /// A set of unique members
+    Set(HashSet<Bytes>),
+    /// A set of members ordered by score
+    ZSet(SortedSet),
+    /// An append-only log of entries
+    Stream(Stream),
+}
+
+impl Value {
+    /// The name of every value type, as reported by TYPE.
+    pub const TYPE_NAMES: [&'static str; 6] = ["string", "list", "hash", "set", "zset", "stream"];
+    /// Returns the type name reported by the TYPE command.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    /// Returns the string payload, or `None` for other types.
+    pub fn as_string(&self) -> Option<&StringValue> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Returns the internal representation reported by OBJECT ENCODING,
+    /// using the Redis names for the equivalent encodings.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(s) => s.encoding(),
+            Value::List(_) => "quicklist",
+            Value::Hash(_) | Value::Set(_) => "hashtable",
+            Value::ZSet(_) => "skiplist",
+            Value::Stream(_) => "stream",
+        }
+    }
+
+    /// Returns the position of this value's type in [`Value::TYPE_NAMES`].
+    fn type_index(&self) -> usize {
+        match self {
+            Value::String(_) => 0,
+            Value::List(_) => 1,
+            Value::Hash(_) => 2,
+            Value::Set(_) => 3,
+            Value::ZSet(_) => 4,
+            Value::Stream(_) => 5,
+        }
+    }
+
+    /// Returns roughly how many allocations freeing this value walks: the
+    /// element count for collections, and for strings one per
+    /// [`LAZYFREE_STRING_UNIT`] bytes, since unmapping a huge buffer costs
+    /// about as much.
+    pub fn free_effort(&self) -> usize {
+        match self {
+            Value::String(s) => 1 + s.len() / LAZYFREE_STRING_UNIT,
+            Value::List(list) => list.len(),
+            Value::Hash(hash) => hash.len(),
+            Value::Set(set) => set.len(),
+            Value::ZSet(zset) => zset.len(),
+            Value::Stream(stream) => stream.len(),
+        }
+    }
+
+    /// Approximate payload size in bytes (used by memory reporting).
+    ///
+    /// Walks every element of a collection, so it is only computed when a
+    /// value is stored whole; entries keep their size up to date from then on.
+    fn approximate_size(&self) -> usize {
+        match self {
+            Value::String(s) => s.heap_size(),
+            Value::List(list) => list.iter().map(|v| v.len()).sum(),
+            Value::Hash(hash) => hash.iter().map(|(f, v)| f.len() + v.len()).sum(),
+            Value::Set(set) => set.iter().map(|m| m.len()).sum(),
+            Value::ZSet(zset) => zset.iter().map(|(m, _)| m.len() + 8).sum(),
+            Value::Stream(stream) => stream.len() * STREAM_ENTRY_SIZE,
+        }
+    }
+}
+
+impl From<Bytes> for Value {
+    fn from(value: Bytes) -> Self {
+        Value::String(StringValue::from(value))
+    }
+}
+
+impl From<StringValue> for Value {
+    fn from(value: StringValue) -> Self {
+        Value::String(value)
+    }
+}
+
+/// Collection types stored in a [`Value`], giving typed access to entries.
+trait Collection: Default {
+    /// Borrows the payload if `value` holds this type.
+    fn get(value: &Value) -> Option<&Self>;
+    /// Mutably borrows the payload if `value` holds this type.
+    fn get_mut(value: &mut Value) -> Option<&mut Self>;
+    /// Wraps the payload in a [`Value`].
+    fn wrap(self) -> Value;
+    /// Whether the collection has no elements.
+    fn is_empty(&self) -> bool;
+}
+
+macro_rules! impl_collection {
+    ($ty:ty, $variant:ident) => {
+        impl Collection for $ty {
+            fn get(value: &Value) -> Option<&Self> {
+                match value {
+                    Value::$variant(v) => Some(v),
+                    _ => None,
+                }
+            }
+
+            fn get_mut(value: &mut Value) -> Option<&mut Self> {
+                match value {
+                    Value::$variant(v) => Some(v),
+                    _ => None,
+                }
+            }
+
+            fn wrap(self) -> Value {
+                Value::$variant(self)
+            }
+
+            fn is_empty(&self) -> bool {
+                <$ty>::is_empty(self)
+            }
+        }
+    };
+}
+
+impl_collection!(List, List);
+impl_collection!(Hash, Hash);
+impl_collection!(Set, Set);
+impl_collection!(SortedSet, ZSet);
+impl_collection!(Stream, Stream);
+
+thread_local! {
+    /// Whether key accesses on this thread are left unrecorded (see
+    /// [`without_touching`])
+    static NO_TOUCH: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` without recording the key accesses it makes on this thread,
+/// for clients that turned on `CLIENT NO-TOUCH`. Idle times and access
+/// frequencies stay as they were; only TOUCH still records an access.
+pub fn without_touching<T>(f: impl FnOnce() -> T) -> T {
+    /// Restores the previous setting, even if `f` panics
+    struct Restore(bool);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            NO_TOUCH.set(self.0);
+        }
+    }
+
+    let _restore = Restore(NO_TOUCH.replace(true));
+    f()
+}
+
+/// Access bookkeeping of an entry.
+///
+/// Atomics let reads running under a shard's read lock record accesses
+/// without upgrading to the write lock. Updates are relaxed: the values
+/// are approximations used for introspection and eviction.
+#[derive(Debug)]
+struct AccessStats {
+    /// Milliseconds between the entry's creation and its last access
+    last_access_ms: AtomicU64,
+    /// Logarithmic access-frequency counter (see [`Entry::frequency`])
+    lfu_counter: AtomicU8,
+}
+
+impl AccessStats {
+    fn new() -> Self {
+        Self {
+            last_access_ms: AtomicU64::new(0),
+            lfu_counter: AtomicU8::new(LFU_INIT_VAL),
+        }
+    }
+}
 
-/// Number of shards for the storage engine.
-/// More shards = less lock contention, but more memory overhead.
-/// 64 is a good balance for most workloads.
-const NUM_SHARDS: usize = 64;
+impl Clone for AccessStats {
+    fn clone(&self) -> Self {
+        Self {
+            last_access_ms: AtomicU64::new(self.last_access_ms.load(Ordering::Relaxed)),
+            lfu_counter: AtomicU8::new(self.lfu_counter.load(Ordering::Relaxed)),
+        }
+    }
+}
 
-/// Represents a stored value with optional expiry time.
+/// A stored value of any type with its expiry metadata.
 #[derive(Debug, Clone)]
 pub struct Entry {
-    /// The actual value stored
-    pub value: Bytes,
+    /// The actual value stored, shared with any live keyspace snapshots
+    /// (modified through the engine, which copies it on write)
+    pub value: Arc<Value>,
     /// When this entry expires (None = never expires)
     pub expires_at: Option<Instant>,
     /// When this entry was created
     pub created_at: Instant,
-    /// Last access time (for potential LRU eviction in the future)
-    pub last_accessed: Instant,
+    /// Last access time and access frequency
+    access: AccessStats,
+    /// Approximate size of the value, as accounted in its shard's memory
+    /// counter
+    size: usize,
+    /// Whether the key was copied into its shard's key arena
+    compact_key: bool,
 }
 
 impl Entry {
     /// Creates a new entry without expiry.
-    pub fn new(value: Bytes) -> Self {
-        let now = Instant::now();
+    pub fn new(value: impl Into<Value>) -> Self {
+        Self::shared(Arc::new(value.into()))
+    }
+
+    /// Creates a new entry without expiry holding an already shared value.
+    pub fn shared(value: Arc<Value>) -> Self {
         Self {
+            size: value.approximate_size(),
             value,
             expires_at: None,
-            created_at: now,
-            last_accessed: now,
+            created_at: clock::coarse_now(),
+            access: AccessStats::new(),
+            compact_key: false,
         }
     }
 
-    /// Creates a new entry with TTL.
-    pub fn with_ttl(value: Bytes, ttl: Duration) -> Self {
-        let now = Instant::now();
-        Self {
-            value,
-            expires_at: Some(now + ttl),
-            created_at: now,
-            last_accessed: now,
-        }
+    /// Creates a new entry with TTL. A zero TTL gives an entry that has
+    /// already expired.
+    pub fn with_ttl(value: impl Into<Value>, ttl: Duration) -> Self {
+        Self::with_deadline(value, clock::deadline_after(ttl))
+    }
+
+    /// Creates a new entry expiring at `deadline`. A deadline that has
+    /// passed gives an entry that has already expired.
+    fn with_deadline(value: impl Into<Value>, deadline: Instant) -> Self {
+        let mut entry = Self::new(value);
+        // Deadlines come from the exact clock, which the coarse one trails;
+        // an already expired entry takes the coarse time, so that expiry
+        // checks see it expired straight away
+        entry.expires_at = Some(if deadline <= Instant::now() {
+            entry.created_at
+        } else {
+            deadline
+        });
+        entry
     }
 
-    /// Checks if this entry has expired.
+    /// Checks if this entry has expired, on the coarse clock: an entry can
+    /// be seen as live for up to [`clock::COARSE_RESOLUTION`] past its
+    /// deadline, but never as expired before it.
     #[inline]
     pub fn is_expired(&self) -> bool {
         self.expires_at
-            .map(|exp| Instant::now() >= exp)
+            .map(|exp| clock::coarse_now() >= exp)
             .unwrap_or(false)
     }
 
+    /// Records an access, updating the idle time and access frequency,
+    /// unless inside [`without_touching`].
+    ///
+    /// Only needs a shared reference, so it can be called under a read lock.
+    pub fn touch(&self) {
+        if !NO_TOUCH.get() {
+            self.record_access();
+        }
+    }
+
+    /// Records an access like [`touch`](Self::touch), even inside
+    /// [`without_touching`] (TOUCH).
+    pub fn record_access(&self) {
+        let counter = eviction::lfu_increment(self.frequency(), eviction::lfu_log_factor());
+        self.access.lfu_counter.store(counter, Ordering::Relaxed);
+        self.access.last_access_ms.store(
+            clock::coarse_now()
+                .saturating_duration_since(self.created_at)
+                .as_millis() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Returns when the entry was last accessed (or created).
+    pub fn last_accessed(&self) -> Instant {
+        self.created_at + Duration::from_millis(self.access.last_access_ms.load(Ordering::Relaxed))
+    }
+
+    /// Returns the access-frequency counter (0-255), decayed by one for
+    /// every `lfu-decay-time` minutes the entry has been idle (see
+    /// [`eviction`]).
+    pub fn frequency(&self) -> u8 {
+        eviction::lfu_decay(
+            self.access.lfu_counter.load(Ordering::Relaxed),
+            self.idle_time(),
+            eviction::lfu_decay_time(),
+        )
+    }
+
+    /// Returns how long ago the entry was last accessed.
+    pub fn idle_time(&self) -> Duration {
+        clock::coarse_now().saturating_duration_since(self.last_accessed())
+    }
+
+    /// Creates a new entry expiring at a Unix time in milliseconds.
+    pub fn with_expire_time_ms(value: impl Into<Value>, unix_ms: i64) -> Self {
+        Self::with_deadline(value, clock::from_unix_ms(unix_ms))
+    }
+
+    /// Returns the expiry as a Unix time in milliseconds, or None if no
+    /// expiry. Unlike `expires_at`, this can be persisted or sent to another
+    /// process.
+    pub fn expire_time_ms(&self) -> Option<i64> {
+        self.expires_at.map(clock::to_unix_ms)
+    }
+
     /// Returns the remaining TTL in milliseconds, or None if no expiry.
     pub fn ttl_ms(&self) -> Option<u64> {
         self.expires_at.map(|exp| {
@@ -96,60 +480,300 @@ impl Entry {
     }
 }
 
-/// Represents a stored list with optional expiry time.
-#[derive(Debug, Clone)]
-pub struct ListEntry {
-    /// The actual list data stored as a deque for O(1) push/pop on both ends
-    pub data: VecDeque<Bytes>,
-    /// When this entry expires (None = never expires)
-    pub expires_at: Option<Instant>,
-    /// When this entry was created
-    pub created_at: Instant,
+/// One shard's contents as of a snapshot, filled in by whichever comes
+/// first: the next write to the shard or the snapshot itself.
+type CaptureSlot = Arc<Mutex<Option<Vec<(Bytes, Entry)>>>>;
+
+/// A single shard containing a portion of the keyspace.
+#[derive(Debug)]
+struct Shard {
+    /// Every key in this shard, whatever the type of its value
+    data: ShardLock<KeyMap>,
+    /// Snapshots still waiting for this shard's contents
+    captures: Mutex<Vec<CaptureSlot>>,
+    /// Whether `captures` is non-empty, checked on every write
+    capture_pending: AtomicBool,
+    /// Approximate bytes taken by the keys in this shard, kept up to date
+    /// by every write so memory reporting never walks the keyspace
+    used_memory: AtomicU64,
+    /// Number of keys in this shard, by kind
+    counts: ShardCounts,
+    /// Chunks new keys are copied into with `compact-keys`
+    arena: Mutex<KeyArena>,
+    /// Bumped whenever a new key enters the shard, so SCAN can tell whether
+    /// its cached order is missing any
+    generation: AtomicU64,
+    /// The keys in SCAN order, as of the last time a scan needed them
+    scan_order: Mutex<Option<Arc<ScanOrder>>>,
 }
 
-impl ListEntry {
-    /// Creates a new empty list entry without expiry.
-    pub fn new() -> Self {
+/// A shard's keys sorted by their SCAN position, shared by every scan
+/// passing through the shard.
+///
+/// It is only rebuilt when a scan enters the shard and keys were added
+/// since it was taken, so other SCAN calls cost a binary search plus the
+/// keys they examine. Keys deleted since are still listed, and skipped;
+/// the order holds on to their bytes until it is rebuilt or the shard is
+/// flushed.
+#[derive(Debug)]
+struct ScanOrder {
+    /// The shard's generation when the order was taken
+    generation: u64,
+    /// Every key with its position, lowest first
+    keys: Vec<(u64, Bytes)>,
+}
+
+/// The key counters of a shard.
+///
+/// They only change while the shard is write-locked, together with the map
+/// they count, so they always agree with it; a flush resets them under the
+/// same lock. Atomics let DBSIZE and INFO add them up without locking.
+#[derive(Debug, Default)]
+struct ShardCounts {
+    /// Keys of every type
+    keys: AtomicU64,
+    /// Keys of each type, indexed like `Value::TYPE_NAMES`
+    by_type: [AtomicU64; Value::TYPE_NAMES.len()],
+    /// Keys with an expiry
+    expires: AtomicU64,
+    /// Total length of the keys
+    key_bytes: AtomicU64,
+    /// Keys stored in the key arena
+    compact_keys: AtomicU64,
+}
+
+impl ShardCounts {
+    /// Zeroes every counter, for a shard whose map was emptied.
+    fn reset(&self) {
+        for count in [
+            &self.keys,
+            &self.expires,
+            &self.key_bytes,
+            &self.compact_keys,
+        ]
+        .into_iter()
+        .chain(&self.by_type)
+        {
+            count.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Shard {
+    fn new(locking: ShardLocking) -> Self {
         Self {
-            data: VecDeque::new(),
-            expires_at: None,
-            created_at: Instant::now(),
+            data: ShardLock::new(locking, KeyMap::default()),
+            captures: Mutex::new(Vec::new()),
+            capture_pending: AtomicBool::new(false),
+            used_memory: AtomicU64::new(0),
+            counts: ShardCounts::default(),
+            arena: Mutex::new(KeyArena::new()),
+            generation: AtomicU64::new(0),
+            scan_order: Mutex::new(None),
         }
     }
 
-    /// Checks if this list entry has expired.
-    #[inline]
-    pub fn is_expired(&self) -> bool {
-        self.expires_at
-            .map(|exp| Instant::now() >= exp)
-            .unwrap_or(false)
+    /// Returns the keys in SCAN order, positioned by the hash bits above
+    /// `shard_bits`. With `refresh`, an order missing keys added since it
+    /// was taken is rebuilt first.
+    fn scan_order(&self, shard_bits: u32, refresh: bool) -> Arc<ScanOrder> {
+        if let Some(order) = &*self.scan_order.lock() {
+            if !refresh || order.generation == self.generation.load(Ordering::Relaxed) {
+                return Arc::clone(order);
+            }
+        }
+
+        // Only the copying is done under the shard lock, not the sorting
+        let (generation, mut keys) = {
+            let data = self.data.read();
+            let keys: Vec<_> = data
+                .keys()
+                .map(|key| (key_hash(key) >> shard_bits, key.clone()))
+                .collect();
+            (self.generation.load(Ordering::Relaxed), keys)
+        };
+        keys.sort_unstable_by_key(|(position, _)| *position);
+        let order = Arc::new(ScanOrder { generation, keys });
+        *self.scan_order.lock() = Some(Arc::clone(&order));
+        order
+    }
+
+    /// Write-locks the shard, first handing its contents to any snapshot
+    /// still waiting for them.
+    fn write(&self) -> WriteGuard<'_, KeyMap> {
+        let data = self.data.write();
+        if self.capture_pending.load(Ordering::Acquire) {
+            self.capture(&data);
+        }
+        data
+    }
+
+    /// Upgrades a lock taken with `data.upgradable_read()` to a write lock,
+    /// handing the contents to any waiting snapshot like [`write`](Self::write).
+    fn upgrade<'a>(&self, data: UpgradableGuard<'a, KeyMap>) -> WriteGuard<'a, KeyMap> {
+        let data = data.upgrade();
+        if self.capture_pending.load(Ordering::Acquire) {
+            self.capture(&data);
+        }
+        data
+    }
+
+    /// Copies the live entries into every waiting capture slot. Must be
+    /// called with the shard locked, so no write can slip in between.
+    fn capture(&self, data: &KeyMap) {
+        let mut captures = self.captures.lock();
+        if let Some(last) = captures.pop() {
+            let entries: Vec<_> = data
+                .iter()
+                .filter(|(_, entry)| !entry.is_expired())
+                .map(|(key, entry)| (key.clone(), entry.clone()))
+                .collect();
+            for slot in captures.drain(..) {
+                *slot.lock() = Some(entries.clone());
+            }
+            *last.lock() = Some(entries);
+        }
+        self.capture_pending.store(false, Ordering::Release);
     }
 }
 
-impl Default for ListEntry {
-    fn default() -> Self {
-        Self::new()
+/// A collection borrowed for modification from its entry, which keeps the
+/// entry's size, and the memory counter of its shard, in step with it.
+///
+/// Writes that change how many bytes the collection holds report it with
+/// [`grow`](Self::grow) and [`shrink`](Self::shrink).
+struct Tracked<'a, T> {
+    value: &'a mut T,
+    size: &'a mut usize,
+    used_memory: &'a AtomicU64,
+}
+
+impl<'a, T: Collection> Tracked<'a, T> {
+    /// Borrows the collection in `entry`, first copying it if a snapshot
+    /// still shares it. Returns `None` if the entry holds another type.
+    fn new(entry: &'a mut Entry, used_memory: &'a AtomicU64) -> Option<Self> {
+        let Entry { value, size, .. } = entry;
+        Some(Self {
+            value: T::get_mut(Arc::make_mut(value))?,
+            size,
+            used_memory,
+        })
     }
 }
 
-/// A single shard containing a portion of the key-value pairs.
-#[derive(Debug)]
-struct Shard {
-    /// The actual data storage for strings
-    data: RwLock<HashMap<Bytes, Entry>>,
-    /// The actual data storage for lists
-    lists: RwLock<HashMap<Bytes, ListEntry>>,
+impl<T> Tracked<'_, T> {
+    /// Accounts `bytes` more held by the collection.
+    fn grow(&mut self, bytes: usize) {
+        *self.size += bytes;
+        self.used_memory.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Accounts `bytes` fewer held by the collection.
+    fn shrink(&mut self, bytes: usize) {
+        let bytes = bytes.min(*self.size);
+        *self.size -= bytes;
+        self.used_memory.fetch_sub(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Accounts the collection as holding `size` bytes.
+    fn resize(&mut self, size: usize) {
+        if size > *self.size {
+            self.grow(size - *self.size);
+        } else {
+            self.shrink(*self.size - size);
+        }
+    }
 }
 
-impl Shard {
-    fn new() -> Self {
-        Self {
-            data: RwLock::new(HashMap::new()),
-            lists: RwLock::new(HashMap::new()),
+impl Tracked<'_, Hash> {
+    /// Sets a field of the hash, accounting the change in its size.
+    ///
+    /// # Returns
+    /// The previous value of the field, if it had one.
+    fn set_field(&mut self, field: Bytes, value: Bytes) -> Option<Bytes> {
+        let field_len = field.len();
+        self.grow(value.len());
+        let old = self.value.insert(field, value);
+        match &old {
+            Some(old) => self.shrink(old.len()),
+            None => self.grow(field_len),
         }
+        old
+    }
+}
+
+impl<T> std::ops::Deref for Tracked<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> std::ops::DerefMut for Tracked<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+/// The shards of a set of keys, write-locked for an atomic multi-key
+/// operation.
+///
+/// See [`StorageEngine::atomically`].
+pub struct KeyLocks<'a> {
+    engine: &'a StorageEngine,
+    guards: BTreeMap<usize, WriteGuard<'a, KeyMap>>,
+}
+
+impl KeyLocks<'_> {
+    /// Returns the shard map holding `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` wasn't among the keys locked.
+    fn data(&mut self, key: &[u8]) -> &mut KeyMap {
+        let index = self.engine.shard_index(key);
+        self.guards
+            .get_mut(&index)
+            .expect("key was not locked by StorageEngine::atomically")
+    }
+
+    /// Returns whether `key` exists, whatever its type.
+    pub fn exists(&mut self, key: &[u8]) -> bool {
+        live_entry(self.data(key), key).is_some()
+    }
+
+    /// Returns the string value of `key`, if it holds one.
+    pub fn get(&mut self, key: &[u8]) -> Option<Bytes> {
+        let entry = live_entry(self.data(key), key)?;
+        entry.touch();
+        entry.value.as_string().map(StringValue::to_bytes)
+    }
+
+    /// Sets `key` to `value` without expiry, replacing whatever it held.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if a new key was created.
+    pub fn set(&mut self, key: Bytes, value: impl Into<Value>) -> bool {
+        let engine = self.engine;
+        engine.set_count.fetch_add(1, Ordering::Relaxed);
+        engine.insert_entry(self.data(&key), key, Entry::new(value))
     }
 }
 
+/// A snapshot that has been started but not yet copied out of the shards.
+///
+/// See [`StorageEngine::begin_snapshot`].
+#[derive(Debug)]
+pub struct PendingSnapshot {
+    /// Index of each database snapshotted, with a slot per shard
+    dbs: Vec<(usize, Vec<CaptureSlot>)>,
+    /// Code of each function library
+    functions: Vec<Bytes>,
+    taken_at_ms: i64,
+}
+
 /// The main storage engine for FlashKV.
 ///
 /// This is the "brain" of the database - it stores all key-value pairs
@@ -180,11 +804,15 @@ impl Shard {
 /// engine.set_with_ttl(Bytes::from("session"), Bytes::from("abc123"), Duration::from_secs(60));
 /// ```
 pub struct StorageEngine {
-    /// Sharded storage for reduced lock contention
+    /// Sharded storage for reduced lock contention; always a power of two
     shards: Vec<Shard>,
 
-    /// Statistics: total number of keys (approximate)
-    key_count: AtomicU64,
+    /// Number of low hash bits selecting a key's shard (log2 of the shard
+    /// count)
+    shard_bits: u32,
+
+    /// How each shard is locked
+    locking: ShardLocking,
 
     /// Statistics: total GET operations
     get_count: AtomicU64,
@@ -195,18 +823,81 @@ pub struct StorageEngine {
     /// Statistics: total DEL operations
     del_count: AtomicU64,
 
+    /// Statistics: reads that found the key they looked up
+    keyspace_hits: AtomicU64,
+
+    /// Statistics: reads that didn't find the key they looked up
+    keyspace_misses: AtomicU64,
+
     /// Statistics: number of expired keys cleaned up
     expired_count: AtomicU64,
 
     /// Statistics: total list operations
     list_op_count: AtomicU64,
+
+    /// Statistics: total hash operations
+    hash_op_count: AtomicU64,
+
+    /// Statistics: total set operations
+    set_op_count: AtomicU64,
+
+    /// Statistics: total sorted set operations
+    zset_op_count: AtomicU64,
+
+    /// Statistics: total stream operations
+    stream_op_count: AtomicU64,
+
+    /// Clients blocked on keys (BZPOPMIN and friends)
+    waiters: KeyWaiters,
+
+    /// Background reclamation of large unlinked values
+    lazy_free: Arc<LazyFree>,
+
+    /// Memory limit and eviction policy
+    eviction: Arc<Eviction>,
+
+    /// Snapshot file and save status
+    snapshots: Arc<Snapshots>,
+
+    /// Append-only file, when enabled
+    aof: Arc<Aof>,
+
+    /// Replicas fed with the writes applied here
+    replication: Arc<Replication>,
+
+    /// Slot layout, when running in cluster mode
+    cluster: Arc<Cluster>,
+
+    /// Pub/sub channels and patterns with their subscribers
+    pubsub: Arc<PubSub>,
+
+    /// Function libraries loaded with FUNCTION LOAD
+    functions: Arc<Functions>,
+
+    /// Keys expired or evicted here and not yet propagated as DELs
+    expired_keys: Mutex<Vec<Bytes>>,
+
+    /// Whether clients may only read; only database 0's is used
+    read_only: AtomicBool,
+
+    /// Whether short keys are copied into the shards' key arenas
+    compact_keys: AtomicBool,
+
+    /// Keys each shard map is sized for when the database is flushed
+    shard_capacity: AtomicUsize,
+
+    /// Index of this database (SELECT)
+    index: usize,
+
+    /// Databases 1 and up, held by database 0; empty in the others
+    dbs: Vec<StorageEngine>,
 }
 
 impl std::fmt::Debug for StorageEngine {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("StorageEngine")
             .field("shards", &self.shards.len())
-            .field("key_count", &self.key_count.load(Ordering::Relaxed))
+            .field("key_count", &self.len())
             .field("get_count", &self.get_count.load(Ordering::Relaxed))
             .field("set_count", &self.set_count.load(Ordering::Relaxed))
             .finish()
@@ -220,27 +911,163 @@ impl Default for StorageEngine {
 }
 
 impl StorageEngine {
-    /// Creates a new storage engine with default settings.
+    /// Creates a new storage engine with default settings, and
+    /// [`DEFAULT_DATABASES`] databases.
     pub fn new() -> Self {
-        let shards = (0..NUM_SHARDS).map(|_| Shard::new()).collect();
+        Self::with_databases(DEFAULT_DATABASES)
+    }
+
+    /// Creates a new storage engine with `count` databases (at least one)
+    /// of [`default_shard_count`] shards.
+    ///
+    /// The engine returned is database 0; the others are reached with
+    /// [`db`](Self::db). Every database has its own keyspace, but the
+    /// persistence, replication, cluster and pub/sub state is the server's
+    /// and shared by all of them.
+    pub fn with_databases(count: usize) -> Self {
+        Self::with_shards(count, default_shard_count())
+    }
+
+    /// Creates a new storage engine with `count` databases of `shards`
+    /// shards each. `shards` is rounded up to a power of two, so a key's
+    /// shard is picked with a mask, and capped at [`MAX_SHARDS`].
+    pub fn with_shards(count: usize, shards: usize) -> Self {
+        Self::with_locking(count, shards, ShardLocking::ReadWrite)
+    }
+
+    /// Creates a new storage engine like [`with_shards`](Self::with_shards),
+    /// with its shards locked as `locking` says: [`ShardLocking::Bucket`]
+    /// is meant for many more, smaller shards than the default, such as
+    /// [`ShardLocking::default_shard_count`] gives.
+    pub fn with_locking(count: usize, shards: usize, locking: ShardLocking) -> Self {
+        let shard_bits = shards
+            .clamp(1, MAX_SHARDS)
+            .next_power_of_two()
+            .trailing_zeros();
+        let mut db0 = Self::database(0, shard_bits, locking, None);
+        db0.dbs = (1..count)
+            .map(|index| Self::database(index, shard_bits, locking, Some(&db0)))
+            .collect();
+        db0
+    }
+
+    /// Creates an empty database sharing the server's state with `db0`,
+    /// or with state of its own when it is database 0.
+    fn database(
+        index: usize,
+        shard_bits: u32,
+        locking: ShardLocking,
+        db0: Option<&StorageEngine>,
+    ) -> Self {
+        let (lazy_free, eviction, snapshots, aof, replication, cluster, pubsub, functions) =
+            match db0 {
+                Some(db0) => (
+                    Arc::clone(&db0.lazy_free),
+                    Arc::clone(&db0.eviction),
+                    Arc::clone(&db0.snapshots),
+                    Arc::clone(&db0.aof),
+                    Arc::clone(&db0.replication),
+                    Arc::clone(&db0.cluster),
+                    Arc::clone(&db0.pubsub),
+                    Arc::clone(&db0.functions),
+                ),
+                None => (
+                    Arc::new(LazyFree::new()),
+                    Arc::new(Eviction::new()),
+                    Arc::new(Snapshots::new()),
+                    Arc::new(Aof::new()),
+                    Arc::new(Replication::new()),
+                    Arc::new(Cluster::new()),
+                    Arc::new(PubSub::new()),
+                    Arc::new(Functions::new()),
+                ),
+            };
+        let shards = (0..1 << shard_bits).map(|_| Shard::new(locking)).collect();
 
         Self {
             shards,
-            key_count: AtomicU64::new(0),
+            shard_bits,
+            locking,
             get_count: AtomicU64::new(0),
             set_count: AtomicU64::new(0),
             del_count: AtomicU64::new(0),
+            keyspace_hits: AtomicU64::new(0),
+            keyspace_misses: AtomicU64::new(0),
             expired_count: AtomicU64::new(0),
             list_op_count: AtomicU64::new(0),
+            hash_op_count: AtomicU64::new(0),
+            set_op_count: AtomicU64::new(0),
+            zset_op_count: AtomicU64::new(0),
+            stream_op_count: AtomicU64::new(0),
+            waiters: KeyWaiters::new(),
+            lazy_free,
+            eviction,
+            snapshots,
+            aof,
+            replication,
+            cluster,
+            pubsub,
+            functions,
+            expired_keys: Mutex::new(Vec::new()),
+            read_only: AtomicBool::new(false),
+            compact_keys: AtomicBool::new(false),
+            shard_capacity: AtomicUsize::new(0),
+            index,
+            dbs: Vec::new(),
+        }
+    }
+
+    /// Returns database `index`, or `None` if there is no such database.
+    ///
+    /// Only database 0 knows the others; any database returns itself for
+    /// its own index.
+    pub fn db(&self, index: usize) -> Option<&StorageEngine> {
+        if index == self.index {
+            return Some(self);
         }
+        match index.checked_sub(1) {
+            Some(i) if self.index == 0 => self.dbs.get(i),
+            _ => None,
+        }
+    }
+
+    /// Iterates over every database, this one first: all of them for
+    /// database 0, and just itself for the others.
+    pub fn databases(&self) -> impl Iterator<Item = &StorageEngine> + '_ {
+        std::iter::once(self).chain(&self.dbs)
+    }
+
+    /// Returns the number of databases (the `databases` setting), when
+    /// called on database 0.
+    pub fn database_count(&self) -> usize {
+        self.dbs.len() + 1
+    }
+
+    /// Returns the index of this database.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the number of shards each database is split into.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns how the shards are locked.
+    pub fn locking(&self) -> ShardLocking {
+        self.locking
+    }
+
+    /// Returns the registry of clients blocked on keys.
+    pub fn waiters(&self) -> &KeyWaiters {
+        &self.waiters
     }
 
-    /// Determines which shard a key belongs to.
+    /// Determines which shard a key belongs to, from its hash tag if it
+    /// has one.
     #[inline]
     fn shard_index(&self, key: &[u8]) -> usize {
-        let mut hasher = DefaultHasher::new();
-        key.hash(&mut hasher);
-        (hasher.finish() as usize) % NUM_SHARDS
+        (key_hash(hash_tag(key)) as usize) & (self.shards.len() - 1)
     }
 
     /// Gets the shard for a given key.
@@ -249,27 +1076,278 @@ impl StorageEngine {
         &self.shards[self.shard_index(key)]
     }
 
-    /// Sets a key-value pair without expiry.
-    ///
-    /// If the key already exists, its value is overwritten.
-    ///
-    /// # Returns
-    ///
-    /// Returns `true` if a new key was created, `false` if an existing key was updated.
-    pub fn set(&self, key: Bytes, value: Bytes) -> bool {
-        self.set_count.fetch_add(1, Ordering::Relaxed);
-
-        let shard = self.get_shard(&key);
-        let mut data = shard.data.write().unwrap();
+    /// Returns the bytes `key` and its entry are accounted for.
+    fn footprint(key: &[u8], entry: &Entry) -> u64 {
+        (key.len() + ENTRY_OVERHEAD + entry.size) as u64
+    }
 
-        let is_new = !data.contains_key(&key);
-        data.insert(key, Entry::new(value));
+    /// Adds up one of the shards' key counters.
+    fn sum_counts(&self, count: impl Fn(&ShardCounts) -> &AtomicU64) -> u64 {
+        self.shards
+            .iter()
+            .map(|shard| count(&shard.counts).load(Ordering::Relaxed))
+            .sum()
+    }
 
-        if is_new {
-            self.key_count.fetch_add(1, Ordering::Relaxed);
+    /// Updates the keyspace counters for an entry entering the keyspace.
+    /// Must be called with the key's shard write-locked.
+    fn count_added(&self, key: &[u8], entry: &Entry) {
+        let shard = self.get_shard(key);
+        let counts = &shard.counts;
+        shard
+            .used_memory
+            .fetch_add(Self::footprint(key, entry), Ordering::Relaxed);
+        counts.keys.fetch_add(1, Ordering::Relaxed);
+        counts
+            .key_bytes
+            .fetch_add(key.len() as u64, Ordering::Relaxed);
+        if entry.compact_key {
+            counts.compact_keys.fetch_add(1, Ordering::Relaxed);
+        }
+        counts.by_type[entry.value.type_index()].fetch_add(1, Ordering::Relaxed);
+        if entry.expires_at.is_some() {
+            counts.expires.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Updates the keyspace counters for an entry leaving the keyspace.
+    /// Must be called with the key's shard write-locked.
+    fn count_removed(&self, key: &[u8], entry: &Entry) {
+        let shard = self.get_shard(key);
+        let counts = &shard.counts;
+        shard
+            .used_memory
+            .fetch_sub(Self::footprint(key, entry), Ordering::Relaxed);
+        counts.keys.fetch_sub(1, Ordering::Relaxed);
+        counts
+            .key_bytes
+            .fetch_sub(key.len() as u64, Ordering::Relaxed);
+        if entry.compact_key {
+            counts.compact_keys.fetch_sub(1, Ordering::Relaxed);
+        }
+        counts.by_type[entry.value.type_index()].fetch_sub(1, Ordering::Relaxed);
+        if entry.expires_at.is_some() {
+            counts.expires.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Changes the expiry of an entry in place, keeping the expires counter
+    /// in step.
+    fn set_expiry(&self, key: &[u8], entry: &mut Entry, expires_at: Option<Instant>) {
+        let expires = &self.get_shard(key).counts.expires;
+        match (entry.expires_at.is_some(), expires_at.is_some()) {
+            (false, true) => expires.fetch_add(1, Ordering::Relaxed),
+            (true, false) => expires.fetch_sub(1, Ordering::Relaxed),
+            _ => 0,
+        };
+        entry.expires_at = expires_at;
+    }
+
+    /// Replaces the value of an entry in place, keeping its shard's memory
+    /// counter in step.
+    fn replace_value(&self, key: &[u8], entry: &mut Entry, value: Value) {
+        let size = value.approximate_size();
+        let shard = self.get_shard(key);
+        shard.used_memory.fetch_add(size as u64, Ordering::Relaxed);
+        shard
+            .used_memory
+            .fetch_sub(entry.size as u64, Ordering::Relaxed);
+        entry.size = size;
+        let (old_type, new_type) = (entry.value.type_index(), value.type_index());
+        if old_type != new_type {
+            shard.counts.by_type[old_type].fetch_sub(1, Ordering::Relaxed);
+            shard.counts.by_type[new_type].fetch_add(1, Ordering::Relaxed);
+        }
+        match Arc::get_mut(&mut entry.value) {
+            Some(current) => self.lazy_free.free(std::mem::replace(current, value)),
+            // The old value is shared with a snapshot, and freed with it
+            None => entry.value = Arc::new(value),
+        }
+    }
+
+    /// Frees a value that left the keyspace, on the lazy-free thread if it
+    /// is large, so the shard lock it was removed under is never held up
+    /// by freeing it. A value still shared with a snapshot is freed with
+    /// the snapshot.
+    fn discard(&self, value: Arc<Value>) {
+        if let Ok(value) = Arc::try_unwrap(value) {
+            self.lazy_free.free(value);
+        }
+    }
+
+    /// Removes `key` from the shard map, updating the keyspace counters.
+    fn remove_entry(&self, data: &mut KeyMap, key: &[u8]) -> Option<Entry> {
+        let entry = data.remove(key)?;
+        self.count_removed(key, &entry);
+        Some(entry)
+    }
+
+    /// Removes an expired key, updating the statistics and noting it to be
+    /// propagated.
+    fn remove_expired(&self, data: &mut KeyMap, key: &[u8]) {
+        if let Some(entry) = self.remove_entry(data, key) {
+            self.discard(entry.value);
+            self.expired_count.fetch_add(1, Ordering::Relaxed);
+            self.pubsub
+                .notify(self.index, EventClass::Expired, "expired", key);
+            self.pubsub.invalidate(&[key], None);
+            if self.propagates_expiry() {
+                self.expired_keys.lock().push(Bytes::copy_from_slice(key));
+            }
+        }
+    }
+
+    /// Returns whether expired keys must be propagated as DELs, because
+    /// writes are logged or fed to replicas.
+    fn propagates_expiry(&self) -> bool {
+        self.aof.is_enabled() || self.replication.is_feeding()
+    }
+
+    /// Returns whether this server removes expired keys on its own. A
+    /// replica leaves that to its master, which sends a DEL for each, so
+    /// both keep the same keys whatever their clocks say.
+    fn expires_keys(&self) -> bool {
+        !self.replication.link().is_replica()
+    }
+
+    /// Takes the keys expired in every database since the last call, as
+    /// the DEL commands that propagate their expiry, preceded by the
+    /// SELECTs `log` needs to reach their database.
+    ///
+    /// Keys are noted under their shard lock, so a write that takes them
+    /// after its own (under the log lock) gets every expiry it depends on,
+    /// in time to propagate them before itself.
+    pub(crate) fn take_expired(&self, log: &mut AofGuard<'_>) -> Vec<Vec<Bytes>> {
+        let mut commands = Vec::new();
+        for db in self.databases() {
+            let keys = std::mem::take(&mut *db.expired_keys.lock());
+            if keys.is_empty() {
+                continue;
+            }
+            commands.extend(log.select(db.index));
+            commands.extend(
+                keys.into_iter()
+                    .map(|key| vec![Bytes::from_static(b"DEL"), key]),
+            );
+        }
+        commands
+    }
+
+    /// Propagates the expiry of the keys expired since the last write, as
+    /// DELs appended to the append-only file and fed to replicas.
+    ///
+    /// Writes propagate the expiries before themselves; this covers keys
+    /// expired by reads and the expiry sweeper while no write comes.
+    pub fn propagate_expired(&self) {
+        if self.databases().all(|db| db.expired_keys.lock().is_empty()) {
+            return;
+        }
+        let mut guard = self.aof.lock();
+        let dels = self.take_expired(&mut guard);
+        guard.append(&dels);
+        self.replication.feed(&dels);
+    }
+
+    /// Returns the live collection of type `T` at `key` for modification.
+    ///
+    /// An expired key is removed on the way. Returns `None` if the key is
+    /// missing, expired or holds another type.
+    fn live_mut<'a, T: Collection>(
+        &'a self,
+        data: &'a mut KeyMap,
+        key: &[u8],
+    ) -> Option<Tracked<'a, T>> {
+        if data.get(key).is_some_and(|e| e.is_expired()) {
+            self.remove_expired(data, key);
+            return None;
+        }
+        let entry = data.get_mut(key)?;
+        entry.touch();
+        Tracked::new(entry, &self.get_shard(key).used_memory)
+    }
+
+    /// Returns the collection of type `T` at `key`, creating an empty one if
+    /// the key is missing or expired.
+    ///
+    /// Returns `None` if the key holds another type.
+    fn get_or_create<'a, T: Collection>(
+        &'a self,
+        data: &'a mut KeyMap,
+        key: &Bytes,
+    ) -> Option<Tracked<'a, T>> {
+        match data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => entry.touch(),
+            Some(_) => {
+                self.remove_expired(data, key);
+                self.insert_entry(data, key.clone(), Entry::new(T::default().wrap()));
+            }
+            None => {
+                self.insert_entry(data, key.clone(), Entry::new(T::default().wrap()));
+            }
+        }
+        let entry = data.get_mut(key)?;
+        Tracked::new(entry, &self.get_shard(key).used_memory)
+    }
+
+    /// Deletes `key` if it holds an empty collection of type `T`
+    /// (empty lists, hashes, sets and sorted sets are never kept).
+    fn remove_if_empty<T: Collection>(&self, data: &mut KeyMap, key: &[u8]) {
+        let empty = data
+            .get(key)
+            .and_then(|e| T::get(&e.value))
+            .is_some_and(|c| c.is_empty());
+        if empty {
+            self.remove_entry(data, key);
+        }
+    }
+
+    /// Returns whether `key` is stored in its shard's key arena.
+    fn is_compact(&self, key: &[u8]) -> bool {
+        key.len() <= COMPACT_KEY_LIMIT && self.compact_keys.load(Ordering::Relaxed)
+    }
+
+    /// Inserts an entry, replacing any existing value of any type.
+    ///
+    /// # Returns
+    /// `true` if a new key was created.
+    fn insert_entry(&self, data: &mut KeyMap, key: Bytes, mut entry: Entry) -> bool {
+        if let Some(current) = data.get_mut(&key) {
+            // An existing key keeps the copy already in the map
+            entry.compact_key = current.compact_key;
+            self.count_added(&key, &entry);
+            let old = std::mem::replace(current, entry);
+            self.count_removed(&key, &old);
+            self.discard(old.value);
+            return false;
         }
 
-        is_new
+        let shard = self.get_shard(&key);
+        entry.compact_key = self.is_compact(&key);
+        let key = if entry.compact_key {
+            shard.arena.lock().copy(&key)
+        } else {
+            key
+        };
+        self.count_added(&key, &entry);
+        shard.generation.fetch_add(1, Ordering::Relaxed);
+        data.insert(key, entry);
+        true
+    }
+
+    /// Sets a key-value pair without expiry.
+    ///
+    /// If the key already exists, its value is overwritten whatever its type.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if a new key was created, `false` if an existing key was updated.
+    pub fn set(&self, key: Bytes, value: impl Into<Value>) -> bool {
+        self.set_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(&key);
+        let mut data = shard.write();
+
+        self.insert_entry(&mut data, key, Entry::new(value))
     }
 
     /// Sets a key-value pair with a TTL (Time-To-Live).
@@ -279,92 +1357,201 @@ impl StorageEngine {
     /// # Returns
     ///
     /// Returns `true` if a new key was created, `false` if an existing key was updated.
-    pub fn set_with_ttl(&self, key: Bytes, value: Bytes, ttl: Duration) -> bool {
+    pub fn set_with_ttl(&self, key: Bytes, value: impl Into<Value>, ttl: Duration) -> bool {
         self.set_count.fetch_add(1, Ordering::Relaxed);
 
         let shard = self.get_shard(&key);
-        let mut data = shard.data.write().unwrap();
+        let mut data = shard.write();
 
-        let is_new = !data.contains_key(&key);
-        data.insert(key, Entry::with_ttl(value, ttl));
+        self.insert_entry(&mut data, key, Entry::with_ttl(value, ttl))
+    }
 
-        if is_new {
-            self.key_count.fetch_add(1, Ordering::Relaxed);
-        }
+    /// Sets a key-value pair expiring at a Unix time in milliseconds (SET
+    /// with EX, PX, EXAT or PXAT). A time that has passed stores a key that
+    /// has already expired.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if a new key was created, `false` if an existing key was updated.
+    pub fn set_with_expire_at(&self, key: Bytes, value: impl Into<Value>, unix_ms: i64) -> bool {
+        self.set_count.fetch_add(1, Ordering::Relaxed);
 
-        is_new
+        let shard = self.get_shard(&key);
+        let mut data = shard.write();
+
+        self.insert_entry(&mut data, key, Entry::with_expire_time_ms(value, unix_ms))
     }
 
-    /// Gets the value for a key.
+    /// Sets a key-value pair, keeping the key's current TTL (SET ... KEEPTTL).
     ///
-    /// Returns `None` if the key doesn't exist or has expired.
-    /// This implements "lazy expiry" - expired keys are detected and removed on access.
-    pub fn get(&self, key: &Bytes) -> Option<Bytes> {
-        self.get_count.fetch_add(1, Ordering::Relaxed);
+    /// # Returns
+    ///
+    /// Returns `true` if a new key was created, `false` if an existing key was updated.
+    pub fn set_keep_ttl(&self, key: Bytes, value: impl Into<Value>) -> bool {
+        self.set_count.fetch_add(1, Ordering::Relaxed);
 
-        let shard = self.get_shard(key);
+        let shard = self.get_shard(&key);
+        let mut data = shard.write();
 
-        // First, try a read lock (fast path for existing, non-expired keys)
-        {
-            let data = shard.data.read().unwrap();
-            if let Some(entry) = data.get(key) {
-                if !entry.is_expired() {
-                    return Some(entry.value.clone());
-                }
-            } else {
-                return None;
+        let mut entry = Entry::new(value);
+        entry.expires_at = live_entry(&data, &key).and_then(|e| e.expires_at);
+        self.insert_entry(&mut data, key, entry)
+    }
+
+    /// Sets multiple keys at once (MSET), replacing whatever they held.
+    ///
+    /// The keys are set [atomically](Self::atomically): no reader sees some
+    /// of them set and others not yet.
+    pub fn mset(&self, pairs: Vec<(Bytes, Bytes)>) {
+        self.set_count
+            .fetch_add(pairs.len() as u64, Ordering::Relaxed);
+
+        // Sorted by shard, then position, so a repeated key ends up with
+        // the last value given
+        let mut pairs: Vec<_> = pairs
+            .into_iter()
+            .enumerate()
+            .map(|(i, (key, value))| (self.shard_index(&key), i, key, value))
+            .collect();
+        pairs.sort_unstable_by_key(|&(shard, i, ..)| (shard, i));
+
+        let mut guards: Vec<(usize, WriteGuard<'_, _>)> = Vec::new();
+        for (shard, _, key, value) in pairs {
+            if guards.last().map(|(index, _)| *index) != Some(shard) {
+                guards.push((shard, self.shards[shard].write()));
             }
+            let (_, data) = guards.last_mut().expect("the key's shard is locked");
+            self.insert_entry(data, key, Entry::new(value));
         }
+    }
 
-        // Key exists but is expired - need write lock to remove it
-        let mut data = shard.data.write().unwrap();
-        if let Some(entry) = data.get(key) {
-            if entry.is_expired() {
-                data.remove(key);
-                self.key_count.fetch_sub(1, Ordering::Relaxed);
-                self.expired_count.fetch_add(1, Ordering::Relaxed);
-                return None;
+    /// Sets multiple keys only if none of them exist (MSETNX).
+    ///
+    /// The keys are checked and set [atomically](Self::atomically), so
+    /// either all keys are set or none are, and no other writer can create
+    /// one of the keys in between the check and the write.
+    ///
+    /// # Returns
+    /// `true` if the keys were set, `false` if at least one already existed.
+    pub fn msetnx(&self, pairs: Vec<(Bytes, Bytes)>) -> bool {
+        let keys: Vec<Bytes> = pairs.iter().map(|(k, _)| k.clone()).collect();
+        self.atomically(&keys, |locks| {
+            if keys.iter().any(|key| locks.exists(key)) {
+                return false;
             }
-            // Race: another thread may have updated the key
-            return Some(entry.value.clone());
+            for (key, value) in pairs {
+                locks.set(key, value);
+            }
+            true
+        })
+    }
+
+    /// Gets the string values of several keys (MGET), as of one moment:
+    /// every involved shard is read-locked for the whole lookup.
+    ///
+    /// A key that doesn't exist or doesn't hold a string gives `None`.
+    pub fn mget(&self, keys: &[Bytes]) -> Vec<Option<Bytes>> {
+        self.get_count
+            .fetch_add(keys.len() as u64, Ordering::Relaxed);
+        let mut hits = 0;
+
+        let mut values = vec![None; keys.len()];
+        let mut guards: Vec<(usize, ReadGuard<'_, _>)> = Vec::new();
+        for (shard, i) in self.by_shard(keys) {
+            if guards.last().map(|(index, _)| *index) != Some(shard) {
+                guards.push((shard, self.shards[shard].data.read()));
+            }
+            let (_, data) = guards.last().expect("the key's shard is locked");
+            values[i] = live_entry(data, &keys[i]).and_then(|entry| {
+                hits += 1;
+                entry.touch();
+                entry.value.as_string().map(StringValue::to_bytes)
+            });
         }
+        self.keyspace_hits.fetch_add(hits, Ordering::Relaxed);
+        self.keyspace_misses
+            .fetch_add(keys.len() as u64 - hits, Ordering::Relaxed);
+        values
+    }
 
-        None
+    /// Gets the string value for a key.
+    ///
+    /// Returns `None` if the key doesn't exist, has expired or doesn't hold a string.
+    /// This implements "lazy expiry" - expired keys are detected and removed on access.
+    pub fn get(&self, key: &Bytes) -> Option<Bytes> {
+        self.get_count.fetch_add(1, Ordering::Relaxed);
+
+        let value = self.read_entry(key, |entry| {
+            entry.touch();
+            entry.value.as_string().map(StringValue::to_bytes)
+        });
+        self.count_lookup(value.is_some());
+        value.flatten()
+    }
+
+    /// Counts a read of a key as a keyspace hit if the key was found, or a
+    /// miss if not. Only reads count, as in Redis.
+    fn count_lookup(&self, found: bool) {
+        let counter = if found {
+            &self.keyspace_hits
+        } else {
+            &self.keyspace_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the live collection of type `T` stored at `key` for a read,
+    /// counting the lookup as a keyspace hit or miss. A key of another type
+    /// is a hit, though the read finds nothing.
+    ///
+    /// Reading a collection counts as an access of the key.
+    fn lookup<'a, T: Collection>(&self, data: &'a KeyMap, key: &[u8]) -> Option<&'a T> {
+        let entry = live_entry(data, key);
+        self.count_lookup(entry.is_some());
+        let entry = entry?;
+        entry.touch();
+        T::get(&entry.value)
     }
 
-    /// Gets the full entry for a key (including metadata).
+    /// Gets the full entry for a key (including metadata), whatever its type.
     ///
-    /// This is useful for commands like TTL that need access to expiry information.
+    /// This clones the value, so prefer the typed accessors for collections.
     pub fn get_entry(&self, key: &Bytes) -> Option<Entry> {
-        let shard = self.get_shard(key);
+        self.read_entry(key, Entry::clone)
+    }
 
+    /// Runs `f` against the live entry stored at `key`, if any, removing
+    /// the key if it has expired.
+    ///
+    /// A live key costs a single read lock. An expired one is checked again
+    /// under an upgradable lock, which is upgraded to remove it without
+    /// letting a writer in between; readers of other keys in the shard
+    /// aren't held up until then, and of several clients finding the same
+    /// expired key only one removes it.
+    fn read_entry<T>(&self, key: &[u8], f: impl FnOnce(&Entry) -> T) -> Option<T> {
+        let shard = self.get_shard(key);
         {
-            let data = shard.data.read().unwrap();
-            if let Some(entry) = data.get(key) {
-                if !entry.is_expired() {
-                    return Some(entry.clone());
-                }
-            } else {
-                return None;
+            let data = shard.data.read();
+            match data.get(key) {
+                Some(entry) if !entry.is_expired() => return Some(f(entry)),
+                Some(_) if self.expires_keys() => {}
+                _ => return None,
             }
         }
 
-        // Lazy cleanup of expired key
-        let mut data = shard.data.write().unwrap();
-        if let Some(entry) = data.get(key) {
-            if entry.is_expired() {
-                data.remove(key);
-                self.key_count.fetch_sub(1, Ordering::Relaxed);
-                self.expired_count.fetch_add(1, Ordering::Relaxed);
-                return None;
+        let data = shard.data.upgradable_read();
+        match data.get(key) {
+            Some(entry) if entry.is_expired() => {
+                let mut data = shard.upgrade(data);
+                self.remove_expired(&mut data, key);
+                None
             }
-            return Some(entry.clone());
+            // Written again, or removed, since the read lock was dropped
+            entry => entry.map(f),
         }
-
-        None
     }
 
-    /// Deletes a key from the database.
+    /// Deletes a key from the database, whatever its type.
     ///
     /// # Returns
     ///
@@ -373,42 +1560,130 @@ impl StorageEngine {
         self.del_count.fetch_add(1, Ordering::Relaxed);
 
         let shard = self.get_shard(key);
-        let mut data = shard.data.write().unwrap();
+        let mut data = shard.write();
 
-        if data.remove(key).is_some() {
-            self.key_count.fetch_sub(1, Ordering::Relaxed);
-            true
-        } else {
-            false
+        match data.get(key) {
+            Some(entry) if entry.is_expired() => {
+                self.remove_expired(&mut data, key);
+                false
+            }
+            Some(_) => {
+                let entry = self.remove_entry(&mut data, key);
+                drop(data);
+                self.discard(entry.unwrap().value);
+                self.pubsub
+                    .notify(self.index, EventClass::Generic, "del", key);
+                true
+            }
+            None => false,
         }
     }
 
+    /// Removes keys from the keyspace immediately but frees their values on a
+    /// background thread when they are large (UNLINK).
+    ///
+    /// Every key removed is freed that way, so this is the same as
+    /// [`delete_many`](Self::delete_many).
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of keys that were removed.
+    pub fn unlink(&self, keys: &[Bytes]) -> u64 {
+        self.delete_many(keys)
+    }
+
+    /// Returns the number of removed values waiting to be freed.
+    pub fn lazyfree_pending(&self) -> u64 {
+        self.lazy_free.pending()
+    }
+
+    /// Returns the number of values freed in the background so far.
+    pub fn lazyfreed(&self) -> u64 {
+        self.lazy_free.freed()
+    }
+
     /// Deletes multiple keys from the database.
     ///
     /// # Returns
     ///
     /// Returns the number of keys that were deleted.
     pub fn delete_many(&self, keys: &[Bytes]) -> u64 {
-        let mut deleted = 0;
-        for key in keys {
-            if self.delete(key) {
-                deleted += 1;
-            }
+        let removed = self.remove_many(keys);
+        let deleted = removed.iter().flatten().count() as u64;
+        for entry in removed.into_iter().flatten() {
+            self.discard(entry.value);
         }
         deleted
     }
 
+    /// Removes `keys`, locking each shard once for all of its keys rather
+    /// than once per key, and returns the entry removed for each key.
+    ///
+    /// The shards are locked one at a time, so unlike [`mset`](Self::mset)
+    /// the removal isn't atomic, just as deleting the keys one by one.
+    fn remove_many(&self, keys: &[Bytes]) -> Vec<Option<Entry>> {
+        self.del_count
+            .fetch_add(keys.len() as u64, Ordering::Relaxed);
+
+        let mut removed: Vec<Option<Entry>> = (0..keys.len()).map(|_| None).collect();
+        let mut locked: Option<(usize, WriteGuard<'_, _>)> = None;
+        for (shard, i) in self.by_shard(keys) {
+            if locked.as_ref().map(|(index, _)| *index) != Some(shard) {
+                drop(locked.take());
+                locked = Some((shard, self.shards[shard].write()));
+            }
+            let (_, data) = locked.as_mut().expect("the key's shard is locked");
+            match data.get(&keys[i]) {
+                Some(entry) if entry.is_expired() => self.remove_expired(data, &keys[i]),
+                Some(_) => removed[i] = self.remove_entry(data, &keys[i]),
+                None => {}
+            }
+        }
+        drop(locked);
+
+        // Published outside the shard locks, in the order the keys were given
+        for (key, entry) in keys.iter().zip(&removed) {
+            if entry.is_some() {
+                self.pubsub
+                    .notify(self.index, EventClass::Generic, "del", key);
+            }
+        }
+        removed
+    }
+
     /// Checks if a key exists (and is not expired).
     pub fn exists(&self, key: &Bytes) -> bool {
         let shard = self.get_shard(key);
-        let data = shard.data.read().unwrap();
+        let data = shard.data.read();
 
         data.get(key).map(|e| !e.is_expired()).unwrap_or(false)
     }
 
     /// Counts how many of the given keys exist.
     pub fn exists_many(&self, keys: &[Bytes]) -> u64 {
-        keys.iter().filter(|k| self.exists(k)).count() as u64
+        let mut count = 0;
+        let mut locked: Option<(usize, ReadGuard<'_, _>)> = None;
+        for (shard, i) in self.by_shard(keys) {
+            if locked.as_ref().map(|(index, _)| *index) != Some(shard) {
+                drop(locked.take());
+                locked = Some((shard, self.shards[shard].data.read()));
+            }
+            let (_, data) = locked.as_ref().expect("the key's shard is locked");
+            if live_entry(data, &keys[i]).is_some() {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Marks keys as accessed without reading them (TOUCH).
+    ///
+    /// # Returns
+    /// The number of keys that exist.
+    pub fn touch(&self, keys: &[Bytes]) -> u64 {
+        keys.iter()
+            .filter(|key| self.with_entry(key, Entry::record_access).is_some())
+            .count() as u64
     }
 
     /// Sets an expiry time on an existing key.
@@ -418,22 +1693,73 @@ impl StorageEngine {
     /// Returns `true` if the expiry was set, `false` if the key doesn't exist.
     pub fn expire(&self, key: &Bytes, ttl: Duration) -> bool {
         let shard = self.get_shard(key);
-        let mut data = shard.data.write().unwrap();
+        let mut data = shard.write();
 
         if let Some(entry) = data.get_mut(key) {
             if entry.is_expired() {
-                data.remove(key);
-                self.key_count.fetch_sub(1, Ordering::Relaxed);
-                self.expired_count.fetch_add(1, Ordering::Relaxed);
+                self.remove_expired(&mut data, key);
                 return false;
             }
-            entry.expires_at = Some(Instant::now() + ttl);
+            self.set_expiry(key, entry, Some(clock::deadline_after(ttl)));
             true
         } else {
             false
         }
     }
 
+    /// Sets the expiry of a key to a Unix time in milliseconds, subject to
+    /// `flags`.
+    ///
+    /// A deadline that has already passed deletes the key.
+    ///
+    /// # Returns
+    ///
+    /// Returns `true` if the expiry was set (or the key deleted), `false` if
+    /// the key doesn't exist or `flags` prevented the change.
+    pub fn expire_at(&self, key: &Bytes, unix_ms: i64, flags: ExpireFlags) -> bool {
+        let deadline = clock::from_unix_ms(unix_ms);
+
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        let Some(entry) = data.get_mut(key) else {
+            return false;
+        };
+        if entry.is_expired() {
+            self.remove_expired(&mut data, key);
+            return false;
+        }
+        if !flags.allows(entry.expires_at, deadline) {
+            return false;
+        }
+
+        if deadline <= Instant::now() {
+            let entry = self.remove_entry(&mut data, key).unwrap();
+            self.discard(entry.value);
+            self.del_count.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.set_expiry(key, entry, Some(deadline));
+        }
+        true
+    }
+
+    /// Gets the absolute expiry of a key as a Unix time in milliseconds.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(ms)` - Unix time at which the key expires
+    /// - `Some(-1)` - Key exists but has no expiry
+    /// - `None` - Key doesn't exist
+    pub fn expire_time(&self, key: &Bytes) -> Option<i64> {
+        self.with_entry(key, |entry| entry.expire_time_ms().unwrap_or(-1))
+    }
+
+    /// Returns the current Unix time in milliseconds, on the clock used for
+    /// expiry deadlines (see [`clock`]).
+    pub fn unix_time_ms(&self) -> i64 {
+        clock::unix_time_ms()
+    }
+
     /// Removes the expiry from a key (makes it persistent).
     ///
     /// # Returns
@@ -442,23 +1768,33 @@ impl StorageEngine {
     /// or didn't have an expiry.
     pub fn persist(&self, key: &Bytes) -> bool {
         let shard = self.get_shard(key);
-        let mut data = shard.data.write().unwrap();
+        let mut data = shard.write();
 
         if let Some(entry) = data.get_mut(key) {
             if entry.is_expired() {
-                data.remove(key);
-                self.key_count.fetch_sub(1, Ordering::Relaxed);
-                self.expired_count.fetch_add(1, Ordering::Relaxed);
+                self.remove_expired(&mut data, key);
                 return false;
             }
             if entry.expires_at.is_some() {
-                entry.expires_at = None;
+                self.set_expiry(key, entry, None);
                 return true;
             }
         }
         false
     }
 
+    /// Returns the remaining TTL of a live key, or `None` if it doesn't exist.
+    fn remaining_ttl(&self, key: &Bytes) -> Option<Option<Duration>> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        data.get(key).filter(|e| !e.is_expired()).map(|entry| {
+            entry
+                .expires_at
+                .map(|exp| exp.saturating_duration_since(Instant::now()))
+        })
+    }
+
     /// Gets the remaining TTL for a key in seconds.
     ///
     /// # Returns
@@ -467,1220 +1803,5533 @@ impl StorageEngine {
     /// - `Some(-1)` if the key exists but has no expiry
     /// - `None` if the key doesn't exist
     pub fn ttl(&self, key: &Bytes) -> Option<i64> {
-        self.get_entry(key).map(|entry| {
-            entry
-                .expires_at
-                .map(|exp| {
-                    let now = Instant::now();
-                    if now >= exp {
-                        0
-                    } else {
-                        (exp - now).as_secs() as i64
-                    }
-                })
-                .unwrap_or(-1)
-        })
+        self.remaining_ttl(key)
+            .map(|ttl| ttl.map(|d| d.as_secs() as i64).unwrap_or(-1))
     }
 
     /// Gets the remaining TTL for a key in milliseconds.
     pub fn pttl(&self, key: &Bytes) -> Option<i64> {
-        self.get_entry(key).map(|entry| {
-            entry
-                .expires_at
-                .map(|exp| {
-                    let now = Instant::now();
-                    if now >= exp {
-                        0
-                    } else {
-                        (exp - now).as_millis() as i64
-                    }
-                })
-                .unwrap_or(-1)
-        })
+        self.remaining_ttl(key)
+            .map(|ttl| ttl.map(|d| d.as_millis() as i64).unwrap_or(-1))
     }
 
-    /// Increments an integer value by 1.
+    /// Renames `key` to `newkey`, moving the value and its expiry whatever
+    /// the type. Any existing value at `newkey` is replaced unless `nx` is set.
     ///
-    /// If the key doesn't exist, it's set to 0 before the operation.
-    /// Returns an error if the value is not a valid integer.
-    pub fn incr(&self, key: &Bytes) -> Result<i64, &'static str> {
-        self.incr_by(key, 1)
-    }
+    /// Both shards are locked together, so the move is atomic.
+    ///
+    /// # Returns
+    /// - `Some(true)` if the key was renamed
+    /// - `Some(false)` if `nx` was set and `newkey` already exists
+    /// - `None` if `key` doesn't exist
+    pub fn rename(&self, key: &Bytes, newkey: Bytes, nx: bool) -> Option<bool> {
+        let mut guards = self.write_shards([key, &newkey].into_iter());
+
+        let src = guards.get_mut(&self.shard_index(key)).unwrap();
+        if src.get(key).is_some_and(|e| e.is_expired()) {
+            self.remove_expired(src, key);
+        }
+        if !src.contains_key(key) {
+            return None;
+        }
+        if key == &newkey {
+            return Some(!nx);
+        }
 
-    /// Increments an integer value by a specified amount.
-    pub fn incr_by(&self, key: &Bytes, delta: i64) -> Result<i64, &'static str> {
-        let shard = self.get_shard(key);
-        let mut data = shard.data.write().unwrap();
+        let dst = guards.get_mut(&self.shard_index(&newkey)).unwrap();
+        if dst.get(&newkey).is_some_and(|e| e.is_expired()) {
+            self.remove_expired(dst, &newkey);
+        }
+        if nx && dst.contains_key(&newkey) {
+            return Some(false);
+        }
 
-        let current = if let Some(entry) = data.get(key) {
-            if entry.is_expired() {
-                0
-            } else {
-                let s = std::str::from_utf8(&entry.value)
-                    .map_err(|_| "value is not an integer or out of range")?;
-                s.parse::<i64>()
-                    .map_err(|_| "value is not an integer or out of range")?
+        let src = guards.get_mut(&self.shard_index(key)).unwrap();
+        let entry = self.remove_entry(src, key).unwrap();
+
+        let dst = guards.get_mut(&self.shard_index(&newkey)).unwrap();
+        self.insert_entry(dst, newkey.clone(), entry);
+        drop(guards);
+
+        self.waiters.notify(&newkey);
+        Some(true)
+    }
+
+    /// Copies the value (and expiry) of `src` to `dst`, whatever the type.
+    ///
+    /// # Returns
+    /// `true` if the value was copied, `false` if `src` doesn't exist or
+    /// `dst` already exists and `replace` isn't set.
+    pub fn copy(&self, src: &Bytes, dst: Bytes, replace: bool) -> bool {
+        let mut guards = self.write_shards([src, &dst].into_iter());
+
+        let entry = match live_entry(&guards[&self.shard_index(src)], src) {
+            Some(entry) => {
+                let mut copy = Entry::shared(Arc::clone(&entry.value));
+                copy.expires_at = entry.expires_at;
+                copy
             }
-        } else {
-            0
+            None => return false,
         };
 
-        let new_value = current
-            .checked_add(delta)
-            .ok_or("increment would overflow")?;
-
-        let value_bytes = Bytes::from(new_value.to_string());
+        let data = guards.get_mut(&self.shard_index(&dst)).unwrap();
+        if !replace && live_entry(data, &dst).is_some() {
+            return false;
+        }
 
-        // Preserve TTL if the key existed
-        let expires_at = data
-            .get(key)
-            .and_then(|e| if e.is_expired() { None } else { e.expires_at });
+        self.set_count.fetch_add(1, Ordering::Relaxed);
+        self.insert_entry(data, dst.clone(), entry);
+        drop(guards);
 
-        let is_new = !data.contains_key(key);
-        let now = Instant::now();
-        data.insert(
-            key.clone(),
-            Entry {
-                value: value_bytes,
-                expires_at,
-                created_at: now,
-                last_accessed: now,
-            },
-        );
+        self.waiters.notify(&dst);
+        true
+    }
 
-        if is_new {
-            self.key_count.fetch_add(1, Ordering::Relaxed);
+    /// Copies the value (and expiry) of `src` to `dst` in database
+    /// `target` (COPY ... DB), like [`copy`](Self::copy).
+    ///
+    /// The source is read before the destination is locked, so the two
+    /// databases are never locked at once.
+    pub fn copy_to(&self, src: &Bytes, target: &StorageEngine, dst: Bytes, replace: bool) -> bool {
+        if target.index == self.index {
+            return self.copy(src, dst, replace);
         }
 
-        Ok(new_value)
-    }
+        let entry = self.with_entry(src, |entry| {
+            let mut copy = Entry::shared(Arc::clone(&entry.value));
+            copy.expires_at = entry.expires_at;
+            copy
+        });
+        let Some(entry) = entry else {
+            return false;
+        };
 
-    /// Decrements an integer value by 1.
-    pub fn decr(&self, key: &Bytes) -> Result<i64, &'static str> {
-        self.incr_by(key, -1)
-    }
+        let mut data = target.get_shard(&dst).write();
+        if !replace && live_entry(&data, &dst).is_some() {
+            return false;
+        }
+        target.set_count.fetch_add(1, Ordering::Relaxed);
+        target.insert_entry(&mut data, dst.clone(), entry);
+        drop(data);
 
-    /// Decrements an integer value by a specified amount.
-    pub fn decr_by(&self, key: &Bytes, delta: i64) -> Result<i64, &'static str> {
-        self.incr_by(key, -delta)
+        target.waiters.notify(&dst);
+        true
     }
 
-    /// Appends a value to an existing string.
+    /// Moves `key`, with its expiry, to database `target` (MOVE).
     ///
-    /// If the key doesn't exist, it's created with the given value.
+    /// Both shards are locked, the lower-numbered database's first, so two
+    /// MOVEs going opposite ways can't deadlock.
     ///
     /// # Returns
-    ///
-    /// Returns the length of the string after the append.
-    pub fn append(&self, key: &Bytes, value: &Bytes) -> usize {
-        let shard = self.get_shard(key);
-        let mut data = shard.data.write().unwrap();
+    /// `true` if the key was moved, `false` if it doesn't exist here, or
+    /// already exists in `target`.
+    pub fn move_to(&self, key: &Bytes, target: &StorageEngine) -> bool {
+        if target.index == self.index {
+            return false;
+        }
 
-        if let Some(entry) = data.get_mut(key) {
-            if entry.is_expired() {
-                // Treat as new key
-                let new_entry = Entry::new(value.clone());
-                let len = value.len();
-                data.insert(key.clone(), new_entry);
-                return len;
-            }
-
-            // Append to existing value
-            let mut new_value = Vec::with_capacity(entry.value.len() + value.len());
-            new_value.extend_from_slice(&entry.value);
-            new_value.extend_from_slice(value);
-            let len = new_value.len();
-            entry.value = Bytes::from(new_value);
-            entry.last_accessed = Instant::now();
-            len
+        let (mut src, mut dst) = if self.index < target.index {
+            let src = self.get_shard(key).write();
+            (src, target.get_shard(key).write())
         } else {
-            // Create new key
-            self.key_count.fetch_add(1, Ordering::Relaxed);
-            let len = value.len();
-            data.insert(key.clone(), Entry::new(value.clone()));
-            len
+            let dst = target.get_shard(key).write();
+            (self.get_shard(key).write(), dst)
+        };
+        if live_entry(&src, key).is_none() || live_entry(&dst, key).is_some() {
+            return false;
         }
-    }
 
-    /// Gets the length of a string value.
-    ///
-    /// Returns 0 if the key doesn't exist.
-    pub fn strlen(&self, key: &Bytes) -> usize {
-        self.get(key).map(|v| v.len()).unwrap_or(0)
+        let entry = self.remove_entry(&mut src, key).unwrap();
+        target.insert_entry(&mut dst, key.clone(), entry);
+        drop((src, dst));
+
+        target.waiters.notify(key);
+        true
     }
 
-    /// Returns all keys matching a pattern (simplified glob matching).
+    /// Sorts the elements of a list, set or sorted set (SORT).
     ///
-    /// Supported patterns:
-    /// - `*` matches everything
-    /// - `h*llo` matches hello, hallo, hxllo
-    /// - `h?llo` matches hello, hallo, but not hllo
-    /// - `h[ae]llo` matches hello and hallo, but not hillo
+    /// BY and GET patterns are resolved with one lookup per element, so
+    /// writes to the referenced keys may interleave with the sort. A missing
+    /// key sorts as empty.
     ///
-    /// **Warning**: This operation scans all keys and can be slow on large databases.
-    pub fn keys(&self, pattern: &str) -> Vec<Bytes> {
-        let mut result = Vec::new();
-        let pattern = GlobPattern::new(pattern);
-
-        for shard in &self.shards {
-            let data = shard.data.read().unwrap();
-            for (key, entry) in data.iter() {
-                if !entry.is_expired() {
-                    if let Ok(key_str) = std::str::from_utf8(key) {
-                        if pattern.matches(key_str) {
-                            result.push(key.clone());
-                        }
-                    }
+    /// # Returns
+    /// The elements in the LIMIT window, or for each of them one value per
+    /// GET pattern (`None` where the lookup found nothing).
+    pub fn sort(
+        &self,
+        key: &Bytes,
+        opts: &SortOptions,
+    ) -> Result<Vec<Option<Bytes>>, &'static str> {
+        let source = self.with_entry(key, |entry| match &*entry.value {
+            Value::List(list) => Ok(list.iter().cloned().collect()),
+            Value::Set(set) => Ok(set.iter().cloned().collect()),
+            Value::ZSet(zset) => Ok(zset.iter().map(|(member, _)| member.clone()).collect()),
+            _ => Err(WRONGTYPE),
+        });
+        let mut elements: Vec<Bytes> = source.transpose()?.unwrap_or_default();
+
+        if opts.sorts() {
+            let mut weighted = elements
+                .into_iter()
+                .map(|e| Ok((self.sort_weight(&e, opts)?, e)))
+                .collect::<Result<Vec<_>, &'static str>>()?;
+            weighted.sort_by(|(wa, a), (wb, b)| {
+                let ord = wa.compare(wb, a, b);
+                if opts.desc {
+                    ord.reverse()
+                } else {
+                    ord
                 }
-            }
+            });
+            elements = weighted.into_iter().map(|(_, e)| e).collect();
         }
 
-        result
+        let (start, end) = opts.window(elements.len());
+        let window = &elements[start..end];
+        if opts.get.is_empty() {
+            return Ok(window.iter().cloned().map(Some).collect());
+        }
+
+        Ok(window
+            .iter()
+            .flat_map(|e| opts.get.iter().map(move |p| self.sort_lookup(p, e)))
+            .collect())
     }
 
-    /// Clears all data from the database.
+    /// Stores a SORT result as a list at `dest` (SORT ... STORE), replacing
+    /// any previous value. Missing values are stored as empty strings and an
+    /// empty result deletes `dest`.
     ///
-    /// This is equivalent to the Redis FLUSHDB command.
-    pub fn flush(&self) {
-        for shard in &self.shards {
-            let mut data = shard.data.write().unwrap();
-            data.clear();
-            let mut lists = shard.lists.write().unwrap();
-            lists.clear();
+    /// # Returns
+    /// The length of the stored list.
+    pub fn sort_store(&self, dest: Bytes, values: Vec<Option<Bytes>>) -> usize {
+        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let list: List = values.into_iter().map(Option::unwrap_or_default).collect();
+        let len = list.len();
+
+        let shard = self.get_shard(&dest);
+        let mut data = shard.write();
+        self.store_result(&mut data, dest.clone(), list);
+        drop(data);
+
+        self.waiters.notify(&dest);
+        len
+    }
+
+    /// Computes the sort weight of `element`.
+    fn sort_weight(&self, element: &Bytes, opts: &SortOptions) -> Result<Weight, &'static str> {
+        let weight = match &opts.by {
+            Some(by) => self.sort_lookup(by, element),
+            None => Some(element.clone()),
+        };
+
+        if opts.alpha {
+            return Ok(Weight::Alpha(weight));
+        }
+        match weight {
+            None => Ok(Weight::Score(0.0)),
+            Some(w) => std::str::from_utf8(&w)
+                .ok()
+                .and_then(|s| s.parse::<f64>().ok())
+                .filter(|f| !f.is_nan())
+                .map(Weight::Score)
+                .ok_or(SORT_NOT_NUMERIC),
         }
-        self.key_count.store(0, Ordering::Relaxed);
     }
 
-    /// Returns the approximate number of keys in the database.
-    ///
-    /// This is an approximation because it uses relaxed atomic ordering.
-    pub fn len(&self) -> u64 {
-        self.key_count.load(Ordering::Relaxed)
+    /// Resolves a BY or GET pattern for `element`.
+    fn sort_lookup(&self, pattern: &[u8], element: &Bytes) -> Option<Bytes> {
+        match sort::resolve(pattern, element)? {
+            Lookup::Element => Some(element.clone()),
+            Lookup::Key(key) => self
+                .with_entry(&key, |e| e.value.as_string().map(StringValue::to_bytes))
+                .flatten(),
+            Lookup::Field(key, field) => self
+                .with_entry(&key, |e| match &*e.value {
+                    Value::Hash(hash) => hash.get(&field).cloned(),
+                    _ => None,
+                })
+                .flatten(),
+        }
     }
 
-    /// Returns true if the database is empty.
-    pub fn is_empty(&self) -> bool {
-        self.len() == 0
+    /// Increments an integer value by 1.
+    ///
+    /// If the key doesn't exist, it's set to 0 before the operation.
+    /// Returns an error if the value is not a valid integer.
+    pub fn incr(&self, key: &Bytes) -> Result<i64, &'static str> {
+        self.incr_by(key, 1)
     }
 
-    /// Returns database statistics.
-    pub fn stats(&self) -> StorageStats {
-        StorageStats {
-            keys: self.key_count.load(Ordering::Relaxed),
-            get_ops: self.get_count.load(Ordering::Relaxed),
-            set_ops: self.set_count.load(Ordering::Relaxed),
-            del_ops: self.del_count.load(Ordering::Relaxed),
-            expired: self.expired_count.load(Ordering::Relaxed),
+    /// Increments an integer value by a specified amount.
+    pub fn incr_by(&self, key: &Bytes, delta: i64) -> Result<i64, &'static str> {
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        match data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                let current = entry
+                    .value
+                    .as_string()
+                    .ok_or(WRONGTYPE)?
+                    .to_i64()
+                    .ok_or("value is not an integer or out of range")?;
+                let new_value = current
+                    .checked_add(delta)
+                    .ok_or("increment would overflow")?;
+
+                // Stored as an integer in place, keeping the TTL
+                self.replace_value(key, entry, StringValue::Int(new_value).into());
+                entry.touch();
+                Ok(new_value)
+            }
+            _ => {
+                let entry = Entry::new(StringValue::Int(delta));
+                self.insert_entry(&mut data, key.clone(), entry);
+                Ok(delta)
+            }
         }
     }
 
-    /// Cleans up expired keys from all shards.
-    ///
-    /// This is called by the background expiry sweeper.
-    ///
-    /// # Returns
+    /// Increments a float value by a specified amount.
     ///
-    /// Returns the number of keys that were cleaned up.
-    pub fn cleanup_expired(&self) -> u64 {
-        let mut cleaned = 0u64;
+    /// If the key doesn't exist, it's set to 0 before the operation. The
+    /// result is stored (and returned) without trailing zeros or exponent,
+    /// rounded like Redis does (see [`FLOAT_DIGITS`]), as HINCRBYFLOAT
+    /// does.
+    pub fn incr_by_float(&self, key: &Bytes, delta: f64) -> Result<Bytes, &'static str> {
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        let live = data.get(key).filter(|e| !e.is_expired());
+        let current = match live {
+            Some(entry) => {
+                let value = entry.value.as_string().ok_or(WRONGTYPE)?;
+                std::str::from_utf8(&value.as_bytes())
+                    .ok()
+                    .and_then(|s| s.parse::<f64>().ok())
+                    .filter(|f| f.is_finite())
+                    .ok_or("value is not a valid float")?
+            }
+            None => 0.0,
+        };
 
-        for shard in &self.shards {
-            let mut data = shard.data.write().unwrap();
-            let before = data.len();
+        let new_value = current + delta;
+        if !new_value.is_finite() {
+            return Err("increment would produce NaN or Infinity");
+        }
 
-            data.retain(|_, entry| !entry.is_expired());
+        let value_bytes = format_float(new_value);
 
-            let removed = (before - data.len()) as u64;
-            cleaned += removed;
-        }
+        // Preserve TTL if the key existed
+        let expires_at = live.and_then(|e| e.expires_at);
 
-        if cleaned > 0 {
-            self.key_count.fetch_sub(cleaned, Ordering::Relaxed);
-            self.expired_count.fetch_add(cleaned, Ordering::Relaxed);
+        let mut entry = Entry::new(value_bytes.clone());
+        entry.expires_at = expires_at;
+        self.insert_entry(&mut data, key.clone(), entry);
+
+        Ok(value_bytes)
+    }
+
+    /// Decrements an integer value by 1.
+    pub fn decr(&self, key: &Bytes) -> Result<i64, &'static str> {
+        self.incr_by(key, -1)
+    }
+
+    /// Decrements an integer value by a specified amount.
+    pub fn decr_by(&self, key: &Bytes, delta: i64) -> Result<i64, &'static str> {
+        self.incr_by(key, -delta)
+    }
+
+    /// Appends a value to an existing string.
+    ///
+    /// If the key doesn't exist, it's created with the given value.
+    ///
+    /// # Returns
+    ///
+    /// Returns the length of the string after the append, or an error if
+    /// the key holds another type.
+    pub fn append(&self, key: &Bytes, value: &Bytes) -> Result<usize, &'static str> {
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        match data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                let current = entry.value.as_string().ok_or(WRONGTYPE)?;
+
+                // Append to existing value
+                let mut new_value = Vec::with_capacity(current.len() + value.len());
+                new_value.extend_from_slice(&current.as_bytes());
+                new_value.extend_from_slice(value);
+                let len = new_value.len();
+                self.replace_value(key, entry, Value::from(Bytes::from(new_value)));
+                entry.touch();
+                Ok(len)
+            }
+            _ => {
+                // Missing or expired: create the key
+                self.insert_entry(&mut data, key.clone(), Entry::new(value.clone()));
+                Ok(value.len())
+            }
         }
+    }
 
-        cleaned
+    /// Gets the length of a string value.
+    ///
+    /// Returns 0 if the key doesn't exist.
+    pub fn strlen(&self, key: &Bytes) -> usize {
+        self.get(key).map(|v| v.len()).unwrap_or(0)
     }
 
     // ========================================================================
-    // LIST OPERATIONS
+    // PERSISTENCE
     // ========================================================================
 
-    /// Pushes one or more values to the left (head) of a list.
-    /// Creates the list if it doesn't exist.
-    ///
-    /// # Returns
-    /// The length of the list after the push operation.
-    pub fn lpush(&self, key: Bytes, values: Vec<Bytes>) -> usize {
-        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+    /// Returns the snapshot configuration and status.
+    pub fn snapshots(&self) -> &Snapshots {
+        &self.snapshots
+    }
 
-        let shard = self.get_shard(&key);
-        let mut lists = shard.lists.write().unwrap();
+    /// Returns the append-only file.
+    pub fn aof(&self) -> &Aof {
+        &self.aof
+    }
 
-        let entry = lists.entry(key).or_insert_with(ListEntry::new);
+    /// Returns the memory limit and eviction policy.
+    pub fn eviction(&self) -> &Eviction {
+        &self.eviction
+    }
 
-        // Check if expired, if so reset it
-        if entry.is_expired() {
-            *entry = ListEntry::new();
-        }
+    /// Returns the replication state.
+    pub fn replication(&self) -> &Replication {
+        &self.replication
+    }
 
-        // Push values to the front (left) - each value is pushed to head in order
-        // So LPUSH key a b c results in [c, b, a] (c pushed last, ends up at head)
-        for value in values.into_iter() {
-            entry.data.push_front(value);
-        }
+    /// Returns the cluster state.
+    pub fn cluster(&self) -> &Cluster {
+        &self.cluster
+    }
 
-        entry.data.len()
+    /// Returns the pub/sub registry.
+    pub fn pubsub(&self) -> &PubSub {
+        &self.pubsub
     }
 
-    /// Pushes one or more values to the right (tail) of a list.
-    /// Creates the list if it doesn't exist.
-    ///
-    /// # Returns
-    /// The length of the list after the push operation.
-    pub fn rpush(&self, key: Bytes, values: Vec<Bytes>) -> usize {
-        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+    /// Returns the function libraries.
+    pub fn functions(&self) -> &Functions {
+        &self.functions
+    }
 
-        let shard = self.get_shard(&key);
-        let mut lists = shard.lists.write().unwrap();
+    /// Takes a consistent, point-in-time copy of the keyspace.
+    ///
+    /// Equivalent to [`begin_snapshot`](Self::begin_snapshot) followed by
+    /// [`finish_snapshot`](Self::finish_snapshot).
+    pub fn snapshot(&self) -> Snapshot {
+        self.finish_snapshot(self.begin_snapshot())
+    }
 
-        let entry = lists.entry(key).or_insert_with(ListEntry::new);
+    /// Starts a point-in-time snapshot of the keyspace, of every database
+    /// (see [`databases`](Self::databases)).
+    ///
+    /// Every shard is write-locked at once (in ascending order, like
+    /// [`write_shards`](Self::write_shards)) only long enough to mark it as
+    /// wanted by the snapshot; nothing is copied yet. From then on, the
+    /// first write to a marked shard copies its keys and value pointers
+    /// before going ahead, so a writer never waits for more than a single
+    /// shard to be copied. Values aren't copied: writers that later modify
+    /// a value still shared with the snapshot copy it first.
+    pub fn begin_snapshot(&self) -> PendingSnapshot {
+        Self::begin_snapshot_of(&self.databases().collect::<Vec<_>>())
+    }
 
-        // Check if expired, if so reset it
-        if entry.is_expired() {
-            *entry = ListEntry::new();
+    /// Starts a snapshot of `dbs`, as [`begin_snapshot`](Self::begin_snapshot).
+    fn begin_snapshot_of(dbs: &[&StorageEngine]) -> PendingSnapshot {
+        let taken_at_ms = clock::unix_time_ms();
+        let functions = dbs
+            .first()
+            .map(|db| db.functions.codes())
+            .unwrap_or_default();
+        let guards: Vec<_> = dbs
+            .iter()
+            .flat_map(|db| &db.shards)
+            .map(|shard| shard.data.write())
+            .collect();
+
+        let dbs = dbs
+            .iter()
+            .map(|db| {
+                let slots = db
+                    .shards
+                    .iter()
+                    .map(|shard| {
+                        let slot = CaptureSlot::default();
+                        shard.captures.lock().push(Arc::clone(&slot));
+                        shard.capture_pending.store(true, Ordering::Release);
+                        slot
+                    })
+                    .collect();
+                (db.index, slots)
+            })
+            .collect();
+        drop(guards);
+
+        PendingSnapshot {
+            dbs,
+            functions,
+            taken_at_ms,
         }
+    }
 
-        // Push values to the back (right)
-        for value in values {
-            entry.data.push_back(value);
-        }
+    /// Copies the shards not yet written to since
+    /// [`begin_snapshot`](Self::begin_snapshot), one at a time, and returns
+    /// the complete snapshot.
+    pub fn finish_snapshot(&self, pending: PendingSnapshot) -> Snapshot {
+        let dbs = pending
+            .dbs
+            .into_iter()
+            .map(|(index, slots)| {
+                let db = self
+                    .db(index)
+                    .expect("snapshot started on this engine's databases");
+                let shards = db
+                    .shards
+                    .iter()
+                    .zip(slots)
+                    .map(|(shard, slot)| {
+                        if slot.lock().is_none() {
+                            let data = shard.data.read();
+                            if shard.capture_pending.load(Ordering::Acquire) {
+                                shard.capture(&data);
+                            }
+                        }
+                        let entries = slot.lock().take();
+                        entries.unwrap_or_default()
+                    })
+                    .collect();
+                (index, shards)
+            })
+            .collect();
+        Snapshot::new(dbs, pending.functions, pending.taken_at_ms)
+    }
 
-        entry.data.len()
+    /// Returns whether the server rejects writes from clients. The setting
+    /// is the server's, held by database 0.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
     }
 
-    /// Removes and returns the first element (head) of a list.
-    ///
-    /// # Returns
-    /// The removed element, or None if the list is empty or doesn't exist.
-    pub fn lpop(&self, key: &Bytes) -> Option<Bytes> {
-        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+    /// Makes the server reject writes from clients, or accept them again
+    /// (call on database 0). A replica still applies its master's writes.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::Relaxed);
+    }
 
-        let shard = self.get_shard(key);
-        let mut lists = shard.lists.write().unwrap();
+    /// Sizes the shards for `keys` keys in all, so a bulk load doesn't
+    /// rehash each shard over and over as it grows. Shards already large
+    /// enough are left alone.
+    pub fn reserve(&self, keys: usize) {
+        let per_shard = keys.div_ceil(self.shards.len());
+        for shard in &self.shards {
+            let mut data = shard.write();
+            let additional = per_shard.saturating_sub(data.len());
+            data.reserve(additional);
+        }
+    }
 
-        if let Some(entry) = lists.get_mut(key) {
-            if entry.is_expired() {
-                lists.remove(key);
-                return None;
-            }
-            let value = entry.data.pop_front();
+    /// Sizes the shards for `keys` keys in all, now and whenever the
+    /// database is flushed (`--expected-keys`). 0 lets them grow from
+    /// empty again after a flush.
+    pub fn set_expected_keys(&self, keys: usize) {
+        self.shard_capacity
+            .store(keys.div_ceil(self.shards.len()), Ordering::Relaxed);
+        self.reserve(keys);
+    }
 
-            // Remove the key if the list is now empty
-            if entry.data.is_empty() {
-                lists.remove(key);
-            }
+    /// Returns the number of keys the shards have room for without
+    /// growing.
+    pub fn capacity(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.data.read().capacity())
+            .sum()
+    }
 
-            value
-        } else {
-            None
+    /// Returns whether short keys are stored compactly (see [`arena`]).
+    ///
+    /// [`arena`]: crate::storage::arena
+    pub fn compact_keys(&self) -> bool {
+        self.compact_keys.load(Ordering::Relaxed)
+    }
+
+    /// Stores short keys compactly in every database, or not (call on
+    /// database 0, before any key is stored: keys are counted as compact
+    /// by their length).
+    pub fn set_compact_keys(&self, enabled: bool) {
+        for db in self.databases() {
+            db.compact_keys.store(enabled, Ordering::Relaxed);
         }
     }
 
-    /// Removes and returns the last element (tail) of a list.
+    /// Writes a snapshot to the configured file, in the foreground (SAVE).
     ///
     /// # Returns
-    /// The removed element, or None if the list is empty or doesn't exist.
-    pub fn rpop(&self, key: &Bytes) -> Option<Bytes> {
-        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+    ///
+    /// The number of keys written, or an error if the save failed or
+    /// another save is running.
+    pub fn save(&self) -> Result<usize, SnapshotError> {
+        self.snapshots.begin(false)?;
+        let result = snapshot::save(
+            &self.snapshot(),
+            &self.snapshots.path(),
+            self.snapshots.compression(),
+        );
+        self.snapshots.finish(result.is_ok());
+        result
+    }
 
-        let shard = self.get_shard(key);
-        let mut lists = shard.lists.write().unwrap();
+    /// Starts writing a snapshot on a background thread (BGSAVE).
+    ///
+    /// The snapshot is started with [`begin_snapshot`](Self::begin_snapshot)
+    /// before returning, so the file holds the data as of the call, and
+    /// the shards are copied on the background thread while writes carry
+    /// on.
+    pub fn bgsave(self: &Arc<Self>) -> Result<(), SnapshotError> {
+        self.snapshots.begin(true)?;
+
+        let pending = self.begin_snapshot();
+        let engine = Arc::clone(self);
+        let spawned = std::thread::Builder::new()
+            .name("flashkv-bgsave".into())
+            .spawn(move || {
+                let snapshot = engine.finish_snapshot(pending);
+                let path = engine.snapshots.path();
+                let result = snapshot::save(&snapshot, &path, engine.snapshots.compression());
+                match &result {
+                    Ok(keys) => info!("Background save of {} keys to {:?} done", keys, path),
+                    Err(e) => warn!("Background save to {:?} failed: {}", path, e),
+                }
+                engine.snapshots.finish(result.is_ok());
+            });
 
-        if let Some(entry) = lists.get_mut(key) {
-            if entry.is_expired() {
-                lists.remove(key);
-                return None;
-            }
-            let value = entry.data.pop_back();
+        spawned.map(|_| ()).map_err(|e| {
+            self.snapshots.finish(false);
+            SnapshotError::Io(e)
+        })
+    }
 
-            // Remove the key if the list is now empty
-            if entry.data.is_empty() {
-                lists.remove(key);
-            }
+    /// Compacts the append-only file in the foreground, replacing it with a
+    /// snapshot preamble.
+    pub fn rewrite_aof(&self) -> Result<(), SnapshotError> {
+        let pending = self.aof.begin_rewrite(self)?;
+        self.aof
+            .finish_rewrite(&self.finish_snapshot(pending), self.snapshots.compression())
+    }
 
-            value
-        } else {
-            None
-        }
+    /// Starts compacting the append-only file on a background thread
+    /// (BGREWRITEAOF). Writes keep being appended to the current file and
+    /// are carried over to the new one.
+    pub fn bgrewriteaof(self: &Arc<Self>) -> Result<(), SnapshotError> {
+        let pending = self.aof.begin_rewrite(self)?;
+
+        let engine = Arc::clone(self);
+        let spawned = std::thread::Builder::new()
+            .name("flashkv-aofrw".into())
+            .spawn(move || {
+                let snapshot = engine.finish_snapshot(pending);
+                match engine
+                    .aof
+                    .finish_rewrite(&snapshot, engine.snapshots.compression())
+                {
+                    Ok(()) => info!("Background append only file rewriting done"),
+                    Err(e) => warn!("Background append only file rewriting failed: {}", e),
+                }
+            });
+
+        spawned.map(|_| ()).map_err(|e| {
+            self.aof.abort_rewrite();
+            SnapshotError::Io(e)
+        })
     }
 
-    /// Returns the length of a list.
+    /// Loads the configured snapshot file, replacing keys of the same name.
     ///
     /// # Returns
-    /// The length of the list, or 0 if the list doesn't exist.
-    pub fn llen(&self, key: &Bytes) -> usize {
-        let shard = self.get_shard(key);
-        let lists = shard.lists.read().unwrap();
+    ///
+    /// The number of keys loaded.
+    pub fn load(&self) -> Result<usize, SnapshotError> {
+        let keys = snapshot::load(self, &self.snapshots.path())?;
+        self.snapshots.mark_saved();
+        Ok(keys)
+    }
 
-        if let Some(entry) = lists.get(key) {
-            if entry.is_expired() {
-                return 0;
+    /// Saves a snapshot in the foreground, loads it back into a fresh engine
+    /// and replaces the keyspace with what was loaded (DEBUG RELOAD).
+    ///
+    /// The keyspace is only replaced once the file has loaded successfully.
+    /// Writes made while the reload runs may be lost.
+    ///
+    /// # Returns
+    ///
+    /// The number of keys loaded.
+    pub fn debug_reload(&self) -> Result<usize, SnapshotError> {
+        self.save()?;
+
+        let fresh =
+            StorageEngine::with_locking(self.database_count(), self.shard_count(), self.locking);
+        let keys = snapshot::load(&fresh, &self.snapshots.path())?;
+
+        self.functions
+            .restore_codes(fresh.functions.codes(), RestorePolicy::Flush)?;
+        self.flush_all();
+        for db in fresh.databases() {
+            let target = self.db(db.index).expect("as many databases as loaded");
+            target.reserve(db.len() as usize);
+            for shard in &db.shards {
+                for (key, entry) in shard.write().drain() {
+                    target.restore(key, entry);
+                }
             }
-            entry.data.len()
-        } else {
-            0
         }
+        Ok(keys)
     }
 
-    /// Returns the element at the specified index in a list.
-    /// Negative indices count from the end (-1 is the last element).
+    /// Writes the keys of this database matching `pattern` (all keys if
+    /// `None`) to `path` as JSON lines or CSV (EXPORT). The path is taken
+    /// relative to the directory of the snapshot file, and may not leave
+    /// it: absolute paths and `..` are rejected, so a client can't
+    /// overwrite files elsewhere.
     ///
     /// # Returns
-    /// The element at the index, or None if index is out of range.
-    pub fn lindex(&self, key: &Bytes, index: i64) -> Option<Bytes> {
-        let shard = self.get_shard(key);
-        let lists = shard.lists.read().unwrap();
-
-        if let Some(entry) = lists.get(key) {
-            if entry.is_expired() {
-                return None;
-            }
-
-            let len = entry.data.len() as i64;
-            let actual_index = if index < 0 { len + index } else { index };
+    ///
+    /// The number of keys written.
+    pub fn export(
+        &self,
+        path: &Path,
+        format: ExportFormat,
+        pattern: Option<&[u8]>,
+    ) -> Result<usize, SnapshotError> {
+        let mut components = path.components().peekable();
+        if components.peek().is_none()
+            || !components.all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(SnapshotError::OutsideDir);
+        }
+        let path = match self.snapshots.path().parent() {
+            Some(dir) => dir.join(path),
+            None => path.to_path_buf(),
+        };
 
-            if actual_index < 0 || actual_index >= len {
-                return None;
-            }
+        let snapshot = self.finish_snapshot(Self::begin_snapshot_of(&[self]));
+        let mut writer = BufWriter::new(File::create(path)?);
+        Ok(export::write(&snapshot, format, pattern, &mut writer)?)
+    }
 
-            entry.data.get(actual_index as usize).cloned()
-        } else {
-            None
-        }
+    /// Inserts a loaded entry, replacing any existing key.
+    pub(crate) fn restore(&self, key: Bytes, entry: Entry) {
+        let shard = self.get_shard(&key);
+        let mut data = shard.write();
+        self.insert_entry(&mut data, key, entry);
     }
 
-    /// Returns a range of elements from a list.
-    /// Both start and stop are inclusive. Negative indices count from the end.
+    // ========================================================================
+    // BITMAP OPERATIONS
+    // ========================================================================
+
+    /// Sets or clears the bit at `offset` in a string value, creating the
+    /// key or growing the value with zero bytes as needed.
     ///
     /// # Returns
-    /// A vector of elements in the specified range.
-    pub fn lrange(&self, key: &Bytes, start: i64, stop: i64) -> Vec<Bytes> {
-        let shard = self.get_shard(key);
-        let lists = shard.lists.read().unwrap();
+    /// The previous value of the bit, or an error if the key holds another type.
+    pub fn setbit(&self, key: &Bytes, offset: u64, bit: bool) -> Result<u8, &'static str> {
+        self.set_count.fetch_add(1, Ordering::Relaxed);
 
-        if let Some(entry) = lists.get(key) {
-            if entry.is_expired() {
-                return Vec::new();
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        match data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                let mut value = entry
+                    .value
+                    .as_string()
+                    .ok_or(WRONGTYPE)?
+                    .as_bytes()
+                    .to_vec();
+                let old = bitmap::set_bit(&mut value, offset, bit);
+                self.replace_value(key, entry, Value::from(Bytes::from(value)));
+                entry.touch();
+                Ok(old)
             }
+            _ => {
+                let mut value = Vec::new();
+                bitmap::set_bit(&mut value, offset, bit);
+                self.insert_entry(&mut data, key.clone(), Entry::new(Bytes::from(value)));
+                Ok(0)
+            }
+        }
+    }
 
-            let len = entry.data.len() as i64;
+    /// Returns the bit at `offset` in a string value (0 if missing).
+    pub fn getbit(&self, key: &Bytes, offset: u64) -> u8 {
+        self.get(key)
+            .map(|value| bitmap::get_bit(&value, offset))
+            .unwrap_or(0)
+    }
 
-            // Convert negative indices
-            let mut actual_start = if start < 0 { len + start } else { start };
-            let mut actual_stop = if stop < 0 { len + stop } else { stop };
+    /// Counts the set bits in a string value, optionally within a range.
+    pub fn bitcount(&self, key: &Bytes, range: Option<BitRange>) -> usize {
+        self.get(key)
+            .map(|value| bitmap::count_bits(&value, range))
+            .unwrap_or(0)
+    }
 
-            // Clamp to valid range
-            if actual_start < 0 {
-                actual_start = 0;
-            }
-            if actual_stop >= len {
-                actual_stop = len - 1;
+    /// Returns the offset of the first bit set to `bit` in a string value.
+    ///
+    /// `end_given` says whether the caller specified an explicit range end,
+    /// which changes how a search for a clear bit in an all-ones value ends.
+    pub fn bitpos(&self, key: &Bytes, bit: bool, range: Option<BitRange>, end_given: bool) -> i64 {
+        match self.get(key) {
+            Some(value) => bitmap::bit_position(&value, bit, range, end_given),
+            // A missing key is an infinite run of zeros
+            None => {
+                if bit {
+                    -1
+                } else {
+                    0
+                }
             }
-
-            if actual_start > actual_stop || actual_start >= len {
-                return Vec::new();
-            }
-
-            entry
-                .data
-                .iter()
-                .skip(actual_start as usize)
-                .take((actual_stop - actual_start + 1) as usize)
-                .cloned()
-                .collect()
-        } else {
-            Vec::new()
         }
     }
 
-    /// Sets the element at the specified index in a list.
-    /// Negative indices count from the end.
+    // ========================================================================
+    // HYPERLOGLOG OPERATIONS
+    // ========================================================================
+
+    /// Adds elements to the HyperLogLog stored at `key`, creating it if
+    /// needed.
     ///
     /// # Returns
-    /// Ok(()) if successful, Err with message if index is out of range or list doesn't exist.
-    pub fn lset(&self, key: &Bytes, index: i64, value: Bytes) -> Result<(), String> {
-        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+    /// `true` if the key was created or its estimate may have changed, or an
+    /// error if the key holds a value that isn't a HyperLogLog.
+    pub fn pfadd(&self, key: &Bytes, elements: &[Bytes]) -> Result<bool, &'static str> {
+        self.set_count.fetch_add(1, Ordering::Relaxed);
 
         let shard = self.get_shard(key);
-        let mut lists = shard.lists.write().unwrap();
-
-        if let Some(entry) = lists.get_mut(key) {
-            if entry.is_expired() {
-                lists.remove(key);
-                return Err("ERR no such key".to_string());
+        let mut data = shard.write();
+
+        match data.get_mut(key) {
+            Some(entry) if !entry.is_expired() => {
+                let mut hll = entry
+                    .value
+                    .as_string()
+                    .and_then(|v| HyperLogLog::from_bytes(&v.as_bytes()))
+                    .ok_or(HLL_INVALID)?;
+                let mut changed = false;
+                for element in elements {
+                    changed |= hll.add(element);
+                }
+                if changed {
+                    self.replace_value(key, entry, Value::from(Bytes::from(hll.into_bytes())));
+                }
+                entry.touch();
+                Ok(changed)
             }
-
-            let len = entry.data.len() as i64;
-            let actual_index = if index < 0 { len + index } else { index };
-
-            if actual_index < 0 || actual_index >= len {
-                return Err("ERR index out of range".to_string());
+            _ => {
+                let mut hll = HyperLogLog::new();
+                for element in elements {
+                    hll.add(element);
+                }
+                let value = Bytes::from(hll.into_bytes());
+                self.insert_entry(&mut data, key.clone(), Entry::new(value));
+                Ok(true)
             }
-
-            entry.data[actual_index as usize] = value;
-            Ok(())
-        } else {
-            Err("ERR no such key".to_string())
         }
     }
 
-    /// Removes elements equal to the given value from a list.
-    ///
-    /// - count > 0: Remove `count` elements equal to value, from head to tail.
-    /// - count < 0: Remove `|count|` elements equal to value, from tail to head.
-    /// - count = 0: Remove all elements equal to value.
+    /// Returns the approximate number of distinct elements in the union of
+    /// the HyperLogLogs at `keys`. Missing keys count as empty.
     ///
-    /// # Returns
-    /// The number of removed elements.
-    pub fn lrem(&self, key: &Bytes, count: i64, value: &Bytes) -> usize {
-        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+    /// With a single key the computed cardinality is cached in the value.
+    pub fn pfcount(&self, keys: &[Bytes]) -> Result<u64, &'static str> {
+        self.get_count.fetch_add(1, Ordering::Relaxed);
 
-        let shard = self.get_shard(key);
-        let mut lists = shard.lists.write().unwrap();
+        if let [key] = keys {
+            let shard = self.get_shard(key);
+            let mut data = shard.write();
+            return match data.get_mut(key) {
+                Some(entry) if !entry.is_expired() => {
+                    let current = entry.value.as_string().ok_or(HLL_INVALID)?.as_bytes();
+                    let mut hll = HyperLogLog::from_bytes(&current).ok_or(HLL_INVALID)?;
+                    let count = hll.count();
+                    if hll.as_bytes() != current.as_ref() {
+                        self.replace_value(key, entry, Value::from(Bytes::from(hll.into_bytes())));
+                    }
+                    entry.touch();
+                    Ok(count)
+                }
+                _ => Ok(0),
+            };
+        }
 
-        if let Some(entry) = lists.get_mut(key) {
-            if entry.is_expired() {
-                lists.remove(key);
-                return 0;
+        let guards = self.read_shards(keys.iter());
+        let mut union = HyperLogLog::new();
+        for key in keys {
+            if let Some(entry) = live_entry(&guards[&self.shard_index(key)], key) {
+                union.merge(&hll_value(entry)?);
             }
+        }
+        Ok(union.count())
+    }
 
-            let mut removed = 0usize;
-            let max_remove = if count == 0 {
-                usize::MAX
-            } else {
-                count.unsigned_abs() as usize
-            };
+    /// Merges the HyperLogLogs at `keys` into `dest` (including any existing
+    /// value of `dest`), creating it if needed.
+    pub fn pfmerge(&self, dest: &Bytes, keys: &[Bytes]) -> Result<(), &'static str> {
+        self.set_count.fetch_add(1, Ordering::Relaxed);
 
-            if count >= 0 {
-                // Remove from head to tail
-                let mut i = 0;
-                while i < entry.data.len() && removed < max_remove {
-                    if &entry.data[i] == value {
-                        entry.data.remove(i);
-                        removed += 1;
-                    } else {
-                        i += 1;
-                    }
-                }
-            } else {
-                // Remove from tail to head
-                let mut i = entry.data.len();
-                while i > 0 && removed < max_remove {
-                    i -= 1;
-                    if &entry.data[i] == value {
-                        entry.data.remove(i);
-                        removed += 1;
-                    }
-                }
-            }
+        let mut guards = self.write_shards(keys.iter().chain(std::iter::once(dest)));
 
-            // Remove the key if the list is now empty
-            if entry.data.is_empty() {
-                lists.remove(key);
+        let mut merged = HyperLogLog::new();
+        for key in keys.iter().chain(std::iter::once(dest)) {
+            if let Some(entry) = live_entry(&guards[&self.shard_index(key)], key) {
+                merged.merge(&hll_value(entry)?);
             }
+        }
 
-            removed
-        } else {
-            0
+        let data = guards.get_mut(&self.shard_index(dest)).unwrap();
+        let value = Value::from(Bytes::from(merged.into_bytes()));
+        match data.get_mut(dest) {
+            Some(entry) if !entry.is_expired() => {
+                self.replace_value(dest, entry, value);
+                entry.touch();
+            }
+            _ => {
+                self.insert_entry(data, dest.clone(), Entry::new(value));
+            }
         }
+        Ok(())
     }
 
-    /// Checks if a key exists as a list.
-    pub fn list_exists(&self, key: &Bytes) -> bool {
-        let shard = self.get_shard(key);
-        let lists = shard.lists.read().unwrap();
-
-        if let Some(entry) = lists.get(key) {
-            !entry.is_expired()
-        } else {
-            false
-        }
+    /// Returns all keys matching a pattern (simplified glob matching).
+    ///
+    /// Supported patterns:
+    /// - `*` matches everything
+    /// - `h*llo` matches hello, hallo, hxllo
+    /// - `h?llo` matches hello, hallo, but not hllo
+    /// - `h[ae]llo` matches hello and hallo, but not hillo
+    ///
+    /// **Warning**: This operation scans all keys and can be slow on large databases.
+    pub fn keys(&self, pattern: &[u8]) -> Vec<Bytes> {
+        let mut result = Vec::new();
+        self.keys_with(pattern, |key| result.push(key.clone()));
+        result
     }
 
-    /// Returns the type of a key ("string", "list", or "none").
-    pub fn key_type(&self, key: &Bytes) -> &'static str {
-        // Check string storage first
-        let shard = self.get_shard(key);
+    /// Calls `visit` with every key matching a pattern, like
+    /// [`keys`](Self::keys) without collecting them, so a caller can
+    /// serialize millions of matches without holding a copy of each.
+    ///
+    /// `visit` runs with a shard's read lock held, so it must not call back
+    /// into the engine.
+    pub fn keys_with(&self, pattern: &[u8], mut visit: impl FnMut(&Bytes)) {
+        let pattern = GlobPattern::new(pattern);
 
-        {
-            let data = shard.data.read().unwrap();
-            if let Some(entry) = data.get(key) {
-                if !entry.is_expired() {
-                    return "string";
+        for shard in &self.shards {
+            let data = shard.data.read();
+            for (key, entry) in data.iter() {
+                if !entry.is_expired() && pattern.matches(key) {
+                    visit(key);
                 }
             }
         }
+    }
 
-        {
-            let lists = shard.lists.read().unwrap();
-            if let Some(entry) = lists.get(key) {
-                if !entry.is_expired() {
-                    return "list";
+    /// Incrementally iterates the keyspace (SCAN).
+    ///
+    /// Keys are visited in a fixed order: by shard, then by the key's hash
+    /// within the shard. The cursor is the position to resume from, so it
+    /// doesn't depend on the layout of the underlying HashMaps: a full scan
+    /// returns every key that existed for the whole scan, however many keys
+    /// were inserted or deleted (and however often the maps were rehashed)
+    /// in between. Keys added or removed during the scan may or may not be
+    /// returned. A key is never returned twice.
+    ///
+    /// `count` is the number of keys to examine (a hint, like in Redis);
+    /// `pattern` and `type_filter` are applied after, so a call may return
+    /// fewer keys, or none, before the scan is complete.
+    ///
+    /// Each shard keeps its keys sorted by position, so a call costs a
+    /// binary search plus the keys it examines. The order is rebuilt when a
+    /// scan enters a shard that gained keys since it was taken, which
+    /// makes that call also pay for sorting the shard.
+    ///
+    /// # Returns
+    /// The cursor for the next call (0 once the scan is complete) and the
+    /// keys found.
+    pub fn scan(
+        &self,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        type_filter: Option<&str>,
+    ) -> (u64, Vec<Bytes>) {
+        let mut result = Vec::new();
+        let next = self.scan_with(cursor, count, pattern, type_filter, |key| {
+            result.push(key.clone())
+        });
+        (next, result)
+    }
+
+    /// Calls `visit` with each key a [`scan`](Self::scan) call would
+    /// return, and returns the cursor for the next call.
+    ///
+    /// `visit` runs with a shard's read lock held, so it must not call back
+    /// into the engine.
+    pub fn scan_with(
+        &self,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        type_filter: Option<&str>,
+        mut visit: impl FnMut(&Bytes),
+    ) -> u64 {
+        let pattern = pattern.map(GlobPattern::new);
+        let wanted = |key: &Bytes, entry: &Entry| {
+            let type_ok = type_filter.is_none_or(|t| entry.value.type_name() == t);
+            let pattern_ok = pattern.as_ref().is_none_or(|p| p.matches(key));
+            type_ok && pattern_ok
+        };
+
+        // The cursor's high `shard_bits` bits hold the shard index, and the
+        // rest the position within the shard
+        let position_bits = 64 - self.shard_bits;
+        let cursor_at = |shard: usize| (shard as u64).checked_shl(position_bits).unwrap_or(0);
+
+        let count = count.max(1);
+        let mut shard = cursor.checked_shr(position_bits).unwrap_or(0) as usize;
+        let mut from = cursor & (u64::MAX >> self.shard_bits);
+        let mut examined = 0;
+
+        while shard < self.shards.len() {
+            if examined >= count {
+                // Resume at the start of this shard
+                return cursor_at(shard);
+            }
+
+            // A scan entering the shard needs every key added before it
+            let order = self.shards[shard].scan_order(self.shard_bits, from == 0);
+            let data = self.shards[shard].data.read();
+            let start = order.keys.partition_point(|(position, _)| *position < from);
+            let mut last = None;
+            for (position, key) in &order.keys[start..] {
+                // Keys sharing a position never straddle two calls
+                if examined >= count && last != Some(*position) {
+                    return cursor_at(shard) + position;
+                }
+                last = Some(*position);
+                examined += 1;
+                if let Some(entry) = data.get(key) {
+                    if !entry.is_expired() && wanted(key, entry) {
+                        visit(key);
+                    }
                 }
             }
+            shard += 1;
+            from = 0;
         }
 
-        "none"
+        0
     }
 
-    /// Returns memory usage information (approximate).
-    pub fn memory_info(&self) -> MemoryInfo {
-        let mut total_keys = 0usize;
-        let mut total_bytes = 0usize;
+    /// Clears all data from the database.
+    ///
+    /// This is equivalent to the Redis FLUSHDB command.
+    pub fn flush(&self) {
+        self.clear(false);
+    }
+
+    /// Clears all data from the database, freeing it in the background
+    /// (FLUSHDB ASYNC).
+    ///
+    /// Each shard swaps in an empty map and hands the old one to the
+    /// lazy-free thread, so clients only wait for the swap, not for every
+    /// value to be dropped.
+    pub fn flush_async(&self) {
+        self.clear(true);
+    }
 
+    /// Empties every shard, dropping the contents in place or, if `lazy`,
+    /// on the lazy-free thread.
+    fn clear(&self, lazy: bool) {
+        let capacity = self.shard_capacity.load(Ordering::Relaxed);
         for shard in &self.shards {
-            let data = shard.data.read().unwrap();
-            for (key, entry) in data.iter() {
-                if !entry.is_expired() {
-                    total_keys += 1;
-                    // Approximate memory usage: key + value + overhead
-                    total_bytes += key.len() + entry.value.len() + 64; // 64 bytes overhead estimate
-                }
+            let mut data = shard.write();
+            // A fresh map, rather than clearing the old one, so the table it
+            // grew to is released too
+            let old = std::mem::replace(
+                &mut *data,
+                KeyMap::with_capacity_and_hasher(capacity, RandomState::new()),
+            );
+            shard.used_memory.store(0, Ordering::Relaxed);
+            shard.counts.reset();
+            *shard.scan_order.lock() = None;
+            drop(data);
+            if lazy {
+                self.lazy_free.free_keyspace(old);
+            } else {
+                drop(old);
             }
         }
+        // Expiries of keys that are gone anyway need no DEL
+        self.expired_keys.lock().clear();
+        self.pubsub.invalidate_all();
+    }
 
-        MemoryInfo {
-            keys: total_keys,
-            used_memory: total_bytes,
+    /// Clears every database (see [`databases`](Self::databases)).
+    ///
+    /// This is equivalent to the Redis FLUSHALL command.
+    pub fn flush_all(&self) {
+        for db in self.databases() {
+            db.flush();
         }
     }
-}
 
-/// Database statistics.
-#[derive(Debug, Clone, Copy)]
-pub struct StorageStats {
-    /// Number of keys currently stored
-    pub keys: u64,
-    /// Total GET operations
-    pub get_ops: u64,
-    /// Total SET operations
-    pub set_ops: u64,
-    /// Total DEL operations
-    pub del_ops: u64,
-    /// Total expired keys cleaned up
-    pub expired: u64,
-}
+    /// Clears every database, freeing the data in the background
+    /// (FLUSHALL ASYNC, see [`flush_async`](Self::flush_async)).
+    pub fn flush_all_async(&self) {
+        for db in self.databases() {
+            db.flush_async();
+        }
+    }
 
-/// Memory usage information.
-#[derive(Debug, Clone, Copy)]
-pub struct MemoryInfo {
-    /// Number of keys
-    pub keys: usize,
-    /// Approximate memory used in bytes
-    pub used_memory: usize,
-}
+    /// Returns the number of keys in the database (DBSIZE), adding up the
+    /// shards' counters.
+    ///
+    /// Each shard's count is exact; writes to other shards made while they
+    /// are added up may or may not be included.
+    pub fn len(&self) -> u64 {
+        self.sum_counts(|counts| &counts.keys)
+    }
 
-/// Simple glob pattern matcher for the KEYS command.
-struct GlobPattern {
-    pattern: String,
-}
+    /// Returns true if the database is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
-impl GlobPattern {
-    fn new(pattern: &str) -> Self {
-        Self {
-            pattern: pattern.to_string(),
+    /// Returns the number of keys of each type, in the order of
+    /// [`Value::TYPE_NAMES`].
+    pub fn keys_by_type(&self) -> Vec<(&'static str, u64)> {
+        Value::TYPE_NAMES
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (*name, self.sum_counts(|counts| &counts.by_type[i])))
+            .collect()
+    }
+
+    /// Returns database statistics.
+    pub fn stats(&self) -> StorageStats {
+        StorageStats {
+            keys: self.len(),
+            expires: self.sum_counts(|counts| &counts.expires),
+            get_ops: self.get_count.load(Ordering::Relaxed),
+            set_ops: self.set_count.load(Ordering::Relaxed),
+            del_ops: self.del_count.load(Ordering::Relaxed),
+            expired: self.expired_count.load(Ordering::Relaxed),
+            keyspace_hits: self.keyspace_hits.load(Ordering::Relaxed),
+            keyspace_misses: self.keyspace_misses.load(Ordering::Relaxed),
         }
     }
 
-    fn matches(&self, text: &str) -> bool {
-        self.matches_recursive(self.pattern.as_bytes(), text.as_bytes())
+    /// Returns the statistics of every database added up (see
+    /// [`databases`](Self::databases)).
+    pub fn total_stats(&self) -> StorageStats {
+        self.databases()
+            .map(StorageEngine::stats)
+            .fold(StorageStats::default(), |total, stats| StorageStats {
+                keys: total.keys + stats.keys,
+                expires: total.expires + stats.expires,
+                get_ops: total.get_ops + stats.get_ops,
+                set_ops: total.set_ops + stats.set_ops,
+                del_ops: total.del_ops + stats.del_ops,
+                expired: total.expired + stats.expired,
+                keyspace_hits: total.keyspace_hits + stats.keyspace_hits,
+                keyspace_misses: total.keyspace_misses + stats.keyspace_misses,
+            })
     }
 
-    fn matches_recursive(&self, pattern: &[u8], text: &[u8]) -> bool {
-        if pattern.is_empty() {
-            return text.is_empty();
+    /// Cleans up expired keys from all shards.
+    ///
+    /// This is called by the background expiry sweeper.
+    ///
+    /// # Returns
+    ///
+    /// Returns the number of keys that were cleaned up.
+    pub fn cleanup_expired(&self) -> u64 {
+        if !self.expires_keys() {
+            return 0;
         }
+        let mut cleaned = 0u64;
 
-        match pattern[0] {
-            b'*' => {
-                // Try matching zero or more characters
-                for i in 0..=text.len() {
-                    if self.matches_recursive(&pattern[1..], &text[i..]) {
-                        return true;
-                    }
-                }
-                false
-            }
-            b'?' => {
-                // Match exactly one character
-                !text.is_empty() && self.matches_recursive(&pattern[1..], &text[1..])
-            }
-            b'[' => {
-                // Character class
-                if text.is_empty() {
-                    return false;
-                }
-
-                let mut i = 1;
-                let mut matched = false;
-                let negate = pattern.get(1) == Some(&b'^');
-                if negate {
-                    i += 1;
-                }
+        let notifies =
+            self.pubsub.notifies(EventClass::Expired) || !self.pubsub.tracking().is_empty();
+        let mut notify = Vec::new();
+        let mut garbage = Vec::new();
 
-                while i < pattern.len() && pattern[i] != b']' {
-                    if pattern[i] == text[0] {
-                        matched = true;
-                    }
-                    // Handle ranges like [a-z]
-                    if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
-                        if text[0] >= pattern[i] && text[0] <= pattern[i + 2] {
-                            matched = true;
-                        }
-                        i += 2;
-                    }
-                    i += 1;
-                }
+        for shard in &self.shards {
+            let mut data = shard.write();
+            let mut expired_keys = self.propagates_expiry().then(|| self.expired_keys.lock());
 
-                if negate {
-                    matched = !matched;
+            data.retain(|key, entry| {
+                if !entry.is_expired() {
+                    return true;
                 }
-
-                if i < pattern.len() {
-                    matched && self.matches_recursive(&pattern[i + 1..], &text[1..])
-                } else {
-                    false
+                self.count_removed(key, entry);
+                // Freed once the shard is unlocked
+                garbage.push(Arc::clone(&entry.value));
+                if let Some(expired_keys) = expired_keys.as_mut() {
+                    expired_keys.push(key.clone());
                 }
-            }
-            b'\\' => {
-                // Escape character
-                if pattern.len() > 1 && !text.is_empty() && pattern[1] == text[0] {
-                    self.matches_recursive(&pattern[2..], &text[1..])
-                } else {
-                    false
+                if notifies {
+                    notify.push(key.clone());
                 }
+                cleaned += 1;
+                false
+            });
+            drop(expired_keys);
+            drop(data);
+
+            for value in garbage.drain(..) {
+                self.discard(value);
             }
-            c => {
-                // Literal character
-                !text.is_empty()
-                    && c == text[0]
-                    && self.matches_recursive(&pattern[1..], &text[1..])
+
+            // Published outside the shard lock
+            for key in notify.drain(..) {
+                self.pubsub
+                    .notify(self.index, EventClass::Expired, "expired", &key);
+                self.pubsub.invalidate(&[&key], None);
             }
         }
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    #[test]
-    fn test_set_and_get() {
-        let engine = StorageEngine::new();
+        if cleaned > 0 {
+            self.expired_count.fetch_add(cleaned, Ordering::Relaxed);
+        }
 
-        engine.set(Bytes::from("key"), Bytes::from("value"));
-        assert_eq!(engine.get(&Bytes::from("key")), Some(Bytes::from("value")));
+        cleaned
     }
 
-    #[test]
-    fn test_get_nonexistent() {
-        let engine = StorageEngine::new();
-        assert_eq!(engine.get(&Bytes::from("nonexistent")), None);
+    /// Pairs the shard holding each of `keys` with the key's position,
+    /// sorted by shard, so a batch can lock each shard once, in the same
+    /// ascending order as every multi-key write.
+    fn by_shard(&self, keys: &[Bytes]) -> impl Iterator<Item = (usize, usize)> {
+        // Packed into one integer each, which sorts faster than pairs
+        let mut batch: Vec<u64> = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (self.shard_index(key) as u64) << 32 | i as u64)
+            .collect();
+        batch.sort_unstable();
+        batch
+            .into_iter()
+            .map(|packed| ((packed >> 32) as usize, packed as u32 as usize))
     }
 
-    #[test]
-    fn test_delete() {
-        let engine = StorageEngine::new();
-
-        engine.set(Bytes::from("key"), Bytes::from("value"));
-        assert!(engine.delete(&Bytes::from("key")));
-        assert_eq!(engine.get(&Bytes::from("key")), None);
-        assert!(!engine.delete(&Bytes::from("key"))); // Already deleted
+    /// Read-locks every shard touched by `keys`, in ascending shard order.
+    fn read_shards<'a>(
+        &self,
+        keys: impl Iterator<Item = &'a Bytes>,
+    ) -> BTreeMap<usize, ReadGuard<'_, KeyMap>> {
+        let indices: BTreeSet<usize> = keys.map(|k| self.shard_index(k)).collect();
+        indices
+            .into_iter()
+            .map(|i| (i, self.shards[i].data.read()))
+            .collect()
     }
 
-    #[test]
-    fn test_exists() {
-        let engine = StorageEngine::new();
+    /// Runs `f` with every shard touched by `keys` write-locked, so other
+    /// clients see either none or all of its changes.
+    ///
+    /// The shards are locked in ascending shard order, as by every built-in
+    /// multi-key write (RENAME, LMOVE, the STORE commands, ...), so
+    /// concurrent multi-key operations cannot deadlock. `f` may only touch
+    /// the keys given, and must not call back into the engine.
+    pub fn atomically<R>(&self, keys: &[Bytes], f: impl FnOnce(&mut KeyLocks<'_>) -> R) -> R {
+        let mut locks = KeyLocks {
+            engine: self,
+            guards: self.write_shards(keys.iter()),
+        };
+        f(&mut locks)
+    }
 
-        assert!(!engine.exists(&Bytes::from("key")));
-        engine.set(Bytes::from("key"), Bytes::from("value"));
-        assert!(engine.exists(&Bytes::from("key")));
+    /// Write-locks every shard touched by `keys`, in ascending shard order.
+    ///
+    /// Locking in a fixed order prevents deadlocks between concurrent
+    /// multi-key writers.
+    fn write_shards<'a>(
+        &self,
+        keys: impl Iterator<Item = &'a Bytes>,
+    ) -> BTreeMap<usize, WriteGuard<'_, KeyMap>> {
+        let indices: BTreeSet<usize> = keys.map(|k| self.shard_index(k)).collect();
+        indices
+            .into_iter()
+            .map(|i| (i, self.shards[i].write()))
+            .collect()
     }
 
-    #[test]
-    fn test_expiry() {
-        let engine = StorageEngine::new();
+    // ========================================================================
+    // LIST OPERATIONS
+    // ========================================================================
 
-        engine.set_with_ttl(
-            Bytes::from("key"),
-            Bytes::from("value"),
-            Duration::from_millis(50),
-        );
+    /// Pushes one or more values to the left (head) of a list.
+    /// Creates the list if it doesn't exist.
+    ///
+    /// # Returns
+    /// The length of the list after the push operation.
+    pub fn lpush(&self, key: Bytes, values: Vec<Bytes>) -> usize {
+        self.list_op_count.fetch_add(1, Ordering::Relaxed);
 
-        // Key should exist immediately
-        assert!(engine.exists(&Bytes::from("key")));
+        let shard = self.get_shard(&key);
+        let mut data = shard.write();
 
-        // Wait for expiry
-        std::thread::sleep(Duration::from_millis(100));
+        let mut list = match self.get_or_create::<List>(&mut data, &key) {
+            Some(list) => list,
+            None => return 0,
+        };
+
+        // Push values to the front (left) - each value is pushed to head in order
+        // So LPUSH key a b c results in [c, b, a] (c pushed last, ends up at head)
+        for value in values.into_iter() {
+            list.grow(value.len());
+            list.push_front(value);
+        }
+
+        let len = list.len();
+        drop(data);
+
+        self.waiters.notify(&key);
+        len
+    }
+
+    /// Pushes one or more values to the right (tail) of a list.
+    /// Creates the list if it doesn't exist.
+    ///
+    /// # Returns
+    /// The length of the list after the push operation.
+    pub fn rpush(&self, key: Bytes, values: Vec<Bytes>) -> usize {
+        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(&key);
+        let mut data = shard.write();
+
+        let mut list = match self.get_or_create::<List>(&mut data, &key) {
+            Some(list) => list,
+            None => return 0,
+        };
+
+        // Push values to the back (right)
+        for value in values {
+            list.grow(value.len());
+            list.push_back(value);
+        }
+
+        let len = list.len();
+        drop(data);
+
+        self.waiters.notify(&key);
+        len
+    }
+
+    /// Removes and returns the first element (head) of a list.
+    ///
+    /// # Returns
+    /// The removed element, or None if the list is empty or doesn't exist.
+    pub fn lpop(&self, key: &Bytes) -> Option<Bytes> {
+        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        let mut list = self.live_mut::<List>(&mut data, key)?;
+        let value = list.pop_front();
+        if let Some(value) = &value {
+            list.shrink(value.len());
+        }
+
+        // Remove the key if the list is now empty
+        self.remove_if_empty::<List>(&mut data, key);
+
+        value
+    }
+
+    /// Removes and returns the last element (tail) of a list.
+    ///
+    /// # Returns
+    /// The removed element, or None if the list is empty or doesn't exist.
+    pub fn rpop(&self, key: &Bytes) -> Option<Bytes> {
+        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        let mut list = self.live_mut::<List>(&mut data, key)?;
+        let value = list.pop_back();
+        if let Some(value) = &value {
+            list.shrink(value.len());
+        }
+
+        // Remove the key if the list is now empty
+        self.remove_if_empty::<List>(&mut data, key);
+
+        value
+    }
+
+    /// Returns the length of a list.
+    ///
+    /// # Returns
+    /// The length of the list, or 0 if the list doesn't exist.
+    pub fn llen(&self, key: &Bytes) -> usize {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        self.lookup::<List>(&data, key).map_or(0, |list| list.len())
+    }
+
+    /// Returns the element at the specified index in a list.
+    /// Negative indices count from the end (-1 is the last element).
+    ///
+    /// # Returns
+    /// The element at the index, or None if index is out of range.
+    pub fn lindex(&self, key: &Bytes, index: i64) -> Option<Bytes> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        let list = self.lookup::<List>(&data, key)?;
+
+        let len = list.len() as i64;
+        let actual_index = if index < 0 { len + index } else { index };
+
+        if actual_index < 0 || actual_index >= len {
+            return None;
+        }
+
+        list.get(actual_index as usize).cloned()
+    }
+
+    /// Returns a range of elements from a list.
+    /// Both start and stop are inclusive. Negative indices count from the end.
+    ///
+    /// # Returns
+    /// A vector of elements in the specified range.
+    pub fn lrange(&self, key: &Bytes, start: i64, stop: i64) -> Vec<Bytes> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        let list = match self.lookup::<List>(&data, key) {
+            Some(list) => list,
+            None => return Vec::new(),
+        };
+
+        let len = list.len() as i64;
+
+        // Convert negative indices
+        let mut actual_start = if start < 0 { len + start } else { start };
+        let mut actual_stop = if stop < 0 { len + stop } else { stop };
+
+        // Clamp to valid range
+        if actual_start < 0 {
+            actual_start = 0;
+        }
+        if actual_stop >= len {
+            actual_stop = len - 1;
+        }
+
+        if actual_start > actual_stop || actual_start >= len {
+            return Vec::new();
+        }
+
+        list.iter()
+            .skip(actual_start as usize)
+            .take((actual_stop - actual_start + 1) as usize)
+            .cloned()
+            .collect()
+    }
+
+    /// Sets the element at the specified index in a list.
+    /// Negative indices count from the end.
+    ///
+    /// # Returns
+    /// Ok(()) if successful, Err with message if index is out of range or list doesn't exist.
+    pub fn lset(&self, key: &Bytes, index: i64, value: Bytes) -> Result<(), String> {
+        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        let mut list = match self.live_mut::<List>(&mut data, key) {
+            Some(list) => list,
+            None => return Err("ERR no such key".to_string()),
+        };
+
+        let len = list.len() as i64;
+        let actual_index = if index < 0 { len + index } else { index };
+
+        if actual_index < 0 || actual_index >= len {
+            return Err("ERR index out of range".to_string());
+        }
+
+        list.grow(value.len());
+        let old = std::mem::replace(&mut list[actual_index as usize], value);
+        list.shrink(old.len());
+        Ok(())
+    }
+
+    /// Removes elements equal to the given value from a list.
+    ///
+    /// - count > 0: Remove `count` elements equal to value, from head to tail.
+    /// - count < 0: Remove `|count|` elements equal to value, from tail to head.
+    /// - count = 0: Remove all elements equal to value.
+    ///
+    /// # Returns
+    /// The number of removed elements.
+    pub fn lrem(&self, key: &Bytes, count: i64, value: &Bytes) -> usize {
+        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        let mut list = match self.live_mut::<List>(&mut data, key) {
+            Some(list) => list,
+            None => return 0,
+        };
+
+        let mut removed = 0usize;
+        let max_remove = if count == 0 {
+            usize::MAX
+        } else {
+            count.unsigned_abs() as usize
+        };
+
+        if count >= 0 {
+            // Remove from head to tail
+            let mut i = 0;
+            while i < list.len() && removed < max_remove {
+                if list[i] == value {
+                    list.remove(i);
+                    removed += 1;
+                } else {
+                    i += 1;
+                }
+            }
+        } else {
+            // Remove from tail to head
+            let mut i = list.len();
+            while i > 0 && removed < max_remove {
+                i -= 1;
+                if list[i] == value {
+                    list.remove(i);
+                    removed += 1;
+                }
+            }
+        }
+        list.shrink(value.len() * removed);
+
+        // Remove the key if the list is now empty
+        self.remove_if_empty::<List>(&mut data, key);
+
+        removed
+    }
+
+    /// Trims a list to the elements between `start` and `stop` (inclusive).
+    /// Negative indices count from the end. A range that selects nothing
+    /// deletes the key.
+    pub fn ltrim(&self, key: &Bytes, start: i64, stop: i64) {
+        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        let mut list = match self.live_mut::<List>(&mut data, key) {
+            Some(list) => list,
+            None => return,
+        };
+
+        let len = list.len() as i64;
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+
+        if start > stop || start >= len {
+            list.clear();
+        } else {
+            list.truncate(stop as usize + 1);
+            list.drain(..start as usize);
+        }
+        let size = list.iter().map(|v| v.len()).sum();
+        list.resize(size);
+
+        // Remove the key if the list is now empty
+        self.remove_if_empty::<List>(&mut data, key);
+    }
+
+    /// Atomically pops an element from one end of `src` and pushes it onto
+    /// one end of `dst` (LMOVE). Both shards are locked together, so the
+    /// element is never missing from both lists or present in both. `src`
+    /// and `dst` may be the same list, which rotates it.
+    ///
+    /// # Returns
+    /// The moved element, `None` if `src` doesn't exist, or an error if
+    /// either key holds another type.
+    pub fn lmove(
+        &self,
+        src: &Bytes,
+        dst: Bytes,
+        from: ListEnd,
+        to: ListEnd,
+    ) -> Result<Option<Bytes>, &'static str> {
+        self.list_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let mut guards = self.write_shards([src, &dst].into_iter());
+        let src_index = self.shard_index(src);
+        let dst_index = self.shard_index(&dst);
+
+        match live_entry(&guards[&src_index], src) {
+            Some(entry) if <List as Collection>::get(&entry.value).is_none() => {
+                return Err(WRONGTYPE)
+            }
+            Some(_) => {}
+            None => return Ok(None),
+        }
+        if live_entry(&guards[&dst_index], &dst)
+            .is_some_and(|e| <List as Collection>::get(&e.value).is_none())
+        {
+            return Err(WRONGTYPE);
+        }
+
+        let data = guards.get_mut(&src_index).unwrap();
+        let mut list = self.live_mut::<List>(data, src).unwrap();
+        let value = match from {
+            ListEnd::Left => list.pop_front(),
+            ListEnd::Right => list.pop_back(),
+        };
+
+        // Lists are never stored empty, so there was an element to pop
+        let value = value.unwrap();
+        list.shrink(value.len());
+        self.remove_if_empty::<List>(data, src);
+
+        let data = guards.get_mut(&dst_index).unwrap();
+        let mut list = self.get_or_create::<List>(data, &dst).unwrap();
+        list.grow(value.len());
+        match to {
+            ListEnd::Left => list.push_front(value.clone()),
+            ListEnd::Right => list.push_back(value.clone()),
+        }
+        drop(guards);
+
+        self.waiters.notify(&dst);
+        Ok(Some(value))
+    }
+
+    /// Checks if a key exists as a list.
+    pub fn list_exists(&self, key: &Bytes) -> bool {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        live::<List>(&data, key).is_some()
+    }
+
+    // ========================================================================
+    // HASH OPERATIONS
+    // ========================================================================
+
+    /// Sets one or more field-value pairs in a hash.
+    /// Creates the hash if it doesn't exist.
+    ///
+    /// # Returns
+    /// The number of fields that were newly added (not updated).
+    pub fn hset(&self, key: Bytes, pairs: Vec<(Bytes, Bytes)>) -> usize {
+        self.hash_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(&key);
+        let mut data = shard.write();
+
+        let mut hash = match self.get_or_create::<Hash>(&mut data, &key) {
+            Some(hash) => hash,
+            None => return 0,
+        };
+
+        let mut added = 0;
+        for (field, value) in pairs {
+            if hash.set_field(field, value).is_none() {
+                added += 1;
+            }
+        }
+
+        added
+    }
+
+    /// Sets a field in a hash only if the field does not yet exist.
+    ///
+    /// # Returns
+    /// `true` if the field was set, `false` if it already existed.
+    pub fn hsetnx(&self, key: Bytes, field: Bytes, value: Bytes) -> bool {
+        self.hash_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(&key);
+        let mut data = shard.write();
+
+        let mut hash = match self.get_or_create::<Hash>(&mut data, &key) {
+            Some(hash) => hash,
+            None => return false,
+        };
+
+        if hash.contains_key(&field) {
+            return false;
+        }
+
+        hash.set_field(field, value);
+        true
+    }
+
+    /// Returns the value of a field in a hash.
+    pub fn hget(&self, key: &Bytes, field: &Bytes) -> Option<Bytes> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        self.lookup::<Hash>(&data, key)
+            .and_then(|hash| hash.get(field).cloned())
+    }
+
+    /// Removes one or more fields from a hash.
+    ///
+    /// # Returns
+    /// The number of fields that were removed.
+    pub fn hdel(&self, key: &Bytes, fields: &[Bytes]) -> usize {
+        self.hash_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        let removed = match self.live_mut::<Hash>(&mut data, key) {
+            Some(mut hash) => fields
+                .iter()
+                .filter(|f| match hash.remove(*f) {
+                    Some(value) => {
+                        hash.shrink(f.len() + value.len());
+                        true
+                    }
+                    None => false,
+                })
+                .count(),
+            None => return 0,
+        };
+
+        // Remove the key if the hash is now empty
+        self.remove_if_empty::<Hash>(&mut data, key);
+
+        removed
+    }
+
+    /// Returns the number of fields in a hash.
+    pub fn hlen(&self, key: &Bytes) -> usize {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        self.lookup::<Hash>(&data, key).map_or(0, |hash| hash.len())
+    }
+
+    /// Checks if a field exists in a hash.
+    pub fn hexists(&self, key: &Bytes, field: &Bytes) -> bool {
+        self.hget(key, field).is_some()
+    }
+
+    /// Returns all field-value pairs of a hash.
+    pub fn hgetall(&self, key: &Bytes) -> Vec<(Bytes, Bytes)> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        match self.lookup::<Hash>(&data, key) {
+            Some(hash) => hash.iter().map(|(f, v)| (f.clone(), v.clone())).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Increments the integer value of a hash field by the given amount.
+    ///
+    /// If the hash or field doesn't exist, the field is set to 0 before the operation.
+    pub fn hincrby(&self, key: &Bytes, field: &Bytes, delta: i64) -> Result<i64, &'static str> {
+        self.hash_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        let mut hash = self
+            .get_or_create::<Hash>(&mut data, key)
+            .ok_or(WRONGTYPE)?;
+
+        let current = match hash.get(field) {
+            Some(v) => std::str::from_utf8(v)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .ok_or("hash value is not an integer")?,
+            None => 0,
+        };
+
+        let new_value = current
+            .checked_add(delta)
+            .ok_or("increment or decrement would overflow")?;
+
+        hash.set_field(field.clone(), Bytes::from(new_value.to_string()));
+
+        Ok(new_value)
+    }
+
+    /// Increments the float value of a hash field by the given amount.
+    ///
+    /// # Returns
+    /// The new value of the field, formatted the same way it is stored.
+    pub fn hincrbyfloat(
+        &self,
+        key: &Bytes,
+        field: &Bytes,
+        delta: f64,
+    ) -> Result<Bytes, &'static str> {
+        self.hash_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        let mut hash = self
+            .get_or_create::<Hash>(&mut data, key)
+            .ok_or(WRONGTYPE)?;
+
+        let current = match hash.get(field) {
+            Some(v) => std::str::from_utf8(v)
+                .ok()
+                .and_then(|s| s.parse::<f64>().ok())
+                .filter(|f| f.is_finite())
+                .ok_or("hash value is not a float")?,
+            None => 0.0,
+        };
+
+        let new_value = current + delta;
+        if !new_value.is_finite() {
+            return Err("increment would produce NaN or Infinity");
+        }
+
+        let value_bytes = format_float(new_value);
+        hash.set_field(field.clone(), value_bytes.clone());
+
+        Ok(value_bytes)
+    }
+
+    /// Returns random field-value pairs from a hash.
+    ///
+    /// - count >= 0: Up to `count` distinct fields.
+    /// - count < 0: Exactly `|count|` fields, possibly repeated.
+    pub fn hrandfield(&self, key: &Bytes, count: i64) -> Vec<(Bytes, Bytes)> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        let hash = match self.lookup::<Hash>(&data, key) {
+            Some(hash) => hash,
+            None => return Vec::new(),
+        };
+
+        let mut rng = rand::thread_rng();
+
+        if count >= 0 {
+            hash.iter()
+                .choose_multiple(&mut rng, count as usize)
+                .into_iter()
+                .map(|(f, v)| (f.clone(), v.clone()))
+                .collect()
+        } else {
+            let pairs: Vec<(&Bytes, &Bytes)> = hash.iter().collect();
+            (0..count.unsigned_abs())
+                .filter_map(|_| pairs.choose(&mut rng))
+                .map(|(f, v)| ((*f).clone(), (*v).clone()))
+                .collect()
+        }
+    }
+
+    /// Checks if a key exists as a hash.
+    pub fn hash_exists(&self, key: &Bytes) -> bool {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        live::<Hash>(&data, key).is_some()
+    }
+
+    // ========================================================================
+    // SET OPERATIONS
+    // ========================================================================
+
+    /// Adds one or more members to a set.
+    /// Creates the set if it doesn't exist.
+    ///
+    /// # Returns
+    /// The number of members that were added (not already present).
+    pub fn sadd(&self, key: Bytes, members: Vec<Bytes>) -> usize {
+        self.set_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(&key);
+        let mut data = shard.write();
+
+        let mut set = match self.get_or_create::<Set>(&mut data, &key) {
+            Some(set) => set,
+            None => return 0,
+        };
+
+        members
+            .into_iter()
+            .filter(|m| {
+                let added = set.insert(m.clone());
+                if added {
+                    set.grow(m.len());
+                }
+                added
+            })
+            .count()
+    }
+
+    /// Removes one or more members from a set.
+    ///
+    /// # Returns
+    /// The number of members that were removed.
+    pub fn srem(&self, key: &Bytes, members: &[Bytes]) -> usize {
+        self.set_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        let removed = match self.live_mut::<Set>(&mut data, key) {
+            Some(mut set) => members
+                .iter()
+                .filter(|m| {
+                    let removed = set.remove(*m);
+                    if removed {
+                        set.shrink(m.len());
+                    }
+                    removed
+                })
+                .count(),
+            None => return 0,
+        };
+
+        // Remove the key if the set is now empty
+        self.remove_if_empty::<Set>(&mut data, key);
+
+        removed
+    }
+
+    /// Returns all members of a set.
+    pub fn smembers(&self, key: &Bytes) -> Vec<Bytes> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        self.lookup::<Set>(&data, key)
+            .map_or_else(Vec::new, |set| set.iter().cloned().collect())
+    }
+
+    /// Checks if a value is a member of a set.
+    pub fn sismember(&self, key: &Bytes, member: &Bytes) -> bool {
+        self.smismember(key, std::slice::from_ref(member))[0]
+    }
+
+    /// Checks membership of multiple values in a set.
+    ///
+    /// # Returns
+    /// One boolean per requested member, in the same order.
+    pub fn smismember(&self, key: &Bytes, members: &[Bytes]) -> Vec<bool> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        match self.lookup::<Set>(&data, key) {
+            Some(set) => members.iter().map(|m| set.contains(m)).collect(),
+            None => vec![false; members.len()],
+        }
+    }
+
+    /// Returns the number of members in a set.
+    pub fn scard(&self, key: &Bytes) -> usize {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        self.lookup::<Set>(&data, key).map_or(0, |set| set.len())
+    }
+
+    /// Computes the intersection, union or difference of the given sets.
+    ///
+    /// All source shards are read-locked together (in ascending shard order)
+    /// so the result reflects a single consistent view of the inputs.
+    pub fn set_op(&self, op: SetOp, keys: &[Bytes]) -> Vec<Bytes> {
+        self.set_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let guards = self.read_shards(keys.iter());
+        let sources: Vec<Option<&Set>> = keys
+            .iter()
+            .map(|k| self.lookup::<Set>(&guards[&self.shard_index(k)], k))
+            .collect();
+
+        op.apply(&sources).into_iter().collect()
+    }
+
+    /// Computes a set operation and stores the result in `dest`.
+    ///
+    /// The destination may live on a different shard than the sources, so
+    /// every involved shard is write-locked up front in ascending shard order.
+    /// This keeps the read-compute-write sequence atomic and prevents
+    /// deadlocks between concurrent multi-key writers. Any existing value at
+    /// `dest` is replaced whatever its type; an empty result deletes `dest`.
+    ///
+    /// # Returns
+    /// The number of members in the resulting set.
+    pub fn set_op_store(&self, op: SetOp, dest: Bytes, keys: &[Bytes]) -> usize {
+        self.set_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let mut guards = self.write_shards(keys.iter().chain(std::iter::once(&dest)));
+
+        let result = {
+            let sources: Vec<Option<&Set>> = keys
+                .iter()
+                .map(|k| self.lookup::<Set>(&guards[&self.shard_index(k)], k))
+                .collect();
+            op.apply(&sources)
+        };
+
+        let len = result.len();
+        let data = guards.get_mut(&self.shard_index(&dest)).unwrap();
+        self.store_result(data, dest, result);
+
+        len
+    }
+
+    /// Returns the cardinality of the intersection of the given sets.
+    ///
+    /// A non-zero `limit` stops counting once that many members were found.
+    pub fn sintercard(&self, keys: &[Bytes], limit: usize) -> usize {
+        self.set_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let guards = self.read_shards(keys.iter());
+        let mut sources = Vec::with_capacity(keys.len());
+        for key in keys {
+            match self.lookup::<Set>(&guards[&self.shard_index(key)], key) {
+                Some(set) => sources.push(set),
+                None => return 0,
+            }
+        }
+
+        // Iterate the smallest set and probe the others
+        sources.sort_by_key(|s| s.len());
+        let (smallest, rest) = match sources.split_first() {
+            Some(split) => split,
+            None => return 0,
+        };
+
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        smallest
+            .iter()
+            .filter(|m| rest.iter().all(|s| s.contains(*m)))
+            .take(limit)
+            .count()
+    }
+
+    /// Stores the result of a STORE operation at `dest`, replacing any
+    /// previous value. An empty result deletes `dest` instead.
+    fn store_result<T: Collection>(&self, data: &mut KeyMap, dest: Bytes, result: T) {
+        if result.is_empty() {
+            if let Some(entry) = self.remove_entry(data, &dest) {
+                self.discard(entry.value);
+            }
+        } else {
+            self.insert_entry(data, dest, Entry::new(result.wrap()));
+        }
+    }
+
+    /// Checks if a key exists as a set.
+    pub fn set_exists(&self, key: &Bytes) -> bool {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        live::<Set>(&data, key).is_some()
+    }
+
+    // ========================================================================
+    // SORTED SET OPERATIONS
+    // ========================================================================
+
+    /// Adds members with scores to a sorted set, honoring ZADD flags.
+    /// Creates the sorted set if it doesn't exist.
+    ///
+    /// # Returns
+    /// Counts of added and updated members plus the final score of the last
+    /// member (used by the INCR flag), or an error if an increment hit NaN.
+    pub fn zadd(
+        &self,
+        key: Bytes,
+        flags: ZAddFlags,
+        pairs: Vec<(f64, Bytes)>,
+    ) -> Result<ZAddResult, &'static str> {
+        self.zset_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(&key);
+        let mut data = shard.write();
+
+        let mut zset = self
+            .get_or_create::<SortedSet>(&mut data, &key)
+            .ok_or(WRONGTYPE)?;
+
+        let mut result = ZAddResult::default();
+        let mut outcome = Ok(());
+        for (score, member) in pairs {
+            let (size, added) = (member.len() + 8, result.added);
+            match zset.add(member, score, flags, &mut result) {
+                Ok(score) => {
+                    if result.added > added {
+                        zset.grow(size);
+                    }
+                    result.score = score;
+                }
+                Err(e) => {
+                    outcome = Err(e);
+                    break;
+                }
+            }
+        }
+
+        // NX/XX may have prevented anything from being added
+        self.remove_if_empty::<SortedSet>(&mut data, &key);
+        drop(data);
+
+        if result.added > 0 {
+            self.waiters.notify(&key);
+        }
+
+        outcome.map(|_| result)
+    }
+
+    /// Increments the score of a member, creating it if needed.
+    ///
+    /// # Returns
+    /// The new score, or an error if the result is NaN.
+    pub fn zincrby(&self, key: Bytes, increment: f64, member: Bytes) -> Result<f64, &'static str> {
+        let flags = ZAddFlags {
+            incr: true,
+            ..Default::default()
+        };
+        let result = self.zadd(key, flags, vec![(increment, member)])?;
+        Ok(result.score.unwrap_or(increment))
+    }
+
+    /// Removes and returns up to `count` members with the lowest scores
+    /// (or the highest scores with `max`).
+    pub fn zpop(&self, key: &Bytes, count: usize, max: bool) -> Vec<(Bytes, f64)> {
+        self.zset_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        let popped: Vec<(Bytes, f64)> = match self.live_mut::<SortedSet>(&mut data, key) {
+            Some(mut zset) => std::iter::from_fn(|| {
+                let (member, score) = zset.pop(max)?;
+                zset.shrink(member.len() + 8);
+                Some((member, score))
+            })
+            .take(count)
+            .collect(),
+            None => return Vec::new(),
+        };
+
+        // Remove the key if the sorted set is now empty
+        self.remove_if_empty::<SortedSet>(&mut data, key);
+
+        popped
+    }
+
+    /// Returns random members with their scores from a sorted set.
+    ///
+    /// - count >= 0: Up to `count` distinct members.
+    /// - count < 0: Exactly `|count|` members, possibly repeated.
+    pub fn zrandmember(&self, key: &Bytes, count: i64) -> Vec<(Bytes, f64)> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        let zset = match self.lookup::<SortedSet>(&data, key) {
+            Some(zset) => zset,
+            None => return Vec::new(),
+        };
+
+        let mut rng = rand::thread_rng();
+
+        if count >= 0 {
+            zset.iter()
+                .choose_multiple(&mut rng, count as usize)
+                .into_iter()
+                .map(|(m, s)| (m.clone(), s))
+                .collect()
+        } else {
+            let pairs: Vec<(&Bytes, f64)> = zset.iter().collect();
+            (0..count.unsigned_abs())
+                .filter_map(|_| pairs.choose(&mut rng))
+                .map(|(m, s)| ((*m).clone(), *s))
+                .collect()
+        }
+    }
+
+    /// Returns the score of a member in a sorted set.
+    pub fn zscore(&self, key: &Bytes, member: &Bytes) -> Option<f64> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        self.lookup::<SortedSet>(&data, key)
+            .and_then(|zset| zset.score(member))
+    }
+
+    /// Removes one or more members from a sorted set.
+    ///
+    /// # Returns
+    /// The number of members that were removed.
+    pub fn zrem(&self, key: &Bytes, members: &[Bytes]) -> usize {
+        self.zset_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        let removed = match self.live_mut::<SortedSet>(&mut data, key) {
+            Some(mut zset) => members
+                .iter()
+                .filter(|m| {
+                    let removed = zset.remove(m);
+                    if removed {
+                        zset.shrink(m.len() + 8);
+                    }
+                    removed
+                })
+                .count(),
+            None => return 0,
+        };
+
+        // Remove the key if the sorted set is now empty
+        self.remove_if_empty::<SortedSet>(&mut data, key);
+
+        removed
+    }
+
+    /// Returns the number of members in a sorted set.
+    pub fn zcard(&self, key: &Bytes) -> usize {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        self.lookup::<SortedSet>(&data, key)
+            .map_or(0, |zset| zset.len())
+    }
+
+    /// Returns members between two rank indices (inclusive) with their scores.
+    /// Negative indices count from the end. With `rev`, index 0 is the highest score.
+    pub fn zrange(&self, key: &Bytes, start: i64, stop: i64, rev: bool) -> Vec<(Bytes, f64)> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        let zset = match self.lookup::<SortedSet>(&data, key) {
+            Some(zset) => zset,
+            None => return Vec::new(),
+        };
+
+        let len = zset.len() as i64;
+
+        // Convert negative indices and clamp to valid range
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            len + stop
+        } else {
+            stop.min(len - 1)
+        };
+
+        if start > stop || start >= len {
+            return Vec::new();
+        }
+
+        zset.range_by_rank(start as usize, stop as usize, rev)
+    }
+
+    /// Returns the rank of a member (ascending, or descending with `rev`).
+    pub fn zrank(&self, key: &Bytes, member: &Bytes, rev: bool) -> Option<usize> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        let zset = self.lookup::<SortedSet>(&data, key)?;
+        if rev {
+            zset.rev_rank(member)
+        } else {
+            zset.rank(member)
+        }
+    }
+
+    /// Computes the union, intersection or difference of sorted sets and
+    /// stores it at `dest`, replacing whatever was there.
+    ///
+    /// Each source score is multiplied by its weight (missing weights are 1)
+    /// and scores of members found in several sources are combined with
+    /// `aggregate`. For a difference, scores come from the first source.
+    ///
+    /// All involved shards are write-locked in ascending order for the whole
+    /// operation, so the result is atomic and concurrent calls cannot deadlock.
+    ///
+    /// # Returns
+    /// The number of members in the resulting sorted set.
+    pub fn zset_op_store(
+        &self,
+        op: SetOp,
+        dest: Bytes,
+        keys: &[Bytes],
+        weights: &[f64],
+        aggregate: Aggregate,
+    ) -> usize {
+        self.zset_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let mut guards = self.write_shards(keys.iter().chain(std::iter::once(&dest)));
+
+        let result = {
+            let sources: Vec<Option<&SortedSet>> = keys
+                .iter()
+                .map(|k| self.lookup::<SortedSet>(&guards[&self.shard_index(k)], k))
+                .collect();
+            op.apply_scored(&sources, weights, aggregate)
+        };
+
+        let len = result.len();
+        let data = guards.get_mut(&self.shard_index(&dest)).unwrap();
+        self.store_result(data, dest.clone(), result);
+        drop(guards);
+
+        if len > 0 {
+            self.waiters.notify(&dest);
+        }
+
+        len
+    }
+
+    // ========================================================================
+    // STREAM OPERATIONS
+    // ========================================================================
+
+    /// Appends an entry to a stream, creating the stream unless `nomkstream`
+    /// is set, and optionally trims it to `maxlen` entries afterwards.
+    ///
+    /// # Returns
+    /// The ID of the new entry, `None` if the stream didn't exist and
+    /// `nomkstream` was set, or an error if the ID is invalid.
+    pub fn xadd(
+        &self,
+        key: Bytes,
+        id: XAddId,
+        fields: Vec<(Bytes, Bytes)>,
+        maxlen: Option<usize>,
+        nomkstream: bool,
+    ) -> Result<Option<StreamId>, &'static str> {
+        self.stream_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(&key);
+        let mut data = shard.write();
+
+        if data.get(&key).is_some_and(|e| e.is_expired()) {
+            self.remove_expired(&mut data, &key);
+        }
+        let created = !data.contains_key(&key);
+        if nomkstream && created {
+            return Ok(None);
+        }
+
+        let mut stream = self
+            .get_or_create::<Stream>(&mut data, &key)
+            .ok_or(WRONGTYPE)?;
+        let id = match stream.add(id, fields) {
+            Ok(id) => id,
+            Err(e) => {
+                // Don't leave behind a stream we just created
+                if created {
+                    self.remove_entry(&mut data, &key);
+                }
+                return Err(e);
+            }
+        };
+
+        if let Some(maxlen) = maxlen {
+            stream.trim_maxlen(maxlen);
+        }
+        let size = stream.len() * STREAM_ENTRY_SIZE;
+        stream.resize(size);
+
+        Ok(Some(id))
+    }
+
+    /// Returns the number of entries in a stream.
+    pub fn xlen(&self, key: &Bytes) -> usize {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        self.lookup::<Stream>(&data, key)
+            .map_or(0, |stream| stream.len())
+    }
+
+    /// Returns stream entries with IDs between `start` and `end` (inclusive),
+    /// oldest first or newest first with `rev`.
+    pub fn xrange(
+        &self,
+        key: &Bytes,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Vec<StreamRecord> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        self.lookup::<Stream>(&data, key)
+            .map_or_else(Vec::new, |stream| stream.range(start, end, count, rev))
+    }
+
+    /// Reads entries newer than the given ID from each stream.
+    ///
+    /// # Returns
+    /// One `(key, entries)` pair per stream that had new entries.
+    pub fn xread(
+        &self,
+        streams: &[(Bytes, StreamId)],
+        count: Option<usize>,
+    ) -> Vec<(Bytes, Vec<StreamRecord>)> {
+        self.stream_op_count.fetch_add(1, Ordering::Relaxed);
+
+        streams
+            .iter()
+            .filter_map(|(key, after)| {
+                let shard = self.get_shard(key);
+                let data = shard.data.read();
+                let records = self.lookup::<Stream>(&data, key)?.read_after(*after, count);
+                (!records.is_empty()).then(|| (key.clone(), records))
+            })
+            .collect()
+    }
+
+    /// Returns the last ID generated for a stream (`0-0` if it doesn't exist).
+    pub fn stream_last_id(&self, key: &Bytes) -> StreamId {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        self.lookup::<Stream>(&data, key)
+            .map_or(StreamId::MIN, |stream| stream.last_id())
+    }
+
+    /// Runs `f` against the live stream stored at `key`, if any. `f` must
+    /// not add or remove entries, which would change the stream's size.
+    fn with_stream_mut<T>(&self, key: &Bytes, f: impl FnOnce(&mut Stream) -> T) -> Option<T> {
+        self.stream_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(key);
+        let mut data = shard.write();
+
+        self.live_mut::<Stream>(&mut data, key)
+            .map(|mut stream| f(&mut stream))
+    }
+
+    /// Creates a consumer group on a stream (XGROUP CREATE).
+    ///
+    /// `last_id = None` means `$` (only entries added from now on).
+    ///
+    /// # Returns
+    /// `Ok(false)` if the group already exists, or an error if the stream
+    /// doesn't exist and `mkstream` wasn't given.
+    pub fn xgroup_create(
+        &self,
+        key: Bytes,
+        group: Bytes,
+        last_id: Option<StreamId>,
+        mkstream: bool,
+    ) -> Result<bool, &'static str> {
+        self.stream_op_count.fetch_add(1, Ordering::Relaxed);
+
+        let shard = self.get_shard(&key);
+        let mut data = shard.write();
+
+        if data.get(&key).is_some_and(|e| e.is_expired()) {
+            self.remove_expired(&mut data, &key);
+        }
+        if !mkstream && !data.contains_key(&key) {
+            return Err("The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.");
+        }
+
+        let mut stream = self
+            .get_or_create::<Stream>(&mut data, &key)
+            .ok_or(WRONGTYPE)?;
+        Ok(stream.create_group(group, last_id))
+    }
+
+    /// Destroys a consumer group (XGROUP DESTROY).
+    pub fn xgroup_destroy(&self, key: &Bytes, group: &[u8]) -> bool {
+        self.with_stream_mut(key, |s| s.destroy_group(group))
+            .unwrap_or(false)
+    }
+
+    /// Creates a consumer in a group (XGROUP CREATECONSUMER).
+    ///
+    /// # Returns
+    /// `None` if the key or group doesn't exist, otherwise whether the
+    /// consumer was created.
+    pub fn xgroup_createconsumer(
+        &self,
+        key: &Bytes,
+        group: &[u8],
+        consumer: &Bytes,
+    ) -> Option<bool> {
+        self.with_stream_mut(key, |s| s.create_consumer(group, consumer))
+            .flatten()
+    }
+
+    /// Deletes a consumer from a group (XGROUP DELCONSUMER).
+    ///
+    /// # Returns
+    /// `None` if the key or group doesn't exist, otherwise the number of
+    /// pending entries the consumer had.
+    pub fn xgroup_delconsumer(&self, key: &Bytes, group: &[u8], consumer: &[u8]) -> Option<usize> {
+        self.with_stream_mut(key, |s| s.delete_consumer(group, consumer))
+            .flatten()
+    }
+
+    /// Reads from a stream on behalf of a group consumer (XREADGROUP).
+    ///
+    /// `after = None` means `>` (entries never delivered to the group).
+    ///
+    /// # Returns
+    /// `None` if the key or group doesn't exist.
+    pub fn xreadgroup(
+        &self,
+        key: &Bytes,
+        group: &[u8],
+        consumer: &Bytes,
+        after: Option<StreamId>,
+        count: Option<usize>,
+        noack: bool,
+    ) -> Option<Vec<(StreamId, Option<StreamFields>)>> {
+        self.with_stream_mut(key, |s| s.read_group(group, consumer, after, count, noack))
+            .flatten()
+    }
+
+    /// Acknowledges entries for a group (XACK).
+    ///
+    /// # Returns
+    /// The number of entries that were pending.
+    pub fn xack(&self, key: &Bytes, group: &[u8], ids: &[StreamId]) -> usize {
+        self.with_stream_mut(key, |s| s.ack(group, ids))
+            .unwrap_or(0)
+    }
+
+    /// Summarizes a group's pending entries (XPENDING key group).
+    pub fn xpending_summary(&self, key: &Bytes, group: &[u8]) -> Option<PendingSummary> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        self.lookup::<Stream>(&data, key)?.pending_summary(group)
+    }
+
+    /// Lists a group's pending entries (extended XPENDING form).
+    pub fn xpending_range(
+        &self,
+        key: &Bytes,
+        group: &[u8],
+        query: PendingQuery<'_>,
+    ) -> Option<Vec<PendingInfo>> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        self.lookup::<Stream>(&data, key)?
+            .pending_range(group, query)
+    }
+
+    /// Claims pending entries for another consumer (XCLAIM).
+    ///
+    /// # Returns
+    /// `None` if the key or group doesn't exist, otherwise the claimed entries.
+    pub fn xclaim(
+        &self,
+        key: &Bytes,
+        group: &[u8],
+        consumer: &Bytes,
+        min_idle: Duration,
+        ids: &[StreamId],
+        justid: bool,
+    ) -> Option<Vec<StreamRecord>> {
+        self.with_stream_mut(key, |s| s.claim(group, consumer, min_idle, ids, justid))
+            .flatten()
+    }
+
+    /// Returns the type of a key ("string", "list", "hash", "set", "zset", "stream", or "none").
+    pub fn key_type(&self, key: &Bytes) -> &'static str {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        live_entry(&data, key).map_or("none", |entry| entry.value.type_name())
+    }
+
+    /// Runs `f` against the live entry stored at `key`, if any.
+    fn with_entry<T>(&self, key: &Bytes, f: impl FnOnce(&Entry) -> T) -> Option<T> {
+        let shard = self.get_shard(key);
+        let data = shard.data.read();
+
+        live_entry(&data, key).map(f)
+    }
+
+    /// Returns the internal encoding of a key's value (OBJECT ENCODING).
+    pub fn object_encoding(&self, key: &Bytes) -> Option<&'static str> {
+        self.with_entry(key, |e| e.value.encoding())
+    }
+
+    /// Returns how long a key has gone without being accessed (OBJECT IDLETIME).
+    pub fn object_idletime(&self, key: &Bytes) -> Option<Duration> {
+        self.with_entry(key, Entry::idle_time)
+    }
+
+    /// Returns the access-frequency counter of a key (OBJECT FREQ).
+    pub fn object_freq(&self, key: &Bytes) -> Option<u8> {
+        self.with_entry(key, Entry::frequency)
+    }
+
+    /// Returns memory usage information (approximate), for every database
+    /// (see [`databases`](Self::databases)).
+    ///
+    /// Reads the counters every write keeps up to date, so it takes no lock
+    /// and costs the same whatever the size of the keyspace. Keys that have
+    /// expired but not been removed yet are still counted.
+    pub fn memory_info(&self) -> MemoryInfo {
+        let databases = || self.databases();
+        MemoryInfo {
+            keys: databases().map(|db| db.len() as usize).sum(),
+            used_memory: self.used_memory() as usize,
+            key_bytes: databases()
+                .map(|db| db.sum_counts(|counts| &counts.key_bytes) as usize)
+                .sum(),
+            compact_keys: databases()
+                .map(|db| db.sum_counts(|counts| &counts.compact_keys) as usize)
+                .sum(),
+        }
+    }
+
+    /// Returns the bytes `key` and its value are accounted for (MEMORY
+    /// USAGE), or `None` if the key doesn't exist.
+    pub fn memory_usage(&self, key: &Bytes) -> Option<usize> {
+        self.with_entry(key, |entry| Self::footprint(key, entry) as usize)
+    }
+
+    /// Evicts keys, as `maxmemory-policy` picks them, until the memory used
+    /// by every database is back within `maxmemory` (see [`eviction`]).
+    ///
+    /// # Returns
+    /// `false` if the memory used is still over the limit, because the
+    /// policy doesn't evict or nothing is left that it may evict.
+    pub fn evict(&self) -> bool {
+        let limit = self.eviction.maxmemory();
+        if limit == 0 {
+            return true;
+        }
+        let policy = self.eviction.policy();
+        let mut used = self.used_memory();
+        while used > limit {
+            if policy == EvictionPolicy::NoEviction {
+                return false;
+            }
+            match self.evict_one(policy) {
+                Some(freed) => used = used.saturating_sub(freed),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Returns the bytes accounted for by every database, the
+    /// `used_memory` of [`memory_info`](Self::memory_info) alone.
+    fn used_memory(&self) -> u64 {
+        self.databases()
+            .flat_map(|db| &db.shards)
+            .map(|shard| shard.used_memory.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Evicts the best candidate of the eviction pool, after sampling every
+    /// database for more.
+    ///
+    /// # Returns
+    /// The bytes freed, or `None` if there was no key to evict.
+    fn evict_one(&self, policy: EvictionPolicy) -> Option<u64> {
+        let volatile = policy.volatile_only();
+        let mut rng = rand::thread_rng();
+        let evictable = |db: &&StorageEngine| {
+            db.sum_counts(|counts| {
+                if volatile {
+                    &counts.expires
+                } else {
+                    &counts.keys
+                }
+            }) > 0
+        };
+
+        for db in self.databases().filter(evictable) {
+            db.sample_for_eviction(volatile, &mut rng);
+        }
+        while let Some(candidate) = self.eviction.take_best() {
+            // The key may have gone since it was sampled
+            let freed = self
+                .db(candidate.db)
+                .and_then(|db| db.evict_key(&candidate.key, volatile));
+            if freed.is_some() {
+                return freed;
+            }
+        }
+
+        // Sampling found nothing, in shards too sparse to pick keys from
+        // at random; walk them instead
+        self.databases().filter(evictable).find_map(|db| {
+            let key = db.find_evictable(volatile, &mut rng)?;
+            db.evict_key(&key, volatile)
+        })
+    }
+
+    /// Offers up to [`EVICTION_SAMPLES`] keys of this database, each picked
+    /// at random from a random shard, to the eviction pool. With
+    /// `volatile`, only keys with an expiry are offered.
+    fn sample_for_eviction(&self, volatile: bool, rng: &mut impl Rng) {
+        let mut offered = 0;
+        for _ in 0..EVICTION_SAMPLES * EVICTION_SAMPLE_TRIES {
+            if offered == EVICTION_SAMPLES {
+                break;
+            }
+            let shard = &self.shards[rng.gen_range(0..self.shards.len())];
+            let candidate = {
+                let data = shard.data.read();
+                random_entry(&data, rng)
+                    .filter(|(_, entry)| !volatile || entry.expires_at.is_some())
+                    .map(|(key, entry)| Candidate {
+                        db: self.index,
+                        key: key.clone(),
+                        rank: (entry.frequency(), Reverse(entry.idle_time())),
+                    })
+            };
+            if let Some(candidate) = candidate {
+                self.eviction.offer(candidate);
+                offered += 1;
+            }
+        }
+    }
+
+    /// Returns a key that may be evicted (one with an expiry if
+    /// `volatile`), walking the shards from a random one.
+    fn find_evictable(&self, volatile: bool, rng: &mut impl Rng) -> Option<Bytes> {
+        let start = rng.gen_range(0..self.shards.len());
+        (0..self.shards.len()).find_map(|i| {
+            let shard = &self.shards[(start + i) & (self.shards.len() - 1)];
+            let data = shard.data.read();
+            data.iter()
+                .find(|(_, entry)| !volatile || entry.expires_at.is_some())
+                .map(|(key, _)| key.clone())
+        })
+    }
+
+    /// Removes `key` to free memory, notifying and propagating its eviction
+    /// as a DEL like an expiry. With `volatile`, a key without an expiry is
+    /// left alone.
+    ///
+    /// # Returns
+    /// The bytes freed, or `None` if the key wasn't evicted.
+    fn evict_key(&self, key: &Bytes, volatile: bool) -> Option<u64> {
+        let mut data = self.get_shard(key).write();
+        if !data
+            .get(key)
+            .is_some_and(|entry| !volatile || entry.expires_at.is_some())
+        {
+            return None;
+        }
+        let entry = self.remove_entry(&mut data, key)?;
+        self.eviction.record_evicted();
+        self.pubsub
+            .notify(self.index, EventClass::Evicted, "evicted", key);
+        self.pubsub.invalidate(&[key], None);
+        if self.propagates_expiry() {
+            self.expired_keys.lock().push(key.clone());
+        }
+        let freed = Self::footprint(key, &entry);
+        self.discard(entry.value);
+        Some(freed)
+    }
+}
+
+/// Conditions under which the EXPIRE family changes a key's expiry.
+///
+/// A key without an expiry is treated as having an infinite TTL.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExpireFlags {
+    /// Only set an expiry when the key has none
+    pub nx: bool,
+    /// Only set an expiry when the key already has one
+    pub xx: bool,
+    /// Only set the expiry when it is later than the current one
+    pub gt: bool,
+    /// Only set the expiry when it is earlier than the current one
+    pub lt: bool,
+}
+
+impl ExpireFlags {
+    /// Returns whether `deadline` may replace the key's `current` expiry.
+    fn allows(self, current: Option<Instant>, deadline: Instant) -> bool {
+        match current {
+            None => !self.xx && !self.gt,
+            Some(current) => {
+                !self.nx && (!self.gt || deadline > current) && (!self.lt || deadline < current)
+            }
+        }
+    }
+}
+
+/// An end of a list, as named by LMOVE and friends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListEnd {
+    /// The head of the list
+    Left,
+    /// The tail of the list
+    Right,
+}
+
+/// Set algebra operations supported by SINTER, SUNION and SDIFF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    /// Members present in every set
+    Inter,
+    /// Members present in any set
+    Union,
+    /// Members of the first set that are not in any of the others
+    Diff,
+}
+
+impl SetOp {
+    /// Applies the operation to the given sources (`None` = missing key).
+    fn apply(self, sources: &[Option<&HashSet<Bytes>>]) -> HashSet<Bytes> {
+        match self {
+            SetOp::Inter => {
+                // A missing key is an empty set, so the intersection is empty
+                let mut present = Vec::with_capacity(sources.len());
+                for source in sources {
+                    match source {
+                        Some(set) => present.push(*set),
+                        None => return HashSet::new(),
+                    }
+                }
+                present.sort_by_key(|s| s.len());
+                match present.split_first() {
+                    Some((smallest, rest)) => smallest
+                        .iter()
+                        .filter(|m| rest.iter().all(|s| s.contains(*m)))
+                        .cloned()
+                        .collect(),
+                    None => HashSet::new(),
+                }
+            }
+            SetOp::Union => sources
+                .iter()
+                .flatten()
+                .flat_map(|s| s.iter())
+                .cloned()
+                .collect(),
+            SetOp::Diff => {
+                let mut result = match sources.first() {
+                    Some(Some(first)) => (*first).clone(),
+                    _ => return HashSet::new(),
+                };
+                for set in sources[1..].iter().flatten() {
+                    result.retain(|m| !set.contains(m));
+                }
+                result
+            }
+        }
+    }
+}
+
+impl SetOp {
+    /// Applies the operation to sorted sets (`None` = missing key),
+    /// weighting and aggregating scores as ZUNIONSTORE/ZINTERSTORE do.
+    fn apply_scored(
+        self,
+        sources: &[Option<&SortedSet>],
+        weights: &[f64],
+        aggregate: Aggregate,
+    ) -> SortedSet {
+        let weight = |i: usize| weights.get(i).copied().unwrap_or(1.0);
+        let mut scores: HashMap<Bytes, f64> = HashMap::new();
+
+        match self {
+            SetOp::Union => {
+                for (i, source) in sources.iter().enumerate() {
+                    for (member, score) in source.iter().flat_map(|z| z.iter()) {
+                        let score = weighted(score, weight(i));
+                        scores
+                            .entry(member.clone())
+                            .and_modify(|acc| *acc = aggregate.combine(*acc, score))
+                            .or_insert(score);
+                    }
+                }
+            }
+            SetOp::Inter => {
+                // A missing key is an empty set, so the intersection is empty
+                if let Some(Some(first)) = sources.first() {
+                    if sources.iter().all(Option::is_some) {
+                        'members: for (member, score) in first.iter() {
+                            let mut acc = weighted(score, weight(0));
+                            for (i, other) in sources.iter().enumerate().skip(1) {
+                                match other.and_then(|z| z.score(member)) {
+                                    Some(s) => acc = aggregate.combine(acc, weighted(s, weight(i))),
+                                    None => continue 'members,
+                                }
+                            }
+                            scores.insert(member.clone(), acc);
+                        }
+                    }
+                }
+            }
+            SetOp::Diff => {
+                if let Some(Some(first)) = sources.first() {
+                    for (member, score) in first.iter() {
+                        let elsewhere = sources[1..]
+                            .iter()
+                            .flatten()
+                            .any(|z| z.score(member).is_some());
+                        if !elsewhere {
+                            scores.insert(member.clone(), score);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut result = SortedSet::new();
+        for (member, score) in scores {
+            result.insert(member, score);
+        }
+        result
+    }
+}
+
+/// Hashes a key. The low bits of its hash tag's hash select its shard, and
+/// the other bits of its own hash are its SCAN position within the shard.
+///
+/// This is xxh3 rather than SipHash: picking a shard needs speed and an
+/// even spread, not resistance to flooding, which the maps inside the
+/// shards keep with their randomly seeded hashers.
+#[inline]
+fn key_hash(key: &[u8]) -> u64 {
+    xxh3_64(key)
+}
+
+/// Returns an entry picked at random from `data`, or `None` if it is empty
+/// or the pick gave up.
+///
+/// Like Redis picking a random bucket, this looks at a random slot of the
+/// map's table and walks to the next full one. That takes a step or two
+/// while the table is reasonably full, but the table doesn't shrink as keys
+/// are deleted, so the walk gives up after [`RANDOM_ENTRY_PROBES`] slots.
+fn random_entry<'a>(data: &'a KeyMap, rng: &mut impl Rng) -> Option<(&'a Bytes, &'a Entry)> {
+    let table = data.raw_table();
+    if table.is_empty() {
+        return None;
+    }
+    let buckets = table.buckets();
+    let start = rng.gen_range(0..buckets);
+    (0..buckets.min(RANDOM_ENTRY_PROBES))
+        .map(|i| (start + i) & (buckets - 1))
+        .find_map(|index| {
+            // SAFETY: `index` is below `buckets()`, a power of two, and a
+            // full bucket holds an element that lives as long as `data`'s
+            // borrow
+            unsafe {
+                table.is_bucket_full(index).then(|| {
+                    let (key, entry) = table.bucket(index).as_ref();
+                    (key, entry)
+                })
+            }
+        })
+}
+
+/// Formats the result of INCRBYFLOAT or HINCRBYFLOAT the way Redis does:
+/// in plain notation, rounded to [`FLOAT_DIGITS`] significant digits with
+/// trailing zeros trimmed, so `0.1 + 0.2` is stored as `0.3`.
+fn format_float(value: f64) -> Bytes {
+    // Rounding through exponent notation keeps the significant digits
+    // whatever the magnitude; the shortest form of the rounded value has
+    // no trailing zeros
+    let rounded: f64 = format!("{:.*e}", FLOAT_DIGITS - 1, value)
+        .parse()
+        .unwrap_or(value);
+    Bytes::from(rounded.to_string())
+}
+
+/// Returns the live (non-expired) entry stored at `key`, if any.
+fn live_entry<'a>(data: &'a KeyMap, key: &[u8]) -> Option<&'a Entry> {
+    data.get(key).filter(|entry| !entry.is_expired())
+}
+
+/// Returns the live collection of type `T` stored at `key`, if any,
+/// without counting a keyspace hit or miss (see
+/// [`StorageEngine::lookup`]).
+///
+/// Reading a collection counts as an access of the key.
+fn live<'a, T: Collection>(data: &'a KeyMap, key: &[u8]) -> Option<&'a T> {
+    let entry = live_entry(data, key)?;
+    entry.touch();
+    T::get(&entry.value)
+}
+
+/// Interprets an entry as a HyperLogLog.
+fn hll_value(entry: &Entry) -> Result<HyperLogLog, &'static str> {
+    entry
+        .value
+        .as_string()
+        .and_then(|v| HyperLogLog::from_bytes(&v.as_bytes()))
+        .ok_or(HLL_INVALID)
+}
+
+/// Database statistics.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StorageStats {
+    /// Number of keys currently stored
+    pub keys: u64,
+    /// Number of keys with an expiry
+    pub expires: u64,
+    /// Total GET operations
+    pub get_ops: u64,
+    /// Total SET operations
+    pub set_ops: u64,
+    /// Total DEL operations
+    pub del_ops: u64,
+    /// Total expired keys cleaned up
+    pub expired: u64,
+    /// Reads that found the key they looked up
+    pub keyspace_hits: u64,
+    /// Reads that didn't find the key they looked up
+    pub keyspace_misses: u64,
+}
+
+/// Memory usage information.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryInfo {
+    /// Number of keys
+    pub keys: usize,
+    /// Approximate memory used in bytes
+    pub used_memory: usize,
+    /// Total length of the keys
+    pub key_bytes: usize,
+    /// Number of keys stored in the key arenas
+    pub compact_keys: usize,
+}
+
+impl MemoryInfo {
+    /// Estimated bytes saved by storing keys compactly.
+    pub fn compact_keys_saved(&self) -> usize {
+        self.compact_keys * KEY_ALLOC_OVERHEAD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_get() {
+        let engine = StorageEngine::new();
+
+        engine.set(Bytes::from("key"), Bytes::from("value"));
+        assert_eq!(engine.get(&Bytes::from("key")), Some(Bytes::from("value")));
+    }
+
+    #[test]
+    fn test_get_nonexistent() {
+        let engine = StorageEngine::new();
+        assert_eq!(engine.get(&Bytes::from("nonexistent")), None);
+    }
+
+    #[test]
+    fn test_delete() {
+        let engine = StorageEngine::new();
+
+        engine.set(Bytes::from("key"), Bytes::from("value"));
+        assert!(engine.delete(&Bytes::from("key")));
+        assert_eq!(engine.get(&Bytes::from("key")), None);
+        assert!(!engine.delete(&Bytes::from("key"))); // Already deleted
+    }
+
+    #[test]
+    fn test_exists() {
+        let engine = StorageEngine::new();
+
+        assert!(!engine.exists(&Bytes::from("key")));
+        engine.set(Bytes::from("key"), Bytes::from("value"));
+        assert!(engine.exists(&Bytes::from("key")));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let engine = StorageEngine::new();
+
+        engine.set_with_ttl(
+            Bytes::from("key"),
+            Bytes::from("value"),
+            Duration::from_millis(50),
+        );
+
+        // Key should exist immediately
+        assert!(engine.exists(&Bytes::from("key")));
+
+        // Wait for expiry
+        std::thread::sleep(Duration::from_millis(100));
 
         // Key should be gone
         assert_eq!(engine.get(&Bytes::from("key")), None);
     }
 
     #[test]
-    fn test_incr() {
+    fn test_lazy_expiry_removes_once() {
+        let engine = Arc::new(StorageEngine::new());
+        let key = Bytes::from("key");
+        engine.set_with_ttl(key.clone(), Bytes::from("v"), Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(40));
+
+        // Many readers find the key expired; one of them removes it
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                let (engine, key) = (Arc::clone(&engine), key.clone());
+                std::thread::spawn(move || {
+                    assert_eq!(engine.get(&key), None);
+                    assert!(engine.get_entry(&key).is_none());
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(engine.stats().expired, 1);
+        assert!(engine.is_empty());
+
+        // A key written again after expiring reads back as written
+        engine.set_with_ttl(key.clone(), Bytes::from("v"), Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(40));
+        engine.set(key.clone(), Bytes::from("fresh"));
+        assert_eq!(engine.get(&key), Some(Bytes::from("fresh")));
+        assert_eq!(engine.stats().expired, 1);
+    }
+
+    #[test]
+    fn test_incr() {
+        let engine = StorageEngine::new();
+
+        // INCR on non-existent key
+        assert_eq!(engine.incr(&Bytes::from("counter")), Ok(1));
+        assert_eq!(engine.incr(&Bytes::from("counter")), Ok(2));
+
+        // INCR on existing numeric string
+        engine.set(Bytes::from("num"), Bytes::from("10"));
+        assert_eq!(engine.incr(&Bytes::from("num")), Ok(11));
+
+        // INCR on non-numeric string should fail
+        engine.set(Bytes::from("text"), Bytes::from("hello"));
+        assert!(engine.incr(&Bytes::from("text")).is_err());
+
+        // Results are stored as integers, keeping the TTL
+        let key = Bytes::from("padded");
+        engine.set_with_ttl(key.clone(), Bytes::from("007"), Duration::from_secs(100));
+        assert_eq!(engine.object_encoding(&key), Some("embstr"));
+        assert_eq!(engine.incr_by(&key, 3), Ok(10));
+        assert_eq!(engine.object_encoding(&key), Some("int"));
+        assert_eq!(engine.get(&key), Some(Bytes::from("10")));
+        assert!(engine.ttl(&key).unwrap() > 0);
+
+        engine.set(key.clone(), Bytes::from(i64::MAX.to_string()));
+        assert!(engine.incr(&key).is_err());
+        assert_eq!(engine.get(&key), Some(Bytes::from(i64::MAX.to_string())));
+    }
+
+    #[test]
+    fn test_incr_by_float() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("f");
+
+        assert_eq!(engine.incr_by_float(&key, 10.5), Ok(Bytes::from("10.5")));
+        assert_eq!(engine.incr_by_float(&key, 0.1), Ok(Bytes::from("10.6")));
+        assert_eq!(engine.incr_by_float(&key, -5.6), Ok(Bytes::from("5")));
+        assert_eq!(engine.get(&key), Some(Bytes::from("5")));
+
+        // Integers and exponent notation are valid floats
+        engine.set(key.clone(), Bytes::from("5.0e3"));
+        assert_eq!(engine.incr_by_float(&key, 200.0), Ok(Bytes::from("5200")));
+
+        engine.set(key.clone(), Bytes::from("abc"));
+        assert!(engine.incr_by_float(&key, 1.0).is_err());
+        engine.set(key.clone(), Bytes::from("1e308"));
+        assert!(engine.incr_by_float(&key, 1e308).is_err());
+        assert_eq!(engine.get(&key), Some(Bytes::from("1e308")));
+
+        // Binary rounding doesn't show, as in Redis
+        let key = Bytes::from("g");
+        assert_eq!(engine.incr_by_float(&key, 0.1), Ok(Bytes::from("0.1")));
+        assert_eq!(engine.incr_by_float(&key, 0.2), Ok(Bytes::from("0.3")));
+        assert_eq!(
+            engine.incr_by_float(&key, 1e20),
+            Ok(Bytes::from("100000000000000000000"))
+        );
+        assert_eq!(
+            engine.hincrbyfloat(&key, &Bytes::from("f"), 1.0),
+            Err(WRONGTYPE)
+        );
+        let (hash, field) = (Bytes::from("h"), Bytes::from("f"));
+        engine.hincrbyfloat(&hash, &field, 0.1).unwrap();
+        assert_eq!(
+            engine.hincrbyfloat(&hash, &field, 0.2),
+            Ok(Bytes::from("0.3"))
+        );
+        assert_eq!(format_float(1.0 / 3.0), Bytes::from("0.333333333333333"));
+        assert_eq!(format_float(-2.5e-7), Bytes::from("-0.00000025"));
+    }
+
+    #[test]
+    fn test_msetnx() {
+        let engine = StorageEngine::new();
+        let pair = |k: &str, v: &str| (Bytes::from(k.to_string()), Bytes::from(v.to_string()));
+
+        assert!(engine.msetnx(vec![pair("a", "1"), pair("b", "2")]));
+        assert_eq!(engine.get(&Bytes::from("b")), Some(Bytes::from("2")));
+
+        // One existing key (of any type) blocks the whole batch
+        assert!(!engine.msetnx(vec![pair("c", "3"), pair("a", "x")]));
+        assert!(!engine.exists(&Bytes::from("c")));
+        assert_eq!(engine.get(&Bytes::from("a")), Some(Bytes::from("1")));
+        engine.rpush(Bytes::from("list"), vec![Bytes::from("v")]);
+        assert!(!engine.msetnx(vec![pair("c", "3"), pair("list", "x")]));
+
+        // Expired keys don't count as existing
+        engine.set_with_ttl(
+            Bytes::from("old"),
+            Bytes::from("v"),
+            Duration::from_millis(5),
+        );
+        std::thread::sleep(Duration::from_millis(35));
+        assert!(engine.msetnx(vec![pair("old", "new"), pair("c", "3")]));
+        assert_eq!(engine.get(&Bytes::from("old")), Some(Bytes::from("new")));
+        assert_eq!(engine.len(), 5);
+    }
+
+    #[test]
+    fn test_mset_is_atomic() {
+        let engine = Arc::new(StorageEngine::new());
+        let keys: Vec<Bytes> = (0..8).map(|i| Bytes::from(format!("key:{}", i))).collect();
+        engine.mset(keys.iter().map(|k| (k.clone(), Bytes::from("0"))).collect());
+
+        let writer = {
+            let engine = Arc::clone(&engine);
+            let keys = keys.clone();
+            std::thread::spawn(move || {
+                for i in 1..=500 {
+                    let value = Bytes::from(i.to_string());
+                    engine.mset(keys.iter().map(|k| (k.clone(), value.clone())).collect());
+                }
+            })
+        };
+        // Readers never see a batch half applied
+        while !writer.is_finished() {
+            let values = engine.mget(&keys);
+            assert!(values.iter().all(|v| v == &values[0]), "{:?}", values);
+        }
+        writer.join().unwrap();
+        assert_eq!(engine.mget(&keys[..1]), vec![Some(Bytes::from("500"))]);
+    }
+
+    #[test]
+    fn test_batches_spanning_shards() {
+        let engine = StorageEngine::with_shards(1, 4);
+        let keys: Vec<Bytes> = (0..32).map(|i| Bytes::from(format!("key:{}", i))).collect();
+
+        // A repeated key keeps the last value given
+        let mut pairs: Vec<_> = keys.iter().map(|k| (k.clone(), k.clone())).collect();
+        pairs.push((keys[3].clone(), Bytes::from("last")));
+        engine.mset(pairs);
+        assert_eq!(engine.len(), 32);
+
+        // Values come back in the order the keys were given
+        let mut wanted = vec![keys[3].clone(), Bytes::from("missing")];
+        wanted.extend(keys.iter().rev().cloned());
+        let values = engine.mget(&wanted);
+        assert_eq!(values[0], Some(Bytes::from("last")));
+        assert_eq!(values[1], None);
+        for (value, key) in values[2..].iter().zip(keys.iter().rev()) {
+            if key != &keys[3] {
+                assert_eq!(value.as_ref(), Some(key));
+            }
+        }
+
+        assert_eq!(engine.exists_many(&wanted), 33);
+        assert_eq!(engine.delete_many(&wanted[..10]), 9);
+        assert_eq!(engine.exists_many(&keys), 23);
+        assert_eq!(engine.unlink(&keys), 23);
+        assert!(engine.is_empty());
+    }
+
+    #[test]
+    fn test_panic_while_locked_does_not_poison_shard() {
+        let engine = Arc::new(StorageEngine::new());
+        let key = Bytes::from("key");
+        engine.set(key.clone(), Bytes::from("value"));
+
+        let result = {
+            let engine = Arc::clone(&engine);
+            std::thread::spawn(move || {
+                let _data = engine.get_shard(b"key").write();
+                panic!("command failed while holding the shard lock");
+            })
+            .join()
+        };
+        assert!(result.is_err());
+
+        // The shard is still usable for reads and writes
+        assert_eq!(engine.get(&key), Some(Bytes::from("value")));
+        engine.set(key.clone(), Bytes::from("new"));
+        assert_eq!(engine.get(&key), Some(Bytes::from("new")));
+    }
+
+    #[test]
+    fn test_atomically() {
+        let engine = StorageEngine::new();
+        let (a, b) = (Bytes::from("a"), Bytes::from("{a}b"));
+        engine.set(a.clone(), Bytes::from("1"));
+        engine.rpush(Bytes::from("list"), vec![Bytes::from("v")]);
+
+        // Swap two keys in one step
+        let keys = [a.clone(), b.clone(), Bytes::from("list")];
+        let swapped = engine.atomically(&keys, |locks| {
+            let (va, vb) = (locks.get(&a), locks.get(&b));
+            locks.set(b.clone(), va.unwrap_or_default());
+            locks.set(a.clone(), vb.unwrap_or_default());
+            (locks.exists(b"list"), locks.get(b"list"))
+        });
+        assert_eq!(swapped, (true, None));
+        assert_eq!(engine.get(&a), Some(Bytes::new()));
+        assert_eq!(engine.get(&b), Some(Bytes::from("1")));
+        assert_eq!(engine.len(), 3);
+    }
+
+    #[test]
+    fn test_append() {
+        let engine = StorageEngine::new();
+
+        // Append to non-existent key
+        assert_eq!(
+            engine.append(&Bytes::from("key"), &Bytes::from("Hello")),
+            Ok(5)
+        );
+
+        // Append to existing key
+        assert_eq!(
+            engine.append(&Bytes::from("key"), &Bytes::from(" World")),
+            Ok(11)
+        );
+        assert_eq!(
+            engine.get(&Bytes::from("key")),
+            Some(Bytes::from("Hello World"))
+        );
+    }
+
+    #[test]
+    fn test_ttl() {
+        let engine = StorageEngine::new();
+
+        // No TTL on non-existent key
+        assert_eq!(engine.ttl(&Bytes::from("nonexistent")), None);
+
+        // No TTL on persistent key
+        engine.set(Bytes::from("persistent"), Bytes::from("value"));
+        assert_eq!(engine.ttl(&Bytes::from("persistent")), Some(-1));
+
+        // TTL on expiring key
+        engine.set_with_ttl(
+            Bytes::from("expiring"),
+            Bytes::from("value"),
+            Duration::from_secs(100),
+        );
+        let ttl = engine.ttl(&Bytes::from("expiring"));
+        assert!(ttl.is_some());
+        assert!(ttl.unwrap() > 0 && ttl.unwrap() <= 100);
+    }
+
+    #[test]
+    fn test_expire() {
+        let engine = StorageEngine::new();
+
+        engine.set(Bytes::from("key"), Bytes::from("value"));
+
+        // Set expiry
+        assert!(engine.expire(&Bytes::from("key"), Duration::from_secs(60)));
+
+        // Check TTL
+        let ttl = engine.ttl(&Bytes::from("key"));
+        assert!(ttl.is_some() && ttl.unwrap() > 0);
+
+        // Persist (remove expiry)
+        assert!(engine.persist(&Bytes::from("key")));
+        assert_eq!(engine.ttl(&Bytes::from("key")), Some(-1));
+    }
+
+    #[test]
+    fn test_expire_at_and_expire_time() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("key");
+        engine.set(key.clone(), Bytes::from("value"));
+        assert_eq!(engine.expire_time(&key), Some(-1));
+        assert_eq!(engine.expire_time(&Bytes::from("missing")), None);
+
+        // Absolute deadlines read back exactly
+        let now_ms = engine.unix_time_ms();
+        assert!(engine.expire_at(&key, now_ms + 60_000, ExpireFlags::default()));
+        assert_eq!(engine.expire_time(&key), Some(now_ms + 60_000));
+        let ttl = engine.pttl(&key).unwrap();
+        assert!(ttl > 50_000 && ttl <= 60_000);
+
+        // Relative expiries map onto the same clock
+        engine.expire(&key, Duration::from_secs(10));
+        let at = engine.expire_time(&key).unwrap();
+        assert!((at - (now_ms + 10_000)).abs() < 1_000);
+
+        // A deadline in the past deletes the key
+        assert!(engine.expire_at(&key, now_ms - 1, ExpireFlags::default()));
+        assert!(!engine.exists(&key));
+        assert!(!engine.expire_at(&key, now_ms + 60_000, ExpireFlags::default()));
+        assert!(engine.is_empty());
+    }
+
+    #[test]
+    fn test_entry_expire_time_round_trip() {
+        let deadline = clock::unix_time_ms() + 30_000;
+        let entry = Entry::with_expire_time_ms(Bytes::from("v"), deadline);
+        assert_eq!(entry.expire_time_ms(), Some(deadline));
+        let ttl = entry.ttl_ms().unwrap();
+        assert!(ttl > 29_000 && ttl <= 30_000);
+
+        // Rebuilding an entry from its wall-clock expiry keeps the deadline
+        let copy = Entry::with_expire_time_ms((*entry.value).clone(), deadline);
+        assert_eq!(copy.expires_at, entry.expires_at);
+
+        assert!(Entry::with_expire_time_ms(Bytes::from("v"), 1).is_expired());
+        assert!(Entry::with_expire_time_ms(Bytes::from("v"), clock::unix_time_ms()).is_expired());
+        // TTLs past what an Instant can hold are clamped
+        let entry = Entry::with_ttl(Bytes::from("v"), Duration::MAX);
+        assert!(!entry.is_expired());
+        assert!(Entry::with_expire_time_ms(Bytes::from("v"), i64::MAX).expire_time_ms() > Some(0));
+        assert_eq!(Entry::new(Bytes::from("v")).expire_time_ms(), None);
+    }
+
+    #[test]
+    fn test_snapshot_is_point_in_time() {
+        let engine = StorageEngine::new();
+        let list = Bytes::from("list");
+        engine.rpush(list.clone(), vec![Bytes::from("a"), Bytes::from("b")]);
+        engine.set(Bytes::from("str"), Bytes::from("v1"));
+        engine.set_with_ttl(
+            Bytes::from("gone"),
+            Bytes::from("x"),
+            Duration::from_millis(1),
+        );
+        std::thread::sleep(Duration::from_millis(30));
+
+        let snapshot = engine.snapshot();
+        assert_eq!(snapshot.len(), 2);
+
+        // Writes after the snapshot copy shared values instead of changing them
+        engine.rpush(list.clone(), vec![Bytes::from("c")]);
+        engine
+            .append(&Bytes::from("str"), &Bytes::from("!"))
+            .unwrap();
+        engine.delete(&Bytes::from("str"));
+        engine.set(Bytes::from("new"), Bytes::from("v"));
+        assert_eq!(engine.lrange(&list, 0, -1).len(), 3);
+
+        let mut entries: Vec<_> = snapshot.into_iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, list);
+        match &*entries[0].1.value {
+            Value::List(items) => assert_eq!(items.len(), 2),
+            other => panic!("unexpected value: {:?}", other),
+        }
+        assert_eq!(
+            entries[1].1.value.as_string().map(StringValue::to_bytes),
+            Some(Bytes::from("v1"))
+        );
+
+        // Once no snapshot shares a value, it is modified in place
+        let value_ptr = || {
+            let snapshot = engine.snapshot();
+            let (_, entry) = snapshot.iter().find(|(key, _)| **key == list).unwrap();
+            Arc::as_ptr(&entry.value)
+        };
+        let before = value_ptr();
+        engine.rpush(list.clone(), vec![Bytes::from("d")]);
+        assert_eq!(value_ptr(), before);
+    }
+
+    #[test]
+    fn test_pending_snapshot_is_captured_by_writes() {
+        let engine = StorageEngine::new();
+        for i in 0..200 {
+            engine.set(Bytes::from(format!("key:{}", i)), Bytes::from("old"));
+        }
+
+        let first = engine.begin_snapshot();
+        // Written before the second snapshot starts, after the first
+        for i in 0..100 {
+            engine.set(Bytes::from(format!("key:{}", i)), Bytes::from("new"));
+        }
+        let second = engine.begin_snapshot();
+        engine.set(Bytes::from("key:150"), Bytes::from("newer"));
+        assert!(engine.msetnx(vec![
+            (Bytes::from("added"), Bytes::from("v")),
+            (Bytes::from("added:2"), Bytes::from("v")),
+        ]));
+        engine.delete(&Bytes::from("key:199"));
+
+        let values = |snapshot: Snapshot| -> HashMap<Bytes, Bytes> {
+            snapshot
+                .into_iter()
+                .map(|(key, entry)| (key, entry.value.as_string().unwrap().to_bytes()))
+                .collect()
+        };
+        let first = values(engine.finish_snapshot(first));
+        assert_eq!(first.len(), 200);
+        assert!(first.values().all(|value| value == "old"));
+
+        let second = values(engine.finish_snapshot(second));
+        assert_eq!(second.len(), 200);
+        assert_eq!(second[&Bytes::from("key:0")], "new");
+        assert_eq!(second[&Bytes::from("key:150")], "old");
+        assert!(second.contains_key(&Bytes::from("key:199")));
+        assert!(!second.contains_key(&Bytes::from("added")));
+
+        assert_eq!(engine.len(), 201);
+        assert_eq!(
+            engine.get(&Bytes::from("key:150")),
+            Some(Bytes::from("newer"))
+        );
+    }
+
+    #[test]
+    fn test_keyspace_accounting() {
+        let engine = StorageEngine::new();
+        let count = |engine: &StorageEngine, name: &str| {
+            engine
+                .keys_by_type()
+                .into_iter()
+                .find(|(n, _)| *n == name)
+                .unwrap()
+                .1
+        };
+
+        engine.set(Bytes::from("s1"), Bytes::from("v"));
+        engine.set_with_ttl(
+            Bytes::from("s2"),
+            Bytes::from("v"),
+            Duration::from_secs(100),
+        );
+        engine.rpush(Bytes::from("l"), vec![Bytes::from("a")]);
+        engine.sadd(Bytes::from("set"), vec![Bytes::from("m")]);
+        assert_eq!(engine.len(), 4);
+        assert_eq!(engine.stats().expires, 1);
+        assert_eq!(count(&engine, "string"), 2);
+        assert_eq!(count(&engine, "list"), 1);
+        assert_eq!(count(&engine, "set"), 1);
+
+        // Overwriting with another type moves the key between counts
+        engine.set(Bytes::from("l"), Bytes::from("v"));
+        assert_eq!(count(&engine, "list"), 0);
+        assert_eq!(count(&engine, "string"), 3);
+
+        // Expiry changes in place, through SET and on rename
+        engine.expire(&Bytes::from("s1"), Duration::from_secs(100));
+        assert_eq!(engine.stats().expires, 2);
+        engine.persist(&Bytes::from("s2"));
+        assert_eq!(engine.stats().expires, 1);
+        engine.set(Bytes::from("s1"), Bytes::from("w"));
+        assert_eq!(engine.stats().expires, 0);
+        engine.expire(&Bytes::from("set"), Duration::from_secs(100));
+        engine.rename(&Bytes::from("set"), Bytes::from("set2"), false);
+        assert_eq!(engine.stats().expires, 1);
+        assert_eq!(count(&engine, "set"), 1);
+
+        // Emptying a collection, deleting and expiring all remove the key
+        engine.srem(&Bytes::from("set2"), &[Bytes::from("m")]);
+        assert_eq!(count(&engine, "set"), 0);
+        assert_eq!(engine.stats().expires, 0);
+        engine.delete(&Bytes::from("s2"));
+        engine.set_with_ttl(Bytes::from("t"), Bytes::from("v"), Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(30));
+        engine.cleanup_expired();
+        assert_eq!(engine.len(), 2);
+        assert_eq!(count(&engine, "string"), 2);
+        assert_eq!(engine.stats().expires, 0);
+
+        engine.flush();
+        assert!(engine.keys_by_type().iter().all(|(_, n)| *n == 0));
+    }
+
+    /// Recomputes the memory used by every database the slow way, by
+    /// walking each entry.
+    fn scanned_memory(engine: &StorageEngine) -> usize {
+        engine
+            .databases()
+            .flat_map(|db| &db.shards)
+            .flat_map(|shard| {
+                let data = shard.data.read();
+                data.iter()
+                    .map(|(key, entry)| key.len() + ENTRY_OVERHEAD + entry.value.approximate_size())
+                    .collect::<Vec<_>>()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_memory_accounting() {
+        let engine = StorageEngine::with_databases(2);
+        let b = |s: &str| Bytes::from(s.to_string());
+        let check = |what: &str| {
+            assert_eq!(
+                engine.memory_info().used_memory,
+                scanned_memory(&engine),
+                "after {}",
+                what
+            );
+        };
+
+        engine.set(b("s"), b("value"));
+        engine.set(b("s"), b("longer value"));
+        engine.append(&b("s"), &b("++")).unwrap();
+        engine.incr_by(&b("n"), 12345).unwrap();
+        engine.setbit(&b("bits"), 100, true).unwrap();
+        engine.pfadd(&b("hll"), &[b("a"), b("b")]).unwrap();
+        engine.pfmerge(&b("hll2"), &[b("hll")]).unwrap();
+        check("strings");
+
+        engine.rpush(b("l"), vec![b("a"), b("bb"), b("ccc"), b("bb")]);
+        engine.lpush(b("l"), vec![b("dddd")]);
+        engine.lpop(&b("l"));
+        engine.lset(&b("l"), 0, b("aaaaa")).unwrap();
+        engine.lrem(&b("l"), 0, &b("bb"));
+        engine.rpush(b("l"), vec![b("x"), b("yy"), b("zzz")]);
+        engine.ltrim(&b("l"), 1, -2);
+        engine
+            .lmove(&b("l"), b("l2"), ListEnd::Left, ListEnd::Right)
+            .unwrap();
+        check("lists");
+
+        engine.hset(b("h"), vec![(b("f"), b("v")), (b("g"), b("vv"))]);
+        engine.hset(b("h"), vec![(b("f"), b("longer"))]);
+        engine.hsetnx(b("h"), b("k"), b("v"));
+        engine.hincrby(&b("h"), &b("n"), 1000).unwrap();
+        engine.hincrbyfloat(&b("h"), &b("n"), 0.5).unwrap();
+        engine.hdel(&b("h"), &[b("g"), b("missing")]);
+        check("hashes");
+
+        engine.sadd(b("set"), vec![b("a"), b("bb"), b("a")]);
+        engine.srem(&b("set"), &[b("a"), b("c")]);
+        engine
+            .zadd(
+                b("z"),
+                ZAddFlags::default(),
+                vec![(1.0, b("a")), (2.0, b("bb")), (3.0, b("a"))],
+            )
+            .unwrap();
+        engine.zincrby(b("z"), 1.0, b("ccc")).unwrap();
+        engine.zpop(&b("z"), 1, false);
+        engine.zrem(&b("z"), &[b("bb")]);
+        check("sets");
+
+        for _ in 0..5 {
+            engine
+                .xadd(b("x"), XAddId::Auto, vec![(b("f"), b("v"))], Some(3), false)
+                .unwrap();
+        }
+        check("streams");
+
+        engine.rename(&b("h"), b("h2"), false);
+        engine.copy(&b("set"), b("set2"), false);
+        engine.move_to(&b("z"), engine.db(1).unwrap());
+        engine.delete(&b("s"));
+        engine.hdel(&b("h2"), &[b("f"), b("k"), b("n")]);
+        engine.set_with_ttl(b("t"), b("v"), Duration::from_millis(1));
+        std::thread::sleep(Duration::from_millis(30));
+        engine.cleanup_expired();
+        check("keyspace changes");
+
+        engine.flush_all();
+        assert_eq!(engine.memory_info().used_memory, 0);
+    }
+
+    #[test]
+    fn test_compact_keys() {
+        let engine = StorageEngine::with_databases(2);
+        engine.set_compact_keys(true);
+        assert!(engine.db(1).unwrap().compact_keys());
+
+        let long = Bytes::from("k".repeat(COMPACT_KEY_LIMIT + 1));
+        engine.set(Bytes::from("a"), Bytes::from("1"));
+        engine.set(Bytes::from("a"), Bytes::from("2"));
+        engine.rpush(Bytes::from("list"), vec![Bytes::from("x")]);
+        engine.set(long.clone(), Bytes::from("1"));
+        engine
+            .db(1)
+            .unwrap()
+            .set(Bytes::from("b"), Bytes::from("1"));
+
+        let info = engine.memory_info();
+        assert_eq!(info.keys, 4);
+        assert_eq!(info.compact_keys, 3);
+        assert_eq!(info.key_bytes, 1 + 4 + long.len() + 1);
+        assert_eq!(info.compact_keys_saved(), 3 * KEY_ALLOC_OVERHEAD);
+        assert_eq!(engine.get(&Bytes::from("a")), Some(Bytes::from("2")));
+
+        engine.delete(&Bytes::from("list"));
+        engine.delete(&long);
+        let info = engine.memory_info();
+        assert_eq!((info.compact_keys, info.key_bytes), (2, 2));
+
+        engine.flush_all();
+        let info = engine.memory_info();
+        assert_eq!((info.compact_keys, info.key_bytes), (0, 0));
+
+        // Off by default
+        let engine = StorageEngine::new();
+        engine.set(Bytes::from("a"), Bytes::from("1"));
+        assert_eq!(engine.memory_info().compact_keys, 0);
+        let usage = engine.memory_usage(&Bytes::from("a")).unwrap();
+        assert_eq!(usage, engine.memory_info().used_memory);
+        assert_eq!(engine.memory_usage(&Bytes::from("missing")), None);
+    }
+
+    #[test]
+    fn test_key_counts_exact() {
+        // A key keeps counting as compact after the option is switched off
+        let engine = StorageEngine::new();
+        engine.set_compact_keys(true);
+        engine.set(Bytes::from("a"), Bytes::from("1"));
+        engine.set_compact_keys(false);
+        engine.set(Bytes::from("a"), Bytes::from("2"));
+        assert_eq!(engine.memory_info().compact_keys, 1);
+        engine.delete(&Bytes::from("a"));
+        assert_eq!(engine.memory_info().compact_keys, 0);
+
+        // Writes racing each other and FLUSHDB leave the counts matching
+        // the keyspace
+        let engine = Arc::new(StorageEngine::with_shards(1, 4));
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let engine = Arc::clone(&engine);
+                std::thread::spawn(move || {
+                    let mut rng = rand::thread_rng();
+                    for i in 0..5_000 {
+                        let key = Bytes::from(format!("key:{}", rng.gen_range(0..50)));
+                        match rng.gen_range(0..7) {
+                            0 => {
+                                engine.set(key, Bytes::from("v"));
+                            }
+                            1 => {
+                                engine.delete(&key);
+                            }
+                            2 => {
+                                let _ = engine.incr(&key);
+                            }
+                            3 => {
+                                let _ = engine.append(&key, &Bytes::from("x"));
+                            }
+                            4 => {
+                                engine.expire(&key, Duration::from_secs(3600));
+                            }
+                            5 => {
+                                let _ = engine.rpush(key, vec![Bytes::from("x")]);
+                            }
+                            _ if (i + t) % 500 == 0 => engine.flush(),
+                            _ => {
+                                engine.persist(&key);
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let keys = engine.keys(b"*");
+        assert_eq!(engine.len(), keys.len() as u64);
+        let by_type: u64 = engine.keys_by_type().iter().map(|(_, n)| n).sum();
+        assert_eq!(by_type, engine.len());
+        let with_ttl = keys.iter().filter(|k| engine.ttl(k) != Some(-1)).count();
+        assert_eq!(engine.stats().expires, with_ttl as u64);
+    }
+
+    #[test]
+    fn test_eviction() {
+        // A single shard, so every sample sees the hot key's neighbours
+        let engine = StorageEngine::with_shards(2, 1);
+        let key = |i: usize| Bytes::from(format!("key:{}", i));
+        for i in 0..100 {
+            engine.set(key(i), Bytes::from(vec![b'x'; 100]));
+        }
+        let db1 = engine.db(1).unwrap();
+        for i in 0..10 {
+            db1.set_with_ttl(key(i), Bytes::from("v"), Duration::from_secs(100));
+        }
+        for _ in 0..100 {
+            engine.get(&key(7));
+        }
+
+        let used = engine.memory_info().used_memory as u64;
+        assert!(engine.evict(), "no limit");
+        engine.eviction().set_maxmemory(used / 2);
+        assert!(!engine.evict());
+        assert_eq!(engine.memory_info().used_memory as u64, used);
+
+        // Only keys with an expiry go, and there aren't enough of them
+        engine.eviction().set_policy(EvictionPolicy::VolatileLfu);
+        assert!(!engine.evict());
+        assert_eq!(db1.len(), 0);
+        assert_eq!(engine.len(), 100);
+        assert_eq!(engine.eviction().evicted(), 10);
+
+        engine.eviction().set_policy(EvictionPolicy::AllKeysLfu);
+        assert!(engine.evict());
+        assert!(engine.memory_info().used_memory as u64 <= used / 2);
+        assert!(engine.len() < 100);
+        assert_eq!(engine.eviction().evicted(), 10 + 100 - engine.len());
+        assert!(engine.exists(&key(7)), "the most used key stays");
+    }
+
+    #[test]
+    fn test_random_entry() {
+        let mut rng = rand::thread_rng();
+        let mut data = KeyMap::default();
+        assert!(random_entry(&data, &mut rng).is_none());
+
+        for i in 0..100 {
+            data.insert(
+                Bytes::from(format!("key:{}", i)),
+                Entry::new(Bytes::from("v")),
+            );
+        }
+        let mut seen = HashSet::new();
+        for _ in 0..10_000 {
+            let (key, _) = random_entry(&data, &mut rng).unwrap();
+            seen.insert(key.clone());
+        }
+        assert_eq!(seen.len(), 100, "every key can be picked");
+    }
+
+    #[test]
+    fn test_eviction_of_sparse_shards() {
+        // Tables left nearly empty by deletes are too sparse to sample
+        let engine = StorageEngine::with_shards(1, 1);
+        let key = |i: usize| Bytes::from(format!("key:{}", i));
+        for i in 0..50_000 {
+            engine.set(key(i), Bytes::from("v"));
+        }
+        for i in 2..50_000 {
+            engine.delete(&key(i));
+        }
+
+        engine.eviction().set_policy(EvictionPolicy::AllKeysLfu);
+        engine.eviction().set_maxmemory(1);
+        assert!(engine.evict());
+        assert!(engine.is_empty());
+        assert_eq!(engine.eviction().evicted(), 2);
+    }
+
+    #[test]
+    fn test_expire_flags() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("key");
+        engine.set(key.clone(), Bytes::from("value"));
+        let now = engine.unix_time_ms();
+        let nx = ExpireFlags {
+            nx: true,
+            ..Default::default()
+        };
+        let xx = ExpireFlags {
+            xx: true,
+            ..Default::default()
+        };
+        let gt = ExpireFlags {
+            gt: true,
+            ..Default::default()
+        };
+        let lt = ExpireFlags {
+            lt: true,
+            ..Default::default()
+        };
+
+        // No expiry counts as infinite: XX and GT refuse, LT accepts
+        assert!(!engine.expire_at(&key, now + 10_000, xx));
+        assert!(!engine.expire_at(&key, now + 10_000, gt));
+        assert!(engine.expire_at(&key, now + 10_000, lt));
+        assert_eq!(engine.expire_time(&key), Some(now + 10_000));
+
+        assert!(!engine.expire_at(&key, now + 20_000, nx));
+        assert!(!engine.expire_at(&key, now + 5_000, gt));
+        assert!(engine.expire_at(&key, now + 20_000, gt));
+        assert!(!engine.expire_at(&key, now + 30_000, lt));
+        assert!(engine.expire_at(&key, now + 30_000, xx));
+        assert_eq!(engine.expire_time(&key), Some(now + 30_000));
+
+        engine.persist(&key);
+        assert!(engine.expire_at(&key, now + 10_000, nx));
+
+        // A refused change to a past deadline leaves the key alone
+        assert!(!engine.expire_at(&key, now - 1, gt));
+        assert!(engine.exists(&key));
+    }
+
+    #[test]
+    fn test_sort() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("list");
+        let items = ["3", "1", "10", "2"].map(Bytes::from).to_vec();
+        engine.rpush(key.clone(), items);
+        let sorted =
+            |opts: &SortOptions| -> Vec<Option<Bytes>> { engine.sort(&key, opts).unwrap() };
+        let values = |v: &[&str]| -> Vec<Option<Bytes>> {
+            v.iter().map(|s| Some(Bytes::from(s.to_string()))).collect()
+        };
+
+        let mut opts = SortOptions::default();
+        assert_eq!(sorted(&opts), values(&["1", "2", "3", "10"]));
+        opts.alpha = true;
+        assert_eq!(sorted(&opts), values(&["1", "10", "2", "3"]));
+        opts.desc = true;
+        opts.limit = Some((1, 2));
+        assert_eq!(sorted(&opts), values(&["2", "10"]));
+
+        // BY weights from other keys; missing weights count as 0
+        for (item, weight) in [("1", "30"), ("2", "20"), ("10", "10")] {
+            engine.set(Bytes::from(format!("w_{}", item)), Bytes::from(weight));
+        }
+        let mut opts = SortOptions {
+            by: Some(Bytes::from("w_*")),
+            ..Default::default()
+        };
+        assert_eq!(sorted(&opts), values(&["3", "10", "2", "1"]));
+
+        // GET from hash fields, with nil for missing lookups
+        engine.hset(
+            Bytes::from("obj_1"),
+            vec![(Bytes::from("name"), Bytes::from("one"))],
+        );
+        opts.by = Some(Bytes::from("nosort"));
+        opts.get = vec![Bytes::from("#"), Bytes::from("obj_*->name")];
+        assert_eq!(
+            sorted(&opts),
+            vec![
+                Some(Bytes::from("3")),
+                None,
+                Some(Bytes::from("1")),
+                Some(Bytes::from("one")),
+                Some(Bytes::from("10")),
+                None,
+                Some(Bytes::from("2")),
+                None,
+            ]
+        );
+
+        // Non-numeric weights, wrong types and missing keys
+        engine.rpush(key.clone(), vec![Bytes::from("x")]);
+        assert_eq!(
+            engine.sort(&key, &SortOptions::default()),
+            Err(SORT_NOT_NUMERIC)
+        );
+        engine.set(Bytes::from("str"), Bytes::from("v"));
+        assert_eq!(
+            engine.sort(&Bytes::from("str"), &SortOptions::default()),
+            Err(WRONGTYPE)
+        );
+        assert_eq!(
+            engine.sort(&Bytes::from("missing"), &SortOptions::default()),
+            Ok(vec![])
+        );
+
+        // STORE writes a list, replacing nils with empty strings
+        let dest = Bytes::from("dest");
+        assert_eq!(
+            engine.sort_store(dest.clone(), vec![Some(Bytes::from("a")), None]),
+            2
+        );
+        assert_eq!(
+            engine.lrange(&dest, 0, -1),
+            vec![Bytes::from("a"), Bytes::new()]
+        );
+        assert_eq!(engine.sort_store(dest.clone(), vec![]), 0);
+        assert!(!engine.exists(&dest));
+    }
+
+    #[test]
+    fn test_keys_pattern() {
+        let engine = StorageEngine::new();
+
+        engine.set(Bytes::from("hello"), Bytes::from("1"));
+        engine.set(Bytes::from("hallo"), Bytes::from("2"));
+        engine.set(Bytes::from("hxllo"), Bytes::from("3"));
+        engine.set(Bytes::from("world"), Bytes::from("4"));
+
+        // Match all
+        let all = engine.keys(b"*");
+        assert_eq!(all.len(), 4);
+
+        // Match h*llo
+        let pattern = engine.keys(b"h*llo");
+        assert_eq!(pattern.len(), 3);
+
+        // Match h?llo
+        let pattern = engine.keys(b"h?llo");
+        assert_eq!(pattern.len(), 3);
+    }
+
+    #[test]
+    fn test_flush() {
+        let engine = StorageEngine::new();
+
+        engine.set(Bytes::from("key1"), Bytes::from("value1"));
+        engine.set(Bytes::from("key2"), Bytes::from("value2"));
+
+        assert_eq!(engine.len(), 2);
+
+        engine.flush();
+
+        assert_eq!(engine.len(), 0);
+        assert!(engine.is_empty());
+    }
+
+    #[test]
+    fn test_cleanup_expired() {
+        let engine = StorageEngine::new();
+
+        engine.set_with_ttl(
+            Bytes::from("key1"),
+            Bytes::from("value1"),
+            Duration::from_millis(10),
+        );
+        engine.set_with_ttl(
+            Bytes::from("key2"),
+            Bytes::from("value2"),
+            Duration::from_millis(10),
+        );
+        engine.set(Bytes::from("key3"), Bytes::from("value3")); // No expiry
+
+        std::thread::sleep(Duration::from_millis(50));
+
+        let cleaned = engine.cleanup_expired();
+        assert_eq!(cleaned, 2);
+        assert_eq!(engine.len(), 1);
+        assert!(engine.exists(&Bytes::from("key3")));
+    }
+
+    #[test]
+    fn test_concurrent_access() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let engine = Arc::new(StorageEngine::new());
+        let mut handles = vec![];
+
+        // Spawn multiple writers
+        for i in 0..10 {
+            let engine = Arc::clone(&engine);
+            handles.push(thread::spawn(move || {
+                for j in 0..100 {
+                    let key = format!("key-{}-{}", i, j);
+                    engine.set(Bytes::from(key.clone()), Bytes::from("value"));
+                    engine.get(&Bytes::from(key));
+                }
+            }));
+        }
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(engine.len(), 1000);
+    }
+
+    #[test]
+    fn test_keys_binary() {
+        let engine = StorageEngine::new();
+        let binary = Bytes::from_static(b"bin:\xff\xfe");
+        engine.set(binary.clone(), Bytes::from("1"));
+        engine.rpush(Bytes::from_static(b"bin:\x80"), vec![Bytes::from("a")]);
+        engine.set(Bytes::from("text"), Bytes::from("2"));
+
+        let mut found = engine.keys(b"bin:*");
+        found.sort();
+        assert_eq!(found, vec![Bytes::from_static(b"bin:\x80"), binary.clone()]);
+        assert_eq!(engine.keys(b"bin:\xff?"), vec![binary.clone()]);
+
+        let (_, keys) = engine.scan(0, 100, Some(b"*\xfe"), None);
+        assert_eq!(keys, vec![binary]);
+    }
+
+    // ========================================================================
+    // List Operation Tests
+    // ========================================================================
+
+    #[test]
+    fn test_lpush_rpush() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("mylist");
+
+        // LPUSH to empty list
+        assert_eq!(engine.lpush(key.clone(), vec![Bytes::from("a")]), 1);
+        assert_eq!(engine.lpush(key.clone(), vec![Bytes::from("b")]), 2);
+
+        // List should be: b, a
+        assert_eq!(
+            engine.lrange(&key, 0, -1),
+            vec![Bytes::from("b"), Bytes::from("a")]
+        );
+
+        // RPUSH
+        assert_eq!(engine.rpush(key.clone(), vec![Bytes::from("c")]), 3);
+
+        // List should be: b, a, c
+        assert_eq!(
+            engine.lrange(&key, 0, -1),
+            vec![Bytes::from("b"), Bytes::from("a"), Bytes::from("c")]
+        );
+
+        // Multiple values at once
+        assert_eq!(
+            engine.lpush(key.clone(), vec![Bytes::from("x"), Bytes::from("y")]),
+            5
+        );
+        // List should be: y, x, b, a, c (y pushed last, ends up at head)
+        assert_eq!(engine.lindex(&key, 0), Some(Bytes::from("y")));
+        assert_eq!(engine.lindex(&key, 1), Some(Bytes::from("x")));
+    }
+
+    #[test]
+    fn test_lpop_rpop() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("mylist");
+
+        // Pop from empty list
+        assert_eq!(engine.lpop(&key), None);
+        assert_eq!(engine.rpop(&key), None);
+
+        // Create list: a, b, c
+        engine.rpush(
+            key.clone(),
+            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")],
+        );
+
+        // LPOP
+        assert_eq!(engine.lpop(&key), Some(Bytes::from("a")));
+        assert_eq!(engine.llen(&key), 2);
+
+        // RPOP
+        assert_eq!(engine.rpop(&key), Some(Bytes::from("c")));
+        assert_eq!(engine.llen(&key), 1);
+
+        // Pop last element
+        assert_eq!(engine.lpop(&key), Some(Bytes::from("b")));
+        assert_eq!(engine.llen(&key), 0);
+
+        // List should be auto-deleted when empty
+        assert!(!engine.list_exists(&key));
+    }
+
+    #[test]
+    fn test_llen() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("mylist");
+
+        // Empty/non-existent list
+        assert_eq!(engine.llen(&key), 0);
+
+        engine.rpush(
+            key.clone(),
+            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")],
+        );
+        assert_eq!(engine.llen(&key), 3);
+    }
+
+    #[test]
+    fn test_lindex() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("mylist");
+
+        engine.rpush(
+            key.clone(),
+            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")],
+        );
+
+        // Positive indices
+        assert_eq!(engine.lindex(&key, 0), Some(Bytes::from("a")));
+        assert_eq!(engine.lindex(&key, 1), Some(Bytes::from("b")));
+        assert_eq!(engine.lindex(&key, 2), Some(Bytes::from("c")));
+
+        // Negative indices
+        assert_eq!(engine.lindex(&key, -1), Some(Bytes::from("c")));
+        assert_eq!(engine.lindex(&key, -2), Some(Bytes::from("b")));
+        assert_eq!(engine.lindex(&key, -3), Some(Bytes::from("a")));
+
+        // Out of range
+        assert_eq!(engine.lindex(&key, 3), None);
+        assert_eq!(engine.lindex(&key, -4), None);
+    }
+
+    #[test]
+    fn test_lrange() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("mylist");
+
+        engine.rpush(
+            key.clone(),
+            vec![
+                Bytes::from("a"),
+                Bytes::from("b"),
+                Bytes::from("c"),
+                Bytes::from("d"),
+                Bytes::from("e"),
+            ],
+        );
+
+        // Full range
+        assert_eq!(
+            engine.lrange(&key, 0, -1),
+            vec![
+                Bytes::from("a"),
+                Bytes::from("b"),
+                Bytes::from("c"),
+                Bytes::from("d"),
+                Bytes::from("e"),
+            ]
+        );
+
+        // Partial range
+        assert_eq!(
+            engine.lrange(&key, 1, 3),
+            vec![Bytes::from("b"), Bytes::from("c"), Bytes::from("d")]
+        );
+
+        // Negative indices
+        assert_eq!(
+            engine.lrange(&key, -3, -1),
+            vec![Bytes::from("c"), Bytes::from("d"), Bytes::from("e")]
+        );
+
+        // Out of range (should clamp)
+        assert_eq!(
+            engine.lrange(&key, 0, 100),
+            vec![
+                Bytes::from("a"),
+                Bytes::from("b"),
+                Bytes::from("c"),
+                Bytes::from("d"),
+                Bytes::from("e"),
+            ]
+        );
+
+        // Invalid range (start > stop)
+        assert_eq!(engine.lrange(&key, 3, 1), Vec::<Bytes>::new());
+    }
+
+    #[test]
+    fn test_lset() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("mylist");
+
+        engine.rpush(
+            key.clone(),
+            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")],
+        );
+
+        // Set at valid index
+        assert!(engine.lset(&key, 1, Bytes::from("B")).is_ok());
+        assert_eq!(engine.lindex(&key, 1), Some(Bytes::from("B")));
+
+        // Set with negative index
+        assert!(engine.lset(&key, -1, Bytes::from("C")).is_ok());
+        assert_eq!(engine.lindex(&key, -1), Some(Bytes::from("C")));
+
+        // Out of range
+        assert!(engine.lset(&key, 10, Bytes::from("X")).is_err());
+
+        // Non-existent key
+        let nonexistent = Bytes::from("nonexistent");
+        assert!(engine.lset(&nonexistent, 0, Bytes::from("X")).is_err());
+    }
+
+    #[test]
+    fn test_lrem() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("mylist");
+
+        // Create list: a, b, a, c, a, d
+        engine.rpush(
+            key.clone(),
+            vec![
+                Bytes::from("a"),
+                Bytes::from("b"),
+                Bytes::from("a"),
+                Bytes::from("c"),
+                Bytes::from("a"),
+                Bytes::from("d"),
+            ],
+        );
+
+        // Remove 2 occurrences of "a" from head
+        assert_eq!(engine.lrem(&key, 2, &Bytes::from("a")), 2);
+        // List should be: b, c, a, d
+        assert_eq!(
+            engine.lrange(&key, 0, -1),
+            vec![
+                Bytes::from("b"),
+                Bytes::from("c"),
+                Bytes::from("a"),
+                Bytes::from("d"),
+            ]
+        );
+
+        // Remove from tail (negative count)
+        engine.flush();
+        engine.rpush(
+            key.clone(),
+            vec![
+                Bytes::from("a"),
+                Bytes::from("b"),
+                Bytes::from("a"),
+                Bytes::from("c"),
+                Bytes::from("a"),
+            ],
+        );
+        assert_eq!(engine.lrem(&key, -2, &Bytes::from("a")), 2);
+        // List should be: a, b, c (removed last two "a"s)
+        assert_eq!(
+            engine.lrange(&key, 0, -1),
+            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")]
+        );
+
+        // Remove all (count = 0)
+        engine.flush();
+        engine.rpush(
+            key.clone(),
+            vec![Bytes::from("a"), Bytes::from("a"), Bytes::from("a")],
+        );
+        assert_eq!(engine.lrem(&key, 0, &Bytes::from("a")), 3);
+        // List should be empty and auto-deleted
+        assert!(!engine.list_exists(&key));
+    }
+
+    #[test]
+    fn test_lmove() {
         let engine = StorageEngine::new();
+        let src = Bytes::from("src");
+        let dst = Bytes::from("dst");
+        engine.rpush(src.clone(), ["a", "b", "c"].map(Bytes::from).to_vec());
 
-        // INCR on non-existent key
-        assert_eq!(engine.incr(&Bytes::from("counter")), Ok(1));
-        assert_eq!(engine.incr(&Bytes::from("counter")), Ok(2));
+        assert_eq!(
+            engine.lmove(&src, dst.clone(), ListEnd::Right, ListEnd::Left),
+            Ok(Some(Bytes::from("c")))
+        );
+        assert_eq!(
+            engine.lmove(&src, dst.clone(), ListEnd::Left, ListEnd::Right),
+            Ok(Some(Bytes::from("a")))
+        );
+        assert_eq!(engine.lrange(&src, 0, -1), vec![Bytes::from("b")]);
+        assert_eq!(
+            engine.lrange(&dst, 0, -1),
+            vec![Bytes::from("c"), Bytes::from("a")]
+        );
 
-        // INCR on existing numeric string
-        engine.set(Bytes::from("num"), Bytes::from("10"));
-        assert_eq!(engine.incr(&Bytes::from("num")), Ok(11));
+        // Moving within one list rotates it
+        assert_eq!(
+            engine.lmove(&dst, dst.clone(), ListEnd::Left, ListEnd::Right),
+            Ok(Some(Bytes::from("c")))
+        );
+        assert_eq!(
+            engine.lrange(&dst, 0, -1),
+            vec![Bytes::from("a"), Bytes::from("c")]
+        );
 
-        // INCR on non-numeric string should fail
-        engine.set(Bytes::from("text"), Bytes::from("hello"));
-        assert!(engine.incr(&Bytes::from("text")).is_err());
+        // Draining the source deletes it
+        engine
+            .lmove(&src, dst.clone(), ListEnd::Left, ListEnd::Left)
+            .unwrap();
+        assert!(!engine.exists(&src));
+        assert_eq!(
+            engine.lmove(&src, dst.clone(), ListEnd::Left, ListEnd::Left),
+            Ok(None)
+        );
+        assert_eq!(engine.len(), 1);
+
+        // Wrong types on either side are rejected without moving anything
+        engine.set(Bytes::from("str"), Bytes::from("v"));
+        assert_eq!(
+            engine.lmove(&dst, Bytes::from("str"), ListEnd::Left, ListEnd::Left),
+            Err(WRONGTYPE)
+        );
+        assert_eq!(
+            engine.lmove(
+                &Bytes::from("str"),
+                dst.clone(),
+                ListEnd::Left,
+                ListEnd::Left
+            ),
+            Err(WRONGTYPE)
+        );
+        assert_eq!(engine.llen(&dst), 3);
     }
 
     #[test]
-    fn test_append() {
+    fn test_ltrim() {
         let engine = StorageEngine::new();
+        let key = Bytes::from("mylist");
+        let items: Vec<Bytes> = ["a", "b", "c", "d", "e"].map(Bytes::from).to_vec();
+
+        engine.rpush(key.clone(), items.clone());
+        engine.ltrim(&key, 1, -2);
+        assert_eq!(engine.lrange(&key, 0, -1), items[1..4].to_vec());
+
+        // Out-of-range indices are clamped
+        engine.ltrim(&key, -100, 100);
+        assert_eq!(engine.lrange(&key, 0, -1), items[1..4].to_vec());
+        engine.ltrim(&key, 0, 0);
+        assert_eq!(engine.lrange(&key, 0, -1), vec![Bytes::from("b")]);
+
+        // An empty range deletes the key
+        engine.ltrim(&key, 5, 10);
+        assert!(!engine.exists(&key));
+        engine.rpush(key.clone(), items);
+        engine.ltrim(&key, 3, 1);
+        assert!(!engine.exists(&key));
+        assert!(engine.is_empty());
+    }
 
-        // Append to non-existent key
-        assert_eq!(engine.append(&Bytes::from("key"), &Bytes::from("Hello")), 5);
+    // ========================================================================
+    // Hash Operation Tests
+    // ========================================================================
+
+    #[test]
+    fn test_hset_hget() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("myhash");
 
-        // Append to existing key
         assert_eq!(
-            engine.append(&Bytes::from("key"), &Bytes::from(" World")),
-            11
+            engine.hset(
+                key.clone(),
+                vec![
+                    (Bytes::from("f1"), Bytes::from("v1")),
+                    (Bytes::from("f2"), Bytes::from("v2")),
+                ]
+            ),
+            2
         );
+        // Updating an existing field doesn't count as added
         assert_eq!(
-            engine.get(&Bytes::from("key")),
-            Some(Bytes::from("Hello World"))
+            engine.hset(key.clone(), vec![(Bytes::from("f1"), Bytes::from("v3"))]),
+            0
         );
+
+        assert_eq!(
+            engine.hget(&key, &Bytes::from("f1")),
+            Some(Bytes::from("v3"))
+        );
+        assert_eq!(engine.hget(&key, &Bytes::from("missing")), None);
+        assert_eq!(engine.hlen(&key), 2);
+
+        // Deleting all fields removes the hash
+        assert_eq!(
+            engine.hdel(&key, &[Bytes::from("f1"), Bytes::from("f2")]),
+            2
+        );
+        assert!(!engine.hash_exists(&key));
     }
 
     #[test]
-    fn test_ttl() {
+    fn test_hsetnx() {
         let engine = StorageEngine::new();
+        let key = Bytes::from("myhash");
 
-        // No TTL on non-existent key
-        assert_eq!(engine.ttl(&Bytes::from("nonexistent")), None);
+        assert!(engine.hsetnx(key.clone(), Bytes::from("f"), Bytes::from("a")));
+        assert!(!engine.hsetnx(key.clone(), Bytes::from("f"), Bytes::from("b")));
+        assert_eq!(engine.hget(&key, &Bytes::from("f")), Some(Bytes::from("a")));
+    }
 
-        // No TTL on persistent key
-        engine.set(Bytes::from("persistent"), Bytes::from("value"));
-        assert_eq!(engine.ttl(&Bytes::from("persistent")), Some(-1));
+    #[test]
+    fn test_hincrby() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("user:1");
+        let field = Bytes::from("visits");
 
-        // TTL on expiring key
-        engine.set_with_ttl(
-            Bytes::from("expiring"),
-            Bytes::from("value"),
-            Duration::from_secs(100),
+        assert_eq!(engine.hincrby(&key, &field, 5), Ok(5));
+        assert_eq!(engine.hincrby(&key, &field, -2), Ok(3));
+
+        engine.hset(
+            key.clone(),
+            vec![(Bytes::from("name"), Bytes::from("Ariz"))],
         );
-        let ttl = engine.ttl(&Bytes::from("expiring"));
-        assert!(ttl.is_some());
-        assert!(ttl.unwrap() > 0 && ttl.unwrap() <= 100);
+        assert!(engine.hincrby(&key, &Bytes::from("name"), 1).is_err());
+
+        engine.hset(
+            key.clone(),
+            vec![(field.clone(), Bytes::from(i64::MAX.to_string()))],
+        );
+        assert!(engine.hincrby(&key, &field, 1).is_err());
     }
 
     #[test]
-    fn test_expire() {
+    fn test_hincrbyfloat() {
         let engine = StorageEngine::new();
+        let key = Bytes::from("user:1");
+        let field = Bytes::from("balance");
 
-        engine.set(Bytes::from("key"), Bytes::from("value"));
-
-        // Set expiry
-        assert!(engine.expire(&Bytes::from("key"), Duration::from_secs(60)));
-
-        // Check TTL
-        let ttl = engine.ttl(&Bytes::from("key"));
-        assert!(ttl.is_some() && ttl.unwrap() > 0);
+        assert_eq!(
+            engine.hincrbyfloat(&key, &field, 10.5),
+            Ok(Bytes::from("10.5"))
+        );
+        assert_eq!(
+            engine.hincrbyfloat(&key, &field, 1.5),
+            Ok(Bytes::from("12"))
+        );
+        assert_eq!(
+            engine.hincrbyfloat(&key, &field, -2.25),
+            Ok(Bytes::from("9.75"))
+        );
 
-        // Persist (remove expiry)
-        assert!(engine.persist(&Bytes::from("key")));
-        assert_eq!(engine.ttl(&Bytes::from("key")), Some(-1));
+        engine.hset(
+            key.clone(),
+            vec![(Bytes::from("name"), Bytes::from("Ariz"))],
+        );
+        assert!(engine
+            .hincrbyfloat(&key, &Bytes::from("name"), 1.0)
+            .is_err());
     }
 
     #[test]
-    fn test_keys_pattern() {
+    fn test_hrandfield() {
         let engine = StorageEngine::new();
+        let key = Bytes::from("myhash");
 
-        engine.set(Bytes::from("hello"), Bytes::from("1"));
-        engine.set(Bytes::from("hallo"), Bytes::from("2"));
-        engine.set(Bytes::from("hxllo"), Bytes::from("3"));
-        engine.set(Bytes::from("world"), Bytes::from("4"));
+        assert!(engine.hrandfield(&key, 1).is_empty());
 
-        // Match all
-        let all = engine.keys("*");
-        assert_eq!(all.len(), 4);
+        engine.hset(
+            key.clone(),
+            vec![
+                (Bytes::from("a"), Bytes::from("1")),
+                (Bytes::from("b"), Bytes::from("2")),
+                (Bytes::from("c"), Bytes::from("3")),
+            ],
+        );
 
-        // Match h*llo
-        let pattern = engine.keys("h*llo");
-        assert_eq!(pattern.len(), 3);
+        // Positive count returns distinct fields, capped at the hash size
+        let mut fields: Vec<Bytes> = engine
+            .hrandfield(&key, 10)
+            .into_iter()
+            .map(|(f, _)| f)
+            .collect();
+        fields.sort();
+        assert_eq!(
+            fields,
+            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")]
+        );
+        assert_eq!(engine.hrandfield(&key, 2).len(), 2);
 
-        // Match h?llo
-        let pattern = engine.keys("h?llo");
-        assert_eq!(pattern.len(), 3);
+        // Negative count may repeat fields and returns exactly |count| entries
+        assert_eq!(engine.hrandfield(&key, -7).len(), 7);
     }
 
+    // ========================================================================
+    // Set Operation Tests
+    // ========================================================================
+
     #[test]
-    fn test_flush() {
+    fn test_sadd_srem() {
         let engine = StorageEngine::new();
+        let key = Bytes::from("tags");
 
-        engine.set(Bytes::from("key1"), Bytes::from("value1"));
-        engine.set(Bytes::from("key2"), Bytes::from("value2"));
-
-        assert_eq!(engine.len(), 2);
+        assert_eq!(
+            engine.sadd(
+                key.clone(),
+                vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("a")]
+            ),
+            2
+        );
+        assert_eq!(engine.sadd(key.clone(), vec![Bytes::from("b")]), 0);
+        assert_eq!(engine.scard(&key), 2);
 
-        engine.flush();
+        assert_eq!(
+            engine.srem(&key, &[Bytes::from("a"), Bytes::from("missing")]),
+            1
+        );
+        assert_eq!(engine.smembers(&key), vec![Bytes::from("b")]);
 
-        assert_eq!(engine.len(), 0);
-        assert!(engine.is_empty());
+        // Removing the last member deletes the set
+        assert_eq!(engine.srem(&key, &[Bytes::from("b")]), 1);
+        assert!(!engine.set_exists(&key));
     }
 
     #[test]
-    fn test_cleanup_expired() {
+    fn test_sismember_smismember() {
         let engine = StorageEngine::new();
+        let key = Bytes::from("tags");
 
-        engine.set_with_ttl(
-            Bytes::from("key1"),
-            Bytes::from("value1"),
-            Duration::from_millis(10),
+        engine.sadd(key.clone(), vec![Bytes::from("rust"), Bytes::from("db")]);
+
+        assert!(engine.sismember(&key, &Bytes::from("rust")));
+        assert!(!engine.sismember(&key, &Bytes::from("go")));
+        assert_eq!(
+            engine.smismember(&key, &[Bytes::from("db"), Bytes::from("go")]),
+            vec![true, false]
         );
-        engine.set_with_ttl(
-            Bytes::from("key2"),
-            Bytes::from("value2"),
-            Duration::from_millis(10),
+        assert_eq!(
+            engine.smismember(&Bytes::from("missing"), &[Bytes::from("db")]),
+            vec![false]
         );
-        engine.set(Bytes::from("key3"), Bytes::from("value3")); // No expiry
-
-        std::thread::sleep(Duration::from_millis(50));
-
-        let cleaned = engine.cleanup_expired();
-        assert_eq!(cleaned, 2);
-        assert_eq!(engine.len(), 1);
-        assert!(engine.exists(&Bytes::from("key3")));
     }
 
     #[test]
-    fn test_concurrent_access() {
-        use std::sync::Arc;
-        use std::thread;
+    fn test_set_algebra() {
+        let engine = StorageEngine::new();
+        let a = Bytes::from("a");
+        let b = Bytes::from("b");
+        let missing = Bytes::from("missing");
 
-        let engine = Arc::new(StorageEngine::new());
-        let mut handles = vec![];
+        engine.sadd(
+            a.clone(),
+            vec![Bytes::from("1"), Bytes::from("2"), Bytes::from("3")],
+        );
+        engine.sadd(
+            b.clone(),
+            vec![Bytes::from("2"), Bytes::from("3"), Bytes::from("4")],
+        );
 
-        // Spawn multiple writers
-        for i in 0..10 {
-            let engine = Arc::clone(&engine);
-            handles.push(thread::spawn(move || {
-                for j in 0..100 {
-                    let key = format!("key-{}-{}", i, j);
-                    engine.set(Bytes::from(key.clone()), Bytes::from("value"));
-                    engine.get(&Bytes::from(key));
-                }
-            }));
-        }
+        let sorted = |mut v: Vec<Bytes>| {
+            v.sort();
+            v
+        };
 
-        for handle in handles {
-            handle.join().unwrap();
-        }
+        assert_eq!(
+            sorted(engine.set_op(SetOp::Inter, &[a.clone(), b.clone()])),
+            vec![Bytes::from("2"), Bytes::from("3")]
+        );
+        assert_eq!(
+            engine.set_op(SetOp::Union, &[a.clone(), b.clone()]).len(),
+            4
+        );
+        assert_eq!(
+            engine.set_op(SetOp::Diff, &[a.clone(), b.clone()]),
+            vec![Bytes::from("1")]
+        );
+        assert!(engine
+            .set_op(SetOp::Inter, &[a.clone(), missing.clone()])
+            .is_empty());
+        assert_eq!(
+            engine
+                .set_op(SetOp::Diff, &[a.clone(), missing.clone()])
+                .len(),
+            3
+        );
 
-        assert_eq!(engine.len(), 1000);
+        assert_eq!(engine.sintercard(&[a.clone(), b.clone()], 0), 2);
+        assert_eq!(engine.sintercard(&[a.clone(), b.clone()], 1), 1);
+        assert_eq!(engine.sintercard(&[a.clone(), missing], 0), 0);
     }
 
     #[test]
-    fn test_glob_pattern() {
-        let pattern = GlobPattern::new("h*llo");
-        assert!(pattern.matches("hello"));
-        assert!(pattern.matches("hallo"));
-        assert!(pattern.matches("hllo"));
-        assert!(pattern.matches("heeeello"));
-        assert!(!pattern.matches("world"));
+    fn test_set_op_store() {
+        let engine = StorageEngine::new();
+        let a = Bytes::from("a");
+        let b = Bytes::from("b");
+        let dest = Bytes::from("dest");
 
-        let pattern = GlobPattern::new("h?llo");
-        assert!(pattern.matches("hello"));
-        assert!(pattern.matches("hallo"));
-        assert!(!pattern.matches("hllo"));
-        assert!(!pattern.matches("heello"));
+        engine.sadd(a.clone(), vec![Bytes::from("1"), Bytes::from("2")]);
+        engine.sadd(b.clone(), vec![Bytes::from("2"), Bytes::from("3")]);
+
+        // Destination of another type is overwritten
+        engine.set(dest.clone(), Bytes::from("string"));
+        assert_eq!(
+            engine.set_op_store(SetOp::Union, dest.clone(), &[a.clone(), b.clone()]),
+            3
+        );
+        assert_eq!(engine.key_type(&dest), "set");
+        assert_eq!(engine.scard(&dest), 3);
 
-        let pattern = GlobPattern::new("*");
-        assert!(pattern.matches(""));
-        assert!(pattern.matches("anything"));
+        // Destination can also be one of the sources
+        assert_eq!(
+            engine.set_op_store(SetOp::Inter, a.clone(), &[a.clone(), b.clone()]),
+            1
+        );
+        assert_eq!(engine.smembers(&a), vec![Bytes::from("2")]);
 
-        let pattern = GlobPattern::new("h[ae]llo");
-        assert!(pattern.matches("hello"));
-        assert!(pattern.matches("hallo"));
-        assert!(!pattern.matches("hillo"));
+        // An empty result removes the destination
+        assert_eq!(
+            engine.set_op_store(SetOp::Diff, dest.clone(), &[a.clone(), b.clone()]),
+            0
+        );
+        assert!(!engine.set_exists(&dest));
     }
 
     // ========================================================================
-    // List Operation Tests
+    // Sorted Set Operation Tests
     // ========================================================================
 
     #[test]
-    fn test_lpush_rpush() {
+    fn test_zadd_zscore() {
         let engine = StorageEngine::new();
-        let key = Bytes::from("mylist");
+        let key = Bytes::from("board");
+
+        let result = engine
+            .zadd(
+                key.clone(),
+                ZAddFlags::default(),
+                vec![(10.0, Bytes::from("alice")), (20.0, Bytes::from("bob"))],
+            )
+            .unwrap();
+        assert_eq!(result.added, 2);
+        assert_eq!(engine.zcard(&key), 2);
+        assert_eq!(engine.zscore(&key, &Bytes::from("bob")), Some(20.0));
+
+        // XX on a missing key doesn't create it
+        let xx = ZAddFlags {
+            xx: true,
+            ..Default::default()
+        };
+        let other = Bytes::from("other");
+        engine
+            .zadd(other.clone(), xx, vec![(1.0, Bytes::from("x"))])
+            .unwrap();
+        assert_eq!(engine.key_type(&other), "none");
+    }
 
-        // LPUSH to empty list
-        assert_eq!(engine.lpush(key.clone(), vec![Bytes::from("a")]), 1);
-        assert_eq!(engine.lpush(key.clone(), vec![Bytes::from("b")]), 2);
+    #[test]
+    fn test_zrange_zrank() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("board");
+
+        engine
+            .zadd(
+                key.clone(),
+                ZAddFlags::default(),
+                vec![
+                    (3.0, Bytes::from("c")),
+                    (1.0, Bytes::from("a")),
+                    (2.0, Bytes::from("b")),
+                ],
+            )
+            .unwrap();
+
+        let names = |v: Vec<(Bytes, f64)>| v.into_iter().map(|(m, _)| m).collect::<Vec<_>>();
 
-        // List should be: b, a
         assert_eq!(
-            engine.lrange(&key, 0, -1),
-            vec![Bytes::from("b"), Bytes::from("a")]
+            names(engine.zrange(&key, 0, -1, false)),
+            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")]
+        );
+        assert_eq!(
+            names(engine.zrange(&key, 0, 1, true)),
+            vec![Bytes::from("c"), Bytes::from("b")]
+        );
+        assert_eq!(
+            names(engine.zrange(&key, -1, 100, false)),
+            vec![Bytes::from("c")]
         );
+        assert!(engine.zrange(&key, 5, 10, false).is_empty());
 
-        // RPUSH
-        assert_eq!(engine.rpush(key.clone(), vec![Bytes::from("c")]), 3);
+        assert_eq!(engine.zrank(&key, &Bytes::from("a"), false), Some(0));
+        assert_eq!(engine.zrank(&key, &Bytes::from("a"), true), Some(2));
+        assert_eq!(engine.zrank(&key, &Bytes::from("z"), false), None);
 
-        // List should be: b, a, c
         assert_eq!(
-            engine.lrange(&key, 0, -1),
-            vec![Bytes::from("b"), Bytes::from("a"), Bytes::from("c")]
+            engine.zrem(
+                &key,
+                &[Bytes::from("a"), Bytes::from("b"), Bytes::from("c")]
+            ),
+            3
         );
+        assert_eq!(engine.key_type(&key), "none");
+    }
 
-        // Multiple values at once
+    #[test]
+    fn test_zincrby_zpop() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("board");
+
+        assert_eq!(engine.zincrby(key.clone(), 2.0, Bytes::from("a")), Ok(2.0));
+        assert_eq!(engine.zincrby(key.clone(), 1.5, Bytes::from("a")), Ok(3.5));
+        engine.zincrby(key.clone(), 1.0, Bytes::from("b")).unwrap();
+        engine.zincrby(key.clone(), 9.0, Bytes::from("c")).unwrap();
+
+        assert_eq!(engine.zpop(&key, 1, true), vec![(Bytes::from("c"), 9.0)]);
         assert_eq!(
-            engine.lpush(key.clone(), vec![Bytes::from("x"), Bytes::from("y")]),
-            5
+            engine.zpop(&key, 5, false),
+            vec![(Bytes::from("b"), 1.0), (Bytes::from("a"), 3.5)]
+        );
+
+        // Popping the last member removes the key
+        assert_eq!(engine.key_type(&key), "none");
+        assert!(engine.zpop(&key, 1, false).is_empty());
+    }
+
+    #[test]
+    fn test_zrandmember() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("board");
+
+        engine
+            .zadd(
+                key.clone(),
+                ZAddFlags::default(),
+                vec![(1.0, Bytes::from("a")), (2.0, Bytes::from("b"))],
+            )
+            .unwrap();
+
+        assert_eq!(engine.zrandmember(&key, 5).len(), 2);
+        assert_eq!(engine.zrandmember(&key, -5).len(), 5);
+        assert!(engine.zrandmember(&Bytes::from("missing"), 3).is_empty());
+    }
+
+    #[test]
+    fn test_xadd_xrange() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("events");
+        let fields = |v: &str| vec![(Bytes::from("v"), Bytes::from(v.to_string()))];
+
+        for i in 1..=3 {
+            let id = XAddId::Explicit(StreamId::new(i, 0));
+            engine
+                .xadd(key.clone(), id, fields(&i.to_string()), None, false)
+                .unwrap();
+        }
+        assert_eq!(engine.xlen(&key), 3);
+        assert_eq!(engine.key_type(&key), "stream");
+
+        // Invalid IDs are rejected and leave the stream untouched
+        let stale = XAddId::Explicit(StreamId::new(2, 0));
+        assert!(engine
+            .xadd(key.clone(), stale, fields("x"), None, false)
+            .is_err());
+        assert_eq!(engine.xlen(&key), 3);
+
+        // A failed XADD on a new key doesn't create it
+        let missing = Bytes::from("missing");
+        let zero = XAddId::Explicit(StreamId::MIN);
+        assert!(engine
+            .xadd(missing.clone(), zero, fields("x"), None, false)
+            .is_err());
+        assert_eq!(engine.key_type(&missing), "none");
+
+        // NOMKSTREAM doesn't create streams
+        assert_eq!(
+            engine.xadd(missing.clone(), XAddId::Auto, fields("x"), None, true),
+            Ok(None)
+        );
+        assert_eq!(engine.key_type(&missing), "none");
+
+        let records = engine.xrange(&key, StreamId::MIN, StreamId::MAX, Some(2), true);
+        assert_eq!(records[0].0, StreamId::new(3, 0));
+        assert_eq!(records[1].1, fields("2"));
+
+        // MAXLEN trims the oldest entries
+        let id = XAddId::Explicit(StreamId::new(4, 0));
+        engine
+            .xadd(key.clone(), id, fields("4"), Some(2), false)
+            .unwrap();
+        assert_eq!(engine.xlen(&key), 2);
+
+        let read = engine.xread(
+            &[(key.clone(), StreamId::new(3, 0)), (missing, StreamId::MIN)],
+            None,
+        );
+        assert_eq!(read.len(), 1);
+        assert_eq!(read[0].1.len(), 1);
+        assert_eq!(engine.stream_last_id(&key), StreamId::new(4, 0));
+    }
+
+    #[test]
+    fn test_stream_consumer_groups() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("jobs");
+        let group = Bytes::from("workers");
+        let worker = Bytes::from("w1");
+
+        // The stream must exist unless MKSTREAM is given
+        assert!(engine
+            .xgroup_create(key.clone(), group.clone(), None, false)
+            .is_err());
+        assert_eq!(
+            engine.xgroup_create(key.clone(), group.clone(), None, true),
+            Ok(true)
+        );
+        assert_eq!(
+            engine.xgroup_create(key.clone(), group.clone(), None, true),
+            Ok(false)
+        );
+        assert_eq!(engine.key_type(&key), "stream");
+
+        let fields = vec![(Bytes::from("task"), Bytes::from("build"))];
+        let id = engine
+            .xadd(key.clone(), XAddId::Auto, fields.clone(), None, false)
+            .unwrap()
+            .unwrap();
+
+        let read = engine
+            .xreadgroup(&key, &group, &worker, None, None, false)
+            .unwrap();
+        assert_eq!(read, vec![(id, Some(fields))]);
+        assert_eq!(engine.xpending_summary(&key, &group).unwrap().count, 1);
+
+        let claimed = engine
+            .xclaim(
+                &key,
+                &group,
+                &Bytes::from("w2"),
+                Duration::ZERO,
+                &[id],
+                true,
+            )
+            .unwrap();
+        assert_eq!(claimed.len(), 1);
+
+        assert_eq!(engine.xack(&key, &group, &[id]), 1);
+        assert_eq!(engine.xack(&key, &group, &[id]), 0);
+        assert!(engine
+            .xreadgroup(&key, b"nope", &worker, None, None, false)
+            .is_none());
+        assert!(engine.xgroup_destroy(&key, &group));
+    }
+
+    #[test]
+    fn test_bitmap_operations() {
+        let engine = StorageEngine::new();
+        let key = Bytes::from("dau");
+
+        assert_eq!(engine.setbit(&key, 7, true), Ok(0));
+        assert_eq!(engine.setbit(&key, 7, true), Ok(1));
+        assert_eq!(engine.get(&key), Some(Bytes::from_static(&[0x01])));
+        assert_eq!(engine.len(), 1);
+
+        engine.setbit(&key, 20, true).unwrap();
+        assert_eq!(engine.getbit(&key, 20), 1);
+        assert_eq!(engine.getbit(&key, 21), 0);
+        assert_eq!(engine.bitcount(&key, None), 2);
+        assert_eq!(engine.bitpos(&key, true, None, false), 7);
+
+        let missing = Bytes::from("missing");
+        assert_eq!(engine.getbit(&missing, 3), 0);
+        assert_eq!(engine.bitcount(&missing, None), 0);
+        assert_eq!(engine.bitpos(&missing, false, None, false), 0);
+        assert_eq!(engine.bitpos(&missing, true, None, false), -1);
+    }
+
+    #[test]
+    fn test_hyperloglog_operations() {
+        let engine = StorageEngine::new();
+        let a = Bytes::from("visitors:mon");
+        let b = Bytes::from("visitors:tue");
+        let elements = |names: &[&str]| -> Vec<Bytes> {
+            names.iter().map(|n| Bytes::from(n.to_string())).collect()
+        };
+
+        assert_eq!(
+            engine.pfadd(&a, &elements(&["alice", "bob", "carol"])),
+            Ok(true)
         );
-        // List should be: y, x, b, a, c (y pushed last, ends up at head)
-        assert_eq!(engine.lindex(&key, 0), Some(Bytes::from("y")));
-        assert_eq!(engine.lindex(&key, 1), Some(Bytes::from("x")));
+        assert_eq!(engine.pfadd(&a, &elements(&["alice"])), Ok(false));
+        assert_eq!(engine.pfadd(&b, &elements(&["carol", "dave"])), Ok(true));
+        assert_eq!(engine.len(), 2);
+
+        assert_eq!(engine.pfcount(std::slice::from_ref(&a)), Ok(3));
+        assert_eq!(engine.pfcount(&[a.clone(), b.clone()]), Ok(4));
+        assert_eq!(engine.pfcount(&[Bytes::from("missing")]), Ok(0));
+
+        let week = Bytes::from("visitors:week");
+        assert_eq!(engine.pfmerge(&week, &[a.clone(), b.clone()]), Ok(()));
+        assert_eq!(engine.pfcount(std::slice::from_ref(&week)), Ok(4));
+        assert_eq!(engine.len(), 3);
+
+        // Plain strings are rejected
+        let plain = Bytes::from("plain");
+        engine.set(plain.clone(), Bytes::from("hello"));
+        assert!(engine.pfadd(&plain, &elements(&["x"])).is_err());
+        assert!(engine.pfcount(&[a, plain.clone()]).is_err());
+        assert!(engine.pfmerge(&week, &[plain]).is_err());
     }
 
     #[test]
-    fn test_lpop_rpop() {
+    fn test_zset_op_store() {
         let engine = StorageEngine::new();
-        let key = Bytes::from("mylist");
+        let zadd = |key: &str, pairs: &[(f64, &str)]| {
+            let pairs = pairs.iter().map(|(s, m)| (*s, Bytes::from(m.to_string())));
+            engine
+                .zadd(
+                    Bytes::from(key.to_string()),
+                    ZAddFlags::default(),
+                    pairs.collect(),
+                )
+                .unwrap();
+        };
+        zadd("z1", &[(1.0, "a"), (2.0, "b")]);
+        zadd("z2", &[(10.0, "b"), (20.0, "c")]);
+
+        let keys = [Bytes::from("z1"), Bytes::from("z2")];
+        let out = Bytes::from("out");
+
+        let len = engine.zset_op_store(SetOp::Union, out.clone(), &keys, &[2.0], Aggregate::Sum);
+        assert_eq!(len, 3);
+        assert_eq!(engine.zscore(&out, &Bytes::from("a")), Some(2.0));
+        assert_eq!(engine.zscore(&out, &Bytes::from("b")), Some(14.0));
+        assert_eq!(engine.zscore(&out, &Bytes::from("c")), Some(20.0));
+
+        let len = engine.zset_op_store(SetOp::Inter, out.clone(), &keys, &[], Aggregate::Max);
+        assert_eq!(len, 1);
+        assert_eq!(engine.zscore(&out, &Bytes::from("b")), Some(10.0));
+        assert_eq!(engine.zscore(&out, &Bytes::from("a")), None);
+
+        let len = engine.zset_op_store(SetOp::Diff, out.clone(), &keys, &[], Aggregate::Sum);
+        assert_eq!(len, 1);
+        assert_eq!(engine.zscore(&out, &Bytes::from("a")), Some(1.0));
+
+        // An empty result deletes the destination, even if it was a string
+        engine.set(out.clone(), Bytes::from("x"));
+        let missing = [Bytes::from("z1"), Bytes::from("missing")];
+        let len = engine.zset_op_store(SetOp::Inter, out.clone(), &missing, &[], Aggregate::Sum);
+        assert_eq!(len, 0);
+        assert_eq!(engine.key_type(&out), "none");
+    }
 
-        // Pop from empty list
-        assert_eq!(engine.lpop(&key), None);
-        assert_eq!(engine.rpop(&key), None);
+    #[test]
+    fn test_key_type() {
+        let engine = StorageEngine::new();
 
-        // Create list: a, b, c
-        engine.rpush(
-            key.clone(),
-            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")],
-        );
+        // Non-existent key
+        assert_eq!(engine.key_type(&Bytes::from("nonexistent")), "none");
 
-        // LPOP
-        assert_eq!(engine.lpop(&key), Some(Bytes::from("a")));
-        assert_eq!(engine.llen(&key), 2);
+        // String key
+        engine.set(Bytes::from("string_key"), Bytes::from("value"));
+        assert_eq!(engine.key_type(&Bytes::from("string_key")), "string");
 
-        // RPOP
-        assert_eq!(engine.rpop(&key), Some(Bytes::from("c")));
-        assert_eq!(engine.llen(&key), 1);
+        // List key
+        engine.rpush(Bytes::from("list_key"), vec![Bytes::from("a")]);
+        assert_eq!(engine.key_type(&Bytes::from("list_key")), "list");
 
-        // Pop last element
-        assert_eq!(engine.lpop(&key), Some(Bytes::from("b")));
-        assert_eq!(engine.llen(&key), 0);
+        // Hash key
+        engine.hset(
+            Bytes::from("hash_key"),
+            vec![(Bytes::from("f"), Bytes::from("v"))],
+        );
+        assert_eq!(engine.key_type(&Bytes::from("hash_key")), "hash");
+
+        // Set key
+        engine.sadd(Bytes::from("set_key"), vec![Bytes::from("m")]);
+        assert_eq!(engine.key_type(&Bytes::from("set_key")), "set");
+
+        // Sorted set key
+        engine
+            .zadd(
+                Bytes::from("zset_key"),
+                ZAddFlags::default(),
+                vec![(1.0, Bytes::from("m"))],
+            )
+            .unwrap();
+        assert_eq!(engine.key_type(&Bytes::from("zset_key")), "zset");
+    }
 
-        // List should be auto-deleted when empty
-        assert!(!engine.list_exists(&key));
+    #[test]
+    fn test_unlink() {
+        let engine = StorageEngine::new();
+        let big = Bytes::from("big");
+        let small = Bytes::from("small");
+        let values: Vec<Bytes> = (0..10_000).map(|i| Bytes::from(i.to_string())).collect();
+        engine.rpush(big.clone(), values);
+        engine.sadd(small.clone(), vec![Bytes::from("m")]);
+
+        let keys = [big.clone(), small.clone(), Bytes::from("missing")];
+        assert_eq!(engine.unlink(&keys), 2);
+        assert!(!engine.exists(&big));
+        assert!(!engine.exists(&small));
+        assert!(engine.is_empty());
+
+        // Only the large value goes to the background thread
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while engine.lazyfreed() < 1 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(engine.lazyfreed(), 1);
+        assert_eq!(engine.lazyfree_pending(), 0);
     }
 
     #[test]
-    fn test_llen() {
+    fn test_lazy_free_on_delete_and_overwrite() {
         let engine = StorageEngine::new();
-        let key = Bytes::from("mylist");
+        let values: Vec<Bytes> = (0..10_000).map(|i| Bytes::from(i.to_string())).collect();
+        let wait_for = |freed| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while engine.lazyfreed() < freed && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(engine.lazyfreed(), freed);
+        };
 
-        // Empty/non-existent list
-        assert_eq!(engine.llen(&key), 0);
+        // DEL of a large list
+        engine.rpush(Bytes::from("list"), values.clone());
+        assert!(engine.delete(&Bytes::from("list")));
+        wait_for(1);
+
+        // SET over a large list
+        engine.rpush(Bytes::from("list"), values);
+        engine.set(Bytes::from("list"), Bytes::from("value"));
+        wait_for(2);
+
+        // A huge string overwritten, then a small one deleted inline
+        let huge = Bytes::from(vec![0u8; 8 << 20]);
+        engine.set(Bytes::from("string"), huge);
+        engine.set(Bytes::from("string"), Bytes::from("value"));
+        wait_for(3);
+        assert!(engine.delete(&Bytes::from("string")));
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(engine.lazyfreed(), 3);
+        assert_eq!(engine.lazyfree_pending(), 0);
+    }
 
-        engine.rpush(
-            key.clone(),
-            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")],
-        );
-        assert_eq!(engine.llen(&key), 3);
+    #[test]
+    fn test_capacity() {
+        let engine = StorageEngine::with_shards(1, 4);
+        let fill = |n: usize| {
+            for i in 0..n {
+                engine.set(Bytes::from(format!("key:{}", i)), Bytes::from("v"));
+            }
+        };
+        assert_eq!(engine.capacity(), 0);
+
+        // Reserving only pre-sizes the current tables
+        engine.reserve(1000);
+        assert!(engine.capacity() >= 1000);
+        fill(2000);
+        engine.flush();
+        assert_eq!(engine.capacity(), 0);
+
+        // Expected keys are room kept across flushes, sync or not
+        engine.set_expected_keys(1000);
+        let expected = engine.capacity();
+        assert!(expected >= 1000);
+        fill(5000);
+        assert!(engine.capacity() > expected);
+        engine.flush();
+        assert_eq!(engine.capacity(), expected);
+        fill(5000);
+        engine.flush_async();
+        assert_eq!(engine.capacity(), expected);
+        assert!(engine.is_empty());
     }
 
     #[test]
-    fn test_lindex() {
+    fn test_flush_async() {
         let engine = StorageEngine::new();
-        let key = Bytes::from("mylist");
-
-        engine.rpush(
-            key.clone(),
-            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")],
-        );
+        let db1 = engine.db(1).unwrap();
+        engine.set(Bytes::from("key"), Bytes::from("value"));
+        db1.set(Bytes::from("key"), Bytes::from("value"));
 
-        // Positive indices
-        assert_eq!(engine.lindex(&key, 0), Some(Bytes::from("a")));
-        assert_eq!(engine.lindex(&key, 1), Some(Bytes::from("b")));
-        assert_eq!(engine.lindex(&key, 2), Some(Bytes::from("c")));
+        // The keys are gone at once, their shards freed in the background
+        engine.flush_all_async();
+        assert!(engine.is_empty());
+        assert!(db1.is_empty());
+        assert!(!db1.exists(&Bytes::from("key")));
 
-        // Negative indices
-        assert_eq!(engine.lindex(&key, -1), Some(Bytes::from("c")));
-        assert_eq!(engine.lindex(&key, -2), Some(Bytes::from("b")));
-        assert_eq!(engine.lindex(&key, -3), Some(Bytes::from("a")));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while engine.lazyfreed() < 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(engine.lazyfreed(), 2);
 
-        // Out of range
-        assert_eq!(engine.lindex(&key, 3), None);
-        assert_eq!(engine.lindex(&key, -4), None);
+        engine.set(Bytes::from("key"), Bytes::from("again"));
+        assert_eq!(engine.get(&Bytes::from("key")), Some(Bytes::from("again")));
     }
 
     #[test]
-    fn test_lrange() {
+    fn test_del_exists_keys_all_types() {
         let engine = StorageEngine::new();
-        let key = Bytes::from("mylist");
-
-        engine.rpush(
-            key.clone(),
-            vec![
-                Bytes::from("a"),
-                Bytes::from("b"),
-                Bytes::from("c"),
-                Bytes::from("d"),
-                Bytes::from("e"),
-            ],
-        );
+        let keys = [
+            Bytes::from("t:str"),
+            Bytes::from("t:list"),
+            Bytes::from("t:hash"),
+            Bytes::from("t:set"),
+            Bytes::from("t:zset"),
+        ];
+
+        engine.set(keys[0].clone(), Bytes::from("v"));
+        engine.rpush(keys[1].clone(), vec![Bytes::from("a")]);
+        engine.hset(keys[2].clone(), vec![(Bytes::from("f"), Bytes::from("v"))]);
+        engine.sadd(keys[3].clone(), vec![Bytes::from("m")]);
+        engine
+            .zadd(
+                keys[4].clone(),
+                ZAddFlags::default(),
+                vec![(1.0, Bytes::from("m"))],
+            )
+            .unwrap();
+        engine.set(Bytes::from("other"), Bytes::from("v"));
+
+        let mut found = engine.keys(b"t:*");
+        found.sort();
+        let mut expected = keys.to_vec();
+        expected.sort();
+        assert_eq!(found, expected);
+        assert_eq!(engine.exists_many(&keys), 5);
+
+        assert_eq!(engine.delete_many(&keys), 5);
+        assert_eq!(engine.exists_many(&keys), 0);
+        assert!(engine.keys(b"t:*").is_empty());
+        assert_eq!(engine.len(), 1);
 
-        // Full range
-        assert_eq!(
-            engine.lrange(&key, 0, -1),
-            vec![
-                Bytes::from("a"),
-                Bytes::from("b"),
-                Bytes::from("c"),
-                Bytes::from("d"),
-                Bytes::from("e"),
-            ]
-        );
+        // Deleting an expired key reports nothing deleted
+        engine.rpush(keys[1].clone(), vec![Bytes::from("a")]);
+        engine.expire(&keys[1], Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(!engine.exists(&keys[1]));
+        assert!(!engine.delete(&keys[1]));
+        assert_eq!(engine.len(), 1);
+        assert_eq!(engine.stats().expired, 1);
+    }
 
-        // Partial range
-        assert_eq!(
-            engine.lrange(&key, 1, 3),
-            vec![Bytes::from("b"), Bytes::from("c"), Bytes::from("d")]
-        );
+    #[test]
+    fn test_object_introspection() {
+        let engine = StorageEngine::new();
+        let encoding = |key: &str| engine.object_encoding(&Bytes::from(key.to_string()));
+
+        engine.set(Bytes::from("int"), Bytes::from("12345"));
+        engine.set(Bytes::from("short"), Bytes::from("hello"));
+        engine.set(Bytes::from("long"), Bytes::from("x".repeat(100)));
+        engine.rpush(Bytes::from("list"), vec![Bytes::from("a")]);
+        engine.sadd(Bytes::from("set"), vec![Bytes::from("a")]);
+
+        assert_eq!(encoding("int"), Some("int"));
+        assert_eq!(encoding("short"), Some("embstr"));
+        assert_eq!(encoding("long"), Some("raw"));
+        assert_eq!(encoding("list"), Some("quicklist"));
+        assert_eq!(encoding("set"), Some("hashtable"));
+        assert_eq!(encoding("missing"), None);
+
+        let key = Bytes::from("list");
+        assert!(engine.object_idletime(&key).unwrap() < Duration::from_secs(1));
+        assert_eq!(engine.object_freq(&key), Some(LFU_INIT_VAL));
+
+        // Frequent access raises the counter, logarithmically
+        for _ in 0..1000 {
+            engine.llen(&key);
+            engine.lpush(key.clone(), vec![Bytes::from("b")]);
+        }
+        let freq = engine.object_freq(&key).unwrap();
+        assert!(freq > LFU_INIT_VAL && freq < 100, "freq {}", freq);
+    }
 
-        // Negative indices
-        assert_eq!(
-            engine.lrange(&key, -3, -1),
-            vec![Bytes::from("c"), Bytes::from("d"), Bytes::from("e")]
-        );
+    #[test]
+    fn test_touch_and_get_refresh_access_time() {
+        let engine = StorageEngine::new();
+        let str_key = Bytes::from("str");
+        let list_key = Bytes::from("list");
+        engine.set(str_key.clone(), Bytes::from("v"));
+        engine.rpush(list_key.clone(), vec![Bytes::from("a")]);
+
+        // Idle times are measured on the coarse clock, which may lag
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(engine.object_idletime(&str_key).unwrap() >= Duration::from_millis(30));
+
+        // GET and collection reads count as accesses, OBJECT doesn't
+        engine.get(&str_key);
+        engine.llen(&list_key);
+        assert!(engine.object_idletime(&str_key).unwrap() < Duration::from_millis(30));
+        assert!(engine.object_idletime(&list_key).unwrap() < Duration::from_millis(30));
+
+        std::thread::sleep(Duration::from_millis(30));
+        let keys = [str_key.clone(), list_key.clone(), Bytes::from("missing")];
+        assert_eq!(engine.touch(&keys), 2);
+        assert!(engine.object_idletime(&str_key).unwrap() < Duration::from_millis(30));
+        assert!(engine.object_idletime(&list_key).unwrap() < Duration::from_millis(30));
+    }
 
-        // Out of range (should clamp)
-        assert_eq!(
-            engine.lrange(&key, 0, 100),
-            vec![
-                Bytes::from("a"),
-                Bytes::from("b"),
-                Bytes::from("c"),
-                Bytes::from("d"),
-                Bytes::from("e"),
-            ]
-        );
+    #[test]
+    fn test_entry_frequency_decay() {
+        let mut entry = Entry::new(Bytes::from("v"));
+        entry.access.lfu_counter.store(20, Ordering::Relaxed);
+        entry.created_at = clock::coarse_now() - Duration::from_secs(3 * 60);
+        assert_eq!(entry.frequency(), 17);
+        assert!(entry.idle_time() >= Duration::from_secs(3 * 60));
+
+        entry.touch();
+        assert!(entry.idle_time() < Duration::from_secs(1));
+        assert!(entry.frequency() >= 17);
+    }
 
-        // Invalid range (start > stop)
-        assert_eq!(engine.lrange(&key, 3, 1), Vec::<Bytes>::new());
+    /// Runs a full SCAN, calling `between` after every call.
+    fn full_scan(
+        engine: &StorageEngine,
+        count: usize,
+        mut between: impl FnMut(usize),
+    ) -> Vec<Bytes> {
+        let mut cursor = 0;
+        let mut keys = Vec::new();
+        let mut calls = 0;
+        loop {
+            let (next, batch) = engine.scan(cursor, count, None, None);
+            keys.extend(batch);
+            calls += 1;
+            between(calls);
+            if next == 0 {
+                return keys;
+            }
+            cursor = next;
+        }
     }
 
     #[test]
-    fn test_lset() {
+    fn test_scan() {
         let engine = StorageEngine::new();
-        let key = Bytes::from("mylist");
+        for i in 0..500 {
+            engine.set(Bytes::from(format!("key:{}", i)), Bytes::from("v"));
+        }
+        engine.rpush(Bytes::from("list"), vec![Bytes::from("a")]);
+
+        for count in [1, 7, 10, 1000] {
+            let mut keys = full_scan(&engine, count, |_| {});
+            assert_eq!(keys.len(), 501, "count {}", count);
+            keys.sort();
+            keys.dedup();
+            assert_eq!(keys.len(), 501, "duplicates with count {}", count);
+        }
 
-        engine.rpush(
-            key.clone(),
-            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")],
+        // MATCH and TYPE filter the examined keys
+        let (_, keys) = engine.scan(0, 10_000, Some(b"key:1?"), None);
+        assert_eq!(keys.len(), 10);
+        let (cursor, keys) = engine.scan(0, 10_000, None, Some("list"));
+        assert_eq!((cursor, keys), (0, vec![Bytes::from("list")]));
+
+        let empty = StorageEngine::new();
+        assert_eq!(empty.scan(0, 10, None, None), (0, Vec::new()));
+    }
+
+    #[test]
+    fn test_shard_count() {
+        assert!(default_shard_count().is_power_of_two());
+        assert_eq!(StorageEngine::with_shards(1, 0).shard_count(), 1);
+        assert_eq!(StorageEngine::with_shards(1, 100).shard_count(), 128);
+        assert_eq!(
+            StorageEngine::with_shards(1, usize::MAX).shard_count(),
+            MAX_SHARDS
         );
 
-        // Set at valid index
-        assert!(engine.lset(&key, 1, Bytes::from("B")).is_ok());
-        assert_eq!(engine.lindex(&key, 1), Some(Bytes::from("B")));
+        let engine = StorageEngine::with_shards(2, 8);
+        assert_eq!(engine.db(1).unwrap().shard_count(), 8);
 
-        // Set with negative index
-        assert!(engine.lset(&key, -1, Bytes::from("C")).is_ok());
-        assert_eq!(engine.lindex(&key, -1), Some(Bytes::from("C")));
+        // SCAN covers every key whatever the shard count, including a
+        // single shard whose cursor is all position bits
+        for shards in [1, 2, 1024] {
+            let engine = StorageEngine::with_shards(1, shards);
+            for i in 0..200 {
+                engine.set(Bytes::from(format!("key:{}", i)), Bytes::from("v"));
+            }
+            let mut keys = full_scan(&engine, 7, |_| {});
+            keys.sort();
+            keys.dedup();
+            assert_eq!(keys.len(), 200, "{} shards", shards);
+        }
+    }
 
-        // Out of range
-        assert!(engine.lset(&key, 10, Bytes::from("X")).is_err());
+    #[test]
+    fn test_bucket_locking() {
+        let engine = Arc::new(StorageEngine::with_locking(2, 4096, ShardLocking::Bucket));
+        assert_eq!(engine.locking(), ShardLocking::Bucket);
+        assert_eq!(engine.db(1).unwrap().shard_count(), 4096);
+        assert_eq!(StorageEngine::new().locking(), ShardLocking::ReadWrite);
+
+        // Multi-key commands lock each bucket once, in ascending order,
+        // while other threads write to the same keys
+        let threads: Vec<_> = (0..4)
+            .map(|t| {
+                let engine = Arc::clone(&engine);
+                std::thread::spawn(move || {
+                    for i in 0..2_000 {
+                        let key = Bytes::from(format!("key:{}", (i + t) % 20));
+                        let other = Bytes::from(format!("key:{}", (i * 7) % 20));
+                        match i % 5 {
+                            0 => engine
+                                .mset(vec![(key, Bytes::from("v")), (other, Bytes::from("w"))]),
+                            1 => {
+                                engine.mget(&[key, other]);
+                            }
+                            2 => {
+                                engine.rename(&key, other, false);
+                            }
+                            3 => {
+                                engine.set_op_store(SetOp::Union, key, &[other]);
+                            }
+                            _ => {
+                                engine.delete(&key);
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(engine.len(), engine.keys(b"*").len() as u64);
+    }
 
-        // Non-existent key
-        let nonexistent = Bytes::from("nonexistent");
-        assert!(engine.lset(&nonexistent, 0, Bytes::from("X")).is_err());
+    #[test]
+    fn test_hash_tags_share_a_shard() {
+        let engine = StorageEngine::new();
+        let shard = engine.shard_index(b"{user:1}:profile");
+        assert_eq!(engine.shard_index(b"{user:1}:sessions"), shard);
+        assert_eq!(engine.shard_index(b"user:1"), shard);
+        // An empty tag doesn't count
+        assert_ne!(engine.shard_index(b"{}:a"), engine.shard_index(b"{}:b"),);
+
+        // Keys crowded into one shard still scan without duplicates
+        for i in 0..200 {
+            engine.set(Bytes::from(format!("{{tag}}:{}", i)), Bytes::from("v"));
+        }
+        let mut keys = full_scan(&engine, 3, |_| {});
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 200);
     }
 
     #[test]
-    fn test_lrem() {
+    fn test_scan_stable_under_writes() {
         let engine = StorageEngine::new();
-        let key = Bytes::from("mylist");
+        for i in 0..300 {
+            engine.set(Bytes::from(format!("stable:{}", i)), Bytes::from("v"));
+            engine.set(Bytes::from(format!("doomed:{}", i)), Bytes::from("v"));
+        }
 
-        // Create list: a, b, a, c, a, d
-        engine.rpush(
-            key.clone(),
-            vec![
-                Bytes::from("a"),
-                Bytes::from("b"),
-                Bytes::from("a"),
-                Bytes::from("c"),
-                Bytes::from("a"),
-                Bytes::from("d"),
-            ],
-        );
+        // Grow the maps (forcing rehashes) and delete keys between calls
+        let mut next = 0;
+        let keys = full_scan(&engine, 20, |call| {
+            for _ in 0..10 {
+                engine.set(Bytes::from(format!("new:{}", next)), Bytes::from("v"));
+                next += 1;
+            }
+            engine.delete(&Bytes::from(format!("doomed:{}", call)));
+        });
 
-        // Remove 2 occurrences of "a" from head
-        assert_eq!(engine.lrem(&key, 2, &Bytes::from("a")), 2);
-        // List should be: b, c, a, d
-        assert_eq!(
-            engine.lrange(&key, 0, -1),
-            vec![
-                Bytes::from("b"),
-                Bytes::from("c"),
-                Bytes::from("a"),
-                Bytes::from("d"),
-            ]
-        );
+        let mut seen = HashSet::new();
+        for key in &keys {
+            assert!(seen.insert(key.clone()), "{:?} returned twice", key);
+        }
+        for i in 0..300 {
+            assert!(seen.contains(&Bytes::from(format!("stable:{}", i))));
+        }
+    }
 
-        // Remove from tail (negative count)
-        engine.flush();
-        engine.rpush(
-            key.clone(),
-            vec![
-                Bytes::from("a"),
-                Bytes::from("b"),
-                Bytes::from("a"),
-                Bytes::from("c"),
-                Bytes::from("a"),
-            ],
-        );
-        assert_eq!(engine.lrem(&key, -2, &Bytes::from("a")), 2);
-        // List should be: a, b, c (removed last two "a"s)
-        assert_eq!(
-            engine.lrange(&key, 0, -1),
-            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")]
-        );
+    #[test]
+    fn test_scan_order_is_reused() {
+        let engine = StorageEngine::with_shards(1, 1);
+        for i in 0..100 {
+            engine.set(Bytes::from(format!("key:{}", i)), Bytes::from("v"));
+        }
+        let order = || engine.shards[0].scan_order.lock().clone().unwrap();
+
+        // Calls within the shard share one order, whatever changes
+        let (cursor, keys) = engine.scan(0, 10, None, None);
+        assert_eq!(keys.len(), 10);
+        let first = order();
+        assert_eq!(first.keys.len(), 100);
+        engine.delete(&keys[0]);
+        engine.set(Bytes::from("late"), Bytes::from("v"));
+        let (cursor, keys) = engine.scan(cursor, 10, None, None);
+        assert_eq!(keys.len(), 10);
+        assert!(Arc::ptr_eq(&first, &order()));
+
+        // A scan starting over sees the keys added since
+        let (_, keys) = engine.scan(0, 1000, None, None);
+        assert_eq!(keys.len(), 100);
+        assert!(keys.contains(&Bytes::from("late")));
+        let second = order();
+        assert!(!Arc::ptr_eq(&first, &second));
+        engine.scan(cursor, 10, None, None);
+        assert!(Arc::ptr_eq(&second, &order()));
+
+        // Without new keys, even a new scan keeps the order
+        engine.delete(&Bytes::from("late"));
+        let (_, keys) = engine.scan(0, 1000, None, None);
+        assert_eq!(keys.len(), 99);
+        assert!(Arc::ptr_eq(&second, &order()));
 
-        // Remove all (count = 0)
         engine.flush();
-        engine.rpush(
-            key.clone(),
-            vec![Bytes::from("a"), Bytes::from("a"), Bytes::from("a")],
-        );
-        assert_eq!(engine.lrem(&key, 0, &Bytes::from("a")), 3);
-        // List should be empty and auto-deleted
-        assert!(!engine.list_exists(&key));
+        assert!(engine.shards[0].scan_order.lock().is_none());
     }
 
     #[test]
-    fn test_key_type() {
+    fn test_unified_keyspace() {
         let engine = StorageEngine::new();
+        let list = Bytes::from("list");
+        let hash = Bytes::from("hash");
+
+        engine.set(Bytes::from("str"), Bytes::from("v"));
+        engine.rpush(list.clone(), vec![Bytes::from("a"), Bytes::from("b")]);
+        engine.hset(hash.clone(), vec![(Bytes::from("f"), Bytes::from("v"))]);
+
+        // Every type counts towards the key count and shows up in KEYS
+        assert_eq!(engine.len(), 3);
+        assert_eq!(engine.keys(b"*").len(), 3);
+
+        // Key-level operations work regardless of type
+        assert!(engine.expire(&list, Duration::from_secs(100)));
+        assert!(engine.ttl(&list).unwrap() > 0);
+        assert!(engine.persist(&list));
+        assert_eq!(engine.ttl(&list), Some(-1));
+        assert!(engine.delete(&hash));
+        assert_eq!(engine.key_type(&hash), "none");
+        assert_eq!(engine.len(), 2);
 
-        // Non-existent key
-        assert_eq!(engine.key_type(&Bytes::from("nonexistent")), "none");
-
-        // String key
-        engine.set(Bytes::from("string_key"), Bytes::from("value"));
-        assert_eq!(engine.key_type(&Bytes::from("string_key")), "string");
+        // Emptying a collection removes the key
+        engine.lpop(&list);
+        engine.lpop(&list);
+        assert!(!engine.exists(&list));
+        assert_eq!(engine.len(), 1);
 
-        // List key
-        engine.rpush(Bytes::from("list_key"), vec![Bytes::from("a")]);
-        assert_eq!(engine.key_type(&Bytes::from("list_key")), "list");
+        // Writing a string over a collection replaces it
+        engine.sadd(Bytes::from("set"), vec![Bytes::from("m")]);
+        engine.set(Bytes::from("set"), Bytes::from("now a string"));
+        assert_eq!(engine.key_type(&Bytes::from("set")), "string");
+        assert_eq!(engine.scard(&Bytes::from("set")), 0);
+
+        // String commands refuse to touch other types
+        engine.rpush(list.clone(), vec![Bytes::from("a")]);
+        assert_eq!(engine.incr(&list), Err(WRONGTYPE));
+        assert_eq!(engine.append(&list, &Bytes::from("x")), Err(WRONGTYPE));
+        assert_eq!(engine.get(&list), None);
+
+        // Expired collections disappear like strings do
+        engine.expire(&list, Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(engine.llen(&list), 0);
+        assert_eq!(engine.cleanup_expired(), 1);
+        engine.flush();
+        assert!(engine.is_empty());
     }
 }
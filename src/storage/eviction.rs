@@ -0,0 +1,362 @@
+//! Key Eviction and Access Frequency
+//!
+//! With `maxmemory` set, the server evicts keys before running a write once
+//! the memory it accounts for goes over the limit. `maxmemory-policy` picks
+//! which keys may go:
+//!
+//! | Policy         | Evicts                                              |
+//! |----------------|-----------------------------------------------------|
+//! | `noeviction`   | Nothing; writes that may use more memory fail (OOM) |
+//! | `allkeys-lfu`  | The least frequently used keys                      |
+//! | `volatile-lfu` | The least frequently used keys with an expiry       |
+//!
+//! ## Access Frequency
+//!
+//! Every entry has an 8-bit logarithmic access counter, as in Redis. A new
+//! key starts at [`LFU_INIT_VAL`], and each access bumps the counter with a
+//! probability of `1 / ((counter - LFU_INIT_VAL) * lfu-log-factor + 1)`, so
+//! the higher it is, the more accesses the next step takes. The counter
+//! drops by one for every `lfu-decay-time` minutes the key sits idle, so
+//! keys that were popular once don't stay ahead forever. Both settings are
+//! the process's (accesses are recorded without reference to the server),
+//! read through [`lfu_log_factor`] and [`lfu_decay_time`].
+//!
+//! ## Sampling
+//!
+//! Finding the least frequently used key exactly would mean ranking the
+//! whole keyspace. Instead, like Redis, each eviction samples
+//! [`EVICTION_SAMPLES`] random keys of every database, each from a random
+//! shard, and offers them to a pool of the [`EVICTION_POOL_SIZE`] best
+//! candidates seen so far. The key with the lowest counter in the pool,
+//! the longest idle among equals, is evicted. The pool outlives each
+//! eviction, so good candidates found by one sample are still there for
+//! the next evictions.
+//!
+//! While evicting, the memory used is added up once and then lowered by
+//! what each evicted key frees, rather than added up again over every
+//! shard of every database for each key.
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::time::Duration;
+
+/// Initial access-frequency counter of new keys, so they aren't
+/// immediately the least frequently used.
+pub const LFU_INIT_VAL: u8 = 5;
+
+/// Default `lfu-log-factor`: about a million accesses saturate the counter.
+pub const DEFAULT_LFU_LOG_FACTOR: u32 = 10;
+
+/// Default `lfu-decay-time`, in minutes.
+pub const DEFAULT_LFU_DECAY_TIME: u64 = 1;
+
+/// Keys sampled from each database for each eviction.
+pub const EVICTION_SAMPLES: usize = 5;
+
+/// Candidates kept between evictions (the same as Redis).
+pub const EVICTION_POOL_SIZE: usize = 16;
+
+/// How quickly the access-frequency counter saturates (higher = slower).
+static LFU_LOG_FACTOR: AtomicU32 = AtomicU32::new(DEFAULT_LFU_LOG_FACTOR);
+
+/// Idle minutes per decrement of the access-frequency counter (0 = never).
+static LFU_DECAY_TIME: AtomicU64 = AtomicU64::new(DEFAULT_LFU_DECAY_TIME);
+
+/// Returns the `lfu-log-factor` setting.
+pub fn lfu_log_factor() -> u32 {
+    LFU_LOG_FACTOR.load(Ordering::Relaxed)
+}
+
+/// Changes the `lfu-log-factor` setting.
+pub fn set_lfu_log_factor(factor: u32) {
+    LFU_LOG_FACTOR.store(factor, Ordering::Relaxed);
+}
+
+/// Returns the `lfu-decay-time` setting, in minutes.
+pub fn lfu_decay_time() -> u64 {
+    LFU_DECAY_TIME.load(Ordering::Relaxed)
+}
+
+/// Changes the `lfu-decay-time` setting, in minutes (0 never decays).
+pub fn set_lfu_decay_time(minutes: u64) {
+    LFU_DECAY_TIME.store(minutes, Ordering::Relaxed);
+}
+
+/// Returns the access-frequency counter after one more access: the same,
+/// or one higher with a probability that falls as the counter grows.
+pub fn lfu_increment(counter: u8, log_factor: u32) -> u8 {
+    if counter == u8::MAX {
+        return counter;
+    }
+    let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+    let p = 1.0 / (base * log_factor as f64 + 1.0);
+    if rand::random::<f64>() < p {
+        counter + 1
+    } else {
+        counter
+    }
+}
+
+/// Returns the access-frequency counter of a key idle for `idle`, one lower
+/// for every `decay_minutes` (never lower with 0).
+pub fn lfu_decay(counter: u8, idle: Duration, decay_minutes: u64) -> u8 {
+    if decay_minutes == 0 {
+        return counter;
+    }
+    let periods = idle.as_secs() / (decay_minutes * 60);
+    counter.saturating_sub(periods.min(u8::MAX as u64) as u8)
+}
+
+/// Which keys may be evicted (`maxmemory-policy`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Never evict
+    #[default]
+    NoEviction,
+    /// Evict the least frequently used keys
+    AllKeysLfu,
+    /// Evict the least frequently used keys among those with an expiry
+    VolatileLfu,
+}
+
+impl EvictionPolicy {
+    /// Every policy, in the order they are listed to users.
+    pub const ALL: [EvictionPolicy; 3] = [
+        EvictionPolicy::NoEviction,
+        EvictionPolicy::AllKeysLfu,
+        EvictionPolicy::VolatileLfu,
+    ];
+
+    /// Parses a policy name such as `"allkeys-lfu"`, in any case.
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|policy| policy.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Returns the name of the policy.
+    pub fn name(self) -> &'static str {
+        match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileLfu => "volatile-lfu",
+        }
+    }
+
+    /// Returns whether only keys with an expiry may be evicted.
+    pub fn volatile_only(self) -> bool {
+        self == EvictionPolicy::VolatileLfu
+    }
+}
+
+impl std::fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A sampled key that may be evicted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Candidate {
+    /// Index of the key's database
+    pub db: usize,
+    pub key: Bytes,
+    /// How it ranked when sampled: the lowest access frequency goes first,
+    /// then the longest idle
+    pub rank: (u8, Reverse<Duration>),
+}
+
+/// The server's memory limit and eviction policy, shared by every database.
+#[derive(Debug, Default)]
+pub struct Eviction {
+    /// Most bytes the keyspace may use (0 = no limit)
+    maxmemory: AtomicU64,
+    /// The [`EvictionPolicy`], as its index in [`EvictionPolicy::ALL`]
+    policy: AtomicU8,
+    /// Keys evicted so far
+    evicted: AtomicU64,
+    /// The best candidates sampled so far, the best last
+    pool: Mutex<Vec<Candidate>>,
+}
+
+impl Eviction {
+    /// Creates an unlimited configuration that never evicts.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the memory limit in bytes, 0 if there is none.
+    pub fn maxmemory(&self) -> u64 {
+        self.maxmemory.load(Ordering::Relaxed)
+    }
+
+    /// Changes the memory limit in bytes (0 removes it).
+    pub fn set_maxmemory(&self, bytes: u64) {
+        self.maxmemory.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Returns the eviction policy.
+    pub fn policy(&self) -> EvictionPolicy {
+        EvictionPolicy::ALL[self.policy.load(Ordering::Relaxed) as usize]
+    }
+
+    /// Changes the eviction policy, forgetting the candidates the old one
+    /// sampled.
+    pub fn set_policy(&self, policy: EvictionPolicy) {
+        let index = EvictionPolicy::ALL
+            .iter()
+            .position(|p| *p == policy)
+            .unwrap();
+        self.policy.store(index as u8, Ordering::Relaxed);
+        self.pool.lock().clear();
+    }
+
+    /// Returns the number of keys evicted so far.
+    pub fn evicted(&self) -> u64 {
+        self.evicted.load(Ordering::Relaxed)
+    }
+
+    /// Counts a key as evicted.
+    pub(crate) fn record_evicted(&self) {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Adds a sampled key to the pool, unless the pool is full of better
+    /// candidates. A key already in the pool is re-ranked.
+    pub(crate) fn offer(&self, candidate: Candidate) {
+        let mut pool = self.pool.lock();
+        pool.retain(|c| c.db != candidate.db || c.key != candidate.key);
+        let at = pool.partition_point(|c| c.rank > candidate.rank);
+        if pool.len() == EVICTION_POOL_SIZE {
+            if at == 0 {
+                return;
+            }
+            pool.remove(0);
+            pool.insert(at - 1, candidate);
+        } else {
+            pool.insert(at, candidate);
+        }
+    }
+
+    /// Takes the best candidate out of the pool.
+    pub(crate) fn take_best(&self) -> Option<Candidate> {
+        self.pool.lock().pop()
+    }
+}
+
+/// Parses a `maxmemory` value: a size in bytes, with an optional `kb`, `mb`
+/// or `gb` suffix, or 0 for no limit.
+pub fn parse_maxmemory(value: &str) -> Option<u64> {
+    let value = value.to_ascii_lowercase();
+    let (digits, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => value.split_at(at),
+        None => (value.as_str(), ""),
+    };
+    let unit = match unit {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_names() {
+        for policy in EvictionPolicy::ALL {
+            assert_eq!(EvictionPolicy::parse(policy.name()), Some(policy));
+        }
+        assert_eq!(
+            EvictionPolicy::parse("AllKeys-LFU"),
+            Some(EvictionPolicy::AllKeysLfu)
+        );
+        assert_eq!(EvictionPolicy::parse("allkeys-lru"), None);
+
+        let eviction = Eviction::new();
+        assert_eq!(eviction.policy(), EvictionPolicy::NoEviction);
+        eviction.set_policy(EvictionPolicy::VolatileLfu);
+        assert_eq!(eviction.policy(), EvictionPolicy::VolatileLfu);
+    }
+
+    #[test]
+    fn test_candidate_pool() {
+        let eviction = Eviction::new();
+        let candidate = |key: usize, freq: u8, idle: u64| Candidate {
+            db: 0,
+            key: Bytes::from(format!("key:{}", key)),
+            rank: (freq, Reverse(Duration::from_secs(idle))),
+        };
+
+        // The least used goes first, the longest idle among equals
+        eviction.offer(candidate(1, 10, 0));
+        eviction.offer(candidate(2, 5, 1));
+        eviction.offer(candidate(3, 5, 9));
+        eviction.offer(candidate(4, 7, 0));
+        assert_eq!(eviction.take_best(), Some(candidate(3, 5, 9)));
+        assert_eq!(eviction.take_best(), Some(candidate(2, 5, 1)));
+
+        // Sampling a key again re-ranks it
+        eviction.offer(candidate(1, 1, 0));
+        assert_eq!(eviction.take_best(), Some(candidate(1, 1, 0)));
+        assert_eq!(eviction.take_best(), Some(candidate(4, 7, 0)));
+        assert_eq!(eviction.take_best(), None);
+
+        // A full pool keeps the best
+        for key in 0..EVICTION_POOL_SIZE * 2 {
+            eviction.offer(candidate(key, key as u8, 0));
+        }
+        eviction.offer(candidate(100, u8::MAX, 0));
+        let mut taken = Vec::new();
+        while let Some(c) = eviction.take_best() {
+            taken.push(c.rank.0 as usize);
+        }
+        assert_eq!(taken, (0..EVICTION_POOL_SIZE).collect::<Vec<_>>());
+
+        // A new policy starts from an empty pool
+        eviction.offer(candidate(1, 1, 0));
+        eviction.set_policy(EvictionPolicy::AllKeysLfu);
+        assert_eq!(eviction.take_best(), None);
+    }
+
+    #[test]
+    fn test_lfu_counter() {
+        // New keys climb fast, then ever more slowly
+        let mut counter = LFU_INIT_VAL;
+        for _ in 0..100 {
+            counter = lfu_increment(counter, DEFAULT_LFU_LOG_FACTOR);
+        }
+        assert!(
+            counter > LFU_INIT_VAL && counter < 30,
+            "counter {}",
+            counter
+        );
+        assert_eq!(lfu_increment(u8::MAX, DEFAULT_LFU_LOG_FACTOR), u8::MAX);
+
+        // A log factor of 0 counts every access
+        assert_eq!(lfu_increment(100, 0), 101);
+
+        let minute = Duration::from_secs(60);
+        assert_eq!(lfu_decay(20, minute * 3, 1), 17);
+        assert_eq!(lfu_decay(20, minute * 3, 2), 19);
+        assert_eq!(lfu_decay(20, minute * 3, 0), 20);
+        assert_eq!(lfu_decay(2, minute * 1000, 1), 0);
+    }
+
+    #[test]
+    fn test_parse_maxmemory() {
+        assert_eq!(parse_maxmemory("0"), Some(0));
+        assert_eq!(parse_maxmemory("100"), Some(100));
+        assert_eq!(parse_maxmemory("64mb"), Some(64 * 1024 * 1024));
+        assert_eq!(parse_maxmemory("1GB"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_maxmemory("1tb"), None);
+        assert_eq!(parse_maxmemory("mb"), None);
+        assert_eq!(parse_maxmemory("-1"), None);
+    }
+}
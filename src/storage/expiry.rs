@@ -143,10 +143,12 @@ async fn sweeper_loop(
         }
 
         // Get current key count before cleanup
-        let keys_before = engine.len();
+        let keys_before: u64 = engine.databases().map(StorageEngine::len).sum();
 
-        // Perform cleanup
-        let expired = engine.cleanup_expired();
+        // Perform cleanup in every database, and send replicas a DEL for
+        // each key expired
+        let expired: u64 = engine.databases().map(StorageEngine::cleanup_expired).sum();
+        engine.propagate_expired();
 
         // Adjust interval based on expiry rate
         if keys_before > 0 {
@@ -227,6 +229,37 @@ mod tests {
         assert!(engine.exists(&Bytes::from("persistent")));
     }
 
+    #[tokio::test]
+    async fn test_sweeper_cleans_expired_collections() {
+        let engine = Arc::new(StorageEngine::new());
+
+        let list = Bytes::from("list");
+        let hash = Bytes::from("hash");
+        let set = Bytes::from("set");
+        engine.rpush(list.clone(), vec![Bytes::from("a")]);
+        engine.hset(hash.clone(), vec![(Bytes::from("f"), Bytes::from("v"))]);
+        engine.sadd(set.clone(), vec![Bytes::from("m")]);
+        for key in [&list, &hash, &set] {
+            engine.expire(key, Duration::from_millis(50));
+        }
+        engine.rpush(Bytes::from("persistent"), vec![Bytes::from("a")]);
+
+        assert_eq!(engine.len(), 4);
+
+        let config = ExpiryConfig {
+            base_interval: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let _sweeper = ExpirySweeper::start(Arc::clone(&engine), config);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Reclaimed by the sweeper without ever being accessed
+        assert_eq!(engine.len(), 1);
+        assert_eq!(engine.stats().expired, 3);
+        assert_eq!(engine.key_type(&Bytes::from("persistent")), "list");
+    }
+
     #[tokio::test]
     async fn test_sweeper_stops_on_drop() {
         let engine = Arc::new(StorageEngine::new());
@@ -0,0 +1,318 @@
+//! Keyspace Export
+//!
+//! EXPORT writes the keyspace to a file in a format other tools can read,
+//! for audits or for loading data into analytics systems. Unlike a
+//! [`snapshot`](crate::storage::snapshot), an export can't be loaded back:
+//! it is meant to be read by people and by other programs.
+//!
+//! ## Formats
+//!
+//! **JSON** writes one object per line (JSON Lines):
+//!
+//! ```text
+//! {"key":"user:1","type":"hash","ttl":-1,"value":{"name":"Ariz"}}
+//! {"key":"board","type":"zset","ttl":5000,"value":[["alice",10]]}
+//! ```
+//!
+//! **CSV** writes a `key,type,ttl,value` header and one row per key. String
+//! values are written as-is; other types use the same JSON encoding as the
+//! JSON format.
+//!
+//! | Type   | Value                                           |
+//! |--------|-------------------------------------------------|
+//! | string | `"text"`                                        |
+//! | list   | `["a","b"]`, head first                         |
+//! | hash   | `{"field":"value"}`, sorted by field            |
+//! | set    | `["a","b"]`, sorted                             |
+//! | zset   | `[["member",score]]`, by score                  |
+//! | stream | `[{"id":"1-0","fields":{"f":"v"}}]`, oldest first |
+//!
+//! `ttl` is the remaining time to live in milliseconds, or -1 for keys
+//! without an expiry. Bytes that aren't valid UTF-8 are replaced with U+FFFD,
+//! and infinite scores are written as the strings `"inf"` and `"-inf"`.
+
+use crate::storage::engine::{Entry, Value};
+use crate::storage::glob::GlobPattern;
+use crate::storage::snapshot::Snapshot;
+use crate::storage::stream::StreamId;
+use bytes::Bytes;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// The file format written by EXPORT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One JSON object per line
+    Json,
+    /// Comma-separated values with a header row
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "JSON" => Ok(ExportFormat::Json),
+            "CSV" => Ok(ExportFormat::Csv),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Writes the keys of `snapshot` matching `pattern` (all keys if `None`)
+/// to `writer`.
+///
+/// # Returns
+///
+/// The number of keys written.
+pub fn write<W: Write>(
+    snapshot: &Snapshot,
+    format: ExportFormat,
+    pattern: Option<&[u8]>,
+    writer: &mut W,
+) -> io::Result<usize> {
+    let pattern = pattern.map(GlobPattern::new);
+    let mut line = String::new();
+    let mut keys = 0;
+
+    if format == ExportFormat::Csv {
+        writer.write_all(b"key,type,ttl,value\r\n")?;
+    }
+
+    for (key, entry) in snapshot.iter() {
+        if pattern.as_ref().is_some_and(|p| !p.matches(key)) {
+            continue;
+        }
+
+        let ttl = entry
+            .expire_time_ms()
+            .map_or(-1, |unix_ms| (unix_ms - snapshot.taken_at_ms()).max(0));
+
+        line.clear();
+        match format {
+            ExportFormat::Json => {
+                line.push_str("{\"key\":");
+                json_string(&mut line, key);
+                let _ = write!(line, ",\"type\":\"{}\"", entry.value.type_name());
+                let _ = write!(line, ",\"ttl\":{},\"value\":", ttl);
+                json_value(&mut line, entry);
+                line.push_str("}\n");
+            }
+            ExportFormat::Csv => {
+                csv_field(&mut line, &String::from_utf8_lossy(key));
+                let _ = write!(line, ",{},{},", entry.value.type_name(), ttl);
+                match &*entry.value {
+                    Value::String(s) => {
+                        csv_field(&mut line, &String::from_utf8_lossy(&s.as_bytes()))
+                    }
+                    _ => {
+                        let mut value = String::new();
+                        json_value(&mut value, entry);
+                        csv_field(&mut line, &value);
+                    }
+                }
+                line.push_str("\r\n");
+            }
+        }
+        writer.write_all(line.as_bytes())?;
+        keys += 1;
+    }
+
+    writer.flush()?;
+    Ok(keys)
+}
+
+/// Appends the JSON encoding of an entry's value.
+fn json_value(out: &mut String, entry: &Entry) {
+    match &*entry.value {
+        Value::String(s) => json_string(out, &s.as_bytes()),
+        Value::List(list) => json_array(out, list.iter()),
+        Value::Set(set) => {
+            let mut members: Vec<_> = set.iter().collect();
+            members.sort();
+            json_array(out, members.into_iter());
+        }
+        Value::Hash(hash) => {
+            let mut fields: Vec<_> = hash.iter().collect();
+            fields.sort();
+            json_object(out, fields.into_iter());
+        }
+        Value::ZSet(zset) => {
+            out.push('[');
+            for (i, (member, score)) in zset.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push('[');
+                json_string(out, member);
+                out.push(',');
+                json_number(out, score);
+                out.push(']');
+            }
+            out.push(']');
+        }
+        Value::Stream(stream) => {
+            out.push('[');
+            let records = stream.range(StreamId::MIN, StreamId::MAX, None, false);
+            for (i, (id, fields)) in records.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                let _ = write!(out, "{{\"id\":\"{}\",\"fields\":", id);
+                json_object(out, fields.iter().map(|(f, v)| (f, v)));
+                out.push('}');
+            }
+            out.push(']');
+        }
+    }
+}
+
+fn json_array<'a>(out: &mut String, items: impl Iterator<Item = &'a Bytes>) {
+    out.push('[');
+    for (i, item) in items.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        json_string(out, item);
+    }
+    out.push(']');
+}
+
+fn json_object<'a>(out: &mut String, pairs: impl Iterator<Item = (&'a Bytes, &'a Bytes)>) {
+    out.push('{');
+    for (i, (field, value)) in pairs.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        json_string(out, field);
+        out.push(':');
+        json_string(out, value);
+    }
+    out.push('}');
+}
+
+fn json_number(out: &mut String, n: f64) {
+    if n.is_finite() {
+        let _ = write!(out, "{}", n);
+    } else if n > 0.0 {
+        out.push_str("\"inf\"");
+    } else {
+        out.push_str("\"-inf\"");
+    }
+}
+
+/// Appends `bytes` as a quoted JSON string.
+fn json_string(out: &mut String, bytes: &[u8]) {
+    out.push('"');
+    for c in String::from_utf8_lossy(bytes).chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Appends a CSV field, quoting it if needed (RFC 4180).
+fn csv_field(out: &mut String, field: &str) {
+    if field.contains([',', '"', '\r', '\n']) {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(field);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::zset::ZAddFlags;
+    use crate::storage::StorageEngine;
+    use std::time::Duration;
+
+    fn export(engine: &StorageEngine, format: ExportFormat, pattern: Option<&[u8]>) -> String {
+        let mut out = Vec::new();
+        write(&engine.snapshot(), format, pattern, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_export_json() {
+        let engine = StorageEngine::new();
+        engine.set(Bytes::from("greeting"), Bytes::from("say \"hi\"\n"));
+        engine.rpush(
+            Bytes::from("list"),
+            vec![Bytes::from("a"), Bytes::from("b")],
+        );
+        engine.sadd(Bytes::from("set"), vec![Bytes::from("y"), Bytes::from("x")]);
+        engine.hset(
+            Bytes::from("hash"),
+            vec![(Bytes::from("name"), Bytes::from("Ariz"))],
+        );
+        engine
+            .zadd(
+                Bytes::from("zset"),
+                ZAddFlags::default(),
+                vec![(2.5, Bytes::from("b")), (f64::INFINITY, Bytes::from("a"))],
+            )
+            .unwrap();
+
+        let out = export(&engine, ExportFormat::Json, None);
+        let mut lines: Vec<_> = out.lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"key":"greeting","type":"string","ttl":-1,"value":"say \"hi\"\n"}"#,
+                r#"{"key":"hash","type":"hash","ttl":-1,"value":{"name":"Ariz"}}"#,
+                r#"{"key":"list","type":"list","ttl":-1,"value":["a","b"]}"#,
+                r#"{"key":"set","type":"set","ttl":-1,"value":["x","y"]}"#,
+                r#"{"key":"zset","type":"zset","ttl":-1,"value":[["b",2.5],["a","inf"]]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_export_csv_with_pattern_and_ttl() {
+        let engine = StorageEngine::new();
+        engine.set_with_ttl(
+            Bytes::from("user:1"),
+            Bytes::from("a,b"),
+            Duration::from_secs(100),
+        );
+        engine.rpush(Bytes::from("user:2"), vec![Bytes::from("x")]);
+        engine.set(Bytes::from("other"), Bytes::from("skipped"));
+
+        let out = export(&engine, ExportFormat::Csv, Some(b"user:*"));
+        let mut lines = out.split("\r\n");
+        assert_eq!(lines.next(), Some("key,type,ttl,value"));
+        let mut rows: Vec<_> = lines.filter(|l| !l.is_empty()).collect();
+        rows.sort();
+        assert_eq!(rows.len(), 2);
+        let ttl: i64 = rows[0]
+            .strip_prefix("user:1,string,")
+            .and_then(|rest| rest.strip_suffix(",\"a,b\""))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(ttl > 99_000 && ttl <= 100_000);
+        assert_eq!(rows[1], r#"user:2,list,-1,"[""x""]""#);
+    }
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!("json".parse(), Ok(ExportFormat::Json));
+        assert_eq!("CSV".parse(), Ok(ExportFormat::Csv));
+        assert_eq!("xml".parse::<ExportFormat>(), Err(()));
+    }
+}
@@ -0,0 +1,312 @@
+//! Glob-Style Pattern Matching
+//!
+//! KEYS, SCAN MATCH, EXPORT MATCH, CONFIG GET and pub/sub patterns all use
+//! Redis's glob syntax:
+//!
+//! | Syntax    | Matches                                          |
+//! |-----------|--------------------------------------------------|
+//! | `*`       | Any run of bytes, including none                 |
+//! | `?`       | Any one byte                                     |
+//! | `[abc]`   | One of the listed bytes; `[a-z]` gives a range   |
+//! | `[^abc]`  | One byte not listed                              |
+//! | `\x`      | The byte `x` itself, inside a class too          |
+//!
+//! Patterns and keys are matched as raw bytes, so binary keys match like
+//! any other. A pattern with an unclosed `[` or a trailing `\` matches
+//! nothing.
+//!
+//! ## Matching in Linear Space, Polynomial Time
+//!
+//! Trying every split of the text at every `*` recursively takes time
+//! exponential in the number of stars: `a*a*a*a*b` against a long run of
+//! `a`s never finishes, and one KEYS call with it could pin a core. A
+//! [`GlobPattern`] is instead compiled once into tokens, with runs of stars
+//! merged and classes turned into 256-bit sets, and matched in a single
+//! loop. Every token but `*` consumes exactly one byte, so when a token
+//! fails it is enough to let the most recent star swallow one more byte and
+//! retry from just after it; earlier stars never need revisiting. Each
+//! retry advances where the last star ends, so matching takes at most
+//! `pattern length × key length` steps, with no recursion.
+
+/// One element of a compiled pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// A run of any bytes (`*`)
+    Star,
+    /// Any one byte (`?`)
+    AnyByte,
+    /// One given byte
+    Byte(u8),
+    /// One byte of a class, as a bit per byte value
+    Class([u64; 4]),
+}
+
+impl Token {
+    /// Returns whether this single-byte token matches `byte`.
+    fn matches(&self, byte: u8) -> bool {
+        match self {
+            Token::Star => false,
+            Token::AnyByte => true,
+            Token::Byte(b) => *b == byte,
+            Token::Class(set) => set[(byte >> 6) as usize] & (1 << (byte & 63)) != 0,
+        }
+    }
+}
+
+/// A glob pattern, compiled for matching many keys.
+#[derive(Debug, Clone)]
+pub(crate) struct GlobPattern {
+    tokens: Vec<Token>,
+    /// Whether the pattern is malformed and matches nothing
+    never: bool,
+}
+
+impl GlobPattern {
+    /// Compiles `pattern`.
+    pub(crate) fn new(pattern: &[u8]) -> Self {
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < pattern.len() {
+            let token = match pattern[i] {
+                b'*' => {
+                    i += 1;
+                    // Consecutive stars match the same as one
+                    if tokens.last() == Some(&Token::Star) {
+                        continue;
+                    }
+                    Token::Star
+                }
+                b'?' => {
+                    i += 1;
+                    Token::AnyByte
+                }
+                b'[' => match parse_class(&pattern[i + 1..]) {
+                    Some((set, len)) => {
+                        i += 1 + len;
+                        Token::Class(set)
+                    }
+                    None => return Self::never(),
+                },
+                b'\\' => match pattern.get(i + 1) {
+                    Some(&b) => {
+                        i += 2;
+                        Token::Byte(b)
+                    }
+                    None => return Self::never(),
+                },
+                b => {
+                    i += 1;
+                    Token::Byte(b)
+                }
+            };
+            tokens.push(token);
+        }
+        Self {
+            tokens,
+            never: false,
+        }
+    }
+
+    /// A pattern matching nothing.
+    fn never() -> Self {
+        Self {
+            tokens: Vec::new(),
+            never: true,
+        }
+    }
+
+    /// Returns whether `text` matches the pattern as a whole.
+    pub(crate) fn matches(&self, text: &[u8]) -> bool {
+        if self.never {
+            return false;
+        }
+        let tokens = &self.tokens;
+        let (mut t, mut s) = (0, 0);
+        // The token after the most recent star, and where in the text the
+        // star's match ends
+        let mut resume: Option<(usize, usize)> = None;
+
+        while s < text.len() {
+            match tokens.get(t) {
+                Some(Token::Star) => {
+                    t += 1;
+                    resume = Some((t, s));
+                    continue;
+                }
+                Some(token) if token.matches(text[s]) => {
+                    t += 1;
+                    s += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            // Let the last star swallow one more byte and retry after it
+            match resume {
+                Some((after_star, end)) => {
+                    t = after_star;
+                    s = end + 1;
+                    resume = Some((after_star, s));
+                }
+                None => return false,
+            }
+        }
+        tokens[t..].iter().all(|token| *token == Token::Star)
+    }
+}
+
+/// Parses a character class from just after its `[`.
+///
+/// # Returns
+/// The set of bytes it matches and the length of the class including the
+/// closing `]`, or `None` if it isn't closed.
+fn parse_class(class: &[u8]) -> Option<([u64; 4], usize)> {
+    let mut set = [0u64; 4];
+    let mut add = |lo: u8, hi: u8| {
+        for b in lo..=hi {
+            set[(b >> 6) as usize] |= 1 << (b & 63);
+        }
+    };
+
+    let negate = class.first() == Some(&b'^');
+    let mut i = usize::from(negate);
+    while i < class.len() && class[i] != b']' {
+        if class[i] == b'\\' && i + 1 < class.len() {
+            // An escaped byte inside a class is taken literally
+            add(class[i + 1], class[i + 1]);
+            i += 2;
+        } else if i + 2 < class.len() && class[i + 1] == b'-' && class[i + 2] != b']' {
+            // A range, in either order as in Redis
+            let (a, b) = (class[i], class[i + 2]);
+            add(a.min(b), a.max(b));
+            i += 3;
+        } else {
+            add(class[i], class[i]);
+            i += 1;
+        }
+    }
+    if i == class.len() {
+        return None;
+    }
+
+    if negate {
+        for word in &mut set {
+            *word = !*word;
+        }
+    }
+    Some((set, i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_glob_pattern() {
+        let pattern = GlobPattern::new(b"h*llo");
+        assert!(pattern.matches(b"hello"));
+        assert!(pattern.matches(b"hallo"));
+        assert!(pattern.matches(b"hllo"));
+        assert!(pattern.matches(b"heeeello"));
+        assert!(!pattern.matches(b"world"));
+
+        let pattern = GlobPattern::new(b"h?llo");
+        assert!(pattern.matches(b"hello"));
+        assert!(pattern.matches(b"hallo"));
+        assert!(!pattern.matches(b"hllo"));
+        assert!(!pattern.matches(b"heello"));
+
+        let pattern = GlobPattern::new(b"*");
+        assert!(pattern.matches(b""));
+        assert!(pattern.matches(b"anything"));
+
+        let pattern = GlobPattern::new(b"h[ae]llo");
+        assert!(pattern.matches(b"hello"));
+        assert!(pattern.matches(b"hallo"));
+        assert!(!pattern.matches(b"hillo"));
+
+        let pattern = GlobPattern::new(b"k[\\]]");
+        assert!(pattern.matches(b"k]"));
+
+        // Non-UTF-8 keys and patterns match byte for byte
+        let pattern = GlobPattern::new(b"bin:\xff?");
+        assert!(pattern.matches(b"bin:\xff\x00"));
+        assert!(!pattern.matches(b"bin:\xfe\x00"));
+        assert!(GlobPattern::new(b"*").matches(b"\x80\x81"));
+    }
+
+    #[test]
+    fn test_classes_and_escapes() {
+        let pattern = GlobPattern::new(b"[a-c][^0-9][z-x]");
+        assert!(pattern.matches(b"bxy"));
+        assert!(!pattern.matches(b"dxy"));
+        assert!(!pattern.matches(b"b5y"));
+        assert!(!pattern.matches(b"bxw"));
+
+        // A dash before the closing bracket is literal
+        assert!(GlobPattern::new(b"[a-]").matches(b"-"));
+        assert!(GlobPattern::new(b"\\*").matches(b"*"));
+        assert!(!GlobPattern::new(b"\\*").matches(b"a"));
+        assert!(GlobPattern::new(b"[^]").matches(b"x"));
+        assert!(!GlobPattern::new(b"[]").matches(b"x"));
+
+        // Malformed patterns match nothing
+        assert!(!GlobPattern::new(b"*[abc").matches(b"a"));
+        assert!(!GlobPattern::new(b"a\\").matches(b"a\\"));
+    }
+
+    #[test]
+    fn test_pathological_pattern() {
+        // Exponential for a backtracking matcher
+        let pattern = GlobPattern::new(b"a*a*a*a*a*a*a*a*a*a*b");
+        let text = vec![b'a'; 100_000];
+        let start = Instant::now();
+        assert!(!pattern.matches(&text));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    /// Matches by dynamic programming over (token, byte) pairs.
+    fn reference_matches(pattern: &GlobPattern, text: &[u8]) -> bool {
+        if pattern.never {
+            return false;
+        }
+        let tokens = &pattern.tokens;
+        // matched[s]: the tokens so far match text[..s]
+        let mut matched = vec![false; text.len() + 1];
+        matched[0] = true;
+        for token in tokens {
+            let mut next = vec![false; text.len() + 1];
+            for s in 0..=text.len() {
+                next[s] = match token {
+                    Token::Star => matched[s] || (s > 0 && next[s - 1]),
+                    _ => s > 0 && matched[s - 1] && token.matches(text[s - 1]),
+                };
+            }
+            matched = next;
+        }
+        matched[text.len()]
+    }
+
+    #[test]
+    fn test_matches_reference() {
+        let mut rng = rand::thread_rng();
+        let alphabet = b"ab*?[]^-\\";
+        for _ in 0..20_000 {
+            let pattern: Vec<u8> = (0..rng.gen_range(0..8))
+                .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                .collect();
+            let text: Vec<u8> = (0..rng.gen_range(0..10))
+                .map(|_| b"ab-]"[rng.gen_range(0..4)])
+                .collect();
+            let glob = GlobPattern::new(&pattern);
+            assert_eq!(
+                glob.matches(&text),
+                reference_matches(&glob, &text),
+                "{:?} against {:?}",
+                String::from_utf8_lossy(&pattern),
+                String::from_utf8_lossy(&text)
+            );
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! HyperLogLog Cardinality Estimation
+//!
+//! A HyperLogLog estimates the number of distinct elements added to it using
+//! a fixed 12 KB of memory, with a standard error of about 0.81%.
+//!
+//! ## Encoding
+//!
+//! HyperLogLogs are stored as ordinary string values so that GET, SET,
+//! RENAME and friends work on them. The layout follows the Redis dense
+//! representation:
+//!
+//! ```text
+//! +------+---+-----+----------+---------------------------------------+
+//! | HYLL | E | N/U | Cardin.  | 16384 x 6-bit registers (12288 bytes) |
+//! +------+---+-----+----------+---------------------------------------+
+//!   4 B   1 B  3 B     8 B
+//! ```
+//!
+//! - `E` is the encoding (only dense, `0`, is supported)
+//! - `Cardin.` caches the last computed cardinality (little endian); the
+//!   most significant bit of its last byte marks the cache as stale
+//!
+//! Elements are hashed with MurmurHash64A using the same seed as Redis, so
+//! the register contents match what Redis would produce.
+
+/// Number of bits of the hash used to select a register.
+const HLL_P: u32 = 14;
+
+/// Number of bits left for counting leading zeros.
+const HLL_Q: u32 = 64 - HLL_P;
+
+/// Number of registers.
+const HLL_REGISTERS: usize = 1 << HLL_P;
+
+/// Bits per register.
+const HLL_BITS: usize = 6;
+
+/// Largest value a register can hold.
+const HLL_REGISTER_MAX: u8 = (1 << HLL_BITS) - 1;
+
+/// Header size: magic, encoding, 3 unused bytes and the cached cardinality.
+const HLL_HDR_SIZE: usize = 16;
+
+/// Total size of a dense HyperLogLog value.
+pub const HLL_DENSE_SIZE: usize = HLL_HDR_SIZE + (HLL_REGISTERS * HLL_BITS).div_ceil(8);
+
+/// Magic bytes at the start of every HyperLogLog value.
+const HLL_MAGIC: &[u8; 4] = b"HYLL";
+
+/// Encoding byte of the dense representation.
+const HLL_DENSE: u8 = 0;
+
+/// Error returned when a string value is not a valid HyperLogLog.
+pub const HLL_INVALID: &str = "Key is not a valid HyperLogLog string value.";
+
+/// Bias-correction constant for the cardinality estimator.
+const HLL_ALPHA_INF: f64 = 0.721_347_520_444_481_7;
+
+/// A dense HyperLogLog backed by its serialized string representation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HyperLogLog {
+    bytes: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HyperLogLog {
+    /// Creates an empty HyperLogLog.
+    pub fn new() -> Self {
+        let mut bytes = vec![0u8; HLL_DENSE_SIZE];
+        bytes[..4].copy_from_slice(HLL_MAGIC);
+        bytes[4] = HLL_DENSE;
+        Self { bytes }
+    }
+
+    /// Interprets a string value as a HyperLogLog.
+    ///
+    /// Returns `None` if the value is not a valid dense HyperLogLog.
+    pub fn from_bytes(value: &[u8]) -> Option<Self> {
+        if value.len() != HLL_DENSE_SIZE || &value[..4] != HLL_MAGIC || value[4] != HLL_DENSE {
+            return None;
+        }
+        Some(Self {
+            bytes: value.to_vec(),
+        })
+    }
+
+    /// Returns the serialized representation.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Consumes the HyperLogLog, returning its serialized representation.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Adds an element.
+    ///
+    /// # Returns
+    /// `true` if a register changed (the estimate may have changed).
+    pub fn add(&mut self, element: &[u8]) -> bool {
+        let (index, count) = register_for(element);
+        if count > self.register(index) {
+            self.set_register(index, count);
+            self.invalidate_cache();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Merges another HyperLogLog into this one (register-wise maximum).
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for i in 0..HLL_REGISTERS {
+            let theirs = other.register(i);
+            if theirs > self.register(i) {
+                self.set_register(i, theirs);
+            }
+        }
+        self.invalidate_cache();
+    }
+
+    /// Returns the estimated cardinality, using and refreshing the cache.
+    pub fn count(&mut self) -> u64 {
+        if let Some(cached) = self.cached_count() {
+            return cached;
+        }
+        let estimate = self.estimate();
+        self.bytes[8..16].copy_from_slice(&estimate.to_le_bytes());
+        estimate
+    }
+
+    /// Returns the cached cardinality, if it is still valid.
+    fn cached_count(&self) -> Option<u64> {
+        if self.bytes[15] & 0x80 != 0 {
+            return None;
+        }
+        let mut card = [0u8; 8];
+        card.copy_from_slice(&self.bytes[8..16]);
+        Some(u64::from_le_bytes(card))
+    }
+
+    /// Marks the cached cardinality as stale.
+    fn invalidate_cache(&mut self) {
+        self.bytes[15] |= 0x80;
+    }
+
+    /// Reads register `index`.
+    fn register(&self, index: usize) -> u8 {
+        let registers = &self.bytes[HLL_HDR_SIZE..];
+        let bit = index * HLL_BITS;
+        let byte = bit / 8;
+        let shift = bit % 8;
+        let b0 = registers[byte] as u16;
+        let b1 = registers.get(byte + 1).copied().unwrap_or(0) as u16;
+        (((b0 >> shift) | (b1 << (8 - shift))) as u8) & HLL_REGISTER_MAX
+    }
+
+    /// Writes register `index`.
+    fn set_register(&mut self, index: usize, value: u8) {
+        let registers = &mut self.bytes[HLL_HDR_SIZE..];
+        let bit = index * HLL_BITS;
+        let byte = bit / 8;
+        let shift = bit % 8;
+        let value = value as u16;
+        let mask = HLL_REGISTER_MAX as u16;
+
+        registers[byte] &= !((mask << shift) as u8);
+        registers[byte] |= (value << shift) as u8;
+        if let Some(next) = registers.get_mut(byte + 1) {
+            *next &= !((mask >> (8 - shift)) as u8);
+            *next |= (value >> (8 - shift)) as u8;
+        }
+    }
+
+    /// Computes the cardinality estimate from the registers
+    /// (Ertl's improved estimator, as used by Redis).
+    fn estimate(&self) -> u64 {
+        let mut histogram = [0u32; 64];
+        for i in 0..HLL_REGISTERS {
+            histogram[self.register(i) as usize] += 1;
+        }
+
+        let m = HLL_REGISTERS as f64;
+        let q = HLL_Q as usize;
+        let mut z = m * tau((m - histogram[q + 1] as f64) / m);
+        for j in (1..=q).rev() {
+            z += histogram[j] as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+
+        (HLL_ALPHA_INF * m * m / z).round() as u64
+    }
+}
+
+/// Returns the register index and the run length of zeros (plus one) for
+/// an element.
+fn register_for(element: &[u8]) -> (usize, u8) {
+    let hash = murmurhash64a(element, 0xadc8_3b19);
+    let index = (hash & (HLL_REGISTERS as u64 - 1)) as usize;
+
+    // Count trailing zeros of the remaining bits, with a sentinel bit so
+    // the count never exceeds Q + 1
+    let rest = (hash >> HLL_P) | (1u64 << HLL_Q);
+    (index, rest.trailing_zeros() as u8 + 1)
+}
+
+fn sigma(mut x: f64) -> f64 {
+    if x == 1.0 {
+        return f64::INFINITY;
+    }
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let prev = z;
+        z += x * y;
+        y += y;
+        if prev == z {
+            return z;
+        }
+    }
+}
+
+fn tau(mut x: f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let prev = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if prev == z {
+            return z / 3.0;
+        }
+    }
+}
+
+/// MurmurHash64A, as used by Redis for HyperLogLog.
+fn murmurhash64a(key: &[u8], seed: u64) -> u64 {
+    const M: u64 = 0xc6a4_a793_5bd1_e995;
+    const R: u32 = 47;
+
+    let mut h = seed ^ (key.len() as u64).wrapping_mul(M);
+
+    let mut chunks = key.chunks_exact(8);
+    for chunk in &mut chunks {
+        let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, b) in tail.iter().enumerate() {
+            h ^= (*b as u64) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registers_roundtrip() {
+        let mut hll = HyperLogLog::new();
+        for i in [0, 1, 2, 3, 1000, HLL_REGISTERS - 1] {
+            hll.set_register(i, 42);
+            assert_eq!(hll.register(i), 42);
+        }
+        // Neighbours are untouched
+        assert_eq!(hll.register(4), 0);
+        assert_eq!(hll.register(HLL_REGISTERS - 2), 0);
+        assert_eq!(hll.as_bytes().len(), HLL_DENSE_SIZE);
+    }
+
+    #[test]
+    fn test_estimate_accuracy() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.count(), 0);
+
+        for i in 0..100_000 {
+            hll.add(format!("user:{}", i).as_bytes());
+        }
+        let estimate = hll.count() as f64;
+        let error = (estimate - 100_000.0).abs() / 100_000.0;
+        assert!(error < 0.03, "estimate {} is too far off", estimate);
+
+        // Adding existing elements doesn't change anything
+        assert!(!hll.add(b"user:1"));
+    }
+
+    #[test]
+    fn test_small_counts_are_exact() {
+        let mut hll = HyperLogLog::new();
+        for element in ["a", "b", "c", "a", "b"] {
+            hll.add(element.as_bytes());
+        }
+        assert_eq!(hll.count(), 3);
+    }
+
+    #[test]
+    fn test_merge_and_serialization() {
+        let mut a = HyperLogLog::new();
+        let mut b = HyperLogLog::new();
+        for i in 0..1000 {
+            a.add(format!("a{}", i).as_bytes());
+            b.add(format!("b{}", i).as_bytes());
+        }
+        a.merge(&b);
+        let merged = a.count() as f64;
+        assert!((merged - 2000.0).abs() / 2000.0 < 0.03);
+
+        let restored = HyperLogLog::from_bytes(a.as_bytes()).unwrap();
+        assert_eq!(restored, a);
+        assert!(HyperLogLog::from_bytes(b"not an hll").is_none());
+    }
+}
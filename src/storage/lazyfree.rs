@@ -0,0 +1,157 @@
+//! Background Reclamation of Large Values
+//!
+//! Dropping a value with millions of elements walks and frees every one of
+//! them. Doing that while holding a shard's write lock stalls every other
+//! client using the shard. Instead, whenever a value leaves the keyspace -
+//! through `DEL` or `UNLINK`, an overwrite such as `SET`, expiry or eviction -
+//! it is detached under the lock and handed to [`LazyFree`] once the lock is
+//! released, which drops it on a background thread. `FLUSHDB ASYNC` and
+//! `FLUSHALL ASYNC` do the same with whole shards, swapping in empty ones and
+//! handing over the old contents.
+//!
+//! ## Design
+//!
+//! A single thread (spawned on first use) receives values over a channel
+//! and drops them. Values whose [free effort](Value::free_effort) is at or
+//! below [`LAZYFREE_THRESHOLD`] are cheaper to drop in place than to send,
+//! so callers drop those synchronously. A flushed shard counts as one
+//! freed value, however many keys it held. The thread exits once the owning
+//! [`LazyFree`] is dropped and the queue has drained.
+
+use crate::storage::engine::KeyMap;
+use crate::storage::Value;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, OnceLock};
+
+/// Values with a free effort above this many elements are freed in the
+/// background (the same threshold Redis uses).
+pub const LAZYFREE_THRESHOLD: usize = 64;
+
+/// Bytes of a string counted as one element of free effort, so strings over
+/// about 4MB are freed in the background.
+pub const LAZYFREE_STRING_UNIT: usize = 64 * 1024;
+
+/// Something to drop on the reclamation thread.
+#[derive(Debug)]
+enum Garbage {
+    /// A value detached from its key
+    Value(Value),
+    /// The former contents of a flushed shard
+    Keyspace(KeyMap),
+}
+
+/// Frees large values on a background thread.
+#[derive(Debug, Default)]
+pub struct LazyFree {
+    /// Channel to the reclamation thread, created on first use
+    sender: OnceLock<Sender<Garbage>>,
+
+    /// Values queued but not yet freed
+    pending: Arc<AtomicU64>,
+
+    /// Values freed in the background so far
+    freed: Arc<AtomicU64>,
+}
+
+impl LazyFree {
+    /// Creates a reclaimer. No thread is started until a value is queued.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Frees `value`, in the background if it is large enough to be worth it.
+    pub fn free(&self, value: Value) {
+        if value.free_effort() <= LAZYFREE_THRESHOLD {
+            return;
+        }
+        self.send(Garbage::Value(value));
+    }
+
+    /// Frees the former contents of a flushed shard in the background.
+    pub fn free_keyspace(&self, data: KeyMap) {
+        if data.is_empty() {
+            return;
+        }
+        self.send(Garbage::Keyspace(data));
+    }
+
+    /// Returns the number of values waiting to be freed.
+    pub fn pending(&self) -> u64 {
+        self.pending.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of values freed in the background so far.
+    pub fn freed(&self) -> u64 {
+        self.freed.load(Ordering::Relaxed)
+    }
+
+    /// Queues `garbage` for the reclamation thread.
+    fn send(&self, garbage: Garbage) {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        if let Err(mpsc::SendError(garbage)) = self.sender().send(garbage) {
+            // The thread is gone (it panicked), free it here instead
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            drop(garbage);
+        }
+    }
+
+    /// Returns the channel to the reclamation thread, spawning it if needed.
+    fn sender(&self) -> &Sender<Garbage> {
+        self.sender.get_or_init(|| {
+            let (tx, rx) = mpsc::channel::<Garbage>();
+            let pending = Arc::clone(&self.pending);
+            let freed = Arc::clone(&self.freed);
+
+            std::thread::Builder::new()
+                .name("flashkv-lazyfree".to_string())
+                .spawn(move || {
+                    for garbage in rx {
+                        match garbage {
+                            Garbage::Value(value) => drop(value),
+                            Garbage::Keyspace(data) => drop(data),
+                        }
+                        pending.fetch_sub(1, Ordering::Relaxed);
+                        freed.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+                .expect("failed to spawn lazyfree thread");
+
+            tx
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use std::collections::VecDeque;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_small_values_are_freed_inline() {
+        let lazy = LazyFree::new();
+        lazy.free(Value::from(Bytes::from(vec![0u8; 1 << 20])));
+        lazy.free(Value::List(VecDeque::from(vec![Bytes::from("a"); 10])));
+
+        assert_eq!(lazy.freed(), 0);
+        assert!(lazy.sender.get().is_none());
+    }
+
+    #[test]
+    fn test_large_values_are_freed_in_background() {
+        let lazy = LazyFree::new();
+        for _ in 0..3 {
+            lazy.free(Value::List(VecDeque::from(vec![Bytes::from("a"); 1000])));
+        }
+        lazy.free(Value::from(Bytes::from(vec![0u8; 8 << 20])));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while lazy.freed() < 4 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(lazy.freed(), 4);
+        assert_eq!(lazy.pending(), 0);
+    }
+}
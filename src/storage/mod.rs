@@ -2,7 +2,23 @@
 //!
 //! This module provides the core storage functionality for FlashKV.
 //! It includes a thread-safe, sharded key-value store with TTL support
-//! and a background expiry sweeper.
+//! and a background expiry sweeper. Strings are stored in the compact
+//! encodings of [`string`], sorted sets are implemented in [`zset`],
+//! streams in [`stream`] and HyperLogLogs in [`hyperloglog`], while
+//! [`waiters`] tracks clients parked on blocking commands, [`lazyfree`]
+//! frees large deleted values in the background, [`eviction`] evicts
+//! keys past the memory limit and [`arena`] packs short keys together,
+//! while [`allocator`] picks the global allocator and reads its statistics.
+//! [`glob`] compiles the patterns of KEYS, SCAN and PSUBSCRIBE.
+//! SORT options and pattern lookups live in [`sort`], [`clock`] maps
+//! expiry deadlines to and from Unix time and keeps a coarse clock for
+//! expiry checks, and
+//! [`snapshot`] saves and loads the keyspace to and from disk ([`autosave`]
+//! triggers saves according to the save rules), [`rdb`] imports dump files
+//! written by Redis, [`export`] writes the keyspace as JSON lines or CSV,
+//! and [`aof`] logs write commands to an append-only file.
+//! [`shard_lock`] locks each shard with an `RwLock`, or with a mutex when
+//! the keyspace is split into many small buckets.
 //!
 //! ## Architecture
 //!
@@ -54,9 +70,49 @@
 //! );
 //! ```
 
+pub mod allocator;
+pub mod aof;
+pub mod arena;
+pub mod autosave;
+pub mod bitmap;
+pub mod clock;
+pub mod compression;
 pub mod engine;
+pub mod eviction;
 pub mod expiry;
+pub mod export;
+pub mod glob;
+pub mod hyperloglog;
+pub mod lazyfree;
+pub mod rdb;
+pub mod shard_lock;
+pub mod snapshot;
+pub mod sort;
+pub mod stream;
+pub mod string;
+pub mod waiters;
+pub mod wal;
+pub mod zset;
 
 // Re-export commonly used types
-pub use engine::{Entry, MemoryInfo, StorageEngine, StorageStats};
+pub use aof::{Aof, AppendFsync};
+pub use autosave::{start_save_scheduler, SaveScheduler};
+pub use bitmap::{BitRange, BitUnit};
+pub use compression::{Codec, Compression};
+pub use engine::{
+    default_shard_count, without_touching, Entry, ExpireFlags, KeyLocks, ListEnd, MemoryInfo,
+    PendingSnapshot, SetOp, StorageEngine, StorageStats, Value, DEFAULT_DATABASES, MAX_SHARDS,
+    WRONGTYPE,
+};
+pub use eviction::{Eviction, EvictionPolicy};
 pub use expiry::{start_expiry_sweeper, ExpiryConfig, ExpirySweeper};
+pub use export::ExportFormat;
+pub use hyperloglog::HyperLogLog;
+pub use lazyfree::LazyFree;
+pub use shard_lock::ShardLocking;
+pub use snapshot::{SaveRule, Snapshot, SnapshotError, Snapshots};
+pub use sort::SortOptions;
+pub use stream::{Stream, StreamId, StreamRecord, XAddId};
+pub use string::StringValue;
+pub use waiters::KeyWaiters;
+pub use zset::{Aggregate, SortedSet, ZAddFlags, ZAddResult};
@@ -0,0 +1,973 @@
+//! Redis RDB Import
+//!
+//! Reads dump files produced by Redis so an existing dataset can be moved
+//! into FlashKV in one step (`--load-rdb dump.rdb`). This is a reader only:
+//! FlashKV saves in its own [`snapshot`](crate::storage::snapshot) format.
+//!
+//! ## Supported Content
+//!
+//! | RDB type                          | Loaded as |
+//! |-----------------------------------|-----------|
+//! | String (raw, integer, LZF)        | string    |
+//! | List, ziplist, quicklist (v1, v2) | list      |
+//! | Set, intset, listpack             | set       |
+//! | Sorted set (v1, v2), ziplist, listpack | zset |
+//! | Hash, ziplist, listpack           | hash      |
+//!
+//! Expiries (seconds and milliseconds) are kept, and keys that have already
+//! expired are skipped. Resize hints pre-size the database they describe.
+//! Auxiliary fields, LFU/LRU hints and functions are read and ignored.
+//! Streams, module values and hashes with per-field expiry are rejected
+//! with an error naming the type. Keys go to the database they were in,
+//! which must exist (see `--databases`).
+//!
+//! The trailing CRC-64 is verified before anything is loaded, so a damaged
+//! file is rejected as a whole.
+
+use crate::storage::clock;
+use crate::storage::engine::{Entry, StorageEngine, Value};
+use crate::storage::snapshot::SnapshotError;
+use crate::storage::zset::SortedSet;
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Newest RDB version understood (Redis 7.4).
+const MAX_VERSION: u32 = 12;
+
+/// Opcodes.
+const OP_SLOT_INFO: u8 = 0xF4;
+const OP_FUNCTION2: u8 = 0xF5;
+const OP_FUNCTION_PRE_GA: u8 = 0xF6;
+const OP_MODULE_AUX: u8 = 0xF7;
+const OP_IDLE: u8 = 0xF8;
+const OP_FREQ: u8 = 0xF9;
+const OP_AUX: u8 = 0xFA;
+const OP_RESIZEDB: u8 = 0xFB;
+const OP_EXPIRETIME_MS: u8 = 0xFC;
+const OP_EXPIRETIME: u8 = 0xFD;
+const OP_SELECTDB: u8 = 0xFE;
+const OP_EOF: u8 = 0xFF;
+
+/// Value types.
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+/// Special string encodings (length byte `11xxxxxx`).
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+/// Quicklist v2 node containers.
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+/// Collections are preallocated for at most this many elements, so a
+/// corrupt length can't trigger a huge allocation.
+const PREALLOC_LIMIT: usize = 4096;
+
+/// Loads the Redis dump at `path` into `engine`, replacing keys of the same
+/// name.
+///
+/// # Returns
+///
+/// The number of keys loaded.
+pub fn load(engine: &StorageEngine, path: &Path) -> Result<usize, SnapshotError> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len() as usize;
+
+    let mut header = [0; 9];
+    file.read_exact(&mut header)
+        .map_err(|_| SnapshotError::Corrupt("not an RDB file"))?;
+    if &header[..5] != b"REDIS" {
+        return Err(SnapshotError::Corrupt("not an RDB file"));
+    }
+    let version: u32 = std::str::from_utf8(&header[5..])
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or(SnapshotError::Corrupt("invalid RDB version"))?;
+    if version == 0 || version > MAX_VERSION {
+        return Err(SnapshotError::Unsupported(format!(
+            "RDB version {}",
+            version
+        )));
+    }
+
+    // Checksums were added in version 5; a zero checksum means disabled
+    if version >= 5 {
+        verify_checksum(&mut file)?;
+    }
+    file.seek(SeekFrom::Start(header.len() as u64))?;
+
+    let mut reader = Reader {
+        inner: BufReader::new(file),
+    };
+    let now = clock::unix_time_ms();
+    let mut db = 0;
+    let mut expire_ms = None;
+    let mut keys = 0;
+
+    loop {
+        let op = reader.u8()?;
+        match op {
+            OP_EOF => break,
+            OP_SELECTDB => db = reader.length()?,
+            OP_RESIZEDB => {
+                let size = reader.length()?;
+                reader.length()?;
+                // Every key takes at least a byte, so a corrupt hint can't
+                // claim more keys than the file has bytes
+                if let Some(target) = engine.db(db) {
+                    target.reserve(size.min(file_len));
+                }
+            }
+            OP_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.length()?;
+                }
+            }
+            OP_AUX => {
+                reader.string()?;
+                reader.string()?;
+            }
+            OP_FUNCTION2 => {
+                reader.string()?;
+            }
+            OP_IDLE => {
+                reader.length()?;
+            }
+            OP_FREQ => {
+                reader.u8()?;
+            }
+            OP_EXPIRETIME => {
+                let mut buf = [0; 4];
+                reader.raw(&mut buf)?;
+                expire_ms = Some(u32::from_le_bytes(buf) as i64 * 1000);
+            }
+            OP_EXPIRETIME_MS => {
+                let mut buf = [0; 8];
+                reader.raw(&mut buf)?;
+                expire_ms = Some(i64::from_le_bytes(buf));
+            }
+            OP_MODULE_AUX | OP_FUNCTION_PRE_GA => {
+                return Err(SnapshotError::Unsupported(format!(
+                    "RDB opcode {:#04x}",
+                    op
+                )));
+            }
+            tag => {
+                let key = reader.string()?;
+                let value = reader.value(tag)?;
+
+                let Some(target) = engine.db(db) else {
+                    return Err(SnapshotError::Unsupported(format!(
+                        "keys in database {} (the server has {})",
+                        db,
+                        engine.database_count()
+                    )));
+                };
+
+                let entry = match expire_ms.take() {
+                    Some(unix_ms) if unix_ms <= now => continue,
+                    Some(unix_ms) => Entry::with_expire_time_ms(value, unix_ms),
+                    None => Entry::new(value),
+                };
+                target.restore(key, entry);
+                keys += 1;
+            }
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Checks the trailing CRC-64 against the rest of the file.
+fn verify_checksum(file: &mut File) -> Result<(), SnapshotError> {
+    let len = file.metadata()?.len();
+    if len < 9 + 1 + 8 {
+        return Err(SnapshotError::Corrupt("file too short"));
+    }
+
+    file.seek(SeekFrom::Start(len - 8))?;
+    let mut stored = [0; 8];
+    file.read_exact(&mut stored)?;
+    let stored = u64::from_le_bytes(stored);
+    if stored == 0 {
+        return Ok(());
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    let mut crc = 0;
+    let mut reader = BufReader::new(&mut *file).take(len - 8);
+    let mut buf = [0; 8192];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        crc = crc64(crc, &buf[..n]);
+    }
+
+    if crc != stored {
+        return Err(SnapshotError::Corrupt("checksum mismatch"));
+    }
+    Ok(())
+}
+
+/// Reads RDB primitives.
+struct Reader<R: Read> {
+    inner: R,
+}
+
+/// A decoded length field.
+enum Length {
+    /// A plain length
+    Len(u64),
+    /// A special string encoding (`ENC_*`)
+    Encoded(u8),
+}
+
+impl<R: Read> Reader<R> {
+    fn raw(&mut self, buf: &mut [u8]) -> Result<(), SnapshotError> {
+        self.inner.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => SnapshotError::Corrupt("unexpected end of file"),
+            _ => SnapshotError::Io(e),
+        })
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        let mut buf = [0; 1];
+        self.raw(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<Vec<u8>, SnapshotError> {
+        // Grow as data arrives rather than trusting the length up front
+        let mut buf = Vec::with_capacity(len.min(PREALLOC_LIMIT));
+        (&mut self.inner).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(SnapshotError::Corrupt("unexpected end of file"));
+        }
+        Ok(buf)
+    }
+
+    fn length_or_encoding(&mut self) -> Result<Length, SnapshotError> {
+        let first = self.u8()?;
+        Ok(match first >> 6 {
+            0 => Length::Len((first & 0x3F) as u64),
+            1 => Length::Len((((first & 0x3F) as u64) << 8) | self.u8()? as u64),
+            2 => match first {
+                0x80 => {
+                    let mut buf = [0; 4];
+                    self.raw(&mut buf)?;
+                    Length::Len(u32::from_be_bytes(buf) as u64)
+                }
+                0x81 => {
+                    let mut buf = [0; 8];
+                    self.raw(&mut buf)?;
+                    Length::Len(u64::from_be_bytes(buf))
+                }
+                _ => return Err(SnapshotError::Corrupt("invalid length encoding")),
+            },
+            _ => Length::Encoded(first & 0x3F),
+        })
+    }
+
+    fn length(&mut self) -> Result<usize, SnapshotError> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => {
+                usize::try_from(len).map_err(|_| SnapshotError::Corrupt("length too large"))
+            }
+            Length::Encoded(_) => Err(SnapshotError::Corrupt("unexpected encoded length")),
+        }
+    }
+
+    fn string(&mut self) -> Result<Bytes, SnapshotError> {
+        let len = match self.length_or_encoding()? {
+            Length::Len(len) => {
+                usize::try_from(len).map_err(|_| SnapshotError::Corrupt("length too large"))?
+            }
+            Length::Encoded(ENC_INT8) => {
+                return Ok(int_bytes(self.u8()? as i8 as i64));
+            }
+            Length::Encoded(ENC_INT16) => {
+                let mut buf = [0; 2];
+                self.raw(&mut buf)?;
+                return Ok(int_bytes(i16::from_le_bytes(buf) as i64));
+            }
+            Length::Encoded(ENC_INT32) => {
+                let mut buf = [0; 4];
+                self.raw(&mut buf)?;
+                return Ok(int_bytes(i32::from_le_bytes(buf) as i64));
+            }
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.length()?;
+                let len = self.length()?;
+                let compressed = self.bytes(compressed_len)?;
+                return lzf_decompress(&compressed, len)
+                    .map(Bytes::from)
+                    .ok_or(SnapshotError::Corrupt("invalid LZF data"));
+            }
+            Length::Encoded(_) => return Err(SnapshotError::Corrupt("unknown string encoding")),
+        };
+        Ok(Bytes::from(self.bytes(len)?))
+    }
+
+    /// Reads a score in the text format of the original sorted set type.
+    fn text_score(&mut self) -> Result<f64, SnapshotError> {
+        let len = self.u8()?;
+        Ok(match len {
+            253 => return Err(SnapshotError::Corrupt("NaN sorted set score")),
+            254 => f64::INFINITY,
+            255 => f64::NEG_INFINITY,
+            len => parse_score(&self.bytes(len as usize)?)?,
+        })
+    }
+
+    fn binary_score(&mut self) -> Result<f64, SnapshotError> {
+        let mut buf = [0; 8];
+        self.raw(&mut buf)?;
+        Ok(f64::from_le_bytes(buf))
+    }
+
+    fn value(&mut self, tag: u8) -> Result<Value, SnapshotError> {
+        Ok(match tag {
+            TYPE_STRING => Value::from(self.string()?),
+            TYPE_LIST => {
+                let len = self.length()?;
+                let mut list = VecDeque::with_capacity(len.min(PREALLOC_LIMIT));
+                for _ in 0..len {
+                    list.push_back(self.string()?);
+                }
+                Value::List(list)
+            }
+            TYPE_SET => {
+                let len = self.length()?;
+                let mut set = HashSet::with_capacity(len.min(PREALLOC_LIMIT));
+                for _ in 0..len {
+                    set.insert(self.string()?);
+                }
+                Value::Set(set)
+            }
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.length()?;
+                let mut zset = SortedSet::new();
+                for _ in 0..len {
+                    let member = self.string()?;
+                    let score = if tag == TYPE_ZSET {
+                        self.text_score()?
+                    } else {
+                        self.binary_score()?
+                    };
+                    zset.insert(member, score);
+                }
+                Value::ZSet(zset)
+            }
+            TYPE_HASH => {
+                let len = self.length()?;
+                let mut hash = HashMap::with_capacity(len.min(PREALLOC_LIMIT));
+                for _ in 0..len {
+                    let field = self.string()?;
+                    hash.insert(field, self.string()?);
+                }
+                Value::Hash(hash)
+            }
+            TYPE_LIST_ZIPLIST => Value::List(ziplist(&self.string()?)?.into()),
+            TYPE_LIST_QUICKLIST => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.length()? {
+                    list.extend(ziplist(&self.string()?)?);
+                }
+                Value::List(list)
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let mut list = VecDeque::new();
+                for _ in 0..self.length()? {
+                    let container = self.length()? as u64;
+                    let node = self.string()?;
+                    match container {
+                        QUICKLIST_NODE_PLAIN => list.push_back(node),
+                        QUICKLIST_NODE_PACKED => list.extend(listpack(&node)?),
+                        _ => return Err(SnapshotError::Corrupt("invalid quicklist node")),
+                    }
+                }
+                Value::List(list)
+            }
+            TYPE_SET_INTSET => Value::Set(intset(&self.string()?)?.into_iter().collect()),
+            TYPE_SET_LISTPACK => Value::Set(listpack(&self.string()?)?.into_iter().collect()),
+            TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+                let blob = self.string()?;
+                let items = if tag == TYPE_ZSET_ZIPLIST {
+                    ziplist(&blob)?
+                } else {
+                    listpack(&blob)?
+                };
+                let mut zset = SortedSet::new();
+                for pair in pairs(items)? {
+                    zset.insert(pair.0, parse_score(&pair.1)?);
+                }
+                Value::ZSet(zset)
+            }
+            TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+                let blob = self.string()?;
+                let items = if tag == TYPE_HASH_ZIPLIST {
+                    ziplist(&blob)?
+                } else {
+                    listpack(&blob)?
+                };
+                Value::Hash(pairs(items)?.into_iter().collect())
+            }
+            tag => {
+                return Err(SnapshotError::Unsupported(format!(
+                    "RDB value type {} ({})",
+                    tag,
+                    type_description(tag)
+                )))
+            }
+        })
+    }
+}
+
+/// Names the unsupported value types for error messages.
+fn type_description(tag: u8) -> &'static str {
+    match tag {
+        6 | 7 => "module value",
+        9 => "zipmap hash",
+        15 | 19 | 21 => "stream",
+        22..=25 => "hash with field expiry",
+        _ => "unknown",
+    }
+}
+
+/// Formats an integer element the way Redis returns it.
+fn int_bytes(value: i64) -> Bytes {
+    Bytes::from(value.to_string())
+}
+
+fn parse_score(text: &[u8]) -> Result<f64, SnapshotError> {
+    std::str::from_utf8(text)
+        .ok()
+        .and_then(|s| match s {
+            "inf" | "+inf" => Some(f64::INFINITY),
+            "-inf" => Some(f64::NEG_INFINITY),
+            s => s.parse().ok(),
+        })
+        .ok_or(SnapshotError::Corrupt("invalid sorted set score"))
+}
+
+/// Groups a flat field-value (or member-score) sequence into pairs.
+fn pairs(items: Vec<Bytes>) -> Result<Vec<(Bytes, Bytes)>, SnapshotError> {
+    if !items.len().is_multiple_of(2) {
+        return Err(SnapshotError::Corrupt("odd number of elements"));
+    }
+    let mut items = items.into_iter();
+    let mut result = Vec::with_capacity(items.len() / 2);
+    while let (Some(a), Some(b)) = (items.next(), items.next()) {
+        result.push((a, b));
+    }
+    Ok(result)
+}
+
+/// A cursor over an in-memory encoded blob.
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|&end| end <= self.buf.len())
+            .ok_or(SnapshotError::Corrupt("truncated encoded value"))?;
+        let slice = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads an `n`-byte little-endian signed integer.
+    fn int_le(&mut self, n: usize) -> Result<i64, SnapshotError> {
+        let bytes = self.take(n)?;
+        let mut buf = [0; 8];
+        buf[..n].copy_from_slice(bytes);
+        // Sign-extend from the top bit of the last byte read
+        let shift = 64 - 8 * n as u32;
+        Ok((i64::from_le_bytes(buf) << shift) >> shift)
+    }
+
+    fn u32_le(&mut self) -> Result<u32, SnapshotError> {
+        Ok(self.int_le(4)? as u32)
+    }
+}
+
+/// Decodes a ziplist (Redis < 7).
+fn ziplist(blob: &[u8]) -> Result<Vec<Bytes>, SnapshotError> {
+    let mut cur = Cursor::new(blob);
+    cur.take(4 + 4)?; // zlbytes, zltail
+    let count = u16::from_le_bytes([cur.u8()?, cur.u8()?]) as usize;
+    let mut items = Vec::with_capacity(count.min(PREALLOC_LIMIT));
+
+    loop {
+        let prevlen = cur.u8()?;
+        if prevlen == 0xFF {
+            break;
+        }
+        if prevlen == 0xFE {
+            cur.take(4)?;
+        }
+
+        let enc = cur.u8()?;
+        let item = match enc >> 6 {
+            0 => Bytes::copy_from_slice(cur.take((enc & 0x3F) as usize)?),
+            1 => {
+                let len = (((enc & 0x3F) as usize) << 8) | cur.u8()? as usize;
+                Bytes::copy_from_slice(cur.take(len)?)
+            }
+            2 => {
+                let len = u32::from_be_bytes(cur.take(4)?.try_into().unwrap()) as usize;
+                Bytes::copy_from_slice(cur.take(len)?)
+            }
+            _ => int_bytes(match enc {
+                0xC0 => cur.int_le(2)?,
+                0xD0 => cur.int_le(4)?,
+                0xE0 => cur.int_le(8)?,
+                0xF0 => cur.int_le(3)?,
+                0xFE => cur.int_le(1)?,
+                0xF1..=0xFD => (enc & 0x0F) as i64 - 1,
+                _ => return Err(SnapshotError::Corrupt("invalid ziplist entry")),
+            }),
+        };
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// Decodes a listpack (Redis 7+).
+fn listpack(blob: &[u8]) -> Result<Vec<Bytes>, SnapshotError> {
+    let mut cur = Cursor::new(blob);
+    cur.take(4)?; // total bytes
+    let count = u16::from_le_bytes([cur.u8()?, cur.u8()?]) as usize;
+    let mut items = Vec::with_capacity(count.min(PREALLOC_LIMIT));
+
+    loop {
+        let start = cur.pos;
+        let enc = cur.u8()?;
+        if enc == 0xFF {
+            break;
+        }
+
+        let item = if enc & 0x80 == 0 {
+            int_bytes((enc & 0x7F) as i64)
+        } else if enc & 0xC0 == 0x80 {
+            Bytes::copy_from_slice(cur.take((enc & 0x3F) as usize)?)
+        } else if enc & 0xE0 == 0xC0 {
+            // 13-bit two's complement integer
+            let raw = (((enc & 0x1F) as i64) << 8) | cur.u8()? as i64;
+            int_bytes((raw << 51) >> 51)
+        } else if enc & 0xF0 == 0xE0 {
+            let len = (((enc & 0x0F) as usize) << 8) | cur.u8()? as usize;
+            Bytes::copy_from_slice(cur.take(len)?)
+        } else {
+            match enc {
+                0xF0 => {
+                    let len = cur.u32_le()? as usize;
+                    Bytes::copy_from_slice(cur.take(len)?)
+                }
+                0xF1 => int_bytes(cur.int_le(2)?),
+                0xF2 => int_bytes(cur.int_le(3)?),
+                0xF3 => int_bytes(cur.int_le(4)?),
+                0xF4 => int_bytes(cur.int_le(8)?),
+                _ => return Err(SnapshotError::Corrupt("invalid listpack entry")),
+            }
+        };
+
+        // Skip the back-length, which encodes the size of this entry
+        let entry_len = cur.pos - start;
+        let backlen = match entry_len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        cur.take(backlen)?;
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// Decodes an intset.
+fn intset(blob: &[u8]) -> Result<Vec<Bytes>, SnapshotError> {
+    let mut cur = Cursor::new(blob);
+    let width = cur.u32_le()? as usize;
+    if !matches!(width, 2 | 4 | 8) {
+        return Err(SnapshotError::Corrupt("invalid intset encoding"));
+    }
+    let count = cur.u32_le()? as usize;
+    let mut items = Vec::with_capacity(count.min(PREALLOC_LIMIT));
+    for _ in 0..count {
+        items.push(int_bytes(cur.int_le(width)?));
+    }
+    Ok(items)
+}
+
+/// Decompresses LZF data to exactly `len` bytes.
+fn lzf_decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len.min(1 << 20));
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+
+        if ctrl < 32 {
+            // Literal run of ctrl + 1 bytes
+            let run = input.get(i..i + ctrl + 1)?;
+            out.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            // Back reference
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i)? as usize;
+                i += 1;
+            }
+            let offset = ((ctrl & 0x1F) << 8) + *input.get(i)? as usize + 1;
+            i += 1;
+            let from = out.len().checked_sub(offset)?;
+            for k in 0..run + 2 {
+                out.push(out[from + k]);
+            }
+        }
+
+        if out.len() > len {
+            return None;
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+/// CRC-64 (Jones polynomial, reflected) lookup table, as used by Redis.
+const CRC64_TABLE: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0x95AC_9329_AC4B_C9B5 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Continues a Redis CRC-64 over `buf`.
+fn crc64(mut crc: u64, buf: &[u8]) -> u64 {
+    for &byte in buf {
+        crc = CRC64_TABLE[((crc ^ byte as u64) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("flashkv-{}-{}.rdb", std::process::id(), name))
+    }
+
+    /// Builds RDB files the way Redis writes them.
+    struct Builder(Vec<u8>);
+
+    impl Builder {
+        fn new(version: &str) -> Self {
+            Self(format!("REDIS{}", version).into_bytes())
+        }
+
+        fn len(&mut self, len: usize) -> &mut Self {
+            if len < 64 {
+                self.0.push(len as u8);
+            } else if len < 16384 {
+                self.0
+                    .extend_from_slice(&[0x40 | (len >> 8) as u8, len as u8]);
+            } else {
+                self.0.push(0x80);
+                self.0.extend_from_slice(&(len as u32).to_be_bytes());
+            }
+            self
+        }
+
+        fn string(&mut self, s: &[u8]) -> &mut Self {
+            self.len(s.len());
+            self.0.extend_from_slice(s);
+            self
+        }
+
+        fn raw(&mut self, bytes: &[u8]) -> &mut Self {
+            self.0.extend_from_slice(bytes);
+            self
+        }
+
+        fn finish(&mut self) -> Vec<u8> {
+            self.0.push(OP_EOF);
+            let crc = crc64(0, &self.0);
+            self.0.extend_from_slice(&crc.to_le_bytes());
+            std::mem::take(&mut self.0)
+        }
+    }
+
+    /// Encodes strings and small integers as a listpack.
+    fn make_listpack(items: &[&[u8]]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for item in items {
+            let start = body.len();
+            match std::str::from_utf8(item)
+                .ok()
+                .and_then(|s| s.parse::<u8>().ok())
+            {
+                Some(n) if n < 128 && item.len() < 4 => body.push(n),
+                _ => {
+                    assert!(item.len() < 64);
+                    body.push(0x80 | item.len() as u8);
+                    body.extend_from_slice(item);
+                }
+            }
+            body.push((body.len() - start) as u8);
+        }
+        let mut lp = Vec::new();
+        lp.extend_from_slice(&((6 + body.len() + 1) as u32).to_le_bytes());
+        lp.extend_from_slice(&(items.len() as u16).to_le_bytes());
+        lp.extend_from_slice(&body);
+        lp.push(0xFF);
+        lp
+    }
+
+    /// Encodes strings as a ziplist.
+    fn make_ziplist(items: &[&[u8]]) -> Vec<u8> {
+        let mut body = Vec::new();
+        let mut prev = 0;
+        for item in items {
+            let start = body.len();
+            body.push(prev as u8);
+            body.push(item.len() as u8);
+            body.extend_from_slice(item);
+            prev = body.len() - start;
+        }
+        let mut zl = Vec::new();
+        zl.extend_from_slice(&((10 + body.len() + 1) as u32).to_le_bytes());
+        zl.extend_from_slice(&0u32.to_le_bytes());
+        zl.extend_from_slice(&(items.len() as u16).to_le_bytes());
+        zl.extend_from_slice(&body);
+        zl.push(0xFF);
+        zl
+    }
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(crc64(0, b"123456789"), 0xE9C6_D914_C4B8_D9CA);
+    }
+
+    #[test]
+    fn test_lzf_decompress() {
+        // "aaaaaaaaaa": one literal, then a back reference of 9 bytes at offset 1
+        let data = [0x00, b'a', 0xE0, 0x00, 0x00];
+        assert_eq!(lzf_decompress(&data, 10), Some(b"aaaaaaaaaa".to_vec()));
+        assert_eq!(lzf_decompress(&data, 9), None);
+        assert_eq!(lzf_decompress(&[0xE0, 0x00, 0x05], 9), None);
+    }
+
+    #[test]
+    fn test_listpack_integers() {
+        let mut lp = Vec::new();
+        lp.extend_from_slice(&[0; 6]);
+        // 13-bit -1, then int16 1000, then int64 -5
+        lp.extend_from_slice(&[0xDF, 0xFF, 2]);
+        lp.push(0xF1);
+        lp.extend_from_slice(&1000i16.to_le_bytes());
+        lp.push(3);
+        lp.push(0xF4);
+        lp.extend_from_slice(&(-5i64).to_le_bytes());
+        lp.push(9);
+        lp.push(0xFF);
+        assert_eq!(
+            listpack(&lp).unwrap(),
+            vec![Bytes::from("-1"), Bytes::from("1000"), Bytes::from("-5")]
+        );
+    }
+
+    #[test]
+    fn test_load_redis_dump() {
+        let now = clock::unix_time_ms();
+        let mut rdb = Builder::new("0011");
+        rdb.raw(&[OP_AUX])
+            .string(b"redis-ver")
+            .string(b"7.2.4")
+            .raw(&[OP_SELECTDB])
+            .len(0)
+            .raw(&[OP_RESIZEDB])
+            .len(9)
+            .len(2);
+
+        // Strings: plain, integer-encoded, LZF-compressed
+        rdb.raw(&[TYPE_STRING]).string(b"name").string(b"flashkv");
+        rdb.raw(&[TYPE_STRING])
+            .string(b"count")
+            .raw(&[0xC1])
+            .raw(&(-300i16).to_le_bytes());
+        rdb.raw(&[TYPE_STRING])
+            .string(b"zipped")
+            .raw(&[0xC3])
+            .len(5)
+            .len(10)
+            .raw(&[0x00, b'a', 0xE0, 0x00, 0x00]);
+
+        // A key with a future expiry and one that already expired
+        rdb.raw(&[OP_EXPIRETIME_MS])
+            .raw(&(now + 60_000).to_le_bytes())
+            .raw(&[OP_FREQ, 5, TYPE_STRING])
+            .string(b"session")
+            .string(b"token");
+        rdb.raw(&[OP_EXPIRETIME])
+            .raw(&1u32.to_le_bytes())
+            .raw(&[TYPE_STRING])
+            .string(b"old")
+            .string(b"gone");
+
+        rdb.raw(&[TYPE_LIST_QUICKLIST_2])
+            .string(b"list")
+            .len(2)
+            .len(QUICKLIST_NODE_PACKED as usize)
+            .string(&make_listpack(&[b"a", b"b"]))
+            .len(QUICKLIST_NODE_PLAIN as usize)
+            .string(b"big");
+        let mut intset = Vec::new();
+        intset.extend_from_slice(&2u32.to_le_bytes());
+        intset.extend_from_slice(&2u32.to_le_bytes());
+        intset.extend_from_slice(&(-1i16).to_le_bytes());
+        intset.extend_from_slice(&7i16.to_le_bytes());
+        rdb.raw(&[TYPE_SET_INTSET]).string(b"ints").string(&intset);
+        rdb.raw(&[TYPE_SET_LISTPACK])
+            .string(b"tags")
+            .string(&make_listpack(&[b"x", b"y", b"z"]));
+        rdb.raw(&[TYPE_HASH_ZIPLIST])
+            .string(b"user")
+            .string(&make_ziplist(&[b"name", b"ariz", b"lang", b"rust"]));
+        rdb.raw(&[TYPE_ZSET_LISTPACK])
+            .string(b"board")
+            .string(&make_listpack(&[b"alice", b"10", b"bob", b"2.5"]));
+        rdb.raw(&[TYPE_ZSET_2])
+            .string(b"scores")
+            .len(1)
+            .string(b"carol")
+            .raw(&(-1.5f64).to_le_bytes());
+
+        let path = temp_path("dump");
+        std::fs::write(&path, rdb.finish()).unwrap();
+        let engine = StorageEngine::new();
+        assert_eq!(load(&engine, &path).unwrap(), 10);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            engine.get(&Bytes::from("name")),
+            Some(Bytes::from("flashkv"))
+        );
+        assert_eq!(engine.get(&Bytes::from("count")), Some(Bytes::from("-300")));
+        assert_eq!(
+            engine.get(&Bytes::from("zipped")),
+            Some(Bytes::from("aaaaaaaaaa"))
+        );
+        assert_eq!(
+            engine.expire_time(&Bytes::from("session")),
+            Some(now + 60_000)
+        );
+        assert!(!engine.exists(&Bytes::from("old")));
+        assert_eq!(
+            engine.lrange(&Bytes::from("list"), 0, -1),
+            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("big")]
+        );
+        assert!(engine.sismember(&Bytes::from("ints"), &Bytes::from("-1")));
+        assert_eq!(engine.scard(&Bytes::from("tags")), 3);
+        assert_eq!(
+            engine.hget(&Bytes::from("user"), &Bytes::from("lang")),
+            Some(Bytes::from("rust"))
+        );
+        assert_eq!(
+            engine.zscore(&Bytes::from("board"), &Bytes::from("bob")),
+            Some(2.5)
+        );
+        assert_eq!(
+            engine.zscore(&Bytes::from("scores"), &Bytes::from("carol")),
+            Some(-1.5)
+        );
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        let path = temp_path("bad");
+        let engine = StorageEngine::new();
+
+        let mut rdb = Builder::new("0011");
+        rdb.raw(&[TYPE_STRING]).string(b"k").string(b"v");
+        let mut data = rdb.finish();
+        let at = data.len() - 10;
+        data[at] ^= 0x01;
+        std::fs::write(&path, &data).unwrap();
+        assert!(matches!(
+            load(&engine, &path),
+            Err(SnapshotError::Corrupt("checksum mismatch"))
+        ));
+
+        let mut rdb = Builder::new("0011");
+        rdb.raw(&[15]).string(b"events").len(0);
+        std::fs::write(&path, rdb.finish()).unwrap();
+        let err = load(&engine, &path).unwrap_err();
+        assert!(err.to_string().contains("stream"));
+
+        std::fs::write(&path, Builder::new("0099").finish()).unwrap();
+        assert!(matches!(
+            load(&engine, &path),
+            Err(SnapshotError::Unsupported(_))
+        ));
+
+        std::fs::write(&path, b"FLASHKV\x01").unwrap();
+        assert!(matches!(
+            load(&engine, &path),
+            Err(SnapshotError::Corrupt(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+        assert!(engine.is_empty());
+    }
+}
@@ -0,0 +1,224 @@
+//! Shard Locks
+//!
+//! Each shard of a database guards its map with one lock, of the kind
+//! picked by [`ShardLocking`] when the engine is created:
+//!
+//! ```text
+//! ReadWrite (default)                 Bucket
+//! ┌──────────┐ ┌──────────┐           ┌───┐┌───┐┌───┐┌───┐┌───┐┌───┐┌───┐
+//! │ RwLock   │ │ RwLock   │  ...      │ M ││ M ││ M ││ M ││ M ││ M ││...│
+//! │ many keys│ │ many keys│           └───┘└───┘└───┘└───┘└───┘└───┘└───┘
+//! └──────────┘ └──────────┘           many small buckets, one mutex each
+//! ```
+//!
+//! A reader-writer lock lets readers of a shard run side by side, but each
+//! of them still writes to the lock's reader count, so on many cores
+//! readers of a hot shard bounce that cache line between them. A mutex is
+//! a single byte that is cheaper to take and release, and with enough
+//! buckets two threads rarely want the same one, so it trades shared
+//! reads for finer-grained locking. `benches/throughput.rs` compares the
+//! two under 8 to 64 threads.
+//!
+//! Either way the engine sees the same guards: a [`ReadGuard`] derefs to
+//! the map, a [`WriteGuard`] also derefs mutably, and an
+//! [`UpgradableGuard`] upgrades to a write guard. With [`Bucket`] every
+//! guard is exclusive, which is why the engine never locks a shard twice
+//! and takes several shards in ascending order.
+//!
+//! [`Bucket`]: ShardLocking::Bucket
+
+use parking_lot::{
+    Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockUpgradableReadGuard, RwLockWriteGuard,
+};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+
+/// How each shard of a database is locked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ShardLocking {
+    /// A reader-writer lock per shard: readers of a shard share it
+    #[default]
+    ReadWrite,
+    /// A mutex per shard, meant for many small shards (buckets): every
+    /// access is exclusive, but cheaper
+    Bucket,
+}
+
+impl ShardLocking {
+    /// Parses a `--shard-locking` value: `rwlock` or `bucket`.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "rwlock" => Some(Self::ReadWrite),
+            "bucket" => Some(Self::Bucket),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of shards a database has with this locking
+    /// unless configured otherwise: [`default_shard_count`] reader-writer
+    /// shards, or 64 buckets per available CPU, between 256 and 16384.
+    ///
+    /// [`default_shard_count`]: super::default_shard_count
+    pub fn default_shard_count(self) -> usize {
+        match self {
+            Self::ReadWrite => super::default_shard_count(),
+            Self::Bucket => {
+                let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+                (cpus * 64).next_power_of_two().clamp(256, 16384)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ShardLocking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::ReadWrite => "rwlock",
+            Self::Bucket => "bucket",
+        })
+    }
+}
+
+/// The lock guarding a shard's map.
+#[derive(Debug)]
+pub(crate) enum ShardLock<T> {
+    ReadWrite(RwLock<T>),
+    Bucket(Mutex<T>),
+}
+
+impl<T> ShardLock<T> {
+    pub(crate) fn new(locking: ShardLocking, value: T) -> Self {
+        match locking {
+            ShardLocking::ReadWrite => Self::ReadWrite(RwLock::new(value)),
+            ShardLocking::Bucket => Self::Bucket(Mutex::new(value)),
+        }
+    }
+
+    /// Locks for reading, shared with other readers unless the shard is a
+    /// bucket.
+    pub(crate) fn read(&self) -> ReadGuard<'_, T> {
+        match self {
+            Self::ReadWrite(lock) => ReadGuard::ReadWrite(lock.read()),
+            Self::Bucket(lock) => ReadGuard::Bucket(lock.lock()),
+        }
+    }
+
+    /// Locks for writing.
+    pub(crate) fn write(&self) -> WriteGuard<'_, T> {
+        match self {
+            Self::ReadWrite(lock) => WriteGuard::ReadWrite(lock.write()),
+            Self::Bucket(lock) => WriteGuard::Bucket(lock.lock()),
+        }
+    }
+
+    /// Locks for reading, in a way that can be upgraded to a write lock
+    /// without letting another writer in between.
+    pub(crate) fn upgradable_read(&self) -> UpgradableGuard<'_, T> {
+        match self {
+            Self::ReadWrite(lock) => UpgradableGuard::ReadWrite(lock.upgradable_read()),
+            Self::Bucket(lock) => UpgradableGuard::Bucket(lock.lock()),
+        }
+    }
+}
+
+/// A shard locked for reading.
+pub(crate) enum ReadGuard<'a, T> {
+    ReadWrite(RwLockReadGuard<'a, T>),
+    Bucket(MutexGuard<'a, T>),
+}
+
+/// A shard locked for writing.
+pub(crate) enum WriteGuard<'a, T> {
+    ReadWrite(RwLockWriteGuard<'a, T>),
+    Bucket(MutexGuard<'a, T>),
+}
+
+/// A shard locked for reading, which can be upgraded to a write lock.
+pub(crate) enum UpgradableGuard<'a, T> {
+    ReadWrite(RwLockUpgradableReadGuard<'a, T>),
+    Bucket(MutexGuard<'a, T>),
+}
+
+impl<'a, T> UpgradableGuard<'a, T> {
+    /// Upgrades to a write lock, waiting for the other readers to leave.
+    pub(crate) fn upgrade(self) -> WriteGuard<'a, T> {
+        match self {
+            Self::ReadWrite(guard) => {
+                WriteGuard::ReadWrite(RwLockUpgradableReadGuard::upgrade(guard))
+            }
+            Self::Bucket(guard) => WriteGuard::Bucket(guard),
+        }
+    }
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Self::ReadWrite(guard) => guard,
+            Self::Bucket(guard) => guard,
+        }
+    }
+}
+
+impl<T> Deref for WriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Self::ReadWrite(guard) => guard,
+            Self::Bucket(guard) => guard,
+        }
+    }
+}
+
+impl<T> DerefMut for WriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match self {
+            Self::ReadWrite(guard) => guard,
+            Self::Bucket(guard) => guard,
+        }
+    }
+}
+
+impl<T> Deref for UpgradableGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Self::ReadWrite(guard) => guard,
+            Self::Bucket(guard) => guard,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(ShardLocking::parse("RWLOCK"), Some(ShardLocking::ReadWrite));
+        assert_eq!(ShardLocking::parse("bucket"), Some(ShardLocking::Bucket));
+        assert_eq!(ShardLocking::parse("dashmap"), None);
+        assert_eq!(ShardLocking::Bucket.to_string(), "bucket");
+        assert!(
+            ShardLocking::Bucket.default_shard_count()
+                > ShardLocking::ReadWrite.default_shard_count()
+        );
+    }
+
+    #[test]
+    fn test_guards() {
+        for locking in [ShardLocking::ReadWrite, ShardLocking::Bucket] {
+            let lock = ShardLock::new(locking, vec![1]);
+            lock.write().push(2);
+            assert_eq!(*lock.read(), [1, 2]);
+            let guard = lock.upgradable_read();
+            assert_eq!(guard.len(), 2);
+            guard.upgrade().push(3);
+            assert_eq!(*lock.read(), [1, 2, 3]);
+        }
+    }
+}
@@ -0,0 +1,1410 @@
+//! Snapshot Persistence
+//!
+//! A snapshot is a point-in-time copy of the whole keyspace, every database
+//! of it ([`Snapshot`]),
+//! and this module dumps one to a single file in the spirit of Redis' RDB. SAVE writes one in the foreground,
+//! BGSAVE on a background thread, and the server loads the configured file
+//! at startup so data survives a restart.
+//!
+//! ## File Format
+//!
+//! ```text
+//! ┌─────────────────────────────────────────────────────────────┐
+//! │ "FLASHKV" │ version (u8)                                    │
+//! ├─────────────────────────────────────────────────────────────┤
+//! │ 0xF5 │ library code                                         │  one per function
+//! │ ...                                                         │  library
+//! ├─────────────────────────────────────────────────────────────┤
+//! │ [0xFC expiry (i64 Unix ms)] │ type (u8) │ key │ value       │  one per key
+//! │ ...                                                         │  of database 0
+//! ├─────────────────────────────────────────────────────────────┤
+//! │ 0xFE │ database index                                       │  then for each
+//! │ [0xFC expiry (i64 Unix ms)] │ type (u8) │ key │ value       │  other database
+//! │ ...                                                         │  with keys
+//! ├─────────────────────────────────────────────────────────────┤
+//! │ 0xFF │ CRC-32 of everything before it (u32)                 │
+//! └─────────────────────────────────────────────────────────────┘
+//! ```
+//!
+//! Lengths and counts are LEB128 varints, strings are a length followed by
+//! the raw bytes, and fixed-width numbers are little-endian. Expiries are
+//! stored as absolute Unix timestamps (see [`clock`](crate::storage::clock)),
+//! so keys whose deadline passed while the server was down are dropped on
+//! load instead of coming back to life. Function libraries are saved as
+//! their code and run again on load; version 1 files, written before
+//! libraries were saved, still load.
+//!
+//! ## Automatic Saves
+//!
+//! Every write command counts as a change. A [`SaveRule`] such as
+//! `300 100` asks for a background save once 100 changes have accumulated
+//! and 300 seconds have passed since the last save; the
+//! [`SaveScheduler`](crate::storage::autosave::SaveScheduler) checks the
+//! rules every second.
+//!
+//! ## Atomicity
+//!
+//! The snapshot is written to a temporary file in the same directory and
+//! renamed over the target once it has been synced, so a crash mid-save
+//! leaves the previous snapshot intact. On load the checksum is verified
+//! before anything is inserted, so a damaged file is rejected as a whole.
+//!
+//! ## Compression
+//!
+//! With a [`Codec`] configured, the file above is written compressed inside
+//! a small frame (see [`compression`](crate::storage::compression)).
+//! Loading detects the frame, so plain and compressed files can be mixed.
+
+use crate::scripting::{FunctionError, RestorePolicy};
+use crate::storage::clock;
+use crate::storage::compression::{self, Codec, Compression};
+use crate::storage::engine::{Entry, StorageEngine, Value};
+use crate::storage::stream::Stream;
+use crate::storage::zset::SortedSet;
+use bytes::Bytes;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::RwLock;
+
+/// Magic bytes at the start of every snapshot.
+const MAGIC: &[u8; 7] = b"FLASHKV";
+
+/// Version of the snapshot format.
+const VERSION: u8 = 2;
+
+/// Oldest version of the snapshot format that still loads.
+const OLDEST_VERSION: u8 = 1;
+
+/// Opcode: the code of a function library follows.
+const OP_FUNCTION: u8 = 0xF5;
+
+/// Opcode: the next key expires at the following Unix time in milliseconds.
+const OP_EXPIRE_MS: u8 = 0xFC;
+
+/// Opcode: the keys that follow belong to the database whose index follows.
+const OP_SELECTDB: u8 = 0xFE;
+
+/// Opcode: end of the keyspace, followed by the checksum.
+const OP_EOF: u8 = 0xFF;
+
+/// Value type tags.
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_HASH: u8 = 2;
+const TYPE_SET: u8 = 3;
+const TYPE_ZSET: u8 = 4;
+const TYPE_STREAM: u8 = 5;
+
+/// Default snapshot file name.
+pub const DEFAULT_DBFILENAME: &str = "dump.fkv";
+
+/// Errors from saving or loading a snapshot.
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    /// Another save is still running
+    #[error("Background save already in progress")]
+    InProgress,
+
+    /// Another append-only file rewrite is still running
+    #[error("Background append only file rewriting already in progress")]
+    RewriteInProgress,
+
+    /// Reading or writing the file failed
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// The file isn't a valid snapshot
+    #[error("corrupt snapshot: {0}")]
+    Corrupt(&'static str),
+
+    /// The append-only file can't be replayed past `offset`
+    #[error("bad append-only file format at byte {offset}: {reason}")]
+    BadAof { offset: u64, reason: &'static str },
+
+    /// The file uses a feature FlashKV can't load
+    #[error("unsupported: {0}")]
+    Unsupported(String),
+
+    /// A function library in the file doesn't load
+    #[error("function library: {0}")]
+    Function(#[from] FunctionError),
+
+    /// An export file name that is absolute or climbs out with `..`
+    #[error("export file name must be a relative path inside the data directory")]
+    OutsideDir,
+}
+
+impl SnapshotError {
+    /// Returns true if the snapshot file doesn't exist.
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, SnapshotError::Io(e) if e.kind() == io::ErrorKind::NotFound)
+    }
+}
+
+/// After a failed background save, automatic saves wait this long before
+/// trying again.
+const SAVE_RETRY_DELAY_SECS: i64 = 5;
+
+/// An automatic save rule: snapshot once at least `changes` writes have
+/// accumulated and `seconds` have passed since the last save.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveRule {
+    /// Minimum time since the last save
+    pub seconds: u64,
+    /// Minimum number of changes since the last save
+    pub changes: u64,
+}
+
+impl SaveRule {
+    /// The rules used unless configured otherwise (the Redis defaults).
+    pub const DEFAULTS: [SaveRule; 3] = [
+        SaveRule::new(3600, 1),
+        SaveRule::new(300, 100),
+        SaveRule::new(60, 10000),
+    ];
+
+    /// Creates a rule.
+    pub const fn new(seconds: u64, changes: u64) -> Self {
+        Self { seconds, changes }
+    }
+
+    /// Parses rules written as `<seconds> <changes>` pairs, such as
+    /// `"3600 1 300 100"`. An empty string means no rules.
+    ///
+    /// # Returns
+    ///
+    /// `None` if the string isn't a list of such pairs.
+    pub fn parse_list(s: &str) -> Option<Vec<SaveRule>> {
+        let numbers: Vec<u64> = s
+            .split_whitespace()
+            .map(|n| n.parse().ok())
+            .collect::<Option<_>>()?;
+        if !numbers.len().is_multiple_of(2) {
+            return None;
+        }
+        Some(
+            numbers
+                .chunks(2)
+                .map(|pair| SaveRule::new(pair[0], pair[1]))
+                .collect(),
+        )
+    }
+}
+
+impl fmt::Display for SaveRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.seconds, self.changes)
+    }
+}
+
+/// Where snapshots go, when they are taken automatically, and how the last
+/// one went.
+#[derive(Debug)]
+pub struct Snapshots {
+    /// Path of the snapshot file
+    path: RwLock<PathBuf>,
+
+    /// Rules triggering automatic background saves
+    rules: RwLock<Vec<SaveRule>>,
+
+    /// How snapshots (and append-only file preambles) are compressed
+    compression: RwLock<Compression>,
+
+    /// Changes since the last successful save
+    changes: AtomicU64,
+
+    /// Value of `changes` when the running save started
+    changes_at_start: AtomicU64,
+
+    /// Whether a save is running
+    saving: AtomicBool,
+
+    /// Whether the running save is a background one
+    background: AtomicBool,
+
+    /// Unix time in milliseconds the running (or last) save started
+    started_ms: AtomicI64,
+
+    /// How long the last save took in milliseconds (-1 if none yet)
+    last_duration_ms: AtomicI64,
+
+    /// Unix time in seconds of the last successful save (or load)
+    last_save: AtomicI64,
+
+    /// Unix time in seconds the last save started
+    last_attempt: AtomicI64,
+
+    /// Whether the last save succeeded
+    last_save_ok: AtomicBool,
+}
+
+impl Default for Snapshots {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Snapshots {
+    /// Creates the state for snapshots saved to [`DEFAULT_DBFILENAME`] in
+    /// the working directory under the [default rules](SaveRule::DEFAULTS).
+    pub fn new() -> Self {
+        let now = clock::unix_time_ms() / 1000;
+        Self {
+            path: RwLock::new(PathBuf::from(DEFAULT_DBFILENAME)),
+            rules: RwLock::new(SaveRule::DEFAULTS.to_vec()),
+            compression: RwLock::new(Compression::NONE),
+            changes: AtomicU64::new(0),
+            changes_at_start: AtomicU64::new(0),
+            saving: AtomicBool::new(false),
+            background: AtomicBool::new(false),
+            started_ms: AtomicI64::new(0),
+            last_duration_ms: AtomicI64::new(-1),
+            last_save: AtomicI64::new(now),
+            last_attempt: AtomicI64::new(now),
+            last_save_ok: AtomicBool::new(true),
+        }
+    }
+
+    /// Returns the path of the snapshot file.
+    pub fn path(&self) -> PathBuf {
+        self.path.read().unwrap().clone()
+    }
+
+    /// Sets the path of the snapshot file.
+    pub fn set_path(&self, path: impl Into<PathBuf>) {
+        *self.path.write().unwrap() = path.into();
+    }
+
+    /// Returns the automatic save rules.
+    pub fn rules(&self) -> Vec<SaveRule> {
+        self.rules.read().unwrap().clone()
+    }
+
+    /// Replaces the automatic save rules (an empty list disables them).
+    pub fn set_rules(&self, rules: Vec<SaveRule>) {
+        *self.rules.write().unwrap() = rules;
+    }
+
+    /// Returns how snapshots are compressed.
+    pub fn compression(&self) -> Compression {
+        *self.compression.read().unwrap()
+    }
+
+    /// Sets how snapshots are compressed from the next save on.
+    pub fn set_compression(&self, compression: Compression) {
+        *self.compression.write().unwrap() = compression;
+    }
+
+    /// Returns the number of changes since the last successful save.
+    pub fn changes(&self) -> u64 {
+        self.changes.load(Ordering::Relaxed)
+    }
+
+    /// Counts `n` changes to the keyspace towards the save rules.
+    pub fn record_changes(&self, n: u64) {
+        self.changes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Returns the first save rule that is due at Unix time `now` (in
+    /// seconds), if any.
+    ///
+    /// Nothing is due while a save is running, or within a few seconds of
+    /// a failed one so a broken disk isn't hammered.
+    pub fn due_rule(&self, now: i64) -> Option<SaveRule> {
+        if self.in_progress()
+            || (!self.last_save_ok() && now - self.last_attempt() < SAVE_RETRY_DELAY_SECS)
+        {
+            return None;
+        }
+
+        let changes = self.changes();
+        let elapsed = u64::try_from(now - self.last_save()).unwrap_or(0);
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .find(|rule| changes >= rule.changes && elapsed >= rule.seconds)
+            .copied()
+    }
+
+    /// Returns true if a save is running.
+    pub fn in_progress(&self) -> bool {
+        self.saving.load(Ordering::Acquire)
+    }
+
+    /// Returns true if a background save is running.
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.in_progress() && self.background.load(Ordering::Relaxed)
+    }
+
+    /// Returns how long the running save has taken so far in seconds, or
+    /// -1 if no save is running.
+    pub fn current_duration_secs(&self) -> i64 {
+        if !self.in_progress() {
+            return -1;
+        }
+        (clock::unix_time_ms() - self.started_ms.load(Ordering::Relaxed)) / 1000
+    }
+
+    /// Returns how long the last save took in seconds, or -1 if there
+    /// hasn't been one.
+    pub fn last_duration_secs(&self) -> i64 {
+        match self.last_duration_ms.load(Ordering::Relaxed) {
+            -1 => -1,
+            ms => ms / 1000,
+        }
+    }
+
+    /// Returns the Unix time in seconds of the last successful save.
+    pub fn last_save(&self) -> i64 {
+        self.last_save.load(Ordering::Relaxed)
+    }
+
+    /// Returns the Unix time in seconds the last save started.
+    pub fn last_attempt(&self) -> i64 {
+        self.last_attempt.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the last save succeeded.
+    pub fn last_save_ok(&self) -> bool {
+        self.last_save_ok.load(Ordering::Relaxed)
+    }
+
+    /// Claims the right to save, failing if another save is running.
+    pub(crate) fn begin(&self, background: bool) -> Result<(), SnapshotError> {
+        self.saving
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .map_err(|_| SnapshotError::InProgress)?;
+        let now_ms = clock::unix_time_ms();
+        self.background.store(background, Ordering::Relaxed);
+        self.changes_at_start
+            .store(self.changes(), Ordering::Relaxed);
+        self.started_ms.store(now_ms, Ordering::Relaxed);
+        self.last_attempt.store(now_ms / 1000, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Records the outcome of a save started with [`begin`](Self::begin).
+    ///
+    /// On success, only the changes made before the save started are
+    /// cleared: later ones may not be in the file.
+    pub(crate) fn finish(&self, ok: bool) {
+        if ok {
+            self.changes.fetch_sub(
+                self.changes_at_start.load(Ordering::Relaxed),
+                Ordering::Relaxed,
+            );
+            self.mark_saved();
+        }
+        let started_ms = self.started_ms.load(Ordering::Relaxed);
+        self.last_duration_ms
+            .store(clock::unix_time_ms() - started_ms, Ordering::Relaxed);
+        self.last_save_ok.store(ok, Ordering::Relaxed);
+        self.saving.store(false, Ordering::Release);
+    }
+
+    /// Records that the keyspace matches the snapshot file as of now.
+    pub(crate) fn mark_saved(&self) {
+        self.last_save
+            .store(clock::unix_time_ms() / 1000, Ordering::Relaxed);
+    }
+}
+
+/// The live entries of each shard of a database.
+type ShardEntries = Vec<Vec<(Bytes, Entry)>>;
+
+/// A consistent, point-in-time copy of the keyspace, taken with
+/// [`StorageEngine::snapshot`].
+///
+/// Values are shared with the live keyspace rather than copied, so a
+/// snapshot is cheap to take and to keep around while it is written out,
+/// streamed to a replica or inspected by a backup tool.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    /// Index of each database, with its entries when the snapshot was taken
+    dbs: Vec<(usize, ShardEntries)>,
+    /// Code of each function library
+    functions: Vec<Bytes>,
+    /// Total number of entries
+    len: usize,
+    /// Unix time in milliseconds the snapshot was taken
+    taken_at_ms: i64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        dbs: Vec<(usize, ShardEntries)>,
+        functions: Vec<Bytes>,
+        taken_at_ms: i64,
+    ) -> Self {
+        Self {
+            functions,
+            len: dbs
+                .iter()
+                .flat_map(|(_, shards)| shards)
+                .map(Vec::len)
+                .sum(),
+            dbs,
+            taken_at_ms,
+        }
+    }
+
+    /// Returns the number of keys in the snapshot.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the snapshot holds no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the code of each function library, sorted by name.
+    pub fn functions(&self) -> &[Bytes] {
+        &self.functions
+    }
+
+    /// Returns the Unix time in milliseconds the snapshot was taken.
+    pub fn taken_at_ms(&self) -> i64 {
+        self.taken_at_ms
+    }
+
+    /// Iterates over the keys and their entries, of every database, in no
+    /// particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Entry)> + '_ {
+        self.dbs
+            .iter()
+            .flat_map(|(_, shards)| shards)
+            .flatten()
+            .map(|(key, entry)| (key, entry))
+    }
+
+    /// Iterates over the keys and their entries of database `db`.
+    pub fn db_iter(&self, db: usize) -> impl Iterator<Item = (&Bytes, &Entry)> + '_ {
+        self.dbs
+            .iter()
+            .filter(move |(index, _)| *index == db)
+            .flat_map(|(_, shards)| shards)
+            .flatten()
+            .map(|(key, entry)| (key, entry))
+    }
+}
+
+impl IntoIterator for Snapshot {
+    type Item = (Bytes, Entry);
+    type IntoIter = std::iter::Flatten<std::vec::IntoIter<Vec<(Bytes, Entry)>>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let shards: Vec<_> = self
+            .dbs
+            .into_iter()
+            .flat_map(|(_, shards)| shards)
+            .collect();
+        shards.into_iter().flatten()
+    }
+}
+
+/// Writes `snapshot` to `path`, compressed as asked.
+///
+/// # Returns
+///
+/// The number of keys written.
+pub fn save(
+    snapshot: &Snapshot,
+    path: &Path,
+    compression: Compression,
+) -> Result<usize, SnapshotError> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tmp = path.with_file_name(format!("temp-{}-{}", std::process::id(), file_name));
+
+    let result = write_file(snapshot, &tmp, compression).and_then(|keys| {
+        fs::rename(&tmp, path)?;
+        Ok(keys)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn write_file(
+    snapshot: &Snapshot,
+    path: &Path,
+    compression: Compression,
+) -> Result<usize, SnapshotError> {
+    let writer = write_compressed_to(snapshot, compression, BufWriter::new(File::create(path)?))?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    Ok(snapshot.len())
+}
+
+/// Encodes `snapshot` into `writer`, returning the writer so more data can
+/// follow it (as in an append-only file with a snapshot preamble).
+pub(crate) fn write_to<W: Write>(snapshot: &Snapshot, writer: W) -> io::Result<W> {
+    let mut enc = Encoder::new(writer);
+    enc.raw(MAGIC)?;
+    enc.u8(VERSION)?;
+    for code in &snapshot.functions {
+        enc.u8(OP_FUNCTION)?;
+        enc.bytes(code)?;
+    }
+
+    let mut current = 0;
+    for (db, shards) in &snapshot.dbs {
+        let mut entries = shards.iter().flatten().peekable();
+        if *db != current && entries.peek().is_some() {
+            enc.u8(OP_SELECTDB)?;
+            enc.len(*db)?;
+            current = *db;
+        }
+        for (key, entry) in entries {
+            if let Some(unix_ms) = entry.expire_time_ms() {
+                enc.u8(OP_EXPIRE_MS)?;
+                enc.i64(unix_ms)?;
+            }
+            write_value(&mut enc, key, &entry.value)?;
+        }
+    }
+
+    enc.u8(OP_EOF)?;
+    let crc = enc.crc.finish();
+    enc.raw(&crc.to_le_bytes())?;
+    Ok(enc.inner)
+}
+
+/// Like [`write_to`], but compresses the snapshot unless the codec is
+/// [`Codec::None`].
+pub(crate) fn write_compressed_to<W: Write>(
+    snapshot: &Snapshot,
+    compression: Compression,
+    mut writer: W,
+) -> io::Result<W> {
+    if compression.codec == Codec::None {
+        return write_to(snapshot, writer);
+    }
+    let framed = compression::compress(compression, |w| write_to(snapshot, w).map(|_| ()))?;
+    writer.write_all(&framed)?;
+    Ok(writer)
+}
+
+fn write_value<W: Write>(enc: &mut Encoder<W>, key: &[u8], value: &Value) -> io::Result<()> {
+    let tag = match value {
+        Value::String(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::Hash(_) => TYPE_HASH,
+        Value::Set(_) => TYPE_SET,
+        Value::ZSet(_) => TYPE_ZSET,
+        Value::Stream(_) => TYPE_STREAM,
+    };
+    enc.u8(tag)?;
+    enc.bytes(key)?;
+
+    match value {
+        Value::String(s) => enc.bytes(&s.as_bytes()),
+        Value::List(list) => {
+            enc.len(list.len())?;
+            list.iter().try_for_each(|item| enc.bytes(item))
+        }
+        Value::Hash(hash) => {
+            enc.len(hash.len())?;
+            hash.iter().try_for_each(|(field, value)| {
+                enc.bytes(field)?;
+                enc.bytes(value)
+            })
+        }
+        Value::Set(set) => {
+            enc.len(set.len())?;
+            set.iter().try_for_each(|member| enc.bytes(member))
+        }
+        Value::ZSet(zset) => {
+            enc.len(zset.len())?;
+            zset.iter().try_for_each(|(member, score)| {
+                enc.bytes(member)?;
+                enc.f64(score)
+            })
+        }
+        Value::Stream(stream) => stream.encode(enc),
+    }
+}
+
+/// Loads the snapshot at `path` into `engine`, replacing keys of the same
+/// name. Keys that expired in the meantime are skipped.
+///
+/// # Returns
+///
+/// The number of keys loaded.
+pub fn load(engine: &StorageEngine, path: &Path) -> Result<usize, SnapshotError> {
+    let mut file = File::open(path)?;
+    let mut magic = [0; compression::MAGIC.len()];
+    if file.read_exact(&mut magic).is_ok() && compression::has_magic(&magic) {
+        let data = fs::read(path)?;
+        let mut rest = &data[..];
+        let keys = load_slice(engine, &mut rest)?;
+        if !rest.is_empty() {
+            return Err(SnapshotError::Corrupt("trailing data after snapshot"));
+        }
+        return Ok(keys);
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    verify_checksum(&mut file)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut dec = Decoder::new(BufReader::new(file));
+    let mut keys = 0;
+    let mut functions = Vec::new();
+    decode(
+        &mut dec,
+        engine.database_count(),
+        &mut functions,
+        |db, key, entry| {
+            database(engine, db).restore(key, entry);
+            keys += 1;
+        },
+    )?;
+    engine
+        .functions()
+        .restore_codes(functions, RestorePolicy::Replace)?;
+    Ok(keys)
+}
+
+/// Loads a snapshot from the start of `reader`, leaving it positioned just
+/// past the snapshot's checksum. The checksum is verified before anything
+/// is inserted.
+///
+/// # Returns
+///
+/// The number of keys loaded.
+pub(crate) fn load_from<R: Read>(
+    engine: &StorageEngine,
+    reader: &mut R,
+) -> Result<usize, SnapshotError> {
+    let mut dec = Decoder::new(reader);
+    let mut entries = Vec::new();
+    let mut functions = Vec::new();
+    decode(
+        &mut dec,
+        engine.database_count(),
+        &mut functions,
+        |db, key, entry| entries.push((db, key, entry)),
+    )?;
+    dec.check_crc()?;
+
+    engine
+        .functions()
+        .restore_codes(functions, RestorePolicy::Replace)?;
+
+    let keys = entries.len();
+    let mut counts = vec![0; engine.database_count()];
+    for (db, _, _) in &entries {
+        counts[*db] += 1;
+    }
+    for (db, count) in counts.into_iter().enumerate().filter(|(_, n)| *n > 0) {
+        database(engine, db).reserve(count);
+    }
+    for (db, key, entry) in entries {
+        database(engine, db).restore(key, entry);
+    }
+    Ok(keys)
+}
+
+/// Loads a snapshot, compressed or not, from the start of `data`, advancing
+/// `data` past it.
+///
+/// # Returns
+///
+/// The number of keys loaded.
+pub(crate) fn load_slice(engine: &StorageEngine, data: &mut &[u8]) -> Result<usize, SnapshotError> {
+    if !compression::has_magic(data) {
+        return load_from(engine, data);
+    }
+
+    let plain = compression::decompress(data).map_err(|e| match e.kind() {
+        io::ErrorKind::Unsupported => SnapshotError::Unsupported(e.to_string()),
+        _ => SnapshotError::Corrupt("invalid compressed snapshot"),
+    })?;
+    let mut plain = &plain[..];
+    let keys = load_from(engine, &mut plain)?;
+    if !plain.is_empty() {
+        return Err(SnapshotError::Corrupt("trailing data after snapshot"));
+    }
+    Ok(keys)
+}
+
+/// Returns database `db` of `engine`, which [`decode`] checked exists.
+fn database(engine: &StorageEngine, db: usize) -> &StorageEngine {
+    engine
+        .db(db)
+        .expect("database index checked while decoding")
+}
+
+/// Decodes the header, function libraries and keys up to the EOF opcode,
+/// collecting the code of each library in `functions` and passing each live
+/// key to `restore` with the index of its database. Fails if a database
+/// index isn't below `databases`.
+fn decode<R: Read>(
+    dec: &mut Decoder<R>,
+    databases: usize,
+    functions: &mut Vec<Bytes>,
+    mut restore: impl FnMut(usize, Bytes, Entry),
+) -> Result<(), SnapshotError> {
+    let mut magic = [0; MAGIC.len()];
+    dec.raw(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(SnapshotError::Corrupt("not a FlashKV snapshot"));
+    }
+    if !(OLDEST_VERSION..=VERSION).contains(&dec.u8()?) {
+        return Err(SnapshotError::Corrupt("unsupported snapshot version"));
+    }
+
+    let now = clock::unix_time_ms();
+    let mut db = 0;
+    loop {
+        let mut op = dec.u8()?;
+        if op == OP_FUNCTION {
+            functions.push(dec.bytes()?);
+            continue;
+        }
+        if op == OP_SELECTDB {
+            db = dec.len()?;
+            if db >= databases {
+                return Err(SnapshotError::Unsupported(format!(
+                    "database {} is out of range (the server has {})",
+                    db, databases
+                )));
+            }
+            continue;
+        }
+        let expire_ms = if op == OP_EXPIRE_MS {
+            let unix_ms = dec.i64()?;
+            op = dec.u8()?;
+            Some(unix_ms)
+        } else {
+            None
+        };
+        if op == OP_EOF {
+            return Ok(());
+        }
+
+        let key = dec.bytes()?;
+        let value = read_value(dec, op)?;
+        match expire_ms {
+            Some(unix_ms) if unix_ms <= now => {}
+            Some(unix_ms) => restore(db, key, Entry::with_expire_time_ms(value, unix_ms)),
+            None => restore(db, key, Entry::new(value)),
+        }
+    }
+}
+
+/// Encodes function libraries, from their code, as a snapshot without keys
+/// (the payload of FUNCTION DUMP).
+pub(crate) fn dump_functions(codes: &[Bytes]) -> Vec<u8> {
+    let snapshot = Snapshot::new(Vec::new(), codes.to_vec(), clock::unix_time_ms());
+    write_to(&snapshot, Vec::new()).expect("writing to a Vec can't fail")
+}
+
+/// Decodes the function libraries of a [`dump_functions`] payload.
+pub(crate) fn read_functions(mut payload: &[u8]) -> Result<Vec<Bytes>, SnapshotError> {
+    let mut functions = Vec::new();
+    let mut keys = 0;
+    let mut dec = Decoder::new(&mut payload);
+    decode(&mut dec, 1, &mut functions, |_, _, _| keys += 1)?;
+    dec.check_crc()?;
+    if keys > 0 || !payload.is_empty() {
+        return Err(SnapshotError::Corrupt("not a function payload"));
+    }
+    Ok(functions)
+}
+
+/// Returns true if `data` starts like a snapshot, compressed or not.
+pub(crate) fn has_magic(data: &[u8]) -> bool {
+    data.starts_with(MAGIC) || compression::has_magic(data)
+}
+
+fn read_value<R: Read>(dec: &mut Decoder<R>, tag: u8) -> Result<Value, SnapshotError> {
+    Ok(match tag {
+        TYPE_STRING => Value::from(dec.bytes()?),
+        TYPE_LIST => {
+            let len = dec.len()?;
+            let mut list = VecDeque::with_capacity(len.min(PREALLOC_LIMIT));
+            for _ in 0..len {
+                list.push_back(dec.bytes()?);
+            }
+            Value::List(list)
+        }
+        TYPE_HASH => {
+            let len = dec.len()?;
+            let mut hash = HashMap::with_capacity(len.min(PREALLOC_LIMIT));
+            for _ in 0..len {
+                let field = dec.bytes()?;
+                hash.insert(field, dec.bytes()?);
+            }
+            Value::Hash(hash)
+        }
+        TYPE_SET => {
+            let len = dec.len()?;
+            let mut set = HashSet::with_capacity(len.min(PREALLOC_LIMIT));
+            for _ in 0..len {
+                set.insert(dec.bytes()?);
+            }
+            Value::Set(set)
+        }
+        TYPE_ZSET => {
+            let len = dec.len()?;
+            let mut zset = SortedSet::new();
+            for _ in 0..len {
+                let member = dec.bytes()?;
+                zset.insert(member, dec.f64()?);
+            }
+            Value::ZSet(zset)
+        }
+        TYPE_STREAM => Value::Stream(Stream::decode(dec)?),
+        _ => return Err(SnapshotError::Corrupt("unknown value type")),
+    })
+}
+
+/// Checks the trailing checksum against the rest of the file.
+fn verify_checksum(file: &mut File) -> Result<(), SnapshotError> {
+    let len = file.metadata()?.len();
+    if len < (MAGIC.len() + 2 + 4) as u64 {
+        return Err(SnapshotError::Corrupt("file too short"));
+    }
+
+    let mut crc = Crc32::new();
+    let mut reader = BufReader::new(&mut *file).take(len - 4);
+    let mut buf = [0; 8192];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        crc.update(&buf[..n]);
+    }
+
+    let mut stored = [0; 4];
+    reader.into_inner().read_exact(&mut stored)?;
+    if u32::from_le_bytes(stored) != crc.finish() {
+        return Err(SnapshotError::Corrupt("checksum mismatch"));
+    }
+    Ok(())
+}
+
+/// Collections are preallocated for at most this many elements, so a
+/// corrupt length can't trigger a huge allocation.
+const PREALLOC_LIMIT: usize = 4096;
+
+/// Writes snapshot primitives, checksumming everything written.
+pub(crate) struct Encoder<W: Write> {
+    inner: W,
+    crc: Crc32,
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            crc: Crc32::new(),
+        }
+    }
+
+    fn raw(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.crc.update(buf);
+        self.inner.write_all(buf)
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.raw(&[value])
+    }
+
+    /// Writes an unsigned integer as a LEB128 varint.
+    pub(crate) fn u64(&mut self, mut value: u64) -> io::Result<()> {
+        let mut buf = [0; 10];
+        let mut n = 0;
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                buf[n] = byte;
+                n += 1;
+                break;
+            }
+            buf[n] = byte | 0x80;
+            n += 1;
+        }
+        self.raw(&buf[..n])
+    }
+
+    pub(crate) fn len(&mut self, len: usize) -> io::Result<()> {
+        self.u64(len as u64)
+    }
+
+    pub(crate) fn i64(&mut self, value: i64) -> io::Result<()> {
+        self.raw(&value.to_le_bytes())
+    }
+
+    pub(crate) fn f64(&mut self, value: f64) -> io::Result<()> {
+        self.raw(&value.to_le_bytes())
+    }
+
+    pub(crate) fn bytes(&mut self, value: &[u8]) -> io::Result<()> {
+        self.len(value.len())?;
+        self.raw(value)
+    }
+}
+
+/// Reads the primitives written by [`Encoder`].
+pub(crate) struct Decoder<R: Read> {
+    inner: R,
+    crc: Crc32,
+}
+
+impl<R: Read> Decoder<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            crc: Crc32::new(),
+        }
+    }
+
+    fn raw(&mut self, buf: &mut [u8]) -> Result<(), SnapshotError> {
+        self.inner.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => SnapshotError::Corrupt("unexpected end of file"),
+            _ => SnapshotError::Io(e),
+        })?;
+        self.crc.update(buf);
+        Ok(())
+    }
+
+    /// Reads the checksum that ends a snapshot and checks it against
+    /// everything decoded before it.
+    fn check_crc(&mut self) -> Result<(), SnapshotError> {
+        let crc = self.crc.finish();
+        let mut stored = [0; 4];
+        self.raw(&mut stored)?;
+        if u32::from_le_bytes(stored) != crc {
+            return Err(SnapshotError::Corrupt("checksum mismatch"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, SnapshotError> {
+        let mut buf = [0; 1];
+        self.raw(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(SnapshotError::Corrupt("varint too long"))
+    }
+
+    pub(crate) fn len(&mut self) -> Result<usize, SnapshotError> {
+        usize::try_from(self.u64()?).map_err(|_| SnapshotError::Corrupt("length too large"))
+    }
+
+    pub(crate) fn i64(&mut self) -> Result<i64, SnapshotError> {
+        let mut buf = [0; 8];
+        self.raw(&mut buf)?;
+        Ok(i64::from_le_bytes(buf))
+    }
+
+    pub(crate) fn f64(&mut self) -> Result<f64, SnapshotError> {
+        let mut buf = [0; 8];
+        self.raw(&mut buf)?;
+        Ok(f64::from_le_bytes(buf))
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<Bytes, SnapshotError> {
+        let len = self.len()?;
+        // Grow as data arrives rather than trusting the length up front
+        let mut buf = Vec::with_capacity(len.min(PREALLOC_LIMIT));
+        (&mut self.inner).take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(SnapshotError::Corrupt("unexpected end of file"));
+        }
+        self.crc.update(&buf);
+        Ok(Bytes::from(buf))
+    }
+}
+
+/// CRC-32 (IEEE) lookup table.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Running CRC-32 (IEEE) checksum.
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Self {
+        Self(0xFFFF_FFFF)
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        for &byte in buf {
+            self.0 = CRC_TABLE[((self.0 ^ byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::stream::{StreamId, XAddId};
+    use crate::storage::zset::ZAddFlags;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("flashkv-{}-{}.fkv", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_crc32() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_parse_save_rules() {
+        assert_eq!(
+            SaveRule::parse_list("900 1 300 10"),
+            Some(vec![SaveRule::new(900, 1), SaveRule::new(300, 10)])
+        );
+        assert_eq!(SaveRule::parse_list(""), Some(vec![]));
+        assert_eq!(SaveRule::parse_list("900"), None);
+        assert_eq!(SaveRule::parse_list("900 x"), None);
+        assert_eq!(SaveRule::parse_list("-1 1"), None);
+    }
+
+    #[test]
+    fn test_due_rule() {
+        let snapshots = Snapshots::new();
+        snapshots.set_rules(vec![SaveRule::new(60, 10), SaveRule::new(300, 1)]);
+        let now = snapshots.last_save();
+        assert_eq!(snapshots.last_duration_secs(), -1);
+
+        assert_eq!(snapshots.due_rule(now + 1000), None);
+        snapshots.record_changes(5);
+        assert_eq!(snapshots.due_rule(now + 100), None);
+        assert_eq!(snapshots.due_rule(now + 300), Some(SaveRule::new(300, 1)));
+        snapshots.record_changes(5);
+        assert_eq!(snapshots.due_rule(now + 60), Some(SaveRule::new(60, 10)));
+
+        // Changes made during a save still count afterwards
+        snapshots.begin(true).unwrap();
+        assert!(snapshots.bgsave_in_progress());
+        assert_eq!(snapshots.current_duration_secs(), 0);
+        assert_eq!(snapshots.due_rule(now + 60), None);
+        snapshots.record_changes(3);
+        snapshots.finish(true);
+        assert_eq!(snapshots.changes(), 3);
+        assert!(!snapshots.bgsave_in_progress());
+        assert_eq!(snapshots.current_duration_secs(), -1);
+        assert_eq!(snapshots.last_duration_secs(), 0);
+
+        // A failed save is retried only after a delay
+        snapshots.begin(false).unwrap();
+        assert!(!snapshots.bgsave_in_progress());
+        snapshots.finish(false);
+        let now = snapshots.last_attempt();
+        snapshots.record_changes(10);
+        assert_eq!(snapshots.due_rule(now + 1), None);
+        snapshots.set_rules(vec![SaveRule::new(0, 1)]);
+        assert_eq!(snapshots.due_rule(now + 1), None);
+        assert_eq!(
+            snapshots.due_rule(now + SAVE_RETRY_DELAY_SECS),
+            Some(SaveRule::new(0, 1))
+        );
+
+        snapshots.set_rules(vec![]);
+        assert_eq!(snapshots.due_rule(now + 10_000), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let path = temp_path("round-trip");
+        let engine = StorageEngine::new();
+
+        engine.set(Bytes::from("str"), Bytes::from("value"));
+        engine.set_with_ttl(
+            Bytes::from("ttl"),
+            Bytes::from("soon"),
+            Duration::from_secs(3600),
+        );
+        engine.rpush(
+            Bytes::from("list"),
+            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")],
+        );
+        engine.hset(
+            Bytes::from("hash"),
+            vec![(Bytes::from("f"), Bytes::from("v"))],
+        );
+        engine.sadd(Bytes::from("set"), vec![Bytes::from("x"), Bytes::from("y")]);
+        engine
+            .zadd(
+                Bytes::from("zset"),
+                ZAddFlags::default(),
+                vec![(1.5, Bytes::from("one")), (-2.0, Bytes::from("two"))],
+            )
+            .unwrap();
+        let stream_key = Bytes::from("stream");
+        engine
+            .xadd(
+                stream_key.clone(),
+                XAddId::Explicit(StreamId::new(1, 1)),
+                vec![(Bytes::from("k"), Bytes::from("v"))],
+                None,
+                false,
+            )
+            .unwrap();
+        engine
+            .xgroup_create(
+                stream_key.clone(),
+                Bytes::from("g"),
+                Some(StreamId::MIN),
+                false,
+            )
+            .unwrap();
+        let consumer = Bytes::from("alice");
+        engine.xreadgroup(&stream_key, b"g", &consumer, None, None, false);
+
+        assert_eq!(
+            save(&engine.snapshot(), &path, Compression::NONE).unwrap(),
+            7
+        );
+
+        let loaded = StorageEngine::new();
+        assert_eq!(load(&loaded, &path).unwrap(), 7);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), 7);
+        assert_eq!(loaded.get(&Bytes::from("str")), Some(Bytes::from("value")));
+        assert_eq!(
+            loaded.expire_time(&Bytes::from("ttl")),
+            engine.expire_time(&Bytes::from("ttl"))
+        );
+        assert_eq!(loaded.stats().expires, 1);
+        assert_eq!(
+            loaded.lrange(&Bytes::from("list"), 0, -1),
+            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")]
+        );
+        assert_eq!(
+            loaded.hget(&Bytes::from("hash"), &Bytes::from("f")),
+            Some(Bytes::from("v"))
+        );
+        assert_eq!(loaded.scard(&Bytes::from("set")), 2);
+        assert_eq!(
+            loaded.zscore(&Bytes::from("zset"), &Bytes::from("two")),
+            Some(-2.0)
+        );
+        assert_eq!(loaded.xlen(&stream_key), 1);
+        assert_eq!(
+            loaded.xpending_summary(&stream_key, b"g"),
+            engine.xpending_summary(&stream_key, b"g")
+        );
+        assert_eq!(loaded.xpending_summary(&stream_key, b"g").unwrap().count, 1);
+    }
+
+    #[test]
+    fn test_databases_round_trip() {
+        let path = temp_path("databases");
+        let engine = StorageEngine::new();
+        engine.set(Bytes::from("key"), Bytes::from("zero"));
+        let db = engine.db(7).unwrap();
+        db.set(Bytes::from("key"), Bytes::from("seven"));
+        db.set(Bytes::from("only"), Bytes::from("seven"));
+
+        let snapshot = engine.snapshot();
+        assert_eq!(snapshot.len(), 3);
+        assert_eq!(snapshot.db_iter(7).count(), 2);
+        assert_eq!(save(&snapshot, &path, Compression::NONE).unwrap(), 3);
+
+        let loaded = StorageEngine::new();
+        assert_eq!(load(&loaded, &path).unwrap(), 3);
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded.get(&Bytes::from("key")), Some(Bytes::from("zero")));
+        let db = loaded.db(7).unwrap();
+        assert_eq!(db.len(), 2);
+        assert_eq!(db.get(&Bytes::from("key")), Some(Bytes::from("seven")));
+
+        // A server with fewer databases can't take the file
+        let small = StorageEngine::with_databases(4);
+        assert!(matches!(
+            load(&small, &path),
+            Err(SnapshotError::Unsupported(_))
+        ));
+        fs::remove_file(&path).unwrap();
+
+        // Database 0 alone is written as before
+        let single = StorageEngine::with_databases(1);
+        single.set(Bytes::from("key"), Bytes::from("zero"));
+        let data = write_to(&single.snapshot(), Vec::new()).unwrap();
+        assert!(!data[MAGIC.len() + 1..data.len() - 4].contains(&OP_SELECTDB));
+    }
+
+    #[test]
+    fn test_functions_round_trip() {
+        let path = temp_path("functions");
+        let engine = StorageEngine::new();
+        let code = Bytes::from("#!lua name=lib\nredis.register_function('f', function() end)");
+        engine.functions().load(code.clone(), false).unwrap();
+        engine.set(Bytes::from("key"), Bytes::from("value"));
+        assert_eq!(
+            save(&engine.snapshot(), &path, Compression::NONE).unwrap(),
+            1
+        );
+
+        let loaded = StorageEngine::new();
+        loaded
+            .functions()
+            .load(
+                Bytes::from("#!lua name=stale\nredis.register_function('g', function() end)"),
+                false,
+            )
+            .unwrap();
+        assert_eq!(load(&loaded, &path).unwrap(), 1);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.functions().len(), 2);
+        assert_eq!(loaded.functions().codes()[0], code);
+        assert_eq!(loaded.get(&Bytes::from("key")), Some(Bytes::from("value")));
+
+        // Version 1 files, written before functions were saved, still load
+        let mut data = write_to(&StorageEngine::new().snapshot(), Vec::new()).unwrap();
+        data.truncate(data.len() - 4);
+        data[MAGIC.len()] = 1;
+        let mut crc = Crc32::new();
+        crc.update(&data);
+        data.extend_from_slice(&crc.finish().to_le_bytes());
+        fs::write(&path, &data).unwrap();
+        assert_eq!(load(&loaded, &path).unwrap(), 0);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.functions().len(), 2);
+    }
+
+    #[test]
+    fn test_compressed_round_trip() {
+        let engine = StorageEngine::new();
+        for i in 0..100 {
+            engine.set(
+                Bytes::from(format!("user:{}", i)),
+                Bytes::from("a fairly repetitive value ".repeat(10)),
+            );
+        }
+        let plain_path = temp_path("plain");
+        save(&engine.snapshot(), &plain_path, Compression::NONE).unwrap();
+        let plain_len = fs::metadata(&plain_path).unwrap().len();
+        fs::remove_file(&plain_path).unwrap();
+
+        for codec in [Codec::Zstd, Codec::Lz4] {
+            let path = temp_path(&format!("compressed-{}", codec));
+            let result = save(&engine.snapshot(), &path, Compression::new(codec));
+            if !codec.is_available() {
+                assert!(result.is_err());
+                assert!(!path.exists());
+                continue;
+            }
+            assert_eq!(result.unwrap(), 100);
+            assert!(compression::has_magic(&fs::read(&path).unwrap()));
+            assert!(fs::metadata(&path).unwrap().len() < plain_len / 5);
+
+            let loaded = StorageEngine::new();
+            assert_eq!(load(&loaded, &path).unwrap(), 100);
+            assert_eq!(
+                loaded.get(&Bytes::from("user:7")),
+                engine.get(&Bytes::from("user:7"))
+            );
+
+            // A truncated file is rejected as a whole
+            let data = fs::read(&path).unwrap();
+            fs::write(&path, &data[..data.len() - 10]).unwrap();
+            let loaded = StorageEngine::new();
+            assert!(matches!(
+                load(&loaded, &path),
+                Err(SnapshotError::Corrupt(_))
+            ));
+            assert!(loaded.is_empty());
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_expired_keys_are_skipped() {
+        let path = temp_path("expired");
+        let engine = StorageEngine::new();
+        engine.set(Bytes::from("kept"), Bytes::from("1"));
+        engine.set_with_ttl(
+            Bytes::from("gone"),
+            Bytes::from("2"),
+            Duration::from_millis(20),
+        );
+        save(&engine.snapshot(), &path, Compression::NONE).unwrap();
+        std::thread::sleep(Duration::from_millis(40));
+
+        let loaded = StorageEngine::new();
+        assert_eq!(load(&loaded, &path).unwrap(), 1);
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.get(&Bytes::from("gone")).is_none());
+    }
+
+    #[test]
+    fn test_corrupt_files_are_rejected() {
+        let path = temp_path("corrupt");
+        let engine = StorageEngine::new();
+        engine.set(Bytes::from("key"), Bytes::from("value"));
+        save(&engine.snapshot(), &path, Compression::NONE).unwrap();
+
+        let mut data = fs::read(&path).unwrap();
+        data[10] ^= 0x01;
+        fs::write(&path, &data).unwrap();
+        let loaded = StorageEngine::new();
+        assert!(matches!(
+            load(&loaded, &path),
+            Err(SnapshotError::Corrupt("checksum mismatch"))
+        ));
+        assert!(loaded.is_empty());
+
+        fs::write(&path, &data[..5]).unwrap();
+        assert!(matches!(
+            load(&loaded, &path),
+            Err(SnapshotError::Corrupt(_))
+        ));
+        fs::remove_file(&path).unwrap();
+
+        assert!(load(&loaded, &path).unwrap_err().is_not_found());
+    }
+}
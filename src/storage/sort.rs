@@ -0,0 +1,169 @@
+//! SORT Options and Pattern Lookups
+//!
+//! SORT orders the elements of a list, set or sorted set, either by their
+//! own value or by weights fetched from other keys. Weights and GET results
+//! are addressed with patterns in which the first `*` is replaced by the
+//! element:
+//!
+//! | Pattern          | Resolves to                                   |
+//! |------------------|-----------------------------------------------|
+//! | `#`              | The element itself                            |
+//! | `weight_*`       | The string value at `weight_<element>`        |
+//! | `object_*->name` | Field `name` of the hash at `object_<element>` |
+//!
+//! A BY pattern without `*` skips sorting altogether, and a GET pattern
+//! without `*` always resolves to nil.
+//!
+//! This module contains the option types and pattern parsing; the storage
+//! engine resolves the lookups and does the sorting.
+
+use bytes::Bytes;
+use std::cmp::Ordering;
+
+/// Error returned when a numeric sort meets a weight that isn't a number.
+pub const SORT_NOT_NUMERIC: &str = "One or more scores can't be converted into double";
+
+/// Options for a SORT call.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SortOptions {
+    /// Pattern giving each element's weight (BY)
+    pub by: Option<Bytes>,
+    /// Offset and count of the returned window (LIMIT)
+    pub limit: Option<(i64, i64)>,
+    /// Patterns to return for each element instead of the element (GET)
+    pub get: Vec<Bytes>,
+    /// Sort in descending order (DESC)
+    pub desc: bool,
+    /// Compare weights as binary strings instead of numbers (ALPHA)
+    pub alpha: bool,
+}
+
+impl SortOptions {
+    /// Returns whether the elements are to be sorted at all.
+    ///
+    /// `BY nosort` (or any pattern without `*`) keeps the source order.
+    pub(crate) fn sorts(&self) -> bool {
+        self.by.as_ref().is_none_or(|by| by.contains(&b'*'))
+    }
+
+    /// Resolves LIMIT to a half-open index range within `len` elements.
+    pub(crate) fn window(&self, len: usize) -> (usize, usize) {
+        let Some((offset, count)) = self.limit else {
+            return (0, len);
+        };
+
+        let start = (offset.max(0) as usize).min(len);
+        let end = if count < 0 {
+            len
+        } else {
+            start.saturating_add(count as usize).min(len)
+        };
+        (start, end)
+    }
+}
+
+/// What a pattern refers to for a given element.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Lookup {
+    /// The element itself (`#`)
+    Element,
+    /// The string value of a key
+    Key(Bytes),
+    /// A field of the hash at a key
+    Field(Bytes, Bytes),
+}
+
+/// Substitutes `element` into `pattern`.
+///
+/// Returns `None` if the pattern has no `*` and so refers to nothing.
+pub(crate) fn resolve(pattern: &[u8], element: &[u8]) -> Option<Lookup> {
+    if pattern == b"#" {
+        return Some(Lookup::Element);
+    }
+
+    let star = pattern.iter().position(|&b| b == b'*')?;
+
+    // A `->` after the `*` followed by a non-empty field name selects a hash field
+    let arrow = pattern[star + 1..]
+        .windows(2)
+        .position(|w| w == b"->")
+        .map(|i| star + 1 + i)
+        .filter(|&i| i + 2 < pattern.len());
+    let key_end = arrow.unwrap_or(pattern.len());
+
+    let mut key = Vec::with_capacity(key_end - 1 + element.len());
+    key.extend_from_slice(&pattern[..star]);
+    key.extend_from_slice(element);
+    key.extend_from_slice(&pattern[star + 1..key_end]);
+    let key = Bytes::from(key);
+
+    Some(match arrow {
+        Some(i) => Lookup::Field(key, Bytes::copy_from_slice(&pattern[i + 2..])),
+        None => Lookup::Key(key),
+    })
+}
+
+/// A sort key for one element.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Weight {
+    /// Numeric weight (missing weights count as 0)
+    Score(f64),
+    /// Binary-string weight for ALPHA sorts (`None` if missing)
+    Alpha(Option<Bytes>),
+}
+
+impl Weight {
+    /// Compares two weights, breaking numeric ties by the elements themselves
+    /// so the result is deterministic.
+    pub(crate) fn compare(&self, other: &Weight, a: &[u8], b: &[u8]) -> Ordering {
+        match (self, other) {
+            (Weight::Score(x), Weight::Score(y)) => x.total_cmp(y).then_with(|| a.cmp(b)),
+            // Missing alpha weights sort first
+            (Weight::Alpha(x), Weight::Alpha(y)) => x.cmp(y),
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_patterns() {
+        assert_eq!(resolve(b"#", b"3"), Some(Lookup::Element));
+        assert_eq!(resolve(b"nosort", b"3"), None);
+        assert_eq!(
+            resolve(b"weight_*", b"3"),
+            Some(Lookup::Key(Bytes::from("weight_3")))
+        );
+        assert_eq!(
+            resolve(b"w_*_x", b"3"),
+            Some(Lookup::Key(Bytes::from("w_3_x")))
+        );
+        assert_eq!(
+            resolve(b"obj_*->name", b"3"),
+            Some(Lookup::Field(Bytes::from("obj_3"), Bytes::from("name")))
+        );
+        // An empty field name means a plain key lookup
+        assert_eq!(
+            resolve(b"obj_*->", b"3"),
+            Some(Lookup::Key(Bytes::from("obj_3->")))
+        );
+    }
+
+    #[test]
+    fn test_window() {
+        let mut opts = SortOptions::default();
+        assert_eq!(opts.window(5), (0, 5));
+
+        opts.limit = Some((1, 2));
+        assert_eq!(opts.window(5), (1, 3));
+        opts.limit = Some((3, 10));
+        assert_eq!(opts.window(5), (3, 5));
+        opts.limit = Some((-1, -1));
+        assert_eq!(opts.window(5), (0, 5));
+        opts.limit = Some((10, 1));
+        assert_eq!(opts.window(5), (5, 5));
+    }
+}
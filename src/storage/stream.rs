@@ -0,0 +1,895 @@
+//! Stream Data Type
+//!
+//! A stream is an append-only log of entries, each identified by a unique,
+//! monotonically increasing ID of the form `<milliseconds>-<sequence>` and
+//! holding a list of field-value pairs.
+//!
+//! ## Design
+//!
+//! Entries are kept in a `BTreeMap` ordered by ID, which gives us cheap
+//! appends at the tail, cheap trimming at the head, and range scans in
+//! either direction for XRANGE/XREVRANGE/XREAD.
+//!
+//! The stream also remembers the last ID it ever generated, even after the
+//! entry itself has been trimmed away, so IDs never go backwards.
+//!
+//! ## Consumer Groups
+//!
+//! A stream can have any number of named consumer groups. Each group tracks
+//! the last ID it delivered and a pending entries list (PEL): entries that
+//! were delivered to one of its consumers but not yet acknowledged with
+//! XACK. Pending entries can be inspected with XPENDING and moved to another
+//! consumer with XCLAIM when their original consumer stalls.
+
+use crate::storage::clock;
+use crate::storage::snapshot::{Decoder, Encoder, SnapshotError};
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The field-value pairs of a stream entry.
+pub type StreamFields = Vec<(Bytes, Bytes)>;
+
+/// A stream entry: its ID and its field-value pairs.
+pub type StreamRecord = (StreamId, StreamFields);
+
+/// The ID of a stream entry (`<ms>-<seq>`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    /// Milliseconds part (usually a Unix timestamp)
+    pub ms: u64,
+    /// Sequence number within the same millisecond
+    pub seq: u64,
+}
+
+impl StreamId {
+    /// The smallest possible ID (`0-0`).
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+
+    /// The largest possible ID.
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Creates a new stream ID.
+    pub fn new(ms: u64, seq: u64) -> Self {
+        Self { ms, seq }
+    }
+
+    /// Parses an ID of the form `ms-seq` or `ms`.
+    ///
+    /// When the sequence part is missing, `default_seq` is used. This lets
+    /// range commands treat `5` as `5-0` for a start bound and `5-<max>` for
+    /// an end bound.
+    pub fn parse(s: &[u8], default_seq: u64) -> Option<Self> {
+        let s = std::str::from_utf8(s).ok()?;
+        match s.split_once('-') {
+            Some((ms, seq)) => Some(Self::new(ms.parse().ok()?, seq.parse().ok()?)),
+            None => Some(Self::new(s.parse().ok()?, default_seq)),
+        }
+    }
+
+    /// Returns the ID immediately after this one, if any.
+    pub fn next(self) -> Option<Self> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(Self::new(self.ms, seq)),
+            None => self.ms.checked_add(1).map(|ms| Self::new(ms, 0)),
+        }
+    }
+
+    /// Returns the ID immediately before this one, if any.
+    pub fn prev(self) -> Option<Self> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(Self::new(self.ms, seq)),
+            None => self.ms.checked_sub(1).map(|ms| Self::new(ms, u64::MAX)),
+        }
+    }
+
+    /// Formats the ID as a bulk string payload.
+    pub fn to_bytes(self) -> Bytes {
+        Bytes::from(self.to_string())
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID argument of XADD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XAddId {
+    /// `*` - generate both parts
+    Auto,
+    /// `<ms>-*` - generate only the sequence part
+    AutoSeq(u64),
+    /// A fully specified ID
+    Explicit(StreamId),
+}
+
+impl XAddId {
+    /// Parses `*`, `<ms>-*` or `<ms>[-<seq>]`.
+    pub fn parse(s: &[u8]) -> Option<Self> {
+        if s == b"*" {
+            return Some(XAddId::Auto);
+        }
+        if let Some(ms) = s.strip_suffix(b"-*") {
+            let ms = std::str::from_utf8(ms).ok()?.parse().ok()?;
+            return Some(XAddId::AutoSeq(ms));
+        }
+        StreamId::parse(s, 0).map(XAddId::Explicit)
+    }
+}
+
+/// An append-only log of field-value entries.
+#[derive(Debug, Clone, Default)]
+pub struct Stream {
+    /// Entries ordered by ID
+    entries: BTreeMap<StreamId, StreamFields>,
+    /// The last ID generated, which later IDs must exceed
+    last_id: StreamId,
+    /// Consumer groups by name
+    groups: HashMap<Bytes, ConsumerGroup>,
+}
+
+/// A consumer group reading from a stream.
+#[derive(Debug, Clone)]
+pub struct ConsumerGroup {
+    /// The last ID delivered to any consumer of the group
+    last_delivered: StreamId,
+    /// Delivered but unacknowledged entries
+    pending: BTreeMap<StreamId, PendingEntry>,
+    /// Known consumers and when they were last active
+    consumers: HashMap<Bytes, Instant>,
+}
+
+/// An entry in a consumer group's pending entries list.
+#[derive(Debug, Clone)]
+pub struct PendingEntry {
+    /// The consumer the entry was last delivered to
+    pub consumer: Bytes,
+    /// When the entry was last delivered
+    pub delivered_at: Instant,
+    /// How many times the entry has been delivered
+    pub delivery_count: u64,
+}
+
+/// The summary form of XPENDING.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingSummary {
+    /// Total number of pending entries
+    pub count: usize,
+    /// Smallest and largest pending IDs (None if nothing is pending)
+    pub bounds: Option<(StreamId, StreamId)>,
+    /// Pending entry count per consumer, sorted by consumer name
+    pub consumers: Vec<(Bytes, usize)>,
+}
+
+/// One line of the extended form of XPENDING.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingInfo {
+    /// The pending entry ID
+    pub id: StreamId,
+    /// The consumer owning the entry
+    pub consumer: Bytes,
+    /// Time since the entry was last delivered
+    pub idle: Duration,
+    /// How many times the entry has been delivered
+    pub delivery_count: u64,
+}
+
+/// Filters for the extended form of XPENDING.
+#[derive(Debug, Clone, Copy)]
+pub struct PendingQuery<'a> {
+    /// Smallest ID to include
+    pub start: StreamId,
+    /// Largest ID to include
+    pub end: StreamId,
+    /// Maximum number of entries to return
+    pub count: usize,
+    /// Only include entries owned by this consumer
+    pub consumer: Option<&'a [u8]>,
+    /// Only include entries idle for at least this long
+    pub min_idle: Option<Duration>,
+}
+
+impl ConsumerGroup {
+    fn new(last_delivered: StreamId) -> Self {
+        Self {
+            last_delivered,
+            pending: BTreeMap::new(),
+            consumers: HashMap::new(),
+        }
+    }
+
+    /// Marks a consumer as active, creating it if needed.
+    ///
+    /// # Returns
+    /// `true` if the consumer was created.
+    fn touch_consumer(&mut self, consumer: &Bytes) -> bool {
+        self.consumers
+            .insert(consumer.clone(), Instant::now())
+            .is_none()
+    }
+}
+
+impl Stream {
+    /// Creates an empty stream.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the stream has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the last ID generated for this stream.
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// Appends an entry, resolving the ID.
+    ///
+    /// # Returns
+    /// The ID of the new entry, or an error if the ID would not be greater
+    /// than the stream's last ID.
+    pub fn add(&mut self, id: XAddId, fields: StreamFields) -> Result<StreamId, &'static str> {
+        let id = self.resolve_id(id)?;
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(id)
+    }
+
+    /// Turns an XADD ID argument into a concrete ID greater than `last_id`.
+    fn resolve_id(&self, id: XAddId) -> Result<StreamId, &'static str> {
+        const TOO_SMALL: &str =
+            "The ID specified in XADD is equal or smaller than the target stream top item";
+
+        let last = self.last_id;
+        match id {
+            XAddId::Auto => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_millis() as u64)
+                    .unwrap_or(0);
+                if now > last.ms {
+                    Ok(StreamId::new(now, 0))
+                } else {
+                    last.next().ok_or(TOO_SMALL)
+                }
+            }
+            XAddId::AutoSeq(ms) => {
+                if ms > last.ms {
+                    Ok(StreamId::new(ms, 0))
+                } else if ms == last.ms {
+                    match last.seq.checked_add(1) {
+                        Some(seq) => Ok(StreamId::new(ms, seq)),
+                        None => Err(TOO_SMALL),
+                    }
+                } else {
+                    Err(TOO_SMALL)
+                }
+            }
+            XAddId::Explicit(id) => {
+                if id == StreamId::MIN {
+                    Err("The ID specified in XADD must be greater than 0-0")
+                } else if id <= last {
+                    Err(TOO_SMALL)
+                } else {
+                    Ok(id)
+                }
+            }
+        }
+    }
+
+    /// Removes the oldest entries until at most `maxlen` remain.
+    ///
+    /// # Returns
+    /// The number of entries removed.
+    pub fn trim_maxlen(&mut self, maxlen: usize) -> usize {
+        let mut removed = 0;
+        while self.entries.len() > maxlen {
+            self.entries.pop_first();
+            removed += 1;
+        }
+        removed
+    }
+
+    /// Returns entries with IDs in `[start, end]`, oldest first
+    /// (or newest first with `rev`), up to `count` entries.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+        rev: bool,
+    ) -> Vec<StreamRecord> {
+        if start > end {
+            return Vec::new();
+        }
+
+        let count = count.unwrap_or(usize::MAX);
+        let range = self.entries.range(start..=end);
+        let to_record = |(id, fields): (&StreamId, &StreamFields)| (*id, fields.clone());
+
+        if rev {
+            range.rev().take(count).map(to_record).collect()
+        } else {
+            range.take(count).map(to_record).collect()
+        }
+    }
+
+    /// Returns up to `count` entries with IDs strictly greater than `after`.
+    pub fn read_after(&self, after: StreamId, count: Option<usize>) -> Vec<StreamRecord> {
+        match after.next() {
+            Some(start) => self.range(start, StreamId::MAX, count, false),
+            None => Vec::new(),
+        }
+    }
+
+    // ========================================================================
+    // Consumer groups
+    // ========================================================================
+
+    /// Creates a consumer group that will deliver entries after `last_id`
+    /// (or only new entries when `last_id` is `None`).
+    ///
+    /// # Returns
+    /// `false` if a group with that name already exists.
+    pub fn create_group(&mut self, name: Bytes, last_id: Option<StreamId>) -> bool {
+        if self.groups.contains_key(&name) {
+            return false;
+        }
+        let last_id = last_id.unwrap_or(self.last_id);
+        self.groups.insert(name, ConsumerGroup::new(last_id));
+        true
+    }
+
+    /// Destroys a consumer group and its pending entries.
+    ///
+    /// # Returns
+    /// `true` if the group existed.
+    pub fn destroy_group(&mut self, name: &[u8]) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// Returns true if the group exists.
+    pub fn has_group(&self, name: &[u8]) -> bool {
+        self.groups.contains_key(name)
+    }
+
+    /// Creates a consumer in a group.
+    ///
+    /// # Returns
+    /// `None` if the group doesn't exist, otherwise whether the consumer
+    /// was newly created.
+    pub fn create_consumer(&mut self, group: &[u8], consumer: &Bytes) -> Option<bool> {
+        let group = self.groups.get_mut(group)?;
+        if group.consumers.contains_key(consumer) {
+            return Some(false);
+        }
+        Some(group.touch_consumer(consumer))
+    }
+
+    /// Deletes a consumer and drops its pending entries.
+    ///
+    /// # Returns
+    /// `None` if the group doesn't exist, otherwise the number of pending
+    /// entries the consumer had.
+    pub fn delete_consumer(&mut self, group: &[u8], consumer: &[u8]) -> Option<usize> {
+        let group = self.groups.get_mut(group)?;
+        if group.consumers.remove(consumer).is_none() {
+            return Some(0);
+        }
+        let before = group.pending.len();
+        group.pending.retain(|_, p| p.consumer.as_ref() != consumer);
+        Some(before - group.pending.len())
+    }
+
+    /// Reads entries for a consumer of a group.
+    ///
+    /// With `after = None` (the `>` ID), delivers entries never delivered to
+    /// the group and adds them to the consumer's pending list (unless
+    /// `noack`). With `after = Some(id)`, re-reads the consumer's own
+    /// pending entries with IDs greater than `id`; entries that were trimmed
+    /// from the stream come back with `None` fields.
+    ///
+    /// # Returns
+    /// `None` if the group doesn't exist.
+    pub fn read_group(
+        &mut self,
+        group: &[u8],
+        consumer: &Bytes,
+        after: Option<StreamId>,
+        count: Option<usize>,
+        noack: bool,
+    ) -> Option<Vec<(StreamId, Option<StreamFields>)>> {
+        let group = self.groups.get_mut(group)?;
+        group.touch_consumer(consumer);
+        let count = count.unwrap_or(usize::MAX);
+
+        let after = match after {
+            Some(after) => {
+                // History of this consumer's pending entries
+                let history = group
+                    .pending
+                    .iter()
+                    .filter(|(id, p)| **id > after && p.consumer == *consumer)
+                    .take(count)
+                    .map(|(id, _)| (*id, self.entries.get(id).cloned()))
+                    .collect();
+                return Some(history);
+            }
+            None => group.last_delivered,
+        };
+
+        let start = match after.next() {
+            Some(start) => start,
+            None => return Some(Vec::new()),
+        };
+
+        let now = Instant::now();
+        let mut delivered = Vec::new();
+        for (id, fields) in self.entries.range(start..).take(count) {
+            group.last_delivered = *id;
+            if !noack {
+                group.pending.insert(
+                    *id,
+                    PendingEntry {
+                        consumer: consumer.clone(),
+                        delivered_at: now,
+                        delivery_count: 1,
+                    },
+                );
+            }
+            delivered.push((*id, Some(fields.clone())));
+        }
+        Some(delivered)
+    }
+
+    /// Acknowledges entries, removing them from a group's pending list.
+    ///
+    /// # Returns
+    /// The number of entries that were pending (0 if the group is missing).
+    pub fn ack(&mut self, group: &[u8], ids: &[StreamId]) -> usize {
+        match self.groups.get_mut(group) {
+            Some(group) => ids
+                .iter()
+                .filter(|id| group.pending.remove(id).is_some())
+                .count(),
+            None => 0,
+        }
+    }
+
+    /// Summarizes a group's pending entries.
+    pub fn pending_summary(&self, group: &[u8]) -> Option<PendingSummary> {
+        let group = self.groups.get(group)?;
+
+        let mut per_consumer: BTreeMap<&Bytes, usize> = BTreeMap::new();
+        for entry in group.pending.values() {
+            *per_consumer.entry(&entry.consumer).or_default() += 1;
+        }
+
+        let bounds = group
+            .pending
+            .keys()
+            .next()
+            .zip(group.pending.keys().next_back())
+            .map(|(min, max)| (*min, *max));
+
+        Some(PendingSummary {
+            count: group.pending.len(),
+            bounds,
+            consumers: per_consumer
+                .into_iter()
+                .map(|(c, n)| (c.clone(), n))
+                .collect(),
+        })
+    }
+
+    /// Lists a group's pending entries matching the query.
+    pub fn pending_range(&self, group: &[u8], query: PendingQuery<'_>) -> Option<Vec<PendingInfo>> {
+        let group = self.groups.get(group)?;
+        if query.start > query.end {
+            return Some(Vec::new());
+        }
+
+        let now = Instant::now();
+        let entries = group
+            .pending
+            .range(query.start..=query.end)
+            .filter(|(_, p)| query.consumer.is_none_or(|c| p.consumer.as_ref() == c))
+            .map(|(id, p)| PendingInfo {
+                id: *id,
+                consumer: p.consumer.clone(),
+                idle: now.saturating_duration_since(p.delivered_at),
+                delivery_count: p.delivery_count,
+            })
+            .filter(|info| query.min_idle.is_none_or(|min| info.idle >= min))
+            .take(query.count)
+            .collect();
+        Some(entries)
+    }
+
+    /// Transfers ownership of pending entries idle for at least `min_idle`
+    /// to `consumer`.
+    ///
+    /// Entries that are no longer in the stream are dropped from the
+    /// pending list instead of being claimed. Unless `justid` is set, each
+    /// claimed entry's delivery count is incremented.
+    ///
+    /// # Returns
+    /// `None` if the group doesn't exist, otherwise the claimed entries.
+    pub fn claim(
+        &mut self,
+        group: &[u8],
+        consumer: &Bytes,
+        min_idle: Duration,
+        ids: &[StreamId],
+        justid: bool,
+    ) -> Option<Vec<StreamRecord>> {
+        let group = self.groups.get_mut(group)?;
+        group.touch_consumer(consumer);
+
+        let now = Instant::now();
+        let mut claimed = Vec::new();
+        for id in ids {
+            let Some(pending) = group.pending.get_mut(id) else {
+                continue;
+            };
+            if now.saturating_duration_since(pending.delivered_at) < min_idle {
+                continue;
+            }
+            let Some(fields) = self.entries.get(id) else {
+                group.pending.remove(id);
+                continue;
+            };
+
+            pending.consumer = consumer.clone();
+            pending.delivered_at = now;
+            if !justid {
+                pending.delivery_count += 1;
+            }
+            claimed.push((*id, fields.clone()));
+        }
+        Some(claimed)
+    }
+
+    /// Writes the stream, including its consumer groups, to a snapshot.
+    ///
+    /// Delivery and activity times are stored as Unix timestamps so idle
+    /// times carry over a restart.
+    pub(crate) fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.len(self.entries.len())?;
+        for (id, fields) in &self.entries {
+            encode_id(enc, *id)?;
+            enc.len(fields.len())?;
+            for (field, value) in fields {
+                enc.bytes(field)?;
+                enc.bytes(value)?;
+            }
+        }
+        encode_id(enc, self.last_id)?;
+
+        enc.len(self.groups.len())?;
+        for (name, group) in &self.groups {
+            enc.bytes(name)?;
+            encode_id(enc, group.last_delivered)?;
+            enc.len(group.pending.len())?;
+            for (id, pending) in &group.pending {
+                encode_id(enc, *id)?;
+                enc.bytes(&pending.consumer)?;
+                enc.i64(clock::to_unix_ms(pending.delivered_at))?;
+                enc.u64(pending.delivery_count)?;
+            }
+            enc.len(group.consumers.len())?;
+            for (consumer, seen) in &group.consumers {
+                enc.bytes(consumer)?;
+                enc.i64(clock::to_unix_ms(*seen))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a stream written by [`encode`](Self::encode).
+    pub(crate) fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        let mut stream = Stream::new();
+        for _ in 0..dec.len()? {
+            let id = decode_id(dec)?;
+            let count = dec.len()?;
+            let mut fields = Vec::with_capacity(count.min(64));
+            for _ in 0..count {
+                let field = dec.bytes()?;
+                fields.push((field, dec.bytes()?));
+            }
+            stream.entries.insert(id, fields);
+        }
+        stream.last_id = decode_id(dec)?;
+
+        for _ in 0..dec.len()? {
+            let name = dec.bytes()?;
+            let mut group = ConsumerGroup::new(decode_id(dec)?);
+            for _ in 0..dec.len()? {
+                let id = decode_id(dec)?;
+                let pending = PendingEntry {
+                    consumer: dec.bytes()?,
+                    delivered_at: clock::from_unix_ms(dec.i64()?),
+                    delivery_count: dec.u64()?,
+                };
+                group.pending.insert(id, pending);
+            }
+            for _ in 0..dec.len()? {
+                let consumer = dec.bytes()?;
+                group
+                    .consumers
+                    .insert(consumer, clock::from_unix_ms(dec.i64()?));
+            }
+            stream.groups.insert(name, group);
+        }
+        Ok(stream)
+    }
+}
+
+fn encode_id<W: Write>(enc: &mut Encoder<W>, id: StreamId) -> io::Result<()> {
+    enc.u64(id.ms)?;
+    enc.u64(id.seq)
+}
+
+fn decode_id<R: Read>(dec: &mut Decoder<R>) -> Result<StreamId, SnapshotError> {
+    Ok(StreamId::new(dec.u64()?, dec.u64()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> StreamFields {
+        pairs
+            .iter()
+            .map(|(f, v)| (Bytes::from(f.to_string()), Bytes::from(v.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_ids() {
+        assert_eq!(StreamId::parse(b"5-3", 0), Some(StreamId::new(5, 3)));
+        assert_eq!(
+            StreamId::parse(b"5", u64::MAX),
+            Some(StreamId::new(5, u64::MAX))
+        );
+        assert_eq!(StreamId::parse(b"abc", 0), None);
+        assert_eq!(StreamId::parse(b"1-x", 0), None);
+
+        assert_eq!(XAddId::parse(b"*"), Some(XAddId::Auto));
+        assert_eq!(XAddId::parse(b"7-*"), Some(XAddId::AutoSeq(7)));
+        assert_eq!(
+            XAddId::parse(b"7-1"),
+            Some(XAddId::Explicit(StreamId::new(7, 1)))
+        );
+        assert_eq!(StreamId::new(1, 2).to_string(), "1-2");
+    }
+
+    #[test]
+    fn test_add_resolves_ids() {
+        let mut stream = Stream::new();
+
+        assert!(stream
+            .add(XAddId::Explicit(StreamId::MIN), fields(&[("a", "1")]))
+            .is_err());
+        assert_eq!(
+            stream.add(XAddId::AutoSeq(0), fields(&[("a", "1")])),
+            Ok(StreamId::new(0, 1))
+        );
+        assert_eq!(
+            stream.add(XAddId::Explicit(StreamId::new(5, 0)), fields(&[("a", "2")])),
+            Ok(StreamId::new(5, 0))
+        );
+        assert_eq!(
+            stream.add(XAddId::AutoSeq(5), fields(&[("a", "3")])),
+            Ok(StreamId::new(5, 1))
+        );
+
+        // IDs must keep increasing
+        assert!(stream
+            .add(XAddId::Explicit(StreamId::new(5, 1)), fields(&[]))
+            .is_err());
+        assert!(stream.add(XAddId::AutoSeq(4), fields(&[])).is_err());
+
+        let auto = stream.add(XAddId::Auto, fields(&[("a", "4")])).unwrap();
+        assert!(auto > StreamId::new(5, 1));
+        assert_eq!(stream.len(), 4);
+        assert_eq!(stream.last_id(), auto);
+    }
+
+    fn stream_with(ids: &[u64]) -> Stream {
+        let mut stream = Stream::new();
+        for ms in ids {
+            stream
+                .add(
+                    XAddId::Explicit(StreamId::new(*ms, 0)),
+                    fields(&[("n", "v")]),
+                )
+                .unwrap();
+        }
+        stream
+    }
+
+    #[test]
+    fn test_group_delivery_and_ack() {
+        let mut stream = stream_with(&[1, 2, 3]);
+        let alice = Bytes::from("alice");
+        let bob = Bytes::from("bob");
+
+        assert!(stream.create_group(Bytes::from("g"), Some(StreamId::MIN)));
+        assert!(!stream.create_group(Bytes::from("g"), None));
+
+        let read = stream
+            .read_group(b"g", &alice, None, Some(2), false)
+            .unwrap();
+        assert_eq!(read.len(), 2);
+        let read = stream.read_group(b"g", &bob, None, None, false).unwrap();
+        assert_eq!(read[0].0, StreamId::new(3, 0));
+
+        // Nothing new left for the group
+        assert!(stream
+            .read_group(b"g", &bob, None, None, false)
+            .unwrap()
+            .is_empty());
+
+        // History re-reads only the consumer's own pending entries
+        let history = stream
+            .read_group(b"g", &alice, Some(StreamId::MIN), None, false)
+            .unwrap();
+        assert_eq!(history.len(), 2);
+
+        let summary = stream.pending_summary(b"g").unwrap();
+        assert_eq!(summary.count, 3);
+        assert_eq!(
+            summary.bounds,
+            Some((StreamId::new(1, 0), StreamId::new(3, 0)))
+        );
+        assert_eq!(
+            summary.consumers,
+            vec![(alice.clone(), 2), (bob.clone(), 1)]
+        );
+
+        assert_eq!(
+            stream.ack(b"g", &[StreamId::new(1, 0), StreamId::new(9, 0)]),
+            1
+        );
+        assert_eq!(stream.pending_summary(b"g").unwrap().count, 2);
+        assert!(stream
+            .read_group(b"missing", &alice, None, None, false)
+            .is_none());
+    }
+
+    #[test]
+    fn test_group_starting_at_last_id() {
+        let mut stream = stream_with(&[1, 2]);
+        let alice = Bytes::from("alice");
+
+        stream.create_group(Bytes::from("g"), None);
+        assert!(stream
+            .read_group(b"g", &alice, None, None, false)
+            .unwrap()
+            .is_empty());
+
+        stream
+            .add(XAddId::Explicit(StreamId::new(3, 0)), fields(&[("n", "v")]))
+            .unwrap();
+        let read = stream.read_group(b"g", &alice, None, None, true).unwrap();
+        assert_eq!(read.len(), 1);
+
+        // NOACK deliveries are not pending
+        assert_eq!(stream.pending_summary(b"g").unwrap().count, 0);
+    }
+
+    #[test]
+    fn test_claim_and_pending_range() {
+        let mut stream = stream_with(&[1, 2]);
+        let alice = Bytes::from("alice");
+        let bob = Bytes::from("bob");
+
+        stream.create_group(Bytes::from("g"), Some(StreamId::MIN));
+        stream.read_group(b"g", &alice, None, None, false).unwrap();
+
+        let query = PendingQuery {
+            start: StreamId::MIN,
+            end: StreamId::MAX,
+            count: 10,
+            consumer: Some(b"alice"),
+            min_idle: None,
+        };
+        assert_eq!(stream.pending_range(b"g", query).unwrap().len(), 2);
+
+        // Entries aren't idle long enough yet
+        let ids = [StreamId::new(1, 0), StreamId::new(2, 0)];
+        let claimed = stream
+            .claim(b"g", &bob, Duration::from_secs(60), &ids, false)
+            .unwrap();
+        assert!(claimed.is_empty());
+
+        let claimed = stream
+            .claim(b"g", &bob, Duration::ZERO, &ids[..1], false)
+            .unwrap();
+        assert_eq!(claimed.len(), 1);
+
+        let all = stream
+            .pending_range(
+                b"g",
+                PendingQuery {
+                    consumer: None,
+                    ..query
+                },
+            )
+            .unwrap();
+        assert_eq!(all[0].consumer, bob);
+        assert_eq!(all[0].delivery_count, 2);
+        assert_eq!(all[1].consumer, alice);
+
+        // Trimmed entries are dropped from the PEL instead of claimed
+        stream.trim_maxlen(0);
+        let claimed = stream
+            .claim(b"g", &bob, Duration::ZERO, &ids, false)
+            .unwrap();
+        assert!(claimed.is_empty());
+        assert_eq!(stream.pending_summary(b"g").unwrap().count, 0);
+
+        assert_eq!(stream.delete_consumer(b"g", b"alice"), Some(0));
+        assert!(stream.destroy_group(b"g"));
+        assert!(!stream.has_group(b"g"));
+    }
+
+    #[test]
+    fn test_range_and_trim() {
+        let mut stream = Stream::new();
+        for i in 1..=5 {
+            stream
+                .add(
+                    XAddId::Explicit(StreamId::new(i, 0)),
+                    fields(&[("n", &i.to_string())]),
+                )
+                .unwrap();
+        }
+
+        let ids = |records: Vec<StreamRecord>| {
+            records.into_iter().map(|(id, _)| id.ms).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            ids(stream.range(StreamId::new(2, 0), StreamId::new(4, 0), None, false)),
+            vec![2, 3, 4]
+        );
+        assert_eq!(
+            ids(stream.range(StreamId::MIN, StreamId::MAX, Some(2), true)),
+            vec![5, 4]
+        );
+        assert_eq!(
+            ids(stream.read_after(StreamId::new(3, 0), None)),
+            vec![4, 5]
+        );
+
+        assert_eq!(stream.trim_maxlen(2), 3);
+        assert_eq!(
+            ids(stream.range(StreamId::MIN, StreamId::MAX, None, false)),
+            vec![4, 5]
+        );
+
+        // The last ID survives trimming
+        stream.trim_maxlen(0);
+        assert!(stream.is_empty());
+        assert!(stream
+            .add(XAddId::Explicit(StreamId::new(5, 0)), fields(&[]))
+            .is_err());
+    }
+}
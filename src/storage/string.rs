@@ -0,0 +1,246 @@
+//! Compact String Encodings
+//!
+//! Most string values are small: counters, flags, short identifiers. Keeping
+//! each of them in its own reference-counted heap buffer costs an allocation
+//! and a header for a handful of bytes. Like Redis, FlashKV picks the
+//! smallest of three encodings when a string is stored:
+//!
+//! | Encoding | Holds                                  | OBJECT ENCODING        |
+//! |----------|----------------------------------------|------------------------|
+//! | `Int`    | The canonical decimal form of an `i64` | `int`                  |
+//! | `Inline` | Up to [`INLINE_CAPACITY`] other bytes  | `embstr`               |
+//! | `Raw`    | Anything longer, as a shared [`Bytes`] | `embstr` (≤ 44), `raw` |
+//!
+//! Neither `Int` nor `Inline` allocates: they live in the space a [`Bytes`]
+//! handle would take in the value anyway. INCR and friends read and write
+//! the `Int` encoding directly, without parsing and formatting the number.
+//!
+//! A string is canonical when formatting the number it parses as gives back
+//! the same bytes (no sign on positive numbers, no leading zeros, no `-0`),
+//! so a value always reads back exactly as it was written.
+
+use bytes::Bytes;
+use std::borrow::Cow;
+
+/// Longest string stored inline, so an inline string is no larger than a
+/// [`Bytes`] handle with its tag.
+pub const INLINE_CAPACITY: usize = 38;
+
+/// Longest string Redis reports as `embstr`.
+const EMBSTR_LIMIT: usize = 44;
+
+/// A string value in its most compact encoding.
+#[derive(Clone)]
+pub enum StringValue {
+    /// An integer, stored instead of its canonical decimal form
+    Int(i64),
+    /// A short string stored in place
+    Inline {
+        /// Number of bytes of `data` in use
+        len: u8,
+        /// The string, padded with zeros
+        data: [u8; INLINE_CAPACITY],
+    },
+    /// A longer string in a shared heap buffer
+    Raw(Bytes),
+}
+
+impl StringValue {
+    /// Returns the length of the string in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            StringValue::Int(n) => {
+                let digits = n
+                    .unsigned_abs()
+                    .checked_ilog10()
+                    .map_or(1, |d| d as usize + 1);
+                digits + usize::from(*n < 0)
+            }
+            StringValue::Inline { len, .. } => *len as usize,
+            StringValue::Raw(bytes) => bytes.len(),
+        }
+    }
+
+    /// Returns whether the string is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the string's bytes, formatting an integer.
+    pub fn as_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            StringValue::Int(n) => Cow::Owned(n.to_string().into_bytes()),
+            StringValue::Inline { len, data } => Cow::Borrowed(&data[..*len as usize]),
+            StringValue::Raw(bytes) => Cow::Borrowed(bytes),
+        }
+    }
+
+    /// Returns the string as [`Bytes`], sharing the buffer of a raw string.
+    pub fn to_bytes(&self) -> Bytes {
+        match self {
+            StringValue::Int(n) => Bytes::from(n.to_string()),
+            StringValue::Inline { len, data } => Bytes::copy_from_slice(&data[..*len as usize]),
+            StringValue::Raw(bytes) => bytes.clone(),
+        }
+    }
+
+    /// Returns the integer the string holds, or `None` if it isn't one.
+    pub fn to_i64(&self) -> Option<i64> {
+        match self {
+            StringValue::Int(n) => Some(*n),
+            _ => std::str::from_utf8(&self.as_bytes()).ok()?.parse().ok(),
+        }
+    }
+
+    /// Returns the name OBJECT ENCODING reports for this string.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            StringValue::Int(_) => "int",
+            _ if self.len() <= EMBSTR_LIMIT => "embstr",
+            _ => "raw",
+        }
+    }
+
+    /// Returns the bytes the string keeps on the heap, beyond the value
+    /// itself.
+    pub fn heap_size(&self) -> usize {
+        match self {
+            StringValue::Raw(bytes) => bytes.len(),
+            _ => 0,
+        }
+    }
+}
+
+impl From<Bytes> for StringValue {
+    fn from(bytes: Bytes) -> Self {
+        if let Some(n) = parse_canonical(&bytes) {
+            StringValue::Int(n)
+        } else if bytes.len() <= INLINE_CAPACITY {
+            let mut data = [0; INLINE_CAPACITY];
+            data[..bytes.len()].copy_from_slice(&bytes);
+            StringValue::Inline {
+                len: bytes.len() as u8,
+                data,
+            }
+        } else {
+            StringValue::Raw(bytes)
+        }
+    }
+}
+
+impl From<Vec<u8>> for StringValue {
+    fn from(bytes: Vec<u8>) -> Self {
+        StringValue::from(Bytes::from(bytes))
+    }
+}
+
+impl From<i64> for StringValue {
+    fn from(n: i64) -> Self {
+        StringValue::Int(n)
+    }
+}
+
+impl PartialEq for StringValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (StringValue::Int(a), StringValue::Int(b)) => a == b,
+            _ => self.as_bytes() == other.as_bytes(),
+        }
+    }
+}
+
+impl Eq for StringValue {}
+
+impl std::fmt::Debug for StringValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StringValue::Int(n) => f.debug_tuple("Int").field(n).finish(),
+            _ => f.debug_tuple("String").field(&self.to_bytes()).finish(),
+        }
+    }
+}
+
+/// Parses `s` as an integer if it is the canonical form of one.
+fn parse_canonical(s: &[u8]) -> Option<i64> {
+    let digits = s.strip_prefix(b"-").unwrap_or(s);
+    let canonical = !digits.is_empty()
+        && digits.iter().all(u8::is_ascii_digit)
+        && (digits[0] != b'0' || s == b"0");
+    if !canonical {
+        return None;
+    }
+    std::str::from_utf8(s).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(s: &str) -> StringValue {
+        StringValue::from(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    #[test]
+    fn test_encodings() {
+        assert!(matches!(encode("0"), StringValue::Int(0)));
+        assert!(matches!(encode("-42"), StringValue::Int(-42)));
+        assert!(matches!(
+            encode("9223372036854775807"),
+            StringValue::Int(i64::MAX)
+        ));
+        assert!(matches!(
+            encode("-9223372036854775808"),
+            StringValue::Int(i64::MIN)
+        ));
+
+        // Integers that wouldn't read back the same stay strings
+        for s in ["007", "+5", "-0", "-", "", "1.5", "9223372036854775808"] {
+            assert!(matches!(encode(s), StringValue::Inline { .. }), "{}", s);
+        }
+
+        let long = "x".repeat(INLINE_CAPACITY + 1);
+        assert!(matches!(encode(&long), StringValue::Raw(_)));
+        assert!(matches!(encode(&long[1..]), StringValue::Inline { .. }));
+
+        // No larger than the Bytes handle it replaces
+        assert!(std::mem::size_of::<StringValue>() <= std::mem::size_of::<Bytes>() + 8);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let long = "y".repeat(100);
+        for s in [
+            "",
+            "0",
+            "-17",
+            "007",
+            "hello",
+            "-9223372036854775808",
+            long.as_str(),
+        ] {
+            let value = encode(s);
+            assert_eq!(value.to_bytes(), Bytes::copy_from_slice(s.as_bytes()));
+            assert_eq!(&*value.as_bytes(), s.as_bytes());
+            assert_eq!(value.len(), s.len(), "{}", s);
+        }
+        for n in [0, 9, 10, -1, -10, 99999, i64::MAX, i64::MIN] {
+            assert_eq!(StringValue::from(n).len(), n.to_string().len());
+        }
+    }
+
+    #[test]
+    fn test_integers_and_names() {
+        assert_eq!(encode("12").to_i64(), Some(12));
+        assert_eq!(encode("+12").to_i64(), Some(12));
+        assert_eq!(encode("12a").to_i64(), None);
+        assert_eq!(encode("12"), StringValue::Int(12));
+
+        assert_eq!(encode("12").encoding(), "int");
+        assert_eq!(encode("short").encoding(), "embstr");
+        assert_eq!(encode(&"z".repeat(44)).encoding(), "embstr");
+        assert_eq!(encode(&"z".repeat(45)).encoding(), "raw");
+
+        assert_eq!(encode("short").heap_size(), 0);
+        assert_eq!(encode(&"z".repeat(45)).heap_size(), 45);
+    }
+}
@@ -0,0 +1,128 @@
+//! Per-Key Waiter Registry for Blocking Commands
+//!
+//! Blocking commands like `BLPOP` and `BZPOPMIN` park a client until
+//! another client writes to one of the keys it is waiting on. This module
+//! keeps track of which clients are waiting on which keys so that writers
+//! can wake them.
+//!
+//! ## Design
+//!
+//! Each blocked client owns a single [`Notify`] which it registers under
+//! every key it is waiting on. When a writer adds data to a key it calls
+//! [`KeyWaiters::notify`], which wakes every client registered for that key.
+//! Woken clients simply retry their command; whoever loses the race goes
+//! back to waiting.
+//!
+//! `Notify::notify_one` stores a permit if the client is not currently
+//! awaiting, so a write that lands between "register" and "await" is never
+//! lost.
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Tracks clients blocked on keys.
+#[derive(Debug, Default)]
+pub struct KeyWaiters {
+    /// Key -> notifiers of the clients blocked on it
+    waiters: Mutex<HashMap<Bytes, Vec<Arc<Notify>>>>,
+
+    /// Number of registrations, so writers can skip the lock when nobody waits
+    registered: AtomicUsize,
+}
+
+impl KeyWaiters {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a client's notifier under each of the given keys.
+    pub fn register(&self, keys: &[Bytes], notify: &Arc<Notify>) {
+        let mut waiters = self.waiters.lock().unwrap();
+        for key in keys {
+            waiters
+                .entry(key.clone())
+                .or_default()
+                .push(Arc::clone(notify));
+            self.registered.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Removes a client's notifier from each of the given keys.
+    pub fn unregister(&self, keys: &[Bytes], notify: &Arc<Notify>) {
+        let mut waiters = self.waiters.lock().unwrap();
+        for key in keys {
+            if let Some(list) = waiters.get_mut(key) {
+                if let Some(pos) = list.iter().position(|n| Arc::ptr_eq(n, notify)) {
+                    list.swap_remove(pos);
+                    self.registered.fetch_sub(1, Ordering::SeqCst);
+                }
+                if list.is_empty() {
+                    waiters.remove(key);
+                }
+            }
+        }
+    }
+
+    /// Wakes every client blocked on a key.
+    pub fn notify(&self, key: &[u8]) {
+        if self.registered.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+
+        let waiters = self.waiters.lock().unwrap();
+        if let Some(list) = waiters.get(key) {
+            for notify in list {
+                notify.notify_one();
+            }
+        }
+    }
+
+    /// Returns the number of clients blocked on a key.
+    pub fn waiting_on(&self, key: &[u8]) -> usize {
+        let waiters = self.waiters.lock().unwrap();
+        waiters.get(key).map_or(0, |list| list.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_notify_wakes_registered_client() {
+        let waiters = KeyWaiters::new();
+        let notify = Arc::new(Notify::new());
+        let keys = vec![Bytes::from("a"), Bytes::from("b")];
+
+        waiters.register(&keys, &notify);
+        assert_eq!(waiters.waiting_on(b"a"), 1);
+
+        // Notification before awaiting is kept as a permit
+        waiters.notify(b"b");
+        tokio::time::timeout(Duration::from_millis(100), notify.notified())
+            .await
+            .expect("client should have been woken");
+
+        waiters.unregister(&keys, &notify);
+        assert_eq!(waiters.waiting_on(b"a"), 0);
+        assert_eq!(waiters.waiting_on(b"b"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_notify_ignores_other_keys() {
+        let waiters = KeyWaiters::new();
+        let notify = Arc::new(Notify::new());
+        let keys = vec![Bytes::from("a")];
+
+        waiters.register(&keys, &notify);
+        waiters.notify(b"other");
+
+        let woken = tokio::time::timeout(Duration::from_millis(50), notify.notified()).await;
+        assert!(woken.is_err());
+    }
+}
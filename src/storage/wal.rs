@@ -0,0 +1,266 @@
+//! Write-Ahead Log With Group Commit
+//!
+//! With `--appendfsync always` every write command pays for its own fsync
+//! while holding the log lock, so writes from every connection queue up
+//! behind the disk one at a time. The `group` policy keeps the same file
+//! format but commits in groups instead:
+//!
+//! 1. A write command is applied and its encoding is queued in memory, still
+//!    under the log lock, and gets the log position just past it.
+//! 2. A flusher thread writes whatever has queued up and fsyncs it in one
+//!    go, then publishes the position it reached as durable.
+//! 3. The client's connection waits for the durable position to pass its
+//!    command's before sending the reply.
+//!
+//! While one fsync runs, commands from other connections keep queueing, and
+//! they are all made durable by the next one. Under load that amortizes a
+//! single fsync over many writes, and no client hears back about a write
+//! that could still be lost.
+//!
+//! A batch that fails to write is cut back out of the file and retried
+//! until the disk recovers; its clients keep waiting meanwhile.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::warn;
+
+/// How long the flusher waits before retrying a batch that failed.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Commands waiting to be committed, and where they go.
+#[derive(Debug, Default)]
+struct Queue {
+    /// Encoded commands not yet handed to the flusher
+    buf: Vec<u8>,
+    /// Log position just past the last queued command
+    appended: u64,
+    /// The log (a handle of its own, so batches are written without the
+    /// log lock)
+    file: Option<File>,
+    /// Set when the log is dropped, to stop the flusher
+    closed: bool,
+}
+
+/// The group-commit queue of the append-only file.
+#[derive(Debug)]
+pub(crate) struct Wal {
+    queue: Mutex<Queue>,
+    /// Signalled when commands are queued or the log is closed
+    ready: Condvar,
+    /// Held while a batch is written, so batches reach the file in order
+    commit: Mutex<()>,
+    /// Log position up to which everything is on disk
+    durable: watch::Sender<u64>,
+    /// Whether the flusher thread was started
+    started: AtomicBool,
+    /// Whether the last batch was committed
+    last_write_ok: AtomicBool,
+}
+
+impl Default for Wal {
+    fn default() -> Self {
+        Self {
+            queue: Mutex::new(Queue::default()),
+            ready: Condvar::new(),
+            commit: Mutex::new(()),
+            durable: watch::Sender::new(0),
+            started: AtomicBool::new(false),
+            last_write_ok: AtomicBool::new(true),
+        }
+    }
+}
+
+impl Wal {
+    /// Starts the flusher thread, unless it is already running.
+    pub(crate) fn start(self: &Arc<Self>) -> io::Result<()> {
+        if self.started.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let wal = Arc::clone(self);
+        std::thread::Builder::new()
+            .name("flashkv-wal".into())
+            .spawn(move || wal.run())
+            .map(|_| ())
+            .inspect_err(|_| self.started.store(false, Ordering::Release))
+    }
+
+    /// Sets the file batches are written to. Called with the log lock held.
+    pub(crate) fn set_file(&self, file: File) {
+        self.queue.lock().unwrap().file = Some(file);
+    }
+
+    /// Queues encoded commands. Called with the log lock held, so commands
+    /// are queued in the order they were applied.
+    ///
+    /// # Returns
+    ///
+    /// The log position the commands are durable at.
+    pub(crate) fn push(&self, buf: &[u8]) -> u64 {
+        let mut queue = self.queue.lock().unwrap();
+        queue.buf.extend_from_slice(buf);
+        queue.appended += buf.len() as u64;
+        self.ready.notify_one();
+        queue.appended
+    }
+
+    /// Drops the queued commands after a rewrite has synced them into the
+    /// new file, and publishes them as durable. Called with the log lock
+    /// held.
+    pub(crate) fn discard_queued(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.buf.clear();
+        let appended = queue.appended;
+        self.durable
+            .send_modify(|durable| *durable = (*durable).max(appended));
+    }
+
+    /// Writes and fsyncs the queued commands, if there are any.
+    pub(crate) fn commit(&self) -> io::Result<()> {
+        let _commit = self.commit.lock().unwrap();
+
+        let (buf, position, file, start) = {
+            let mut queue = self.queue.lock().unwrap();
+            let Some(file) = queue.file.as_ref() else {
+                return Ok(());
+            };
+            if queue.buf.is_empty() {
+                return Ok(());
+            }
+            let file = file.try_clone()?;
+            let start = file.metadata()?.len();
+            (std::mem::take(&mut queue.buf), queue.appended, file, start)
+        };
+
+        match (&file).write_all(&buf).and_then(|()| file.sync_data()) {
+            Ok(()) => {
+                self.last_write_ok.store(true, Ordering::Relaxed);
+                self.durable
+                    .send_modify(|durable| *durable = (*durable).max(position));
+                Ok(())
+            }
+            Err(e) => {
+                // Take back what made it to the file and requeue the batch
+                // ahead of anything queued since
+                let _ = file.set_len(start);
+                let mut queue = self.queue.lock().unwrap();
+                let newer = std::mem::replace(&mut queue.buf, buf);
+                queue.buf.extend_from_slice(&newer);
+                self.last_write_ok.store(false, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    /// Waits until everything up to log position `position` is on disk.
+    pub(crate) async fn wait_durable(&self, position: u64) {
+        let mut durable = self.durable.subscribe();
+        let _ = durable.wait_for(|&durable| durable >= position).await;
+    }
+
+    /// Returns true if the last batch was committed (or none has run).
+    pub(crate) fn last_write_ok(&self) -> bool {
+        self.last_write_ok.load(Ordering::Relaxed)
+    }
+
+    /// Stops the flusher once the queue is empty.
+    pub(crate) fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+
+    /// The flusher: commits batches as commands queue up.
+    fn run(&self) {
+        loop {
+            {
+                let mut queue = self.queue.lock().unwrap();
+                while queue.buf.is_empty() && !queue.closed {
+                    queue = self.ready.wait(queue).unwrap();
+                }
+                if queue.buf.is_empty() {
+                    return;
+                }
+            }
+
+            if let Err(e) = self.commit() {
+                warn!("Failed to commit to the append-only file: {}", e);
+                std::thread::sleep(RETRY_DELAY);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::fs::OpenOptions;
+
+    fn open(name: &str) -> (Arc<Wal>, std::path::PathBuf) {
+        let path =
+            std::env::temp_dir().join(format!("flashkv-{}-{}.wal", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        let wal = Arc::new(Wal::default());
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        wal.set_file(file);
+        (wal, path)
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_pushes_are_committed_in_groups() {
+        let (wal, path) = open("group");
+        wal.start().unwrap();
+
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let wal = Arc::clone(&wal);
+                std::thread::spawn(move || {
+                    (0..50)
+                        .map(|_| wal.push(format!("{}", i).as_bytes()))
+                        .max()
+                        .unwrap()
+                })
+            })
+            .collect();
+        let last = writers.into_iter().map(|w| w.join().unwrap()).max();
+
+        wal.wait_durable(last.unwrap()).await;
+        assert_eq!(*wal.durable.borrow(), 400);
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 400);
+        for i in 0..8 {
+            let digit = b'0' + i;
+            assert_eq!(data.iter().filter(|&&b| b == digit).count(), 50);
+        }
+
+        wal.close();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_commit_and_discard() {
+        let (wal, path) = open("commit");
+
+        assert_eq!(wal.push(b"abc"), 3);
+        assert_eq!(*wal.durable.borrow(), 0);
+        wal.commit().unwrap();
+        assert_eq!(*wal.durable.borrow(), 3);
+        assert_eq!(fs::read(&path).unwrap(), b"abc");
+
+        // Queued commands already synced by a rewrite aren't written again
+        assert_eq!(wal.push(b"de"), 5);
+        wal.discard_queued();
+        assert_eq!(*wal.durable.borrow(), 5);
+        wal.commit().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"abc");
+        assert!(wal.last_write_ok());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,719 @@
+//! Sorted Set Implementation
+//!
+//! This module implements the data structure behind Redis sorted sets.
+//! Every member has a floating-point score, and members are kept ordered
+//! by `(score, member)`.
+//!
+//! ## Design
+//!
+//! Like Redis, a sorted set is made of two structures that are kept in sync:
+//!
+//! ```text
+//! ┌──────────────────────────────┐     ┌──────────────────────────────────┐
+//! │  HashMap<member, score>      │     │  SkipList ordered by             │
+//! │                              │     │  (score, member)                 │
+//! │  O(1) ZSCORE / membership    │     │  O(log n) insert/remove/rank     │
+//! └──────────────────────────────┘     └──────────────────────────────────┘
+//! ```
+//!
+//! The skiplist stores a *span* on every forward link (the number of nodes
+//! the link jumps over). Summing spans while descending gives the rank of a
+//! node in O(log n), which is what makes ZRANK and ZRANGE fast.
+//!
+//! Nodes live in a `Vec` arena and link to each other by index, which keeps
+//! the implementation free of `unsafe` and reference-counting overhead.
+
+use bytes::Bytes;
+use std::collections::HashMap;
+
+/// Maximum number of levels in the skiplist (enough for 2^64 elements with p = 1/4).
+const MAX_LEVEL: usize = 32;
+
+/// Probability of promoting a node to the next level.
+const LEVEL_PROBABILITY: f64 = 0.25;
+
+/// Sentinel index meaning "no node".
+const NIL: usize = usize::MAX;
+
+/// Index of the header node in the arena.
+const HEAD: usize = 0;
+
+/// A forward link at one level of a node.
+#[derive(Debug, Clone, Copy)]
+struct Level {
+    /// Next node at this level (or NIL)
+    forward: usize,
+    /// Number of level-0 nodes this link skips over
+    span: usize,
+}
+
+/// A single skiplist node.
+#[derive(Debug, Clone)]
+struct Node {
+    member: Bytes,
+    score: f64,
+    /// Previous node at level 0 (or NIL for the first node)
+    backward: usize,
+    levels: Vec<Level>,
+}
+
+impl Node {
+    /// Returns true if this node sorts strictly before `(score, member)`.
+    #[inline]
+    fn less_than(&self, score: f64, member: &[u8]) -> bool {
+        self.score < score || (self.score == score && self.member[..] < *member)
+    }
+
+    /// Returns true if this node sorts before or equal to `(score, member)`.
+    #[inline]
+    fn less_or_equal(&self, score: f64, member: &[u8]) -> bool {
+        self.score < score || (self.score == score && self.member[..] <= *member)
+    }
+}
+
+/// An indexable skiplist ordered by `(score, member)`.
+#[derive(Debug, Clone)]
+struct SkipList {
+    /// Node arena; index 0 is the header
+    nodes: Vec<Node>,
+    /// Indices of freed nodes available for reuse
+    free: Vec<usize>,
+    /// Last node at level 0 (or NIL)
+    tail: usize,
+    /// Current number of levels in use
+    level: usize,
+    /// Number of elements
+    len: usize,
+}
+
+impl SkipList {
+    fn new() -> Self {
+        let header = Node {
+            member: Bytes::new(),
+            score: 0.0,
+            backward: NIL,
+            levels: vec![
+                Level {
+                    forward: NIL,
+                    span: 0,
+                };
+                MAX_LEVEL
+            ],
+        };
+
+        Self {
+            nodes: vec![header],
+            free: Vec::new(),
+            tail: NIL,
+            level: 1,
+            len: 0,
+        }
+    }
+
+    /// Picks a random level for a new node.
+    fn random_level() -> usize {
+        let mut level = 1;
+        while level < MAX_LEVEL && rand::random::<f64>() < LEVEL_PROBABILITY {
+            level += 1;
+        }
+        level
+    }
+
+    /// Stores a node in the arena, reusing a free slot if possible.
+    fn alloc(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = node;
+                idx
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    /// Inserts a new element. The caller guarantees it is not already present.
+    fn insert(&mut self, score: f64, member: Bytes) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0usize; MAX_LEVEL];
+
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+            loop {
+                let next = self.nodes[x].levels[i].forward;
+                if next != NIL && self.nodes[next].less_than(score, &member) {
+                    rank[i] += self.nodes[x].levels[i].span;
+                    x = next;
+                } else {
+                    break;
+                }
+            }
+            update[i] = x;
+        }
+
+        let level = Self::random_level();
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = HEAD;
+                self.nodes[HEAD].levels[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let new = self.alloc(Node {
+            member,
+            score,
+            backward: NIL,
+            levels: vec![
+                Level {
+                    forward: NIL,
+                    span: 0,
+                };
+                level
+            ],
+        });
+
+        for i in 0..level {
+            let u = update[i];
+            let skipped = rank[0] - rank[i];
+            self.nodes[new].levels[i].forward = self.nodes[u].levels[i].forward;
+            self.nodes[new].levels[i].span = self.nodes[u].levels[i].span - skipped;
+            self.nodes[u].levels[i].forward = new;
+            self.nodes[u].levels[i].span = skipped + 1;
+        }
+
+        // Untouched higher levels now span one more node
+        for (i, &u) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[u].levels[i].span += 1;
+        }
+
+        self.nodes[new].backward = if update[0] == HEAD { NIL } else { update[0] };
+        let next = self.nodes[new].levels[0].forward;
+        if next != NIL {
+            self.nodes[next].backward = new;
+        } else {
+            self.tail = new;
+        }
+
+        self.len += 1;
+    }
+
+    /// Removes an element. Returns false if it wasn't found.
+    fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let mut update = [HEAD; MAX_LEVEL];
+
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            loop {
+                let next = self.nodes[x].levels[i].forward;
+                if next != NIL && self.nodes[next].less_than(score, member) {
+                    x = next;
+                } else {
+                    break;
+                }
+            }
+            update[i] = x;
+        }
+
+        let target = self.nodes[x].levels[0].forward;
+        if target == NIL
+            || self.nodes[target].score != score
+            || self.nodes[target].member[..] != *member
+        {
+            return false;
+        }
+
+        for (i, &u) in update.iter().enumerate().take(self.level) {
+            if self.nodes[u].levels[i].forward == target {
+                self.nodes[u].levels[i].span += self.nodes[target].levels[i].span;
+                self.nodes[u].levels[i].span -= 1;
+                self.nodes[u].levels[i].forward = self.nodes[target].levels[i].forward;
+            } else {
+                self.nodes[u].levels[i].span -= 1;
+            }
+        }
+
+        let next = self.nodes[target].levels[0].forward;
+        if next != NIL {
+            self.nodes[next].backward = self.nodes[target].backward;
+        } else {
+            self.tail = self.nodes[target].backward;
+        }
+
+        while self.level > 1 && self.nodes[HEAD].levels[self.level - 1].forward == NIL {
+            self.level -= 1;
+        }
+
+        // Release the member bytes and recycle the slot
+        self.nodes[target].member = Bytes::new();
+        self.nodes[target].levels = Vec::new();
+        self.free.push(target);
+        self.len -= 1;
+        true
+    }
+
+    /// Returns the 0-based rank of an element, if present.
+    fn rank(&self, score: f64, member: &[u8]) -> Option<usize> {
+        let mut traversed = 0;
+        let mut x = HEAD;
+
+        for i in (0..self.level).rev() {
+            loop {
+                let next = self.nodes[x].levels[i].forward;
+                if next != NIL && self.nodes[next].less_or_equal(score, member) {
+                    traversed += self.nodes[x].levels[i].span;
+                    x = next;
+                } else {
+                    break;
+                }
+            }
+            if x != HEAD && self.nodes[x].member[..] == *member {
+                return Some(traversed - 1);
+            }
+        }
+
+        None
+    }
+
+    /// Returns the arena index of the node at a 0-based rank.
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        if rank >= self.len {
+            return None;
+        }
+
+        let target = rank + 1;
+        let mut traversed = 0;
+        let mut x = HEAD;
+
+        for i in (0..self.level).rev() {
+            loop {
+                let next = self.nodes[x].levels[i].forward;
+                if next != NIL && traversed + self.nodes[x].levels[i].span <= target {
+                    traversed += self.nodes[x].levels[i].span;
+                    x = next;
+                } else {
+                    break;
+                }
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+
+        None
+    }
+}
+
+/// A sorted set: unique members ordered by score.
+///
+/// # Example
+///
+/// ```
+/// use flashkv::storage::zset::SortedSet;
+/// use bytes::Bytes;
+///
+/// let mut zset = SortedSet::new();
+/// zset.insert(Bytes::from("alice"), 100.0);
+/// zset.insert(Bytes::from("bob"), 50.0);
+///
+/// assert_eq!(zset.rank(b"bob"), Some(0));
+/// assert_eq!(zset.score(b"alice"), Some(100.0));
+/// ```
+#[derive(Debug, Clone)]
+pub struct SortedSet {
+    /// Member -> score lookup
+    scores: HashMap<Bytes, f64>,
+    /// Score-ordered index
+    list: SkipList,
+}
+
+impl Default for SortedSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SortedSet {
+    /// Creates an empty sorted set.
+    pub fn new() -> Self {
+        Self {
+            scores: HashMap::new(),
+            list: SkipList::new(),
+        }
+    }
+
+    /// Returns the number of members.
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    /// Returns true if the set has no members.
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Returns the score of a member.
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Inserts a member or updates its score.
+    ///
+    /// # Returns
+    /// `true` if the member was newly added.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> bool {
+        match self.scores.get(&member).copied() {
+            Some(old) if old == score => false,
+            Some(old) => {
+                self.list.remove(old, &member);
+                self.list.insert(score, member.clone());
+                self.scores.insert(member, score);
+                false
+            }
+            None => {
+                self.list.insert(score, member.clone());
+                self.scores.insert(member, score);
+                true
+            }
+        }
+    }
+
+    /// Removes a member.
+    ///
+    /// # Returns
+    /// `true` if the member was present.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => {
+                self.list.remove(score, member);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes and returns the member with the lowest score
+    /// (or the highest score with `max`).
+    pub fn pop(&mut self, max: bool) -> Option<(Bytes, f64)> {
+        let (member, score) = self.range_by_rank(0, 0, max).pop()?;
+        self.remove(&member);
+        Some((member, score))
+    }
+
+    /// Returns the 0-based rank of a member in ascending score order.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        self.list.rank(score, member)
+    }
+
+    /// Returns the 0-based rank of a member in descending score order.
+    pub fn rev_rank(&self, member: &[u8]) -> Option<usize> {
+        self.rank(member).map(|r| self.len() - 1 - r)
+    }
+
+    /// Returns members with scores between two inclusive ranks.
+    ///
+    /// Ranks are 0-based and must already be clamped to the set size.
+    /// With `rev`, rank 0 is the member with the highest score.
+    pub fn range_by_rank(&self, start: usize, stop: usize, rev: bool) -> Vec<(Bytes, f64)> {
+        if start > stop || start >= self.len() {
+            return Vec::new();
+        }
+        let stop = stop.min(self.len() - 1);
+        let count = stop - start + 1;
+
+        let first = if rev {
+            self.list.node_at(self.len() - 1 - start)
+        } else {
+            self.list.node_at(start)
+        };
+
+        let mut result = Vec::with_capacity(count);
+        let mut x = first.unwrap_or(NIL);
+        while x != NIL && result.len() < count {
+            let node = &self.list.nodes[x];
+            result.push((node.member.clone(), node.score));
+            x = if rev {
+                node.backward
+            } else {
+                node.levels[0].forward
+            };
+        }
+        result
+    }
+
+    /// Iterates over all members in ascending score order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, f64)> + '_ {
+        let mut x = self.list.nodes[HEAD].levels[0].forward;
+        std::iter::from_fn(move || {
+            if x == NIL {
+                return None;
+            }
+            let node = &self.list.nodes[x];
+            x = node.levels[0].forward;
+            Some((&node.member, node.score))
+        })
+    }
+}
+
+/// Options controlling how ZADD treats existing and new members.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ZAddFlags {
+    /// Only add new members, never update existing ones
+    pub nx: bool,
+    /// Only update existing members, never add new ones
+    pub xx: bool,
+    /// Only update when the new score is greater than the current one
+    pub gt: bool,
+    /// Only update when the new score is less than the current one
+    pub lt: bool,
+    /// Treat the score as an increment (ZINCRBY semantics)
+    pub incr: bool,
+}
+
+/// The outcome of a ZADD call.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ZAddResult {
+    /// Number of new members added
+    pub added: usize,
+    /// Number of existing members whose score changed
+    pub updated: usize,
+    /// Final score of the last member processed (None if it was skipped)
+    pub score: Option<f64>,
+}
+
+impl SortedSet {
+    /// Adds or updates a member according to ZADD flags.
+    ///
+    /// # Returns
+    /// The resulting score, `Ok(None)` if the flags prevented the change,
+    /// or an error if an increment produced NaN.
+    pub fn add(
+        &mut self,
+        member: Bytes,
+        score: f64,
+        flags: ZAddFlags,
+        result: &mut ZAddResult,
+    ) -> Result<Option<f64>, &'static str> {
+        match self.score(&member) {
+            Some(current) => {
+                if flags.nx {
+                    return Ok(None);
+                }
+
+                let new_score = if flags.incr {
+                    let s = current + score;
+                    if s.is_nan() {
+                        return Err("resulting score is not a number (NaN)");
+                    }
+                    s
+                } else {
+                    score
+                };
+
+                if (flags.gt && new_score <= current) || (flags.lt && new_score >= current) {
+                    return Ok(None);
+                }
+
+                if new_score != current {
+                    self.insert(member, new_score);
+                    result.updated += 1;
+                }
+                Ok(Some(new_score))
+            }
+            None => {
+                if flags.xx {
+                    return Ok(None);
+                }
+                self.insert(member, score);
+                result.added += 1;
+                Ok(Some(score))
+            }
+        }
+    }
+}
+
+/// How ZUNIONSTORE/ZINTERSTORE combine the scores of a member found in
+/// several sources.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Aggregate {
+    /// Add the scores together (the default)
+    #[default]
+    Sum,
+    /// Keep the lowest score
+    Min,
+    /// Keep the highest score
+    Max,
+}
+
+impl Aggregate {
+    /// Combines an accumulated score with another (already weighted) score.
+    pub fn combine(self, acc: f64, score: f64) -> f64 {
+        match self {
+            // inf + -inf is NaN; Redis treats that as 0
+            Aggregate::Sum => zero_if_nan(acc + score),
+            Aggregate::Min => acc.min(score),
+            Aggregate::Max => acc.max(score),
+        }
+    }
+}
+
+/// Multiplies a score by a weight, mapping NaN (e.g. `inf * 0`) to 0.
+pub fn weighted(score: f64, weight: f64) -> f64 {
+    zero_if_nan(score * weight)
+}
+
+fn zero_if_nan(score: f64) -> f64 {
+    if score.is_nan() {
+        0.0
+    } else {
+        score
+    }
+}
+
+/// Formats a score the way Redis replies with it (e.g. `1.5`, `3`, `inf`).
+pub fn format_score(score: f64) -> Bytes {
+    Bytes::from(score.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(zset: &SortedSet) -> Vec<Bytes> {
+        zset.iter().map(|(m, _)| m.clone()).collect()
+    }
+
+    #[test]
+    fn test_insert_orders_by_score_then_member() {
+        let mut zset = SortedSet::new();
+        assert!(zset.insert(Bytes::from("c"), 2.0));
+        assert!(zset.insert(Bytes::from("a"), 1.0));
+        assert!(zset.insert(Bytes::from("b"), 2.0));
+
+        assert_eq!(
+            members(&zset),
+            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")]
+        );
+
+        // Updating a score moves the member
+        assert!(!zset.insert(Bytes::from("a"), 3.0));
+        assert_eq!(
+            members(&zset),
+            vec![Bytes::from("b"), Bytes::from("c"), Bytes::from("a")]
+        );
+        assert_eq!(zset.len(), 3);
+    }
+
+    #[test]
+    fn test_rank_and_range() {
+        let mut zset = SortedSet::new();
+        for i in 0..1000 {
+            zset.insert(Bytes::from(format!("m{:04}", i)), i as f64);
+        }
+
+        assert_eq!(zset.rank(b"m0000"), Some(0));
+        assert_eq!(zset.rank(b"m0500"), Some(500));
+        assert_eq!(zset.rev_rank(b"m0999"), Some(0));
+        assert_eq!(zset.rank(b"missing"), None);
+
+        let range = zset.range_by_rank(10, 12, false);
+        assert_eq!(
+            range,
+            vec![
+                (Bytes::from("m0010"), 10.0),
+                (Bytes::from("m0011"), 11.0),
+                (Bytes::from("m0012"), 12.0),
+            ]
+        );
+
+        let range = zset.range_by_rank(0, 1, true);
+        assert_eq!(
+            range,
+            vec![(Bytes::from("m0999"), 999.0), (Bytes::from("m0998"), 998.0)]
+        );
+    }
+
+    #[test]
+    fn test_remove_keeps_ranks_consistent() {
+        let mut zset = SortedSet::new();
+        for i in 0..200 {
+            zset.insert(Bytes::from(format!("m{:03}", i)), i as f64);
+        }
+        for i in (0..200).step_by(2) {
+            assert!(zset.remove(format!("m{:03}", i).as_bytes()));
+        }
+        assert!(!zset.remove(b"m000"));
+
+        assert_eq!(zset.len(), 100);
+        for i in 0..100 {
+            let member = format!("m{:03}", i * 2 + 1);
+            assert_eq!(zset.rank(member.as_bytes()), Some(i));
+        }
+        assert_eq!(zset.range_by_rank(99, 99, false)[0].0, Bytes::from("m199"));
+    }
+
+    #[test]
+    fn test_pop() {
+        let mut zset = SortedSet::new();
+        zset.insert(Bytes::from("a"), 1.0);
+        zset.insert(Bytes::from("b"), 2.0);
+        zset.insert(Bytes::from("c"), 3.0);
+
+        assert_eq!(zset.pop(false), Some((Bytes::from("a"), 1.0)));
+        assert_eq!(zset.pop(true), Some((Bytes::from("c"), 3.0)));
+        assert_eq!(zset.pop(true), Some((Bytes::from("b"), 2.0)));
+        assert_eq!(zset.pop(false), None);
+        assert!(zset.is_empty());
+    }
+
+    #[test]
+    fn test_aggregate() {
+        assert_eq!(Aggregate::Sum.combine(1.5, 2.0), 3.5);
+        assert_eq!(Aggregate::Min.combine(1.5, 2.0), 1.5);
+        assert_eq!(Aggregate::Max.combine(1.5, 2.0), 2.0);
+        assert_eq!(
+            Aggregate::Sum.combine(f64::INFINITY, f64::NEG_INFINITY),
+            0.0
+        );
+        assert_eq!(weighted(f64::INFINITY, 0.0), 0.0);
+        assert_eq!(weighted(2.0, 3.0), 6.0);
+    }
+
+    #[test]
+    fn test_add_flags() {
+        let mut zset = SortedSet::new();
+        let mut result = ZAddResult::default();
+
+        let gt = ZAddFlags {
+            gt: true,
+            ..Default::default()
+        };
+        zset.add(Bytes::from("a"), 5.0, gt, &mut result).unwrap();
+        assert_eq!(result.added, 1);
+
+        // GT refuses to lower the score
+        assert_eq!(zset.add(Bytes::from("a"), 3.0, gt, &mut result), Ok(None));
+        assert_eq!(zset.score(b"a"), Some(5.0));
+
+        let incr = ZAddFlags {
+            incr: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            zset.add(Bytes::from("a"), 2.5, incr, &mut result),
+            Ok(Some(7.5))
+        );
+        assert_eq!(result.updated, 1);
+
+        let xx = ZAddFlags {
+            xx: true,
+            ..Default::default()
+        };
+        assert_eq!(zset.add(Bytes::from("new"), 1.0, xx, &mut result), Ok(None));
+        assert_eq!(zset.len(), 1);
+    }
+}